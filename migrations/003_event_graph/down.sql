@@ -0,0 +1,3 @@
+DROP TABLE event_edges;
+
+ALTER TABLE events DROP COLUMN depth;
@@ -0,0 +1,24 @@
+ALTER TABLE events ADD COLUMN depth BIGINT NOT NULL DEFAULT 1;
+
+CREATE TABLE event_edges (
+    event_id TEXT NOT NULL,
+    prev_event_id TEXT NOT NULL,
+    PRIMARY KEY (event_id, prev_event_id)
+);
+
+CREATE INDEX event_edges_prev_event_id ON event_edges (prev_event_id);
+
+-- Existing rooms have a linear history, so each event follows the one before it.
+INSERT INTO event_edges (event_id, prev_event_id)
+SELECT id, prev_event_id FROM (
+    SELECT id, lag(id) OVER (PARTITION BY room_id ORDER BY ordering) AS prev_event_id
+    FROM events
+) AS linear_history
+WHERE prev_event_id IS NOT NULL;
+
+UPDATE events SET depth = linear_history.depth
+FROM (
+    SELECT id, row_number() OVER (PARTITION BY room_id ORDER BY ordering) AS depth
+    FROM events
+) AS linear_history
+WHERE events.id = linear_history.id;
@@ -0,0 +1,10 @@
+CREATE TABLE user_threepids (
+    medium TEXT NOT NULL,
+    address TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    validated_at TIMESTAMP,
+    added_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (medium, address)
+);
+
+CREATE INDEX user_threepids_user_id ON user_threepids (user_id);
@@ -0,0 +1,17 @@
+ALTER TABLE access_tokens ADD COLUMN device_id TEXT;
+
+UPDATE access_tokens SET device_id = 'TOKEN' || id;
+
+ALTER TABLE access_tokens ALTER COLUMN device_id SET NOT NULL;
+
+CREATE TABLE user_ips (
+    access_token_id BIGINT NOT NULL REFERENCES access_tokens (id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    user_agent TEXT NOT NULL,
+    last_seen TIMESTAMP NOT NULL,
+    PRIMARY KEY (access_token_id, ip, user_agent)
+);
+
+CREATE INDEX user_ips_user_id ON user_ips (user_id);
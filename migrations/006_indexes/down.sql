@@ -0,0 +1,9 @@
+DROP INDEX room_memberships_user_id_membership;
+
+DROP INDEX room_aliases_room_id;
+
+DROP INDEX events_room_id_event_type_state_key;
+DROP INDEX events_room_id_ordering;
+
+DROP INDEX access_tokens_user_id;
+DROP INDEX access_tokens_value;
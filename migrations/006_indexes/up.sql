@@ -0,0 +1,9 @@
+CREATE UNIQUE INDEX access_tokens_value ON access_tokens (value);
+CREATE INDEX access_tokens_user_id ON access_tokens (user_id);
+
+CREATE INDEX events_room_id_ordering ON events (room_id, ordering);
+CREATE INDEX events_room_id_event_type_state_key ON events (room_id, event_type, state_key, ordering);
+
+CREATE INDEX room_aliases_room_id ON room_aliases (room_id);
+
+CREATE INDEX room_memberships_user_id_membership ON room_memberships (user_id, membership);
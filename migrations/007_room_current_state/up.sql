@@ -0,0 +1,13 @@
+CREATE TABLE room_current_state (
+    room_id TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    state_key TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    PRIMARY KEY (room_id, event_type, state_key)
+);
+
+INSERT INTO room_current_state (room_id, event_type, state_key, event_id)
+SELECT DISTINCT ON (room_id, event_type, state_key) room_id, event_type, state_key, id
+FROM events
+WHERE state_key IS NOT NULL
+ORDER BY room_id, event_type, state_key, ordering DESC;
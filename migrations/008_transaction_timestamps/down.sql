@@ -0,0 +1,3 @@
+DROP INDEX transactions_access_token_created_at;
+
+ALTER TABLE transactions DROP COLUMN created_at;
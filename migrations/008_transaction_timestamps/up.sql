@@ -0,0 +1,3 @@
+ALTER TABLE transactions ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT now();
+
+CREATE INDEX transactions_access_token_created_at ON transactions (access_token, created_at);
@@ -0,0 +1 @@
+CREATE INDEX background_jobs_kind_destination ON background_jobs (kind, (payload::jsonb ->> 'destination')) WHERE NOT dead;
@@ -0,0 +1,6 @@
+CREATE TABLE remote_alias_cache (
+    alias TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    servers TEXT[] NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE room_current_state DROP COLUMN etag;
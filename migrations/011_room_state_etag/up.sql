@@ -0,0 +1,2 @@
+-- Existing rooms get an ETag with their next state change.
+ALTER TABLE room_current_state ADD COLUMN etag TEXT NOT NULL DEFAULT '';
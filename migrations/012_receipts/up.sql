@@ -0,0 +1,9 @@
+CREATE TABLE receipts (
+    room_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    receipt_type TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    private BOOLEAN NOT NULL DEFAULT FALSE,
+    updated_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (room_id, user_id, receipt_type)
+);
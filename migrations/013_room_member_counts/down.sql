@@ -0,0 +1,2 @@
+DROP INDEX room_memberships_room_id_membership;
+DROP TABLE room_member_counts;
@@ -0,0 +1,15 @@
+CREATE TABLE room_member_counts (
+    room_id TEXT PRIMARY KEY,
+    joined BIGINT NOT NULL,
+    invited BIGINT NOT NULL
+);
+
+INSERT INTO room_member_counts (room_id, joined, invited)
+SELECT
+    room_id,
+    COUNT(*) FILTER (WHERE membership = 'join'),
+    COUNT(*) FILTER (WHERE membership = 'invite')
+FROM room_memberships
+GROUP BY room_id;
+
+CREATE INDEX room_memberships_room_id_membership ON room_memberships (room_id, membership);
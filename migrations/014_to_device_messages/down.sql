@@ -0,0 +1 @@
+DROP TABLE to_device_messages;
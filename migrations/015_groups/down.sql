@@ -0,0 +1,2 @@
+DROP TABLE group_rooms;
+DROP TABLE groups;
@@ -0,0 +1,18 @@
+CREATE TABLE groups (
+    id TEXT PRIMARY KEY,
+    name TEXT,
+    avatar_url TEXT,
+    short_description TEXT,
+    long_description TEXT,
+    public BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE TABLE group_rooms (
+    group_id TEXT NOT NULL REFERENCES groups (id) ON DELETE CASCADE,
+    room_id TEXT NOT NULL,
+    public BOOLEAN NOT NULL DEFAULT TRUE,
+    PRIMARY KEY (group_id, room_id)
+);
+
+CREATE INDEX group_rooms_room_id ON group_rooms (room_id);
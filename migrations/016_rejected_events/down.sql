@@ -0,0 +1,3 @@
+ALTER TABLE events DROP COLUMN soft_failed;
+
+DROP TABLE rejected_events;
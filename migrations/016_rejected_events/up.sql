@@ -0,0 +1,13 @@
+CREATE TABLE rejected_events (
+    id BIGSERIAL PRIMARY KEY,
+    event_id TEXT,
+    room_id TEXT,
+    origin TEXT NOT NULL,
+    event_json TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    rejected_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX rejected_events_rejected_at ON rejected_events (rejected_at);
+
+ALTER TABLE events ADD COLUMN soft_failed BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,9 @@
+-- User IDs differing only in case can't be indexed. The server reports them at startup, and
+-- creates the index once they are resolved.
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM users GROUP BY lower(id) HAVING count(*) > 1) THEN
+        CREATE UNIQUE INDEX users_lower_id_key ON users (lower(id));
+    END IF;
+END
+$$;
@@ -0,0 +1,6 @@
+CREATE TABLE admin_metrics (
+    ts TIMESTAMP NOT NULL,
+    metric_name TEXT NOT NULL,
+    value BIGINT NOT NULL,
+    PRIMARY KEY (metric_name, ts)
+);
@@ -0,0 +1,2 @@
+DROP TABLE key_backup;
+DROP TABLE key_backup_versions;
@@ -0,0 +1,6 @@
+CREATE TABLE remote_profiles (
+    user_id TEXT PRIMARY KEY,
+    avatar_url TEXT,
+    displayname TEXT,
+    expires_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,7 @@
+CREATE TABLE push_rule_overrides (
+    user_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    rule_id TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL,
+    PRIMARY KEY (user_id, kind, rule_id)
+);
@@ -0,0 +1 @@
+DROP TABLE claimed_megolm_sessions;
@@ -0,0 +1,7 @@
+CREATE TABLE claimed_megolm_sessions (
+    user_id TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    room_id TEXT NOT NULL,
+    session_id TEXT NOT NULL,
+    PRIMARY KEY (user_id, device_id, room_id, session_id)
+);
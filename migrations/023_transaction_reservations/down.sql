@@ -0,0 +1,8 @@
+DELETE FROM transactions WHERE response IS NULL OR method <> 'PUT';
+
+ALTER TABLE transactions DROP CONSTRAINT transactions_pkey;
+
+ALTER TABLE transactions ALTER COLUMN response SET NOT NULL;
+ALTER TABLE transactions DROP COLUMN method;
+
+ALTER TABLE transactions ADD PRIMARY KEY (path, access_token);
@@ -0,0 +1,7 @@
+ALTER TABLE transactions DROP CONSTRAINT transactions_pkey;
+
+ALTER TABLE transactions ADD COLUMN method TEXT NOT NULL DEFAULT 'PUT';
+ALTER TABLE transactions ALTER COLUMN method DROP DEFAULT;
+ALTER TABLE transactions ALTER COLUMN response DROP NOT NULL;
+
+ALTER TABLE transactions ADD PRIMARY KEY (method, path, access_token);
@@ -0,0 +1 @@
+DROP FUNCTION background_job_payload_field(TEXT, TEXT);
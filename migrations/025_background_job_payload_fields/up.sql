@@ -0,0 +1,4 @@
+-- The value of a top-level field of a job's JSON payload, as text.
+CREATE FUNCTION background_job_payload_field(payload TEXT, field TEXT) RETURNS TEXT AS $$
+    SELECT payload::jsonb ->> field
+$$ LANGUAGE SQL IMMUTABLE STRICT;
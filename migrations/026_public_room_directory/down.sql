@@ -0,0 +1,4 @@
+DROP FUNCTION public_room_relevance(TEXT, TEXT, TEXT[], TEXT, TEXT);
+DROP FUNCTION directory_match_score(TEXT, TEXT, INTEGER);
+DROP VIEW public_room_directory;
+DROP FUNCTION room_state_content(TEXT, TEXT);
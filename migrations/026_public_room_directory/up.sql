@@ -0,0 +1,71 @@
+-- The content of the event setting a piece of a room's current state with an empty state key.
+CREATE FUNCTION room_state_content(room TEXT, state_type TEXT) RETURNS JSONB AS $$
+    SELECT events.content::jsonb
+    FROM room_current_state
+    JOIN events ON events.id = room_current_state.event_id
+    WHERE room_current_state.room_id = room
+        AND room_current_state.event_type = state_type
+        AND room_current_state.state_key = ''
+$$ LANGUAGE SQL STABLE STRICT;
+
+-- The public rooms as listed in the directory.
+CREATE VIEW public_room_directory AS
+SELECT
+    rooms.id AS room_id,
+    room_state_content(rooms.id, 'm.room.name') ->> 'name' AS name,
+    room_state_content(rooms.id, 'm.room.topic') ->> 'topic' AS topic,
+    room_state_content(rooms.id, 'm.room.canonical_alias') ->> 'alias' AS canonical_alias,
+    room_state_content(rooms.id, 'm.room.avatar') ->> 'url' AS avatar_url,
+    COALESCE(
+        room_state_content(rooms.id, 'm.room.guest_access') ->> 'guest_access' = 'can_join',
+        FALSE
+    ) AS guest_can_join,
+    COALESCE(
+        room_state_content(rooms.id, 'm.room.history_visibility') ->> 'history_visibility'
+            = 'world_readable',
+        FALSE
+    ) AS world_readable,
+    (
+        SELECT COUNT(*) FROM room_memberships
+        WHERE room_memberships.room_id = rooms.id AND room_memberships.membership = 'join'
+    ) AS num_joined_members,
+    ARRAY(
+        SELECT room_aliases.alias FROM room_aliases
+        WHERE room_aliases.room_id = rooms.id
+        ORDER BY room_aliases.alias
+    ) AS aliases,
+    room_state_content(rooms.id, 'm.room.related_groups')::TEXT AS related_groups
+FROM rooms
+WHERE rooms.public;
+
+-- How well a value matches a search term: twice the weight if it starts with the term, the
+-- weight if it contains it elsewhere, and 0 otherwise. The search ignores case and, unlike ILIKE,
+-- treats every character of the term literally.
+CREATE FUNCTION directory_match_score(value TEXT, term TEXT, weight INTEGER) RETURNS INTEGER AS $$
+    SELECT CASE strpos(lower(value), lower(term))
+        WHEN 0 THEN 0
+        WHEN 1 THEN weight * 2
+        ELSE weight
+    END
+$$ LANGUAGE SQL IMMUTABLE STRICT;
+
+-- How relevant a room of the directory is to a search term, 0 if it doesn't match. Matches in
+-- the name count the most, followed by the canonical alias, the other aliases, and the topic.
+-- Without a search term, every room is equally relevant.
+CREATE FUNCTION public_room_relevance(
+    name TEXT,
+    canonical_alias TEXT,
+    aliases TEXT[],
+    topic TEXT,
+    term TEXT
+) RETURNS INTEGER AS $$
+    SELECT CASE WHEN term IS NULL THEN 1 ELSE
+        COALESCE(directory_match_score(name, term, 8), 0) +
+        COALESCE(directory_match_score(canonical_alias, term, 4), 0) +
+        COALESCE(
+            (SELECT MAX(directory_match_score(alias, term, 2)) FROM unnest(aliases) AS alias),
+            0
+        ) +
+        COALESCE(directory_match_score(topic, term, 1), 0)
+    END
+$$ LANGUAGE SQL IMMUTABLE;
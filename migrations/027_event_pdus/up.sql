@@ -0,0 +1,2 @@
+-- The signed PDUs of events received from other servers, kept so they can be relayed unchanged.
+ALTER TABLE events ADD COLUMN pdu TEXT;
@@ -0,0 +1,270 @@
+//! An in-memory cache of access token lookups.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read as PersistentRead;
+use ruma_identifiers::UserId;
+
+use clock::Clock;
+use error::ApiError;
+use models::access_token::AccessToken;
+use models::user::User;
+
+/// Caches the access tokens and users looked up by `AccessTokenAuth`, so that authenticated
+/// requests don't have to query the database every time.
+///
+/// Entries expire after `ttl` and the cache holds at most `capacity` tokens. Handlers revoking
+/// access tokens or changing users must invalidate the affected entries, so that the change
+/// applies to the next request.
+#[derive(Clone, Debug)]
+pub struct AccessTokenCache {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    capacity: usize,
+    clock: Arc<Clock>,
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    access_token: AccessToken,
+    user: User,
+    expires_at: SystemTime,
+}
+
+impl AccessTokenCache {
+    /// Creates an empty `AccessTokenCache` holding at most `capacity` tokens for `ttl` each, as
+    /// measured by `clock`.
+    ///
+    /// A capacity of 0 disables caching.
+    pub fn new(capacity: usize, ttl: Duration, clock: Arc<Clock>) -> Self {
+        AccessTokenCache {
+            inner: Arc::new(Inner {
+                capacity: capacity,
+                clock: clock,
+                ttl: ttl,
+                entries: Mutex::new(HashMap::new()),
+                hits: AtomicUsize::new(0),
+                misses: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Returns the cached access token and its user, unless the entry is missing or expired.
+    pub fn get(&self, token: &str) -> Option<(AccessToken, User)> {
+        let now = self.inner.clock.now();
+        let mut entries = self.lock();
+
+        let cached = match entries.get(token) {
+            Some(entry) if entry.expires_at > now => {
+                Some((entry.access_token.clone(), entry.user.clone()))
+            }
+            Some(_) => {
+                entries.remove(token);
+
+                None
+            }
+            None => None,
+        };
+
+        if cached.is_some() {
+            self.inner.hits.fetch_add(1, Ordering::SeqCst);
+        } else {
+            self.inner.misses.fetch_add(1, Ordering::SeqCst);
+        }
+
+        cached
+    }
+
+    /// Caches an access token and its user after they were loaded from the database.
+    ///
+    /// If the cache is full, expired entries are dropped first, then the entry closest to
+    /// expiring.
+    pub fn insert(&self, access_token: AccessToken, user: User) {
+        if self.inner.capacity == 0 {
+            return;
+        }
+
+        let now = self.inner.clock.now();
+        let mut entries = self.lock();
+
+        if !entries.contains_key(&access_token.value) && entries.len() >= self.inner.capacity {
+            entries.retain(|_, entry| entry.expires_at > now);
+
+            if entries.len() >= self.inner.capacity {
+                let closest_to_expiring = entries.iter()
+                    .min_by_key(|&(_, entry)| entry.expires_at)
+                    .map(|(token, _)| token.clone());
+
+                if let Some(token) = closest_to_expiring {
+                    entries.remove(&token);
+                }
+            }
+        }
+
+        let entry = Entry {
+            access_token: access_token.clone(),
+            user: user,
+            expires_at: now + self.inner.ttl,
+        };
+
+        entries.insert(access_token.value, entry);
+    }
+
+    /// Drops the cached access token, e.g. after it was revoked.
+    pub fn invalidate(&self, token: &str) {
+        self.lock().remove(token);
+    }
+
+    /// Drops all cached access tokens of the user, e.g. after the user was deactivated.
+    pub fn invalidate_user(&self, user_id: &UserId) {
+        self.lock().retain(|_, entry| &entry.user.id != user_id);
+    }
+
+    /// The number of access tokens currently cached.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Whether or not any access token is cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of lookups answered from the cache.
+    pub fn hits(&self) -> usize {
+        self.inner.hits.load(Ordering::SeqCst)
+    }
+
+    /// The number of lookups that had to fall back to the database.
+    pub fn misses(&self) -> usize {
+        self.inner.misses.load(Ordering::SeqCst)
+    }
+
+    /// Extract the `AccessTokenCache` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<AccessTokenCache>, ApiError> {
+        request.get::<PersistentRead<AccessTokenCache>>().map_err(ApiError::from)
+    }
+
+    /// Locks the entries, recovering them if another thread panicked while holding the lock.
+    fn lock(&self) -> MutexGuard<HashMap<String, Entry>> {
+        match self.inner.entries.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Key for AccessTokenCache {
+    type Value = AccessTokenCache;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use diesel::pg::data_types::PgTimestamp;
+    use ruma_identifiers::UserId;
+
+    use clock::{MockClock, SystemClock};
+    use models::access_token::AccessToken;
+    use models::user::User;
+    use super::AccessTokenCache;
+
+    fn entry(user: &str, token: &str) -> (AccessToken, User) {
+        let user_id = UserId::try_from(format!("@{}:ruma.test", user).as_ref()).unwrap();
+
+        let access_token = AccessToken {
+            id: 0,
+            user_id: user_id.clone(),
+            value: token.to_string(),
+            revoked: false,
+            created_at: PgTimestamp(0),
+            updated_at: PgTimestamp(0),
+            device_id: "DEVICE".to_string(),
+        };
+
+        let user = User {
+            id: user_id,
+            password_hash: String::new(),
+            active: true,
+            created_at: PgTimestamp(0),
+            updated_at: PgTimestamp(0),
+            admin: false,
+        };
+
+        (access_token, user)
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let cache = AccessTokenCache::new(10, Duration::from_secs(60), Arc::new(SystemClock));
+
+        assert!(cache.get("token").is_none());
+
+        let (access_token, user) = entry("alice", "token");
+        cache.insert(access_token, user);
+
+        assert_eq!(cache.get("token").unwrap().1.id.to_string(), "@alice:ruma.test");
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 1);
+    }
+
+    #[test]
+    fn expired_entries_are_misses() {
+        let clock = Arc::new(MockClock::new());
+        let cache = AccessTokenCache::new(10, Duration::from_secs(60), clock.clone());
+
+        let (access_token, user) = entry("alice", "token");
+        cache.insert(access_token, user);
+        clock.advance(Duration::from_secs(61));
+
+        assert!(cache.get("token").is_none());
+        assert!(cache.is_empty());
+        assert_eq!(cache.misses(), 1);
+    }
+
+    #[test]
+    fn invalidate_user_drops_all_tokens_of_user() {
+        let cache = AccessTokenCache::new(10, Duration::from_secs(60), Arc::new(SystemClock));
+
+        for &(user, token) in &[("alice", "a1"), ("alice", "a2"), ("bob", "b1")] {
+            let (access_token, user) = entry(user, token);
+            cache.insert(access_token, user);
+        }
+
+        cache.invalidate_user(&UserId::try_from("@alice:ruma.test").unwrap());
+
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("b1").is_some());
+    }
+
+    #[test]
+    fn evicts_entry_closest_to_expiring() {
+        let clock = Arc::new(MockClock::new());
+        let cache = AccessTokenCache::new(2, Duration::from_secs(60), clock.clone());
+
+        for token in &["t1", "t2", "t3"] {
+            let (access_token, user) = entry("alice", token);
+            cache.insert(access_token, user);
+            clock.advance(Duration::from_secs(1));
+        }
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("t1").is_none());
+        assert!(cache.get("t3").is_some());
+    }
+}
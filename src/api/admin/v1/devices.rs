@@ -0,0 +1,224 @@
+//! Endpoints for inspecting and ending the sessions of users.
+
+use bodyparser;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::UserId;
+
+use access_token_cache::AccessTokenCache;
+use api::r0::milliseconds_since_epoch;
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, AdminAuth, JsonRequest, MiddlewareChain, UserIdParam};
+use models::access_token::AccessToken;
+use models::user::User;
+use models::user_ip::UserIp;
+use modifier::{EmptyResponse, SerializableResponse};
+use request_ext::extension;
+
+/// The GET `/users/:user_id/devices` endpoint.
+pub struct GetDevices;
+
+#[derive(Debug, Serialize)]
+struct GetDevicesResponse {
+    devices: Vec<Device>,
+}
+
+/// A device with an access token that has not been revoked.
+#[derive(Debug, Serialize)]
+struct Device {
+    /// The ID of the device.
+    device_id: String,
+    /// The user the device belongs to.
+    user_id: UserId,
+    /// The IP address the device was last seen at.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_seen_ip: Option<String>,
+    /// The user agent of the device when it was last seen.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_seen_user_agent: Option<String>,
+    /// The time the device was last seen, in milliseconds since the Unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_seen_ts: Option<u64>,
+}
+
+middleware_chain!(GetDevices, [AccessTokenAuth, AdminAuth, UserIdParam]);
+
+impl Handler for GetDevices {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user_id = extension::<UserIdParam>(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        if User::find_registered_user(&connection, &user_id)?.is_none() {
+            Err(ApiError::not_found(format!("The user {} was not found on this server", user_id)))?;
+        }
+
+        let access_tokens = AccessToken::find_valid_by_uid(&connection, &user_id)?;
+        let user_ips = UserIp::find_by_access_tokens(&connection, &access_tokens)?;
+
+        let mut devices: Vec<Device> = Vec::new();
+
+        for access_token in access_tokens {
+            if devices.iter().any(|device| device.device_id == access_token.device_id) {
+                continue;
+            }
+
+            // Clients are ordered by the time they were last seen, most recent first.
+            let last_seen = user_ips.iter().find(|user_ip| user_ip.device_id == access_token.device_id);
+
+            devices.push(Device {
+                last_seen_ip: last_seen.map(|user_ip| user_ip.ip.clone()),
+                last_seen_user_agent: last_seen.map(|user_ip| user_ip.user_agent.clone()),
+                last_seen_ts: match last_seen {
+                    Some(user_ip) => Some(milliseconds_since_epoch(user_ip.last_seen)?),
+                    None => None,
+                },
+                device_id: access_token.device_id,
+                user_id: access_token.user_id,
+            });
+        }
+
+        let response = GetDevicesResponse {
+            devices: devices,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The POST `/users/:user_id/delete_devices` endpoint.
+pub struct DeleteDevices;
+
+#[derive(Clone, Debug, Deserialize)]
+struct DeleteDevicesRequest {
+    /// The IDs of the devices to log out.
+    devices: Vec<String>,
+}
+
+middleware_chain!(DeleteDevices, [JsonRequest, AccessTokenAuth, AdminAuth, UserIdParam]);
+
+impl Handler for DeleteDevices {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let delete_devices_request = match request.get::<bodyparser::Struct<DeleteDevicesRequest>>() {
+            Ok(Some(delete_devices_request)) => delete_devices_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let user_id = extension::<UserIdParam>(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        let revoked = AccessToken::revoke_by_device_ids(
+            &connection,
+            &user_id,
+            &delete_devices_request.devices,
+        )?;
+
+        AccessTokenCache::from_request(request)?.invalidate_user(&user_id);
+
+        info!("Revoked {} access tokens of {} through the admin API.", revoked, user_id);
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::headers::{ContentType, Headers, UserAgent};
+    use iron::method::Method;
+    use iron::status::Status;
+
+    use test::{Response, Test};
+
+    fn get_with_user_agent(test: &Test, path: &str, user_agent: &str) -> Response {
+        let mut headers = Headers::new();
+
+        headers.set(ContentType::json());
+        headers.set(UserAgent(user_agent.to_string()));
+
+        test.request_with_headers(Method::Get, path, "", headers)
+    }
+
+    #[test]
+    fn list_devices() {
+        let test = Test::new();
+        let admin = test.create_admin();
+        let carl = test.create_user();
+
+        let phone_token = test.login_device(&carl, "PHONE");
+        let laptop_token = test.login_device(&carl, "LAPTOP");
+
+        let pushers_path = "/_matrix/client/r0/pushers?access_token=";
+        assert!(get_with_user_agent(&test, &format!("{}{}", pushers_path, phone_token), "Phone/1.0").status.is_success());
+        assert!(get_with_user_agent(&test, &format!("{}{}", pushers_path, laptop_token), "Laptop/2.0").status.is_success());
+
+        let response = test.get(&format!(
+            "/_synapse/admin/v1/users/{}/devices?access_token={}",
+            carl.id,
+            admin.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let devices = response.json().get("devices").unwrap().as_array().unwrap();
+        let device = |device_id: &str| {
+            devices.iter()
+                .find(|device| device.get("device_id").unwrap().as_str().unwrap() == device_id)
+                .unwrap()
+        };
+
+        assert_eq!(device("PHONE").get("last_seen_user_agent").unwrap().as_str().unwrap(), "Phone/1.0");
+        assert_eq!(device("PHONE").get("last_seen_ip").unwrap().as_str().unwrap(), "127.0.0.1");
+        assert!(device("PHONE").get("last_seen_ts").unwrap().is_u64());
+        assert_eq!(device("LAPTOP").get("last_seen_user_agent").unwrap().as_str().unwrap(), "Laptop/2.0");
+    }
+
+    #[test]
+    fn delete_devices() {
+        let test = Test::new();
+        let admin = test.create_admin();
+        let carl = test.create_user();
+
+        let phone_token = test.login_device(&carl, "PHONE");
+        let laptop_token = test.login_device(&carl, "LAPTOP");
+
+        let response = test.post(
+            &format!("/_synapse/admin/v1/users/{}/delete_devices?access_token={}", carl.id, admin.token),
+            r#"{"devices": ["PHONE"]}"#,
+        );
+        test.check_empty_response(response);
+
+        let response = test.get(&format!("/_matrix/client/r0/pushers?access_token={}", phone_token));
+        assert_eq!(response.status, Status::Unauthorized);
+
+        let response = test.get(&format!("/_matrix/client/r0/pushers?access_token={}", laptop_token));
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_synapse/admin/v1/users/{}/devices?access_token={}",
+            carl.id,
+            admin.token
+        ));
+        let devices = response.json().get("devices").unwrap().as_array().unwrap();
+        assert!(devices.iter().all(|device| device.get("device_id").unwrap().as_str().unwrap() != "PHONE"));
+    }
+
+    #[test]
+    fn non_admins_cannot_inspect_devices() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let response = test.get(&format!(
+            "/_synapse/admin/v1/users/{}/devices?access_token={}",
+            carl.id,
+            carl.token
+        ));
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.post(
+            &format!("/_synapse/admin/v1/users/{}/delete_devices?access_token={}", carl.id, carl.token),
+            r#"{"devices": []}"#,
+        );
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
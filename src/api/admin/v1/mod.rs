@@ -0,0 +1,15 @@
+//! API endpoints for version 1 of the admin API.
+
+pub use self::devices::{DeleteDevices, GetDevices};
+pub use self::purge_history::PurgeHistory;
+pub use self::rejected_events::GetRejectedEvents;
+pub use self::rooms::{ForceJoin, RemoveUser};
+pub use self::statistics::{GetMetrics, GetMonthlyActiveUsers};
+pub use self::whois::Whois;
+
+mod devices;
+mod purge_history;
+mod rejected_events;
+mod rooms;
+mod statistics;
+mod whois;
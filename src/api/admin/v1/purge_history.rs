@@ -0,0 +1,121 @@
+//! Endpoints for purging room history.
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use bodyparser;
+use diesel::Connection;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, AdminAuth, JsonRequest, MiddlewareChain, RoomIdParam};
+use models::event::Event;
+use models::room::Room;
+use modifier::SerializableResponse;
+use request_ext::extension;
+
+/// The POST `/purge_history/:room_id` endpoint.
+///
+/// Purges a room's events regardless of its retention policy, with the same exceptions as the
+/// scheduled purge.
+pub struct PurgeHistory;
+
+#[derive(Clone, Debug, Deserialize)]
+struct PurgeHistoryRequest {
+    /// Events created before this time, in milliseconds since the Unix epoch, are purged.
+    purge_up_to_ts: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct PurgeHistoryResponse {
+    /// The number of purged events.
+    purged_events: usize,
+}
+
+middleware_chain!(PurgeHistory, [JsonRequest, AccessTokenAuth, AdminAuth, RoomIdParam]);
+
+impl Handler for PurgeHistory {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let purge_history_request = match request.get::<bodyparser::Struct<PurgeHistoryRequest>>() {
+            Ok(Some(purge_history_request)) => purge_history_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let room_id = extension::<RoomIdParam>(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        if Room::find(&connection, &room_id)?.is_none() {
+            Err(ApiError::not_found(format!("The room {} was not found on this server", room_id)))?;
+        }
+
+        let before = UNIX_EPOCH + Duration::from_millis(purge_history_request.purge_up_to_ts);
+
+        let purged_events = connection.transaction::<usize, ApiError, _>(|| {
+            Event::purge_room_history(&connection, &room_id, before)
+        }).map_err(ApiError::from)?;
+
+        info!("Purged {} events of room {} through the admin API.", purged_events, room_id);
+
+        let response = PurgeHistoryResponse {
+            purged_events: purged_events,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    #[test]
+    fn purge_history() {
+        let test = Test::new();
+        let admin = test.create_admin();
+        let carl = test.create_user();
+        let room_id = test.create_room(&carl.token);
+
+        test.send_message(&carl.token, &room_id, "Hi", 1);
+        test.send_message(&carl.token, &room_id, "Hi again", 2);
+
+        let path = format!("/_synapse/admin/v1/purge_history/{}?access_token={}", room_id, admin.token);
+
+        // Nothing was created before the Unix epoch.
+        let response = test.post(&path, r#"{"purge_up_to_ts": 0}"#);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("purged_events").unwrap().as_u64().unwrap(), 0);
+
+        let response = test.post(&path, r#"{"purge_up_to_ts": 99999999999999}"#);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("purged_events").unwrap().as_u64().unwrap(), 1);
+    }
+
+    #[test]
+    fn purge_history_requires_admin() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = test.create_room(&carl.token);
+
+        let response = test.post(
+            &format!("/_synapse/admin/v1/purge_history/{}?access_token={}", room_id, carl.token),
+            r#"{"purge_up_to_ts": 0}"#,
+        );
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn purge_history_of_unknown_room() {
+        let test = Test::new();
+        let admin = test.create_admin();
+
+        let response = test.post(
+            &format!("/_synapse/admin/v1/purge_history/!nope:ruma.test?access_token={}", admin.token),
+            r#"{"purge_up_to_ts": 0}"#,
+        );
+        assert_eq!(response.status, Status::NotFound);
+    }
+}
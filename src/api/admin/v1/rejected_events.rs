@@ -0,0 +1,147 @@
+//! Endpoints for debugging events rejected from other servers.
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+use serde_json::{Value, from_str};
+
+use api::r0::milliseconds_since_epoch;
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, AdminAuth, MiddlewareChain};
+use models::rejected_event::RejectedEvent;
+use modifier::SerializableResponse;
+use query_params;
+
+/// The default number of rejected events listed.
+const DEFAULT_LIMIT: u64 = 50;
+
+/// The maximum number of rejected events listed.
+const MAX_LIMIT: u64 = 1000;
+
+/// The GET `/rejected_events` endpoint.
+///
+/// Lists the most recently rejected events, newest first.
+pub struct GetRejectedEvents;
+
+#[derive(Debug, Serialize)]
+struct GetRejectedEventsResponse {
+    rejected_events: Vec<RejectedEventInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct RejectedEventInfo {
+    /// The ID of the event, if it had one.
+    event_id: Option<String>,
+    /// The room of the event, if it had one.
+    room_id: Option<String>,
+    /// The server the event was received from.
+    origin: String,
+    /// The event as it was received.
+    event: Value,
+    /// Why the event was rejected.
+    reason: String,
+    /// The time the event was rejected, in milliseconds since the Unix epoch.
+    rejected_at: u64,
+}
+
+middleware_chain!(GetRejectedEvents, [AccessTokenAuth, AdminAuth]);
+
+impl Handler for GetRejectedEvents {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let limit = query_params::get_u64(request, "limit", DEFAULT_LIMIT, MAX_LIMIT)?;
+
+        let connection = DB::from_request(request)?;
+
+        let rejected_events = RejectedEvent::find_recent(&connection, limit as i64)?
+            .into_iter()
+            .map(|rejected_event| {
+                Ok(RejectedEventInfo {
+                    event: from_str(&rejected_event.event_json).map_err(ApiError::from)?,
+                    rejected_at: milliseconds_since_epoch(rejected_event.rejected_at)?,
+                    event_id: rejected_event.event_id,
+                    room_id: rejected_event.room_id,
+                    origin: rejected_event.origin,
+                    reason: rejected_event.reason,
+                })
+            })
+            .collect::<Result<Vec<RejectedEventInfo>, ApiError>>()?;
+
+        let response = GetRejectedEventsResponse {
+            rejected_events: rejected_events,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::method::Method;
+    use iron::status::Status;
+
+    use test::Test;
+
+    fn send_forged_pdu(test: &Test, transaction_id: u64, event_id: &str) {
+        let response = test.federation_request(
+            Method::Put,
+            &format!("/_matrix/federation/v1/send/{}", transaction_id),
+            &format!(
+                r#"{{"pdus": [{{
+                    "event_id": "{}",
+                    "room_id": "!room:ruma.test",
+                    "sender": "@mallory:evil.test",
+                    "type": "m.room.message",
+                    "content": {{}}
+                }}]}}"#,
+                event_id
+            ),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn list_rejected_events() {
+        let test = Test::new();
+        let admin = test.create_admin();
+
+        send_forged_pdu(&test, 1, "$first:ruma.test");
+        send_forged_pdu(&test, 2, "$second:ruma.test");
+
+        let response = test.get(&format!(
+            "/_synapse/admin/v1/rejected_events?access_token={}",
+            admin.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let rejected_events = response.json().get("rejected_events").unwrap().as_array().unwrap();
+        assert_eq!(rejected_events.len(), 2);
+
+        let latest = &rejected_events[0];
+        assert_eq!(latest.get("event_id").unwrap().as_str().unwrap(), "$second:ruma.test");
+        assert_eq!(latest.get("origin").unwrap().as_str().unwrap(), "ruma.test");
+        assert!(latest.get("reason").unwrap().as_str().unwrap().contains("@mallory:evil.test"));
+        assert_eq!(
+            latest.pointer("/event/sender").unwrap().as_str().unwrap(),
+            "@mallory:evil.test"
+        );
+
+        let response = test.get(&format!(
+            "/_synapse/admin/v1/rejected_events?limit=1&access_token={}",
+            admin.token
+        ));
+        assert_eq!(response.json().get("rejected_events").unwrap().as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn listing_rejected_events_requires_admin() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let response = test.get(&format!(
+            "/_synapse/admin/v1/rejected_events?access_token={}",
+            carl.token
+        ));
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
@@ -0,0 +1,281 @@
+//! Endpoints for moderating the memberships of rooms.
+
+use bodyparser;
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::{RoomId, UserId};
+
+use clock;
+use config::Config;
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, AdminAuth, JsonRequest, MiddlewareChain, RoomIdParam};
+use models::room::Room;
+use models::room_membership::{RoomMembership, RoomMembershipOptions};
+use models::user::User;
+use modifier::{EmptyResponse, SerializableResponse};
+use request_ext::{authed_user, extension};
+use state_cache::StateCache;
+
+/// The POST `/join/:room_id` endpoint.
+///
+/// Joins a local user to a room, even if the room is invite-only or the user was banned.
+pub struct ForceJoin;
+
+#[derive(Clone, Debug, Deserialize)]
+struct ForceJoinRequest {
+    /// The local user to join to the room.
+    user_id: UserId,
+}
+
+#[derive(Debug, Serialize)]
+struct ForceJoinResponse {
+    /// The joined room.
+    room_id: RoomId,
+}
+
+middleware_chain!(ForceJoin, [JsonRequest, AccessTokenAuth, AdminAuth, RoomIdParam]);
+
+impl Handler for ForceJoin {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user_id = match request.get::<bodyparser::Struct<ForceJoinRequest>>() {
+            Ok(Some(force_join_request)) => force_join_request.user_id,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let admin = authed_user(request)?;
+        let room_id = extension::<RoomIdParam>(request)?;
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
+
+        find_room_and_local_user(&connection, &config, &room_id, &user_id)?;
+
+        let room_membership_options = RoomMembershipOptions {
+            room_id: room_id,
+            user_id: user_id,
+            sender: admin.id,
+            membership: "join".to_string(),
+        };
+
+        let room_membership = RoomMembership::force_upsert(
+            &connection,
+            &state_cache,
+            &*clock,
+            &config,
+            room_membership_options,
+        )?;
+
+        info!(
+            "Administrator {} joined {} to room {}.",
+            room_membership.sender,
+            room_membership.user_id,
+            room_membership.room_id
+        );
+
+        let response = ForceJoinResponse {
+            room_id: room_membership.room_id,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The POST `/rooms/:room_id/remove_user` endpoint.
+///
+/// Kicks or bans a local user from a room, regardless of the power levels in the room.
+pub struct RemoveUser;
+
+#[derive(Clone, Debug, Deserialize)]
+struct RemoveUserRequest {
+    /// The local user to remove from the room.
+    user_id: UserId,
+    /// Whether the user should be banned rather than kicked.
+    #[serde(default)]
+    ban: bool,
+}
+
+middleware_chain!(RemoveUser, [JsonRequest, AccessTokenAuth, AdminAuth, RoomIdParam]);
+
+impl Handler for RemoveUser {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let remove_user_request = match request.get::<bodyparser::Struct<RemoveUserRequest>>() {
+            Ok(Some(remove_user_request)) => remove_user_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let admin = authed_user(request)?;
+        let room_id = extension::<RoomIdParam>(request)?;
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
+
+        let user_id = remove_user_request.user_id;
+
+        find_room_and_local_user(&connection, &config, &room_id, &user_id)?;
+
+        let membership = if remove_user_request.ban {
+            "ban"
+        } else {
+            match RoomMembership::find(&connection, &room_id, &user_id)? {
+                Some(ref membership) if membership.membership == "join" || membership.membership == "invite" => {
+                    "leave"
+                }
+                _ => Err(ApiError::not_found(format!("The user {} is not in the room {}", user_id, room_id)))?,
+            }
+        };
+
+        let room_membership_options = RoomMembershipOptions {
+            room_id: room_id,
+            user_id: user_id,
+            sender: admin.id,
+            membership: membership.to_string(),
+        };
+
+        let room_membership = RoomMembership::force_upsert(
+            &connection,
+            &state_cache,
+            &*clock,
+            &config,
+            room_membership_options,
+        )?;
+
+        info!(
+            "Administrator {} changed the membership of {} in room {} to {}.",
+            room_membership.sender,
+            room_membership.user_id,
+            room_membership.room_id,
+            room_membership.membership
+        );
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// Ensure the room exists and the user is registered on this server.
+fn find_room_and_local_user(
+    connection: &PgConnection,
+    config: &Config,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> Result<(), ApiError> {
+    if Room::find(connection, room_id)?.is_none() {
+        return Err(ApiError::not_found(format!("The room {} was not found on this server", room_id)));
+    }
+
+    if user_id.hostname().to_string() != config.domain {
+        return Err(ApiError::invalid_param("user_id", "Only local users can be moderated."));
+    }
+
+    if User::find_registered_user(connection, user_id)?.is_none() {
+        return Err(ApiError::not_found(format!("The user {} was not found on this server", user_id)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use iron::status::Status;
+    use ruma_identifiers::{RoomId, UserId};
+
+    use models::room_membership::RoomMembership;
+    use test::Test;
+
+    #[test]
+    fn force_join_invite_only_room() {
+        let test = Test::new();
+        let admin = test.create_admin();
+        let (_, room_id) = test.initial_fixtures(r#"{"visibility": "private"}"#);
+        let alice = test.create_user();
+
+        assert_eq!(test.join_room(&alice.token, &room_id).status, Status::Forbidden);
+
+        let response = test.post(
+            &format!("/_synapse/admin/v1/join/{}?access_token={}", room_id, admin.token),
+            &format!(r#"{{"user_id":"{}"}}"#, alice.id),
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("room_id").unwrap().as_str().unwrap(), room_id);
+
+        {
+            let connection = test.connection();
+            let membership = RoomMembership::find(
+                &connection,
+                &RoomId::try_from(room_id.as_ref()).unwrap(),
+                &UserId::try_from(alice.id.as_ref()).unwrap(),
+            ).unwrap().unwrap();
+
+            assert_eq!(membership.membership, "join");
+            assert_eq!(membership.sender.to_string(), admin.id);
+        }
+
+        assert_eq!(test.send_message(&alice.token, &room_id, "Hi", 1).status, Status::Ok);
+    }
+
+    #[test]
+    fn remove_room_admin() {
+        let test = Test::new();
+        let admin = test.create_admin();
+        let carl = test.create_user();
+        let room_id = test.create_room(&carl.token);
+
+        assert_eq!(test.send_message(&carl.token, &room_id, "Hi", 1).status, Status::Ok);
+
+        let remove_user_path = format!(
+            "/_synapse/admin/v1/rooms/{}/remove_user?access_token={}",
+            room_id,
+            admin.token
+        );
+
+        let response = test.post(&remove_user_path, &format!(r#"{{"user_id":"{}"}}"#, carl.id));
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.send_message(&carl.token, &room_id, "Hi", 2);
+        assert_eq!(response.status, Status::Forbidden);
+
+        // Carl is no longer in the room, so the user can only be banned now.
+        let response = test.post(&remove_user_path, &format!(r#"{{"user_id":"{}"}}"#, carl.id));
+        assert_eq!(response.status, Status::NotFound);
+
+        let response = test.post(&remove_user_path, &format!(r#"{{"user_id":"{}","ban":true}}"#, carl.id));
+        assert_eq!(response.status, Status::Ok);
+
+        let connection = test.connection();
+        let membership = RoomMembership::find(
+            &connection,
+            &RoomId::try_from(room_id.as_ref()).unwrap(),
+            &UserId::try_from(carl.id.as_ref()).unwrap(),
+        ).unwrap().unwrap();
+
+        assert_eq!(membership.membership, "ban");
+        assert_eq!(membership.sender.to_string(), admin.id);
+    }
+
+    #[test]
+    fn moderation_requires_admin() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let alice = test.create_user();
+        let room_id = test.create_room(&carl.token);
+
+        let response = test.post(
+            &format!("/_synapse/admin/v1/join/{}?access_token={}", room_id, alice.token),
+            &format!(r#"{{"user_id":"{}"}}"#, alice.id),
+        );
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.post(
+            &format!("/_synapse/admin/v1/rooms/{}/remove_user?access_token={}", room_id, alice.token),
+            &format!(r#"{{"user_id":"{}"}}"#, carl.id),
+        );
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
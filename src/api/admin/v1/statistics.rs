@@ -0,0 +1,169 @@
+//! Endpoints for following the growth of the server.
+
+use std::time::Duration;
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::status::Status;
+
+use access_token_cache::AccessTokenCache;
+use api::r0::milliseconds_since_epoch;
+use clock;
+use db::DB;
+use metrics::{LiveMetric, prometheus_text};
+use middleware::{AccessTokenAuth, AdminAuth, MiddlewareChain};
+use models::admin_metric::{AdminMetric, MONTHLY_ACTIVE_USERS};
+use modifier::SerializableResponse;
+use query_params;
+use shutdown::Shutdown;
+
+/// The default number of days the monthly active users are listed for.
+const DEFAULT_DAYS: u64 = 30;
+
+/// The maximum number of days the monthly active users are listed for.
+const MAX_DAYS: u64 = 366;
+
+/// The GET `/statistics/users/monthly_active` endpoint.
+///
+/// Lists the number of monthly active users on each of the last days, oldest first.
+pub struct GetMonthlyActiveUsers;
+
+#[derive(Debug, Serialize)]
+struct GetMonthlyActiveUsersResponse {
+    monthly_active_users: Vec<DailyCount>,
+}
+
+#[derive(Debug, Serialize)]
+struct DailyCount {
+    /// The start of the day in UTC, in milliseconds since the Unix epoch.
+    ts: u64,
+    /// The number of monthly active users at the end of the day.
+    count: i64,
+}
+
+middleware_chain!(GetMonthlyActiveUsers, [AccessTokenAuth, AdminAuth]);
+
+impl Handler for GetMonthlyActiveUsers {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let days = query_params::get_u64(request, "days", DEFAULT_DAYS, MAX_DAYS)?;
+
+        let connection = DB::from_request(request)?;
+        let clock = clock::from_request(request)?;
+
+        let since = clock.now() - Duration::from_secs(days * 24 * 60 * 60);
+        let daily_counts = AdminMetric::find_daily(&connection, MONTHLY_ACTIVE_USERS, since)?;
+
+        let monthly_active_users = daily_counts
+            .into_iter()
+            .map(|(day, count)| {
+                Ok(DailyCount {
+                    ts: milliseconds_since_epoch(day)?,
+                    count: count,
+                })
+            })
+            .collect::<Result<Vec<DailyCount>, _>>()?;
+
+        let response = GetMonthlyActiveUsersResponse {
+            monthly_active_users: monthly_active_users,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/metrics` endpoint.
+///
+/// Exposes the latest snapshot of the server metrics in the Prometheus text format, along with the
+/// hits and misses of the access token cache as counters and the number of busy worker threads as
+/// a gauge.
+pub struct GetMetrics;
+
+middleware_chain!(GetMetrics, [AccessTokenAuth, AdminAuth]);
+
+impl Handler for GetMetrics {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let connection = DB::from_request(request)?;
+
+        let metrics = AdminMetric::find_latest(&connection)?;
+
+        let access_token_cache = AccessTokenCache::from_request(request)?;
+        let shutdown = Shutdown::from_request(request)?;
+
+        let live_metrics = [
+            LiveMetric::Counter("access_token_cache_hits_total", access_token_cache.hits() as u64),
+            LiveMetric::Counter(
+                "access_token_cache_misses_total",
+                access_token_cache.misses() as u64,
+            ),
+            LiveMetric::Gauge("busy_workers", shutdown.busy_workers() as i64),
+        ];
+
+        let mut response = Response::with((Status::Ok, prometheus_text(&metrics, &live_metrics)));
+        response.headers.set(ContentType::plaintext());
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use metrics::collect_metrics;
+    use test::Test;
+
+    #[test]
+    fn monthly_active_users_are_listed_by_day() {
+        let test = Test::new();
+        let admin = test.create_admin();
+        let alice = test.create_user();
+        test.create_room(&alice.token);
+
+        collect_metrics(&test.connection(), test.clock()).unwrap();
+
+        let response = test.get(&format!(
+            "/_matrix/admin/v1/statistics/users/monthly_active?access_token={}",
+            admin.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let days = response.json().get("monthly_active_users").unwrap().as_array().unwrap();
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].get("count").unwrap().as_i64().unwrap(), 1);
+        assert_eq!(days[0].get("ts").unwrap().as_u64().unwrap() % (24 * 60 * 60 * 1000), 0);
+    }
+
+    #[test]
+    fn metrics_are_exposed_for_prometheus() {
+        let test = Test::new();
+        let admin = test.create_admin();
+        test.create_room(&admin.token);
+
+        collect_metrics(&test.connection(), test.clock()).unwrap();
+
+        let response = test.get(&format!("/_matrix/admin/v1/metrics?access_token={}", admin.token));
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.body.contains("# TYPE ruma_total_rooms gauge\nruma_total_rooms 1\n"));
+        assert!(response.body.contains("ruma_total_users 1\n"));
+        assert!(response.body.contains("# TYPE ruma_access_token_cache_hits_total counter\n"));
+        assert!(response.body.contains("# TYPE ruma_access_token_cache_misses_total counter\n"));
+
+        // The metrics request itself keeps a worker busy.
+        assert!(response.body.contains("# TYPE ruma_busy_workers gauge\nruma_busy_workers 1\n"));
+    }
+
+    #[test]
+    fn statistics_require_admin() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/admin/v1/statistics/users/monthly_active?access_token={}",
+            carl.token
+        ));
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.get(&format!("/_matrix/admin/v1/metrics?access_token={}", carl.token));
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
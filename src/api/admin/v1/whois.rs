@@ -0,0 +1,234 @@
+//! Endpoints for information about the clients of users.
+
+use std::collections::HashMap;
+
+use iron::{Chain, Handler, IronError, IronResult, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::UserId;
+
+use api::r0::milliseconds_since_epoch;
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, MiddlewareChain, UserIdParam};
+use models::access_token::AccessToken;
+use models::user::User;
+use models::user_ip::UserIp;
+use modifier::SerializableResponse;
+use request_ext::{authed_user, extension};
+
+/// The GET `/whois/:user_id` endpoint, also mounted as `/admin/whois/:user_id` in the client API.
+///
+/// Server admins can look up any user, other users only themselves.
+pub struct Whois;
+
+#[derive(Debug, Serialize)]
+struct WhoisResponse {
+    user_id: UserId,
+    /// The user's devices, by device ID.
+    devices: HashMap<String, DeviceInfo>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct DeviceInfo {
+    /// One session for each access token of the device.
+    sessions: Vec<SessionInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct SessionInfo {
+    /// The clients that used the session's access token, most recently seen first.
+    connections: Vec<ConnectionInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct ConnectionInfo {
+    /// The IP address of the client.
+    ip: String,
+    /// The last time the client was seen, in milliseconds since the Unix epoch.
+    last_seen: u64,
+    /// The user agent of the client.
+    user_agent: String,
+}
+
+middleware_chain!(Whois, [AccessTokenAuth, UserIdParam]);
+
+impl Handler for Whois {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = authed_user(request)?;
+
+        let user_id = extension::<UserIdParam>(request)?;
+
+        if !user.admin && user.id != user_id {
+            let error = ApiError::unauthorized(
+                "Only server admins can look up other users.".to_string()
+            );
+
+            return Err(IronError::from(error));
+        }
+
+        let connection = DB::from_request(request)?;
+
+        if User::find_registered_user(&connection, &user_id)?.is_none() {
+            Err(ApiError::not_found(format!("The user {} was not found on this server", user_id)))?;
+        }
+
+        let access_tokens = AccessToken::find_valid_by_uid(&connection, &user_id)?;
+        let user_ips = UserIp::find_by_access_tokens(&connection, &access_tokens)?;
+
+        let mut devices: HashMap<String, DeviceInfo> = HashMap::new();
+
+        for access_token in access_tokens {
+            let connections = user_ips.iter()
+                .filter(|user_ip| user_ip.access_token_id == access_token.id)
+                .map(|user_ip| {
+                    Ok(ConnectionInfo {
+                        ip: user_ip.ip.clone(),
+                        last_seen: milliseconds_since_epoch(user_ip.last_seen)?,
+                        user_agent: user_ip.user_agent.clone(),
+                    })
+                })
+                .collect::<Result<Vec<ConnectionInfo>, ApiError>>()?;
+
+            devices.entry(access_token.device_id)
+                .or_insert_with(DeviceInfo::default)
+                .sessions
+                .push(SessionInfo { connections: connections });
+        }
+
+        let response = WhoisResponse {
+            user_id: user_id,
+            devices: devices,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::headers::{ContentType, Headers, UserAgent};
+    use iron::method::Method;
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::Test;
+
+    fn get_pushers_with_user_agent(test: &Test, access_token: &str, user_agent: &str) {
+        let mut headers = Headers::new();
+
+        headers.set(ContentType::json());
+        headers.set(UserAgent(user_agent.to_string()));
+
+        let path = format!("/_matrix/client/r0/pushers?access_token={}", access_token);
+
+        assert!(test.request_with_headers(Method::Get, &path, "", headers).status.is_success());
+    }
+
+    fn user_agents(whois: &Value, device_id: &str) -> Vec<String> {
+        whois.pointer(&format!("/devices/{}/sessions", device_id))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|session| session.get("connections").unwrap().as_array().unwrap().iter())
+            .map(|connection| connection.get("user_agent").unwrap().as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn whois() {
+        let test = Test::new();
+        let admin = test.create_admin();
+        let carl = test.create_user();
+
+        let phone_token = test.login_device(&carl, "PHONE");
+        get_pushers_with_user_agent(&test, &phone_token, "Phone/1.0");
+        get_pushers_with_user_agent(&test, &phone_token, "Phone/1.1");
+        get_pushers_with_user_agent(&test, &carl.token, "Desktop/3.0");
+
+        let response = test.get(&format!("/_synapse/admin/v1/whois/{}?access_token={}", carl.id, admin.token));
+        assert_eq!(response.status, Status::Ok);
+
+        let whois = response.json();
+        assert_eq!(whois.get("user_id").unwrap().as_str().unwrap(), carl.id);
+
+        let mut phone_user_agents = user_agents(whois, "PHONE");
+        phone_user_agents.sort();
+        assert_eq!(phone_user_agents, vec!["Phone/1.0", "Phone/1.1"]);
+
+        let devices = whois.get("devices").unwrap().as_object().unwrap();
+        assert_eq!(devices.len(), 2);
+        assert!(devices.keys().any(|device_id| {
+            user_agents(whois, device_id) == vec!["Desktop/3.0".to_string()]
+        }));
+    }
+
+    #[test]
+    fn whois_without_ip_collection() {
+        let test = Test::with_config(|config| config.collect_user_ips = false);
+        let admin = test.create_admin();
+        let carl = test.create_user();
+
+        let phone_token = test.login_device(&carl, "PHONE");
+        get_pushers_with_user_agent(&test, &phone_token, "Phone/1.0");
+
+        let response = test.get(&format!("/_synapse/admin/v1/whois/{}?access_token={}", carl.id, admin.token));
+        assert_eq!(response.status, Status::Ok);
+        assert!(user_agents(response.json(), "PHONE").is_empty());
+    }
+
+    #[test]
+    fn whois_unknown_user() {
+        let test = Test::new();
+        let admin = test.create_admin();
+
+        let response = test.get(&format!(
+            "/_synapse/admin/v1/whois/@nobody:ruma.test?access_token={}",
+            admin.token
+        ));
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn whois_via_client_api() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        get_pushers_with_user_agent(&test, &carl.token, "Desktop/3.0");
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/whois/{}?access_token={}",
+            carl.id,
+            carl.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let whois = response.json();
+        let devices = whois.get("devices").unwrap().as_object().unwrap();
+        assert_eq!(devices.len(), 1);
+        assert!(devices.keys().all(|device_id| {
+            user_agents(whois, device_id) == vec!["Desktop/3.0".to_string()]
+        }));
+    }
+
+    #[test]
+    fn whois_of_other_user_requires_admin() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let carl = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/whois/{}?access_token={}",
+            carl.id,
+            alice.token
+        ));
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.get(&format!(
+            "/_synapse/admin/v1/whois/{}?access_token={}",
+            carl.id,
+            alice.token
+        ));
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
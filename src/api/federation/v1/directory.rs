@@ -0,0 +1,149 @@
+//! Endpoints for directory queries of other servers.
+
+use std::collections::HashSet;
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::{RoomAliasId, RoomId};
+
+use config::Config;
+use db::DB;
+use error::ApiError;
+use federation::auth::Origin;
+use federation::directory::{MAX_ALIAS_RESOLUTION_DEPTH, find_room_aliases};
+use federation::sender::participating_servers;
+use identifiers;
+use middleware::{FederationAuth, MiddlewareChain};
+use models::room_alias::RoomAlias;
+use modifier::SerializableResponse;
+use query_params;
+use request_ext::extension;
+
+/// The GET `/query/directory` endpoint.
+///
+/// With a `room_alias`, resolves an alias of this server to its room. With a `room_id` instead,
+/// lists the aliases of the room known to this server, which is only answered for servers with
+/// members in the room. See `federation::directory`.
+pub struct QueryDirectory;
+
+#[derive(Debug, Serialize)]
+struct QueryDirectoryResponse {
+    /// The room ID associated with the room alias.
+    room_id: RoomId,
+    /// A list of servers that are aware of this room ID.
+    servers: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReverseQueryDirectoryResponse {
+    /// The aliases of the room.
+    aliases: Vec<RoomAliasId>,
+}
+
+middleware_chain!(QueryDirectory, [FederationAuth]);
+
+impl Handler for QueryDirectory {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        if let Some(room_alias) = query_params::get(request, "room_alias") {
+            let room_alias_id = identifiers::parse::<RoomAliasId>("room_alias", &room_alias)?;
+
+            if room_alias_id.hostname().to_string() != config.domain {
+                Err(ApiError::not_found("The alias is not an alias of this server.".to_string()))?;
+            }
+
+            let room_alias = RoomAlias::find_by_alias(&connection, &room_alias_id)?;
+
+            let response = QueryDirectoryResponse {
+                room_id: room_alias.room_id,
+                servers: room_alias.servers,
+            };
+
+            return Ok(Response::with((Status::Ok, SerializableResponse(response))));
+        }
+
+        let room_id = match query_params::get(request, "room_id") {
+            Some(room_id) => identifiers::parse::<RoomId>("room_id", &room_id)?,
+            None => Err(ApiError::missing_param("room_alias"))?,
+        };
+        let depth = query_params::get_u64(request, "depth", 1, MAX_ALIAS_RESOLUTION_DEPTH)?;
+
+        let origin = extension::<Origin>(request)?;
+
+        if !participating_servers(&connection, &room_id)?.contains(&origin) {
+            Err(ApiError::unauthorized("The server has no members in the room.".to_string()))?;
+        }
+
+        let mut skip = HashSet::new();
+        skip.insert(origin);
+
+        let response = ReverseQueryDirectoryResponse {
+            aliases: find_room_aliases(&connection, &config, &room_id, depth, &skip)?,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    #[test]
+    fn resolve_alias() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room_with_params(&alice.token, r#"{"room_alias_name": "lobby"}"#);
+
+        let response = test.federation_get(
+            "/_matrix/federation/v1/query/directory?room_alias=%23lobby%3Aruma.test"
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("room_id").unwrap().as_str().unwrap(), room_id);
+    }
+
+    #[test]
+    fn list_aliases_of_room() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room_with_params(&alice.token, r#"{"room_alias_name": "lobby"}"#);
+
+        let response = test.put(
+            &format!("/_matrix/client/r0/directory/room/hall?access_token={}", alice.token),
+            &format!(r#"{{"room_id": "{}"}}"#, room_id),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.federation_get(
+            &format!("/_matrix/federation/v1/query/directory?room_id={}&depth=1", room_id)
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let mut aliases: Vec<String> = response.json().get("aliases").unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|alias| alias.as_str().unwrap().to_string())
+            .collect();
+        aliases.sort();
+
+        assert_eq!(aliases, vec!["#hall:ruma.test", "#lobby:ruma.test"]);
+    }
+
+    #[test]
+    fn list_aliases_of_unknown_room() {
+        let test = Test::new();
+
+        let response = test.federation_get(
+            "/_matrix/federation/v1/query/directory?room_id=!nope:ruma.test"
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
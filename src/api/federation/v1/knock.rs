@@ -0,0 +1,415 @@
+//! Endpoints for users of other servers knocking on rooms of this server.
+//!
+//! Knocking asks the members of a room to be let in. The knock shows up as a membership event in
+//! the room, and its members accept it by inviting the user, or decline it by kicking them.
+
+use std::convert::TryInto;
+
+use bodyparser;
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use ruma_events::EventType;
+use ruma_events::room::join_rules::JoinRule;
+use ruma_events::stripped::StrippedState;
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde_json::Value;
+
+use clock;
+use config::Config;
+use db::DB;
+use error::ApiError;
+use federation::auth::Origin;
+use federation::membership::{MembershipEventTemplate, apply_membership_event, membership_event};
+use identifiers;
+use middleware::{FederationAuth, JsonRequest, MiddlewareChain, RoomIdParam, UserIdParam};
+use models::event::Event;
+use models::room::Room;
+use models::room_membership::RoomMembership;
+use models::room_state::RoomState;
+use modifier::SerializableResponse;
+use request_ext::{extension, path_param};
+use state_cache::StateCache;
+
+/// The state events shown to a user who knocks, so their client can tell what they knocked on.
+const KNOCK_ROOM_STATE_TYPES: [EventType; 6] = [
+    EventType::RoomCreate,
+    EventType::RoomJoinRules,
+    EventType::RoomName,
+    EventType::RoomAvatar,
+    EventType::RoomCanonicalAlias,
+    EventType::RoomTopic,
+];
+
+/// The GET `/make_knock/:room_id/:user_id` endpoint.
+///
+/// Returns a template of the knock event for a user of the requesting server, if the room's join
+/// rule is `knock` and the user isn't joined to, invited to or banned from the room.
+pub struct MakeKnock;
+
+#[derive(Debug, Serialize)]
+struct MakeKnockResponse {
+    /// The version of the room.
+    room_version: String,
+    /// The unsigned knock event.
+    event: MembershipEventTemplate,
+}
+
+middleware_chain!(MakeKnock, [FederationAuth, RoomIdParam, UserIdParam]);
+
+impl Handler for MakeKnock {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let origin = extension::<Origin>(request)?;
+        let room_id = extension::<RoomIdParam>(request)?;
+        let user_id = extension::<UserIdParam>(request)?;
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
+
+        let state = check_can_knock(&connection, &state_cache, &origin, &room_id, &user_id)?;
+
+        let response = MakeKnockResponse {
+            room_version: state.room_version()?,
+            event: MembershipEventTemplate::new(
+                &config.domain,
+                clock.now_ms(),
+                &room_id,
+                &user_id,
+                "knock",
+            ),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The PUT `/send_knock/:room_id/:event_id` endpoint.
+///
+/// Accepts a knock event signed by the server of the user who knocks, applies it to the room's
+/// state, and sends it on to the other servers in the room as that server signed it. Sending the
+/// same event again has no further effect.
+pub struct SendKnock;
+
+#[derive(Debug, Serialize)]
+struct SendKnockResponse {
+    /// A subset of the room's state describing the room.
+    knock_room_state: Vec<StrippedState>,
+}
+
+middleware_chain!(SendKnock, [JsonRequest, FederationAuth, RoomIdParam]);
+
+impl Handler for SendKnock {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let event = match request.get::<bodyparser::Json>() {
+            Ok(Some(Value::Object(event))) => event,
+            Ok(_) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let origin = extension::<Origin>(request)?;
+        let room_id = extension::<RoomIdParam>(request)?;
+        let event_id = path_param(request, "event_id")?;
+        let event_id = identifiers::parse::<EventId>("event_id", &event_id)?;
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
+
+        let knock_event = membership_event(&config, &origin, &room_id, &event_id, &event, "knock")?;
+
+        if Event::find(&connection, &event_id)?.is_some() {
+            debug!("Ignoring the knock event {} sent again by {}.", event_id, origin);
+
+            let state = RoomState::current(&connection, &state_cache, &room_id)?;
+
+            return Ok(send_knock_response(&state)?);
+        }
+
+        check_can_knock(&connection, &state_cache, &origin, &room_id, &knock_event.user_id)?;
+
+        apply_membership_event(
+            &connection,
+            &state_cache,
+            &*clock,
+            &config,
+            knock_event,
+            "knock",
+        )?;
+
+        let state = RoomState::current(&connection, &state_cache, &room_id)?;
+
+        Ok(send_knock_response(&state)?)
+    }
+}
+
+/// The response to `send_knock`, the room's state shown to the user who knocked.
+fn send_knock_response(state: &RoomState) -> Result<Response, ApiError> {
+    let mut knock_room_state = Vec::new();
+
+    for event_type in KNOCK_ROOM_STATE_TYPES.iter() {
+        if let Some(event) = state.get(event_type, "") {
+            let stripped_state: StrippedState = event.clone().try_into()?;
+
+            knock_room_state.push(stripped_state);
+        }
+    }
+
+    let response = SendKnockResponse { knock_room_state: knock_room_state };
+
+    Ok(Response::with((Status::Ok, SerializableResponse(response))))
+}
+
+/// Checks that the server `origin` may make `user_id` knock on the room: the user has to be one of
+/// its users, the room's join rule has to be `knock`, and the user must not be joined to, invited
+/// to or banned from the room.
+///
+/// Returns the current state of the room.
+fn check_can_knock(
+    connection: &PgConnection,
+    state_cache: &StateCache,
+    origin: &str,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> Result<RoomState, ApiError> {
+    if user_id.hostname().to_string() != origin {
+        Err(ApiError::unauthorized(format!("{} is not a user of {}.", user_id, origin)))?;
+    }
+
+    if Room::find(connection, room_id)?.is_none() {
+        Err(ApiError::not_found(format!("The room {} is unknown to this server.", room_id)))?;
+    }
+
+    let state = RoomState::current(connection, state_cache, room_id)?;
+
+    // `knock_restricted` isn't an allowed join rule on this server, so `knock` is the only one
+    // to check for.
+    if state.join_rule()? != Some(JoinRule::Knock) {
+        Err(ApiError::unauthorized(format!("The room {} does not allow knocking.", room_id)))?;
+    }
+
+    let membership = RoomMembership::find(connection, room_id, user_id)?
+        .map(|membership| membership.membership);
+
+    match membership.as_ref().map(|membership| &membership[..]) {
+        Some("join") => Err(ApiError::unauthorized(
+            format!("{} has already joined the room.", user_id)
+        )),
+        Some("invite") => Err(ApiError::unauthorized(
+            format!("{} is already invited to the room.", user_id)
+        )),
+        Some("ban") => Err(ApiError::unauthorized(
+            format!("{} is banned from the room.", user_id)
+        )),
+        _ => Ok(state),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::{ExecuteDsl, insert};
+    use iron::method::Method;
+    use iron::status::Status;
+    use ruma_identifiers::{EventId, RoomId, UserId};
+    use serde_json::{Map, Value, to_string};
+
+    use federation::sender::{SEND_EVENT_JOB, sign_pdu, signed_pdu};
+    use models::background_job::Job;
+    use models::event::Event;
+    use models::room_membership::NewRoomMembership;
+    use schema::room_memberships;
+    use test::{Test, TestUser, assert_matrix_error, json_at, signing_key};
+
+    const KNOCK_ROOM_OPTIONS: &'static str = r#"{
+        "initial_state": [{
+            "state_key": "",
+            "content": { "join_rule": "knock" },
+            "type": "m.room.join_rules"
+        }]
+    }"#;
+
+    /// Makes `bob` of b.ruma.test knock on the room of a.ruma.test with `make_knock` and
+    /// `send_knock`, and returns the ID of the knock event.
+    fn knock(a: &Test, room_id: &str, bob: &TestUser) -> String {
+        let response = a.federation_request_from(
+            "b.ruma.test",
+            Method::Get,
+            &format!("/_matrix/federation/v1/make_knock/{}/{}", room_id, bob.id),
+            "",
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(json_at(&response, "event.content.membership"), "knock");
+
+        let mut event: Map<String, Value> = response.json()
+            .get("event")
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .clone();
+        let event_id = "$knock:b.ruma.test".to_string();
+
+        event.insert("event_id".to_string(), Value::String(event_id.clone()));
+
+        let event = sign_pdu("b.ruma.test", &signing_key(), event).unwrap();
+
+        let response = a.federation_request_from(
+            "b.ruma.test",
+            Method::Put,
+            &format!("/_matrix/federation/v1/send_knock/{}/{}", room_id, event_id),
+            &to_string(&event).unwrap(),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let knock_room_state = response.json()
+            .get("knock_room_state")
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert!(knock_room_state.iter().any(|event| {
+            event.pointer("/content/join_rule").and_then(Value::as_str) == Some("knock")
+        }));
+
+        event_id
+    }
+
+    /// Returns the membership event of `user_id` in the room's timeline of the user's sync.
+    fn membership_in_sync(test: &Test, user: &TestUser, room_id: &str, user_id: &str) -> Value {
+        let response = user.sync_since(test, None);
+        let events = response.json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .clone();
+
+        events.into_iter()
+            .filter(|event| {
+                event.get("type").and_then(Value::as_str) == Some("m.room.member") &&
+                    event.get("state_key").and_then(Value::as_str) == Some(user_id)
+            })
+            .last()
+            .unwrap()
+    }
+
+    #[test]
+    fn knocks_can_be_accepted_with_an_invite() {
+        let (a, b) = Test::new_pair();
+        let alice = a.register("alice");
+        let bob = b.register("bob");
+
+        let room_id = alice.create_room(&a, KNOCK_ROOM_OPTIONS);
+        let event_id = knock(&a, &room_id, &bob);
+
+        let knock_event = membership_in_sync(&a, &alice, &room_id, &bob.id);
+        assert_eq!(knock_event.get("event_id").unwrap().as_str(), Some(&event_id[..]));
+        assert_eq!(knock_event.pointer("/content/membership").unwrap().as_str(), Some("knock"));
+
+        assert_eq!(alice.invite(&a, &room_id, &bob).status, Status::Ok);
+
+        let invite_event = membership_in_sync(&a, &alice, &room_id, &bob.id);
+        assert_eq!(invite_event.pointer("/content/membership").unwrap().as_str(), Some("invite"));
+
+        // Invited users don't need to knock anymore.
+        let response = a.federation_request_from(
+            "b.ruma.test",
+            Method::Get,
+            &format!("/_matrix/federation/v1/make_knock/{}/{}", room_id, bob.id),
+            "",
+        );
+        assert_matrix_error(&response, Status::Forbidden, "M_FORBIDDEN");
+    }
+
+    #[test]
+    fn knocks_can_be_declined_with_a_kick() {
+        let (a, b) = Test::new_pair();
+        let alice = a.register("alice");
+        let bob = b.register("bob");
+
+        let room_id = alice.create_room(&a, KNOCK_ROOM_OPTIONS);
+        knock(&a, &room_id, &bob);
+
+        let response = a.kick_from_room(&alice.token, &room_id, &bob.id, None);
+        assert_eq!(response.status, Status::Ok);
+
+        let leave_event = membership_in_sync(&a, &alice, &room_id, &bob.id);
+        assert_eq!(leave_event.pointer("/content/membership").unwrap().as_str(), Some("leave"));
+        assert_eq!(leave_event.get("sender").unwrap().as_str(), Some(&alice.id[..]));
+    }
+
+    #[test]
+    fn knocks_are_relayed_as_signed_by_the_knocking_server() {
+        let (a, b) = Test::new_pair();
+        let alice = a.register("alice");
+        let bob = b.register("bob");
+
+        let room_id = alice.create_room(&a, KNOCK_ROOM_OPTIONS);
+
+        let carl_id = UserId::try_from("@carl:c.ruma.test").unwrap();
+        insert(&NewRoomMembership {
+            event_id: EventId::new("c.ruma.test").unwrap(),
+            room_id: RoomId::try_from(&room_id[..]).unwrap(),
+            user_id: carl_id.clone(),
+            sender: carl_id,
+            membership: "join".to_string(),
+        }).into(room_memberships::table).execute(&*a.connection()).unwrap();
+
+        let event_id = knock(&a, &room_id, &bob);
+
+        let connection = a.connection();
+        let destinations: Vec<String> = Job::all(&connection).unwrap()
+            .into_iter()
+            .filter(|job| job.kind == SEND_EVENT_JOB)
+            .map(|job| job.payload().unwrap()["destination"].as_str().unwrap().to_string())
+            .collect();
+
+        // The knocking server isn't sent its own event back.
+        assert_eq!(destinations, vec!["c.ruma.test".to_string()]);
+
+        let event = Event::find(&connection, &EventId::try_from(&event_id[..]).unwrap())
+            .unwrap()
+            .unwrap();
+        let pdu = signed_pdu(&connection, "a.ruma.test", &signing_key(), &event).unwrap();
+
+        assert_eq!(pdu.get("event_id").unwrap().as_str(), Some(&event_id[..]));
+        assert!(pdu.pointer("/hashes/sha256").is_some());
+        assert!(pdu.pointer("/signatures/b.ruma.test").is_some());
+        assert!(pdu.pointer("/signatures/a.ruma.test").is_none());
+    }
+
+    #[test]
+    fn rooms_without_the_knock_join_rule_reject_knocks() {
+        let (a, b) = Test::new_pair();
+        let alice = a.register("alice");
+        let bob = b.register("bob");
+
+        let room_id = alice.create_room(&a, "{}");
+
+        let response = a.federation_request_from(
+            "b.ruma.test",
+            Method::Get,
+            &format!("/_matrix/federation/v1/make_knock/{}/{}", room_id, bob.id),
+            "",
+        );
+        assert_matrix_error(&response, Status::Forbidden, "M_FORBIDDEN");
+    }
+
+    #[test]
+    fn servers_can_only_make_their_own_users_knock() {
+        let (a, _b) = Test::new_pair();
+        let alice = a.register("alice");
+        let carl = a.register("carl");
+
+        let room_id = alice.create_room(&a, KNOCK_ROOM_OPTIONS);
+
+        let response = a.federation_request_from(
+            "b.ruma.test",
+            Method::Get,
+            &format!("/_matrix/federation/v1/make_knock/{}/{}", room_id, carl.id),
+            "",
+        );
+        assert_matrix_error(&response, Status::Forbidden, "M_FORBIDDEN");
+    }
+}
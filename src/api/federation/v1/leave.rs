@@ -0,0 +1,364 @@
+//! Endpoints for users of other servers leaving rooms of this server.
+//!
+//! Remote users can't join rooms of this server yet, so this is mostly how they reject invites.
+
+use bodyparser;
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde_json::{Map, Value};
+
+use clock;
+use config::Config;
+use db::DB;
+use error::ApiError;
+use federation::auth::Origin;
+use federation::membership::{MembershipEventTemplate, apply_membership_event, membership_event};
+use identifiers;
+use middleware::{FederationAuth, JsonRequest, MiddlewareChain, RoomIdParam, UserIdParam};
+use models::event::Event;
+use models::room::Room;
+use models::room_membership::RoomMembership;
+use models::room_state::RoomState;
+use modifier::SerializableResponse;
+use request_ext::{extension, path_param};
+use state_cache::StateCache;
+
+/// The GET `/make_leave/:room_id/:user_id` endpoint.
+///
+/// Returns a template of the leave event for a user of the requesting server who is joined to or
+/// invited to the room.
+pub struct MakeLeave;
+
+#[derive(Debug, Serialize)]
+struct MakeLeaveResponse {
+    /// The version of the room.
+    room_version: String,
+    /// The unsigned leave event.
+    event: MembershipEventTemplate,
+}
+
+middleware_chain!(MakeLeave, [FederationAuth, RoomIdParam, UserIdParam]);
+
+impl Handler for MakeLeave {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let origin = extension::<Origin>(request)?;
+        let room_id = extension::<RoomIdParam>(request)?;
+        let user_id = extension::<UserIdParam>(request)?;
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
+
+        check_can_leave(&connection, &origin, &room_id, &user_id)?;
+
+        let room_version = RoomState::current(&connection, &state_cache, &room_id)?
+            .room_version()?;
+
+        let response = MakeLeaveResponse {
+            room_version: room_version,
+            event: MembershipEventTemplate::new(
+                &config.domain,
+                clock.now_ms(),
+                &room_id,
+                &user_id,
+                "leave",
+            ),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The PUT `/send_leave/:room_id/:event_id` endpoint.
+///
+/// Accepts a leave event signed by the server of the user who leaves, applies it to the room's
+/// state, and sends it on to the other servers in the room. Sending the same event again has no
+/// further effect.
+pub struct SendLeave;
+
+middleware_chain!(SendLeave, [JsonRequest, FederationAuth, RoomIdParam]);
+
+impl Handler for SendLeave {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let event = match request.get::<bodyparser::Json>() {
+            Ok(Some(Value::Object(event))) => event,
+            Ok(_) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let origin = extension::<Origin>(request)?;
+        let room_id = extension::<RoomIdParam>(request)?;
+        let event_id = path_param(request, "event_id")?;
+        let event_id = identifiers::parse::<EventId>("event_id", &event_id)?;
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
+
+        let leave_event = membership_event(&config, &origin, &room_id, &event_id, &event, "leave")?;
+
+        if Event::find(&connection, &event_id)?.is_some() {
+            debug!("Ignoring the leave event {} sent again by {}.", event_id, origin);
+
+            return Ok(send_leave_response());
+        }
+
+        check_can_leave(&connection, &origin, &room_id, &leave_event.user_id)?;
+
+        apply_membership_event(
+            &connection,
+            &state_cache,
+            &*clock,
+            &config,
+            leave_event,
+            "leave",
+        )?;
+
+        Ok(send_leave_response())
+    }
+}
+
+/// The response to `send_leave`, `[200, {}]` in version 1 of the API.
+fn send_leave_response() -> Response {
+    let response: (u16, Map<String, Value>) = (200, Map::new());
+
+    Response::with((Status::Ok, SerializableResponse(response)))
+}
+
+/// Checks that the server `origin` may make `user_id` leave the room: the user has to be one of
+/// its users and be joined to or invited to a room of this server.
+fn check_can_leave(connection: &PgConnection, origin: &str, room_id: &RoomId, user_id: &UserId)
+-> Result<(), ApiError> {
+    if user_id.hostname().to_string() != origin {
+        Err(ApiError::unauthorized(format!("{} is not a user of {}.", user_id, origin)))?;
+    }
+
+    if Room::find(connection, room_id)?.is_none() {
+        Err(ApiError::not_found(format!("The room {} is unknown to this server.", room_id)))?;
+    }
+
+    match RoomMembership::find(connection, room_id, user_id)? {
+        Some(ref membership) if membership.membership == "join" ||
+            membership.membership == "invite" => Ok(()),
+        _ => Err(ApiError::unauthorized(
+            format!("{} is neither joined to nor invited to the room.", user_id)
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use iron::method::Method;
+    use iron::status::Status;
+    use ruma_identifiers::EventId;
+    use serde_json::{Map, Value, from_str, to_string};
+
+    use federation::sender::{SEND_EVENT_JOB, sign_pdu, signed_pdu};
+    use models::background_job::Job;
+    use models::event::Event;
+    use test::{Response, Test, assert_matrix_error, json_at, signing_key};
+
+    /// Makes the leave event of `user_id` with `make_leave` on `test` as `origin`, then fills in
+    /// the event ID and signs it as `origin`.
+    fn signed_leave_event(test: &Test, origin: &str, room_id: &str, user_id: &str)
+    -> (String, Value) {
+        let response = test.federation_request_from(
+            origin,
+            Method::Get,
+            &format!("/_matrix/federation/v1/make_leave/{}/{}", room_id, user_id),
+            "",
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let mut event: Map<String, Value> = response.json()
+            .get("event")
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .clone();
+        let event_id = format!("$leave:{}", origin);
+
+        event.insert("event_id".to_string(), Value::String(event_id.clone()));
+
+        (event_id, sign_pdu(origin, &signing_key(), event).unwrap())
+    }
+
+    fn send_leave(test: &Test, origin: &str, room_id: &str, event_id: &str, event: &Value)
+    -> Response {
+        test.federation_request_from(
+            origin,
+            Method::Put,
+            &format!("/_matrix/federation/v1/send_leave/{}/{}", room_id, event_id),
+            &to_string(event).unwrap(),
+        )
+    }
+
+    #[test]
+    fn remote_users_can_reject_invites() {
+        let (a, b) = Test::new_pair();
+        let alice = a.register("alice");
+        let bob = b.register("bob");
+
+        let room_id = alice.create_room(&a, "{}");
+        assert_eq!(alice.invite(&a, &room_id, &bob).status, Status::Ok);
+
+        let response = a.federation_request_from(
+            "b.ruma.test",
+            Method::Get,
+            &format!("/_matrix/federation/v1/make_leave/{}/{}", room_id, bob.id),
+            "",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(json_at(&response, "room_version"), "1");
+        assert_eq!(json_at(&response, "event.type"), "m.room.member");
+        assert_eq!(json_at(&response, "event.state_key"), &bob.id[..]);
+        assert_eq!(json_at(&response, "event.content.membership"), "leave");
+        assert_eq!(json_at(&response, "event.origin"), "a.ruma.test");
+
+        let (event_id, event) = signed_leave_event(&a, "b.ruma.test", &room_id, &bob.id);
+
+        let response = send_leave(&a, "b.ruma.test", &room_id, &event_id, &event);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(json_at(&response, "0"), 200);
+        assert_eq!(json_at(&response, "1"), &Value::Object(Map::new()));
+
+        let response = alice.sync_since(&a, None);
+        let events = response.json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+        let leave = events.iter()
+            .find(|event| event.get("event_id").and_then(Value::as_str) == Some(&event_id[..]))
+            .unwrap();
+
+        assert_eq!(leave.pointer("/content/membership").unwrap().as_str(), Some("leave"));
+        assert_eq!(leave.get("sender").unwrap().as_str(), Some(&bob.id[..]));
+
+        // Sending the same event again changes nothing.
+        let response = send_leave(&a, "b.ruma.test", &room_id, &event_id, &event);
+        assert_eq!(response.status, Status::Ok);
+
+        // Bob isn't invited anymore, so he can't leave again.
+        let response = a.federation_request_from(
+            "b.ruma.test",
+            Method::Get,
+            &format!("/_matrix/federation/v1/make_leave/{}/{}", room_id, bob.id),
+            "",
+        );
+        assert_matrix_error(&response, Status::Forbidden, "M_FORBIDDEN");
+    }
+
+    #[test]
+    fn leave_events_are_kept_as_signed_by_the_users_server() {
+        let (a, b) = Test::new_pair();
+        let alice = a.register("alice");
+        let bob = b.register("bob");
+
+        let room_id = alice.create_room(&a, "{}");
+        assert_eq!(alice.invite(&a, &room_id, &bob).status, Status::Ok);
+
+        let (event_id, event) = signed_leave_event(&a, "b.ruma.test", &room_id, &bob.id);
+        let queued_deliveries = || {
+            Job::all(&a.connection()).unwrap()
+                .iter()
+                .filter(|job| job.kind == SEND_EVENT_JOB)
+                .count()
+        };
+        let queued_before = queued_deliveries();
+
+        assert_eq!(send_leave(&a, "b.ruma.test", &room_id, &event_id, &event).status, Status::Ok);
+
+        // Bob's server already has the event, and no other server is in the room.
+        assert_eq!(queued_deliveries(), queued_before);
+
+        let connection = a.connection();
+        let stored_event = Event::find(&connection, &EventId::try_from(&event_id[..]).unwrap())
+            .unwrap()
+            .unwrap();
+        let pdu: Value = from_str(stored_event.pdu.as_ref().unwrap()).unwrap();
+
+        assert_eq!(pdu, event);
+        assert!(pdu.pointer("/signatures/b.ruma.test").is_some());
+        assert_eq!(
+            signed_pdu(&connection, "a.ruma.test", &signing_key(), &stored_event).unwrap(),
+            event
+        );
+    }
+
+    #[test]
+    fn servers_can_only_make_their_own_users_leave() {
+        let (a, b) = Test::new_pair();
+        let alice = a.register("alice");
+        let bob = b.register("bob");
+
+        let room_id = alice.create_room(&a, "{}");
+        assert_eq!(alice.invite(&a, &room_id, &bob).status, Status::Ok);
+
+        let response = a.federation_request_from(
+            "b.ruma.test",
+            Method::Get,
+            &format!("/_matrix/federation/v1/make_leave/{}/{}", room_id, alice.id),
+            "",
+        );
+        assert_matrix_error(&response, Status::Forbidden, "M_FORBIDDEN");
+
+        let (event_id, event) = signed_leave_event(&a, "b.ruma.test", &room_id, &bob.id);
+
+        let mut forged_event = event.clone();
+        forged_event.as_object_mut().unwrap().insert(
+            "sender".to_string(),
+            Value::String(alice.id.clone()),
+        );
+        forged_event.as_object_mut().unwrap().insert(
+            "state_key".to_string(),
+            Value::String(alice.id.clone()),
+        );
+
+        let response = send_leave(&a, "b.ruma.test", &room_id, &event_id, &forged_event);
+        assert_matrix_error(&response, Status::Forbidden, "M_FORBIDDEN");
+    }
+
+    #[test]
+    fn leave_events_must_be_signed_by_the_users_server() {
+        let (a, b) = Test::new_pair();
+        let alice = a.register("alice");
+        let bob = b.register("bob");
+
+        let room_id = alice.create_room(&a, "{}");
+        assert_eq!(alice.invite(&a, &room_id, &bob).status, Status::Ok);
+
+        let (event_id, mut event) = signed_leave_event(&a, "b.ruma.test", &room_id, &bob.id);
+        event.as_object_mut().unwrap().insert(
+            "content".to_string(),
+            Value::Object(vec![
+                ("membership".to_string(), Value::String("leave".to_string())),
+                ("reason".to_string(), Value::String("Tampered".to_string())),
+            ].into_iter().collect()),
+        );
+
+        let response = send_leave(&a, "b.ruma.test", &room_id, &event_id, &event);
+        assert_matrix_error(&response, Status::Forbidden, "M_FORBIDDEN");
+    }
+
+    #[test]
+    fn make_leave_for_unknown_room() {
+        let (a, b) = Test::new_pair();
+        let bob = b.register("bob");
+
+        let response = a.federation_request_from(
+            "b.ruma.test",
+            Method::Get,
+            &format!("/_matrix/federation/v1/make_leave/!unknown:a.ruma.test/{}", bob.id),
+            "",
+        );
+
+        assert_matrix_error(&response, Status::NotFound, "M_NOT_FOUND");
+    }
+}
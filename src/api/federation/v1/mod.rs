@@ -0,0 +1,17 @@
+//! API endpoints for version 1 of the Matrix server-server API.
+
+pub use self::directory::QueryDirectory;
+pub use self::knock::{MakeKnock, SendKnock};
+pub use self::leave::{MakeLeave, SendLeave};
+pub use self::profile::QueryProfile;
+pub use self::public_rooms::GetPublicRooms;
+pub use self::send::SendTransaction;
+pub use self::version::Version;
+
+mod directory;
+mod knock;
+mod leave;
+mod profile;
+mod public_rooms;
+mod send;
+mod version;
@@ -0,0 +1,121 @@
+//! Endpoints for profile queries of other servers.
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::UserId;
+
+use config::Config;
+use db::DB;
+use error::ApiError;
+use identifiers;
+use middleware::{FederationAuth, MiddlewareChain};
+use models::profile::Profile;
+use models::user::User;
+use modifier::SerializableResponse;
+use query_params;
+
+/// The GET `/query/profile` endpoint.
+///
+/// Returns the profile of a user of this server. With a `field` of `displayname` or `avatar_url`,
+/// only that field is returned.
+pub struct QueryProfile;
+
+#[derive(Debug, Default, Serialize)]
+struct QueryProfileResponse {
+    /// The user's avatar URL, if they have set one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+    /// The user's display name, if they have set one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    displayname: Option<String>,
+}
+
+middleware_chain!(QueryProfile, [FederationAuth]);
+
+impl Handler for QueryProfile {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let user_id = match query_params::get(request, "user_id") {
+            Some(user_id) => identifiers::parse::<UserId>("user_id", &user_id)?,
+            None => Err(ApiError::missing_param("user_id"))?,
+        };
+        let field = query_params::get(request, "field");
+
+        if user_id.hostname().to_string() != config.domain {
+            Err(ApiError::not_found("The user is not a user of this server.".to_string()))?;
+        }
+
+        if User::find_active_user(&connection, &user_id)?.is_none() {
+            Err(ApiError::not_found(format!("No profile found for {}", user_id)))?;
+        }
+
+        let profile = Profile::find_by_uid(&connection, &user_id)?;
+        let (avatar_url, displayname) = match profile {
+            Some(profile) => (profile.avatar_url, profile.displayname),
+            None => (None, None),
+        };
+
+        let response = match field.as_ref().map(|field| &field[..]) {
+            None => QueryProfileResponse { avatar_url: avatar_url, displayname: displayname },
+            Some("avatar_url") => {
+                QueryProfileResponse { avatar_url: avatar_url, ..Default::default() }
+            }
+            Some("displayname") => {
+                QueryProfileResponse { displayname: displayname, ..Default::default() }
+            }
+            Some(_) => Err(ApiError::invalid_param(
+                "field",
+                "The field must be either displayname or avatar_url.",
+            ))?,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::{Test, assert_matrix_error, json_at};
+
+    #[test]
+    fn query_profile() {
+        let test = Test::new();
+        let carl = test.register("carl");
+
+        let response = test.put_as(
+            &carl,
+            "/_matrix/client/r0/profile/@carl:ruma.test/displayname",
+            r#"{"displayname": "Carl"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.federation_get(
+            "/_matrix/federation/v1/query/profile?user_id=%40carl%3Aruma.test"
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(json_at(&response, "displayname"), "Carl");
+
+        let response = test.federation_get(
+            "/_matrix/federation/v1/query/profile?user_id=%40carl%3Aruma.test&field=avatar_url"
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("displayname").is_none());
+    }
+
+    #[test]
+    fn query_profile_of_unknown_user() {
+        let test = Test::new();
+
+        let response = test.federation_get(
+            "/_matrix/federation/v1/query/profile?user_id=%40nobody%3Aruma.test"
+        );
+
+        assert_matrix_error(&response, Status::NotFound, "M_NOT_FOUND");
+    }
+}
@@ -0,0 +1,146 @@
+//! Endpoints for the federated room directory.
+
+use std::error::Error;
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+use serde_json::from_str;
+
+use config::Config;
+use db::DB;
+use error::ApiError;
+use middleware::{FederationAuth, MiddlewareChain};
+use models::room_directory::{DirectoryAccess, PublicRooms, PublicRoomsFilter};
+use modifier::SerializableResponse;
+use query_params;
+
+/// The GET `/publicRooms` endpoint.
+///
+/// Lists the rooms in this server's public room directory. Rooms of third party networks are
+/// only available through application services, which Ruma does not support yet, so
+/// `include_all_networks` and `third_party_instance_id` do not add any rooms.
+pub struct GetPublicRooms;
+
+middleware_chain!(GetPublicRooms, [FederationAuth]);
+
+impl Handler for GetPublicRooms {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+
+        let max_limit = config.max_pagination_limit;
+        let limit = query_params::get_u64(request, "limit", max_limit, max_limit)? as usize;
+        let since = query_params::get(request, "since");
+        let filter = match query_params::get(request, "filter") {
+            Some(filter) => from_str(&filter)
+                .map_err(|err| ApiError::invalid_param("filter", err.description()))?,
+            None => PublicRoomsFilter::default(),
+        };
+        query_params::get_bool(request, "include_all_networks", false)?;
+        let third_party_instance_id = query_params::get(request, "third_party_instance_id");
+
+        let connection = DB::from_request(request)?;
+
+        let response = if third_party_instance_id.is_some() {
+            PublicRooms::find(&connection, Some(0), None, &filter, DirectoryAccess::Authenticated)?
+        } else {
+            PublicRooms::find(
+                &connection,
+                Some(limit),
+                since.as_ref().map(|since| &since[..]),
+                &filter,
+                DirectoryAccess::Authenticated,
+            )?
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    #[test]
+    fn public_rooms_lists_only_public_rooms() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let public_room_id = test.create_room_with_params(
+            &alice.token,
+            r#"{"visibility": "public", "name": "Ruma Lounge", "room_alias_name": "lounge"}"#,
+        );
+        test.create_room_with_params(&alice.token, r#"{"visibility": "private"}"#);
+
+        let response = test.federation_get("/_matrix/federation/v1/publicRooms");
+
+        assert_eq!(response.status, Status::Ok);
+
+        let chunk = response.json().get("chunk").unwrap().as_array().unwrap();
+        assert_eq!(chunk.len(), 1);
+        assert_eq!(chunk[0].get("room_id").unwrap().as_str().unwrap(), public_room_id);
+        assert_eq!(chunk[0].get("name").unwrap().as_str().unwrap(), "Ruma Lounge");
+        assert_eq!(chunk[0].get("num_joined_members").unwrap().as_u64().unwrap(), 1);
+        assert_eq!(
+            chunk[0].get("aliases").unwrap().as_array().unwrap()[0].as_str().unwrap(),
+            "#lounge:ruma.test"
+        );
+        assert_eq!(
+            response.json().get("total_room_count_estimate").unwrap().as_u64().unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn public_rooms_paginates() {
+        let test = Test::new();
+        let alice = test.create_user();
+        test.create_room_with_params(&alice.token, r#"{"visibility": "public"}"#);
+        test.create_room_with_params(&alice.token, r#"{"visibility": "public"}"#);
+
+        let response = test.federation_get("/_matrix/federation/v1/publicRooms?limit=1");
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("chunk").unwrap().as_array().unwrap().len(), 1);
+
+        let next_batch = response.json().get("next_batch").unwrap().as_str().unwrap().to_string();
+
+        let response = test.federation_get(
+            &format!("/_matrix/federation/v1/publicRooms?limit=1&since={}", next_batch)
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("chunk").unwrap().as_array().unwrap().len(), 1);
+        assert!(response.json().get("next_batch").is_none());
+        assert!(response.json().get("prev_batch").is_some());
+    }
+
+    #[test]
+    fn public_rooms_filters_by_search_term() {
+        let test = Test::new();
+        let alice = test.create_user();
+        test.create_room_with_params(&alice.token, r#"{"visibility": "public", "name": "Rust"}"#);
+        test.create_room_with_params(&alice.token, r#"{"visibility": "public", "name": "Go"}"#);
+
+        let response = test.federation_get(
+            "/_matrix/federation/v1/publicRooms?filter=%7B%22generic_search_term%22%3A%22rus%22%7D"
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let chunk = response.json().get("chunk").unwrap().as_array().unwrap();
+        assert_eq!(chunk.len(), 1);
+        assert_eq!(chunk[0].get("name").unwrap().as_str().unwrap(), "Rust");
+    }
+
+    #[test]
+    fn public_rooms_requires_server_authentication() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/federation/v1/publicRooms");
+
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNAUTHORIZED"
+        );
+    }
+}
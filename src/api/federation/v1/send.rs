@@ -0,0 +1,454 @@
+//! Endpoints for receiving transactions from other servers.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use bodyparser;
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde_json::{Value, to_string};
+
+use clock::{self, Clock};
+use config::Config;
+use db::{DB, transaction_with_retry};
+use error::ApiError;
+use federation::auth::Origin;
+use middleware::{FederationAuth, JsonRequest, MiddlewareChain, TransactionIdParam};
+use models::event::{Event, NewEvent};
+use models::rejected_event::RejectedEvent;
+use models::room::Room;
+use models::room_membership::RoomMembership;
+use modifier::SerializableResponse;
+use request_ext::extension;
+use room_version::{self, DEFAULT_ROOM_VERSION};
+
+/// The PUT `/send/:transaction_id` endpoint.
+///
+/// Checks the PDUs of a transaction from another server. PDUs that are malformed, claim to be
+/// sent by another server, create a room of an unsupported version, or belong to a room this
+/// server doesn't know are rejected and recorded as rejected events. The remaining PDUs are
+/// saved, soft-failed if their sender isn't joined to the room. Ruma can't change the state of
+/// rooms over federation or fetch missing events yet, so state events and events following
+/// unknown ones are answered with an error, but they aren't recorded.
+pub struct SendTransaction;
+
+#[derive(Clone, Debug, Deserialize)]
+struct SendTransactionRequest {
+    /// The persistent data units of the transaction.
+    #[serde(default)]
+    pdus: Vec<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct SendTransactionResponse {
+    /// The results of processing the PDUs, by event ID.
+    pdus: BTreeMap<String, PduResult>,
+}
+
+#[derive(Debug, Serialize)]
+struct PduResult {
+    /// Why the PDU wasn't accepted, if it wasn't.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+middleware_chain!(SendTransaction, [JsonRequest, FederationAuth, TransactionIdParam]);
+
+impl Handler for SendTransaction {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let send_transaction_request =
+            match request.get::<bodyparser::Struct<SendTransactionRequest>>() {
+                Ok(Some(send_transaction_request)) => send_transaction_request,
+                Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+            };
+
+        let origin = extension::<Origin>(request)?;
+        let transaction_id = extension::<TransactionIdParam>(request)?;
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+        let clock = clock::from_request(request)?;
+
+        let mut pdus = BTreeMap::new();
+
+        for pdu in &send_transaction_request.pdus {
+            let event_id = pdu.get("event_id").and_then(Value::as_str).unwrap_or("").to_string();
+
+            let error = match check_pdu(&connection, &origin, pdu)? {
+                Ok((event, prev_events)) => save_pdu(&connection, &*clock, &event, &prev_events)?,
+                Err(reason) => {
+                    RejectedEvent::record(
+                        &connection,
+                        config.max_rejected_events,
+                        &origin,
+                        pdu,
+                        &reason,
+                    )?;
+
+                    Some(reason)
+                }
+            };
+
+            pdus.insert(event_id, PduResult { error: error });
+        }
+
+        debug!("Processed transaction {} from {}.", transaction_id, origin);
+
+        let response = SendTransactionResponse {
+            pdus: pdus,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Checks a PDU sent by `origin`, returning its event and `prev_events`, or why it is rejected.
+fn check_pdu(connection: &PgConnection, origin: &str, pdu: &Value)
+-> Result<Result<(NewEvent, Vec<EventId>), String>, ApiError> {
+    let field = |key: &str| pdu.get(key).and_then(Value::as_str);
+
+    let (event_id, room_id, sender, event_type, content) = match (
+        field("event_id"),
+        field("room_id"),
+        field("sender"),
+        field("type"),
+        pdu.get("content"),
+    ) {
+        (Some(event_id), Some(room_id), Some(sender), Some(event_type), Some(content))
+        if content.is_object() => {
+            (event_id, room_id, sender, event_type, content)
+        }
+        _ => return Ok(Err("The PDU is missing required fields.".to_string())),
+    };
+
+    let event_id = match EventId::try_from(event_id) {
+        Ok(event_id) => event_id,
+        Err(_) => return Ok(Err(format!("The event ID {} is invalid.", event_id))),
+    };
+
+    let sender = match UserId::try_from(sender) {
+        Ok(sender) => sender,
+        Err(_) => return Ok(Err(format!("The sender {} is invalid.", sender))),
+    };
+
+    if sender.hostname().to_string() != origin {
+        return Ok(Err(format!("The sender {} does not belong to the server {}.", sender, origin)));
+    }
+
+    let room_id = match RoomId::try_from(room_id) {
+        Ok(room_id) => room_id,
+        Err(_) => return Ok(Err(format!("The room ID {} is invalid.", room_id))),
+    };
+
+    if event_type == "m.room.create" {
+        let version = pdu.pointer("/content/room_version")
+            .and_then(Value::as_str)
+            .unwrap_or(DEFAULT_ROOM_VERSION);
+
+        if room_version::check(version).is_err() {
+            return Ok(Err(format!("The room version {} is not supported.", version)));
+        }
+    }
+
+    if Room::find(connection, &room_id)?.is_none() {
+        return Ok(Err(format!("The room {} is unknown to this server.", room_id)));
+    }
+
+    let prev_events = match prev_event_ids(pdu) {
+        Some(prev_events) => prev_events,
+        None => return Ok(Err("The prev_events of the PDU are missing or invalid.".to_string())),
+    };
+
+    let event = NewEvent {
+        event_type: event_type.to_string(),
+        extra_content: None,
+        id: event_id,
+        content: to_string(content).map_err(ApiError::from)?,
+        room_id: room_id,
+        state_key: field("state_key").map(str::to_string),
+        user_id: sender,
+        pdu: Some(to_string(pdu).map_err(ApiError::from)?),
+    };
+
+    Ok(Ok((event, prev_events)))
+}
+
+/// The IDs of the PDU's `prev_events`, given as IDs or as pairs of IDs and hashes. `None` if there
+/// are none, or they are invalid.
+fn prev_event_ids(pdu: &Value) -> Option<Vec<EventId>> {
+    let prev_events = match pdu.get("prev_events").and_then(Value::as_array) {
+        Some(prev_events) if !prev_events.is_empty() => prev_events,
+        _ => return None,
+    };
+
+    prev_events.iter()
+        .map(|prev_event| {
+            prev_event.as_str()
+                .or_else(|| prev_event.get(0).and_then(Value::as_str))
+                .and_then(|prev_event_id| EventId::try_from(prev_event_id).ok())
+        })
+        .collect()
+}
+
+/// Saves an event received over federation, returning why it isn't accepted, if it isn't.
+///
+/// Events whose sender isn't joined to the room are soft-failed: they stay in the event graph,
+/// but are hidden from clients. Events that were already received are accepted again.
+fn save_pdu(connection: &PgConnection, clock: &Clock, event: &NewEvent, prev_events: &[EventId])
+-> Result<Option<String>, ApiError> {
+    if event.state_key.is_some() {
+        return Ok(Some("Receiving state events over federation is not supported yet.".to_string()));
+    }
+
+    transaction_with_retry(connection, || {
+        if Event::find(connection, &event.id)?.is_some() {
+            return Ok(None);
+        }
+
+        for prev_event_id in prev_events {
+            if Event::find(connection, prev_event_id)?.is_none() {
+                return Ok(Some(format!("The event {} is unknown to this server.", prev_event_id)));
+            }
+        }
+
+        let is_joined = match RoomMembership::find(connection, &event.room_id, &event.user_id)? {
+            Some(membership) => membership.membership == "join",
+            None => false,
+        };
+
+        event.save_with_prev_events(connection, clock, prev_events)?;
+
+        if !is_joined {
+            warn!("Soft-failed event {}: {} isn't joined to the room.", event.id, event.user_id);
+
+            Event::soft_fail(connection, &event.id)?;
+        }
+
+        Ok(None)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use iron::method::Method;
+    use iron::status::Status;
+    use ruma_identifiers::{EventId, RoomId};
+
+    use models::event::Event;
+    use models::rejected_event::RejectedEvent;
+    use test::{Response, Test};
+
+    /// Sends a message PDU from `sender` to the room, following its latest event.
+    fn send_message_pdu(test: &Test, event_id: &str, room_id: &str, sender: &str) -> Response {
+        let prev_event_id = {
+            let connection = test.connection();
+            let room_id = RoomId::try_from(room_id).unwrap();
+
+            Event::find_latest_event_ids(&connection, &room_id).unwrap().remove(0)
+        };
+
+        test.federation_request(
+            Method::Put,
+            "/_matrix/federation/v1/send/1",
+            &format!(
+                r#"{{"pdus": [{{
+                    "event_id": "{}",
+                    "room_id": "{}",
+                    "sender": "{}",
+                    "type": "m.room.message",
+                    "content": {{"msgtype": "m.text", "body": "Hello"}},
+                    "prev_events": [["{}", {{}}]]
+                }}]}}"#,
+                event_id,
+                room_id,
+                sender,
+                prev_event_id
+            ),
+        )
+    }
+
+    /// The IDs of the events in the room's timeline of the user's sync.
+    fn synced_event_ids(test: &Test, access_token: &str, room_id: &str) -> Vec<String> {
+        let response = test.get(&format!("/_matrix/client/r0/sync?access_token={}", access_token));
+
+        response.json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event.get("event_id").unwrap().as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn forged_pdus_are_rejected_and_recorded() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = test.create_room(&carl.token);
+
+        let response = test.federation_request(
+            Method::Put,
+            "/_matrix/federation/v1/send/1",
+            &format!(
+                r#"{{"pdus": [{{
+                    "event_id": "$forged:ruma.test",
+                    "room_id": "{}",
+                    "sender": "@mallory:evil.test",
+                    "type": "m.room.message",
+                    "content": {{"msgtype": "m.text", "body": "Forged"}}
+                }}]}}"#,
+                room_id
+            ),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let error = response.json().pointer("/pdus/$forged:ruma.test/error").unwrap();
+        assert!(error.as_str().unwrap().contains("@mallory:evil.test"));
+
+        let connection = test.connection();
+        let rejected_events = RejectedEvent::find_recent(&connection, 10).unwrap();
+
+        assert_eq!(rejected_events.len(), 1);
+        assert_eq!(rejected_events[0].event_id, Some("$forged:ruma.test".to_string()));
+        assert_eq!(rejected_events[0].room_id, Some(room_id.clone()));
+        assert_eq!(rejected_events[0].origin, "ruma.test");
+        assert!(rejected_events[0].reason.contains("does not belong to the server"));
+        drop(connection);
+
+        let response = test.get(&format!("/_matrix/client/r0/sync?access_token={}", carl.token));
+        let timeline_events = response.json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+
+        assert!(timeline_events.iter().all(|event| {
+            event.get("event_id").unwrap().as_str().unwrap() != "$forged:ruma.test"
+        }));
+    }
+
+    #[test]
+    fn events_of_joined_senders_are_saved() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = test.create_room(&carl.token);
+
+        let response = send_message_pdu(&test, "$hello:ruma.test", &room_id, &carl.id);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().pointer("/pdus/$hello:ruma.test").unwrap().to_string(), "{}");
+
+        let event_ids = synced_event_ids(&test, &carl.token, &room_id);
+        assert!(event_ids.contains(&"$hello:ruma.test".to_string()));
+    }
+
+    #[test]
+    fn events_of_senders_outside_the_room_are_soft_failed() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = test.create_room(&carl.token);
+
+        let response = send_message_pdu(&test, "$intruder:ruma.test", &room_id, "@dave:ruma.test");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().pointer("/pdus/$intruder:ruma.test/error").is_none());
+
+        let connection = test.connection();
+        let event_id = EventId::try_from("$intruder:ruma.test").unwrap();
+
+        assert!(Event::find(&connection, &event_id).unwrap().unwrap().soft_failed);
+        assert!(RejectedEvent::find_recent(&connection, 10).unwrap().is_empty());
+        drop(connection);
+
+        let event_ids = synced_event_ids(&test, &carl.token, &room_id);
+        assert!(!event_ids.contains(&"$intruder:ruma.test".to_string()));
+    }
+
+    #[test]
+    fn only_the_most_recent_rejected_events_are_kept() {
+        let test = Test::with_config(|config| config.max_rejected_events = 2);
+
+        for event_id in &["$first:ruma.test", "$second:ruma.test", "$third:ruma.test"] {
+            let response = test.federation_request(
+                Method::Put,
+                "/_matrix/federation/v1/send/1",
+                &format!(r#"{{"pdus": [{{"event_id": "{}"}}]}}"#, event_id),
+            );
+
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        let connection = test.connection();
+        let event_ids: Vec<Option<String>> = RejectedEvent::find_recent(&connection, 10)
+            .unwrap()
+            .into_iter()
+            .map(|rejected_event| rejected_event.event_id)
+            .collect();
+
+        assert_eq!(
+            event_ids,
+            vec![Some("$third:ruma.test".to_string()), Some("$second:ruma.test".to_string())]
+        );
+    }
+
+    #[test]
+    fn malformed_pdus_are_rejected() {
+        let test = Test::new();
+
+        let response = test.federation_request(
+            Method::Put,
+            "/_matrix/federation/v1/send/1",
+            r#"{"pdus": [{"event_id": "$incomplete:ruma.test"}]}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().pointer("/pdus/$incomplete:ruma.test/error").is_some());
+
+        let connection = test.connection();
+        let rejected_events = RejectedEvent::find_recent(&connection, 10).unwrap();
+
+        assert_eq!(rejected_events.len(), 1);
+        assert_eq!(rejected_events[0].room_id, None);
+    }
+
+    #[test]
+    fn create_events_of_unsupported_room_versions_are_rejected() {
+        let test = Test::new();
+
+        let response = test.federation_request(
+            Method::Put,
+            "/_matrix/federation/v1/send/1",
+            r#"{"pdus": [{
+                "event_id": "$create:ruma.test",
+                "room_id": "!new:ruma.test",
+                "sender": "@carl:ruma.test",
+                "type": "m.room.create",
+                "state_key": "",
+                "content": {"creator": "@carl:ruma.test", "room_version": "999"}
+            }]}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let connection = test.connection();
+        let rejected_events = RejectedEvent::find_recent(&connection, 10).unwrap();
+
+        assert_eq!(rejected_events.len(), 1);
+        assert_eq!(rejected_events[0].reason, "The room version 999 is not supported.");
+    }
+
+    #[test]
+    fn unsigned_transactions_are_refused() {
+        let test = Test::new();
+
+        let response = test.put("/_matrix/federation/v1/send/1", r#"{"pdus": []}"#);
+
+        assert_eq!(response.status, Status::Unauthorized);
+    }
+}
@@ -0,0 +1,59 @@
+//! Endpoints for information about the server implementation.
+
+use iron::{Handler, IronResult, Request, Response, status};
+
+use modifier::SerializableResponse;
+
+/// The /version endpoint.
+#[derive(Serialize)]
+pub struct Version {
+    server: Server,
+}
+
+/// The name and version of the server implementation.
+#[derive(Serialize)]
+struct Server {
+    name: &'static str,
+    version: &'static str,
+}
+
+impl Version {
+    /// Returns the `Version` of this build of Ruma.
+    pub fn current() -> Self {
+        Version {
+            server: Server {
+                name: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+            },
+        }
+    }
+}
+
+impl Handler for Version {
+    fn handle(&self, _request: &mut Request) -> IronResult<Response> {
+        Ok(Response::with((status::Ok, SerializableResponse(&self))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    #[test]
+    fn version_reports_server_name_and_version() {
+        let test = Test::new();
+        let response = test.get("/_matrix/federation/v1/version");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().pointer("/server/name").unwrap().as_str().unwrap(),
+            env!("CARGO_PKG_NAME")
+        );
+        assert_eq!(
+            response.json().pointer("/server/version").unwrap().as_str().unwrap(),
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+}
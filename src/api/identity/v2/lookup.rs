@@ -0,0 +1,212 @@
+//! Endpoints for discovering users by their third-party identifiers.
+
+use bodyparser;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::method::Method;
+use iron::status::Status;
+use serde_json::to_value;
+
+use config::Config;
+use error::ApiError;
+use identity_server::IdentityServerClient;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
+use modifier::SerializableResponse;
+
+/// The only hashing algorithm lookups are forwarded with.
+const SHA256: &'static str = "sha256";
+
+/// The length of an unpadded URL-safe Base64 encoded SHA-256 hash.
+const HASHED_ADDRESS_LENGTH: usize = 43;
+
+/// The GET `/hash_details` endpoint.
+///
+/// Returns the identity server's pepper and the hashing algorithms it supports.
+pub struct HashDetails;
+
+middleware_chain!(HashDetails, [AccessTokenAuth]);
+
+impl Handler for HashDetails {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+        let client = identity_server_client(&config)?;
+
+        let response = client.request(Method::Get, "/_matrix/identity/v2/hash_details", None)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The POST `/lookup` endpoint.
+///
+/// Only lookups of peppered SHA-256 hashes are forwarded to the identity server, so the
+/// third-party identifiers of the user's contacts are never sent in plain text.
+pub struct Lookup;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct LookupRequest {
+    /// The hashed addresses to look up.
+    addresses: Vec<String>,
+    /// The algorithm the addresses were hashed with.
+    algorithm: String,
+    /// The pepper from `/hash_details` that was added to the addresses before hashing.
+    pepper: String,
+}
+
+middleware_chain!(Lookup, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for Lookup {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let lookup_request = match request.get::<bodyparser::Struct<LookupRequest>>() {
+            Ok(Some(lookup_request)) => lookup_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        if lookup_request.algorithm != SHA256 {
+            Err(ApiError::invalid_param("algorithm", "Only sha256 lookups are supported."))?;
+        }
+
+        if lookup_request.pepper.is_empty() {
+            Err(ApiError::invalid_param("pepper", "Lookups must be peppered."))?;
+        }
+
+        if !lookup_request.addresses.iter().all(|address| is_hashed_address(address)) {
+            Err(ApiError::invalid_param("addresses", "Addresses must be hashed."))?;
+        }
+
+        let config = Config::from_request(request)?;
+        let client = identity_server_client(&config)?;
+
+        let content = to_value(&lookup_request).map_err(ApiError::from)?;
+        let response = client.request(Method::Post, "/_matrix/identity/v2/lookup", Some(&content))?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Creates a client for the configured identity server, failing if there is none.
+fn identity_server_client(config: &Config) -> Result<IdentityServerClient, ApiError> {
+    match IdentityServerClient::from_config(config)? {
+        Some(client) => Ok(client),
+        None => Err(ApiError::not_found("Contact discovery is not enabled on this server.".to_string())),
+    }
+}
+
+/// Checks that an address looks like an unpadded URL-safe Base64 encoded SHA-256 hash rather than
+/// a plain text email address or phone number.
+fn is_hashed_address(address: &str) -> bool {
+    address.len() == HASHED_ADDRESS_LENGTH && address.chars().all(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' => true,
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::method::Method;
+    use iron::status::Status;
+
+    use test::Test;
+    use test::http_stub::{HttpStub, StubResponse};
+
+    const HASHED_ADDRESS: &'static str = "4kenr7N9drpCJ4AfalmlGQVsOn3o2RHjkADUpXJWZUc";
+
+    #[test]
+    fn lookup_is_signed_and_forwarded() {
+        let identity_server = HttpStub::start();
+        identity_server.on(
+            Method::Post,
+            "/_matrix/identity/v2/lookup",
+            StubResponse::json(
+                &format!(r#"{{"mappings":{{"{}":"@carl:ruma.test"}}}}"#, HASHED_ADDRESS)
+            ),
+        );
+
+        let test = Test::with_config(|config| {
+            config.identity_server_url = Some(identity_server.url());
+        });
+        let user = test.create_user();
+
+        let response = test.post(
+            &format!("/_matrix/identity/v2/lookup?access_token={}", user.token),
+            &format!(r#"{{"addresses":["{}"],"algorithm":"sha256","pepper":"matrixrocks"}}"#, HASHED_ADDRESS),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().pointer(&format!("/mappings/{}", HASHED_ADDRESS)).unwrap().as_str().unwrap(),
+            "@carl:ruma.test"
+        );
+
+        let received = identity_server.received();
+        assert_eq!(received.len(), 1);
+
+        let authorization = received[0].headers.get_raw("Authorization")
+            .map(|values| String::from_utf8(values[0].clone()).unwrap());
+        assert_eq!(received[0].path, "/_matrix/identity/v2/lookup");
+        assert!(authorization.unwrap().starts_with("X-Matrix origin=ruma.test,"));
+        assert!(received[0].body.contains("matrixrocks"));
+    }
+
+    #[test]
+    fn lookup_fails_if_the_identity_server_fails() {
+        let identity_server = HttpStub::start();
+        identity_server.on(Method::Post, "/_matrix/identity/v2/lookup", StubResponse::failure());
+
+        let test = Test::with_config(|config| {
+            config.identity_server_url = Some(identity_server.url());
+        });
+        let user = test.create_user();
+
+        let response = test.post(
+            &format!("/_matrix/identity/v2/lookup?access_token={}", user.token),
+            &format!(
+                r#"{{"addresses":["{}"],"algorithm":"sha256","pepper":"matrixrocks"}}"#,
+                HASHED_ADDRESS
+            ),
+        );
+
+        assert!(response.status.is_server_error());
+        identity_server.assert_no_unexpected_requests();
+    }
+
+    #[test]
+    fn lookup_requires_hashed_addresses() {
+        let test = Test::with_config(|config| {
+            config.identity_server_url = Some("http://127.0.0.1:1".to_string())
+        });
+        let user = test.create_user();
+        let path = format!("/_matrix/identity/v2/lookup?access_token={}", user.token);
+
+        let response = test.post(&path, r#"{"addresses":["carl@example.com"],"algorithm":"none","pepper":""}"#);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_INVALID_PARAM");
+
+        let response = test.post(
+            &path,
+            r#"{"addresses":["carl@example.com"],"algorithm":"sha256","pepper":"matrixrocks"}"#,
+        );
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_INVALID_PARAM");
+
+        let response = test.post(
+            &path,
+            &format!(r#"{{"addresses":["{}"],"algorithm":"sha256","pepper":""}}"#, HASHED_ADDRESS),
+        );
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn lookup_is_disabled_by_default() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let response = test.post(
+            &format!("/_matrix/identity/v2/lookup?access_token={}", user.token),
+            &format!(r#"{{"addresses":["{}"],"algorithm":"sha256","pepper":"matrixrocks"}}"#, HASHED_ADDRESS),
+        );
+        assert_eq!(response.status, Status::NotFound);
+
+        let response = test.get(&format!("/_matrix/identity/v2/hash_details?access_token={}", user.token));
+        assert_eq!(response.status, Status::NotFound);
+    }
+}
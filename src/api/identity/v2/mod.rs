@@ -0,0 +1,5 @@
+//! API endpoints for version 2 of the Matrix identity service API.
+
+pub use self::lookup::{HashDetails, Lookup};
+
+mod lookup;
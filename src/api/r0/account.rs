@@ -1,9 +1,15 @@
 //! Endpoints for accounts.
+use std::collections::HashMap;
+
 use bodyparser;
 use diesel::SaveChangesDsl;
+use diesel::result::Error as DieselError;
 use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
 use iron::status::Status;
+use ruma_identifiers::RoomId;
+use serde_json::{Value, from_str};
 
+use access_token_cache::AccessTokenCache;
 use crypto::hash_password;
 use db::DB;
 use error::ApiError;
@@ -17,6 +23,7 @@ use middleware::{
 };
 use models::access_token::AccessToken;
 use models::account_data::{
+    EMPTY_CONTENT,
     AccountData,
     NewAccountData,
     RoomAccountData,
@@ -24,7 +31,10 @@ use models::account_data::{
 };
 use models::room_membership::RoomMembership;
 use models::user::User;
-use modifier::EmptyResponse;
+use models::user_threepid::UserThreepid;
+use modifier::{EmptyResponse, SerializableResponse};
+use request_ext::{authed_user, extension, extension_mut};
+use super::milliseconds_since_epoch;
 
 /// The `/account/password` endpoint.
 #[derive(Debug)]
@@ -46,9 +56,7 @@ impl Handler for AccountPassword {
                 Ok(None) | Err(_) => Err(ApiError::not_json(None))?,
             };
 
-        let mut user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let mut user = authed_user(request)?;
 
         user.password_hash = hash_password(&account_password_request.new_password)?;
 
@@ -57,6 +65,8 @@ impl Handler for AccountPassword {
         user.save_changes::<User>(&*connection)
             .map_err(|_| ApiError::unauthorized(None))?;
 
+        AccessTokenCache::from_request(request)?.invalidate_user(&user.id);
+
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
 }
@@ -70,18 +80,18 @@ middleware_chain!(DeactivateAccount, [AccessTokenAuth]);
 impl Handler for DeactivateAccount {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
         let connection = DB::from_request(request)?;
+        let access_token_cache = AccessTokenCache::from_request(request)?;
 
         {
-            let token = request.extensions.get_mut::<AccessToken>()
-                .expect("AccessTokenAuth should ensure an access token");
+            let token = extension_mut::<AccessToken>(request)?;
 
             token.revoke(&connection)?;
         }
 
-        let user = request.extensions.get_mut::<User>()
-            .expect("AccessTokenAuth should ensure a user");
+        let user = extension_mut::<User>(request)?;
 
         user.deactivate(&connection)?;
+        access_token_cache.invalidate_user(&user.id);
 
         // Delete all the account data associated with the user.
         AccountData::delete_by_uid(&connection, &user.id)?;
@@ -91,6 +101,63 @@ impl Handler for DeactivateAccount {
     }
 }
 
+/// The GET `/account/3pid` endpoint.
+#[derive(Debug)]
+pub struct GetThreePids;
+
+#[derive(Debug, Serialize)]
+struct GetThreePidsResponse {
+    threepids: Vec<ThreePid>,
+}
+
+/// A third-party identifier associated with the user.
+#[derive(Debug, Serialize)]
+struct ThreePid {
+    /// The medium of the identifier, e.g. *email*.
+    medium: String,
+    /// The identifier itself.
+    address: String,
+    /// The time the identifier was added, in milliseconds since the Unix epoch.
+    added_at: u64,
+    /// The time the identity server validated the identifier, in milliseconds since the Unix
+    /// epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    validated_at: Option<u64>,
+}
+
+middleware_chain!(GetThreePids, [AccessTokenAuth]);
+
+impl Handler for GetThreePids {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = authed_user(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        let threepids = UserThreepid::find_by_uid(&connection, &user.id)?
+            .into_iter()
+            .map(|threepid| {
+                let validated_at = match threepid.validated_at {
+                    Some(validated_at) => Some(milliseconds_since_epoch(validated_at)?),
+                    None => None,
+                };
+
+                Ok(ThreePid {
+                    added_at: milliseconds_since_epoch(threepid.added_at)?,
+                    validated_at: validated_at,
+                    medium: threepid.medium,
+                    address: threepid.address,
+                })
+            })
+            .collect::<Result<Vec<ThreePid>, ApiError>>()?;
+
+        let response = GetThreePidsResponse {
+            threepids: threepids,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
 /// The `/user/:user_id/account_data/:type` endpoint.
 #[derive(Debug)]
 pub struct PutAccountData;
@@ -99,11 +166,9 @@ middleware_chain!(PutAccountData, [JsonRequest, UserIdParam, DataTypeParam, Acce
 
 impl Handler for PutAccountData {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
+        let user_id = extension::<UserIdParam>(request)?;
 
         if user_id != user.id {
             let error = ApiError::unauthorized(
@@ -113,8 +178,7 @@ impl Handler for PutAccountData {
             return Err(IronError::from(error));
         }
 
-        let data_type = request.extensions.get::<DataTypeParam>()
-            .expect("DataTypeParam should ensure a data type").clone();
+        let data_type = extension::<DataTypeParam>(request)?;
 
         let content = match request.get::<bodyparser::Json>() {
             Ok(Some(content)) => content.to_string().clone(),
@@ -135,6 +199,46 @@ impl Handler for PutAccountData {
     }
 }
 
+/// The `DELETE /user/:user_id/account_data/:type` endpoint.
+///
+/// The entry is kept with empty content rather than removed, so that it shows up as an empty
+/// `account_data` event to the user's other sessions.
+#[derive(Debug)]
+pub struct DeleteAccountData;
+
+middleware_chain!(DeleteAccountData, [UserIdParam, DataTypeParam, AccessTokenAuth]);
+
+impl Handler for DeleteAccountData {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = authed_user(request)?;
+
+        let user_id = extension::<UserIdParam>(request)?;
+
+        if user_id != user.id {
+            let error = ApiError::unauthorized(
+                "The given user_id does not correspond to the authenticated user".to_string()
+            );
+
+            return Err(IronError::from(error));
+        }
+
+        let data_type = extension::<DataTypeParam>(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        let mut data = match AccountData::find_by_uid_and_type(&connection, &user.id, &data_type) {
+            Ok(ref data) if data.content == EMPTY_CONTENT => Err(ApiError::not_found(None))?,
+            Ok(data) => data,
+            Err(DieselError::NotFound) => Err(ApiError::not_found(None))?,
+            Err(err) => Err(ApiError::from(err))?,
+        };
+
+        data.update(&connection, EMPTY_CONTENT.to_string())?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
 /// The `/user/:user_id/rooms/:room_id/account_data/:type` endpoint.
 #[derive(Debug)]
 pub struct PutRoomAccountData;
@@ -143,11 +247,9 @@ middleware_chain!(PutRoomAccountData, [JsonRequest, UserIdParam, RoomIdParam, Da
 
 impl Handler for PutRoomAccountData {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
+        let user_id = extension::<UserIdParam>(request)?;
 
         if user_id != user.id {
             let error = ApiError::unauthorized(
@@ -157,8 +259,7 @@ impl Handler for PutRoomAccountData {
             return Err(IronError::from(error));
         }
 
-        let room_id = request.extensions.get::<RoomIdParam>()
-            .expect("RoomIdParam should ensure a RoomId").clone();
+        let room_id = extension::<RoomIdParam>(request)?;
 
         let connection = DB::from_request(request)?;
 
@@ -179,8 +280,7 @@ impl Handler for PutRoomAccountData {
             return Err(IronError::from(error));
         }
 
-        let data_type = request.extensions.get::<DataTypeParam>()
-            .expect("DataTypeParam should ensure a data type").clone();
+        let data_type = extension::<DataTypeParam>(request)?;
 
         let content = match request.get::<bodyparser::Json>() {
             Ok(Some(content)) => content.to_string().clone(),
@@ -200,8 +300,60 @@ impl Handler for PutRoomAccountData {
     }
 }
 
+/// The GET `/user/:user_id/account_data_all_rooms` endpoint.
+///
+/// This is not part of the Matrix specification. It returns the account data of every room at
+/// once, so clients can initialize without looking it up room by room.
+#[derive(Debug)]
+pub struct GetAllRoomAccountData;
+
+#[derive(Debug, Serialize)]
+struct AllRoomAccountDataResponse {
+    rooms: HashMap<RoomId, HashMap<String, Value>>,
+}
+
+middleware_chain!(GetAllRoomAccountData, [UserIdParam, AccessTokenAuth]);
+
+impl Handler for GetAllRoomAccountData {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = authed_user(request)?;
+
+        let user_id = extension::<UserIdParam>(request)?;
+
+        if user_id != user.id {
+            let error = ApiError::unauthorized(
+                "The given user_id does not correspond to the authenticated user".to_string()
+            );
+
+            return Err(IronError::from(error));
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let mut rooms: HashMap<RoomId, HashMap<String, Value>> = HashMap::new();
+
+        for data in RoomAccountData::find_by_uid(&connection, &user_id)? {
+            let content = from_str(&data.content).map_err(ApiError::from)?;
+
+            rooms.entry(data.room_id).or_insert_with(HashMap::new).insert(data.data_type, content);
+        }
+
+        let response = AllRoomAccountDataResponse {
+            rooms: rooms,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
+
+    use ruma_identifiers::UserId;
+
+    use models::account_data::AccountData;
+    use models::user_threepid::{NewUserThreepid, UserThreepid};
     use test::Test;
     use iron::status::Status;
 
@@ -247,6 +399,68 @@ mod tests {
         );
     }
 
+    #[test]
+    fn get_threepids() {
+        let test = Test::new();
+        let user = test.create_user();
+        let user_id = UserId::try_from(user.id.as_ref()).unwrap();
+
+        let connection = test.connection();
+        let threepid = UserThreepid::create(&connection, &NewUserThreepid {
+            medium: "email".to_string(),
+            address: "carl@example.com".to_string(),
+            user_id: user_id.clone(),
+        }).unwrap();
+        threepid.validate(&connection).unwrap();
+        UserThreepid::create(&connection, &NewUserThreepid {
+            medium: "msisdn".to_string(),
+            address: "15555550123".to_string(),
+            user_id: user_id,
+        }).unwrap();
+        drop(connection);
+
+        let response = test.get(&format!("/_matrix/client/r0/account/3pid?access_token={}", user.token));
+        assert_eq!(response.status, Status::Ok);
+
+        // Both were added in the test transaction, at the same time, so they are ordered by medium.
+        let threepids = response.json().get("threepids").unwrap().as_array().unwrap();
+        assert_eq!(threepids.len(), 2);
+
+        assert_eq!(threepids[0].get("medium").unwrap().as_str().unwrap(), "email");
+        assert_eq!(threepids[0].get("address").unwrap().as_str().unwrap(), "carl@example.com");
+        assert!(threepids[0].get("added_at").unwrap().is_u64());
+        assert!(threepids[0].get("validated_at").unwrap().is_u64());
+
+        assert_eq!(threepids[1].get("medium").unwrap().as_str().unwrap(), "msisdn");
+        assert!(threepids[1].get("validated_at").is_none());
+    }
+
+    #[test]
+    fn threepids_of_other_users_are_hidden() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let connection = test.connection();
+        UserThreepid::create(&connection, &NewUserThreepid {
+            medium: "email".to_string(),
+            address: "alice@example.com".to_string(),
+            user_id: UserId::try_from(alice.id.as_ref()).unwrap(),
+        }).unwrap();
+        drop(connection);
+
+        let response = test.get(&format!("/_matrix/client/r0/account/3pid?access_token={}", bob.token));
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("threepids").unwrap().as_array().unwrap().is_empty());
+
+        let response = test.get("/_matrix/client/r0/account/3pid");
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_MISSING_TOKEN"
+        );
+    }
+
     #[test]
     fn update_account_data() {
         let test = Test::new();
@@ -287,7 +501,7 @@ mod tests {
         assert_eq!(response.status, Status::BadRequest);
         assert_eq!(
             response.json().get("errcode").unwrap().as_str().unwrap(),
-            "IO_RUMA_INVALID_PARAM"
+            "M_INVALID_PARAM"
         );
         assert_eq!(
             response.json().get("error").unwrap().as_str().unwrap(),
@@ -307,6 +521,69 @@ mod tests {
         );
     }
 
+    #[test]
+    fn delete_account_data() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let data_type = "org.matrix.personal.config";
+        let account_data_path = format!(
+            "/_matrix/client/r0/user/{}/account_data/{}?access_token={}",
+            user.id, data_type, user.token
+        );
+
+        let response = test.put(&account_data_path, r#"{"email": "user@email.com"}"#);
+        test.check_empty_response(response);
+
+        let response = test.delete(&account_data_path);
+        test.check_empty_response(response);
+
+        let connection = test.connection();
+        let user_id = UserId::try_from(user.id.as_ref()).unwrap();
+        let data = AccountData::find_by_uid_and_type(&connection, &user_id, data_type).unwrap();
+        assert_eq!(data.content, "{}");
+
+        // Already deleted.
+        assert_eq!(test.delete(&account_data_path).status, Status::NotFound);
+    }
+
+    #[test]
+    fn delete_missing_account_data() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let account_data_path = format!(
+            "/_matrix/client/r0/user/{}/account_data/org.matrix.personal.config?access_token={}",
+            user.id, user.token
+        );
+
+        assert_eq!(test.delete(&account_data_path).status, Status::NotFound);
+    }
+
+    #[test]
+    fn delete_account_data_of_other_user() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let data_type = "org.matrix.personal.config";
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/user/{}/account_data/{}?access_token={}",
+                alice.id, data_type, alice.token
+            ),
+            r#"{"email": "user@email.com"}"#,
+        );
+        test.check_empty_response(response);
+
+        let response = test.delete(&format!(
+            "/_matrix/client/r0/user/{}/account_data/{}?access_token={}",
+            alice.id, data_type, bob.token
+        ));
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
     #[test]
     fn update_room_account_data() {
         let test = Test::new();
@@ -376,7 +653,7 @@ mod tests {
         assert_eq!(response.status, Status::BadRequest);
         assert_eq!(
             response.json().get("errcode").unwrap().as_str().unwrap(),
-            "IO_RUMA_INVALID_PARAM"
+            "M_INVALID_PARAM"
         );
         assert_eq!(
             response.json().get("error").unwrap().as_str().unwrap(),
@@ -406,5 +683,58 @@ mod tests {
             "No membership entry was found."
         );
     }
-}
 
+    #[test]
+    fn get_account_data_of_all_rooms() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let first_room_id = alice.create_room(&test, "{}");
+        let second_room_id = alice.create_room(&test, "{}");
+
+        let put_room_account_data = |room_id: &str, data_type: &str, content: &str| {
+            let response = test.put_as(
+                &alice,
+                &format!(
+                    "/_matrix/client/r0/user/{}/rooms/{}/account_data/{}",
+                    alice.id, room_id, data_type
+                ),
+                content,
+            );
+            assert_eq!(response.status, Status::Ok);
+        };
+
+        put_room_account_data(&first_room_id, "org.example.color", r#"{"color": "yellow"}"#);
+        put_room_account_data(&first_room_id, "org.example.muted", r#"{"muted": true}"#);
+        put_room_account_data(&second_room_id, "org.example.color", r#"{"color": "blue"}"#);
+
+        let response = test.get_as(
+            &alice,
+            &format!("/_matrix/client/r0/user/{}/account_data_all_rooms", alice.id),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let rooms = response.json().get("rooms").unwrap();
+        let content = |room_id: &str, data_type: &str, key: &str| {
+            rooms.pointer(&format!("/{}/{}/{}", room_id, data_type, key)).unwrap().clone()
+        };
+
+        assert_eq!(rooms.as_object().unwrap().len(), 2);
+        assert_eq!(content(&first_room_id, "org.example.color", "color"), "yellow");
+        assert_eq!(content(&first_room_id, "org.example.muted", "muted").as_bool(), Some(true));
+        assert_eq!(content(&second_room_id, "org.example.color", "color"), "blue");
+    }
+
+    #[test]
+    fn get_account_data_of_all_rooms_of_other_user() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let bob = test.register("bob");
+
+        let response = test.get_as(
+            &bob,
+            &format!("/_matrix/client/r0/user/{}/account_data_all_rooms", alice.id),
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
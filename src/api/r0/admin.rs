@@ -0,0 +1,181 @@
+//! Endpoints for server administration.
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+use serde_json::Value;
+
+use config::Config;
+use db::DB;
+use error::ApiError;
+use federation::sender::dropped_deliveries;
+use middleware::{AccessTokenAuth, AdminAuth, MiddlewareChain};
+use models::background_job::Job;
+use modifier::SerializableResponse;
+use notifier::Notifier;
+use shutdown::Shutdown;
+use super::milliseconds_since_epoch;
+
+/// The GET `/admin/background_jobs` endpoint.
+pub struct GetBackgroundJobs;
+
+#[derive(Debug, Serialize)]
+struct GetBackgroundJobsResponse {
+    /// The jobs in the queue, including dead-lettered ones.
+    jobs: Vec<BackgroundJob>,
+    /// The number of federation deliveries dropped since the server started because their
+    /// destination already had too many events queued.
+    dropped_federation_events: usize,
+}
+
+/// A job in the background job queue.
+#[derive(Debug, Serialize)]
+struct BackgroundJob {
+    /// The job's ID.
+    id: i64,
+    /// The kind of the job.
+    kind: String,
+    /// The input for the job's handler.
+    payload: Value,
+    /// The earliest time the job may run, in milliseconds since the Unix epoch.
+    run_at: u64,
+    /// The number of times a worker has claimed the job.
+    attempts: i32,
+    /// The ID of the worker currently running the job.
+    locked_by: Option<String>,
+    /// The error of the last failed attempt.
+    last_error: Option<String>,
+    /// Whether or not the job failed too many times to be retried.
+    dead: bool,
+}
+
+middleware_chain!(GetBackgroundJobs, [AccessTokenAuth, AdminAuth]);
+
+impl Handler for GetBackgroundJobs {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let connection = DB::from_request(request)?;
+
+        let jobs = Job::all(&connection)?
+            .into_iter()
+            .map(|job| {
+                Ok(BackgroundJob {
+                    payload: job.payload()?,
+                    run_at: milliseconds_since_epoch(job.run_at)?,
+                    id: job.id,
+                    kind: job.kind,
+                    attempts: job.attempts,
+                    locked_by: job.locked_by,
+                    last_error: job.last_error,
+                    dead: job.dead,
+                })
+            })
+            .collect::<Result<Vec<BackgroundJob>, ApiError>>()?;
+
+        let response = GetBackgroundJobsResponse {
+            jobs: jobs,
+            dropped_federation_events: dropped_deliveries(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/admin/workers` endpoint.
+pub struct GetWorkers;
+
+#[derive(Debug, Serialize)]
+struct GetWorkersResponse {
+    /// The configured number of threads for requests other than waiting syncs.
+    worker_threads: usize,
+    /// The configured number of syncs that may wait for new events at once.
+    sync_workers: usize,
+    /// The number of threads handling a request other than a waiting sync, including this one.
+    busy_workers: usize,
+    /// The number of syncs currently waiting for new events.
+    waiting_syncs: usize,
+}
+
+middleware_chain!(GetWorkers, [AccessTokenAuth, AdminAuth]);
+
+impl Handler for GetWorkers {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+        let notifier = Notifier::from_request(request)?;
+        let shutdown = Shutdown::from_request(request)?;
+
+        let response = GetWorkersResponse {
+            worker_threads: config.worker_threads,
+            sync_workers: config.sync_workers,
+            busy_workers: shutdown.busy_workers(),
+            waiting_syncs: notifier.waiting(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use iron::status::Status;
+    use serde_json::from_str;
+
+    use models::background_job::Job;
+    use test::Test;
+
+    #[test]
+    fn admin_can_list_background_jobs() {
+        let test = Test::new();
+        let admin = test.create_admin();
+
+        {
+            let connection = test.connection();
+
+            Job::enqueue(
+                &connection,
+                "test.noop",
+                &from_str(r#"{"key": "value"}"#).unwrap(),
+                SystemTime::now(),
+            ).unwrap();
+        }
+
+        let response = test.get(
+            &format!("/_matrix/client/r0/admin/background_jobs?access_token={}", admin.token)
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let jobs = response.json().get("jobs").unwrap().as_array().unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].get("kind").unwrap().as_str().unwrap(), "test.noop");
+        assert_eq!(jobs[0].pointer("/payload/key").unwrap().as_str().unwrap(), "value");
+        assert_eq!(jobs[0].get("dead").unwrap().as_bool().unwrap(), false);
+    }
+
+    #[test]
+    fn non_admin_cannot_list_background_jobs() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.get(
+            &format!("/_matrix/client/r0/admin/background_jobs?access_token={}", alice.token)
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn admin_can_see_busy_workers() {
+        let test = Test::with_config(|config| config.worker_threads = 4);
+        let admin = test.create_admin();
+
+        let response = test.get(
+            &format!("/_matrix/client/r0/admin/workers?access_token={}", admin.token)
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("worker_threads").unwrap().as_u64().unwrap(), 4);
+        assert_eq!(response.json().get("busy_workers").unwrap().as_u64().unwrap(), 1);
+        assert_eq!(response.json().get("waiting_syncs").unwrap().as_u64().unwrap(), 0);
+    }
+}
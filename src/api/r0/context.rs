@@ -0,0 +1,283 @@
+//! Endpoint for retrieving the events around an event.
+
+use std::convert::TryInto;
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+use ruma_events::collections::all::{RoomEvent, StateEvent};
+use ruma_identifiers::EventId;
+
+use config::Config;
+use db::DB;
+use error::ApiError;
+use identifiers;
+use middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use models::event::{Event, PaginationDirection};
+use models::room_membership::RoomMembership;
+use modifier::SerializableResponse;
+use query::{Batch, room_event};
+use query_params;
+use request_ext::{authed_user, extension, path_param};
+use state_cache::StateCache;
+
+/// The number of events around the context event returned if the request doesn't set a limit.
+const DEFAULT_LIMIT: u64 = 10;
+
+/// The GET `/rooms/:room_id/context/:event_id` endpoint.
+///
+/// Returns up to `limit` events around the given event, split evenly between the events before
+/// and after it. If one side has fewer events than its share, the rest of the limit goes to the
+/// other side. The `state` is the state of the room at the context event.
+pub struct Context;
+
+#[derive(Debug, Serialize)]
+struct ContextResponse {
+    /// A token to paginate backwards from the first event in `events_before`.
+    start: String,
+    /// A token to paginate forwards from the last event in `events_after`.
+    end: String,
+    /// The events before the context event, most recent first.
+    events_before: Vec<RoomEvent>,
+    /// The context event.
+    event: RoomEvent,
+    /// The events after the context event, oldest first.
+    events_after: Vec<RoomEvent>,
+    /// The state of the room at the context event.
+    state: Vec<StateEvent>,
+}
+
+middleware_chain!(Context, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for Context {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = authed_user(request)?;
+        let room_id = extension::<RoomIdParam>(request)?;
+
+        let event_id = path_param(request, "event_id")?;
+        let event_id = identifiers::parse::<EventId>("event_id", &event_id)?;
+
+        let config = Config::from_request(request)?;
+
+        let limit = query_params::get_u64(
+            request,
+            "limit",
+            DEFAULT_LIMIT,
+            config.max_pagination_limit,
+        )? as i64;
+
+        let connection = DB::from_request(request)?;
+
+        let state_cache = StateCache::from_request(request)?;
+
+        RoomMembership::require(&connection, &state_cache, &room_id, &user.id, &["join"])?;
+
+        let event = match Event::find(&connection, &event_id)? {
+            Some(event) if event.room_id == room_id && !event.soft_failed => event,
+            _ => Err(ApiError::not_found("The event was not found in the room.".to_string()))?,
+        };
+
+        // Both sides are fetched with the whole limit, so that a side with fewer events than its
+        // share can hand the rest over to the other side.
+        let mut before = Event::find_room_events_page(
+            &connection,
+            &room_id,
+            event.ordering - 1,
+            None,
+            PaginationDirection::Backward,
+            limit,
+            None,
+        )?;
+        let mut after = Event::find_room_events_page(
+            &connection,
+            &room_id,
+            event.ordering,
+            None,
+            PaginationDirection::Forward,
+            limit,
+            None,
+        )?;
+
+        let (before_count, after_count) = split_limit(limit, before.len(), after.len());
+        before.truncate(before_count);
+        after.truncate(after_count);
+
+        let start_key = before.last().map_or(event.ordering, |event| event.ordering) - 1;
+        let end_key = after.last().map_or(event.ordering, |event| event.ordering);
+
+        let state = Event::get_room_state_events_until(&connection, &room_id, &event)?;
+
+        let mut state_events: Vec<StateEvent> = Vec::with_capacity(state.len());
+
+        for state_event in state {
+            state_events.push(state_event.try_into()?);
+        }
+
+        let response = ContextResponse {
+            start: Batch::new(start_key, 0).to_string(),
+            end: Batch::new(end_key, 0).to_string(),
+            events_before: room_events(before)?,
+            event: match room_event(event)? {
+                Some(event) => event,
+                None => Err(ApiError::not_found(
+                    "The event was not found in the room.".to_string()
+                ))?,
+            },
+            events_after: room_events(after)?,
+            state: state_events,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The number of events to return before and after the context event, given how many are
+/// available on each side.
+///
+/// The limit is split evenly, with the odd event going after the context event. A side with
+/// fewer events than its share gives the remainder to the other side.
+fn split_limit(limit: i64, available_before: usize, available_after: usize) -> (usize, usize) {
+    let limit = limit as usize;
+    let half_before = limit / 2;
+    let half_after = limit - half_before;
+
+    if available_before < half_before {
+        let after = limit - available_before;
+        (available_before, if available_after < after { available_after } else { after })
+    } else if available_after < half_after {
+        let before = limit - available_after;
+        (if available_before < before { available_before } else { before }, available_after)
+    } else {
+        (half_before, half_after)
+    }
+}
+
+/// Converts the events to room events, skipping those of unsupported types.
+fn room_events(events: Vec<Event>) -> Result<Vec<RoomEvent>, ApiError> {
+    let mut room_events = Vec::with_capacity(events.len());
+
+    for event in events {
+        if let Some(event) = room_event(event)? {
+            room_events.push(event);
+        }
+    }
+
+    Ok(room_events)
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::{Response, Test};
+
+    use super::split_limit;
+
+    fn context(test: &Test, access_token: &str, room_id: &str, event_id: &str, limit: u64)
+    -> Response {
+        test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/context/{}?limit={}&access_token={}",
+            room_id,
+            event_id,
+            limit,
+            access_token
+        ))
+    }
+
+    fn event_id(response: &Response) -> String {
+        response.json().get("event_id").unwrap().as_str().unwrap().to_string()
+    }
+
+    /// The message bodies of the events in the given field of the response.
+    fn bodies(response: &Response, field: &str) -> Vec<String> {
+        response.json().get(field).unwrap().as_array().unwrap().iter().filter_map(|event| {
+            event.get("content").unwrap().get("body").and_then(Value::as_str)
+                .map(|body| body.to_string())
+        }).collect()
+    }
+
+    #[test]
+    fn limit_is_split_evenly() {
+        assert_eq!(split_limit(10, 10, 10), (5, 5));
+        assert_eq!(split_limit(11, 10, 10), (5, 6));
+        assert_eq!(split_limit(10, 2, 10), (2, 8));
+        assert_eq!(split_limit(10, 10, 1), (9, 1));
+        assert_eq!(split_limit(10, 2, 3), (2, 3));
+        assert_eq!(split_limit(0, 2, 3), (0, 0));
+    }
+
+    #[test]
+    fn events_around_the_context_event() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let mut event_ids = Vec::new();
+
+        for i in 0..7 {
+            let response = test.send_message(&alice.token, &room_id, &i.to_string(), i);
+            event_ids.push(event_id(&response));
+        }
+
+        let response = context(&test, &alice.token, &room_id, &event_ids[3], 4);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(bodies(&response, "events_before"), vec!["2", "1"]);
+        assert_eq!(bodies(&response, "events_after"), vec!["4", "5"]);
+        assert_eq!(
+            response.json().pointer("/event/content/body").unwrap().as_str().unwrap(),
+            "3"
+        );
+    }
+
+    #[test]
+    fn remainder_goes_to_the_other_side() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let mut event_ids = Vec::new();
+
+        for i in 0..7 {
+            let response = test.send_message(&alice.token, &room_id, &i.to_string(), i);
+            event_ids.push(event_id(&response));
+        }
+
+        let response = context(&test, &alice.token, &room_id, &event_ids[5], 4);
+
+        assert_eq!(bodies(&response, "events_before"), vec!["4", "3", "2"]);
+        assert_eq!(bodies(&response, "events_after"), vec!["6"]);
+    }
+
+    #[test]
+    fn state_is_at_the_context_event() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        test.send_state_event(&alice.token, &room_id, "m.room.topic", r#"{"topic":"Rust"}"#);
+        let response = test.send_message(&alice.token, &room_id, "hello", 1);
+        let event_id = event_id(&response);
+        test.send_state_event(&alice.token, &room_id, "m.room.topic", r#"{"topic":"Go"}"#);
+
+        let response = context(&test, &alice.token, &room_id, &event_id, 10);
+
+        let topics: Vec<&str> = response.json().get("state").unwrap().as_array().unwrap().iter()
+            .filter(|event| event.get("type").unwrap() == "m.room.topic")
+            .map(|event| event.pointer("/content/topic").unwrap().as_str().unwrap())
+            .collect();
+
+        assert_eq!(topics, vec!["Rust"]);
+    }
+
+    #[test]
+    fn event_from_another_room_is_not_found() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+        let other_room_id = test.create_room(&alice.token);
+
+        let response = test.send_message(&alice.token, &other_room_id, "hello", 1);
+        let event_id = event_id(&response);
+
+        let response = context(&test, &alice.token, &room_id, &event_id, 10);
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+}
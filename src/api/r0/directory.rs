@@ -1,19 +1,37 @@
 //! Endpoints for managing room aliases.
 
+use std::collections::HashSet;
+
 use bodyparser;
 use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
 use iron::status::Status;
-use ruma_identifiers::RoomId;
+use ruma_identifiers::{RoomAliasId, RoomId};
+use serde_json::{Value, from_str};
 
+use clock;
 use config::Config;
 use db::DB;
 use error::ApiError;
-use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomAliasIdParam};
+use identifiers;
+use federation::directory::{find_room_aliases, resolve_remote_alias};
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomAliasIdParam, RoomIdParam};
+use models::event::Event;
 use models::room_alias::{RoomAlias, NewRoomAlias};
-use models::user::User;
+use models::room_membership::RoomMembership;
 use modifier::{SerializableResponse, EmptyResponse};
+use request_ext::{authed_user, extension};
+use state_cache::StateCache;
+
+/// The event type marking a room as replaced by an upgraded one.
+const TOMBSTONE_EVENT_TYPE: &'static str = "m.room.tombstone";
 
 /// The GET `/directory/room/:room_alias` endpoint.
+///
+/// Aliases of other servers are resolved by querying their server over federation. The answer is
+/// cached for `remote_alias_cache_ttl` seconds.
+///
+/// If the room has been upgraded, the response names the room that replaced it, as set by the
+/// room's `m.room.tombstone` event.
 pub struct GetRoomAlias;
 
 #[derive(Debug, Serialize)]
@@ -22,22 +40,48 @@ struct GetRoomAliasResponse {
     room_id: RoomId,
     /// A list of servers that are aware of this room ID.
     servers: Vec<String>,
+    /// The ID of the room that replaced the room, if it has been upgraded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replacement_room: Option<String>,
 }
 
 middleware_chain!(GetRoomAlias, [RoomAliasIdParam]);
 
 impl Handler for GetRoomAlias {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let room_alias_id = request.extensions.get::<RoomAliasIdParam>()
-            .expect("RoomAliasIdParam should ensure a RoomAliasId").clone();
+        let room_alias_id = extension::<RoomAliasIdParam>(request)?;
 
+        let config = Config::from_request(request)?;
         let connection = DB::from_request(request)?;
 
-        let room_alias = RoomAlias::find_by_alias(&connection, &room_alias_id)?;
+        let (room_id, servers) = if room_alias_id.hostname().to_string() == config.domain {
+            let room_alias = RoomAlias::find_by_alias(&connection, &room_alias_id)?;
+
+            (room_alias.room_id, room_alias.servers)
+        } else {
+            let remote_alias = resolve_remote_alias(&connection, &config, &room_alias_id, None)?;
+
+            (remote_alias.room_id, remote_alias.servers)
+        };
+
+        let tombstone = Event::find_current_state_event(
+            &connection,
+            &room_id,
+            TOMBSTONE_EVENT_TYPE,
+            "",
+        )?;
+
+        let replacement_room = tombstone.and_then(|event| {
+            from_str::<Value>(&event.content).ok()
+                .and_then(|content| {
+                    content.get("replacement_room").and_then(Value::as_str).map(str::to_string)
+                })
+        });
 
         let response = GetRoomAliasResponse {
-            room_id: room_alias.room_id,
-            servers: room_alias.servers,
+            room_id: room_id,
+            servers: servers,
+            replacement_room: replacement_room,
         };
 
         Ok(Response::with((Status::Ok, SerializableResponse(response))))
@@ -45,21 +89,22 @@ impl Handler for GetRoomAlias {
 }
 
 /// The DELETE `/directory/room/:room_alias` endpoint.
+///
+/// Aliases can be deleted by the user who created them and by the admins of the aliased room.
 pub struct DeleteRoomAlias;
 
 middleware_chain!(DeleteRoomAlias, [RoomAliasIdParam, AccessTokenAuth]);
 
 impl Handler for DeleteRoomAlias {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let room_alias_id = request.extensions.get::<RoomAliasIdParam>()
-            .expect("RoomAliasIdParam should ensure a RoomAliasId").clone();
+        let room_alias_id = extension::<RoomAliasIdParam>(request)?;
 
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
         let connection = DB::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
 
-        let affected_rows = RoomAlias::delete(&connection, &room_alias_id, &user.id)?;
+        let affected_rows = RoomAlias::delete(&connection, &state_cache, &room_alias_id, &user.id)?;
 
         if affected_rows > 0 {
             Ok(Response::with(EmptyResponse(Status::Ok)))
@@ -86,18 +131,24 @@ impl Handler for PutRoomAlias {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
         let config = Config::from_request(request)?;
 
-        let room_alias_id = request.extensions.get::<RoomAliasIdParam>()
-            .expect("RoomAliasIdParam should ensure a RoomAliasId").clone();
+        let room_alias_id = extension::<RoomAliasIdParam>(request)?;
+
+        if room_alias_id.hostname().to_string() != config.domain {
+            Err(ApiError::invalid_param("room_alias", "Must be an alias on this server."))?;
+        }
 
         let room_id = match request.get::<bodyparser::Struct<PutRoomAliasRequest>>() {
             Ok(Some(req)) => req.room_id,
             Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
         };
 
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        identifiers::check_length("room_id", &room_id.to_string())?;
+
+        let user = authed_user(request)?;
 
         let connection = DB::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
 
         let new_room_alias = NewRoomAlias {
             alias: room_alias_id,
@@ -106,71 +157,199 @@ impl Handler for PutRoomAlias {
             servers: vec![config.domain.to_string()],
         };
 
-        RoomAlias::create(&connection, &config.domain.to_string(), &new_room_alias)?;
+        RoomAlias::create(
+            &connection,
+            &state_cache,
+            &*clock,
+            &config,
+            &new_room_alias,
+        )?;
 
         Ok(Response::with(Status::Ok))
     }
 }
 
+/// The GET `/rooms/:room_id/aliases` endpoint.
+///
+/// Besides this server's aliases, lists the aliases the other servers in the room know of, up to
+/// `max_alias_resolution_depth` hops away. See `federation::directory`.
+pub struct GetRoomAliases;
+
+#[derive(Debug, Serialize)]
+struct GetRoomAliasesResponse {
+    /// The room's aliases.
+    aliases: Vec<RoomAliasId>,
+}
+
+middleware_chain!(GetRoomAliases, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for GetRoomAliases {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = extension::<RoomIdParam>(request)?;
+
+        let user = authed_user(request)?;
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let state_cache = StateCache::from_request(request)?;
+
+        RoomMembership::require(&connection, &state_cache, &room_id, &user.id, &["join"])?;
+
+        let response = GetRoomAliasesResponse {
+            aliases: find_room_aliases(&connection, &config, &room_id, 0, &HashSet::new())?,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use test::Test;
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
     use iron::status::Status;
+    use ruma_identifiers::{RoomAliasId, RoomId};
+    use serde_json::Value;
+
+    use models::remote_alias::RemoteAlias;
+    use test::{AuthMode, Test, assert_matrix_error, json_at};
 
     #[test]
     fn get_room_alias() {
         let test = Test::new();
-        let user = test.create_user();
+        let alice = test.register("alice");
+        let room_id = alice.create_room(&test, r#"{"room_alias_name": "my_room"}"#);
+
+        let response = test.get("/_matrix/client/r0/directory/room/my_room");
+
+        assert_eq!(json_at(&response, "room_id"), &room_id);
+        assert!(json_at(&response, "servers").is_array());
+    }
+
+    #[test]
+    fn get_room_alias_of_upgraded_room() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let room_id = alice.create_room(&test, r#"{"room_alias_name": "my_room"}"#);
 
-        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
-                                       user.token);
-        let response = test.post(&create_room_path, r#"{"room_alias_name": "my_room"}"#);
-        let room_id = response.json().get("room_id").unwrap().as_str().unwrap();
+        let response = test.get("/_matrix/client/r0/directory/room/my_room");
+        assert!(response.json().get("replacement_room").is_none());
+
+        let new_room_id = alice.create_room(&test, "{}");
+        let response = test.put_as(
+            &alice,
+            &format!("/_matrix/client/r0/rooms/{}/state/m.room.tombstone", room_id),
+            &format!(
+                r#"{{"body": "This room has been replaced", "replacement_room": "{}"}}"#,
+                new_room_id
+            ),
+        );
+        assert_eq!(response.status, Status::Ok);
 
         let response = test.get("/_matrix/client/r0/directory/room/my_room");
 
-        assert_eq!(response.json().get("room_id").unwrap().as_str().unwrap(), room_id);
-        assert!(response.json().get("servers").unwrap().is_array());
+        assert_eq!(json_at(&response, "room_id"), &room_id);
+        assert_eq!(json_at(&response, "replacement_room"), &new_room_id);
     }
 
     #[test]
     fn get_unknown_room_alias() {
         let test = Test::new();
-        let user = test.create_user();
-
-        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
-                                       user.token);
-        let _ = test.post(&create_room_path, r#"{"room_alias_name": "my_room"}"#);
+        let alice = test.register("alice");
+        alice.create_room(&test, r#"{"room_alias_name": "my_room"}"#);
 
         let response = test.get("/_matrix/client/r0/directory/room/no_room");
 
-        assert_eq!(response.status, Status::NotFound);
+        assert_matrix_error(&response, Status::NotFound, "M_NOT_FOUND");
         assert_eq!(
-            response.json().get("errcode").unwrap().as_str().unwrap(),
-            "M_NOT_FOUND"
+            json_at(&response, "error"),
+            "The room alias #no_room:ruma.test was not found."
         );
     }
 
     #[test]
-    fn delete_room_alias() {
+    fn get_malformed_room_alias() {
         let test = Test::new();
-        let user = test.create_user();
 
-        let create_room_path = format!(
-            "/_matrix/client/r0/createRoom?access_token={}",
-            user.token
-        );
+        let cases = vec![
+            ("my%20room", r#""my room" is not a valid room alias"#),
+            ("%E2%80%AEmoor", r#""\u{202e}moor" is not a valid room alias"#),
+            ("%22quoted%22", r#""\"quoted\"" is not a valid room alias"#),
+            ("%23no_server", r#""#no_server" is not a valid room alias"#),
+        ];
+
+        for (alias, message) in cases {
+            let response = test.get(&format!("/_matrix/client/r0/directory/room/{}", alias));
+
+            assert_matrix_error(&response, Status::BadRequest, "M_INVALID_PARAM");
+            assert!(
+                json_at(&response, "error").as_str().unwrap().contains(message),
+                "{}",
+                response.body
+            );
+        }
+    }
+
+    #[test]
+    fn get_cached_remote_room_alias() {
+        let test = Test::new();
+
+        {
+            let connection = test.connection();
+
+            RemoteAlias::store(
+                &connection,
+                &RoomAliasId::try_from("#rust:example.com").unwrap(),
+                &RoomId::try_from("!abc:example.com").unwrap(),
+                vec!["example.com".to_string(), "example.org".to_string()],
+                Duration::from_secs(60),
+            ).unwrap();
+        }
+
+        let response = test.get("/_matrix/client/r0/directory/room/%23rust:example.com");
 
-        test.post(&create_room_path, r#"{"room_alias_name": "my_room"}"#);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(json_at(&response, "room_id"), "!abc:example.com");
+        assert_eq!(json_at(&response, "servers").as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn get_full_local_room_alias() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let room_id = alice.create_room(&test, r#"{"room_alias_name": "my_room"}"#);
+
+        let response = test.get("/_matrix/client/r0/directory/room/%23my_room:ruma.test");
+
+        assert_eq!(json_at(&response, "room_id"), &room_id);
+    }
 
-        let delete_room_path = format!(
-            "/_matrix/client/r0/directory/room/my_room?access_token={}",
-            user.token
+    #[test]
+    fn put_remote_room_alias() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let room_id = alice.create_room(&test, "{}");
+
+        let response = test.put_as(
+            &alice,
+            "/_matrix/client/r0/directory/room/%23rust:example.com",
+            &format!(r#"{{"room_id": "{}"}}"#, room_id),
         );
 
-        let delete_response = test.delete(&delete_room_path);
+        assert_eq!(response.status, Status::BadRequest);
+    }
 
-        assert_eq!(delete_response.status, Status::Ok);
+    #[test]
+    fn delete_room_alias() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        alice.create_room(&test, r#"{"room_alias_name": "my_room"}"#);
+
+        let response = test.delete_as(&alice, "/_matrix/client/r0/directory/room/my_room");
+
+        assert_eq!(response.status, Status::Ok);
 
         let response = test.get("/_matrix/client/r0/directory/room/my_room");
 
@@ -180,80 +359,166 @@ mod tests {
     #[test]
     fn delete_room_alias_from_different_user() {
         let test = Test::new();
-        let user = test.create_user();
+        let alice = test.register("alice");
+        let henry = test.register("henry");
+        alice.create_room(&test, r#"{"room_alias_name": "my_room"}"#);
 
-        let create_room_path = format!(
-            "/_matrix/client/r0/createRoom?access_token={}",
-            user.token
-        );
+        let response = test.delete_as(&henry, "/_matrix/client/r0/directory/room/my_room");
 
-        test.post(&create_room_path, r#"{"room_alias_name": "my_room"}"#);
-
-        let henry = test.create_user();
+        assert_eq!(response.status, Status::NotFound);
+    }
 
-        let delete_room_path = format!(
-            "/_matrix/client/r0/directory/room/my_room?access_token={}",
-            henry.token
+    #[test]
+    fn delete_room_alias_as_room_admin() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let bob = test.register("bob");
+        let carl = test.register("carl");
+        let room_id = alice.create_room(&test, r#"{"visibility": "public"}"#);
+
+        assert_eq!(bob.join(&test, &room_id).status, Status::Ok);
+        assert_eq!(carl.join(&test, &room_id).status, Status::Ok);
+
+        let response = test.put_as(
+            &bob,
+            "/_matrix/client/r0/directory/room/bobs_room",
+            &format!(r#"{{"room_id": "{}"}}"#, room_id),
         );
+        assert_eq!(response.status, Status::Ok);
 
-        let response = test.delete(&delete_room_path);
+        // Carl is a member of the room, but not an admin.
+        let response = test.delete_as(&carl, "/_matrix/client/r0/directory/room/bobs_room");
+        assert_eq!(response.status, Status::NotFound);
 
+        let response = test.delete_as(&alice, "/_matrix/client/r0/directory/room/bobs_room");
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get("/_matrix/client/r0/directory/room/bobs_room");
         assert_eq!(response.status, Status::NotFound);
     }
 
     #[test]
     fn put_room_alias() {
         let test = Test::new();
-        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let carl = test.register("carl");
+        let room_id = carl.create_room(&test, r#"{"visibility": "public"}"#);
 
-        let put_room_alias_path = format!(
-            "/_matrix/client/r0/directory/room/my_room?access_token={}", carl.token
+        let response = test.put_as(
+            &carl,
+            "/_matrix/client/r0/directory/room/my_room",
+            &format!(r#"{{"room_id": "{}"}}"#, room_id),
         );
-        let put_room_alias_body = format!(r#"{{"room_id": "{}"}}"#, room_id);
-        let response = test.put(&put_room_alias_path, &put_room_alias_body);
 
         assert_eq!(response.status, Status::Ok);
 
         let response = test.get("/_matrix/client/r0/directory/room/my_room");
 
-        assert_eq!(response.json().get("room_id").unwrap().as_str().unwrap(), room_id);
-        assert!(response.json().get("servers").unwrap().is_array());
+        assert_eq!(json_at(&response, "room_id"), &room_id);
+        assert!(json_at(&response, "servers").is_array());
+    }
+
+    #[test]
+    fn put_room_alias_with_query_param_auth() {
+        let test = Test::new();
+        let carl = test.register("carl").with_auth_mode(AuthMode::QueryParam);
+        let room_id = carl.create_room(&test, "{}");
+
+        let response = test.put_as(
+            &carl,
+            "/_matrix/client/r0/directory/room/my_room",
+            &format!(r#"{{"room_id": "{}"}}"#, room_id),
+        );
+
+        assert_eq!(response.status, Status::Ok);
     }
 
     #[test]
     fn put_room_alias_with_no_room() {
         let test = Test::new();
-        let user = test.create_user();
+        let alice = test.register("alice");
 
-        let put_room_alias_path = format!(
-            "/_matrix/client/r0/directory/room/my_room?access_token={}", user.token
+        let response = test.put_as(
+            &alice,
+            "/_matrix/client/r0/directory/room/my_room",
+            r#"{"room_id": "!nonexistent:ruma.test"}"#,
         );
-        let put_room_alias_body = r#"{"room_id": "!nonexistent:ruma.test"}"#;
-        let response = test.put(&put_room_alias_path, &put_room_alias_body);
 
-        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(response.status, Status::BadRequest);
     }
 
     #[test]
     fn put_existing_room_alias() {
         let test = Test::new();
-        let user = test.create_user();
+        let alice = test.register("alice");
+        let room_id = alice.create_room(&test, r#"{"room_alias_name": "my_room"}"#);
+
+        let response = test.put_as(
+            &alice,
+            "/_matrix/client/r0/directory/room/my_room",
+            &format!(r#"{{"room_id": "{}"}}"#, room_id),
+        );
+
+        assert_matrix_error(&response, Status::Conflict, "IO_RUMA_ALIAS_TAKEN");
+    }
+
+    #[test]
+    fn put_invalid_room_alias() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let room_id = alice.create_room(&test, "{}");
+        let body = format!(r#"{{"room_id": "{}"}}"#, room_id);
+
+        for alias in &["my%20room", "my%00room", "caf%C3%A9", "%E2%80%AEmoor"] {
+            let response = test.put_as(
+                &alice,
+                &format!("/_matrix/client/r0/directory/room/{}", alias),
+                &body,
+            );
+
+            assert_matrix_error(&response, Status::BadRequest, "M_INVALID_PARAM");
+        }
+    }
 
-        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
-                                       user.token);
-        let response = test.post(&create_room_path, r#"{"room_alias_name": "my_room"}"#);
-        let room_id = response.json().get("room_id").unwrap().as_str().unwrap();
+    #[test]
+    fn room_alias_length_is_limited() {
+        let test = Test::with_config(|config| config.max_alias_length = 8);
+        let alice = test.register("alice");
+
+        let response = test.post_as(
+            &alice,
+            "/_matrix/client/r0/createRoom",
+            r#"{"room_alias_name": "too_long_alias"}"#,
+        );
+        assert_eq!(response.status, Status::BadRequest);
 
-        let put_room_alias_path = format!(
-            "/_matrix/client/r0/directory/room/my_room?access_token={}", user.token
+        let response = test.post_as(
+            &alice,
+            "/_matrix/client/r0/createRoom",
+            r#"{"room_alias_name": "short"}"#,
         );
-        let put_room_alias_body = format!(r#"{{"room_id": "{}"}}"#, room_id);
-        let response = test.put(&put_room_alias_path, &put_room_alias_body);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn get_room_aliases() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let bob = test.register("bob");
+        let room_id = alice.create_room(&test, r#"{"room_alias_name": "my_room"}"#);
 
-        assert_eq!(response.status, Status::Conflict);
+        let aliases_path = format!("/_matrix/client/r0/rooms/{}/aliases", room_id);
+        let response = test.get_as(&alice, &aliases_path);
+
+        assert_eq!(response.status, Status::Ok);
         assert_eq!(
-            response.json().get("errcode").unwrap().as_str().unwrap(),
-            "IO_RUMA_ALIAS_TAKEN"
+            response.json().get("aliases").unwrap().as_array().unwrap(),
+            &vec![Value::String("#my_room:ruma.test".to_string())]
         );
+
+        let response = test.get_as(&bob, &aliases_path);
+        let missing = test.get_as(&bob, "/_matrix/client/r0/rooms/!missing:ruma.test/aliases");
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(response.raw_body, missing.raw_body);
     }
 }
@@ -1,12 +1,13 @@
 //! Endpoints for creating events.
 
-use std::convert::TryInto;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::mem::replace;
+use std::time::Duration;
 
 use bodyparser;
-use diesel::{Connection, ExecuteDsl, insert};
 use diesel::pg::PgConnection;
-use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response, status};
-use router::Router;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response, status};
 use ruma_events::call::answer::AnswerEvent;
 use ruma_events::call::candidates::CandidatesEvent;
 use ruma_events::call::hangup::HangupEvent;
@@ -18,17 +19,21 @@ use ruma_events::room::history_visibility::HistoryVisibilityEvent;
 use ruma_events::room::join_rules::JoinRulesEvent;
 use ruma_events::room::message::MessageEvent;
 use ruma_events::room::name::NameEvent;
-use ruma_events::room::power_levels::PowerLevelsEvent;
+use ruma_events::room::power_levels::{PowerLevelsEvent, PowerLevelsEventContent};
 use ruma_events::room::third_party_invite::ThirdPartyInviteEvent;
 use ruma_events::room::topic::TopicEvent;
 use ruma_events::{CustomRoomEvent, CustomStateEvent, EventType};
-use ruma_identifiers::{RoomId, EventId};
+use ruma_identifiers::{EventId, RoomAliasId, RoomId, UserId};
 use serde::Deserialize;
 use serde_json::{Value, from_str, from_value, to_string};
 
-use db::DB;
+use clock;
+use db::{DB, transaction_with_retry};
 use config::Config;
+use content_validation;
 use error::{ApiError, MapApiError};
+use federation::directory::resolve_remote_alias;
+use ids::{generate_event_id, with_unique_id};
 use middleware::{
     AccessTokenAuth,
     EventTypeParam,
@@ -36,15 +41,25 @@ use middleware::{
     MiddlewareChain,
     RoomIdParam,
     TransactionIdParam,
+    TransactionIdempotency,
 };
 use models::access_token::AccessToken;
 use models::event::NewEvent;
+use models::event_batch::EventBatch;
+use models::group::RELATED_GROUPS_EVENT_TYPE;
 use models::room::Room;
+use models::room_alias::RoomAlias;
 use models::room_membership::RoomMembership;
+use models::room_state::RoomState;
 use models::transaction::Transaction;
 use models::user::User;
 use modifier::SerializableResponse;
-use schema::events;
+use request_ext::{authed_user, extension, params};
+use state_cache::StateCache;
+use text_validation;
+
+/// How long to wait for another server to resolve an alias set as canonical alias.
+const CANONICAL_ALIAS_LOOKUP_TIMEOUT_SECS: u64 = 5;
 
 macro_rules! room_event {
     (
@@ -98,99 +113,83 @@ struct EventResponse {
 /// The `/rooms/:room_id/send/:event_type/:transaction_id` endpoint.
 pub struct SendMessageEvent;
 
-middleware_chain!(SendMessageEvent, [JsonRequest, RoomIdParam, EventTypeParam, TransactionIdParam, AccessTokenAuth]);
+impl MiddlewareChain for SendMessageEvent {
+    fn chain() -> Chain {
+        let mut chain = Chain::new(SendMessageEvent);
+
+        chain.link_before(JsonRequest);
+        chain.link_before(RoomIdParam);
+        chain.link_before(EventTypeParam);
+        chain.link_before(TransactionIdParam);
+        chain.link_before(AccessTokenAuth);
+        chain.link_around(TransactionIdempotency);
+
+        chain
+    }
+}
 
 impl Handler for SendMessageEvent {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let room_id = request.extensions.get::<RoomIdParam>()
-            .expect("Should have been required by RoomIdParam.").clone();
+        let room_id = extension::<RoomIdParam>(request)?;
 
-        let event_type = request.extensions.get::<EventTypeParam>()
-            .expect("EventTypeParam should ensure an EventType").clone();
+        let event_type = extension::<EventTypeParam>(request)?;
 
-        request.extensions.get::<TransactionIdParam>()
-            .expect("TransactionIdParam should ensure a TransactionId").clone();
+        extension::<TransactionIdParam>(request)?;
 
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
         let event_content = request
             .get::<bodyparser::Json>()
             .expect("JsonRequest verifies the Result is Ok")
             .expect("JsonRequest verifies the Option is Some");
+        let event_content = content_validation::validate(&event_type, event_content)?;
+        let event_content = text_validation::strip_nul_characters(event_content);
         let config = Config::from_request(request)?;
-        let event_id = EventId::new(&config.domain).map_api_err(|_| {
-            ApiError::unknown("Failed to generated event ID for the new event.".to_string())
-        })?;
-
-        let room_event: NewEvent = match event_type {
-            EventType::CallAnswer => {
-                room_event!(AnswerEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::CallCandidates => {
-                room_event!(CandidatesEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::CallHangup => {
-                room_event!(HangupEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::CallInvite => {
-                room_event!(InviteEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::RoomMessage => {
-                room_event!(MessageEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::Custom(ref custom_event_type) => {
-                CustomRoomEvent {
-                    content: event_content,
-                    event_id: event_id.clone(),
-                    event_type: EventType::Custom(custom_event_type.clone()),
-                    room_id: room_id.clone(),
-                    unsigned: None,
-                    user_id: user.id.clone(),
-                }.try_into().map_err(ApiError::from)?
-            }
-            _ => {
-                let error = ApiError::bad_event(
-                    format!("Events of type {} cannot be created with this API.", event_type)
-                );
-
-                return Err(IronError::from(error));
-            }
-        };
-
         let connection = DB::from_request(request)?;
-
-        let path = request.url.path().join("/").to_string();
-        let token = (*request.extensions.get::<AccessToken>()
-            .expect("AccessTokenAuth should ensure an access token")).clone();
-
-        if let Some(transaction) = Transaction::find(&connection, &path, &token.value)? {
-            let response: EventResponse = from_str(&transaction.response).map_err(ApiError::from)?;
-            return Ok(Response::with((status::Ok, SerializableResponse(response))));
-        }
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
+
+        let room_version = RoomState::current(&connection, &state_cache, &room_id)?
+            .room_version()?;
+        let event_id = generate_event_id(&config.domain, &room_version)?;
+
+        let room_event = new_room_event(&event_type, event_content, &event_id, &room_id, &user)?;
+
+        let remote_aliases = resolve_remote_aliases(&connection, &config, &[&room_event])?;
+
+        let mut next_event_id = Some(event_id);
+
+        let event_id = with_unique_id(
+            || match next_event_id.take() {
+                Some(event_id) => Ok(event_id),
+                None => generate_event_id(&config.domain, &room_version),
+            },
+            |event_id| {
+                let mut room_event = room_event.clone();
+                room_event.id = event_id.clone();
+
+                transaction_with_retry(&connection, || {
+                    verify_event(
+                        &connection,
+                        &state_cache,
+                        &room_id,
+                        &user,
+                        &event_type,
+                        &room_event,
+                        &remote_aliases,
+                    )?;
+
+                    room_event.save(&connection, &*clock)
+                })?;
+
+                Ok(event_id)
+            },
+        )?;
 
         let response = EventResponse {
             event_id: event_id.opaque_id().to_string(),
         };
 
-        connection.transaction(|| {
-            verify_permissions(&connection, &room_id, &user, &event_type)?;
-
-            insert(&room_event)
-                .into(events::table)
-                .execute(&*connection)
-                .map_err(ApiError::from)?;
-
-            let serialized_response = to_string(&response).map_err(ApiError::from)?;
-
-            Transaction::create(
-                &connection,
-                path.clone(),
-                token.value.clone(),
-                serialized_response,
-            )
-        }).map_err(ApiError::from)?;
-
         Ok(Response::with((status::Ok, SerializableResponse(response))))
     }
 }
@@ -203,190 +202,569 @@ middleware_chain!(StateMessageEvent, [JsonRequest, RoomIdParam, EventTypeParam,
 
 impl Handler for StateMessageEvent {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let params = request.extensions.get::<Router>().expect("Params object is missing").clone();
+        let params = params(request)?.clone();
 
-        let room_id = request.extensions.get::<RoomIdParam>()
-            .expect("Should have been required by RoomIdParam.")
-            .clone();
+        let room_id = extension::<RoomIdParam>(request)?;
 
-        let event_type = request.extensions.get::<EventTypeParam>()
-            .expect("EventTypeParam should ensure an EventType").clone();
+        let event_type = extension::<EventTypeParam>(request)?;
 
         let state_key = params
             .find("state_key")
             .unwrap_or("");
 
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
         let event_content = request
             .get::<bodyparser::Json>()
             .expect("JsonRequest verifies the Result is Ok")
             .expect("JsonRequest verifies the Option is Some");
+        let event_content = content_validation::validate(&event_type, event_content)?;
+        let event_content = text_validation::strip_nul_characters(event_content);
         let config = Config::from_request(request)?;
-        let event_id = EventId::new(&config.domain).map_api_err(|_| {
-            ApiError::unknown("Failed to generated event ID for the new event.".to_string())
-        })?;
+        let connection = DB::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
+
+        let room_version = RoomState::current(&connection, &state_cache, &room_id)?
+            .room_version()?;
+        let event_id = generate_event_id(&config.domain, &room_version)?;
+
+        let state_event = new_state_event(
+            &event_type,
+            event_content,
+            &event_id,
+            &room_id,
+            state_key,
+            &user,
+        )?;
+
+        let remote_aliases = resolve_remote_aliases(&connection, &config, &[&state_event])?;
+
+        let mut next_event_id = Some(event_id);
+
+        let event_id = with_unique_id(
+            || match next_event_id.take() {
+                Some(event_id) => Ok(event_id),
+                None => generate_event_id(&config.domain, &room_version),
+            },
+            |event_id| {
+                let mut state_event = state_event.clone();
+                state_event.id = event_id.clone();
+
+                transaction_with_retry(&connection, || {
+                    verify_event(
+                        &connection,
+                        &state_cache,
+                        &room_id,
+                        &user,
+                        &event_type,
+                        &state_event,
+                        &remote_aliases,
+                    )?;
+
+                    state_event.save(&connection, &*clock)
+                })?;
+
+                Ok(event_id)
+            },
+        )?;
+
+        state_cache.invalidate(&room_id);
 
-        let state_event: NewEvent = match event_type {
-            EventType::RoomAvatar => {
-                ensure_empty_state_key(state_key, &event_type)?;
+        let response = EventResponse {
+            event_id: event_id.opaque_id().to_string(),
+        };
 
-                state_event!(
-                    AvatarEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomCanonicalAlias => {
-                ensure_empty_state_key(state_key, &event_type)?;
+        Ok(Response::with((status::Ok, SerializableResponse(response))))
+    }
+}
 
-                state_event!(
-                    CanonicalAliasEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomGuestAccess => {
-                ensure_empty_state_key(state_key, &event_type)?;
+/// The POST `/rooms/:room_id/batch_send` endpoint.
+///
+/// Not part of the Matrix spec. Sends up to `max_batch_send_events` events to a room at once, for
+/// bots and bridges that create many events quickly. Either all of the events are sent or none.
+/// The permissions for all of them are checked against the state of the room before the batch.
+///
+/// Each event is deduplicated like an event sent with a transaction ID, with the `txn_id` of the
+/// batch and the event's position in it in place of the transaction ID. A repeated batch gets the
+/// IDs of the events already sent, and only events added to the end of it are sent.
+pub struct BatchSendEvents;
+
+#[derive(Clone, Debug, Deserialize)]
+struct BatchSendEventsRequest {
+    /// The client's ID for the batch, unique per access token like a transaction ID.
+    txn_id: String,
+    /// The events to send, in order.
+    events: Vec<BatchEvent>,
+}
 
-                state_event!(
-                    GuestAccessEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomHistoryVisibility => {
-                ensure_empty_state_key(state_key, &event_type)?;
+/// An event in a batch.
+#[derive(Clone, Debug, Deserialize)]
+struct BatchEvent {
+    /// The type of the event.
+    #[serde(rename = "type")]
+    event_type: EventType,
+    /// The content of the event.
+    content: Value,
+    /// The state key, if the event is a state event.
+    state_key: Option<String>,
+}
 
-                state_event!(
-                    HistoryVisibilityEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomJoinRules => {
-                ensure_empty_state_key(state_key, &event_type)?;
+#[derive(Debug, Serialize)]
+struct BatchSendEventsResponse {
+    /// The IDs of the events, in the order they were given.
+    event_ids: Vec<String>,
+}
 
-                state_event!(
-                    JoinRulesEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomName => {
-                ensure_empty_state_key(state_key, &event_type)?;
+middleware_chain!(BatchSendEvents, [JsonRequest, RoomIdParam, AccessTokenAuth]);
 
-                state_event!(
-                    NameEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomPowerLevels => {
-                ensure_empty_state_key(state_key, &event_type)?;
+impl Handler for BatchSendEvents {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let batch_request = match request.get::<bodyparser::Struct<BatchSendEventsRequest>>() {
+            Ok(Some(batch_request)) => batch_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
 
-                state_event!(
-                    PowerLevelsEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomThirdPartyInvite => {
-                state_event!(
-                    ThirdPartyInviteEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomTopic => {
-                ensure_empty_state_key(state_key, &event_type)?;
+        let room_id = extension::<RoomIdParam>(request)?;
+        let user = authed_user(request)?;
+        let access_token = extension::<AccessToken>(request)?.value;
+        let method = request.method.to_string();
+        let path = request.url.path().join("/");
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
+
+        if batch_request.events.is_empty() {
+            Err(ApiError::invalid_param("events", "The batch has no events."))?;
+        }
+
+        if batch_request.events.len() > config.max_batch_send_events {
+            Err(ApiError::invalid_param(
+                "events",
+                &format!("A batch can have at most {} events.", config.max_batch_send_events),
+            ))?;
+        }
+
+        let room_version = RoomState::current(&connection, &state_cache, &room_id)?
+            .room_version()?;
+        let has_state_events = batch_request.events.iter().any(|event| event.state_key.is_some());
 
-                state_event!(
-                    TopicEvent,
+        let mut events = Vec::with_capacity(batch_request.events.len());
+
+        for (index, event) in batch_request.events.into_iter().enumerate() {
+            let event_content = content_validation::validate(&event.event_type, event.content)?;
+            let event_content = text_validation::strip_nul_characters(event_content);
+            let event_id = generate_event_id(&config.domain, &room_version)?;
+
+            let new_event = match event.state_key {
+                Some(ref state_key) => new_state_event(
+                    &event.event_type,
                     event_content,
-                    event_type,
-                    event_id,
-                    room_id,
+                    &event_id,
+                    &room_id,
                     state_key,
-                    user
-                )
-            }
-            EventType::Custom(ref custom_event_type) => {
-                CustomStateEvent {
-                    content: event_content,
-                    event_id: event_id.clone(),
-                    event_type: EventType::Custom(custom_event_type.clone()),
-                    prev_content: None,
-                    room_id: room_id.clone(),
-                    state_key: state_key.to_string(),
-                    unsigned: None,
-                    user_id: user.id.clone(),
-                }.try_into().map_err(ApiError::from)?
-            }
-            _ => {
-                let error = ApiError::bad_event(
-                    format!("Events of type {} cannot be created with this API.", event_type)
-                );
+                    &user,
+                )?,
+                None => {
+                    new_room_event(&event.event_type, event_content, &event_id, &room_id, &user)?
+                }
+            };
+
+            // The path the event is deduplicated with, as if it had been sent with a transaction
+            // ID of its own.
+            let transaction_path = format!("{}/{}/{}", path, batch_request.txn_id, index);
+
+            events.push((event.event_type, new_event, transaction_path));
+        }
+
+        // Events are verified against the state in the transaction, including the state events
+        // saved earlier in the batch, instead of the shared cache, which is only invalidated once
+        // the transaction is committed.
+        let transaction_state = StateCache::new(0);
+
+        let new_events: Vec<&NewEvent> = events.iter()
+            .map(|&(_, ref new_event, _)| new_event)
+            .collect();
+        let remote_aliases = resolve_remote_aliases(&connection, &config, &new_events)?;
+
+        let mut next_event_ids = Some(
+            events.iter().map(|&(_, ref new_event, _)| new_event.id.clone()).collect()
+        );
+
+        let event_ids = with_unique_id(
+            || match next_event_ids.take() {
+                Some(event_ids) => Ok(event_ids),
+                None => {
+                    events.iter()
+                        .map(|_| generate_event_id(&config.domain, &room_version))
+                        .collect::<Result<Vec<EventId>, ApiError>>()
+                }
+            },
+            |new_event_ids| transaction_with_retry(&connection, || {
+                let mut event_ids = Vec::with_capacity(events.len());
+                let mut batch = EventBatch::new();
+
+                for (&(ref event_type, ref new_event, ref transaction_path), new_event_id) in
+                events.iter().zip(&new_event_ids) {
+                    let saved_response = Transaction::reserve(
+                        &connection,
+                        &*clock,
+                        &method,
+                        transaction_path,
+                        &access_token,
+                    )?;
+
+                    if let Some(saved_response) = saved_response {
+                        let response: EventResponse =
+                            from_str(&saved_response).map_err(ApiError::from)?;
+
+                        event_ids.push(response.event_id);
+
+                        continue;
+                    }
+
+                    let mut new_event = new_event.clone();
+                    new_event.id = new_event_id.clone();
+
+                    verify_event(
+                        &connection,
+                        &transaction_state,
+                        &room_id,
+                        &user,
+                        event_type,
+                        &new_event,
+                        &remote_aliases,
+                    )?;
+
+                    let is_state_event = new_event.state_key.is_some();
+
+                    batch.add_event(new_event);
+
+                    let response = EventResponse {
+                        event_id: new_event_id.opaque_id().to_string(),
+                    };
+
+                    Transaction::complete(
+                        &connection,
+                        &method,
+                        transaction_path,
+                        &access_token,
+                        &to_string(&response).map_err(ApiError::from)?,
+                    )?;
+
+                    event_ids.push(response.event_id);
+
+                    // The events after a state event are verified against the state it changed,
+                    // so it is saved with the events before it right away.
+                    if is_state_event {
+                        replace(&mut batch, EventBatch::new()).commit(
+                            &connection,
+                            &transaction_state,
+                            &*clock,
+                            &config,
+                        )?;
+                    }
+                }
+
+                if !batch.is_empty() {
+                    batch.commit(&connection, &transaction_state, &*clock, &config)?;
+                }
+
+                Ok(event_ids)
+            }),
+        )?;
+
+        if has_state_events {
+            state_cache.invalidate(&room_id);
+        }
+
+        let response = BatchSendEventsResponse {
+            event_ids: event_ids,
+        };
+
+        Ok(Response::with((status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Builds a new room event of the given type from the content sent by the user.
+fn new_room_event(
+    event_type: &EventType,
+    event_content: Value,
+    event_id: &EventId,
+    room_id: &RoomId,
+    user: &User,
+) -> Result<NewEvent, ApiError> {
+    let room_event: NewEvent = match *event_type {
+        EventType::CallAnswer => {
+            room_event!(AnswerEvent, event_content, event_type, event_id, room_id, user)
+        }
+        EventType::CallCandidates => {
+            room_event!(CandidatesEvent, event_content, event_type, event_id, room_id, user)
+        }
+        EventType::CallHangup => {
+            room_event!(HangupEvent, event_content, event_type, event_id, room_id, user)
+        }
+        EventType::CallInvite => {
+            room_event!(InviteEvent, event_content, event_type, event_id, room_id, user)
+        }
+        EventType::RoomMessage => {
+            room_event!(MessageEvent, event_content, event_type, event_id, room_id, user)
+        }
+        EventType::Custom(ref custom_event_type) => {
+            CustomRoomEvent {
+                content: event_content,
+                event_id: event_id.clone(),
+                event_type: EventType::Custom(custom_event_type.clone()),
+                room_id: room_id.clone(),
+                unsigned: None,
+                user_id: user.id.clone(),
+            }.try_into().map_err(ApiError::from)?
+        }
+        _ => {
+            return Err(ApiError::bad_event(
+                format!("Events of type {} cannot be created with this API.", event_type)
+            ));
+        }
+    };
+
+    Ok(room_event)
+}
 
-                return Err(IronError::from(error));
+/// Builds a new state event of the given type from the content sent by the user.
+fn new_state_event(
+    event_type: &EventType,
+    event_content: Value,
+    event_id: &EventId,
+    room_id: &RoomId,
+    state_key: &str,
+    user: &User,
+) -> Result<NewEvent, ApiError> {
+    let state_event: NewEvent = match *event_type {
+        EventType::RoomAvatar => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                AvatarEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user
+            )
+        }
+        EventType::RoomCanonicalAlias => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                CanonicalAliasEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user
+            )
+        }
+        EventType::RoomGuestAccess => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                GuestAccessEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user
+            )
+        }
+        EventType::RoomHistoryVisibility => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                HistoryVisibilityEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user
+            )
+        }
+        EventType::RoomJoinRules => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                JoinRulesEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user
+            )
+        }
+        EventType::RoomName => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                NameEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user
+            )
+        }
+        EventType::RoomPowerLevels => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                PowerLevelsEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user
+            )
+        }
+        EventType::RoomThirdPartyInvite => {
+            state_event!(
+                ThirdPartyInviteEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user
+            )
+        }
+        EventType::RoomTopic => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                TopicEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user
+            )
+        }
+        EventType::Custom(ref custom_event_type) => {
+            if custom_event_type == RELATED_GROUPS_EVENT_TYPE {
+                ensure_empty_state_key(state_key, event_type)?;
             }
+
+            CustomStateEvent {
+                content: event_content,
+                event_id: event_id.clone(),
+                event_type: EventType::Custom(custom_event_type.clone()),
+                prev_content: None,
+                room_id: room_id.clone(),
+                state_key: state_key.to_string(),
+                unsigned: None,
+                user_id: user.id.clone(),
+            }.try_into().map_err(ApiError::from)?
+        }
+        _ => {
+            return Err(ApiError::bad_event(
+                format!("Events of type {} cannot be created with this API.", event_type)
+            ));
+        }
+    };
+
+    Ok(state_event)
+}
+
+/// The rooms the canonical aliases of other servers in new events point to, or `None` for those
+/// that couldn't be resolved.
+type RemoteAliases = HashMap<RoomAliasId, Option<RoomId>>;
+
+/// Resolves the aliases of other servers set as canonical alias by the new events.
+///
+/// This may ask other servers, giving up after `CANONICAL_ALIAS_LOOKUP_TIMEOUT_SECS`, so it is done
+/// before the events are verified and saved in a transaction, which would otherwise hold the lock
+/// of the room while waiting.
+fn resolve_remote_aliases(connection: &PgConnection, config: &Config, new_events: &[&NewEvent])
+-> Result<RemoteAliases, ApiError> {
+    let mut remote_aliases = RemoteAliases::new();
+
+    for new_event in new_events {
+        if new_event.event_type != EventType::RoomCanonicalAlias.to_string() {
+            continue;
+        }
+
+        let room_alias_id = match canonical_alias(new_event)? {
+            Some(room_alias_id) => room_alias_id,
+            None => continue,
         };
 
-        let connection = DB::from_request(request)?;
+        if room_alias_id.hostname().to_string() == config.domain ||
+            remote_aliases.contains_key(&room_alias_id) {
+            continue;
+        }
 
-        connection.transaction(|| {
-            verify_permissions(&connection, &room_id, &user, &event_type)?;
+        let timeout = Some(Duration::from_secs(CANONICAL_ALIAS_LOOKUP_TIMEOUT_SECS));
 
-            insert(&state_event)
-                .into(events::table)
-                .execute(&*connection)
-                .map_err(ApiError::from)
-        }).map_err(ApiError::from)?;
+        let room_id = match resolve_remote_alias(connection, config, &room_alias_id, timeout) {
+            Ok(remote_alias) => Some(remote_alias.room_id),
+            Err(error) => {
+                debug!("Could not resolve canonical alias {}: {}", room_alias_id, error);
 
-        let response = EventResponse {
-            event_id: event_id.opaque_id().to_string(),
+                None
+            }
         };
 
-        Ok(Response::with((status::Ok, SerializableResponse(response))))
+        remote_aliases.insert(room_alias_id, room_id);
+    }
+
+    Ok(remote_aliases)
+}
+
+/// Check that the user may send the new event to the room.
+///
+/// The canonical aliases of other servers must have been resolved with `resolve_remote_aliases`.
+fn verify_event(
+    connection: &PgConnection,
+    state_cache: &StateCache,
+    room_id: &RoomId,
+    user: &User,
+    event_type: &EventType,
+    new_event: &NewEvent,
+    remote_aliases: &RemoteAliases,
+) -> Result<(), ApiError> {
+    let is_state_event = new_event.state_key.is_some();
+
+    verify_permissions(connection, state_cache, room_id, user, event_type, is_state_event)?;
+
+    match *event_type {
+        EventType::RoomMessage => verify_unencrypted_message(connection, state_cache, room_id),
+        EventType::RoomPowerLevels => {
+            verify_power_levels_change(connection, state_cache, room_id, user, new_event)
+        }
+        EventType::RoomCanonicalAlias => {
+            verify_canonical_alias(connection, room_id, new_event, remote_aliases)
+        }
+        _ => Ok(()),
     }
 }
 
 /// Check if a `User` has permission to create an event in a given `Room`.
-fn verify_permissions(connection: &PgConnection, room_id: &RoomId, user: &User, event_type: &EventType)
--> Result<(), ApiError> {
+fn verify_permissions(
+    connection: &PgConnection,
+    state_cache: &StateCache,
+    room_id: &RoomId,
+    user: &User,
+    event_type: &EventType,
+    is_state_event: bool,
+) -> Result<(), ApiError> {
     let room = match Room::find(connection, room_id)? {
         Some(room) => room,
         None => Err(ApiError::unauthorized("The room was not found on this server".to_string()))?,
@@ -407,17 +785,11 @@ fn verify_permissions(connection: &PgConnection, room_id: &RoomId, user: &User,
         }
     }
 
-    let power_levels = room.current_power_levels(&*connection)?;
-    let user_power_level = power_levels
-        .users
-        .get(&user.id)
-        .unwrap_or(&power_levels.users_default);
-    let required_power_level = power_levels
-        .events
-        .get(event_type)
-        .unwrap_or(&power_levels.events_default);
+    let power_levels = room.current_power_levels(connection, state_cache)?;
+
+    let required = required_power_level(&power_levels, event_type, is_state_event);
 
-    if required_power_level > user_power_level {
+    if required > user_power_level(&power_levels, &user.id) {
         return Err(
             ApiError::unauthorized("Insufficient power level to create this event.".to_string())
         );
@@ -426,12 +798,138 @@ fn verify_permissions(connection: &PgConnection, room_id: &RoomId, user: &User,
     Ok(())
 }
 
+/// Check that a plaintext message isn't sent to a room with end-to-end encryption enabled.
+fn verify_unencrypted_message(
+    connection: &PgConnection,
+    state_cache: &StateCache,
+    room_id: &RoomId,
+) -> Result<(), ApiError> {
+    if RoomState::current(connection, state_cache, room_id)?.is_encrypted() {
+        return Err(ApiError::bad_state(
+            "Room is encrypted; send m.room.encrypted instead.".to_string()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that a new canonical alias points to the room, so a room can't claim another's alias.
+///
+/// Aliases of other servers are looked up in `remote_aliases`, the others in the aliases of this
+/// server. Removing the canonical alias is always allowed.
+fn verify_canonical_alias(
+    connection: &PgConnection,
+    room_id: &RoomId,
+    new_event: &NewEvent,
+    remote_aliases: &RemoteAliases,
+) -> Result<(), ApiError> {
+    let room_alias_id = match canonical_alias(new_event)? {
+        Some(room_alias_id) => room_alias_id,
+        None => return Ok(()),
+    };
+
+    let is_alias_of_room = match remote_aliases.get(&room_alias_id) {
+        Some(&Some(ref alias_room_id)) => alias_room_id == room_id,
+        Some(&None) => return Err(ApiError::bad_state(
+            format!("{} could not be resolved to this room.", room_alias_id)
+        )),
+        None => {
+            RoomAlias::find_by_room_id(connection, room_id)?
+                .iter()
+                .any(|room_alias| room_alias.alias == room_alias_id)
+        }
+    };
+
+    if !is_alias_of_room {
+        return Err(ApiError::bad_state(format!("{} is not an alias of this room.", room_alias_id)));
+    }
+
+    Ok(())
+}
+
+/// The alias a canonical alias event sets, or `None` if it removes the canonical alias.
+fn canonical_alias(new_event: &NewEvent) -> Result<Option<RoomAliasId>, ApiError> {
+    let content: Value = from_str(&new_event.content).map_err(ApiError::from)?;
+
+    let alias = match content.get("alias").and_then(Value::as_str) {
+        Some(alias) if !alias.is_empty() => alias,
+        _ => return Ok(None),
+    };
+
+    RoomAliasId::try_from(alias)
+        .map(Some)
+        .map_err(|_| ApiError::bad_state(format!("{} is not a valid room alias.", alias)))
+}
+
+/// Check that new power levels don't lock the sender out of changing them again, and that they
+/// don't raise anyone above the sender's current power level.
+fn verify_power_levels_change(
+    connection: &PgConnection,
+    state_cache: &StateCache,
+    room_id: &RoomId,
+    user: &User,
+    new_event: &NewEvent,
+) -> Result<(), ApiError> {
+    let room = match Room::find(connection, room_id)? {
+        Some(room) => room,
+        None => Err(ApiError::unauthorized("The room was not found on this server".to_string()))?,
+    };
+
+    let current = room.current_power_levels(connection, state_cache)?;
+    let new: PowerLevelsEventContent = from_str(&new_event.content).map_err(ApiError::from)?;
+
+    let sender_power_level = user_power_level(&current, &user.id);
+
+    if required_power_level(&new, &EventType::RoomPowerLevels, true) >
+        user_power_level(&new, &user.id)
+    {
+        return Err(ApiError::unauthorized(
+            "The new power levels would prevent you from changing the power levels again.".to_string()
+        ));
+    }
+
+    for (user_id, &power_level) in &new.users {
+        if power_level > sender_power_level && power_level > user_power_level(&current, user_id) {
+            return Err(ApiError::unauthorized(format!(
+                "Cannot raise the power level of {} above your own power level of {}.",
+                user_id,
+                sender_power_level
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// The power level of a user in a room.
+fn user_power_level(power_levels: &PowerLevelsEventContent, user_id: &UserId) -> u64 {
+    *power_levels.users.get(user_id).unwrap_or(&power_levels.users_default)
+}
+
+/// The power level required to send events of the given type in a room.
+///
+/// Types without their own power level fall back to `state_default` for state events and to
+/// `events_default` for all others.
+fn required_power_level(
+    power_levels: &PowerLevelsEventContent,
+    event_type: &EventType,
+    is_state_event: bool,
+) -> u64 {
+    let default = if is_state_event {
+        power_levels.state_default
+    } else {
+        power_levels.events_default
+    };
+
+    *power_levels.events.get(event_type).unwrap_or(&default)
+}
+
 /// Enforces an empty state key for an event type that requires it.
-fn ensure_empty_state_key(state_key: &str, event_type: &EventType) -> Result<(), IronError> {
+fn ensure_empty_state_key(state_key: &str, event_type: &EventType) -> Result<(), ApiError> {
     if state_key == "" {
         Ok(())
     } else {
-        Err(ApiError::bad_event(format!("Events of type {} must have an empty state key.", event_type)))?
+        Err(ApiError::bad_event(format!("Events of type {} must have an empty state key.", event_type)))
     }
 }
 
@@ -450,7 +948,15 @@ where T: for<'de> Deserialize<'de> {
 
 #[cfg(test)]
 mod tests {
-    use test::Test;
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    use ruma_identifiers::{EventId, RoomAliasId, RoomId, UserId};
+    use serde_json::{Value, from_str};
+
+    use models::event::{Event, NewEvent};
+    use models::remote_alias::RemoteAlias;
+    use test::{Response, Test, TestUser, assert_matrix_error, json_at};
     use iron::status::Status;
 
     #[test]
@@ -640,21 +1146,660 @@ mod tests {
     }
 
     #[test]
-    fn create_events_with_transactions() {
+    fn state_events_without_their_own_power_level_require_state_default() {
         let test = Test::new();
-        let (alice, room_id) = test.initial_fixtures("{}");
+        let alice = test.create_user();
+        let bob = test.create_user();
 
-        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
-        let first_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        let room_options = format!(r#"{{ "invite": [ "{}" ] }}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
 
-        // Using the same transaction ID.
-        let response = test.send_message(&alice.token, &room_id, "Hi again", 1);
-        let second_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
-        assert_eq!(first_event_id, second_event_id);
+        let power_levels_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id,
+            alice.token
+        );
 
-        // Using a different transaction ID.
-        let response = test.send_message(&alice.token, &room_id, "Hi once again", 2);
-        let third_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
-        assert_ne!(third_event_id, second_event_id);
+        let event_content = format!(r#"{{
+            "ban": 100,
+            "events": {{ "m.room.power_levels": 100 }},
+            "events_default": 0,
+            "invite": 100,
+            "kick": 100,
+            "redact": 0,
+            "state_default": 50,
+            "users": {{ "{}": 100, "{}": 10 }},
+            "users_default": 0
+        }}"#, alice.id, bob.id);
+
+        let response = test.put(&power_levels_path, &event_content);
+        assert_eq!(response.status, Status::Ok);
+
+        let topic_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+            room_id,
+            bob.token
+        );
+
+        let response = test.put(&topic_path, r#"{"topic": "Bob's topic"}"#);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Insufficient power level to create this event."
+        );
+
+        let response = test.send_message(&bob.token, &room_id, "Hello", 1);
+        assert_eq!(response.status, Status::Ok);
+
+        // Without their own entry, the power levels can only be changed again with `state_default`.
+        let event_content = format!(r#"{{
+            "ban": 100,
+            "events": {{}},
+            "events_default": 0,
+            "invite": 100,
+            "kick": 100,
+            "redact": 0,
+            "state_default": 100,
+            "users": {{ "{}": 50 }},
+            "users_default": 0
+        }}"#, alice.id);
+
+        let response = test.put(&power_levels_path, &event_content);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "The new power levels would prevent you from changing the power levels again."
+        );
+    }
+
+    #[test]
+    fn permission_checks_use_cached_state() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        assert_eq!(test.send_message(&alice.token, &room_id, "Hello", 1).status, Status::Ok);
+        let loads = test.state_cache().loads();
+
+        assert_eq!(test.send_message(&alice.token, &room_id, "Hello again", 2).status, Status::Ok);
+        assert_eq!(test.state_cache().loads(), loads);
+    }
+
+    #[test]
+    fn state_update_invalidates_cached_state() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_options = format!(r#"{{ "invite": [ "{}" ] }}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        // Caches the state of the room.
+        assert_eq!(test.send_message(&bob.token, &room_id, "Hello", 1).status, Status::Ok);
+
+        let state_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id,
+            alice.token
+        );
+
+        let event_content = format!(r#"{{
+            "ban": 100,
+            "events": {{ "m.room.message": 100 }},
+            "events_default": 0,
+            "invite": 100,
+            "kick": 100,
+            "redact": 0,
+            "state_default": 0,
+            "users": {{ "{}": 100 }},
+            "users_default": 0
+        }}"#, alice.id);
+
+        assert_eq!(test.put(&state_event_path, &event_content).status, Status::Ok);
+
+        let response = test.send_message(&bob.token, &room_id, "Hello again", 2);
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn power_levels_cannot_lock_out_sender() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let state_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id,
+            alice.token
+        );
+
+        let event_content = format!(r#"{{
+            "ban": 50,
+            "events": {{ "m.room.power_levels": 100 }},
+            "events_default": 0,
+            "invite": 50,
+            "kick": 50,
+            "redact": 50,
+            "state_default": 0,
+            "users": {{ "{}": 50 }},
+            "users_default": 0
+        }}"#, alice.id);
+
+        let response = test.put(&state_event_path, &event_content);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "The new power levels would prevent you from changing the power levels again."
+        );
+    }
+
+    #[test]
+    fn power_levels_cannot_raise_users_above_sender() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        let room_options = format!(r#"{{ "invite": [ "{}" ] }}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let event_content = format!(r#"{{
+            "ban": 50,
+            "events": {{}},
+            "events_default": 0,
+            "invite": 50,
+            "kick": 50,
+            "redact": 50,
+            "state_default": 0,
+            "users": {{ "{}": 100, "{}": 50 }},
+            "users_default": 0
+        }}"#, alice.id, bob.id);
+
+        let alice_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id,
+            alice.token
+        );
+        assert_eq!(test.put(&alice_path, &event_content).status, Status::Ok);
+
+        let bob_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id,
+            bob.token
+        );
+
+        // Alice's power level stays above Bob's, which is allowed since it isn't raised.
+        let event_content = format!(r#"{{
+            "ban": 50,
+            "events": {{}},
+            "events_default": 0,
+            "invite": 50,
+            "kick": 50,
+            "redact": 50,
+            "state_default": 0,
+            "users": {{ "{}": 100, "{}": 50, "{}": 75 }},
+            "users_default": 0
+        }}"#, alice.id, bob.id, carl.id);
+
+        let response = test.put(&bob_path, &event_content);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            format!("Cannot raise the power level of {} above your own power level of 50.", carl.id)
+        );
+
+        let event_content = format!(r#"{{
+            "ban": 50,
+            "events": {{}},
+            "events_default": 0,
+            "invite": 50,
+            "kick": 50,
+            "redact": 50,
+            "state_default": 0,
+            "users": {{ "{}": 100, "{}": 50, "{}": 50 }},
+            "users_default": 0
+        }}"#, alice.id, bob.id, carl.id);
+
+        assert_eq!(test.put(&bob_path, &event_content).status, Status::Ok);
+    }
+
+    #[test]
+    fn power_levels_must_be_integers() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let state_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id,
+            alice.token
+        );
+
+        let event_content = format!(r#"{{
+            "ban": "50",
+            "events": {{}},
+            "events_default": 0,
+            "invite": 50,
+            "kick": 50,
+            "redact": 50,
+            "state_default": 0,
+            "users": {{ "{}": 100 }},
+            "users_default": 0
+        }}"#, alice.id);
+
+        let response = test.put(&state_event_path, &event_content);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_BAD_JSON");
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Event content field `ban` must be an integer."
+        );
+
+        let response = test.get(&state_event_path);
+        assert_eq!(response.json().get("ban").unwrap().as_u64().unwrap(), 50);
+    }
+
+    #[test]
+    fn message_without_msgtype() {
+        let test = Test::new();
+        let user = test.create_user();
+        let room_id = test.create_room(&user.token);
+
+        let create_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?access_token={}",
+            room_id,
+            user.token
+        );
+
+        let response = test.put(&create_event_path, r#"{"body":"Hi"}"#);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_BAD_JSON");
+    }
+
+    #[test]
+    fn nul_characters_are_stripped_from_content() {
+        let test = Test::new();
+        let user = test.create_user();
+        let room_id = test.create_room(&user.token);
+
+        let create_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?access_token={}",
+            room_id,
+            user.token
+        );
+
+        let response = test.put(&create_event_path, r#"{"msgtype":"m.text","body":"a\u0000b"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        let event_id = EventId::try_from(event_id).unwrap();
+
+        let connection = test.connection();
+        let event = Event::find(&connection, &event_id).unwrap().unwrap();
+
+        let content: Value = from_str(&event.content).unwrap();
+
+        assert_eq!(content.get("body").unwrap().as_str().unwrap(), "ab");
+    }
+
+    #[test]
+    fn create_events_with_transactions() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let first_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        // Using the same transaction ID.
+        let response = test.send_message(&alice.token, &room_id, "Hi again", 1);
+        let second_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        assert_eq!(first_event_id, second_event_id);
+
+        // Using a different transaction ID.
+        let response = test.send_message(&alice.token, &room_id, "Hi once again", 2);
+        let third_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        assert_ne!(third_event_id, second_event_id);
+    }
+
+    #[test]
+    fn message_event_follows_latest_event() {
+        let test = Test::new();
+        let user = test.create_user();
+        let room_id = RoomId::try_from(test.create_room(&user.token).as_ref()).unwrap();
+
+        let connection = test.connection();
+        let latest_event_ids = Event::find_latest_event_ids(&connection, &room_id).unwrap();
+        let latest_event = Event::find(&connection, &latest_event_ids[0]).unwrap().unwrap();
+        drop(connection);
+
+        let response = test.send_message(&user.token, &room_id.to_string(), "Hi", 1);
+        assert_eq!(response.status, Status::Ok);
+
+        let connection = test.connection();
+        let event_ids = Event::find_latest_event_ids(&connection, &room_id).unwrap();
+        let event = Event::find(&connection, &event_ids[0]).unwrap().unwrap();
+
+        assert_eq!(event.depth, latest_event.depth + 1);
+        assert_eq!(Event::find_prev_event_ids(&connection, &event.id).unwrap(), latest_event_ids);
+    }
+
+    #[test]
+    fn canonical_alias_must_point_to_the_room() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room_with_params(&alice.token, r#"{"room_alias_name": "own"}"#);
+        test.create_room_with_params(&alice.token, r#"{"room_alias_name": "other"}"#);
+
+        let set_canonical_alias = |alias: &str| {
+            let content = format!(r#"{{"alias": "{}"}}"#, alias);
+
+            test.send_state_event(&alice.token, &room_id, "m.room.canonical_alias", &content)
+        };
+
+        assert_eq!(set_canonical_alias("#own:ruma.test").status, Status::Ok);
+
+        for alias in &["#other:ruma.test", "#missing:ruma.test"] {
+            let response = set_canonical_alias(alias);
+
+            assert_eq!(response.status, Status::BadRequest);
+            assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_BAD_STATE");
+        }
+    }
+
+    #[test]
+    fn remote_canonical_alias_must_resolve_to_the_room() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        {
+            let connection = test.connection();
+
+            for &(alias, target) in &[
+                ("#room:remote.test", room_id.as_str()),
+                ("#elsewhere:remote.test", "!elsewhere:remote.test"),
+            ] {
+                RemoteAlias::store(
+                    &connection,
+                    &RoomAliasId::try_from(alias).unwrap(),
+                    &RoomId::try_from(target).unwrap(),
+                    vec!["remote.test".to_string()],
+                    Duration::from_secs(60),
+                ).unwrap();
+            }
+        }
+
+        let set_canonical_alias = |alias: &str| {
+            let content = format!(r#"{{"alias": "{}"}}"#, alias);
+
+            test.send_state_event(&alice.token, &room_id, "m.room.canonical_alias", &content)
+        };
+
+        assert_eq!(set_canonical_alias("#room:remote.test").status, Status::Ok);
+
+        let response = set_canonical_alias("#elsewhere:remote.test");
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_BAD_STATE");
+    }
+
+    #[test]
+    fn plaintext_messages_are_rejected_in_encrypted_rooms() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        assert_eq!(test.send_message(&alice.token, &room_id, "Hi", 1).status, Status::Ok);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.encryption",
+            r#"{"algorithm": "m.megolm.v1.aes-sha2"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.send_message(&alice.token, &room_id, "Hi", 2);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_BAD_STATE");
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Room is encrypted; send m.room.encrypted instead."
+        );
+
+        let encrypted_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.encrypted/3?access_token={}",
+            room_id,
+            alice.token
+        );
+        let encrypted_content = r#"{
+            "algorithm": "m.megolm.v1.aes-sha2",
+            "ciphertext": "AwgAEnAC",
+            "session_id": "session"
+        }"#;
+        let response = test.put(&encrypted_event_path, encrypted_content);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn event_cannot_be_its_own_prev_event() {
+        let test = Test::new();
+        let user = test.create_user();
+        let room_id = RoomId::try_from(test.create_room(&user.token).as_ref()).unwrap();
+
+        let connection = test.connection();
+        let event = new_message_event(&room_id, &user.id);
+        let prev_events = vec![event.id.clone()];
+
+        assert!(event.save_with_prev_events(&connection, test.clock(), &prev_events).is_err());
+        assert!(Event::find(&connection, &event.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn event_cannot_follow_its_descendants() {
+        let test = Test::new();
+        let user = test.create_user();
+        let room_id = RoomId::try_from(test.create_room(&user.token).as_ref()).unwrap();
+
+        let connection = test.connection();
+        let parent = new_message_event(&room_id, &user.id);
+        let child = new_message_event(&room_id, &user.id);
+
+        // The child arrives first, referring to a parent the server has not seen yet.
+        child.save_with_prev_events(&connection, test.clock(), &[parent.id.clone()]).unwrap();
+
+        let prev_events = vec![child.id.clone()];
+
+        assert!(parent.save_with_prev_events(&connection, test.clock(), &prev_events).is_err());
+        assert!(Event::find(&connection, &parent.id).unwrap().is_none());
+    }
+
+    fn new_message_event(room_id: &RoomId, user_id: &str) -> NewEvent {
+        NewEvent {
+            event_type: "m.room.message".to_string(),
+            extra_content: None,
+            id: EventId::new("ruma.test").unwrap(),
+            content: r#"{"body":"Hi","msgtype":"m.text"}"#.to_string(),
+            room_id: room_id.clone(),
+            state_key: None,
+            user_id: UserId::try_from(user_id).unwrap(),
+            pdu: None,
+        }
+    }
+
+    fn batch_send(test: &Test, user: &TestUser, room_id: &str, body: &str) -> Response {
+        test.post_as(user, &format!("/_matrix/client/r0/rooms/{}/batch_send", room_id), body)
+    }
+
+    fn batch_event_ids(response: &Response) -> Vec<String> {
+        json_at(response, "event_ids")
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event_id| event_id.as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn batch_send_sends_events_in_order() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let room_id = alice.create_room(&test, "{}");
+
+        let response = batch_send(&test, &alice, &room_id, r#"{"txn_id": "1", "events": [
+            {"type": "m.room.message", "content": {"msgtype": "m.text", "body": "One"}},
+            {"type": "m.room.topic", "state_key": "", "content": {"topic": "Batches"}},
+            {"type": "m.room.message", "content": {"msgtype": "m.text", "body": "Two"}}
+        ]}"#);
+
+        assert_eq!(response.status, Status::Ok, "{}", response.body);
+        let event_ids = batch_event_ids(&response);
+        assert_eq!(event_ids.len(), 3);
+
+        let sync = alice.sync_since(&test, None);
+        let timeline = json_at(&sync, &format!("rooms.join.{}.timeline.events", room_id))
+            .as_array()
+            .unwrap();
+        let last_events = &timeline[timeline.len() - 3..];
+
+        for (event, event_id) in last_events.iter().zip(&event_ids) {
+            assert!(event.get("event_id").unwrap().as_str().unwrap().contains(&event_id[..]));
+        }
+
+        assert_eq!(last_events[0].pointer("/content/body").unwrap().as_str().unwrap(), "One");
+        assert_eq!(last_events[1].pointer("/content/topic").unwrap().as_str().unwrap(), "Batches");
+        assert_eq!(last_events[2].pointer("/content/body").unwrap().as_str().unwrap(), "Two");
+
+        let response = test.get_as(
+            &alice,
+            &format!("/_matrix/client/r0/rooms/{}/state/m.room.topic", room_id),
+        );
+        assert_eq!(json_at(&response, "topic"), "Batches");
+    }
+
+    #[test]
+    fn repeated_batches_only_send_new_events() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let room_id = alice.create_room(&test, "{}");
+
+        let one = r#"{"type": "m.room.message", "content": {"msgtype": "m.text", "body": "One"}}"#;
+        let two = r#"{"type": "m.room.message", "content": {"msgtype": "m.text", "body": "Two"}}"#;
+
+        let first = batch_send(
+            &test,
+            &alice,
+            &room_id,
+            &format!(r#"{{"txn_id": "1", "events": [{}]}}"#, one),
+        );
+        let repeated = batch_send(
+            &test,
+            &alice,
+            &room_id,
+            &format!(r#"{{"txn_id": "1", "events": [{}, {}]}}"#, one, two),
+        );
+        let other = batch_send(
+            &test,
+            &alice,
+            &room_id,
+            &format!(r#"{{"txn_id": "2", "events": [{}]}}"#, one),
+        );
+
+        let first_event_ids = batch_event_ids(&first);
+        let repeated_event_ids = batch_event_ids(&repeated);
+
+        assert_eq!(repeated_event_ids.len(), 2);
+        assert_eq!(repeated_event_ids[0], first_event_ids[0]);
+        assert!(repeated_event_ids[1] != first_event_ids[0]);
+        assert!(batch_event_ids(&other)[0] != first_event_ids[0]);
+    }
+
+    #[test]
+    fn batches_are_sent_entirely_or_not_at_all() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let room_id = alice.create_room(&test, "{}");
+
+        let response = test.put_as(
+            &alice,
+            &format!("/_matrix/client/r0/rooms/{}/state/m.room.encryption", room_id),
+            r#"{"algorithm": "m.megolm.v1.aes-sha2"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = batch_send(&test, &alice, &room_id, r#"{"txn_id": "1", "events": [
+            {"type": "m.room.topic", "state_key": "", "content": {"topic": "Secrets"}},
+            {"type": "m.room.message", "content": {"msgtype": "m.text", "body": "Plaintext"}}
+        ]}"#);
+
+        assert_matrix_error(&response, Status::BadRequest, "M_BAD_STATE");
+
+        let response = test.get_as(
+            &alice,
+            &format!("/_matrix/client/r0/rooms/{}/state/m.room.topic", room_id),
+        );
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn later_events_are_verified_against_state_sent_earlier_in_the_batch() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let room_id = alice.create_room(&test, "{}");
+
+        // Caches the state of the room from before the batch.
+        let response = alice.send_message(&test, &room_id, "Before");
+        assert_eq!(response.status, Status::Ok);
+
+        let response = batch_send(&test, &alice, &room_id, r#"{"txn_id": "1", "events": [
+            {"type": "m.room.encryption", "state_key": "", "content": {
+                "algorithm": "m.megolm.v1.aes-sha2"
+            }},
+            {"type": "m.room.message", "content": {"msgtype": "m.text", "body": "Plaintext"}}
+        ]}"#);
+
+        assert_matrix_error(&response, Status::BadRequest, "M_BAD_STATE");
+
+        let response = test.get_as(
+            &alice,
+            &format!("/_matrix/client/r0/rooms/{}/state/m.room.encryption", room_id),
+        );
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn batch_size_is_limited() {
+        let test = Test::with_config(|config| config.max_batch_send_events = 2);
+        let alice = test.register("alice");
+        let room_id = alice.create_room(&test, "{}");
+
+        let event = r#"{"type": "m.room.message", "content": {"msgtype": "m.text", "body": "Hi"}}"#;
+
+        let response = batch_send(
+            &test,
+            &alice,
+            &room_id,
+            &format!(r#"{{"txn_id": "1", "events": [{}, {}, {}]}}"#, event, event, event),
+        );
+        assert_matrix_error(&response, Status::BadRequest, "M_INVALID_PARAM");
+
+        let response = batch_send(&test, &alice, &room_id, r#"{"txn_id": "2", "events": []}"#);
+        assert_matrix_error(&response, Status::BadRequest, "M_INVALID_PARAM");
+
+        let response = batch_send(
+            &test,
+            &alice,
+            &room_id,
+            &format!(r#"{{"txn_id": "3", "events": [{}, {}]}}"#, event, event),
+        );
+        assert_eq!(response.status, Status::Ok);
     }
 }
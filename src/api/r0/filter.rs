@@ -8,8 +8,8 @@ use db::DB;
 use error::ApiError;
 use middleware::{AccessTokenAuth, FilterIdParam, JsonRequest, MiddlewareChain, UserIdParam};
 use models::filter::{Filter, ContentFilter};
-use models::user::User;
 use modifier::SerializableResponse;
+use request_ext::{authed_user, extension};
 
 /// The GET `/user/:user_id/filter/:filter_id` endpoint.
 pub struct GetFilter;
@@ -18,11 +18,9 @@ middleware_chain!(GetFilter, [AccessTokenAuth, FilterIdParam, UserIdParam]);
 
 impl Handler for GetFilter {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
+        let user_id = extension::<UserIdParam>(request)?;
 
-        let filter_id = *request.extensions.get::<FilterIdParam>()
-            .expect("FilterIdParam should ensure a FilterIdParam");
+        let filter_id = extension::<FilterIdParam>(request)?;
 
         let connection = DB::from_request(request)?;
         let filter = Filter::find(&connection, user_id, filter_id)?;
@@ -44,11 +42,9 @@ middleware_chain!(PostFilter, [JsonRequest, AccessTokenAuth, UserIdParam]);
 
 impl Handler for PostFilter {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
+        let user_id = extension::<UserIdParam>(request)?;
 
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
         if user_id != user.id {
             Err(ApiError::unauthorized("The given user_id does not correspond to the authenticated user".to_string()))?;
@@ -124,5 +120,10 @@ mod tests {
 
         let response = test.get(&get_filter_path);
         assert_eq!(response.status, Status::NotFound);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_NOT_FOUND");
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "No resource was found for this request."
+        );
     }
 }
@@ -0,0 +1,145 @@
+//! Endpoints for the groups rooms are affiliated with.
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+
+use db::DB;
+use middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use models::group::find_related_groups;
+use models::room::Room;
+use models::room_membership::RoomMembership;
+use modifier::SerializableResponse;
+use request_ext::{authed_user, extension};
+use state_cache::StateCache;
+
+/// The GET `/rooms/:room_id/related_groups` endpoint.
+///
+/// Returns the groups in the room's `m.room.related_groups` event, which is an empty list for
+/// rooms without one. Only members of the room can look them up, unless the room is listed in
+/// the public room directory, which shows them anyway.
+pub struct GetRelatedGroups;
+
+#[derive(Debug, Serialize)]
+struct GetRelatedGroupsResponse {
+    /// The IDs of the groups.
+    groups: Vec<String>,
+}
+
+middleware_chain!(GetRelatedGroups, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for GetRelatedGroups {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = authed_user(request)?;
+
+        let room_id = extension::<RoomIdParam>(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        let is_public = Room::find(&connection, &room_id)?.map_or(false, |room| room.public);
+
+        if !is_public {
+            let state_cache = StateCache::from_request(request)?;
+
+            RoomMembership::require(&connection, &state_cache, &room_id, &user.id, &["join"])?;
+        }
+
+        let response = GetRelatedGroupsResponse {
+            groups: find_related_groups(&connection, &room_id)?,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    fn related_groups_path(room_id: &str, access_token: &str) -> String {
+        format!(
+            "/_matrix/client/r0/rooms/{}/related_groups?access_token={}",
+            room_id,
+            access_token
+        )
+    }
+
+    fn set_related_groups(test: &Test, access_token: &str, room_id: &str, groups: &str) -> Status {
+        test.send_state_event(
+            access_token,
+            room_id,
+            "m.room.related_groups",
+            &format!(r#"{{"groups": {}}}"#, groups),
+        ).status
+    }
+
+    #[test]
+    fn related_groups() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.get(&related_groups_path(&room_id, &alice.token));
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("groups").unwrap().as_array().unwrap().is_empty());
+
+        let groups = r#"["+ruma:ruma.test", "+matrix:matrix.org"]"#;
+        assert_eq!(set_related_groups(&test, &alice.token, &room_id, groups), Status::Ok);
+
+        let response = test.get(&related_groups_path(&room_id, &alice.token));
+        let groups: Vec<&str> = response.json().get("groups").unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|group| group.as_str().unwrap())
+            .collect();
+        assert_eq!(groups, vec!["+ruma:ruma.test", "+matrix:matrix.org"]);
+    }
+
+    #[test]
+    fn invalid_group_ids_are_rejected() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let status = set_related_groups(&test, &alice.token, &room_id, r#"["ruma:ruma.test"]"#);
+
+        assert_eq!(status, Status::BadRequest);
+    }
+
+    #[test]
+    fn related_groups_of_private_room_need_membership() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.get(&related_groups_path(&room_id, &bob.token));
+        let missing = test.get(&related_groups_path("!missing:ruma.test", &bob.token));
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(response.raw_body, missing.raw_body);
+    }
+
+    #[test]
+    fn related_groups_are_listed_in_directory() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room_with_params(&alice.token, r#"{"visibility": "public"}"#);
+
+        let groups = r#"["+ruma:ruma.test"]"#;
+        assert_eq!(set_related_groups(&test, &alice.token, &room_id, groups), Status::Ok);
+
+        let response =
+            test.get(&format!("/_matrix/client/r0/publicRooms?access_token={}", alice.token));
+        assert_eq!(response.status, Status::Ok);
+
+        let chunk = response.json().pointer("/chunk/0").unwrap();
+        assert_eq!(chunk.get("room_id").unwrap().as_str().unwrap(), room_id);
+        assert_eq!(
+            chunk.pointer("/related_groups/0").unwrap().as_str().unwrap(),
+            "+ruma:ruma.test"
+        );
+    }
+}
@@ -9,18 +9,26 @@ use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
 use ruma_identifiers::{UserId, RoomId, RoomIdOrAliasId};
 
+use clock::{self, Clock};
 use config::Config;
 use db::DB;
 use error::ApiError;
+use federation::join::{join_servers, make_join};
+use identifiers;
 use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomIdParam, RoomIdOrAliasParam};
 use models::room::Room;
 use models::room_alias::RoomAlias;
 use models::room_membership::{RoomMembership, RoomMembershipOptions};
 use models::user::User;
 use modifier::{SerializableResponse, EmptyResponse};
+use query_params;
+use request_ext::{authed_user, extension};
+use state_cache::StateCache;
 
 
 /// The `/rooms/:room_id/join` endpoint.
+///
+/// Rooms this server isn't in are joined through the servers given as `via` parameters.
 pub struct JoinRoom;
 
 #[derive(Debug, Serialize)]
@@ -33,55 +41,79 @@ middleware_chain!(JoinRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
 
 impl Handler for JoinRoom {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user = request.extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = authed_user(request)?;
 
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
 
-        let room_id = request.extensions.get::<RoomIdParam>()
-            .expect("Should have been required by RoomIdParam.")
-            .clone();
+        let room_id = extension::<RoomIdParam>(request)?;
+        let via = query_params::get_all(request, "via");
 
-        join_room(room_id, user, &connection, &config)
+        join_room(room_id, &via, user, &connection, &state_cache, &*clock, &config)
     }
 }
 
 /// The `/join/:room_id_or_alias` endpoint.
+///
+/// Like `/rooms/:room_id/join`, this takes `via` parameters. The servers of a local alias are
+/// tried after them.
 pub struct JoinRoomWithIdOrAlias;
 
 middleware_chain!(JoinRoomWithIdOrAlias, [JsonRequest, RoomIdOrAliasParam, AccessTokenAuth]);
 
 impl Handler for JoinRoomWithIdOrAlias {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user = request.extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = authed_user(request)?;
 
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
 
-        let room_id_or_alias = request.extensions.get::<RoomIdOrAliasParam>()
-            .expect("Should have been required by RoomIdOrAliasParam.")
-            .clone();
+        let room_id_or_alias = extension::<RoomIdOrAliasParam>(request)?;
+
+        let mut via = query_params::get_all(request, "via");
 
         let room_id = match room_id_or_alias {
             RoomIdOrAliasId::RoomId(id) => id,
             RoomIdOrAliasId::RoomAliasId(alias) => {
                 let room_alias = RoomAlias::find_by_alias(&connection, &alias)?;
+                via.extend(room_alias.servers);
                 room_alias.room_id
             }
         };
 
-        join_room(room_id, user, &connection, &config)
+        join_room(room_id, &via, user, &connection, &state_cache, &*clock, &config)
     }
 }
 
 /// Handles the work of actually saving the user to the room membership table
-fn join_room(room_id: RoomId, user: User, connection: &PgConnection, config: &Config) -> IronResult<Response> {
+///
+/// Rooms this server isn't in are joined through the `via` servers instead.
+fn join_room(
+    room_id: RoomId,
+    via: &[String],
+    user: User,
+    connection: &PgConnection,
+    state_cache: &StateCache,
+    clock: &Clock,
+    config: &Config,
+) -> IronResult<Response> {
+    let servers = join_servers(config, &room_id, via)?;
+
+    if Room::find(connection, &room_id)?.is_none() && !servers.is_empty() {
+        let template = make_join(config, &room_id, &user.id, &servers)?;
+
+        info!("{} offered to let {} join {}.", template.server, user.id, room_id);
+
+        Err(ApiError::unimplemented(format!(
+            "{} accepted the join, but joining rooms over federation is not supported yet.",
+            template.server
+        )))?;
+    }
+
     let room_membership_options = RoomMembershipOptions {
         room_id: room_id.clone(),
         user_id: user.id.clone(),
@@ -91,7 +123,9 @@ fn join_room(room_id: RoomId, user: User, connection: &PgConnection, config: &Co
 
     let room_membership = RoomMembership::upsert(
         connection,
-        &config.domain,
+        state_cache,
+        clock,
+        config,
         room_membership_options
     )?;
 
@@ -107,17 +141,14 @@ middleware_chain!(LeaveRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
 
 impl Handler for LeaveRoom {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user = request.extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = authed_user(request)?;
 
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
 
-        let room_id = request.extensions.get::<RoomIdParam>()
-            .expect("Should have been required by RoomIdParam.")
-            .clone();
+        let room_id = extension::<RoomIdParam>(request)?;
 
         let room_membership_options = RoomMembershipOptions {
             room_id: room_id.clone(),
@@ -137,7 +168,9 @@ impl Handler for LeaveRoom {
                     "join" | "invite" => {
                         room_membership.update(
                             &connection,
-                            &config.domain,
+                            &state_cache,
+                            &*clock,
+                            &config,
                             room_membership_options)?;
                         Ok(Response::with(EmptyResponse(Status::Ok)))
                     },
@@ -167,11 +200,9 @@ middleware_chain!(KickFromRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
 
 impl Handler for KickFromRoom {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let room_id = request.extensions.get::<RoomIdParam>()
-            .expect("RoomIdParam should ensure a room_id").clone();
+        let room_id = extension::<RoomIdParam>(request)?;
 
-        let kicker = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let kicker = authed_user(request)?;
 
         let kickee_id = match request.get::<bodyparser::Struct<KickFromRoomRequest>>() {
             Ok(Some(req)) => req.user_id,
@@ -179,8 +210,12 @@ impl Handler for KickFromRoom {
             Err(err) => Err(ApiError::bad_json(err.description().to_string()))?,
         };
 
+        identifiers::check_length("user_id", &kickee_id.to_string())?;
+
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
 
         let room = match Room::find(&connection, &room_id)? {
             Some(room) => room,
@@ -193,11 +228,13 @@ impl Handler for KickFromRoom {
         };
 
         let mut kickee_membership = match RoomMembership::find(&connection, &room_id, &kickee_id)? {
-            Some(ref membership) if membership.membership == "join" => membership.clone(),
+            // Kicking a user who knocked declines their knock.
+            Some(ref membership) if membership.membership == "join" ||
+                membership.membership == "knock" => membership.clone(),
             _ => Err(ApiError::unauthorized("The kickee is not currently in the room".to_string()))?,
         };
 
-        let power_levels = room.current_power_levels(&connection)?;
+        let power_levels = room.current_power_levels(&connection, &state_cache)?;
         let user_power_level = power_levels
             .users
             .get(&kicker.id)
@@ -214,13 +251,96 @@ impl Handler for KickFromRoom {
             membership: "leave".to_string(),
         };
 
-        kickee_membership.update(&connection, &config.domain, room_membership_options)?;
+        kickee_membership.update(
+            &connection,
+            &state_cache,
+            &*clock,
+            &config,
+            room_membership_options,
+        )?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// The `/rooms/:room_id/unban` endpoint.
+pub struct UnbanFromRoom;
+
+#[derive(Clone, Debug, Deserialize)]
+struct UnbanFromRoomRequest {
+    /// The fully qualified user ID of the user being unbanned.
+    pub user_id: UserId,
+}
+
+middleware_chain!(UnbanFromRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for UnbanFromRoom {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = extension::<RoomIdParam>(request)?;
+
+        let unbanner = authed_user(request)?;
+
+        let unbanned_id = match request.get::<bodyparser::Struct<UnbanFromRoomRequest>>() {
+            Ok(Some(req)) => req.user_id,
+            Ok(None) => Err(ApiError::bad_json(None))?,
+            Err(err) => Err(ApiError::bad_json(err.description().to_string()))?,
+        };
+
+        identifiers::check_length("user_id", &unbanned_id.to_string())?;
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
+
+        let room = match Room::find(&connection, &room_id)? {
+            Some(room) => room,
+            None => Err(ApiError::unauthorized("The room was not found on this server".to_string()))?,
+        };
+
+        match RoomMembership::find(&connection, &room_id, &unbanner.id)? {
+            Some(ref membership) if membership.membership == "join" => { },
+            _ => Err(ApiError::unauthorized("The unbanner is not currently in the room".to_string()))?,
+        };
+
+        let mut unbanned_membership = match RoomMembership::find(&connection, &room_id, &unbanned_id)? {
+            Some(ref membership) if membership.membership == "ban" => membership.clone(),
+            _ => Err(ApiError::unauthorized("The user is not banned from the room".to_string()))?,
+        };
+
+        let power_levels = room.current_power_levels(&connection, &state_cache)?;
+        let user_power_level = power_levels
+            .users
+            .get(&unbanner.id)
+            .unwrap_or(&power_levels.users_default);
+
+        if power_levels.ban > *user_power_level {
+            Err(ApiError::unauthorized("Insufficient power level to unban a user".to_string()))?;
+        }
+
+        let room_membership_options = RoomMembershipOptions {
+            room_id: room_id,
+            user_id: unbanned_id,
+            sender: unbanner.id,
+            membership: "leave".to_string(),
+        };
+
+        unbanned_membership.update(
+            &connection,
+            &state_cache,
+            &*clock,
+            &config,
+            room_membership_options,
+        )?;
 
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
 }
 
 /// The `/rooms/:room_id/invite` endpoint.
+///
+/// Users of other servers can be invited as well. Their servers learn about the invite over
+/// federation.
 #[derive(Debug)]
 pub struct InviteToRoom;
 
@@ -234,11 +354,9 @@ middleware_chain!(InviteToRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
 
 impl Handler for InviteToRoom {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let room_id = request.extensions.get::<RoomIdParam>()
-            .expect("RoomIdParam should ensure a room_id").clone();
+        let room_id = extension::<RoomIdParam>(request)?;
 
-        let inviter = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let inviter = authed_user(request)?;
 
         let invitee_id = match request.get::<bodyparser::Struct<InviteToRoomRequest>>() {
             Ok(Some(req)) => req.user_id,
@@ -246,11 +364,17 @@ impl Handler for InviteToRoom {
             Err(err) => Err(ApiError::bad_json(err.description().to_string()))?,
         };
 
+        identifiers::check_length("user_id", &invitee_id.to_string())?;
+
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
 
         let invitee_membership = connection.transaction::<Option<RoomMembership>, ApiError, _>(|| {
-            if User::find_active_user(&connection, &invitee_id)?.is_none() {
+            let is_local_invitee = invitee_id.hostname().to_string() == config.domain;
+
+            if is_local_invitee && User::find_active_user(&connection, &invitee_id)?.is_none() {
                 return Err(
                     ApiError::not_found(format!("The invited user {} was not found on this server", invitee_id))
                 );
@@ -300,7 +424,9 @@ impl Handler for InviteToRoom {
                 _ => {
                     entry.update(
                         &connection,
-                        &config.domain,
+                        &state_cache,
+                        &*clock,
+                        &config,
                         new_membership_options
                     )?;
 
@@ -310,7 +436,9 @@ impl Handler for InviteToRoom {
             None => {
                 RoomMembership::create(
                     &connection,
-                    &config.domain,
+                    &state_cache,
+                    &*clock,
+                    &config,
                     new_membership_options
                 )?;
 
@@ -325,8 +453,13 @@ impl Handler for InviteToRoom {
 
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
+
     use test::Test;
     use iron::status::Status;
+    use ruma_identifiers::{RoomId, UserId};
+
+    use models::room_membership::{RoomMembership, RoomMembershipOptions};
 
     #[test]
     fn join_own_public_room_via_join_endpoint() {
@@ -365,6 +498,62 @@ mod tests {
         assert_eq!(response.json().get("room_id").unwrap().as_str().unwrap().to_string(), room_id);
     }
 
+    #[test]
+    fn join_local_room_with_via() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_room_with_params(&alice.token, r#"{"visibility": "public"}"#);
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/join?via=example.com&via=ruma.test&access_token={}",
+                room_id,
+                bob.token
+            ),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("room_id").unwrap().as_str().unwrap(), room_id);
+    }
+
+    #[test]
+    fn join_with_invalid_via() {
+        let test = Test::new();
+        let bob = test.create_user();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/rooms/!room:example.com/join?via={}&via={}&access_token={}",
+                "example.com",
+                "https%3A%2F%2Fexample.com",
+                bob.token
+            ),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_INVALID_PARAM");
+    }
+
+    #[test]
+    fn join_remote_room_through_unreachable_servers() {
+        let test = Test::new();
+        let bob = test.create_user();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/rooms/!room:example.com/join?via=127.0.0.1:1&access_token={}",
+                bob.token
+            ),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::InternalServerError);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_UNKNOWN");
+    }
+
     #[test]
     fn join_own_public_room() {
         let test = Test::new();
@@ -504,7 +693,23 @@ mod tests {
         // Empty body.
         let response = test.post(&invite_path, "{}");
 
-        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn invite_with_too_long_user_id() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "private"}"#);
+
+        let user_id = format!("@{}:ruma.test", "a".repeat(290));
+        let response = test.invite(&carl.token, &room_id, &user_id);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+        assert!(response.json().get("error").unwrap().as_str().unwrap().contains("'user_id'"));
     }
 
     #[test]
@@ -529,7 +734,7 @@ mod tests {
 
         let response = test.invite(&carl.token, &room_id, "mark.ruma.test");
 
-        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(response.status, Status::BadRequest);
     }
 
     #[test]
@@ -812,4 +1017,82 @@ mod tests {
             "The kickee is not currently in the room"
         );
     }
+
+    fn ban(test: &Test, room_id: &str, user_id: &str, sender: &str) {
+        let connection = test.connection();
+        let options = RoomMembershipOptions {
+            room_id: RoomId::try_from(room_id).unwrap(),
+            user_id: UserId::try_from(user_id).unwrap(),
+            sender: UserId::try_from(sender).unwrap(),
+            membership: "ban".to_string(),
+        };
+
+        RoomMembership::force_upsert(
+            &connection,
+            test.state_cache(),
+            test.clock(),
+            &Test::config(),
+            options,
+        ).unwrap();
+    }
+
+    #[test]
+    fn unban_user() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_options = format!(r#"{{"invite": ["{}"]}}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        ban(&test, &room_id, &bob.id, &alice.id);
+
+        assert_eq!(test.unban_from_room(&alice.token, &room_id, &bob.id).status, Status::Ok);
+
+        {
+            let connection = test.connection();
+            let membership = RoomMembership::find(
+                &connection,
+                &RoomId::try_from(room_id.as_ref()).unwrap(),
+                &UserId::try_from(bob.id.as_ref()).unwrap(),
+            ).unwrap().unwrap();
+
+            assert_eq!(membership.membership, "leave");
+            assert_eq!(membership.sender.to_string(), alice.id);
+        }
+
+        let response = test.unban_from_room(&alice.token, &room_id, &bob.id);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "The user is not banned from the room"
+        );
+    }
+
+    #[test]
+    fn unban_user_without_permissions() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        let room_options = format!(r#"{{"invite": ["{}", "{}"]}}"#, bob.id, carl.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&carl.token, &room_id).status, Status::Ok);
+
+        ban(&test, &room_id, &carl.id, &alice.id);
+
+        let response = test.unban_from_room(&bob.token, &room_id, &carl.id);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Insufficient power level to unban a user"
+        );
+    }
 }
@@ -13,6 +13,7 @@ use db::DB;
 use error::ApiError;
 use middleware::{JsonRequest, MiddlewareChain};
 use models::access_token::AccessToken;
+use models::user::User;
 use modifier::SerializableResponse;
 
 /// The `/login` endpoint.
@@ -56,12 +57,16 @@ struct LoginRequest {
     pub user: String,
     /// The user's password.
     pub password: String,
+    /// The ID of the client device. A new one is generated if it isn't given.
+    pub device_id: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 struct LoginResponse {
     /// An access token for the account. This access token can then be used to authorize other requests.
     pub access_token: String,
+    /// The ID of the device the access token was issued to.
+    pub device_id: String,
     /// The hostname of the homeserver on which the account has been registered.
     pub home_server: String,
     /// The fully-qualified Matrix ID that has been registered.
@@ -80,7 +85,10 @@ impl Handler for Login {
 
         let config = Config::from_request(request)?;
 
-        let user_id = match UserId::try_from(&login_request.user) {
+        // Localparts are lowercase. Older users with uppercase ones are still found below.
+        let user = login_request.user.to_lowercase();
+
+        let user_id = match UserId::try_from(&user) {
             Ok(user_id) => {
                 if user_id.hostname().to_string() != config.domain {
                     Err(ApiError::unauthorized("User cannot be identified by this homeserver".to_string()))?;
@@ -88,23 +96,36 @@ impl Handler for Login {
 
                 user_id
             },
-            Err(_) => UserId::try_from(&format!("@{}:{}", login_request.user, &config.domain))
+            Err(_) => UserId::try_from(&format!("@{}:{}", user, &config.domain))
                         .map_err(ApiError::from)?,
         };
 
+        let connection = DB::from_request(request)?;
+
+        // Log in with the user's stored ID even if the localpart was given in another case.
+        let user_id = match User::find_registered_user_ignoring_case(&connection, &user_id)? {
+            Some(user) => user.id,
+            None => user_id,
+        };
+
         let auth_params = AuthParams::Password(PasswordAuthParams {
             password: login_request.password,
             user_id: user_id,
         });
 
-        let connection = DB::from_request(request)?;
         let registered_user = auth_params.authenticate(&connection)
             .map_err(|_| ApiError::unauthorized("Invalid credentials".to_string()))?;
 
-        let access_token = AccessToken::create(&connection, &registered_user.id, &config.macaroon_secret_key)?;
+        let access_token = AccessToken::create(
+            &connection,
+            &registered_user.id,
+            login_request.device_id,
+            &config.macaroon_secret_key,
+        )?;
 
         let response = LoginResponse {
             access_token: access_token.value,
+            device_id: access_token.device_id,
             home_server: config.domain.clone(),
             user_id: registered_user.id,
         };
@@ -136,6 +157,29 @@ mod tests {
         assert_eq!(response.json().get("user_id").unwrap().as_str().unwrap(), "@carl:ruma.test");
     }
 
+    #[test]
+    fn localpart_is_case_insensitive() {
+        let test = Test::new();
+
+        assert!(test.register_user(
+            r#"{"username": "alice", "password": "secret"}"#
+        ).status.is_success());
+
+        for user in &["Alice", "@ALICE:ruma.test"] {
+            let body = format!(
+                r#"{{"type": "m.login.password", "user": "{}", "password": "secret"}}"#,
+                user
+            );
+            let response = test.post("/_matrix/client/r0/login", &body);
+
+            assert_eq!(response.status, Status::Ok);
+            assert_eq!(
+                response.json().get("user_id").unwrap().as_str().unwrap(),
+                "@alice:ruma.test"
+            );
+        }
+    }
+
     #[test]
     fn invalid_credentials() {
         let test = Test::new();
@@ -163,7 +207,7 @@ mod tests {
             r#"{"type": "m.login.email", "user": "carl", "password": "secret"}"#,
         );
 
-        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(response.status, Status::BadRequest);
     }
 
     #[test]
@@ -1,10 +1,12 @@
 use iron::{Chain, Handler, IronResult, Request, Response};
 use iron::status::Status;
 
+use access_token_cache::AccessTokenCache;
 use db::DB;
 use middleware::{AccessTokenAuth, MiddlewareChain};
 use models::access_token::AccessToken;
 use modifier::EmptyResponse;
+use request_ext::extension_mut;
 
 /// The `/logout` endpoint.
 pub struct Logout;
@@ -14,11 +16,12 @@ middleware_chain!(Logout, [AccessTokenAuth]);
 impl Handler for Logout {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
         let connection = DB::from_request(request)?;
+        let access_token_cache = AccessTokenCache::from_request(request)?;
 
-        let access_token = request.extensions.get_mut::<AccessToken>()
-            .expect("AccessTokenAuth should ensure an access token");
+        let access_token = extension_mut::<AccessToken>(request)?;
 
         access_token.revoke(&connection)?;
+        access_token_cache.invalidate(&access_token.value);
 
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
@@ -39,6 +42,26 @@ mod tests {
                                  user.token);
 
         assert!(test.post(&login_path, "{}").status.is_success());
-        assert_eq!(test.post(&login_path, "{}").status, Status::Forbidden);
+        let response = test.post(&login_path, "{}");
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNKNOWN_TOKEN"
+        );
+    }
+
+    #[test]
+    fn logout_invalidates_cached_access_token() {
+        let test = Test::new();
+        let user = test.create_user();
+        let threepids_path = format!("/_matrix/client/r0/account/3pid?access_token={}", user.token);
+
+        assert_eq!(test.get(&threepids_path).status, Status::Ok);
+        assert!(!test.access_token_cache().is_empty());
+
+        let logout_path = format!("/_matrix/client/r0/logout?access_token={}", user.token);
+        assert!(test.post(&logout_path, "{}").status.is_success());
+
+        assert_eq!(test.get(&threepids_path).status, Status::Unauthorized);
     }
 }
@@ -1,14 +1,19 @@
 //! Endpoints for room members.
 
+use std::collections::BTreeMap;
+
 use iron::{Chain, Handler, IronResult, Request, Response};
 use iron::status::Status;
 use ruma_events::room::member::MemberEvent;
 
 use db::DB;
+use error::ApiError;
 use middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use models::room::Room;
 use models::room_membership::RoomMembership;
-use models::user::User;
 use modifier::SerializableResponse;
+use request_ext::{authed_user, extension};
+use state_cache::StateCache;
 
 /// The `/rooms/:room_id/members` endpoint.
 pub struct Members;
@@ -22,15 +27,14 @@ middleware_chain!(Members, [RoomIdParam, AccessTokenAuth]);
 
 impl Handler for Members {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        request.extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user");
+        let user = authed_user(request)?;
 
         let connection = DB::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+
+        let room_id = extension::<RoomIdParam>(request)?;
 
-        let room_id = request.extensions.get::<RoomIdParam>()
-            .expect("Should have been required by RoomIdParam.")
-            .clone();
+        RoomMembership::require(&connection, &state_cache, &room_id, &user.id, &["join"])?;
 
         let events = RoomMembership::get_events_by_room(&connection, room_id)?;
 
@@ -40,6 +44,63 @@ impl Handler for Members {
     }
 }
 
+/// The GET `/rooms/:room_id/invited_members` endpoint.
+///
+/// Lists the users with pending invites to the room. Only joined members and server admins may
+/// see them.
+pub struct InvitedMembers;
+
+#[derive(Debug, Serialize)]
+struct InvitedMembersResponse {
+    /// The invited users, by user ID.
+    invited: BTreeMap<String, InvitedMember>,
+}
+
+#[derive(Debug, Serialize)]
+struct InvitedMember {
+    /// The display name of the user when they were invited.
+    display_name: Option<String>,
+    /// The avatar URL of the user when they were invited.
+    avatar_url: Option<String>,
+}
+
+middleware_chain!(InvitedMembers, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for InvitedMembers {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = authed_user(request)?;
+
+        let connection = DB::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+
+        let room_id = extension::<RoomIdParam>(request)?;
+
+        if !user.admin {
+            RoomMembership::require(&connection, &state_cache, &room_id, &user.id, &["join"])?;
+        } else if Room::find(&connection, &room_id)?.is_none() {
+            Err(ApiError::not_found("The room was not found.".to_string()))?;
+        }
+
+        let events = RoomMembership::get_events_by_room_and_state(&connection, &room_id, "invite")?;
+
+        let invited = events
+            .into_iter()
+            .map(|event| {
+                let member = InvitedMember {
+                    display_name: event.content.displayname,
+                    avatar_url: event.content.avatar_url,
+                };
+
+                (event.state_key, member)
+            })
+            .collect();
+
+        let response = InvitedMembersResponse { invited: invited };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use test::Test;
@@ -70,4 +131,81 @@ mod tests {
         let chunk = chunk.as_array().unwrap();
         assert_eq!(chunk.len(), 1);
     }
+
+    #[test]
+    fn non_members_cant_tell_private_rooms_from_missing_ones() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures("{}");
+        let bob = test.create_user();
+
+        let members_path = |room_id: &str| {
+            format!("/_matrix/client/r0/rooms/{}/members?access_token={}", room_id, bob.token)
+        };
+
+        let response = test.get(&members_path(&room_id));
+        let missing = test.get(&members_path("!missing:ruma.test"));
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(missing.status, Status::NotFound);
+        assert_eq!(response.raw_body, missing.raw_body);
+    }
+
+    #[test]
+    fn invited_members() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let carl = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+                bob.id,
+                bob.token
+            ),
+            r#"{"displayname": "Bob"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        assert_eq!(test.invite(&alice.token, &room_id, &bob.id).status, Status::Ok);
+        assert_eq!(test.invite(&alice.token, &room_id, &carl.id).status, Status::Ok);
+        assert_eq!(test.join_room(&carl.token, &room_id).status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/invited_members?access_token={}",
+            room_id,
+            alice.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let invited = response.json().get("invited").unwrap().as_object().unwrap();
+        assert_eq!(invited.len(), 1);
+        assert_eq!(
+            invited.get(&bob.id).unwrap().get("display_name").unwrap().as_str().unwrap(),
+            "Bob"
+        );
+    }
+
+    #[test]
+    fn only_joined_members_and_admins_see_invited_members() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let admin = test.create_admin();
+        let room_id = test.create_room(&alice.token);
+
+        assert_eq!(test.invite(&alice.token, &room_id, &bob.id).status, Status::Ok);
+
+        let invited_members_path = |token: &str| {
+            format!("/_matrix/client/r0/rooms/{}/invited_members?access_token={}", room_id, token)
+        };
+
+        let response = test.get(&invited_members_path(&bob.token));
+        assert_eq!(response.status, Status::NotFound);
+
+        let response = test.get(&invited_members_path(&admin.token));
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().pointer(&format!("/invited/{}", bob.id)).is_some());
+    }
 }
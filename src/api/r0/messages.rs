@@ -0,0 +1,619 @@
+//! Endpoints for paginating through the history of a room.
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::error::Error;
+
+use base64::decode;
+use bodyparser;
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use ruma_events::CustomRoomEvent;
+use ruma_events::collections::all::RoomEvent;
+use ruma_identifiers::UserId;
+use serde_json::{Map, Value, from_str};
+
+use config::Config;
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomIdParam};
+use models::access_token::AccessToken;
+use models::event::{Event, PaginationDirection};
+use models::filter::{ContentFilter, Filter, RoomEventFilter};
+use models::megolm_session::ClaimedMegolmSession;
+use models::room_membership::RoomMembership;
+use models::user::User;
+use modifier::{EmptyResponse, SerializableResponse};
+use query::{Batch, room_event};
+use query_params;
+use request_ext::{authed_user, extension};
+use state_cache::StateCache;
+
+/// The number of events returned if neither the request nor its filter sets a limit.
+const DEFAULT_LIMIT: u64 = 10;
+
+/// The type of end-to-end encrypted events.
+const ENCRYPTED_EVENT_TYPE: &'static str = "m.room.encrypted";
+
+/// The encryption algorithm of the sessions devices can claim.
+const MEGOLM_ALGORITHM: &'static str = "m.megolm.v1.aes-sha2";
+
+/// The values of the `dir` parameter.
+const DIRECTIONS: [(&'static str, PaginationDirection); 2] = [
+    ("f", PaginationDirection::Forward),
+    ("b", PaginationDirection::Backward),
+];
+
+/// The GET `/rooms/:room_id/messages` endpoint.
+///
+/// Pages through the room's events from the `from` token, backwards if `dir` is `b` and forwards
+/// if it is `f`, stopping at the `to` token if given. The `filter` parameter is the ID of a filter
+/// created with the filter API or a filter JSON object, optionally base64 encoded. The types and
+/// senders of its `room.timeline` filter restrict the events returned.
+///
+/// Not part of the Matrix spec: once the requesting device has claimed Megolm sessions of the
+/// room with `megolm_sessions`, `m.room.encrypted` events of other sessions get
+/// `"encrypted_for_this_device": false` in their `unsigned` data, so that clients can show them as
+/// undecryptable right away.
+pub struct Messages;
+
+#[derive(Debug, Serialize)]
+struct MessagesResponse {
+    /// The token the pagination started at.
+    start: String,
+    /// The token to continue the pagination from.
+    end: String,
+    /// The events, in the order they were paginated through.
+    chunk: Vec<RoomEvent>,
+}
+
+middleware_chain!(Messages, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for Messages {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = authed_user(request)?;
+        let device_id = extension::<AccessToken>(request)?.device_id;
+        let room_id = extension::<RoomIdParam>(request)?;
+
+        let config = Config::from_request(request)?;
+
+        let from = match query_params::get_token::<Batch>(request, "from")? {
+            Some(from) => from,
+            None => Err(ApiError::missing_param("from"))?,
+        };
+
+        let to = query_params::get_token::<Batch>(request, "to")?;
+
+        let direction = match query_params::get_enum(request, "dir", &DIRECTIONS)? {
+            Some(direction) => direction,
+            None => Err(ApiError::missing_param("dir"))?,
+        };
+
+        let filter = query_params::get(request, "filter");
+
+        let connection = DB::from_request(request)?;
+
+        let state_cache = StateCache::from_request(request)?;
+
+        RoomMembership::require(&connection, &state_cache, &room_id, &user.id, &["join"])?;
+
+        let timeline_filter = match filter {
+            Some(filter) => timeline_filter(&connection, &user, &filter)?,
+            None => None,
+        };
+
+        let default_limit = match timeline_filter {
+            Some(ref filter) if filter.limit > 0 => filter.limit as u64,
+            _ => DEFAULT_LIMIT,
+        };
+        let limit = query_params::get_u64(
+            request,
+            "limit",
+            default_limit,
+            config.max_pagination_limit,
+        )?;
+
+        let events = Event::find_room_events_page(
+            &connection,
+            &room_id,
+            from.room_key,
+            to.map(|to| to.room_key),
+            direction,
+            limit as i64,
+            timeline_filter.as_ref(),
+        )?;
+
+        let end_key = match (events.last(), direction) {
+            (Some(event), PaginationDirection::Forward) => event.ordering,
+            (Some(event), PaginationDirection::Backward) => event.ordering - 1,
+            (None, _) => from.room_key,
+        };
+
+        let claimed_sessions = ClaimedMegolmSession::find_session_ids(
+            &connection,
+            &user.id,
+            &device_id,
+            &room_id,
+        )?;
+
+        let mut chunk = Vec::with_capacity(events.len());
+
+        for event in events {
+            if event.event_type == ENCRYPTED_EVENT_TYPE {
+                let event = encrypted_event(event, &user.id, &device_id, &claimed_sessions)?;
+
+                chunk.push(RoomEvent::CustomRoom(event));
+            } else if let Some(event) = room_event(event)? {
+                chunk.push(event);
+            }
+        }
+
+        let response = MessagesResponse {
+            start: from.to_string(),
+            end: Batch::new(end_key, from.presence_key).to_string(),
+            chunk: chunk,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The POST `/rooms/:room_id/megolm_sessions` endpoint.
+///
+/// Not part of the Matrix spec. Records that the requesting device holds the keys of the Megolm
+/// sessions in `session_ids`, in addition to those it claimed before, so that `messages` can tell
+/// it which encrypted events it can't decrypt.
+pub struct ClaimMegolmSessions;
+
+#[derive(Clone, Debug, Deserialize)]
+struct ClaimMegolmSessionsRequest {
+    /// The IDs of the sessions of the room whose keys the device holds.
+    session_ids: Vec<String>,
+}
+
+middleware_chain!(ClaimMegolmSessions, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for ClaimMegolmSessions {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let claim_request = match request.get::<bodyparser::Struct<ClaimMegolmSessionsRequest>>() {
+            Ok(Some(claim_request)) => claim_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let user = authed_user(request)?;
+        let device_id = extension::<AccessToken>(request)?.device_id;
+        let room_id = extension::<RoomIdParam>(request)?;
+
+        let connection = DB::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+
+        RoomMembership::require(&connection, &state_cache, &room_id, &user.id, &["join"])?;
+
+        ClaimedMegolmSession::claim(
+            &connection,
+            &user.id,
+            &device_id,
+            &room_id,
+            &claim_request.session_ids,
+        )?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// Converts an `m.room.encrypted` event, marking it if the device can't decrypt it.
+///
+/// Devices that haven't claimed any session of the room get no hints, since the server knows
+/// nothing about their keys.
+fn encrypted_event(
+    event: Event,
+    user_id: &UserId,
+    device_id: &str,
+    claimed_sessions: &HashSet<String>,
+) -> Result<CustomRoomEvent, ApiError> {
+    let mut event: CustomRoomEvent = event.try_into()?;
+
+    if !claimed_sessions.is_empty() && !can_decrypt(&event, user_id, device_id, claimed_sessions) {
+        let mut unsigned = Map::new();
+        unsigned.insert("encrypted_for_this_device".to_string(), Value::Bool(false));
+
+        event.unsigned = Some(Value::Object(unsigned));
+    }
+
+    Ok(event)
+}
+
+/// Whether or not the device may be able to decrypt the encrypted event, as far as the server
+/// knows. Only Megolm events are known to be undecryptable, when the device neither claimed their
+/// session nor sent them.
+fn can_decrypt(
+    event: &CustomRoomEvent,
+    user_id: &UserId,
+    device_id: &str,
+    claimed_sessions: &HashSet<String>,
+) -> bool {
+    let field = |key: &str| event.content.get(key).and_then(Value::as_str);
+
+    if field("algorithm") != Some(MEGOLM_ALGORITHM) {
+        return true;
+    }
+
+    // A device holds the keys of the sessions it sends messages with.
+    if event.user_id == *user_id && field("device_id") == Some(device_id) {
+        return true;
+    }
+
+    match field("session_id") {
+        Some(session_id) => claimed_sessions.contains(session_id),
+        None => true,
+    }
+}
+
+/// The `room.timeline` part of the filter given by ID or as JSON.
+fn timeline_filter(connection: &PgConnection, user: &User, filter: &str)
+-> Result<Option<RoomEventFilter>, ApiError> {
+    let content = match filter.parse::<i64>() {
+        Ok(id) => Filter::find(connection, user.id.clone(), id)?.content,
+        Err(_) if filter.starts_with('{') => filter.to_string(),
+        Err(_) => {
+            let decoded = decode(filter)
+                .map_err(|err| ApiError::invalid_param("filter", err.description()))?;
+
+            String::from_utf8(decoded)
+                .map_err(|err| ApiError::invalid_param("filter", err.description()))?
+        }
+    };
+
+    let filter: ContentFilter = from_str(&content)
+        .map_err(|err| ApiError::invalid_param("filter", err.description()))?;
+
+    Ok(filter.room.and_then(|room| room.timeline))
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::encode;
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::{Response, Test};
+
+    fn messages(test: &Test, access_token: &str, room_id: &str, query: &str) -> Response {
+        test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/messages?{}&access_token={}",
+            room_id,
+            query,
+            access_token
+        ))
+    }
+
+    /// The types and message bodies of the events in the response's chunk.
+    fn chunk(response: &Response) -> Vec<(String, Option<String>)> {
+        response.json().get("chunk").unwrap().as_array().unwrap().iter().map(|event| {
+            let event_type = event.get("type").unwrap().as_str().unwrap().to_string();
+            let body = event.get("content").unwrap().get("body").and_then(Value::as_str);
+
+            (event_type, body.map(|body| body.to_string()))
+        }).collect()
+    }
+
+    fn bodies(response: &Response) -> Vec<String> {
+        chunk(response).into_iter().filter_map(|(_, body)| body).collect()
+    }
+
+    fn token(response: &Response, name: &str) -> String {
+        response.json().get(name).unwrap().as_str().unwrap().to_string()
+    }
+
+    /// Sends a Megolm event from the given device and session.
+    fn send_encrypted(
+        test: &Test,
+        access_token: &str,
+        room_id: &str,
+        device_id: &str,
+        session_id: &str,
+        txn_id: u64,
+    ) {
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/send/m.room.encrypted/{}?access_token={}",
+                room_id,
+                txn_id,
+                access_token
+            ),
+            &format!(
+                r#"{{
+                    "algorithm": "m.megolm.v1.aes-sha2",
+                    "ciphertext": "AwgAEnAC",
+                    "device_id": "{}",
+                    "sender_key": "c2VuZGVyIGtleQ",
+                    "session_id": "{}"
+                }}"#,
+                device_id,
+                session_id
+            ),
+        );
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    fn claim_sessions(test: &Test, access_token: &str, room_id: &str, session_ids: &str)
+    -> Response {
+        test.post(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/megolm_sessions?access_token={}",
+                room_id,
+                access_token
+            ),
+            &format!(r#"{{"session_ids": {}}}"#, session_ids),
+        )
+    }
+
+    /// The session IDs of the encrypted events in the response's chunk, with their
+    /// `encrypted_for_this_device` hints.
+    fn decryption_hints(response: &Response) -> Vec<(String, Option<bool>)> {
+        response.json().get("chunk").unwrap().as_array().unwrap().iter()
+            .filter(|event| event.get("type").and_then(Value::as_str) == Some("m.room.encrypted"))
+            .map(|event| {
+                let session_id = event.pointer("/content/session_id").unwrap().as_str().unwrap();
+                let hint = event.pointer("/unsigned/encrypted_for_this_device")
+                    .and_then(Value::as_bool);
+
+                (session_id.to_string(), hint)
+            })
+            .collect()
+    }
+
+    /// A token for the position after all events of the test.
+    fn latest_token(test: &Test, access_token: &str) -> String {
+        let response = test.get(&format!("/_matrix/client/r0/sync?access_token={}", access_token));
+
+        token(&response, "next_batch")
+    }
+
+    #[test]
+    fn paginate_backwards_and_forwards() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        test.send_message(&alice.token, &room_id, "one", 1);
+        test.send_message(&alice.token, &room_id, "two", 2);
+        test.send_message(&alice.token, &room_id, "three", 3);
+
+        let latest = latest_token(&test, &alice.token);
+
+        let query = format!("from={}&dir=b&limit=2", latest);
+        let response = messages(&test, &alice.token, &room_id, &query);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(bodies(&response), vec!["three", "two"]);
+        assert_eq!(token(&response, "start"), latest);
+
+        let end = token(&response, "end");
+        let query = format!("from={}&dir=b&limit=1", end);
+        let response = messages(&test, &alice.token, &room_id, &query);
+
+        assert_eq!(bodies(&response), vec!["one"]);
+
+        let query = format!("from={}&dir=f", end);
+        let response = messages(&test, &alice.token, &room_id, &query);
+
+        assert_eq!(bodies(&response), vec!["two", "three"]);
+        assert_eq!(token(&response, "end"), latest);
+    }
+
+    #[test]
+    fn pagination_stops_at_to() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        test.send_message(&alice.token, &room_id, "one", 1);
+        let middle = latest_token(&test, &alice.token);
+        test.send_message(&alice.token, &room_id, "two", 2);
+
+        let latest = latest_token(&test, &alice.token);
+
+        let response = messages(
+            &test,
+            &alice.token,
+            &room_id,
+            &format!("from={}&to={}&dir=b", latest, middle),
+        );
+
+        assert_eq!(chunk(&response), vec![("m.room.message".to_string(), Some("two".to_string()))]);
+    }
+
+    #[test]
+    fn filter_by_type() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        test.send_message(&alice.token, &room_id, "one", 1);
+        test.send_state_event(&alice.token, &room_id, "m.room.topic", r#"{"topic":"Rust"}"#);
+        test.send_message(&alice.token, &room_id, "two", 2);
+
+        let latest = latest_token(&test, &alice.token);
+
+        let filter = r#"{"room":{"timeline":{"types":["m.room.message"]}}}"#;
+        let response = messages(
+            &test,
+            &alice.token,
+            &room_id,
+            &format!("from={}&dir=b&limit=2&filter={}", latest, filter),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(bodies(&response), vec!["two", "one"]);
+        assert_eq!(chunk(&response).len(), 2);
+
+        let filter = encode(r#"{"room":{"timeline":{"not_types":["m.room.message"]}}}"#);
+        let response = messages(
+            &test,
+            &alice.token,
+            &room_id,
+            &format!("from={}&dir=b&limit=1&filter={}", latest, filter),
+        );
+
+        assert_eq!(chunk(&response), vec![("m.room.topic".to_string(), None)]);
+    }
+
+    #[test]
+    fn filter_values_are_not_sql() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        test.send_message(&alice.token, &room_id, "one", 1);
+
+        let latest = latest_token(&test, &alice.token);
+
+        let filter = encode(r#"{"room":{"timeline":{"types":["x' OR 'x' = 'x"]}}}"#);
+        let response = messages(
+            &test,
+            &alice.token,
+            &room_id,
+            &format!("from={}&dir=b&filter={}", latest, filter),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(chunk(&response).is_empty());
+    }
+
+    #[test]
+    fn filter_by_sender() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_options = format!(r#"{{"invite":["{}"]}}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        test.send_message(&alice.token, &room_id, "from alice", 1);
+        test.send_message(&bob.token, &room_id, "from bob", 1);
+
+        let latest = latest_token(&test, &alice.token);
+
+        let filter_id = test.create_filter(
+            &alice.token,
+            &alice.id,
+            &format!(r#"{{"room":{{"timeline":{{"senders":["{}"],"limit":1}}}}}}"#, bob.id),
+        );
+        let response = messages(
+            &test,
+            &alice.token,
+            &room_id,
+            &format!("from={}&dir=b&filter={}", latest, filter_id),
+        );
+
+        assert_eq!(bodies(&response), vec!["from bob"]);
+
+        let filter = format!(
+            r#"{{"room":{{"timeline":{{"types":["m.room.message"],"not_senders":["{}"]}}}}}}"#,
+            bob.id
+        );
+        let response = messages(
+            &test,
+            &alice.token,
+            &room_id,
+            &format!("from={}&dir=b&filter={}", latest, filter),
+        );
+
+        assert_eq!(bodies(&response), vec!["from alice"]);
+    }
+
+    #[test]
+    fn invalid_query() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = messages(&test, &alice.token, &room_id, "dir=b");
+        assert_eq!(response.status, Status::BadRequest);
+
+        let response = messages(&test, &alice.token, &room_id, "from=1_0");
+        assert_eq!(response.status, Status::BadRequest);
+
+        let response = messages(&test, &alice.token, &room_id, "from=1_0&dir=x");
+        assert_eq!(response.status, Status::BadRequest);
+
+        let response = messages(&test, &alice.token, &room_id, "from=1_0&dir=b&filter=[]");
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn non_member_cant_tell_the_room_exists() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures("{}");
+        let bob = test.create_user();
+
+        let response = messages(&test, &bob.token, &room_id, "from=1_0&dir=b");
+        let missing = messages(&test, &bob.token, "!missing:ruma.test", "from=1_0&dir=b");
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(missing.status, Status::NotFound);
+        assert_eq!(response.raw_body, missing.raw_body);
+    }
+
+    #[test]
+    fn encrypted_events_of_unclaimed_sessions_are_marked() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_options = format!(r#"{{"invite":["{}"]}}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let phone = test.login_device(&bob, "PHONE");
+
+        send_encrypted(&test, &alice.token, &room_id, "ALICE", "known", 1);
+        send_encrypted(&test, &alice.token, &room_id, "ALICE", "unknown", 2);
+        send_encrypted(&test, &bob.token, &room_id, "PHONE", "own", 1);
+
+        let latest = latest_token(&test, &alice.token);
+        let query = format!("from={}&dir=b", latest);
+
+        // Without claims, the server knows nothing about the keys of the device.
+        let response = messages(&test, &phone, &room_id, &query);
+        assert_eq!(
+            decryption_hints(&response),
+            vec![
+                ("own".to_string(), None),
+                ("unknown".to_string(), None),
+                ("known".to_string(), None),
+            ]
+        );
+
+        let response = claim_sessions(&test, &phone, &room_id, r#"["known"]"#);
+        assert_eq!(response.status, Status::Ok);
+
+        // Claiming a session again changes nothing.
+        let response = claim_sessions(&test, &phone, &room_id, r#"["known", "known"]"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = messages(&test, &phone, &room_id, &query);
+        assert_eq!(
+            decryption_hints(&response),
+            vec![
+                ("own".to_string(), None),
+                ("unknown".to_string(), Some(false)),
+                ("known".to_string(), None),
+            ]
+        );
+
+        // The claims of one device don't apply to the other devices of the user.
+        let response = messages(&test, &bob.token, &room_id, &query);
+        assert!(decryption_hints(&response).iter().all(|&(_, hint)| hint.is_none()));
+    }
+
+    #[test]
+    fn non_members_cant_claim_sessions() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures("{}");
+        let bob = test.create_user();
+
+        let response = claim_sessions(&test, &bob.token, &room_id, r#"["session"]"#);
+        assert_eq!(response.status, Status::NotFound);
+    }
+}
@@ -1,42 +1,92 @@
 //! API endpoints for the 0.x.x version of the Matrix spec.
 
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use error::ApiError;
+
 pub use self::account::{
     AccountPassword,
     DeactivateAccount,
+    DeleteAccountData,
+    GetAllRoomAccountData,
+    GetThreePids,
     PutAccountData,
     PutRoomAccountData,
 };
-pub use self::directory::{GetRoomAlias, DeleteRoomAlias, PutRoomAlias};
-pub use self::event_creation::{SendMessageEvent, StateMessageEvent};
-pub use self::join::{InviteToRoom, JoinRoom, JoinRoomWithIdOrAlias, KickFromRoom, LeaveRoom};
+pub use self::admin::{GetBackgroundJobs, GetWorkers};
+pub use self::context::Context;
+pub use self::directory::{GetRoomAlias, GetRoomAliases, DeleteRoomAlias, PutRoomAlias};
+pub use self::event_creation::{BatchSendEvents, SendMessageEvent, StateMessageEvent};
+pub use self::join::{
+    InviteToRoom,
+    JoinRoom,
+    JoinRoomWithIdOrAlias,
+    KickFromRoom,
+    LeaveRoom,
+    UnbanFromRoom,
+};
 pub use self::login::Login;
 pub use self::logout::Logout;
-pub use self::members::Members;
+pub use self::members::{InvitedMembers, Members};
+pub use self::messages::{ClaimMegolmSessions, Messages};
+pub use self::notifications::GetRoomNotifications;
 pub use self::presence::{GetPresenceList, GetPresenceStatus, PostPresenceList, PutPresenceStatus};
 pub use self::profile::{Profile, GetAvatarUrl, PutAvatarUrl, GetDisplayName, PutDisplayName};
+pub use self::public_rooms::{GetPublicRooms, PostPublicRooms};
+pub use self::push_rules::{GetPushRuleEnabled, PutPushRuleEnabled};
 pub use self::pushers::{GetPushers, SetPushers};
+pub use self::receipts::PrivateReadReceipt;
 pub use self::registration::Register;
 pub use self::room_creation::CreateRoom;
-pub use self::room_info::RoomState;
-pub use self::tags::{DeleteTag, GetTags, PutTag};
+pub use self::room_info::{GetStateEvent, RoomState};
+pub use self::room_keys::{
+    CreateKeyBackupVersion,
+    DeleteKeyBackupVersion,
+    GetKeyBackupVersion,
+    GetRoomKeys,
+    PutRoomKey,
+    PutRoomKeys,
+};
+pub use self::tags::{DeleteTag, GetAllTags, GetTags, PutTag};
+pub use self::timestamp_to_event::TimestampToEvent;
+pub use self::to_device::SendToDevice;
 pub use self::sync::Sync;
 pub use self::versions::Versions;
 pub use self::filter::{GetFilter, PostFilter};
+pub use self::groups::GetRelatedGroups;
 
 mod account;
+mod admin;
+mod context;
 mod directory;
 mod event_creation;
 mod filter;
+mod groups;
 mod join;
 mod login;
 mod logout;
 mod members;
+mod messages;
+mod notifications;
 mod presence;
 mod profile;
+mod public_rooms;
+mod push_rules;
 mod pushers;
+mod receipts;
 mod registration;
 mod room_creation;
 mod room_info;
+mod room_keys;
 mod tags;
+mod timestamp_to_event;
+mod to_device;
 mod sync;
 mod versions;
+
+/// Converts a `SystemTime` to milliseconds since the Unix epoch.
+pub fn milliseconds_since_epoch(time: SystemTime) -> Result<u64, ApiError> {
+    let duration = time.duration_since(UNIX_EPOCH)?;
+
+    Ok(duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64)
+}
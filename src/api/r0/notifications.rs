@@ -0,0 +1,193 @@
+//! Endpoints for notifications.
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+use ruma_events::collections::all::RoomEvent;
+
+use config::Config;
+use db::DB;
+use middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use models::notification::Notification;
+use models::room_membership::RoomMembership;
+use modifier::SerializableResponse;
+use query::room_event;
+use query_params;
+use request_ext::{authed_user, extension};
+use state_cache::StateCache;
+
+/// The GET `/rooms/:room_id/notifications` endpoint.
+///
+/// Counts the user's unread notifications in the room, i.e. those for events after their latest
+/// read receipt, and returns the most recent `limit` of their events, oldest first.
+pub struct GetRoomNotifications;
+
+#[derive(Debug, Serialize)]
+struct GetRoomNotificationsResponse {
+    /// The number of unread notifications.
+    notification_count: u64,
+    /// The number of unread notifications that mention the user.
+    highlight_count: u64,
+    /// The events of the unread notifications.
+    events: Vec<RoomEvent>,
+}
+
+middleware_chain!(GetRoomNotifications, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for GetRoomNotifications {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = authed_user(request)?;
+
+        let room_id = extension::<RoomIdParam>(request)?;
+
+        let config = Config::from_request(request)?;
+        let max_limit = config.max_pagination_limit;
+        let limit = query_params::get_u64(request, "limit", max_limit, max_limit)? as usize;
+
+        let connection = DB::from_request(request)?;
+
+        let state_cache = StateCache::from_request(request)?;
+
+        RoomMembership::require(&connection, &state_cache, &room_id, &user.id, &["join"])?;
+
+        let notifications = Notification::find_unread(&connection, &room_id, &user.id)?;
+
+        let notification_count = notifications.len() as u64;
+        let highlight_count = notifications.iter()
+            .filter(|notification| notification.highlight)
+            .count() as u64;
+
+        let skip = notifications.len().saturating_sub(limit);
+        let mut events = Vec::new();
+
+        for notification in notifications.into_iter().skip(skip) {
+            if let Some(event) = room_event(notification.event)? {
+                events.push(event);
+            }
+        }
+
+        let response = GetRoomNotificationsResponse {
+            notification_count: notification_count,
+            highlight_count: highlight_count,
+            events: events,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::{Test, json_at};
+
+    #[test]
+    fn unread_notifications_since_read_receipt() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_id = test.create_room_with_params(&alice.token, r#"{"visibility": "public"}"#);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        test.send_message(&bob.token, &room_id, "Hi everyone", 1);
+        test.send_message(&alice.token, &room_id, "Hi Bob", 1);
+        let response = test.send_message(
+            &bob.token,
+            &room_id,
+            &format!("How are you, {}?", alice.name),
+            2,
+        );
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap().to_string();
+
+        let notifications_path = format!(
+            "/_matrix/client/r0/rooms/{}/notifications?access_token={}",
+            room_id,
+            alice.token
+        );
+
+        let response = test.get(&notifications_path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("notification_count").unwrap().as_u64().unwrap(), 2);
+        assert_eq!(response.json().get("highlight_count").unwrap().as_u64().unwrap(), 1);
+        assert_eq!(response.json().get("events").unwrap().as_array().unwrap().len(), 2);
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/receipt/m.read.private/{}?access_token={}",
+                room_id,
+                event_id,
+                alice.token
+            ),
+            "{}",
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&notifications_path);
+
+        assert_eq!(response.json().get("notification_count").unwrap().as_u64().unwrap(), 0);
+        assert_eq!(response.json().get("highlight_count").unwrap().as_u64().unwrap(), 0);
+        assert!(response.json().get("events").unwrap().as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn notifications_of_room_without_membership() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/notifications?access_token={}",
+            room_id,
+            bob.token
+        ));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn disabled_push_rules_do_not_notify() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let bob = test.register("bob");
+
+        let room_id = alice.create_room(&test, r#"{"visibility": "public"}"#);
+        assert_eq!(bob.join(&test, &room_id).status, Status::Ok);
+
+        bob.send_message(&test, &room_id, "Hi everyone");
+        bob.send_message(&test, &room_id, "How are you, alice?");
+
+        let notifications_path = format!("/_matrix/client/r0/rooms/{}/notifications", room_id);
+        let set_enabled = |kind, rule_id, enabled| {
+            let response = test.put_as(
+                &alice,
+                &format!("/_matrix/client/r0/pushrules/global/{}/{}/enabled", kind, rule_id),
+                &format!(r#"{{"enabled": {}}}"#, enabled),
+            );
+            assert_eq!(response.status, Status::Ok);
+        };
+        let counts = || {
+            let response = test.get_as(&alice, &notifications_path);
+
+            (
+                json_at(&response, "notification_count").as_u64().unwrap(),
+                json_at(&response, "highlight_count").as_u64().unwrap(),
+            )
+        };
+
+        assert_eq!(counts(), (2, 1));
+
+        set_enabled("underride", ".m.rule.message", false);
+        assert_eq!(counts(), (1, 1));
+
+        set_enabled("content", ".m.rule.contains_user_name", false);
+        assert_eq!(counts(), (0, 0));
+
+        set_enabled("underride", ".m.rule.message", true);
+        set_enabled("content", ".m.rule.contains_user_name", true);
+        set_enabled("override", ".m.rule.master", true);
+        assert_eq!(counts(), (0, 0));
+    }
+}
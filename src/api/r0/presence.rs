@@ -6,6 +6,7 @@ use iron::{Chain, Handler, IronResult, IronError, Plugin, Request, Response};
 use ruma_identifiers::UserId;
 use ruma_events::presence::PresenceState;
 
+use clock;
 use config::Config;
 use db::DB;
 use error::ApiError;
@@ -13,8 +14,8 @@ use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, UserIdParam};
 use models::room_membership::RoomMembership;
 use models::presence_list::PresenceList;
 use models::presence_status::{PresenceStatus, get_now};
-use models::user::User;
 use modifier::{SerializableResponse, EmptyResponse};
+use request_ext::{authed_user, extension};
 
 /// The PUT `/presence/:user_id/status` endpoint.
 pub struct PutPresenceStatus;
@@ -31,11 +32,9 @@ middleware_chain!(PutPresenceStatus, [UserIdParam, JsonRequest, AccessTokenAuth]
 
 impl Handler for PutPresenceStatus {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
+        let user_id = extension::<UserIdParam>(request)?;
 
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
         let put_presence_status_request = match request.get::<bodyparser::Struct<PutPresenceStatusRequest>>() {
             Ok(Some(request)) => request,
@@ -44,6 +43,7 @@ impl Handler for PutPresenceStatus {
 
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
+        let clock = clock::from_request(request)?;
 
         if user_id != user.id {
             let error = ApiError::unauthorized(
@@ -54,6 +54,7 @@ impl Handler for PutPresenceStatus {
 
         PresenceStatus::upsert(
             &connection,
+            &*clock,
             &config.domain,
             &user_id,
             Some(put_presence_status_request.presence),
@@ -84,13 +85,12 @@ struct GetPresenceStatusResponse {
 
 impl Handler for GetPresenceStatus {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
+        let user_id = extension::<UserIdParam>(request)?;
 
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
         let connection = DB::from_request(request)?;
+        let clock = clock::from_request(request)?;
 
         if user.id != user_id {
             let rooms = RoomMembership::find_common_rooms(
@@ -116,7 +116,7 @@ impl Handler for GetPresenceStatus {
         let presence_state: PresenceState = status.presence.parse()
             .expect("Database insert should ensure a PresenceState");
 
-        let now = get_now();
+        let now = get_now(&*clock);
         let last_active_ago = now - status.updated_at.0;
 
         let response = GetPresenceStatusResponse {
@@ -149,11 +149,9 @@ impl Handler for PostPresenceList {
             Ok(Some(request)) => request,
             Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
         };
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
+        let user_id = extension::<UserIdParam>(request)?;
 
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
         let connection = DB::from_request(request)?;
 
@@ -183,13 +181,14 @@ middleware_chain!(GetPresenceList, [UserIdParam, AccessTokenAuth]);
 
 impl Handler for GetPresenceList {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
+        let user_id = extension::<UserIdParam>(request)?;
 
         let connection = DB::from_request(request)?;
+        let clock = clock::from_request(request)?;
 
         let (_, events) = PresenceList::find_events_by_uid(
             &connection,
+            &*clock,
             &user_id,
             None
         )?;
@@ -200,7 +199,6 @@ impl Handler for GetPresenceList {
 
 #[cfg(test)]
 mod tests {
-    use std::thread;
     use std::time::Duration;
 
     use iron::status::Status;
@@ -346,6 +344,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn inviting_to_presence_list_twice_is_a_conflict() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let alice = test.create_user();
+        assert_eq!(test.join_room(&alice.token, &room_id).status, Status::Ok);
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id,
+            alice.token
+        );
+        let body = format!(r#"{{"invite":["{}"], "drop": []}}"#, carl.id);
+
+        assert_eq!(test.post(&presence_list_path, &body).status, Status::Ok);
+
+        let response = test.post(&presence_list_path, &body);
+        assert_eq!(response.status, Status::Conflict);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "IO_RUMA_CONFLICT");
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "The user is already on the presence list."
+        );
+        assert!(!response.body.contains("duplicate key"));
+        assert!(!response.body.contains("presence_list_pkey"));
+    }
+
     #[test]
     fn forbidden_presence_list_no_shared_room() {
         let test = Test::new();
@@ -373,7 +398,7 @@ mod tests {
             alice.token
         );
         let response = test.post(&presence_list_path, r#"{"invite":["@carl:ruma.test"], "drop": []}"#);
-        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(response.status, Status::BadRequest);
     }
 
     #[test]
@@ -387,7 +412,7 @@ mod tests {
             alice.token
         );
         let response = test.post(&presence_list_path, r#"{"invite":[], "drop": ["@carl:ruma.test"]}"#);
-        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(response.status, Status::BadRequest);
     }
 
     #[test]
@@ -452,10 +477,10 @@ mod tests {
         assert_eq!(test.join_room(&carl.token, &room_id).status, Status::Ok);
 
         test.update_presence(&alice.token, &alice.id, r#"{"presence":"online"}"#);
-        thread::sleep(Duration::from_secs(2));
+        test.clock().advance(Duration::from_secs(2));
 
         test.update_presence(&bob.token, &bob.id, r#"{"presence":"online"}"#);
-        thread::sleep(Duration::from_secs(2));
+        test.clock().advance(Duration::from_secs(2));
 
         let alice_presence_path = format!(
             "/_matrix/client/r0/presence/{}/status?access_token={}",
@@ -472,13 +497,11 @@ mod tests {
         let bob_response = test.get(&bob_presence_path);
         assert_eq!(bob_response.status, Status::Ok);
         let last_active_ago = bob_response.json().get("last_active_ago").unwrap().as_u64().unwrap();
-        assert!(last_active_ago > 2_000);
-        assert!(last_active_ago < 2_500);
+        assert_eq!(last_active_ago, 2_000);
 
         let alice_response = test.get(&alice_presence_path);
         assert_eq!(alice_response.status, Status::Ok);
         let last_active_ago = alice_response.json().get("last_active_ago").unwrap().as_u64().unwrap();
-        assert!(last_active_ago > 4_000);
-        assert!(last_active_ago < 4_500);
+        assert_eq!(last_active_ago, 4_000);
     }
 }
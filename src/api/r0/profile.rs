@@ -4,15 +4,22 @@ use bodyparser;
 use iron::{Chain, Handler, IronResult, IronError, Plugin, Request, Response};
 use iron::status::Status;
 
+use clock;
 use config::Config;
 use db::DB;
 use error::ApiError;
+use federation::profile::fetch_remote_profile;
 use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, UserIdParam};
 use models::profile::{Profile as DataProfile};
-use models::user::User;
 use modifier::{SerializableResponse, EmptyResponse};
+use request_ext::{authed_user, extension};
+use state_cache::StateCache;
+use text_validation;
 
 /// The `/profile/:user_id` endpoint.
+///
+/// Profiles of users of other servers are fetched from their server over federation and cached
+/// for `remote_profile_cache_ttl` seconds.
 pub struct Profile;
 
 #[derive(Clone, Debug, Serialize)]
@@ -27,14 +34,25 @@ middleware_chain!(Profile, [UserIdParam, AccessTokenAuth]);
 
 impl Handler for Profile {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        authed_user(request)?;
 
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
+        let user_id = extension::<UserIdParam>(request)?;
 
+        let config = Config::from_request(request)?;
         let connection = DB::from_request(request)?;
 
+        if user_id.hostname().to_string() != config.domain {
+            let clock = clock::from_request(request)?;
+            let remote_profile = fetch_remote_profile(&connection, &config, &*clock, &user_id)?;
+
+            let response = ProfileResponse {
+                avatar_url: remote_profile.avatar_url,
+                displayname: remote_profile.displayname,
+            };
+
+            return Ok(Response::with((Status::Ok, SerializableResponse(response))));
+        }
+
         let profile = DataProfile::find_by_uid(&connection, &user_id)?;
 
         let response = match profile {
@@ -64,11 +82,9 @@ middleware_chain!(GetAvatarUrl, [UserIdParam, AccessTokenAuth]);
 
 impl Handler for GetAvatarUrl {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        authed_user(request)?;
 
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
+        let user_id = extension::<UserIdParam>(request)?;
 
         let connection = DB::from_request(request)?;
 
@@ -112,14 +128,16 @@ impl Handler for PutAvatarUrl {
             Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
         };
 
+        text_validation::check_optional("avatar_url", &avatar_url_request.avatar_url)?;
+
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
 
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
+        let user_id = extension::<UserIdParam>(request)?;
 
         if user_id != user.id {
             let error = ApiError::unauthorized(
@@ -131,12 +149,19 @@ impl Handler for PutAvatarUrl {
 
         DataProfile::update_avatar_url(
             &connection,
+            &*clock,
             &config.domain,
             user_id.clone(),
             avatar_url_request.avatar_url
         )?;
 
-        DataProfile::update_memberships(&connection, &config.domain, user_id.clone())?;
+        DataProfile::update_memberships(
+            &connection,
+            &state_cache,
+            &*clock,
+            &config,
+            user_id.clone(),
+        )?;
 
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
@@ -155,11 +180,9 @@ middleware_chain!(GetDisplayName, [UserIdParam, AccessTokenAuth]);
 
 impl Handler for GetDisplayName {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        authed_user(request)?;
 
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
+        let user_id = extension::<UserIdParam>(request)?;
 
         let connection = DB::from_request(request)?;
 
@@ -203,14 +226,16 @@ impl Handler for PutDisplayName {
             Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
         };
 
+        text_validation::check_optional("displayname", &displayname_request.displayname)?;
+
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
 
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
+        let user_id = extension::<UserIdParam>(request)?;
 
         if user_id != user.id {
             let error = ApiError::unauthorized(
@@ -222,12 +247,19 @@ impl Handler for PutDisplayName {
 
         DataProfile::update_displayname(
             &connection,
+            &*clock,
             &config.domain,
             user_id.clone(),
             displayname_request.displayname
         )?;
 
-        DataProfile::update_memberships(&connection, &config.domain, user_id.clone())?;
+        DataProfile::update_memberships(
+            &connection,
+            &state_cache,
+            &*clock,
+            &config,
+            user_id.clone(),
+        )?;
 
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
@@ -235,9 +267,16 @@ impl Handler for PutDisplayName {
 
 #[cfg(test)]
 mod tests {
-    use test::Test;
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    use test::{Test, json_at};
+    use test::http_stub::{HttpStub, StubResponse};
+    use iron::method::Method;
     use iron::status::Status;
+    use models::remote_profile::RemoteProfile;
     use query::SyncOptions;
+    use ruma_identifiers::UserId;
 
     #[test]
     fn get_new_user_profile() {
@@ -617,4 +656,111 @@ mod tests {
         assert_eq!(content.get("user_id").unwrap().as_str().unwrap(), carl.id);
         assert_eq!(content.get("displayname").unwrap().as_str().unwrap(), "Bogus");
     }
+
+    #[test]
+    fn put_displayname_with_nul_character() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+                alice.id,
+                alice.token
+            ),
+            r#"{"displayname": "Al\u0000ice"}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_BAD_JSON");
+    }
+
+    #[test]
+    fn put_displayname_with_lone_surrogate() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+                alice.id,
+                alice.token
+            ),
+            r#"{"displayname": "Alice \ud800"}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_BAD_JSON");
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Field `displayname` contains an invalid surrogate escape."
+        );
+    }
+
+    #[test]
+    fn get_profile_of_remote_user() {
+        let (a, b) = Test::new_pair();
+        let alice = a.register("alice");
+        let bob = b.register("bob");
+
+        let displayname_path = format!("/_matrix/client/r0/profile/{}/displayname", bob.id);
+        let response = b.put_as(&bob, &displayname_path, r#"{"displayname": "Bob"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let profile_path = format!("/_matrix/client/r0/profile/{}", bob.id);
+        let response = a.get_as(&alice, &profile_path);
+
+        assert_eq!(response.status, Status::Ok, "{}", response.body);
+        assert_eq!(json_at(&response, "displayname"), "Bob");
+
+        // The profile is cached, so the new display name isn't seen until it expires.
+        b.put_as(&bob, &displayname_path, r#"{"displayname": "Robert"}"#);
+
+        let response = a.get_as(&alice, &profile_path);
+
+        assert_eq!(json_at(&response, "displayname"), "Bob");
+
+        a.advance_time(Duration::from_secs(Test::config().remote_profile_cache_ttl));
+
+        let response = a.get_as(&alice, &profile_path);
+
+        assert_eq!(json_at(&response, "displayname"), "Robert");
+    }
+
+    #[test]
+    fn get_expired_profile_of_unreachable_remote_user() {
+        let remote_server = HttpStub::start();
+        remote_server.on(
+            Method::Get,
+            "/_matrix/federation/v1/query/profile",
+            StubResponse::failure(),
+        );
+
+        let test = Test::with_config(|config| {
+            config.known_servers.insert("remote.test".to_string(), remote_server.known_server());
+        });
+        let alice = test.register("alice");
+
+        let response = test.get_as(&alice, "/_matrix/client/r0/profile/@bob:remote.test");
+        assert!(!response.status.is_success());
+
+        RemoteProfile::store(
+            &test.connection(),
+            test.clock(),
+            &UserId::try_from("@bob:remote.test").unwrap(),
+            None,
+            Some("Bob".to_string()),
+            Duration::from_secs(0),
+        ).unwrap();
+
+        let response = test.get_as(&alice, "/_matrix/client/r0/profile/@bob:remote.test");
+
+        assert_eq!(response.status, Status::Ok, "{}", response.body);
+        assert_eq!(json_at(&response, "displayname"), "Bob");
+
+        let received = remote_server.received();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1].query, Some("user_id=%40bob%3Aremote.test".to_string()));
+        remote_server.assert_no_unexpected_requests();
+    }
 }
@@ -0,0 +1,244 @@
+//! Endpoints for the public room directory.
+
+use std::error::Error;
+
+use bodyparser;
+use iron::{BeforeMiddleware, Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use serde_json::from_str;
+
+use config::Config;
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, find_access_token};
+use models::room_directory::{DirectoryAccess, PublicRooms, PublicRoomsFilter};
+use modifier::SerializableResponse;
+use query_params;
+
+/// The GET `/publicRooms` endpoint.
+///
+/// Authentication is optional. Requests without an access token, in the query string or the
+/// `Authorization` header, are treated as coming from a guest, and only list the rooms guests may
+/// join or view.
+pub struct GetPublicRooms;
+
+middleware_chain!(GetPublicRooms, []);
+
+impl Handler for GetPublicRooms {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+
+        let access_token = find_access_token(request).is_some();
+        let max_limit = config.max_pagination_limit;
+        let limit = query_params::get_u64(request, "limit", max_limit, max_limit)? as usize;
+        let since = query_params::get(request, "since");
+        let filter = match query_params::get(request, "filter") {
+            Some(filter) => from_str(&filter)
+                .map_err(|err| ApiError::invalid_param("filter", err.description()))?,
+            None => PublicRoomsFilter::default(),
+        };
+
+        let access = if access_token {
+            AccessTokenAuth.before(request)?;
+
+            DirectoryAccess::Authenticated
+        } else {
+            DirectoryAccess::Guest
+        };
+
+        let connection = DB::from_request(request)?;
+
+        let response = PublicRooms::find(
+            &connection,
+            Some(limit),
+            since.as_ref().map(|since| &since[..]),
+            &filter,
+            access,
+        )?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The POST `/publicRooms` endpoint.
+///
+/// Rooms of third party networks are only available through application services, which Ruma
+/// does not support yet, so `include_all_networks` and `third_party_instance_id` do not add any
+/// rooms.
+pub struct PostPublicRooms;
+
+#[derive(Clone, Debug, Deserialize)]
+struct PostPublicRoomsRequest {
+    /// The maximum number of rooms to return.
+    limit: Option<usize>,
+    /// A pagination token from a previous response.
+    since: Option<String>,
+    /// Criteria for narrowing down the rooms.
+    filter: Option<PublicRoomsFilter>,
+    /// The third party network to list the rooms of.
+    third_party_instance_id: Option<String>,
+}
+
+middleware_chain!(PostPublicRooms, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for PostPublicRooms {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let post_request = match request.get::<bodyparser::Struct<PostPublicRoomsRequest>>() {
+            Ok(Some(post_request)) => post_request,
+            Ok(None) => Err(ApiError::bad_json(None))?,
+            Err(err) => Err(ApiError::bad_json(err.description().to_string()))?,
+        };
+
+        let connection = DB::from_request(request)?;
+        let filter = post_request.filter.unwrap_or_default();
+
+        let response = if post_request.third_party_instance_id.is_some() {
+            PublicRooms::find(&connection, Some(0), None, &filter, DirectoryAccess::Authenticated)?
+        } else {
+            PublicRooms::find(
+                &connection,
+                post_request.limit,
+                post_request.since.as_ref().map(|since| &since[..]),
+                &filter,
+                DirectoryAccess::Authenticated,
+            )?
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::headers::{Authorization, Headers};
+    use iron::method::Method;
+    use iron::status::Status;
+
+    use test::{Test, assert_json_snapshot};
+
+    #[test]
+    fn search_matches_names_topics_and_aliases_case_insensitively() {
+        let test = Test::new();
+        let alice = test.create_user();
+        test.create_room_with_params(&alice.token, r#"{"visibility": "public", "name": "Rust Lounge"}"#);
+        test.create_room_with_params(
+            &alice.token,
+            r#"{"visibility": "public", "topic": "All things RUST"}"#,
+        );
+        test.create_room_with_params(
+            &alice.token,
+            r#"{"visibility": "public", "room_alias_name": "rustaceans"}"#,
+        );
+        test.create_room_with_params(&alice.token, r#"{"visibility": "public", "name": "Go"}"#);
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/publicRooms?access_token={}", alice.token),
+            r#"{"filter": {"generic_search_term": "rust"}}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let chunk = response.json().get("chunk").unwrap().as_array().unwrap();
+        assert_eq!(chunk.len(), 3);
+
+        // Ranked by where the term matches: name, then canonical alias, then topic.
+        assert_eq!(chunk[0].get("name").unwrap().as_str().unwrap(), "Rust Lounge");
+        assert_eq!(
+            chunk[1].get("canonical_alias").unwrap().as_str().unwrap(),
+            "#rustaceans:ruma.test"
+        );
+        assert_eq!(chunk[2].get("topic").unwrap().as_str().unwrap(), "All things RUST");
+    }
+
+    #[test]
+    fn get_accepts_search_term_in_query() {
+        let test = Test::new();
+        let alice = test.create_user();
+        test.create_room_with_params(&alice.token, r#"{"visibility": "public", "name": "Rust"}"#);
+        test.create_room_with_params(&alice.token, r#"{"visibility": "public", "name": "Go"}"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/publicRooms?access_token={}&filter={}",
+            alice.token,
+            "%7B%22generic_search_term%22%3A%22rus%22%7D"
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let chunk = response.json().get("chunk").unwrap().as_array().unwrap();
+        assert_eq!(chunk.len(), 1);
+        assert_eq!(chunk[0].get("name").unwrap().as_str().unwrap(), "Rust");
+    }
+
+    #[test]
+    fn guests_only_see_rooms_open_to_guests() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let world_readable_room_id = test.create_room_with_params(
+            &alice.token,
+            r#"{"visibility": "public", "initial_state": [{
+                "type": "m.room.history_visibility",
+                "state_key": "",
+                "content": {"history_visibility": "world_readable"}
+            }]}"#,
+        );
+        test.create_room_with_params(&alice.token, r#"{"visibility": "public"}"#);
+
+        let response = test.get("/_matrix/client/r0/publicRooms");
+        assert_eq!(response.status, Status::Ok);
+
+        let chunk = response.json().get("chunk").unwrap().as_array().unwrap();
+        assert_eq!(chunk.len(), 1);
+        assert_eq!(chunk[0].get("room_id").unwrap().as_str().unwrap(), world_readable_room_id);
+
+        let response = test.get(
+            &format!("/_matrix/client/r0/publicRooms?access_token={}", alice.token)
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("chunk").unwrap().as_array().unwrap().len(), 2);
+
+        let mut headers = Headers::new();
+        headers.set(Authorization(format!("Bearer {}", alice.token)));
+
+        let response =
+            test.request_with_headers(Method::Get, "/_matrix/client/r0/publicRooms", "", headers);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("chunk").unwrap().as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn public_rooms_snapshot() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let bob = test.register("bob");
+        let room_id = alice.create_room(
+            &test,
+            r#"{
+                "visibility": "public",
+                "name": "Rust Lounge",
+                "topic": "All things Rust",
+                "room_alias_name": "rust"
+            }"#,
+        );
+        alice.create_room(&test, r#"{"visibility": "public", "name": "Go"}"#);
+        assert_eq!(bob.join(&test, &room_id).status, Status::Ok);
+
+        let response = test.post_as(&alice, "/_matrix/client/r0/publicRooms", "{}");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_json_snapshot(&response, "public_rooms");
+    }
+
+    #[test]
+    fn invalid_access_token_is_rejected() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/publicRooms?access_token=invalid");
+
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNKNOWN_TOKEN"
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Endpoints for push rules.
+
+use bodyparser;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
+use models::push_rule::{PUSH_RULE_KINDS, PushRuleOverride, PushRules};
+use modifier::{EmptyResponse, SerializableResponse};
+use request_ext::{authed_user, path_param};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct PushRuleEnabled {
+    /// Whether or not the push rule is enabled.
+    enabled: bool,
+}
+
+/// The GET `/pushrules/global/:kind/:rule_id/enabled` endpoint.
+pub struct GetPushRuleEnabled;
+
+middleware_chain!(GetPushRuleEnabled, [AccessTokenAuth]);
+
+impl Handler for GetPushRuleEnabled {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let (kind, rule_id) = push_rule_path(request)?;
+        let user = authed_user(request)?;
+        let connection = DB::from_request(request)?;
+
+        let enabled = match PushRules::find(&connection, &user.id)?.enabled(&kind, &rule_id) {
+            Some(enabled) => enabled,
+            None => Err(unknown_push_rule(&kind, &rule_id))?,
+        };
+
+        let response = PushRuleEnabled {
+            enabled: enabled,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The PUT `/pushrules/global/:kind/:rule_id/enabled` endpoint.
+///
+/// Enables or disables one of the server's default push rules for the user.
+pub struct PutPushRuleEnabled;
+
+middleware_chain!(PutPushRuleEnabled, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for PutPushRuleEnabled {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let push_rule_enabled = match request.get::<bodyparser::Struct<PushRuleEnabled>>() {
+            Ok(Some(push_rule_enabled)) => push_rule_enabled,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let (kind, rule_id) = push_rule_path(request)?;
+        let user = authed_user(request)?;
+        let connection = DB::from_request(request)?;
+
+        let updated = PushRuleOverride::set_enabled(
+            &connection,
+            &user.id,
+            &kind,
+            &rule_id,
+            push_rule_enabled.enabled,
+        )?;
+
+        if !updated {
+            Err(unknown_push_rule(&kind, &rule_id))?;
+        }
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// The kind and ID of the push rule in the request's path.
+fn push_rule_path(request: &Request) -> Result<(String, String), ApiError> {
+    let kind = path_param(request, "kind")?;
+    let rule_id = path_param(request, "rule_id")?;
+
+    if !PUSH_RULE_KINDS.contains(&kind.as_ref()) {
+        return Err(ApiError::invalid_param("kind", &format!("Unknown push rule kind {}.", kind)));
+    }
+
+    Ok((kind, rule_id))
+}
+
+/// The error for a push rule that doesn't exist.
+fn unknown_push_rule(kind: &str, rule_id: &str) -> ApiError {
+    ApiError::not_found(format!("Unknown {} push rule {}.", kind, rule_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::{Test, assert_matrix_error, json_at};
+
+    const MESSAGE_RULE_PATH: &'static str =
+        "/_matrix/client/r0/pushrules/global/underride/.m.rule.message/enabled";
+
+    #[test]
+    fn toggle_push_rule() {
+        let test = Test::new();
+        let alice = test.register("alice");
+
+        let response = test.get_as(&alice, MESSAGE_RULE_PATH);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(json_at(&response, "enabled").as_bool(), Some(true));
+
+        let response = test.put_as(&alice, MESSAGE_RULE_PATH, r#"{"enabled": false}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get_as(&alice, MESSAGE_RULE_PATH);
+        assert_eq!(json_at(&response, "enabled").as_bool(), Some(false));
+
+        let bob = test.register("bob");
+        let response = test.get_as(&bob, MESSAGE_RULE_PATH);
+        assert_eq!(json_at(&response, "enabled").as_bool(), Some(true));
+    }
+
+    #[test]
+    fn master_rule_is_disabled_by_default() {
+        let test = Test::new();
+        let alice = test.register("alice");
+
+        let response = test.get_as(
+            &alice,
+            "/_matrix/client/r0/pushrules/global/override/.m.rule.master/enabled",
+        );
+        assert_eq!(json_at(&response, "enabled").as_bool(), Some(false));
+    }
+
+    #[test]
+    fn unknown_push_rules() {
+        let test = Test::new();
+        let alice = test.register("alice");
+
+        let response = test.get_as(
+            &alice,
+            "/_matrix/client/r0/pushrules/global/override/.m.rule.unknown/enabled",
+        );
+        assert_matrix_error(&response, Status::NotFound, "M_NOT_FOUND");
+
+        let response = test.put_as(
+            &alice,
+            "/_matrix/client/r0/pushrules/global/room/.m.rule.message/enabled",
+            r#"{"enabled": false}"#,
+        );
+        assert_matrix_error(&response, Status::NotFound, "M_NOT_FOUND");
+
+        let response = test.get_as(
+            &alice,
+            "/_matrix/client/r0/pushrules/global/nonsense/.m.rule.message/enabled",
+        );
+        assert_matrix_error(&response, Status::BadRequest, "M_INVALID_PARAM");
+
+        let response = test.put_as(&alice, MESSAGE_RULE_PATH, r#"{"enabled": "no"}"#);
+        assert_matrix_error(&response, Status::BadRequest, "M_BAD_JSON");
+    }
+}
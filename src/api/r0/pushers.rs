@@ -8,8 +8,8 @@ use db::DB;
 use error::{ApiError, MapApiError};
 use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
 use models::pusher::{Pusher, PusherOptions};
-use models::user::User;
 use modifier::{SerializableResponse, EmptyResponse};
+use request_ext::authed_user;
 
 /// The GET `/pushers` endpoint.
 pub struct GetPushers;
@@ -23,8 +23,7 @@ middleware_chain!(GetPushers, [AccessTokenAuth]);
 
 impl Handler for GetPushers {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
         let connection = DB::from_request(request)?;
 
@@ -45,8 +44,7 @@ middleware_chain!(SetPushers, [JsonRequest, AccessTokenAuth]);
 
 impl Handler for SetPushers {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
         let value: Value = match request.get::<bodyparser::Struct<Value>>() {
             Ok(Some(request)) => request,
@@ -136,7 +134,7 @@ mod tests {
         };
 
         let response = test.set_pusher(&carl.token, options.clone());
-        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(response.status, Status::BadRequest);
     }
 
     #[test]
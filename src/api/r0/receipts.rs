@@ -0,0 +1,136 @@
+//! Endpoints for read receipts.
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::EventId;
+
+use db::DB;
+use error::ApiError;
+use identifiers;
+use middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use models::event::Event;
+use models::receipt::{PRIVATE_READ_RECEIPT, Receipt};
+use models::room_membership::RoomMembership;
+use modifier::EmptyResponse;
+use request_ext::{authed_user, extension, path_param};
+
+/// The POST `/rooms/:room_id/receipt/m.read.private/:event_id` endpoint.
+///
+/// Marks the event as read without telling the other members of the room (MSC2285). The receipt
+/// is stored as private, so it is never part of the `m.receipt` events of the room.
+pub struct PrivateReadReceipt;
+
+middleware_chain!(PrivateReadReceipt, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for PrivateReadReceipt {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = authed_user(request)?;
+
+        let room_id = extension::<RoomIdParam>(request)?;
+
+        let event_id = path_param(request, "event_id")?;
+        let event_id = identifiers::parse::<EventId>("event_id", &event_id)?;
+
+        let connection = DB::from_request(request)?;
+
+        match RoomMembership::find(&connection, &room_id, &user.id)? {
+            Some(ref entry) if entry.membership == "join" => {}
+            _ => Err(ApiError::unauthorized("The user is not a member of the room".to_string()))?,
+        }
+
+        match Event::find(&connection, &event_id)? {
+            Some(ref event) if event.room_id == room_id => {}
+            _ => Err(ApiError::not_found("The event was not found in the room.".to_string()))?,
+        }
+
+        Receipt::upsert(&connection, &room_id, &user.id, PRIVATE_READ_RECEIPT, &event_id, true)?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use iron::status::Status;
+    use ruma_identifiers::{EventId, RoomId, UserId};
+    use serde_json::from_str;
+
+    use models::receipt::{PRIVATE_READ_RECEIPT, Receipt};
+    use query::SyncOptions;
+    use test::Test;
+
+    #[test]
+    fn private_read_receipts_are_not_shared() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_id = test.create_room_with_params(&alice.token, r#"{"visibility": "public"}"#);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.send_message(&alice.token, &room_id, "Hi Bob", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap().to_string();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/receipt/m.read.private/{}?access_token={}",
+                room_id,
+                event_id,
+                bob.token
+            ),
+            "{}",
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let receipts = Receipt::find_by_user(
+            &test.connection(),
+            &RoomId::try_from(room_id.as_ref()).unwrap(),
+            &UserId::try_from(bob.id.as_ref()).unwrap(),
+        ).unwrap();
+
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receipt_type, PRIVATE_READ_RECEIPT);
+        assert_eq!(receipts[0].event_id, EventId::try_from(event_id.as_ref()).unwrap());
+        assert!(receipts[0].private);
+
+        let options = SyncOptions {
+            filter: Some(from_str(r#"{"room":{"timeline":{"limit":10}}}"#).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0
+        };
+        let response = test.sync(&alice.token, options);
+        let ephemeral = response.json()
+            .pointer(&format!("/rooms/join/{}/ephemeral/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .clone();
+
+        assert!(ephemeral.iter().all(|event| {
+            event.get("type").unwrap().as_str().unwrap() != "m.receipt"
+        }));
+    }
+
+    #[test]
+    fn private_read_receipt_for_unknown_event() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/receipt/m.read.private/{}?access_token={}",
+                room_id,
+                "$nope:ruma.test",
+                alice.token
+            ),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+}
@@ -1,6 +1,5 @@
 //! Endpoints for user account registration.
 
-use std::convert::TryFrom;
 use std::fmt::{Formatter, Result as FmtResult};
 
 use bodyparser;
@@ -12,6 +11,7 @@ use config::Config;
 use crypto::hash_password;
 use db::DB;
 use error::ApiError;
+use identifiers;
 use middleware::{JsonRequest, MiddlewareChain};
 use models::profile::Profile;
 use models::user::{NewUser, User};
@@ -43,6 +43,8 @@ enum RegistrationKind {
 struct RegistrationResponse {
     /// An access token for the account. This access token can then be used to authorize other requests.
     pub access_token: String,
+    /// The ID of the device the access token was issued to.
+    pub device_id: String,
     /// The hostname of the homeserver on which the account has been registered.
     pub home_server: String,
     /// The fully-qualified Matrix ID that has been registered.
@@ -92,21 +94,22 @@ impl Handler for Register {
 
         let config = Config::from_request(request)?;
 
+        // Localparts are lowercase, so `Alice` registers `@alice`.
         let new_user = NewUser {
             id: match registration_request.username {
                 Some(username) => {
-                    UserId::try_from(&format!("@{}:{}", username, &config.domain))
-                        .map_err(ApiError::from)?
+                    identifiers::new_user_id("username", &username.to_lowercase(), &config.domain)?
                 }
                 None => UserId::new(&config.domain).map_err(ApiError::from)?,
             },
             password_hash: hash_password(&registration_request.password)?,
+            admin: false,
         };
 
         let connection = DB::from_request(request)?;
 
-        if User::find_registered_user(&connection, &new_user.id)?.is_some() {
-            let error = ApiError::unauthorized("This user_id already exists".to_string());
+        if User::find_registered_user_ignoring_case(&connection, &new_user.id)?.is_some() {
+            let error = ApiError::user_in_use("This user_id already exists".to_string());
 
             return Err(IronError::from(error));
         }
@@ -127,6 +130,7 @@ impl Handler for Register {
 
         let response = RegistrationResponse {
             access_token: access_token.value,
+            device_id: access_token.device_id,
             home_server: config.domain.clone(),
             user_id: user.id,
         };
@@ -180,6 +184,38 @@ mod tests {
         );
     }
 
+    #[test]
+    fn username_with_capital_letters_is_lowercased() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "Alice", "password": "secret"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("user_id").unwrap().as_str().unwrap(), "@alice:ruma.test");
+
+        let response = test.register_user(r#"{"username": "ALICE", "password": "secret"}"#);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_USER_IN_USE"
+        );
+    }
+
+    #[test]
+    fn username_with_invalid_characters() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl!", "password": "secret"}"#);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+        assert!(response.json().get("error").unwrap().as_str().unwrap().contains("'username'"));
+    }
+
     #[test]
     fn user_already_registered() {
         let test = Test::new();
@@ -194,7 +230,11 @@ mod tests {
             r#"{"bind_email": true, "kind": "user", "username": "alice", "password": "secret"}"#
         );
 
-        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_USER_IN_USE"
+        );
         assert_eq!(
             response.json().get("error").unwrap().as_str().unwrap(),
             "This user_id already exists"
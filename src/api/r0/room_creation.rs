@@ -1,22 +1,28 @@
 //! Endpoints for room creation.
 
-use std::convert::From;
+use std::convert::{From, TryFrom};
 
 use bodyparser;
 use diesel::Connection;
 use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
 use iron::status::Status;
 use ruma_events::stripped::StrippedState;
-use ruma_identifiers::{RoomId, UserId};
+use ruma_identifiers::{RoomAliasId, RoomId, UserId};
 
+use clock;
 use config::Config;
 use db::DB;
 use error::ApiError;
+use ids::{generate_room_id, with_unique_id};
 use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
 use models::room::{CreationOptions, NewRoom, Room, RoomPreset, RoomVisibility};
+use models::room_alias::{RoomAlias, validate_alias_localpart};
 use models::room_membership::{RoomMembership, RoomMembershipOptions};
-use models::user::User;
 use modifier::SerializableResponse;
+use request_ext::authed_user;
+use room_version::{self, DEFAULT_ROOM_VERSION};
+use state_cache::StateCache;
+use text_validation;
 
 /// The `/createRoom` endpoint.
 pub struct CreateRoom;
@@ -36,6 +42,8 @@ struct CreateRoomRequest {
     pub preset: Option<RoomPreset>,
     /// The desired room alias local part.
     pub room_alias_name: Option<String>,
+    /// The version of the room, one of `SUPPORTED_ROOM_VERSIONS`.
+    pub room_version: Option<String>,
     /// Indicates the room's topic.
     pub topic: Option<String>,
     /// Indicates whether or not that the room will be shown in the published room list.
@@ -58,21 +66,36 @@ middleware_chain!(CreateRoom, [JsonRequest, AccessTokenAuth]);
 
 impl Handler for CreateRoom {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
         let create_room_request = match request.get::<bodyparser::Struct<CreateRoomRequest>>() {
             Ok(Some(create_room_request)) => create_room_request,
             Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
         };
 
+        text_validation::check_optional("name", &create_room_request.name)?;
+        text_validation::check_optional("topic", &create_room_request.topic)?;
+
+        let version = create_room_request.room_version.clone()
+            .unwrap_or_else(|| DEFAULT_ROOM_VERSION.to_string());
+        room_version::check(&version)?;
+
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
+        let state_cache = StateCache::from_request(request)?;
+        let clock = clock::from_request(request)?;
 
-        let new_room = NewRoom {
-            id: RoomId::new(&config.domain).map_err(ApiError::from)?,
-            user_id: user.id,
-            public: create_room_request.visibility.map_or(false, |v| v == RoomVisibility::Public),
-        };
+        if let Some(ref room_alias_name) = create_room_request.room_alias_name {
+            validate_alias_localpart(room_alias_name, config.max_alias_length)?;
+
+            let alias = format!("#{}:{}", room_alias_name, config.domain);
+            let alias = RoomAliasId::try_from(&alias[..]).map_err(ApiError::from)?;
+
+            if RoomAlias::find_by_alias(&connection, &alias).is_ok() {
+                Err(ApiError::room_in_use(None))?;
+            }
+        }
+
+        let public = create_room_request.visibility.map_or(false, |v| v == RoomVisibility::Public);
 
         let federate = match create_room_request.creation_content {
             Some(creation_content) => creation_content.federate.unwrap_or(true),
@@ -81,7 +104,7 @@ impl Handler for CreateRoom {
 
         let preset = match create_room_request.preset {
             Some(preset) => preset,
-            None => if new_room.public {
+            None => if public {
                 RoomPreset::PublicChat
             } else {
                 RoomPreset::PrivateChat
@@ -95,24 +118,45 @@ impl Handler for CreateRoom {
             invite_list: create_room_request.invite,
             name: create_room_request.name,
             preset: preset,
+            room_version: version,
             topic: create_room_request.topic,
         };
 
-        let room: Room = connection.transaction::<Room, ApiError, _>(|| {
-            let room = Room::create(&connection, &new_room, &config.domain, &creation_options)?;
-
-            let options = RoomMembershipOptions {
-                room_id: room.id.clone(),
-                user_id: room.user_id.clone(),
-                sender: room.user_id.clone(),
-                membership: "join".to_string(),
+        let room: Room = with_unique_id(|| generate_room_id(&config.domain), |room_id| {
+            let new_room = NewRoom {
+                id: room_id,
+                user_id: user.id.clone(),
+                public: public,
             };
 
-            RoomMembership::create(&connection, &config.domain, options)?;
-
-            Ok(room)
-        })
-        .map_err(ApiError::from)?;
+            connection.transaction::<Room, ApiError, _>(|| {
+                let room = Room::create(
+                    &connection,
+                    &state_cache,
+                    &*clock,
+                    &new_room,
+                    &config,
+                    &creation_options,
+                )?;
+
+                let options = RoomMembershipOptions {
+                    room_id: room.id.clone(),
+                    user_id: room.user_id.clone(),
+                    sender: room.user_id.clone(),
+                    membership: "join".to_string(),
+                };
+
+                RoomMembership::create(
+                    &connection,
+                    &state_cache,
+                    &*clock,
+                    &config,
+                    options,
+                )?;
+
+                Ok(room)
+            })
+        })?;
 
         let response = CreateRoomResponse {
             room_id: room.id,
@@ -130,12 +174,9 @@ mod tests {
     #[test]
     fn no_parameters() {
         let test = Test::new();
-        let user = test.create_user();
+        let alice = test.register("alice");
 
-        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
-                                       user.token);
-
-        let response = test.post(&create_room_path, "{}");
+        let response = test.post_as(&alice, "/_matrix/client/r0/createRoom", "{}");
 
         assert!(response.json().get("room_id").unwrap().as_str().is_some());
     }
@@ -143,33 +184,28 @@ mod tests {
     #[test]
     fn with_room_alias() {
         let test = Test::new();
-        let user = test.create_user();
-
-        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
-                                       user.token);
+        let alice = test.register("alice");
 
-        let response = test.post(&create_room_path, r#"{"room_alias_name": "my_room"}"#);
-        let room_id = response.json().get("room_id").unwrap().as_str();
-
-        assert!(room_id.is_some());
+        let room_id = alice.create_room(&test, r#"{"room_alias_name": "my_room"}"#);
 
         let alias_response = test.get("/_matrix/client/r0/directory/room/my_room");
 
         assert_eq!(
             alias_response.json().get("room_id").unwrap().as_str().unwrap(),
-            room_id.unwrap()
+            room_id
         );
     }
 
     #[test]
     fn with_public_visibility() {
         let test = Test::new();
-        let user = test.create_user();
-
-        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
-                                       user.token);
+        let alice = test.register("alice");
 
-        let response = test.post(&create_room_path, r#"{"visibility": "public"}"#);
+        let response = test.post_as(
+            &alice,
+            "/_matrix/client/r0/createRoom",
+            r#"{"visibility": "public"}"#,
+        );
 
         assert!(response.json().get("room_id").unwrap().as_str().is_some());
     }
@@ -177,12 +213,13 @@ mod tests {
     #[test]
     fn with_private_visibility() {
         let test = Test::new();
-        let user = test.create_user();
-
-        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
-                                       user.token);
+        let alice = test.register("alice");
 
-        let response = test.post(&create_room_path, r#"{"visibility": "private"}"#);
+        let response = test.post_as(
+            &alice,
+            "/_matrix/client/r0/createRoom",
+            r#"{"visibility": "private"}"#,
+        );
 
         assert!(response.json().get("room_id").unwrap().as_str().is_some());
     }
@@ -190,12 +227,13 @@ mod tests {
     #[test]
     fn with_invalid_visibility() {
         let test = Test::new();
-        let user = test.create_user();
-
-        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
-                                       user.token);
+        let alice = test.register("alice");
 
-        let response = test.post(&create_room_path, r#"{"visibility": "bogus"}"#);
+        let response = test.post_as(
+            &alice,
+            "/_matrix/client/r0/createRoom",
+            r#"{"visibility": "bogus"}"#,
+        );
 
         assert_eq!(
             response.json().get("errcode").unwrap().as_str().unwrap(),
@@ -206,9 +244,9 @@ mod tests {
     #[test]
     fn with_invited_users() {
         let test = Test::new();
-        let alice = test.create_user();
-        let bob = test.create_user();
-        let carl = test.create_user();
+        let alice = test.register("alice");
+        let bob = test.register("bob");
+        let carl = test.register("carl");
 
         let room_options = format!(r#"{{"visibility": "private",
                                         "invite": [
@@ -216,18 +254,18 @@ mod tests {
                                            "{}"
                                         ]}}"#, bob.id, carl.id);
 
-        let room_id = test.create_room_with_params(&alice.token, &room_options);
+        let room_id = alice.create_room(&test, &room_options);
 
-        assert!(test.join_room(&alice.token, &room_id).status.is_success());
-        assert!(test.join_room(&bob.token, &room_id).status.is_success());
-        assert!(test.join_room(&carl.token, &room_id).status.is_success());
+        assert!(alice.join(&test, &room_id).status.is_success());
+        assert!(bob.join(&test, &room_id).status.is_success());
+        assert!(carl.join(&test, &room_id).status.is_success());
     }
 
     #[test]
     fn with_unknown_invited_users() {
         let test = Test::new();
-        let _bob = test.create_user();
-        let alice = test.create_user();
+        test.register("bob");
+        let alice = test.register("alice");
 
         let room_options = r#"{"visibility": "private",
                                "invite": [
@@ -236,10 +274,7 @@ mod tests {
                                    "@dan:ruma.test"
                                ]}"#;
 
-        let response = test.post(
-            &format!( "/_matrix/client/r0/createRoom?access_token={}", alice.token),
-            room_options
-        );
+        let response = test.post_as(&alice, "/_matrix/client/r0/createRoom", room_options);
 
         assert_eq!(
             response.json().get("errcode").unwrap().as_str().unwrap(),
@@ -251,14 +286,15 @@ mod tests {
         assert!(error.starts_with("Unknown users in invite list:"));
         assert!(error.contains("@carl:ruma.test"));
         assert!(error.contains("@dan:ruma.test"));
+        assert!(!error.contains("@bob:ruma.test"));
     }
 
     #[test]
     fn creator_has_max_power_level_from_initial_state() {
         let test = Test::new();
 
-        let alice = test.create_user();
-        let bob = test.create_user();
+        let alice = test.register("alice");
+        let bob = test.register("bob");
 
         let room_options = format!(r#"{{
             "invite": [ "{}" ],
@@ -279,15 +315,15 @@ mod tests {
             }}]
         }}"#, bob.id);
 
-        let room_id = test.create_room_with_params(&alice.token, &room_options);
+        let room_id = alice.create_room(&test, &room_options);
 
-        let response = test.join_room(&bob.token, &room_id);
+        let response = bob.join(&test, &room_id);
         assert_eq!(response.status, Status::Ok);
 
-        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let response = alice.send_message(&test, &room_id, "Hi");
         assert_eq!(response.status, Status::Ok);
 
-        let response = test.send_message(&bob.token, &room_id, "Hi", 2);
+        let response = bob.send_message(&test, &room_id, "Hi");
         assert_eq!(response.status, Status::Forbidden);
         assert_eq!(
             response.json().get("error").unwrap().as_str().unwrap(),
@@ -298,17 +334,18 @@ mod tests {
     #[test]
     fn creator_has_max_power_level_by_default() {
         let test = Test::new();
-        let (alice, room_id) = test.initial_fixtures("{}");
-        let bob = test.create_user();
-        let carl = test.create_user();
+        let alice = test.register("alice");
+        let bob = test.register("bob");
+        let carl = test.register("carl");
+        let room_id = alice.create_room(&test, "{}");
 
-        let response = test.invite(&alice.token, &room_id, &bob.id);
+        let response = alice.invite(&test, &room_id, &bob);
         assert_eq!(response.status, Status::Ok);
 
-        let response = test.join_room(&bob.token, &room_id);
+        let response = bob.join(&test, &room_id);
         assert_eq!(response.status, Status::Ok);
 
-        let response = test.invite(&bob.token, &room_id, &carl.id);
+        let response = bob.invite(&test, &room_id, &carl);
         assert_eq!(response.status, Status::Forbidden);
         assert_eq!(
             response.json().get("error").unwrap().as_str().unwrap(),
@@ -319,11 +356,11 @@ mod tests {
     #[test]
     fn with_power_levels_in_initial_state() {
         let test = Test::new();
-        let alice = test.create_user();
-        let bob = test.create_user();
-        let carl = test.create_user();
-        let dan = test.create_user();
-        let eve = test.create_user();
+        let alice = test.register("alice");
+        let bob = test.register("bob");
+        let carl = test.register("carl");
+        let dan = test.register("dan");
+        let eve = test.register("eve");
 
         let room_options = format!(r#"{{
             "invite": [
@@ -350,28 +387,79 @@ mod tests {
             }}]
         }}"#, bob.id, carl.id);
 
-        let room_id = test.create_room_with_params(&alice.token, &room_options);
+        let room_id = alice.create_room(&test, &room_options);
 
-        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
-        assert_eq!(test.join_room(&carl.token, &room_id).status, Status::Ok);
+        assert_eq!(bob.join(&test, &room_id).status, Status::Ok);
+        assert_eq!(carl.join(&test, &room_id).status, Status::Ok);
 
         // Bob has enough power to invite other users.
+        assert_eq!(bob.invite(&test, &room_id, &eve).status, Status::Ok);
+
+        // Carl doesn't ...
+        assert_eq!(carl.invite(&test, &room_id, &dan).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn with_taken_room_alias() {
+        let test = Test::new();
+        let carl = test.register("carl");
+
+        let response = test.post_as(
+            &carl,
+            "/_matrix/client/r0/createRoom",
+            r#"{"room_alias_name": "my_room"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post_as(
+            &carl,
+            "/_matrix/client/r0/createRoom",
+            r#"{"room_alias_name": "my_room"}"#,
+        );
+        assert_eq!(response.status, Status::BadRequest);
         assert_eq!(
-            test.invite(&bob.token, &room_id, &eve.id).status,
-            Status::Ok
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_ROOM_IN_USE"
         );
+    }
 
-        // Carl doesn't ...
+    #[test]
+    fn with_default_room_version() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let room_id = alice.create_room(&test, "{}");
+
+        let response = test.get_as(
+            &alice,
+            &format!("/_matrix/client/r0/rooms/{}/state/m.room.create", room_id),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("room_version").unwrap().as_str().unwrap(), "1");
+    }
+
+    #[test]
+    fn with_unsupported_room_version() {
+        let test = Test::new();
+        let alice = test.register("alice");
+
+        let response = test.post_as(
+            &alice,
+            "/_matrix/client/r0/createRoom",
+            r#"{"room_version": "999"}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
         assert_eq!(
-            test.invite(&carl.token, &room_id, &dan.id).status,
-            Status::Forbidden
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNSUPPORTED_ROOM_VERSION"
         );
     }
 
     #[test]
     fn with_room_aliases_in_initial_state() {
         let test = Test::new();
-        let alice = test.create_user();
+        let alice = test.register("alice");
 
         let room_options = r##"{
             "initial_state": [{
@@ -383,7 +471,7 @@ mod tests {
             }]
         }"##;
 
-        let room_id = test.create_room_with_params(&alice.token, &room_options);
+        let room_id = alice.create_room(&test, room_options);
 
         let first_alias_response = test.get_room_by_alias("alias_1");
         let second_alias_response = test.get_room_by_alias("alias_2");
@@ -402,8 +490,8 @@ mod tests {
     #[test]
     fn with_join_rules_in_initial_state() {
         let test = Test::new();
-        let alice = test.create_user();
-        let bob = test.create_user();
+        let alice = test.register("alice");
+        let bob = test.register("bob");
 
         let room_options = r#"{
             "initial_state":[{
@@ -413,38 +501,38 @@ mod tests {
             }]
         }"#;
 
-        let room_id = test.create_room_with_params(&alice.token, &room_options);
+        let room_id = alice.create_room(&test, room_options);
 
         // Bob can join without an invite.
-        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(bob.join(&test, &room_id).status, Status::Ok);
     }
 
     #[test]
     fn with_increased_power_levels_in_trusted_chats_by_default() {
         let test = Test::new();
 
-        let carl = test.create_user();
-        let bob = test.create_user();
-        let alice = test.create_user();
+        let carl = test.register("carl");
+        let bob = test.register("bob");
+        let alice = test.register("alice");
 
         let room_options = format!(r#"{{
             "invite": ["{}", "{}"],
             "preset": "trusted_private_chat"
         }}"#, bob.id, carl.id);
 
-        let room_id = test.create_room_with_params(&alice.token, &room_options);
+        let room_id = alice.create_room(&test, &room_options);
 
-        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
-        assert_eq!(test.invite(&bob.token, &room_id, &carl.id).status, Status::Ok);
+        assert_eq!(bob.join(&test, &room_id).status, Status::Ok);
+        assert_eq!(bob.invite(&test, &room_id, &carl).status, Status::Ok);
     }
 
     #[test]
     fn with_increased_power_levels_in_trusted_chats_from_initial_state() {
         let test = Test::new();
 
-        let carl = test.create_user();
-        let bob = test.create_user();
-        let alice = test.create_user();
+        let carl = test.register("carl");
+        let bob = test.register("bob");
+        let alice = test.register("alice");
 
         let room_options = format!(r#"{{
             "invite": ["{}"],
@@ -466,11 +554,49 @@ mod tests {
             }}]
         }}"#, bob.id);
 
-        let room_id = test.create_room_with_params(&alice.token, &room_options);
+        let room_id = alice.create_room(&test, &room_options);
+
+        assert_eq!(bob.join(&test, &room_id).status, Status::Ok);
+        assert_eq!(bob.invite(&test, &room_id, &carl).status, Status::Ok);
+        assert_eq!(bob.send_message(&test, &room_id, "Hi").status, Status::Ok);
+        assert_eq!(alice.send_message(&test, &room_id, "Hi").status, Status::Ok);
+    }
 
-        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
-        assert_eq!(test.invite(&bob.token, &room_id, &carl.id).status, Status::Ok);
-        assert_eq!(test.send_message(&bob.token, &room_id, "Hi", 1).status, Status::Ok);
-        assert_eq!(test.send_message(&alice.token, &room_id, "Hi", 1).status, Status::Ok);
+    #[test]
+    fn queries_do_not_grow_with_initial_state() {
+        let test = Test::new();
+        let alice = test.register("alice");
+
+        let (_, without_initial_state) = test.measure(|| alice.create_room(&test, "{}"));
+
+        let (_, with_initial_state) = test.measure(|| alice.create_room(&test, r#"{
+            "initial_state": [
+                {"type": "m.room.name", "state_key": "", "content": {"name": "Name"}},
+                {"type": "m.room.topic", "state_key": "", "content": {"topic": "Topic"}},
+                {"type": "m.room.join_rules", "state_key": "", "content": {"join_rule": "public"}},
+                {
+                    "type": "m.room.history_visibility",
+                    "state_key": "",
+                    "content": {"history_visibility": "joined"}
+                }
+            ]
+        }"#));
+
+        // Not only the events, but all of the tables the request touches.
+        let mut tables = without_initial_state.table_names();
+        tables.extend(with_initial_state.table_names());
+        tables.sort();
+        tables.dedup();
+
+        for table in tables {
+            assert_eq!(
+                with_initial_state.table(table).scans,
+                without_initial_state.table(table).scans,
+                "Scans of {} grew with the initial state:\n{}\nwithout it:\n{}",
+                table,
+                with_initial_state,
+                without_initial_state
+            );
+        }
     }
 }
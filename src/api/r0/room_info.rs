@@ -1,49 +1,104 @@
-//! Endpoint for retrieving the state of a room.
+//! Endpoints for retrieving the state of a room.
 
 use std::convert::TryInto;
+use std::error::Error;
 
 use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::headers::{ETag, EntityTag, IfNoneMatch};
 use iron::status::Status;
 use ruma_events::collections::all::StateEvent;
+use serde_json::{Value, from_str};
+use url::Url;
+use url::percent_encoding::percent_decode;
 
+use config::Config;
 use db::DB;
 use error::ApiError;
-use middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use federation::sender::signed_pdu;
+use middleware::{AccessTokenAuth, EventTypeParam, MiddlewareChain, RoomIdParam};
 use models::event::Event;
-use models::room::Room;
 use models::room_membership::RoomMembership;
-use models::user::User;
+use models::room_state::RoomState as CurrentState;
 use modifier::SerializableResponse;
+use request_ext::{authed_user, extension, params};
+use state_cache::StateCache;
 
 /// The `/rooms/:room_id/state` endpoint.
+///
+/// The `format` query parameter selects between the client event format, the default, and the
+/// signed federation PDU format.
+///
+/// Responses with the current state of a room in the client format carry an `ETag` header that
+/// changes with the room's state. If it matches the request's `If-None-Match` header, the state is
+/// not sent again and the response is `304 Not Modified`.
 pub struct RoomState;
 
+/// The formats `RoomState` can return events in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EventFormat {
+    /// The event format of the client-server API.
+    Client,
+    /// The PDU format of the server-server API, including hashes and signatures.
+    Federation,
+}
+
 middleware_chain!(RoomState, [RoomIdParam, AccessTokenAuth]);
 
 impl Handler for RoomState {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
-        let room_id = request.extensions.get::<RoomIdParam>()
-            .expect("RoomIdParam should ensure a room_id").clone();
+        let room_id = extension::<RoomIdParam>(request)?;
 
-        let connection = DB::from_request(request)?;
+        let url: Url = request.url.clone().into();
+        let mut format = EventFormat::Client;
 
-        let room = match Room::find(&connection, &room_id)? {
-            Some(room) => room,
-            None => {
-                Err(ApiError::unauthorized("The room was not found on this server".to_string()))?
+        for (name, value) in url.query_pairs() {
+            if name == "format" {
+                format = match value.as_ref() {
+                    "client" => EventFormat::Client,
+                    "federation" => EventFormat::Federation,
+                    _ => Err(ApiError::invalid_param("format", "Must be client or federation."))?,
+                };
             }
+        }
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+
+        let state_cache = StateCache::from_request(request)?;
+
+        let membership = RoomMembership::require(
+            &connection,
+            &state_cache,
+            &room_id,
+            &user.id,
+            &["join", "invite", "leave", "ban"],
+        )?;
+
+        let membership_state = membership.membership.clone();
+
+        let etag = if format == EventFormat::Client && membership_state == "join" {
+            CurrentState::etag(&connection, &room_id)?.map(EntityTag::strong)
+        } else {
+            None
         };
 
-        let membership = RoomMembership::find(&connection, &room.id, &user.id)?;
+        if let Some(ref etag) = etag {
+            let is_unchanged = match request.headers.get::<IfNoneMatch>() {
+                Some(&IfNoneMatch::Any) => true,
+                Some(&IfNoneMatch::Items(ref tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+                None => false,
+            };
 
-        if membership.is_none() {
-            Err(ApiError::unauthorized("The user is not a member of the room".to_string()))?
+            if is_unchanged {
+                let mut response = Response::with(Status::NotModified);
+                response.headers.set(ETag(etag.clone()));
+
+                return Ok(response);
+            }
         }
 
-        let membership_state = membership.clone().unwrap().membership;
         let mut events = Vec::<Event>::new();
 
         match membership_state.as_ref() {
@@ -53,7 +108,7 @@ impl Handler for RoomState {
                 );
             },
             "leave" => {
-                let last_event = Event::find(&connection, &membership.unwrap().event_id)?
+                let last_event = Event::find(&connection, &membership.event_id)?
                     .expect("A room membership should be associated with an event");
 
                 events.append(
@@ -63,24 +118,181 @@ impl Handler for RoomState {
             _ => {}
         }
 
+        if format == EventFormat::Federation {
+            let signing_key = match config.signing_key {
+                Some(ref signing_key) => signing_key,
+                None => Err(ApiError::unavailable(
+                    "Events can't be signed without a signing key.".to_string()
+                ))?,
+            };
+
+            let mut pdus: Vec<Value> = Vec::new();
+
+            for event in events {
+                pdus.push(signed_pdu(&connection, &config.domain, signing_key, &event)?);
+            }
+
+            return Ok(Response::with((Status::Ok, SerializableResponse(pdus))));
+        }
+
         let mut state_events: Vec<StateEvent> = Vec::new();
 
         for event in events {
             state_events.push(event.try_into()?);
         }
 
-        Ok(Response::with((Status::Ok, SerializableResponse(state_events))))
+        let mut response = Response::with((Status::Ok, SerializableResponse(state_events)));
+
+        if let Some(etag) = etag {
+            response.headers.set(ETag(etag));
+        }
+
+        Ok(response)
+    }
+}
+
+/// The GET `/rooms/:room_id/state/:event_type/:state_key` endpoint.
+///
+/// Returns the content of a single state event, looked up through the index on the room, event
+/// type, and state key, so it stays fast in rooms with many members.
+pub struct GetStateEvent;
+
+middleware_chain!(GetStateEvent, [RoomIdParam, EventTypeParam, AccessTokenAuth]);
+
+impl Handler for GetStateEvent {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let params = params(request)?.clone();
+
+        let user = authed_user(request)?;
+
+        let room_id = extension::<RoomIdParam>(request)?;
+
+        let event_type = extension::<EventTypeParam>(request)?.to_string();
+
+        let state_key = percent_decode(params.find("state_key").unwrap_or("").as_bytes())
+            .decode_utf8()
+            .map_err(|err| ApiError::invalid_param("state_key", err.description()))?
+            .into_owned();
+
+        let connection = DB::from_request(request)?;
+
+        let state_cache = StateCache::from_request(request)?;
+
+        let membership = RoomMembership::require(
+            &connection,
+            &state_cache,
+            &room_id,
+            &user.id,
+            &["join", "leave"],
+        )?;
+
+        let event = match membership.membership.as_ref() {
+            "join" => {
+                Event::find_current_state_event(&connection, &room_id, &event_type, &state_key)?
+            }
+            _ => {
+                let last_event = Event::find(&connection, &membership.event_id)?
+                    .expect("A room membership should be associated with an event");
+
+                Event::find_state_event_until(
+                    &connection,
+                    &room_id,
+                    &event_type,
+                    &state_key,
+                    &last_event,
+                )?
+            }
+        };
+
+        let event = match event {
+            Some(event) => event,
+            // Rooms without power levels use the defaults from the specification.
+            None if event_type == "m.room.power_levels" && state_key.is_empty() => {
+                let power_levels = CurrentState::current(&connection, &state_cache, &room_id)?
+                    .power_levels()?;
+
+                return Ok(Response::with((Status::Ok, SerializableResponse(power_levels))));
+            }
+            None => Err(ApiError::not_found(format!(
+                "The room has no {} event with the state key \"{}\"",
+                event_type,
+                state_key
+            )))?,
+        };
+
+        let content: Value = from_str(&event.content).map_err(ApiError::from)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(content))))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::Connection;
+    use ruma_identifiers::RoomId;
     use test::Test;
+    use iron::headers::{ETag, EntityTag, Headers, IfNoneMatch};
+    use iron::method::Method;
     use iron::status::Status;
     use serde_json::Value;
 
     #[test]
-    fn forbidden_for_non_members() {
+    fn federation_format() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state?format=federation&access_token={}",
+            room_id,
+            alice.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let events = response.json().as_array().unwrap();
+        assert!(events.len() > 0);
+
+        for e in events.iter() {
+            assert_eq!(e.get("room_id").unwrap().as_str().unwrap(), room_id);
+            assert_eq!(e.get("origin").unwrap().as_str().unwrap(), "ruma.test");
+            assert!(e.get("auth_events").unwrap().is_array());
+            assert!(e.get("prev_events").unwrap().is_array());
+            assert!(e.get("depth").unwrap().as_i64().unwrap() > 0);
+            assert!(e.pointer("/hashes/sha256").unwrap().is_string());
+            assert!(e.pointer("/signatures/ruma.test/ed25519:1").unwrap().is_string());
+        }
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state?format=client&access_token={}",
+            room_id,
+            alice.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        for e in response.json().as_array().unwrap().iter() {
+            assert!(e.get("signatures").is_none());
+            assert!(e.get("hashes").is_none());
+        }
+    }
+
+    #[test]
+    fn unknown_format() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state?format=raw&access_token={}",
+            room_id,
+            alice.token
+        ));
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn not_found_for_non_members() {
         let test = Test::new();
         let alice = test.create_user();
         let bob = test.create_user();
@@ -92,8 +304,40 @@ mod tests {
             room_id,
             bob.token
         );
+        let missing_room_state_path = format!(
+            "/_matrix/client/r0/rooms/!missing:ruma.test/state?access_token={}",
+            bob.token
+        );
+
+        let response = test.get(&room_state_path);
+        let missing = test.get(&missing_room_state_path);
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(response.raw_body, missing.raw_body);
+    }
+
+    #[test]
+    fn forbidden_for_non_members_of_world_readable_rooms() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_id = test.create_room_with_params(&alice.token, r#"{
+            "visibility": "public",
+            "initial_state": [{
+                "state_key": "",
+                "type": "m.room.history_visibility",
+                "content": {"history_visibility": "world_readable"}
+            }]
+        }"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id,
+            bob.token
+        ));
 
-        assert_eq!(test.get(&room_state_path).status, Status::Forbidden);
+        assert_eq!(response.status, Status::Forbidden);
     }
 
     #[test]
@@ -353,4 +597,184 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn get_member_event() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let carl = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.member/{}?access_token={}",
+            room_id,
+            bob.id,
+            alice.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("membership").unwrap().as_str().unwrap(), "join");
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.member/{}?access_token={}",
+            room_id,
+            carl.id.replace("@", "%40").replace(":", "%3A"),
+            alice.token
+        ));
+        assert_eq!(response.status, Status::NotFound);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.member/{}?access_token={}",
+            room_id,
+            alice.id,
+            carl.token
+        ));
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn missing_power_levels_are_filled_with_defaults() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        // Rooms created here always get power levels, so forget them as if they were never sent.
+        {
+            let connection = test.connection();
+
+            connection.execute(&format!(
+                "DELETE FROM room_current_state \
+                WHERE room_id = '{}' AND event_type = 'm.room.power_levels'",
+                room_id
+            )).unwrap();
+        }
+        test.state_cache().invalidate(&RoomId::try_from(room_id.as_str()).unwrap());
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id,
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let power_levels = response.json();
+
+        let creator_power_level = power_levels.pointer(&format!("/users/{}", alice.id)).unwrap();
+
+        assert_eq!(creator_power_level.as_u64().unwrap(), 100);
+        assert_eq!(power_levels.get("users_default").unwrap().as_u64().unwrap(), 0);
+        assert_eq!(power_levels.get("events_default").unwrap().as_u64().unwrap(), 0);
+        assert_eq!(power_levels.get("state_default").unwrap().as_u64().unwrap(), 0);
+        assert_eq!(power_levels.get("ban").unwrap().as_u64().unwrap(), 50);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+            room_id,
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn get_state_event_without_state_key() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room_with_params(&alice.token, r#"{"name": "Room"}"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.name?access_token={}",
+            room_id,
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("name").unwrap().as_str().unwrap(), "Room");
+    }
+
+    #[test]
+    fn former_members_get_state_from_when_they_left() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_room_with_params(
+            &alice.token,
+            r#"{"visibility": "public", "topic": "Before"}"#,
+        );
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.put(
+            &format!("/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}", room_id, alice.token),
+            r#"{"topic": "After"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+            room_id,
+            bob.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("topic").unwrap().as_str().unwrap(), "Before");
+    }
+
+    #[test]
+    fn unchanged_state_is_not_modified() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id,
+            alice.token
+        );
+        let response = test.get(&path);
+
+        assert_eq!(response.status, Status::Ok);
+        let etag = response.headers.get::<ETag>().unwrap().0.clone();
+
+        let mut headers = Headers::new();
+        headers.set(IfNoneMatch::Items(vec![etag.clone()]));
+        let response = test.request_with_headers(Method::Get, &path, "", headers.clone());
+
+        assert_eq!(response.status, Status::NotModified);
+        assert!(response.body.is_empty());
+        assert_eq!(response.headers.get::<ETag>(), Some(&ETag(etag.clone())));
+
+        test.send_state_event(&alice.token, &room_id, "m.room.topic", r#"{"topic":"Rust"}"#);
+
+        let response = test.request_with_headers(Method::Get, &path, "", headers);
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.headers.get::<ETag>().unwrap().0 != etag);
+    }
+
+    #[test]
+    fn messages_do_not_change_etag() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id,
+            alice.token
+        );
+        let etag = test.get(&path).headers.get::<ETag>().unwrap().0.clone();
+
+        test.send_message(&alice.token, &room_id, "Hi", 1);
+
+        let mut headers = Headers::new();
+        headers.set(IfNoneMatch::Items(vec![EntityTag::weak(etag.tag().to_string())]));
+        let response = test.request_with_headers(Method::Get, &path, "", headers);
+
+        assert_eq!(response.status, Status::NotModified);
+    }
 }
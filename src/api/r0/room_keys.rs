@@ -0,0 +1,523 @@
+//! Endpoints for backing up end-to-end encryption room keys on the server.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use bodyparser;
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Value, from_str, to_string};
+
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomIdParam};
+use models::key_backup::{BackedUpKey, KeyBackupVersion};
+use modifier::{EmptyResponse, SerializableResponse};
+use query_params;
+use request_ext::{authed_user, extension, path_param};
+
+/// A room key as clients back it up.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct KeyBackupData {
+    /// The index of the first message the key can decrypt.
+    first_message_index: u64,
+    /// The number of times the key has been forwarded between devices.
+    forwarded_count: u64,
+    /// Whether or not the device the key came from is verified.
+    is_verified: bool,
+    /// The key, encrypted by the client.
+    session_data: Value,
+}
+
+/// The backed up keys of a room, by session ID.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct RoomKeyBackup {
+    sessions: BTreeMap<String, KeyBackupData>,
+}
+
+/// The backed up keys of several rooms, by room ID.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct RoomKeys {
+    rooms: BTreeMap<String, RoomKeyBackup>,
+}
+
+#[derive(Debug, Serialize)]
+struct PutRoomKeysResponse {
+    /// Changes whenever keys are added to the backup.
+    etag: String,
+    /// The number of keys in the backup.
+    count: i64,
+}
+
+/// The POST `/room_keys/version` endpoint.
+///
+/// Creates a new version of the user's key backup, which becomes its current version.
+pub struct CreateKeyBackupVersion;
+
+#[derive(Clone, Debug, Deserialize)]
+struct CreateKeyBackupVersionRequest {
+    /// The algorithm the keys are encrypted with.
+    algorithm: String,
+    /// The data about the backup the algorithm needs, e.g. its public key.
+    auth_data: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct CreateKeyBackupVersionResponse {
+    version: String,
+}
+
+middleware_chain!(CreateKeyBackupVersion, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for CreateKeyBackupVersion {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let create_request =
+            match request.get::<bodyparser::Struct<CreateKeyBackupVersionRequest>>() {
+                Ok(Some(create_request)) => create_request,
+                Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+            };
+
+        if !create_request.auth_data.is_object() {
+            Err(ApiError::bad_json("auth_data must be an object.".to_string()))?;
+        }
+
+        let user = authed_user(request)?;
+        let connection = DB::from_request(request)?;
+
+        let auth_data = to_string(&create_request.auth_data).map_err(ApiError::from)?;
+        let version = KeyBackupVersion::create(
+            &connection,
+            &user.id,
+            &create_request.algorithm,
+            &auth_data,
+        )?;
+
+        let response = CreateKeyBackupVersionResponse {
+            version: version.version.to_string(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/room_keys/version` endpoint.
+///
+/// Describes the current version of the user's key backup.
+pub struct GetKeyBackupVersion;
+
+#[derive(Debug, Serialize)]
+struct GetKeyBackupVersionResponse {
+    algorithm: String,
+    auth_data: Value,
+    count: i64,
+    etag: String,
+    version: String,
+}
+
+middleware_chain!(GetKeyBackupVersion, [AccessTokenAuth]);
+
+impl Handler for GetKeyBackupVersion {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = authed_user(request)?;
+        let connection = DB::from_request(request)?;
+
+        let version = match KeyBackupVersion::find_current(&connection, &user.id)? {
+            Some(version) => version,
+            None => Err(ApiError::not_found("No current backup version.".to_string()))?,
+        };
+
+        let response = GetKeyBackupVersionResponse {
+            auth_data: from_str(&version.auth_data).map_err(ApiError::from)?,
+            count: version.count_keys(&connection)?,
+            etag: version.etag.to_string(),
+            version: version.version.to_string(),
+            algorithm: version.algorithm,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The DELETE `/room_keys/version/:version` endpoint.
+///
+/// Deletes a version of the user's key backup along with its keys.
+pub struct DeleteKeyBackupVersion;
+
+middleware_chain!(DeleteKeyBackupVersion, [AccessTokenAuth]);
+
+impl Handler for DeleteKeyBackupVersion {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let version = path_param(request, "version")?;
+        let user = authed_user(request)?;
+        let connection = DB::from_request(request)?;
+
+        let deleted = match version.parse::<i64>() {
+            Ok(version) => KeyBackupVersion::delete(&connection, &user.id, version)?,
+            Err(_) => false,
+        };
+
+        if !deleted {
+            Err(ApiError::not_found(format!("Unknown backup version {}.", version)))?;
+        }
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// The PUT `/room_keys/keys` endpoint.
+///
+/// Stores keys of several rooms in the current version of the user's key backup, given by the
+/// `version` parameter. Keys already in the backup are only replaced by better ones.
+pub struct PutRoomKeys;
+
+middleware_chain!(PutRoomKeys, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for PutRoomKeys {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_keys = match request.get::<bodyparser::Struct<RoomKeys>>() {
+            Ok(Some(room_keys)) => room_keys,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let user = authed_user(request)?;
+        let connection = DB::from_request(request)?;
+
+        let mut version = find_current_version(&connection, request, &user.id)?;
+
+        let mut keys = Vec::new();
+
+        for (room_id, room_key_backup) in room_keys.rooms {
+            let room_id = RoomId::try_from(room_id.as_ref()).map_err(|_| {
+                ApiError::invalid_param("rooms", &format!("{} is not a valid room ID.", room_id))
+            })?;
+
+            for (session_id, key_backup_data) in room_key_backup.sessions {
+                keys.push(backed_up_key(&version, &room_id, session_id, key_backup_data)?);
+            }
+        }
+
+        version.store_keys(&connection, keys)?;
+
+        put_room_keys_response(&connection, &version)
+    }
+}
+
+/// The PUT `/room_keys/keys/:room_id/:session_id` endpoint.
+///
+/// Stores a single key in the current version of the user's key backup, given by the `version`
+/// parameter, unless the backup already has a better key for the session.
+pub struct PutRoomKey;
+
+middleware_chain!(PutRoomKey, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for PutRoomKey {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let key_backup_data = match request.get::<bodyparser::Struct<KeyBackupData>>() {
+            Ok(Some(key_backup_data)) => key_backup_data,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let room_id = extension::<RoomIdParam>(request)?;
+        let session_id = path_param(request, "session_id")?;
+        let user = authed_user(request)?;
+        let connection = DB::from_request(request)?;
+
+        let mut version = find_current_version(&connection, request, &user.id)?;
+        let key = backed_up_key(&version, &room_id, session_id, key_backup_data)?;
+
+        version.store_keys(&connection, vec![key])?;
+
+        put_room_keys_response(&connection, &version)
+    }
+}
+
+/// The GET `/room_keys/keys` endpoint.
+///
+/// Lists the keys of a version of the user's key backup, given by the `version` parameter.
+pub struct GetRoomKeys;
+
+middleware_chain!(GetRoomKeys, [AccessTokenAuth]);
+
+impl Handler for GetRoomKeys {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = authed_user(request)?;
+        let connection = DB::from_request(request)?;
+
+        let version = query_params::get_token::<i64>(request, "version")?
+            .ok_or_else(|| ApiError::missing_param("version"))?;
+
+        let version = match KeyBackupVersion::find(&connection, &user.id, version)? {
+            Some(version) => version,
+            None => Err(ApiError::not_found(format!("Unknown backup version {}.", version)))?,
+        };
+
+        let mut response = RoomKeys::default();
+
+        for key in version.keys(&connection)? {
+            let key_backup_data = KeyBackupData {
+                first_message_index: key.first_message_index as u64,
+                forwarded_count: key.forwarded_count as u64,
+                is_verified: key.is_verified,
+                session_data: from_str(&key.session_data).map_err(ApiError::from)?,
+            };
+
+            response.rooms
+                .entry(key.room_id.to_string())
+                .or_insert_with(RoomKeyBackup::default)
+                .sessions
+                .insert(key.session_id, key_backup_data);
+        }
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The current version of the user's key backup, which the `version` parameter must name.
+fn find_current_version(connection: &PgConnection, request: &Request, user_id: &UserId)
+-> Result<KeyBackupVersion, ApiError> {
+    let version = query_params::get_token::<i64>(request, "version")?
+        .ok_or_else(|| ApiError::missing_param("version"))?;
+
+    match KeyBackupVersion::find_current(connection, user_id)? {
+        Some(ref current) if current.version == version => Ok(current.clone()),
+        current => Err(ApiError::wrong_room_keys_version(
+            current.map(|current| current.version.to_string())
+        )),
+    }
+}
+
+/// A key of the backup version, from the data the client sent for it.
+///
+/// Counts too large to be stored are rejected as invalid.
+fn backed_up_key(
+    version: &KeyBackupVersion,
+    room_id: &RoomId,
+    session_id: String,
+    key_backup_data: KeyBackupData,
+) -> Result<BackedUpKey, ApiError> {
+    let to_i64 = |name: &str, value: u64| {
+        i64::try_from(value).map_err(|_| ApiError::invalid_param(name, "The number is too large."))
+    };
+
+    Ok(BackedUpKey {
+        user_id: version.user_id.clone(),
+        version: version.version,
+        room_id: room_id.clone(),
+        session_id: session_id,
+        first_message_index: to_i64("first_message_index", key_backup_data.first_message_index)?,
+        forwarded_count: to_i64("forwarded_count", key_backup_data.forwarded_count)?,
+        is_verified: key_backup_data.is_verified,
+        session_data: to_string(&key_backup_data.session_data).map_err(ApiError::from)?,
+    })
+}
+
+/// The response to storing keys in the backup version.
+fn put_room_keys_response(connection: &PgConnection, version: &KeyBackupVersion)
+-> IronResult<Response> {
+    let response = PutRoomKeysResponse {
+        etag: version.etag.to_string(),
+        count: version.count_keys(connection)?,
+    };
+
+    Ok(Response::with((Status::Ok, SerializableResponse(response))))
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::{Test, TestUser};
+
+    fn create_version(test: &Test, user: &TestUser) -> String {
+        let response = test.post_as(
+            user,
+            "/_matrix/client/r0/room_keys/version",
+            r#"{
+                "algorithm": "m.megolm_backup.v1.curve25519-aes-sha2",
+                "auth_data": {"public_key": "abcdefg"}
+            }"#,
+        );
+        assert_eq!(response.status, Status::Ok, "{}", response.body);
+
+        response.json().get("version").unwrap().as_str().unwrap().to_string()
+    }
+
+    fn key(first_message_index: u64, ciphertext: &str) -> String {
+        format!(
+            r#"{{
+                "first_message_index": {},
+                "forwarded_count": 0,
+                "is_verified": false,
+                "session_data": {{"ciphertext": "{}"}}
+            }}"#,
+            first_message_index,
+            ciphertext
+        )
+    }
+
+    #[test]
+    fn keys_are_backed_up_and_returned() {
+        let test = Test::new();
+        let carl = test.register("carl");
+        let version = create_version(&test, &carl);
+        assert_eq!(version, "1");
+
+        let response = test.put_as(
+            &carl,
+            "/_matrix/client/r0/room_keys/keys/!room:ruma.test/first?version=1",
+            &key(0, "first"),
+        );
+        assert_eq!(response.status, Status::Ok, "{}", response.body);
+        assert_eq!(response.json().get("count").unwrap().as_i64().unwrap(), 1);
+
+        let response = test.put_as(
+            &carl,
+            "/_matrix/client/r0/room_keys/keys?version=1",
+            &format!(
+                r#"{{"rooms": {{"!other:ruma.test": {{"sessions": {{"second": {}}}}}}}}}"#,
+                key(0, "second")
+            ),
+        );
+        assert_eq!(response.status, Status::Ok, "{}", response.body);
+        assert_eq!(response.json().get("count").unwrap().as_i64().unwrap(), 2);
+
+        let response = test.get_as(&carl, "/_matrix/client/r0/room_keys/keys?version=1");
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json()
+                .pointer("/rooms/!room:ruma.test/sessions/first/session_data/ciphertext")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "first"
+        );
+        assert_eq!(
+            response.json()
+                .pointer("/rooms/!other:ruma.test/sessions/second/session_data/ciphertext")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "second"
+        );
+
+        let response = test.get_as(&carl, "/_matrix/client/r0/room_keys/version");
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("version").unwrap().as_str().unwrap(), "1");
+        assert_eq!(response.json().get("count").unwrap().as_i64().unwrap(), 2);
+        assert_eq!(
+            response.json().pointer("/auth_data/public_key").unwrap().as_str().unwrap(),
+            "abcdefg"
+        );
+    }
+
+    #[test]
+    fn worse_keys_do_not_replace_better_ones() {
+        let test = Test::new();
+        let carl = test.register("carl");
+        create_version(&test, &carl);
+
+        let path = "/_matrix/client/r0/room_keys/keys/!room:ruma.test/session?version=1";
+
+        let response = test.put_as(&carl, path, &key(5, "original"));
+        let etag = response.json().get("etag").unwrap().as_str().unwrap().to_string();
+
+        let response = test.put_as(&carl, path, &key(10, "worse"));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("etag").unwrap().as_str().unwrap(), etag);
+
+        let response = test.get_as(&carl, "/_matrix/client/r0/room_keys/keys?version=1");
+        assert_eq!(
+            response.json()
+                .pointer("/rooms/!room:ruma.test/sessions/session/session_data/ciphertext")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "original"
+        );
+
+        let response = test.put_as(&carl, path, &key(0, "better"));
+        assert!(response.json().get("etag").unwrap().as_str().unwrap() != etag);
+
+        let response = test.get_as(&carl, "/_matrix/client/r0/room_keys/keys?version=1");
+        assert_eq!(
+            response.json()
+                .pointer("/rooms/!room:ruma.test/sessions/session/session_data/ciphertext")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "better"
+        );
+    }
+
+    #[test]
+    fn keys_with_too_large_counts_are_rejected() {
+        let test = Test::new();
+        let carl = test.register("carl");
+        create_version(&test, &carl);
+
+        let response = test.put_as(
+            &carl,
+            "/_matrix/client/r0/room_keys/keys/!room:ruma.test/first?version=1",
+            &key(u64::max_value(), "first"),
+        );
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+
+        let response = test.get_as(&carl, "/_matrix/client/r0/room_keys/version");
+        assert_eq!(response.json().get("count").unwrap().as_i64().unwrap(), 0);
+    }
+
+    #[test]
+    fn keys_must_be_sent_for_the_current_version() {
+        let test = Test::new();
+        let carl = test.register("carl");
+        create_version(&test, &carl);
+        create_version(&test, &carl);
+
+        let response = test.put_as(
+            &carl,
+            "/_matrix/client/r0/room_keys/keys/!room:ruma.test/session?version=1",
+            &key(0, "outdated"),
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_WRONG_ROOM_KEYS_VERSION"
+        );
+        assert_eq!(response.json().get("current_version").unwrap().as_str().unwrap(), "2");
+    }
+
+    #[test]
+    fn deleted_versions_are_gone_with_their_keys() {
+        let test = Test::new();
+        let carl = test.register("carl");
+        let alice = test.register("alice");
+        create_version(&test, &carl);
+
+        test.put_as(
+            &carl,
+            "/_matrix/client/r0/room_keys/keys/!room:ruma.test/session?version=1",
+            &key(0, "secret"),
+        );
+
+        let response = test.delete_as(&alice, "/_matrix/client/r0/room_keys/version/1");
+        assert_eq!(response.status, Status::NotFound);
+
+        let response = test.delete_as(&carl, "/_matrix/client/r0/room_keys/version/1");
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get_as(&carl, "/_matrix/client/r0/room_keys/version");
+        assert_eq!(response.status, Status::NotFound);
+
+        let response = test.get_as(&carl, "/_matrix/client/r0/room_keys/keys?version=1");
+        assert_eq!(response.status, Status::NotFound);
+
+        assert_eq!(create_version(&test, &carl), "2");
+    }
+}
@@ -1,82 +1,67 @@
 //! Endpoints for syncing.
 use std::u64;
 use std::error::Error;
-use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use iron::{Chain, Handler, IronResult, Request, Response};
 use iron::status::Status;
 use ruma_events::presence::PresenceState;
 use serde_json::from_str;
-use url::Url;
 
+use clock;
 use config::Config;
-use db::DB;
+use db::{DB, snapshot_with_retry};
 use error::ApiError;
 use middleware::{AccessTokenAuth, MiddlewareChain};
-use models::user::User;
-use modifier::SerializableResponse;
+use models::event::Event;
+use modifier::StreamingJsonSerializer;
+use notifier::Notifier;
 use query::{self, Batch, SyncOptions};
+use query_params;
+use request_ext::authed_user;
+use shutdown::ShuttingDown;
+
+/// The values of the `set_presence` parameter.
+const PRESENCE_STATES: [(&'static str, PresenceState); 3] = [
+    ("online", PresenceState::Online),
+    ("unavailable", PresenceState::Unavailable),
+    ("offline", PresenceState::Offline),
+];
+
+/// How long a sync that can't wait for new events, as all sync workers are busy, asks the client
+/// to wait before syncing again.
+const BUSY_RETRY_AFTER_MS: u64 = 1000;
 
 /// The `/sync` endpoint.
+///
+/// An incremental sync without new events waits for them until its timeout, if one of the
+/// configured sync workers is free. Otherwise it is answered with a 503 error
+/// telling the client when to retry, so that it doesn't sync again right away.
 pub struct Sync;
 
 middleware_chain!(Sync, [AccessTokenAuth]);
 
 impl Handler for Sync {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = authed_user(request)?;
 
-        let connection = DB::from_request(request)?;
+        let pool = DB::pool_from_request(request)?;
         let config = Config::from_request(request)?;
-
-        let url: Url = request.url.clone().into();
-        let query_pairs = url.query_pairs().into_owned();
-
-        let mut filter = None;
-        let mut since = None;
-        let mut full_state = false;
-        let mut set_presence = None;
-        let mut timeout = 0;
-        for tuple in query_pairs {
-            match (tuple.0.as_ref(), tuple.1.as_ref()) {
-                ("filter", value) => {
-                    let content = from_str(value)
-                        .map_err(|err| ApiError::invalid_param("filter", err.description()))?;
-                    filter = Some(content);
-                },
-                ("since", value) => {
-                    let batch = Batch::from_str(value)
-                        .map_err(|err| ApiError::invalid_param("since", &err))?;
-                    since = Some(batch);
-                }
-                ("full_state", "true") => {
-                    full_state = true;
-                }
-                ("full_state", "false") => {
-                    full_state = false;
-                }
-                ("full_state", _) => {
-                    Err(ApiError::invalid_param("set_presence", "No boolean!"))?;
-                }
-                ("set_presence", "online") => {
-                    set_presence = Some(PresenceState::Online);
-                }
-                ("set_presence", "unavailable") => {
-                    set_presence = Some(PresenceState::Unavailable);
-                }
-                ("set_presence", "offline") => {
-                    set_presence = Some(PresenceState::Offline);
-                }
-                ("set_presence", _) => {
-                    Err(ApiError::invalid_param("set_presence", "Invalid enum!"))?;
-                }
-                ("timeout", value) => {
-                    timeout = u64::from_str_radix(value, 10).map_err(|err| ApiError::invalid_param("timeout", err.description()))?;
-                }
-                _ => (),
-            }
-        }
+        let clock = clock::from_request(request)?;
+        let notifier = Notifier::from_request(request)?;
+        let shutting_down = ShuttingDown::from_request(request)?;
+
+        let filter = match query_params::get(request, "filter") {
+            Some(filter) => Some(
+                from_str(&filter)
+                    .map_err(|err| ApiError::invalid_param("filter", err.description()))?
+            ),
+            None => None,
+        };
+        let since = query_params::get_token::<Batch>(request, "since")?;
+        let full_state = query_params::get_bool(request, "full_state", false)?;
+        let set_presence = query_params::get_enum(request, "set_presence", &PRESENCE_STATES)?;
+        let timeout = query_params::get_u64(request, "timeout", 0, u64::MAX)?;
 
         let options = SyncOptions {
             filter: filter,
@@ -86,25 +71,82 @@ impl Handler for Sync {
             timeout: timeout,
         };
 
-        let response = query::Sync::sync(&connection, &config.domain, &user, options)?;
+        let deadline = Instant::now() + Duration::from_millis(options.timeout);
+        let mut wait_slot = None;
+
+        loop {
+            let connection = pool.get().map_err(ApiError::from)?;
+            let stable_ordering = Event::stable_ordering(&connection)?;
+            let response = snapshot_with_retry(&connection, || {
+                query::Sync::sync(
+                    &connection,
+                    &*clock,
+                    &config.domain,
+                    &user,
+                    stable_ordering,
+                    options.clone(),
+                )
+            })?;
+            drop(connection);
+
+            let done = options.since.is_none() ||
+                !response.is_empty() ||
+                shutting_down.is_set() ||
+                Instant::now() >= deadline;
+
+            if !done && wait_slot.is_none() {
+                wait_slot = notifier.reserve_wait_slot();
+
+                if wait_slot.is_none() {
+                    debug!("Not waiting for new events for {}: all sync workers are busy.", user.id);
+
+                    Err(
+                        ApiError::unavailable("All sync workers are busy.".to_string())
+                            .with_retry_after(Duration::from_millis(BUSY_RETRY_AFTER_MS))
+                    )?;
+                }
+            }
+
+            if done {
+                return Ok(Response::with((Status::Ok, StreamingJsonSerializer(response))));
+            }
 
-        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+            notifier.wait_until(deadline);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
     use std::convert::TryFrom;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
 
     use test::Test;
+    use iron::headers::ContentLength;
     use iron::status::Status;
     use ruma_events::presence::PresenceState;
     use ruma_identifiers::EventId;
     use serde_json::from_str;
 
+    use models::event::Event;
     use models::filter::ContentFilter;
     use query::{SyncOptions};
 
+    #[test]
+    fn initial_sync_is_streamed() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.get(&format!("/_matrix/client/r0/sync?access_token={}", alice.token));
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.headers.get::<ContentLength>().is_none());
+        assert!(response.json().pointer(&format!("/rooms/join/{}/timeline", room_id)).is_some());
+    }
+
     #[test]
     fn sync_without_new_events() {
         let test = Test::new();
@@ -1055,4 +1097,195 @@ mod tests {
         let response = test.get(&format!("/_matrix/client/r0/sync?full_state={}&access_token={}", "{10s_234", carl.token));
         assert_eq!(response.status, Status::BadRequest);
     }
+
+    #[test]
+    fn joined_rooms_have_a_summary() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.invite(&alice.token, &room_id, &carl.id).status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0
+        };
+        let response = test.sync(&alice.token, options.clone());
+        let summary = response.json()
+            .pointer(&format!("/rooms/join/{}/summary", room_id))
+            .unwrap()
+            .clone();
+
+        assert_eq!(summary.get("m.joined_member_count").unwrap().as_u64().unwrap(), 2);
+        assert_eq!(summary.get("m.invited_member_count").unwrap().as_u64().unwrap(), 1);
+
+        let heroes: Vec<&str> = summary.get("m.heroes").unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|hero| hero.as_str().unwrap())
+            .collect();
+        assert_eq!(heroes, vec![bob.id.as_str(), carl.id.as_str()]);
+
+        let name = r#"{"name": "Rust"}"#;
+        let response = test.send_state_event(&alice.token, &room_id, "m.room.name", name);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.sync(&alice.token, options);
+        let summary = response.json()
+            .pointer(&format!("/rooms/join/{}/summary", room_id))
+            .unwrap()
+            .clone();
+
+        assert!(summary.get("m.heroes").is_none());
+        assert_eq!(summary.get("m.joined_member_count").unwrap().as_u64().unwrap(), 2);
+    }
+
+    #[test]
+    fn syncs_are_asked_to_retry_later_when_no_sync_worker_is_free() {
+        let test = Test::with_config(|config| config.sync_workers = 0);
+        let (alice, _) = test.initial_fixtures("{}");
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0
+        };
+        let next_batch = Test::get_next_batch(&test.sync(&alice.token, options));
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 30000
+        };
+        let response = test.sync(&alice.token, options);
+
+        assert_eq!(response.status, Status::ServiceUnavailable);
+        assert_eq!(response.json().get("retry_after_ms").unwrap().as_u64().unwrap(), 1000);
+        assert_eq!(response.headers.get_raw("Retry-After").unwrap()[0], b"1".to_vec());
+    }
+
+    #[test]
+    fn soft_failed_events_are_not_synced() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = test.send_message(&alice.token, &room_id, "Hidden", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap().to_string();
+        assert_eq!(test.send_message(&alice.token, &room_id, "Shown", 2).status, Status::Ok);
+
+        {
+            let connection = test.connection();
+            let event_id = EventId::try_from(event_id.as_str()).unwrap();
+
+            Event::soft_fail(&connection, &event_id).unwrap();
+        }
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0
+        };
+        let response = test.sync(&alice.token, options);
+        let event_ids: Vec<&str> = response.json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event.get("event_id").unwrap().as_str().unwrap())
+            .collect();
+
+        assert!(!event_ids.is_empty());
+        assert!(!event_ids.contains(&event_id.as_str()));
+    }
+
+    #[test]
+    fn incremental_syncs_see_concurrent_messages_exactly_once_and_in_order() {
+        let test = Arc::new(Test::new());
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let sync = |since| {
+            let options = SyncOptions {
+                filter: Some(from_str(r#"{"room":{"timeline":{"limit":100}}}"#).unwrap()),
+                since: since,
+                full_state: false,
+                set_presence: None,
+                timeout: 0,
+            };
+
+            test.sync(&alice.token, options)
+        };
+        let mut since = Test::get_next_batch(&sync(None));
+
+        let sent_count = Arc::new(AtomicUsize::new(0));
+        let senders: Vec<_> = (0..50).map(|txn_id| {
+            let test = test.clone();
+            let sent_count = sent_count.clone();
+            let token = alice.token.clone();
+            let room_id = room_id.clone();
+
+            thread::spawn(move || {
+                let response = test.send_message(&token, &room_id, &format!("{}", txn_id), txn_id);
+                sent_count.fetch_add(1, Ordering::SeqCst);
+
+                response.status
+            })
+        }).collect();
+
+        // Chain incremental syncs while the messages are being sent, and once more afterwards.
+        let mut received = Vec::new();
+
+        loop {
+            let all_sent = sent_count.load(Ordering::SeqCst) == 50;
+
+            let response = sync(Some(since));
+            since = Test::get_next_batch(&response);
+
+            if let Some(events) = response.json()
+                .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+                .and_then(|events| events.as_array()) {
+                for event in events {
+                    let event_id = event.get("event_id").unwrap().as_str().unwrap();
+                    let body = event.pointer("/content/body").unwrap().as_str().unwrap();
+
+                    received.push((event_id.to_string(), body.to_string()));
+                }
+            }
+
+            if all_sent {
+                break;
+            }
+        }
+
+        for sender in senders {
+            assert_eq!(sender.join().unwrap(), Status::Ok);
+        }
+
+        let bodies: HashSet<String> = received.iter().map(|&(_, ref body)| body.clone()).collect();
+        let sent: HashSet<String> = (0..50).map(|txn_id| format!("{}", txn_id)).collect();
+
+        assert_eq!(received.len(), 50);
+        assert_eq!(bodies, sent);
+
+        let connection = test.connection();
+        let orderings: Vec<i64> = received.iter().map(|&(ref event_id, _)| {
+            let event_id = EventId::try_from(event_id.as_str()).unwrap();
+
+            Event::find(&connection, &event_id).unwrap().unwrap().ordering
+        }).collect();
+
+        assert!(orderings.windows(2).all(|pair| pair[0] < pair[1]));
+    }
 }
@@ -5,14 +5,15 @@ use bodyparser;
 use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
 use iron::status::Status;
 use ruma_events::tag::TagInfo;
+use ruma_identifiers::RoomId;
 use serde_json::Value;
 
 use db::DB;
 use error::ApiError;
 use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomIdParam, UserIdParam, TagParam};
 use models::tags::RoomTag;
-use models::user::User;
 use modifier::{SerializableResponse, EmptyResponse};
+use request_ext::{authed_user, extension};
 
 /// The GET `/user/:user_id/rooms/:room_id/tags` endpoint.
 pub struct GetTags;
@@ -26,12 +27,9 @@ pub struct TagsResponse {
 
 impl Handler for GetTags {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
-        let room_id = request.extensions.get::<RoomIdParam>()
-            .expect("RoomIdParam should ensure a RoomId").clone();
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user_id = extension::<UserIdParam>(request)?;
+        let room_id = extension::<RoomIdParam>(request)?;
+        let user = authed_user(request)?;
 
         // Check if the given user_id corresponds to the authenticated user.
         if user_id != user.id {
@@ -50,6 +48,44 @@ impl Handler for GetTags {
     }
 }
 
+/// The GET `/user/:user_id/tags` endpoint.
+///
+/// This is not part of the Matrix specification. It returns the tags of every room at once,
+/// the same data that is otherwise delivered per room through sync.
+pub struct GetAllTags;
+
+middleware_chain!(GetAllTags, [UserIdParam, AccessTokenAuth]);
+
+#[derive(Debug, Serialize)]
+pub struct AllTagsResponse {
+    rooms: HashMap<RoomId, TagsResponse>,
+}
+
+impl Handler for GetAllTags {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user_id = extension::<UserIdParam>(request)?;
+        let user = authed_user(request)?;
+
+        // Check if the given user_id corresponds to the authenticated user.
+        if user_id != user.id {
+            Err(ApiError::unauthorized("The given user_id does not correspond to the authenticated user".to_string()))?;
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let rooms = RoomTag::find_by_user(&connection, user_id)?
+            .into_iter()
+            .map(|(room_id, tags)| (room_id, TagsResponse { tags: tags }))
+            .collect();
+
+        let response = AllTagsResponse {
+            rooms: rooms,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
 /// The PUT `/user/:user_id/rooms/:room_id/tags/:tag` endpoint.
 pub struct PutTag;
 
@@ -57,14 +93,10 @@ middleware_chain!(PutTag, [UserIdParam, RoomIdParam, TagParam, JsonRequest, Acce
 
 impl Handler for PutTag {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
-        let room_id = request.extensions.get::<RoomIdParam>()
-            .expect("RoomIdParam should ensure a RoomId").clone();
-        let tag = request.extensions.get::<TagParam>()
-            .expect("TagParam should ensure a tag").clone();
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user_id = extension::<UserIdParam>(request)?;
+        let room_id = extension::<RoomIdParam>(request)?;
+        let tag = extension::<TagParam>(request)?;
+        let user = authed_user(request)?;
 
         // Check if the given user_id corresponds to the authenticated user.
         if user_id != user.id {
@@ -85,27 +117,36 @@ impl Handler for PutTag {
     }
 }
 
+/// The tags defined by the Matrix specification. Any other tag has to start with `u.`.
+const SPEC_TAGS: [&'static str; 3] = ["m.favourite", "m.lowpriority", "m.server_notice"];
+
 /// The DELETE `/user/:user_id/rooms/:room_id/tags/:tag` endpoint.
+///
+/// Fails with `M_INVALID_PARAM` for tags that are neither defined by the specification nor
+/// user-defined, and with `M_NOT_FOUND` if the room doesn't have the tag.
 pub struct DeleteTag;
 
 middleware_chain!(DeleteTag, [UserIdParam, RoomIdParam, TagParam, AccessTokenAuth]);
 
 impl Handler for DeleteTag {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user_id = request.extensions.get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId").clone();
-        let room_id = request.extensions.get::<RoomIdParam>()
-            .expect("RoomIdParam should ensure a RoomId").clone();
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
-        let tag = request.extensions.get::<TagParam>()
-            .expect("TagParam should ensure a tag").clone();
+        let user_id = extension::<UserIdParam>(request)?;
+        let room_id = extension::<RoomIdParam>(request)?;
+        let user = authed_user(request)?;
+        let tag = extension::<TagParam>(request)?;
 
         // Check if the given user_id corresponds to the authenticated user.
         if user_id != user.id {
             Err(ApiError::unauthorized("The given user_id does not correspond to the authenticated user".to_string()))?;
         }
 
+        if !SPEC_TAGS.contains(&tag.as_str()) && !tag.starts_with("u.") {
+            Err(ApiError::invalid_param(
+                "tag",
+                "Must be m.favourite, m.lowpriority, m.server_notice or start with u.",
+            ))?;
+        }
+
         let connection = DB::from_request(request)?;
 
         RoomTag::delete(&connection, user_id, room_id, tag)?;
@@ -146,6 +187,57 @@ mod tests {
         assert_eq!(content.to_string(), r#"{"order":"test"}"#);
     }
 
+    #[test]
+    fn get_all_tags() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let alice = test.create_user();
+
+        let work_room_id = test.create_public_room(&carl.token);
+        let home_room_id = test.create_public_room(&carl.token);
+        let untagged_room_id = test.create_public_room(&carl.token);
+        let alice_room_id = test.create_public_room(&alice.token);
+
+        test.create_tag(&carl.token, &work_room_id, &carl.id, "work", r#"{"order":"0.5"}"#);
+        test.create_tag(&carl.token, &home_room_id, &carl.id, "m.favourite", r#"{"order":"0.1"}"#);
+        test.create_tag(&carl.token, &home_room_id, &carl.id, "home", r#"{}"#);
+        test.create_tag(&alice.token, &alice_room_id, &alice.id, "work", r#"{}"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/user/{}/tags?access_token={}",
+            carl.id,
+            carl.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let rooms = response.json().get("rooms").unwrap().as_object().unwrap();
+        assert_eq!(rooms.len(), 2);
+        assert!(rooms.get(&untagged_room_id).is_none());
+        assert!(rooms.get(&alice_room_id).is_none());
+
+        let work_tags = rooms.get(&work_room_id).unwrap().get("tags").unwrap().as_object().unwrap();
+        assert_eq!(work_tags.len(), 1);
+        assert_eq!(work_tags.get("work").unwrap().to_string(), r#"{"order":"0.5"}"#);
+
+        let home_tags = rooms.get(&home_room_id).unwrap().get("tags").unwrap().as_object().unwrap();
+        assert_eq!(home_tags.len(), 2);
+        assert_eq!(home_tags.get("m.favourite").unwrap().to_string(), r#"{"order":"0.1"}"#);
+    }
+
+    #[test]
+    fn get_all_tags_forbidden() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let alice = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/user/{}/tags?access_token={}",
+            carl.id,
+            alice.token
+        ));
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
     #[test]
     fn get_tags_forbidden() {
         let test = Test::new();
@@ -191,10 +283,10 @@ mod tests {
 
         let room_id = test.create_public_room(&carl.token);
 
-        test.create_tag(&carl.token, &room_id, carl.id.as_str(), "delete", r#"{"order":"test"}"#);
+        test.create_tag(&carl.token, &room_id, carl.id.as_str(), "u.delete", r#"{"order":"test"}"#);
 
         let delete_tag_path = format!(
-            "/_matrix/client/r0/user/{}/rooms/{}/tags/delete?access_token={}",
+            "/_matrix/client/r0/user/{}/rooms/{}/tags/u.delete?access_token={}",
             carl.id,
             room_id,
             alice.token
@@ -236,10 +328,10 @@ mod tests {
 
         let room_id = test.create_public_room(&carl.token);
 
-        test.create_tag(&carl.token, &room_id, &carl.id, "delete", r#"{"order":"test"}"#);
+        test.create_tag(&carl.token, &room_id, &carl.id, "u.delete", r#"{"order":"test"}"#);
 
         let delete_tag_path = format!(
-            "/_matrix/client/r0/user/{}/rooms/{}/tags/delete?access_token={}",
+            "/_matrix/client/r0/user/{}/rooms/{}/tags/u.delete?access_token={}",
             carl.id,
             room_id,
             carl.token
@@ -306,7 +398,7 @@ mod tests {
         let room_id = test.create_public_room(&carl.token);
 
         let delete_tag_path = format!(
-            "/_matrix/client/r0/user/{}/rooms/{}/tags/test?access_token={}",
+            "/_matrix/client/r0/user/{}/rooms/{}/tags/u.test?access_token={}",
             carl.id,
             room_id,
             carl.token
@@ -318,7 +410,47 @@ mod tests {
         let room_id = "!n8f893n9:ruma.test";
 
         let delete_tag_path = format!(
-            "/_matrix/client/r0/user/{}/rooms/{}/tags/test?access_token={}",
+            "/_matrix/client/r0/user/{}/rooms/{}/tags/u.test?access_token={}",
+            carl.id,
+            room_id,
+            carl.token
+        );
+
+        let response = test.delete(&delete_tag_path);
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn delete_tag_with_invalid_name() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = test.create_public_room(&carl.token);
+
+        test.create_tag(&carl.token, &room_id, &carl.id, "work", r#"{"order":"test"}"#);
+
+        let delete_tag_path = format!(
+            "/_matrix/client/r0/user/{}/rooms/{}/tags/work?access_token={}",
+            carl.id,
+            room_id,
+            carl.token
+        );
+
+        let response = test.delete(&delete_tag_path);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn delete_spec_tag() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = test.create_public_room(&carl.token);
+
+        let delete_tag_path = format!(
+            "/_matrix/client/r0/user/{}/rooms/{}/tags/m.favourite?access_token={}",
             carl.id,
             room_id,
             carl.token
@@ -326,5 +458,10 @@ mod tests {
 
         let response = test.delete(&delete_tag_path);
         assert_eq!(response.status, Status::NotFound);
+
+        test.create_tag(&carl.token, &room_id, &carl.id, "m.favourite", r#"{"order":"0.5"}"#);
+
+        let response = test.delete(&delete_tag_path);
+        assert_eq!(response.status, Status::Ok);
     }
 }
@@ -0,0 +1,241 @@
+//! Endpoint for finding the event of a room closest to a point in time.
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::EventId;
+use url::Url;
+
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use models::event::{Event, TimestampDirection};
+use models::room_membership::RoomMembership;
+use modifier::SerializableResponse;
+use request_ext::{authed_user, extension};
+use state_cache::StateCache;
+use unstable_features::UnstableFeatures;
+
+/// The GET `/rooms/:room_id/timestamp_to_event` endpoint.
+///
+/// The `ts` query parameter is a time in milliseconds since the Unix epoch, and `dir` is `f` to
+/// look for the earliest event at or after it, or `b` for the latest event at or before it.
+pub struct TimestampToEvent;
+
+#[derive(Debug, Serialize)]
+struct TimestampToEventResponse {
+    /// The ID of the closest event.
+    event_id: EventId,
+    /// The timestamp of the closest event, in milliseconds since the Unix epoch.
+    origin_server_ts: i64,
+}
+
+impl TimestampToEvent {
+    /// Registers the endpoint as unstable feature `org.matrix.msc3030`, which can be advertised by
+    /// enabling it in the configuration.
+    pub fn register_unstable_features(unstable_features: &mut UnstableFeatures) {
+        unstable_features.register("org.matrix.msc3030", false);
+    }
+}
+
+middleware_chain!(TimestampToEvent, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for TimestampToEvent {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = authed_user(request)?;
+
+        let room_id = extension::<RoomIdParam>(request)?;
+
+        let url: Url = request.url.clone().into();
+        let mut ts = None;
+        let mut direction = None;
+
+        for (name, value) in url.query_pairs() {
+            match name.as_ref() {
+                "ts" => {
+                    ts = Some(value.parse::<i64>().map_err(|_| {
+                        ApiError::invalid_param("ts", "Must be milliseconds since the Unix epoch.")
+                    })?);
+                }
+                "dir" => {
+                    direction = match value.as_ref() {
+                        "f" => Some(TimestampDirection::Forward),
+                        "b" => Some(TimestampDirection::Backward),
+                        _ => Err(ApiError::invalid_param("dir", "Must be f or b."))?,
+                    };
+                }
+                _ => {}
+            }
+        }
+
+        let ts = match ts {
+            Some(ts) => ts,
+            None => Err(ApiError::missing_param("ts"))?,
+        };
+
+        let direction = match direction {
+            Some(direction) => direction,
+            None => Err(ApiError::missing_param("dir"))?,
+        };
+
+        let connection = DB::from_request(request)?;
+
+        let state_cache = StateCache::from_request(request)?;
+
+        RoomMembership::require(&connection, &state_cache, &room_id, &user.id, &["join"])?;
+
+        let event = match Event::find_closest_to_timestamp(&connection, &room_id, ts, direction)? {
+            Some(event) => event,
+            None => Err(ApiError::not_found(
+                "No event was found in the given direction.".to_string()
+            ))?,
+        };
+
+        let response = TimestampToEventResponse {
+            origin_server_ts: event.origin_server_ts(),
+            event_id: event.id,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::{ExecuteDsl, ExpressionMethods, FindDsl, update};
+    use diesel::pg::data_types::PgTimestamp;
+    use iron::status::Status;
+    use ruma_identifiers::EventId;
+
+    use models::event::POSTGRES_EPOCH_MS;
+    use schema::events;
+    use test::{Response, Test};
+
+    /// A time far enough in the future to be after all events created by the test setup.
+    const FUTURE_MS: i64 = 4_000_000_000_000;
+
+    fn timestamp_to_event(test: &Test, access_token: &str, room_id: &str, query: &str)
+    -> Response {
+        test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/timestamp_to_event?{}&access_token={}",
+            room_id,
+            query,
+            access_token
+        ))
+    }
+
+    fn send_message_at(test: &Test, access_token: &str, room_id: &str, txn_id: u64, ts: i64)
+    -> String {
+        let response = test.send_message(access_token, room_id, "Hi", txn_id);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap().to_string();
+
+        update(events::table.find(EventId::try_from(&event_id[..]).unwrap()))
+            .set(events::created_at.eq(PgTimestamp((ts - POSTGRES_EPOCH_MS) * 1000)))
+            .execute(&*test.connection())
+            .unwrap();
+
+        event_id
+    }
+
+    #[test]
+    fn find_event_in_both_directions() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let first = send_message_at(&test, &alice.token, &room_id, 1, FUTURE_MS + 1000);
+        let second = send_message_at(&test, &alice.token, &room_id, 2, FUTURE_MS + 2000);
+
+        let response = timestamp_to_event(
+            &test,
+            &alice.token,
+            &room_id,
+            &format!("ts={}&dir=f", FUTURE_MS + 500),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("event_id").unwrap().as_str().unwrap(), first);
+        assert_eq!(
+            response.json().get("origin_server_ts").unwrap().as_i64().unwrap(),
+            FUTURE_MS + 1000
+        );
+
+        let response = timestamp_to_event(
+            &test,
+            &alice.token,
+            &room_id,
+            &format!("ts={}&dir=b", FUTURE_MS + 2500),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("event_id").unwrap().as_str().unwrap(), second);
+    }
+
+    #[test]
+    fn skewed_timestamps_are_compared() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        send_message_at(&test, &alice.token, &room_id, 1, FUTURE_MS + 1000);
+        send_message_at(&test, &alice.token, &room_id, 2, FUTURE_MS + 3000);
+        let skewed = send_message_at(&test, &alice.token, &room_id, 3, FUTURE_MS + 2000);
+
+        let response = timestamp_to_event(
+            &test,
+            &alice.token,
+            &room_id,
+            &format!("ts={}&dir=f", FUTURE_MS + 1500),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("event_id").unwrap().as_str().unwrap(), skewed);
+    }
+
+    #[test]
+    fn no_event_in_direction() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = timestamp_to_event(
+            &test,
+            &alice.token,
+            &room_id,
+            &format!("ts={}&dir=f", FUTURE_MS),
+        );
+
+        assert_eq!(response.status, Status::NotFound);
+
+        let response = timestamp_to_event(&test, &alice.token, &room_id, "ts=0&dir=b");
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn invalid_query() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = timestamp_to_event(&test, &alice.token, &room_id, "ts=0");
+        assert_eq!(response.status, Status::BadRequest);
+
+        let response = timestamp_to_event(&test, &alice.token, &room_id, "ts=0&dir=x");
+        assert_eq!(response.status, Status::BadRequest);
+
+        let response = timestamp_to_event(&test, &alice.token, &room_id, "ts=soon&dir=f");
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn non_member_cant_tell_the_room_exists() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures("{}");
+        let bob = test.create_user();
+
+        let response = timestamp_to_event(&test, &bob.token, &room_id, "ts=0&dir=f");
+        let missing = timestamp_to_event(&test, &bob.token, "!missing:ruma.test", "ts=0&dir=f");
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(missing.status, Status::NotFound);
+        assert_eq!(response.raw_body, missing.raw_body);
+    }
+}
@@ -0,0 +1,279 @@
+//! Endpoints for sending messages directly to devices.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use bodyparser;
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use ruma_identifiers::UserId;
+use serde_json::{Value, to_string};
+
+use config::Config;
+use db::{DB, transaction_with_retry};
+use error::ApiError;
+use identifiers;
+use middleware::{
+    AccessTokenAuth,
+    EventTypeParam,
+    JsonRequest,
+    MiddlewareChain,
+    TransactionIdParam,
+    TransactionIdempotency,
+};
+use models::access_token::AccessToken;
+use models::to_device_message::ToDeviceMessage;
+use models::user::User;
+use modifier::SerializableResponse;
+use request_ext::{authed_user, extension};
+
+/// The device ID that addresses all devices of a user.
+const ALL_DEVICES: &'static str = "*";
+
+/// The PUT `/sendToDevice/:event_type/:transaction_id` endpoint.
+///
+/// Queues the messages for the devices they are addressed to, where `*` stands for all devices
+/// of the user. Messages for users who can't be reached, e.g. because they were deactivated or
+/// are on another server, are listed in `failures` instead.
+pub struct SendToDevice;
+
+#[derive(Clone, Debug, Deserialize)]
+struct SendToDeviceRequest {
+    /// The content of the messages, by user ID and device ID.
+    messages: HashMap<String, HashMap<String, Value>>,
+}
+
+#[derive(Debug, Serialize)]
+struct SendToDeviceResponse {
+    /// The device IDs that the messages couldn't be delivered to, by user ID.
+    failures: BTreeMap<String, Vec<String>>,
+}
+
+impl MiddlewareChain for SendToDevice {
+    fn chain() -> Chain {
+        let mut chain = Chain::new(SendToDevice);
+
+        chain.link_before(JsonRequest);
+        chain.link_before(EventTypeParam);
+        chain.link_before(TransactionIdParam);
+        chain.link_before(AccessTokenAuth);
+        chain.link_around(TransactionIdempotency);
+
+        chain
+    }
+}
+
+impl Handler for SendToDevice {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let event_type = extension::<EventTypeParam>(request)?.to_string();
+
+        extension::<TransactionIdParam>(request)?;
+
+        let user = authed_user(request)?;
+
+        let body = request.get::<bodyparser::Struct<SendToDeviceRequest>>();
+        let send_to_device_request = match body {
+            Ok(Some(request)) => request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let failures = transaction_with_retry(&connection, || {
+            send_messages(
+                &connection,
+                &config,
+                &user.id,
+                &event_type,
+                &send_to_device_request.messages,
+            )
+        })?;
+
+        let response = SendToDeviceResponse {
+            failures: failures,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Queues the messages, returning the devices that couldn't be reached by user ID.
+fn send_messages(
+    connection: &PgConnection,
+    config: &Config,
+    sender: &UserId,
+    event_type: &str,
+    messages: &HashMap<String, HashMap<String, Value>>,
+) -> Result<BTreeMap<String, Vec<String>>, ApiError> {
+    let mut failures = BTreeMap::new();
+
+    for (user_id, messages) in messages {
+        let mut unreachable: Vec<String> = Vec::new();
+
+        match find_recipient(connection, config, user_id)? {
+            Some(user_id) => {
+                let device_ids: HashSet<String> = AccessToken::find_valid_by_uid(
+                    connection,
+                    &user_id,
+                )?.into_iter().map(|access_token| access_token.device_id).collect();
+
+                for (device_id, content) in messages {
+                    let content = to_string(content).map_err(ApiError::from)?;
+
+                    if device_id == ALL_DEVICES {
+                        ToDeviceMessage::create_for_all_devices(
+                            connection,
+                            sender,
+                            event_type,
+                            &user_id,
+                            &content,
+                        )?;
+                    } else if device_ids.contains(device_id) {
+                        ToDeviceMessage::create(
+                            connection,
+                            sender,
+                            event_type,
+                            &user_id,
+                            device_id,
+                            &content,
+                        )?;
+                    } else {
+                        unreachable.push(device_id.clone());
+                    }
+                }
+            }
+            None => unreachable.extend(messages.keys().cloned()),
+        }
+
+        if !unreachable.is_empty() {
+            unreachable.sort();
+            failures.insert(user_id.clone(), unreachable);
+        }
+    }
+
+    Ok(failures)
+}
+
+/// Looks up a recipient of messages, which must be an active user of this server.
+fn find_recipient(connection: &PgConnection, config: &Config, user_id: &str)
+-> Result<Option<UserId>, ApiError> {
+    let user_id = match identifiers::parse::<UserId>("messages", user_id) {
+        Ok(user_id) => user_id,
+        Err(_) => return Ok(None),
+    };
+
+    if user_id.hostname().to_string() != config.domain {
+        return Ok(None);
+    }
+
+    Ok(User::find_active_user(connection, &user_id)?.map(|user| user.id))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use iron::status::Status;
+    use ruma_identifiers::UserId;
+
+    use models::to_device_message::ToDeviceMessage;
+    use test::Test;
+
+    fn messages_for(test: &Test, user_id: &str, device_id: &str) -> Vec<ToDeviceMessage> {
+        let connection = test.connection();
+        let user_id = UserId::try_from(user_id).unwrap();
+
+        ToDeviceMessage::find_by_device(&connection, &user_id, device_id).unwrap()
+    }
+
+    #[test]
+    fn send_to_all_devices() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        test.login_device(&bob, "LAPTOP");
+        test.login_device(&bob, "PHONE");
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/sendToDevice/m.room_key_request/1?access_token={}",
+                alice.token
+            ),
+            &format!(r#"{{"messages": {{"{}": {{"*": {{"action": "request"}}}}}}}}"#, bob.id),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("failures").unwrap().as_object().unwrap().is_empty());
+
+        for device_id in &["LAPTOP", "PHONE"] {
+            let messages = messages_for(&test, &bob.id, device_id);
+
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].sender.to_string(), alice.id);
+            assert_eq!(messages[0].event_type, "m.room_key_request");
+            assert_eq!(messages[0].content, r#"{"action":"request"}"#);
+        }
+    }
+
+    #[test]
+    fn send_to_one_device() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        test.login_device(&bob, "LAPTOP");
+        test.login_device(&bob, "PHONE");
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/sendToDevice/m.room_key_request/1?access_token={}",
+                alice.token
+            ),
+            &format!(r#"{{"messages": {{"{}": {{"PHONE": {{}}}}}}}}"#, bob.id),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(messages_for(&test, &bob.id, "PHONE").len(), 1);
+        assert!(messages_for(&test, &bob.id, "LAPTOP").is_empty());
+    }
+
+    #[test]
+    fn unreachable_users_and_devices_are_failures() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/account/deactivate?access_token={}", carl.token),
+            "{}",
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/sendToDevice/m.room_key_request/1?access_token={}",
+                alice.token
+            ),
+            &format!(
+                r#"{{"messages": {{
+                    "{}": {{"UNKNOWN": {{}}}},
+                    "{}": {{"*": {{}}}},
+                    "@dan:example.com": {{"*": {{}}}}
+                }}}}"#,
+                bob.id,
+                carl.id
+            ),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let failures = response.json().get("failures").unwrap();
+
+        assert_eq!(failures.get(&bob.id).unwrap().as_array().unwrap().len(), 1);
+        assert_eq!(failures.get(&carl.id).unwrap().get(0).unwrap().as_str().unwrap(), "*");
+        assert!(failures.get("@dan:example.com").is_some());
+    }
+}
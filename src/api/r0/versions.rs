@@ -2,21 +2,29 @@
 
 use iron::{Handler, IronResult, Request, Response, status};
 
+use config::Config;
 use modifier::SerializableResponse;
+use unstable_features::UnstableFeatures;
 
 /// The /versions endpoint.
 #[derive(Serialize)]
 pub struct Versions {
     versions: Vec<&'static str>,
+    unstable_features: UnstableFeatures,
 }
 
 impl Versions {
-    /// Returns the list of supported `Versions` of the Matrix spec.
-    pub fn supported() -> Self {
+    /// Returns the list of supported `Versions` of the Matrix spec, along with the unstable
+    /// features enabled in the `Config`.
+    ///
+    /// The r0 versions are served under the `r0` prefix and the v1.x versions under `v3`.
+    pub fn supported(config: &Config) -> Self {
         Versions {
             versions: vec![
-                "r0.2.0"
-            ]
+                "r0.2.0",
+                "v1.1",
+            ],
+            unstable_features: UnstableFeatures::from_config(config),
         }
     }
 }
@@ -26,3 +34,60 @@ impl Handler for Versions {
         Ok(Response::with((status::Ok, SerializableResponse(&self))))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    #[test]
+    fn versions_lists_spec_versions_and_unstable_features() {
+        let test = Test::new();
+        let response = test.get("/_matrix/client/versions");
+
+        assert_eq!(response.status, Status::Ok);
+
+        let versions = response.json().get("versions").unwrap().as_array().unwrap();
+        assert!(versions.iter().any(|version| version.as_str() == Some("r0.2.0")));
+        assert!(versions.iter().any(|version| version.as_str() == Some("v1.1")));
+
+        let unstable_features = response.json().get("unstable_features").unwrap();
+        assert_eq!(
+            unstable_features.get("m.lazy_load_members").unwrap().as_bool(),
+            Some(false)
+        );
+        assert_eq!(
+            unstable_features.get("org.matrix.msc3030").unwrap().as_bool(),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn configured_unstable_feature_is_advertised_as_enabled() {
+        let test = Test::with_config(|config| {
+            config.unstable_features = vec!["org.matrix.msc3030".to_string()];
+        });
+        let response = test.get("/_matrix/client/versions");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().pointer("/unstable_features/org.matrix.msc3030").unwrap().as_bool(),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn configured_unimplemented_unstable_feature_stays_disabled() {
+        let test = Test::with_config(|config| {
+            config.unstable_features = vec!["m.lazy_load_members".to_string()];
+        });
+        let response = test.get("/_matrix/client/versions");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().pointer("/unstable_features/m.lazy_load_members").unwrap().as_bool(),
+            Some(false)
+        );
+    }
+}
@@ -6,7 +6,7 @@ use iron::modifier::Modifier;
 use ruma_identifiers::UserId;
 use serde::{Serialize, Serializer};
 
-use error::{ApiError, ApiErrorCode};
+use error::{ApiError, ErrCode};
 use models::user::User;
 
 /// A set of authorization flows the user can follow to authenticate a request.
@@ -26,7 +26,7 @@ impl InteractiveAuth {
 
 impl<'a> Modifier<Response> for &'a InteractiveAuth {
     fn modify(self, response: &mut Response) {
-        response.status = Some(ApiErrorCode::Forbidden.status_code());
+        response.status = Some(ErrCode::Forbidden.status_code());
         response.body = Some(Box::new(r#"{"flows":[{"stages":["m.login.dummy"]}]}"#));
     }
 }
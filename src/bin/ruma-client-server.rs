@@ -4,7 +4,7 @@ extern crate ruma;
 
 use clap::{App, AppSettings, Arg, SubCommand};
 
-use ruma::config::Config;
+use ruma::cli::{generate_keys, load_valid_config};
 use ruma::crypto::generate_macaroon_secret_key;
 use ruma::server::Server;
 
@@ -27,6 +27,9 @@ fn main() {
                      .value_name("PATH")
                      .help("Path to a configuration file")
                      .takes_value(true))
+                .arg(Arg::with_name("generate-keys")
+                     .long("generate-keys")
+                     .help("Creates the missing key files named in the configuration, then exits"))
         )
         .subcommand(
             SubCommand::with_name("secret")
@@ -36,14 +39,13 @@ fn main() {
 
     match matches.subcommand() {
         ("run", Some(submatches)) => {
-            let config = match Config::from_file(submatches.value_of("config")) {
-                Ok(config) => config,
-                Err(error) => {
-                    eprintln!("Failed to load configuration file: {}", error);
+            if submatches.is_present("generate-keys") {
+                generate_keys(submatches.value_of("config"));
 
-                    return;
-                }
-            };
+                return;
+            }
+
+            let config = load_valid_config(submatches.value_of("config"));
 
             match Server::new(&config).mount_client() {
                 Ok(server) => {
@@ -65,4 +67,3 @@ fn main() {
         _ => println!("{}", matches.usage()),
     };
 }
-
@@ -4,7 +4,7 @@ extern crate ruma;
 
 use clap::{App, AppSettings, Arg, SubCommand};
 
-use ruma::config::Config;
+use ruma::cli::load_valid_config;
 use ruma::server::Server;
 
 fn main() {
@@ -31,14 +31,7 @@ fn main() {
 
     match matches.subcommand() {
         ("run", Some(submatches)) => {
-            let config = match Config::from_file(submatches.value_of("config")) {
-                Ok(config) => config,
-                Err(error) => {
-                    eprintln!("Failed to load configuration file: {}", error);
-
-                    return;
-                }
-            };
+            let config = load_valid_config(submatches.value_of("config"));
 
             let server = Server::new(&config).mount_extra();
 
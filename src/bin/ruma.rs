@@ -1,11 +1,19 @@
 extern crate clap;
+extern crate diesel;
 extern crate env_logger;
 extern crate ruma;
 
+use std::process;
+
 use clap::{App, AppSettings, Arg, SubCommand};
+use diesel::Connection;
+use diesel::pg::PgConnection;
 
+use ruma::cli::{self, check_config, exit_with_config_errors, generate_keys, load_valid_config};
+use ruma::cli::{prompt_password, read_password_file};
 use ruma::config::Config;
 use ruma::crypto::generate_macaroon_secret_key;
+use ruma::error::CliError;
 use ruma::server::Server;
 
 fn main() {
@@ -15,17 +23,43 @@ fn main() {
 
     let matches = App::new("ruma")
         .version(env!("CARGO_PKG_VERSION"))
-        .about("A Matrix homeserver.")
+        .about("A Matrix homeserver. Runs the server if no subcommand is given.")
         .setting(AppSettings::GlobalVersion)
-        .setting(AppSettings::SubcommandRequiredElseHelp)
         .subcommand(
             SubCommand::with_name("run")
                 .about("Runs the server")
-                .arg(Arg::with_name("config")
-                     .short("c")
-                     .long("config")
-                     .value_name("PATH")
+                .arg(config_arg())
+                .arg(Arg::with_name("generate-keys")
+                     .long("generate-keys")
+                     .help("Creates the missing key files named in the configuration, then exits"))
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Runs the pending database migrations, then exits")
+                .arg(config_arg())
+                .arg(Arg::with_name("dry-run")
+                     .long("dry-run")
+                     .help("Lists the pending migrations without running them"))
+        )
+        .subcommand(
+            SubCommand::with_name("check-config")
+                .about("Validates the configuration and prints it with secrets redacted")
+                .arg(Arg::with_name("FILE")
                      .help("Path to a configuration file")
+                     .index(1))
+        )
+        .subcommand(
+            SubCommand::with_name("create-admin")
+                .about("Creates a user who can use the admin APIs")
+                .arg(config_arg())
+                .arg(Arg::with_name("LOCALPART")
+                     .help("The localpart of the user ID")
+                     .required(true)
+                     .index(1))
+                .arg(Arg::with_name("password-file")
+                     .long("password-file")
+                     .value_name("PATH")
+                     .help("Reads the password from a file instead of asking for it")
                      .takes_value(true))
         )
         .subcommand(
@@ -36,32 +70,151 @@ fn main() {
 
     match matches.subcommand() {
         ("run", Some(submatches)) => {
-            let config = match Config::from_file(submatches.value_of("config")) {
-                Ok(config) => config,
-                Err(error) => {
-                    eprintln!("Failed to load configuration file: {}", error);
-
-                    return;
-                }
-            };
-
-            match Server::new(&config).mount_all() {
-                Ok(server) => {
-                    if let Err(error) = server.run() {
-                        eprintln!("{}", error);
-                    }
-                },
-                Err(error) => {
-                    eprintln!("Failed to create server: {}", error);
-
-                    return;
-                }
+            if submatches.is_present("generate-keys") {
+                generate_keys(submatches.value_of("config"));
+
+                return;
             }
+
+            run(submatches.value_of("config"));
+        }
+        ("migrate", Some(submatches)) => {
+            migrate(submatches.value_of("config"), submatches.is_present("dry-run"));
+        }
+        ("check-config", Some(submatches)) => match check_config(submatches.value_of("FILE")) {
+            Ok(effective_config) => println!("{}", effective_config),
+            Err(errors) => exit_with_config_errors(errors),
+        },
+        ("create-admin", Some(submatches)) => {
+            create_admin(
+                submatches.value_of("config"),
+                submatches.value_of("LOCALPART").unwrap_or(""),
+                submatches.value_of("password-file"),
+            );
         }
         ("secret", Some(_)) => match generate_macaroon_secret_key() {
             Ok(key) => println!("{}", key),
             Err(error) => eprintln!("Failed to generate macaroon secret key: {}", error),
         },
-        _ => println!("{}", matches.usage()),
+        _ => run(None),
+    };
+}
+
+/// The option naming the configuration file.
+fn config_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("config")
+        .short("c")
+        .long("config")
+        .value_name("PATH")
+        .help("Path to a configuration file")
+        .takes_value(true)
+}
+
+/// Runs the server until it is shut down.
+fn run(config_path: Option<&str>) {
+    let config = load_valid_config(config_path);
+
+    match Server::new(&config).mount_all() {
+        Ok(server) => {
+            if let Err(error) = server.run() {
+                eprintln!("{}", error);
+            }
+        },
+        Err(error) => {
+            eprintln!("Failed to create server: {}", error);
+
+            return;
+        }
+    }
+}
+
+/// Runs the pending migrations, or only lists them with `dry_run`.
+fn migrate(config_path: Option<&str>, dry_run: bool) {
+    let config = load_config(config_path);
+
+    let connection = match PgConnection::establish(&config.postgres_url) {
+        Ok(connection) => connection,
+        Err(error) => {
+            eprintln!("Cannot connect to PostgreSQL: {}", error);
+
+            process::exit(1);
+        }
     };
+
+    let migrations = match cli::migrate(&connection, dry_run) {
+        Ok(migrations) => migrations,
+        Err(error) => {
+            eprintln!("Failed to run the migrations: {}", error);
+
+            process::exit(1);
+        }
+    };
+
+    if migrations.is_empty() {
+        println!("No pending migrations.");
+    }
+
+    for version in migrations {
+        if dry_run {
+            println!("Pending migration {}", version);
+        } else {
+            println!("Ran migration {}", version);
+        }
+    }
+}
+
+/// Creates an admin with the password read from `password_file`, or asked for on the terminal.
+fn create_admin(config_path: Option<&str>, localpart: &str, password_file: Option<&str>) {
+    let config = load_config(config_path);
+
+    let password = match password_file {
+        Some(path) => read_password_file(path),
+        None => ask_for_new_password(),
+    };
+
+    let password = match password {
+        Ok(password) => password,
+        Err(error) => {
+            eprintln!("{}", error);
+
+            process::exit(1);
+        }
+    };
+
+    let connection = match PgConnection::establish(&config.postgres_url) {
+        Ok(connection) => connection,
+        Err(error) => {
+            eprintln!("Cannot connect to PostgreSQL: {}", error);
+
+            process::exit(1);
+        }
+    };
+
+    match cli::create_admin(&connection, &config.domain, localpart, &password) {
+        Ok(user) => println!("Created the admin {}", user.id),
+        Err(error) => {
+            eprintln!("Failed to create the admin: {}", error);
+
+            process::exit(1);
+        }
+    }
+}
+
+/// Asks for a new password twice on the terminal.
+fn ask_for_new_password() -> Result<String, CliError> {
+    let password = prompt_password("Password: ")?;
+
+    if prompt_password("Confirm password: ")? != password {
+        return Err(CliError::new("The passwords do not match."));
+    }
+
+    Ok(password)
+}
+
+/// Loads the configuration without validating it, exiting if it can't be loaded.
+fn load_config(config_path: Option<&str>) -> Config {
+    match Config::from_file(config_path) {
+        Ok(config) => config,
+        Err(errors) => exit_with_config_errors(errors),
+    }
 }
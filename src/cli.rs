@@ -0,0 +1,364 @@
+//! The subcommands of the `ruma` binary besides running the server.
+
+use std::io::{BufRead, Write, stdin, stdout};
+use std::mem;
+use std::process;
+
+use diesel::Connection;
+use diesel::migrations::setup_database;
+use diesel::pg::PgConnection;
+use serde_json::to_string_pretty;
+
+use config::Config;
+use crypto::hash_password;
+use embedded_migrations::run_with_output as run_pending_migrations_with_output;
+use error::{ApiError, CliError, ConfigError};
+use identifiers::new_user_id;
+use key_files::{generate_key_files, read_key_file};
+use models::profile::Profile;
+use models::user::{NewUser, User};
+
+/// What Diesel prints before running each migration, followed by its version.
+const RUNNING_MIGRATION: &'static str = "Running migration ";
+
+/// Runs the migrations that haven't been run in the database yet.
+///
+/// Returns the versions of the migrations, in the order they were run. With `dry_run`, the
+/// migrations are run in a transaction that is rolled back, so they are only listed.
+pub fn migrate(connection: &PgConnection, dry_run: bool) -> Result<Vec<String>, CliError> {
+    if !dry_run {
+        return run_migrations(connection);
+    }
+
+    let mut pending = Ok(Vec::new());
+
+    // The error only rolls the transaction back. The result of the migrations is kept outside.
+    let _ = connection.transaction::<(), CliError, _>(|| {
+        pending = run_migrations(connection);
+
+        Err(CliError::new("Rolling back the dry run."))
+    });
+
+    pending
+}
+
+/// Validates the configuration the server would be started with, and returns it as pretty JSON
+/// with the defaults filled in and secrets redacted.
+///
+/// Like `ruma run`, this checks that the server can listen on the configured addresses and
+/// connect to PostgreSQL.
+pub fn check_config(path: Option<&str>) -> Result<String, Vec<ConfigError>> {
+    let config = Config::from_file(path)?;
+
+    config.validate()?;
+
+    to_string_pretty(&config).map_err(|error| vec![ConfigError::new("config", error.to_string())])
+}
+
+/// Loads and validates the configuration the server is started with, exiting with the problems
+/// found if it is invalid.
+pub fn load_valid_config(path: Option<&str>) -> Config {
+    let config = Config::from_file(path).and_then(|config| config.validate().map(|_| config));
+
+    match config {
+        Ok(config) => config,
+        Err(errors) => exit_with_config_errors(errors),
+    }
+}
+
+/// Prints the problems found in the configuration and exits with a failure status.
+pub fn exit_with_config_errors(errors: Vec<ConfigError>) -> ! {
+    eprintln!("Invalid configuration:");
+
+    for error in errors {
+        eprintln!("  {}", error);
+    }
+
+    process::exit(1);
+}
+
+/// Creates the key files named by `macaroon_secret_key_file` and `signing_key_file` that don't
+/// exist yet, and prints the public signing key.
+///
+/// Exits with a failure status if the configuration is invalid or the keys can't be created.
+pub fn generate_keys(config_path: Option<&str>) {
+    let key_files = match Config::key_files(config_path) {
+        Ok(key_files) => key_files,
+        Err(errors) => exit_with_config_errors(errors),
+    };
+
+    if key_files.macaroon_secret_key_file.is_none() && key_files.signing_key_file.is_none() {
+        eprintln!("Neither macaroon_secret_key_file nor signing_key_file is set.");
+
+        process::exit(1);
+    }
+
+    let generated = match generate_key_files(&key_files) {
+        Ok(generated) => generated,
+        Err(error) => {
+            eprintln!("Failed to generate keys: {}", error);
+
+            process::exit(1);
+        }
+    };
+
+    for path in generated.created {
+        println!("Created {}", path);
+    }
+
+    if let Some(signing_key) = generated.signing_key {
+        println!("Public signing key {}: {}", signing_key.key_id(), signing_key.verify_key());
+    }
+}
+
+/// Creates a user who can use the admin APIs with the given localpart and password.
+///
+/// Like registration, the localpart is lowercased and must not be taken regardless of case. No
+/// access token is created, the admin logs in like everyone else.
+pub fn create_admin(connection: &PgConnection, domain: &str, localpart: &str, password: &str)
+-> Result<User, ApiError> {
+    let id = new_user_id("localpart", &localpart.to_lowercase(), domain)?;
+
+    if password.is_empty() {
+        return Err(ApiError::invalid_param("password", "must not be empty"));
+    }
+
+    if User::find_registered_user_ignoring_case(connection, &id)?.is_some() {
+        return Err(ApiError::user_in_use(format!("{} already exists.", id)));
+    }
+
+    let new_user = NewUser {
+        id: id,
+        password_hash: hash_password(password)?,
+        admin: true,
+    };
+
+    connection.transaction::<User, ApiError, _>(|| {
+        let user = User::create_without_access_token(connection, &new_user)?;
+
+        let new_profile = Profile {
+            id: user.id.clone(),
+            avatar_url: None,
+            displayname: None,
+        };
+
+        Profile::create(connection, &new_profile)?;
+
+        Ok(user)
+    })
+}
+
+/// Reads a password from a file that only its owner may read.
+///
+/// Surrounding whitespace, like a trailing newline, is ignored.
+pub fn read_password_file(path: &str) -> Result<String, CliError> {
+    read_key_file(path)
+}
+
+/// Asks for a password on the terminal without echoing it, or reads a line from the standard
+/// input if it isn't a terminal.
+pub fn prompt_password(prompt: &str) -> Result<String, CliError> {
+    print!("{}", prompt);
+    stdout().flush().map_err(CliError::from)?;
+
+    let mut password = String::new();
+
+    {
+        let _hidden = HiddenInput::new();
+
+        let stdin = stdin();
+        stdin.lock().read_line(&mut password).map_err(CliError::from)?;
+    }
+
+    println!();
+
+    Ok(password.trim_right_matches(|c| c == '\n' || c == '\r').to_string())
+}
+
+/// Runs the pending migrations and collects their versions from Diesel's output.
+fn run_migrations(connection: &PgConnection) -> Result<Vec<String>, CliError> {
+    let mut output = Vec::new();
+
+    setup_database(connection).map_err(CliError::from)?;
+    run_pending_migrations_with_output(connection, &mut output).map_err(CliError::from)?;
+
+    let output = String::from_utf8(output).map_err(CliError::from)?;
+
+    Ok(output.lines()
+        .filter(|line| line.starts_with(RUNNING_MIGRATION))
+        .map(|line| line[RUNNING_MIGRATION.len()..].trim().to_string())
+        .collect())
+}
+
+/// Turns off the echo of the terminal on the standard input while it is alive.
+struct HiddenInput {
+    /// The settings of the terminal to restore, if the standard input is one.
+    original: Option<::libc::termios>,
+}
+
+impl HiddenInput {
+    fn new() -> Self {
+        unsafe {
+            if ::libc::isatty(::libc::STDIN_FILENO) != 1 {
+                return HiddenInput { original: None };
+            }
+
+            let mut termios: ::libc::termios = mem::zeroed();
+
+            if ::libc::tcgetattr(::libc::STDIN_FILENO, &mut termios) != 0 {
+                return HiddenInput { original: None };
+            }
+
+            let original = termios;
+
+            termios.c_lflag &= !::libc::ECHO;
+            ::libc::tcsetattr(::libc::STDIN_FILENO, ::libc::TCSANOW, &termios);
+
+            HiddenInput { original: Some(original) }
+        }
+    }
+}
+
+impl Drop for HiddenInput {
+    fn drop(&mut self) {
+        if let Some(ref original) = self.original {
+            unsafe {
+                ::libc::tcsetattr(::libc::STDIN_FILENO, ::libc::TCSANOW, original);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{File, read_dir};
+    use std::io::Write;
+
+    use diesel::Connection;
+    use diesel::pg::PgConnection;
+    use iron::status::Status;
+
+    use test::{TempDir, Test, TestDatabase};
+    use super::{check_config, create_admin, migrate};
+
+    #[test]
+    fn migrate_runs_pending_migrations_once() {
+        let database = TestDatabase::empty();
+        let connection = PgConnection::establish(&database.url()).unwrap();
+
+        // Every migration of the source tree is pending, in order.
+        let mut migrations: Vec<String> =
+            read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+                .unwrap()
+                .map(|entry| {
+                    let name = entry.unwrap().file_name().into_string().unwrap();
+
+                    name.split('_').next().unwrap().to_string()
+                })
+                .collect();
+        migrations.sort();
+
+        let pending = migrate(&connection, true).unwrap();
+        assert_eq!(pending, migrations);
+
+        // The dry run changed nothing.
+        assert_eq!(migrate(&connection, true).unwrap(), pending);
+
+        assert_eq!(migrate(&connection, false).unwrap(), pending);
+        assert!(migrate(&connection, true).unwrap().is_empty());
+        assert!(migrate(&connection, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_databases_have_no_pending_migrations() {
+        let test = Test::new();
+
+        assert!(migrate(&test.connection(), true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn check_config_redacts_secrets() {
+        let test = Test::new();
+        let dir = TempDir::new();
+        let path = dir.file("ruma.json");
+
+        let mut file = File::create(&path).unwrap();
+        write!(
+            file,
+            r#"{{
+                "version": "1",
+                "domain": "ruma.test",
+                "bind_port": "0",
+                "macaroon_secret_key": "YymznUuUm1dWl9BkiFhWhMtOSfhyKsWWvJTKCFOhDoM=",
+                "postgres_url": "{}",
+                "trusted_proxies": ["10.0.0.0/8"]
+            }}"#,
+            test.database_url()
+        ).unwrap();
+
+        let effective_config = check_config(Some(&path)).unwrap();
+
+        assert!(effective_config.contains(r#""macaroon_secret_key": "<redacted>""#));
+        assert!(effective_config.contains(":redacted@"));
+        assert!(!effective_config.contains(":test@"));
+        assert!(effective_config.contains(r#""signing_key": null"#));
+        assert!(effective_config.contains(r#""max_pagination_limit": 1000"#));
+        assert!(effective_config.contains(r#""10.0.0.0/8""#));
+    }
+
+    #[test]
+    fn check_config_reports_invalid_configurations() {
+        let dir = TempDir::new();
+        let path = dir.file("ruma.json");
+
+        File::create(&path).unwrap().write_all(br#"{"version": "1", "domain": "ruma.test"}"#)
+            .unwrap();
+
+        let errors: Vec<String> = check_config(Some(&path)).err().unwrap()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec!["postgres_url: Must be set.", "macaroon_secret_key: Must be set."]
+        );
+    }
+
+    #[test]
+    fn created_admins_can_log_in_and_use_the_admin_apis() {
+        let test = Test::new();
+
+        {
+            let connection = test.connection();
+            let admin = create_admin(&connection, "ruma.test", "Root", "correct horse").unwrap();
+
+            assert_eq!(admin.id.to_string(), "@root:ruma.test");
+            assert!(admin.admin);
+        }
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "root", "password": "correct horse"}"#,
+        );
+        assert_eq!(response.status, Status::Ok, "{}", response.body);
+
+        let access_token = response.json().get("access_token").unwrap().as_str().unwrap();
+
+        let response = test.get(
+            &format!("/_matrix/admin/v1/metrics?access_token={}", access_token)
+        );
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn create_admin_refuses_taken_user_ids() {
+        let test = Test::new();
+        test.register("alice");
+
+        let connection = test.connection();
+        let error = create_admin(&connection, "ruma.test", "ALICE", "secret").err().unwrap();
+
+        assert_eq!(error.to_string(), "@alice:ruma.test already exists.");
+    }
+}
@@ -0,0 +1,105 @@
+//! The source of the current time.
+//!
+//! Anything that depends on how much time has passed, like cache expiry, presence or retention,
+//! reads the time from the server's `Clock` instead of the system. Tests use a `MockClock` and
+//! advance it instead of sleeping.
+
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read as PersistentRead;
+
+use error::ApiError;
+
+/// Tells the current time.
+pub trait Clock: Debug + Send + Sync {
+    /// The current time.
+    fn now(&self) -> SystemTime;
+
+    /// The current time in milliseconds since the Unix epoch.
+    fn now_ms(&self) -> u64 {
+        let since_epoch = self.now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+
+        since_epoch.as_secs() * 1000 + (since_epoch.subsec_nanos() / 1_000_000) as u64
+    }
+}
+
+/// The clock of the system, used outside of tests.
+#[derive(Debug)]
+pub struct SystemClock;
+
+/// A clock that stands still until it is advanced.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<SystemTime>,
+}
+
+/// The server's `Clock`, stored in the request's extensions.
+pub struct ServerClock;
+
+impl Key for ServerClock {
+    type Value = Arc<Clock>;
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+impl MockClock {
+    /// Creates a new `MockClock` set to the current time of the system.
+    pub fn new() -> Self {
+        MockClock {
+            now: Mutex::new(SystemTime::now()),
+        }
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = match self.now.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        *now = *now + duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        match self.now.lock() {
+            Ok(guard) => *guard,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+}
+
+/// Extract the server's `Clock` stored in the request.
+pub fn from_request(request: &mut Request) -> Result<Arc<Clock>, ApiError> {
+    request.get::<PersistentRead<ServerClock>>()
+        .map(|clock| (*clock).clone())
+        .map_err(ApiError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Clock, MockClock};
+
+    #[test]
+    fn mock_clock_only_moves_when_advanced() {
+        let clock = MockClock::new();
+        let start = clock.now_ms();
+
+        assert_eq!(clock.now_ms(), start);
+
+        clock.advance(Duration::from_secs(2));
+
+        assert_eq!(clock.now_ms(), start + 2000);
+    }
+}
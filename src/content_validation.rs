@@ -0,0 +1,332 @@
+//! Validation of the content of events clients send.
+//!
+//! Deserializing into the `ruma_events` types catches most malformed content, but not all of it
+//! is deserialized before it is stored, and other clients can break on what slips through. The
+//! well-known event types are therefore checked here first: structurally invalid content is
+//! rejected with `M_BAD_JSON`, and numbers that must be integers are normalized or rejected.
+//! `m.room.create` events of room versions this server doesn't support are rejected with
+//! `M_UNSUPPORTED_ROOM_VERSION`.
+//! Content of other event types is passed through untouched.
+
+use ruma_events::EventType;
+use serde_json::{Map, Value};
+
+use error::ApiError;
+use models::group::{RELATED_GROUPS_EVENT_TYPE, is_valid_group_id};
+use room_version;
+
+/// The `join_rule`s of the spec.
+const JOIN_RULES: [&'static str; 4] = ["public", "knock", "invite", "private"];
+
+/// The `history_visibility` settings of the spec.
+const HISTORY_VISIBILITIES: [&'static str; 4] = ["invited", "joined", "shared", "world_readable"];
+
+/// The `membership` states of the spec.
+const MEMBERSHIPS: [&'static str; 5] = ["invite", "join", "knock", "leave", "ban"];
+
+/// The power levels required for actions, directly in the content of `m.room.power_levels`.
+const POWER_LEVEL_FIELDS: [&'static str; 7] = [
+    "ban",
+    "events_default",
+    "invite",
+    "kick",
+    "redact",
+    "state_default",
+    "users_default",
+];
+
+/// The maps of power levels in the content of `m.room.power_levels`.
+const POWER_LEVEL_MAPS: [&'static str; 3] = ["events", "notifications", "users"];
+
+/// Validates the content of an event of the given type, returning the content to store.
+pub fn validate(event_type: &EventType, content: Value) -> Result<Value, ApiError> {
+    if !is_well_known(event_type) {
+        return Ok(content);
+    }
+
+    let mut content = match content {
+        Value::Object(content) => content,
+        _ => return Err(ApiError::bad_json("Event content must be a JSON object.".to_string())),
+    };
+
+    match *event_type {
+        EventType::RoomAvatar => {
+            required_string(&content, "url")?;
+            optional_object(&content, "info")?;
+        }
+        EventType::RoomCanonicalAlias => {
+            required_string(&content, "alias")?;
+            optional_string_array(&content, "alt_aliases")?;
+        }
+        EventType::RoomCreate => validate_room_version(&content)?,
+        EventType::RoomHistoryVisibility => {
+            required_enum(&content, "history_visibility", &HISTORY_VISIBILITIES)?;
+        }
+        EventType::RoomJoinRules => required_enum(&content, "join_rule", &JOIN_RULES)?,
+        EventType::RoomMember => {
+            required_enum(&content, "membership", &MEMBERSHIPS)?;
+            optional_string(&content, "avatar_url")?;
+            optional_string(&content, "displayname")?;
+        }
+        EventType::RoomMessage => {
+            required_string(&content, "msgtype")?;
+            required_string(&content, "body")?;
+        }
+        EventType::RoomName => required_string(&content, "name")?,
+        EventType::RoomPowerLevels => validate_power_levels(&mut content)?,
+        EventType::RoomTopic => required_string(&content, "topic")?,
+        EventType::Custom(ref event_type) if event_type == RELATED_GROUPS_EVENT_TYPE => {
+            validate_related_groups(&content)?;
+        }
+        _ => {}
+    }
+
+    Ok(Value::Object(content))
+}
+
+/// Whether or not the content of events of the type is validated.
+fn is_well_known(event_type: &EventType) -> bool {
+    match *event_type {
+        EventType::RoomAvatar |
+        EventType::RoomCanonicalAlias |
+        EventType::RoomCreate |
+        EventType::RoomHistoryVisibility |
+        EventType::RoomJoinRules |
+        EventType::RoomMember |
+        EventType::RoomMessage |
+        EventType::RoomName |
+        EventType::RoomPowerLevels |
+        EventType::RoomTopic => true,
+        EventType::Custom(ref event_type) => event_type == RELATED_GROUPS_EVENT_TYPE,
+        _ => false,
+    }
+}
+
+/// Checks that all power levels are integers, normalizing the ones sent as integral floats.
+fn validate_power_levels(content: &mut Map<String, Value>) -> Result<(), ApiError> {
+    for field in &POWER_LEVEL_FIELDS {
+        if let Some(level) = content.get_mut(*field) {
+            *level = power_level(field, level)?;
+        }
+    }
+
+    for field in &POWER_LEVEL_MAPS {
+        let levels = match content.get_mut(*field) {
+            Some(&mut Value::Object(ref mut levels)) => levels,
+            Some(_) => return Err(invalid_field(field, "must be an object")),
+            None => continue,
+        };
+
+        for (key, level) in levels.iter_mut() {
+            *level = power_level(&format!("{}.{}", field, key), level)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the `room_version` is supported, if there is one.
+fn validate_room_version(content: &Map<String, Value>) -> Result<(), ApiError> {
+    optional_string(content, "room_version")?;
+
+    match content.get("room_version").and_then(Value::as_str) {
+        Some(version) => room_version::check(version),
+        None => Ok(()),
+    }
+}
+
+/// Checks that `groups` is a list of group IDs.
+fn validate_related_groups(content: &Map<String, Value>) -> Result<(), ApiError> {
+    let groups = match content.get("groups") {
+        Some(&Value::Array(ref groups)) => groups,
+        Some(_) => return Err(invalid_field("groups", "must be an array of group IDs")),
+        None => return Err(ApiError::bad_json("Event content is missing `groups`.".to_string())),
+    };
+
+    for group in groups {
+        match group.as_str() {
+            Some(group_id) if is_valid_group_id(group_id) => {}
+            _ => {
+                let message = format!("contains the invalid group ID {}", group);
+
+                return Err(invalid_field("groups", &message));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the power level as an integer, if it is one.
+fn power_level(field: &str, level: &Value) -> Result<Value, ApiError> {
+    if let Some(level) = level.as_i64() {
+        return Ok(Value::from(level));
+    }
+
+    match level.as_f64() {
+        Some(level) if level.fract() == 0.0 && level.abs() < 1e15 => Ok(Value::from(level as i64)),
+        _ => Err(invalid_field(field, "must be an integer")),
+    }
+}
+
+fn required_string(content: &Map<String, Value>, field: &str) -> Result<(), ApiError> {
+    match content.get(field) {
+        Some(&Value::String(_)) => Ok(()),
+        Some(_) => Err(invalid_field(field, "must be a string")),
+        None => Err(ApiError::bad_json(format!("Event content is missing `{}`.", field))),
+    }
+}
+
+fn optional_string(content: &Map<String, Value>, field: &str) -> Result<(), ApiError> {
+    match content.get(field) {
+        Some(&Value::String(_)) | Some(&Value::Null) | None => Ok(()),
+        Some(_) => Err(invalid_field(field, "must be a string")),
+    }
+}
+
+fn optional_object(content: &Map<String, Value>, field: &str) -> Result<(), ApiError> {
+    match content.get(field) {
+        Some(&Value::Object(_)) | None => Ok(()),
+        Some(_) => Err(invalid_field(field, "must be an object")),
+    }
+}
+
+fn optional_string_array(content: &Map<String, Value>, field: &str) -> Result<(), ApiError> {
+    match content.get(field) {
+        Some(&Value::Array(ref values)) if values.iter().all(Value::is_string) => Ok(()),
+        Some(_) => Err(invalid_field(field, "must be an array of strings")),
+        None => Ok(()),
+    }
+}
+
+fn required_enum(content: &Map<String, Value>, field: &str, allowed: &[&str])
+-> Result<(), ApiError> {
+    required_string(content, field)?;
+
+    match content.get(field).and_then(Value::as_str) {
+        Some(value) if allowed.contains(&value) => Ok(()),
+        _ => Err(invalid_field(field, &format!("must be one of: {}", allowed.join(", ")))),
+    }
+}
+
+fn invalid_field(field: &str, message: &str) -> ApiError {
+    ApiError::bad_json(format!("Event content field `{}` {}.", field, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma_events::EventType;
+    use serde_json::{Value, from_str, to_value};
+
+    use super::validate;
+
+    /// Validates the content, returning the error code if it is invalid.
+    fn check(event_type: EventType, content: &str) -> Result<Value, String> {
+        validate(&event_type, from_str(content).unwrap()).map_err(|error| {
+            to_value(&error).unwrap().get("errcode").unwrap().as_str().unwrap().to_string()
+        })
+    }
+
+    #[test]
+    fn content_must_be_an_object() {
+        assert_eq!(check(EventType::RoomMessage, r#""Hi""#), Err("M_BAD_JSON".to_string()));
+        assert!(check(EventType::RoomName, "[]").is_err());
+    }
+
+    #[test]
+    fn message() {
+        assert!(check(EventType::RoomMessage, r#"{"msgtype": "m.text", "body": "Hi"}"#).is_ok());
+        assert!(check(EventType::RoomMessage, r#"{"msgtype": "m.text"}"#).is_err());
+        assert!(check(EventType::RoomMessage, r#"{"body": "Hi"}"#).is_err());
+        assert!(check(EventType::RoomMessage, r#"{"msgtype": 1, "body": "Hi"}"#).is_err());
+    }
+
+    #[test]
+    fn name_topic_and_avatar() {
+        assert!(check(EventType::RoomName, r#"{"name": ""}"#).is_ok());
+        assert!(check(EventType::RoomName, r#"{"name": null}"#).is_err());
+        assert!(check(EventType::RoomTopic, r#"{"topic": "Ruma"}"#).is_ok());
+        assert!(check(EventType::RoomTopic, r#"{"topic": ["Ruma"]}"#).is_err());
+        assert!(check(EventType::RoomAvatar, r#"{"url": "mxc://ruma.test/a"}"#).is_ok());
+        assert!(check(EventType::RoomAvatar, r#"{"url": "mxc://ruma.test/a", "info": 1}"#)
+            .is_err());
+    }
+
+    #[test]
+    fn power_levels() {
+        let content = check(
+            EventType::RoomPowerLevels,
+            r#"{"ban": 50.0, "users": {"@alice:ruma.test": 100}, "events": {}}"#,
+        ).unwrap();
+
+        assert_eq!(content.get("ban").unwrap(), &Value::from(50));
+        assert!(content.get("ban").unwrap().is_i64());
+
+        assert_eq!(
+            check(EventType::RoomPowerLevels, r#"{"ban": "50"}"#),
+            Err("M_BAD_JSON".to_string())
+        );
+        assert!(check(EventType::RoomPowerLevels, r#"{"kick": 12.5}"#).is_err());
+        assert!(check(EventType::RoomPowerLevels, r#"{"users": {"@alice:ruma.test": "100"}}"#)
+            .is_err());
+        assert!(check(EventType::RoomPowerLevels, r#"{"events": []}"#).is_err());
+    }
+
+    #[test]
+    fn join_rules_and_history_visibility() {
+        assert!(check(EventType::RoomJoinRules, r#"{"join_rule": "invite"}"#).is_ok());
+        assert!(check(EventType::RoomJoinRules, r#"{"join_rule": "everyone"}"#).is_err());
+        assert!(check(EventType::RoomHistoryVisibility, r#"{"history_visibility": "shared"}"#)
+            .is_ok());
+        assert!(check(EventType::RoomHistoryVisibility, r#"{}"#).is_err());
+    }
+
+    #[test]
+    fn canonical_alias() {
+        assert!(check(EventType::RoomCanonicalAlias, r##"{"alias": "#a:ruma.test"}"##).is_ok());
+        assert!(check(EventType::RoomCanonicalAlias, r#"{"alias": 1}"#).is_err());
+        assert!(
+            check(EventType::RoomCanonicalAlias, r##"{"alias": "", "alt_aliases": [1]}"##).is_err()
+        );
+    }
+
+    #[test]
+    fn member() {
+        assert!(check(EventType::RoomMember, r#"{"membership": "join", "displayname": null}"#)
+            .is_ok());
+        assert!(check(EventType::RoomMember, r#"{"membership": "joined"}"#).is_err());
+        assert!(check(EventType::RoomMember, r#"{"membership": "join", "avatar_url": 1}"#)
+            .is_err());
+    }
+
+    #[test]
+    fn related_groups() {
+        let related_groups = || EventType::Custom("m.room.related_groups".to_string());
+
+        assert!(check(related_groups(), r#"{"groups": ["+ruma:ruma.test"]}"#).is_ok());
+        assert!(check(related_groups(), r#"{"groups": []}"#).is_ok());
+        assert!(check(related_groups(), r#"{"groups": ["ruma:ruma.test"]}"#).is_err());
+        assert!(check(related_groups(), r#"{"groups": [1]}"#).is_err());
+        assert!(check(related_groups(), r#"{"groups": "+ruma:ruma.test"}"#).is_err());
+    }
+
+    #[test]
+    fn create() {
+        assert!(check(EventType::RoomCreate, r#"{"creator": "@alice:ruma.test"}"#).is_ok());
+        assert!(check(EventType::RoomCreate, r#"{"room_version": "1"}"#).is_ok());
+        assert_eq!(
+            check(EventType::RoomCreate, r#"{"room_version": "999"}"#),
+            Err("M_UNSUPPORTED_ROOM_VERSION".to_string())
+        );
+        assert!(check(EventType::RoomCreate, r#"{"room_version": 1}"#).is_err());
+    }
+
+    #[test]
+    fn unknown_event_types_pass_through() {
+        let custom = || EventType::Custom("org.example.custom".to_string());
+        let content = r#"{"ban": "50", "body": 1}"#;
+
+        assert_eq!(check(custom(), content).unwrap(), from_str::<Value>(content).unwrap());
+        assert_eq!(check(custom(), "[]").unwrap(), Value::Array(Vec::new()));
+    }
+}
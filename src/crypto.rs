@@ -1,11 +1,81 @@
 //! Cryptographic operations.
 
 use argon2rs::verifier::Encoded;
-use base64::encode;
+use base64::{decode, encode};
 use rand::{OsRng, Rng};
+use ring::rand::SystemRandom;
+use ring::signature::Ed25519KeyPair as RingEd25519KeyPair;
+use ruma_signatures::Ed25519KeyPair;
 
 use error::{ApiError, CliError};
 
+/// The Ed25519 key the server signs federation requests and events with.
+#[derive(Clone)]
+pub struct SigningKey {
+    /// The version of the key, used in the key ID `ed25519:{version}`.
+    pub version: String,
+    /// The 32-byte private key.
+    pub private_key: Vec<u8>,
+    /// The 32-byte public key.
+    pub public_key: Vec<u8>,
+}
+
+impl SigningKey {
+    /// Decodes a signing key from the Base64 encoding of the private key followed by the public
+    /// key.
+    pub fn from_base64(version: &str, encoded: &str) -> Result<SigningKey, CliError> {
+        let bytes = decode(encoded).map_err(|_| CliError::new("signing_key must be valid Base64."))?;
+
+        if bytes.len() != 64 {
+            return Err(CliError::new("signing_key must be 64 bytes."));
+        }
+
+        let (private_key, public_key) = bytes.split_at(32);
+
+        Ok(SigningKey {
+            version: version.to_string(),
+            private_key: private_key.to_vec(),
+            public_key: public_key.to_vec(),
+        })
+    }
+
+    /// Generates a new random signing key with the given version.
+    pub fn generate(version: &str) -> Result<SigningKey, CliError> {
+        let (_, bytes) = RingEd25519KeyPair::generate_serializable(&SystemRandom::new())
+            .map_err(|_| CliError::new("Failed to generate a signing key."))?;
+
+        Ok(SigningKey {
+            version: version.to_string(),
+            private_key: bytes.private_key.to_vec(),
+            public_key: bytes.public_key.to_vec(),
+        })
+    }
+
+    /// Encodes the key as `from_base64` decodes it.
+    pub fn to_base64(&self) -> String {
+        let mut bytes = self.private_key.clone();
+        bytes.extend_from_slice(&self.public_key);
+
+        encode(&bytes)
+    }
+
+    /// The ID of the key, `ed25519:{version}`.
+    pub fn key_id(&self) -> String {
+        format!("ed25519:{}", self.version)
+    }
+
+    /// The public key as unpadded Base64, the way other servers are told about it.
+    pub fn verify_key(&self) -> String {
+        encode(&self.public_key).trim_right_matches('=').to_string()
+    }
+
+    /// Creates the key pair used for signing.
+    pub fn key_pair(&self) -> Result<Ed25519KeyPair, ApiError> {
+        Ed25519KeyPair::new(&self.public_key, &self.private_key, self.version.clone())
+            .map_err(ApiError::from)
+    }
+}
+
 /// Generates a random 32-byte secret key for macaroons.
 pub fn generate_macaroon_secret_key() -> Result<String, CliError> {
     let mut rng = OsRng::new()?;
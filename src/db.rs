@@ -1,17 +1,61 @@
 //! Database-related functionality.
 
+use std::ops::Deref;
+use std::rc::Rc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use diesel::Connection;
+use diesel::connection::TransactionManager;
 use diesel::pg::PgConnection;
 use iron::{Plugin, Request};
 use iron::typemap::Key;
-use persistent::Write;
-use r2d2::{Config as R2D2Config, InitializationError, Pool, PooledConnection};
+use persistent::Read;
+use r2d2::{Config as R2D2Config, CustomizeConnection, InitializationError, Pool, PooledConnection};
 use r2d2_diesel::{ConnectionManager, Error as R2D2DieselError};
+use rand::{Rng, thread_rng};
 
+use config::Config;
 use error::ApiError;
 
+/// How many times `transaction_with_retry` runs a transaction before giving up.
+const TRANSACTION_ATTEMPTS: u32 = 3;
+
+/// The delay before the first retry of a transaction, in milliseconds. It doubles with every
+/// further retry, and up to as much again is added at random so that the transactions that
+/// conflicted don't retry in lockstep.
+const TRANSACTION_RETRY_DELAY_MS: u64 = 20;
+
 /// An Iron plugin for attaching a database connection pool to an Iron request.
 pub struct DB;
 
+/// A database connection used by a request.
+pub enum RequestConnection {
+    /// A connection of its own, taken from the pool.
+    Pooled(PooledConnection<ConnectionManager<PgConnection>>),
+    /// The connection of a transaction spanning the whole request, see `SharedConnection`.
+    Shared(Rc<PooledConnection<ConnectionManager<PgConnection>>>),
+}
+
+/// A connection stored in the request's extensions, which `DB::from_request` hands out instead
+/// of taking one from the pool.
+///
+/// Middleware that needs the handler's queries to be part of its own transaction opens the
+/// transaction on this connection before running the handler.
+pub struct SharedConnection;
+
+/// Sets PostgreSQL's `statement_timeout`, in milliseconds, on every new connection in the pool.
+#[derive(Debug)]
+pub struct StatementTimeout(pub u64);
+
+impl CustomizeConnection<PgConnection, R2D2DieselError> for StatementTimeout {
+    fn on_acquire(&self, connection: &mut PgConnection) -> Result<(), R2D2DieselError> {
+        connection.execute(&format!("SET statement_timeout = {}", self.0))
+            .map(|_| ())
+            .map_err(R2D2DieselError::QueryError)
+    }
+}
+
 impl DB {
     /// Creates a connection pool for the PostgreSQL database at the given URL.
     pub fn create_connection_pool(
@@ -23,16 +67,297 @@ impl DB {
         Pool::new(r2d2_config, connection_manager)
     }
 
+    /// Creates the connection pool configuration for the given server `Config`.
+    pub fn r2d2_config(config: &Config) -> R2D2Config<PgConnection, R2D2DieselError> {
+        let builder = R2D2Config::builder()
+            .pool_size(config.database_pool_size)
+            .connection_timeout(Duration::from_millis(config.database_connection_timeout));
+
+        match config.database_statement_timeout {
+            Some(statement_timeout) => {
+                builder.connection_customizer(Box::new(StatementTimeout(statement_timeout))).build()
+            }
+            None => builder.build(),
+        }
+    }
+
     /// Extract a database conection from the pool stored in the request.
-    pub fn from_request(request: &mut Request)
-        -> Result<PooledConnection<ConnectionManager<PgConnection>>, ApiError>
+    ///
+    /// Waits for a connection to be returned to the pool if all of them are in use, and fails
+    /// with 503 Service Unavailable if none is returned in time. If the request has a
+    /// `SharedConnection`, that connection is returned instead.
+    pub fn from_request(request: &mut Request) -> Result<RequestConnection, ApiError> {
+        if let Some(connection) = request.extensions.get::<SharedConnection>() {
+            return Ok(RequestConnection::Shared(connection.clone()));
+        }
+
+        let pool = request.get::<Read<DB>>().map_err(ApiError::from)?;
+        pool.get().map(RequestConnection::Pooled).map_err(ApiError::from)
+    }
+
+    /// Extract the connection pool stored in the request.
+    ///
+    /// Useful for handlers that must not hold on to a connection while waiting.
+    pub fn pool_from_request(request: &mut Request)
+        -> Result<Pool<ConnectionManager<PgConnection>>, ApiError>
     {
-        let mutex = request.get::<Write<DB>>().map_err(ApiError::from)?;
-        let pool = mutex.lock().map_err(ApiError::from)?;
-        pool.get().map_err(ApiError::from)
+        let pool = request.get::<Read<DB>>().map_err(ApiError::from)?;
+        Ok((*pool).clone())
     }
 }
 
 impl Key for DB {
     type Value = Pool<ConnectionManager<PgConnection>>;
 }
+
+impl Key for SharedConnection {
+    type Value = Rc<PooledConnection<ConnectionManager<PgConnection>>>;
+}
+
+impl Deref for RequestConnection {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &PgConnection {
+        match *self {
+            RequestConnection::Pooled(ref connection) => connection,
+            RequestConnection::Shared(ref connection) => connection,
+        }
+    }
+}
+
+/// Runs `f` in a transaction, running it again if the transaction failed to serialize with a
+/// concurrent one or was aborted to resolve a deadlock.
+///
+/// `f` may be called several times, so it must not have any effects outside of the transaction.
+/// If it still fails after `TRANSACTION_ATTEMPTS` attempts, its `M_UNAVAILABLE` error is returned,
+/// telling the client when to retry. Transactions nested in another one are not retried, since
+/// PostgreSQL has already aborted the enclosing transaction, which has to be retried instead.
+pub fn transaction_with_retry<T, F>(connection: &PgConnection, f: F) -> Result<T, ApiError>
+where F: Fn() -> Result<T, ApiError> {
+    let is_nested = connection.transaction_manager().get_transaction_depth() > 0;
+    let mut attempt = 1;
+
+    loop {
+        match connection.transaction::<T, ApiError, _>(&f) {
+            Err(ref error)
+            if error.is_retryable() && !is_nested && attempt < TRANSACTION_ATTEMPTS => {
+                let delay = TRANSACTION_RETRY_DELAY_MS << (attempt - 1);
+                let delay = delay + thread_rng().gen_range(0, delay + 1);
+
+                debug!("Retrying transaction in {}ms after attempt {}: {}", delay, attempt, error);
+
+                sleep(Duration::from_millis(delay));
+            }
+            result => return result,
+        }
+
+        attempt += 1;
+    }
+}
+
+/// Runs `f` like `transaction_with_retry`, but on a single snapshot of the database.
+///
+/// Every statement of `f` sees the changes committed before the transaction started, and none
+/// committed while it runs. A transaction nested in another one shares its isolation level.
+pub fn snapshot_with_retry<T, F>(connection: &PgConnection, f: F) -> Result<T, ApiError>
+where F: Fn() -> Result<T, ApiError> {
+    let is_nested = connection.transaction_manager().get_transaction_depth() > 0;
+
+    transaction_with_retry(connection, || {
+        if !is_nested {
+            connection.execute("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+                .map_err(ApiError::from)?;
+        }
+
+        f()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::sync::{Arc, Barrier};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use diesel::{Connection, LoadDsl};
+    use diesel::expression::dsl::sql;
+    use diesel::pg::PgConnection;
+    use diesel::types::Text;
+    use iron::status::Status;
+    use rand::{Rng, thread_rng};
+
+    use error::ApiError;
+    use test::{Test, captured_logs};
+    use super::{snapshot_with_retry, transaction_with_retry};
+
+    #[test]
+    fn requests_wait_for_a_free_connection() {
+        let test = Test::with_config(|config| config.database_connection_timeout = 5000);
+        let carl = test.create_user();
+
+        // The test pool only has one connection, so the request can't run until it's returned.
+        let connection = test.connection();
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            drop(connection);
+        });
+
+        let response = test.get(&format!("/_matrix/client/r0/account/3pid?access_token={}", carl.token));
+
+        assert_eq!(response.status, Status::Ok);
+
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn exhausted_pool_returns_service_unavailable() {
+        let test = Test::with_config(|config| config.database_connection_timeout = 100);
+        let carl = test.create_user();
+
+        let connection = test.connection();
+
+        let response = test.get(&format!("/_matrix/client/r0/account/3pid?access_token={}", carl.token));
+
+        assert_eq!(response.status, Status::ServiceUnavailable);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_UNKNOWN");
+        assert!(captured_logs("ruma::error").iter().any(|line| {
+            line.contains("Timed out waiting for a database connection")
+        }));
+
+        drop(connection);
+    }
+
+    #[test]
+    fn deadlocked_transactions_are_retried() {
+        let test = Test::new();
+        let postgres_url = test.database_url();
+
+        // The test connections never commit, so two connections of their own take two advisory
+        // locks in opposite orders, which deadlocks like two conflicting membership updates.
+        let (first_lock, second_lock) = (thread_rng().gen::<i64>(), thread_rng().gen::<i64>());
+        let barrier = Arc::new(Barrier::new(2));
+        let attempts = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = vec![(first_lock, second_lock), (second_lock, first_lock)]
+            .into_iter()
+            .map(|(lock, other_lock)| {
+                let postgres_url = postgres_url.clone();
+                let barrier = barrier.clone();
+                let attempts = attempts.clone();
+
+                thread::spawn(move || {
+                    let connection = PgConnection::establish(&postgres_url).unwrap();
+                    let is_first_attempt = Cell::new(true);
+
+                    transaction_with_retry(&connection, || {
+                        attempts.fetch_add(1, Ordering::SeqCst);
+
+                        connection.execute(&format!("SELECT pg_advisory_xact_lock({})", lock))
+                            .map_err(ApiError::from)?;
+
+                        // Only wait for the other thread to hold its lock the first time, so the
+                        // retry of the transaction that was aborted doesn't wait forever.
+                        if is_first_attempt.get() {
+                            is_first_attempt.set(false);
+                            barrier.wait();
+                        }
+
+                        connection.execute(&format!("SELECT pg_advisory_xact_lock({})", other_lock))
+                            .map_err(ApiError::from)?;
+
+                        Ok(())
+                    })
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            assert!(handle.join().unwrap().is_ok());
+        }
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn snapshot_transactions_are_repeatable_read() {
+        let test = Test::new();
+        let connection = PgConnection::establish(&test.database_url()).unwrap();
+
+        let isolation_level = || {
+            sql::<Text>("SELECT current_setting('transaction_isolation')")
+                .get_result::<String>(&connection)
+                .map_err(ApiError::from)
+        };
+
+        assert_eq!(snapshot_with_retry(&connection, &isolation_level).unwrap(), "repeatable read");
+        let isolation = transaction_with_retry(&connection, &isolation_level).unwrap();
+        assert_eq!(isolation, "read committed");
+
+        // A nested snapshot can't change the isolation level, and doesn't try to.
+        let nested = transaction_with_retry(&connection, || {
+            snapshot_with_retry(&connection, &isolation_level)
+        });
+
+        assert_eq!(nested.unwrap(), "read committed");
+    }
+
+    #[test]
+    fn hot_queries_use_indexes() {
+        let test = Test::new();
+
+        test.assert_no_seq_scan(
+            "access_tokens",
+            "SELECT * FROM access_tokens WHERE value = 'token' AND revoked = false",
+        );
+        test.assert_no_seq_scan(
+            "access_tokens",
+            "SELECT * FROM access_tokens WHERE user_id = '@carl:ruma.test' AND revoked = false",
+        );
+        test.assert_no_seq_scan(
+            "room_aliases",
+            "SELECT * FROM room_aliases WHERE alias = '#alias:ruma.test'",
+        );
+        test.assert_no_seq_scan(
+            "room_aliases",
+            "SELECT * FROM room_aliases WHERE room_id = '!room:ruma.test'",
+        );
+        test.assert_no_seq_scan(
+            "room_memberships",
+            "SELECT * FROM room_memberships \
+            WHERE room_id = '!room:ruma.test' AND user_id = '@carl:ruma.test'",
+        );
+        test.assert_no_seq_scan(
+            "room_memberships",
+            "SELECT * FROM room_memberships WHERE user_id = '@carl:ruma.test' AND membership = 'join'",
+        );
+        test.assert_no_seq_scan(
+            "events",
+            "SELECT * FROM events WHERE room_id = '!room:ruma.test' AND ordering > 10 ORDER BY ordering",
+        );
+        test.assert_no_seq_scan(
+            "events",
+            "SELECT id FROM events WHERE room_id = '!room:ruma.test' ORDER BY ordering DESC LIMIT 1",
+        );
+        test.assert_no_seq_scan(
+            "events",
+            "SELECT * FROM events \
+            WHERE room_id = '!room:ruma.test' AND event_type = 'm.room.name' AND state_key = '' \
+            ORDER BY ordering DESC LIMIT 1",
+        );
+        test.assert_no_seq_scan(
+            "events",
+            "SELECT * FROM events \
+            WHERE room_id = '!room:ruma.test' AND event_type = 'm.room.member' \
+            AND state_key = '@carl:ruma.test' AND ordering < 10 \
+            ORDER BY ordering DESC LIMIT 1",
+        );
+        test.assert_no_seq_scan(
+            "room_current_state",
+            "SELECT event_id FROM room_current_state \
+            WHERE room_id = '!room:ruma.test' AND event_type = 'm.room.member' \
+            AND state_key = '@carl:ruma.test'",
+        );
+    }
+}
@@ -6,39 +6,92 @@ use std::fmt::Error as FmtError;
 use std::io::Error as IoError;
 use std::string::FromUtf8Error;
 use std::sync::PoisonError;
-use std::time::SystemTimeError;
+use std::time::{Duration, SystemTimeError};
 
 use argon2rs::verifier::DecodeError;
 use base64::Base64Error;
-use diesel::result::Error as DieselError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use iron::{IronError, Response};
-use iron::headers::ContentType;
 use iron::modifier::Modifier;
 use iron::status::Status;
 use macaroons::error::Error as MacaroonsError;
 use persistent::PersistentError;
 use r2d2::GetTimeout;
 use ruma_identifiers::Error as RumaIdentifiersError;
+use ruma_signatures::Error as RumaSignaturesError;
 use serde::ser::{Serialize, Serializer};
 use serde_json::{Error as SerdeJsonError, to_string};
 
+use ids::ID_CONSTRAINTS;
+use modifier::set_json_body;
+
+/// The user-facing descriptions of violations of unique constraints, by constraint name.
+const UNIQUE_CONSTRAINTS: [(&'static str, &'static str); 8] = [
+    ("presence_list_pkey", "The user is already on the presence list."),
+    ("profiles_pkey", "The user already has a profile."),
+    ("room_aliases_pkey", "The room alias is already taken."),
+    ("room_memberships_room_id_user_id_key", "The user already has a membership in the room."),
+    ("room_tags_user_id_room_id_tag_key", "The room already has the tag."),
+    ("user_threepids_pkey", "The third party identifier is already in use."),
+    ("users_lower_id_key", "The user ID is already taken."),
+    ("users_pkey", "The user ID is already taken."),
+];
+
+/// The start of PostgreSQL's messages for transactions that failed to serialize (SQLSTATE 40001)
+/// and that were aborted to resolve a deadlock (40P01). See `is_retryable_failure`.
+const RETRYABLE_FAILURES: [&'static str; 2] = ["could not serialize access", "deadlock detected"];
+
+/// How long clients should wait before retrying a request whose transaction failed to serialize.
+const SERIALIZATION_RETRY_AFTER_MS: u64 = 500;
+
 /// A client-facing error.
+///
+/// The response for it has the status of its `ErrCode`, and a body with the `errcode`, the
+/// `error` message and any of the optional fields that are set.
 #[derive(Clone, Debug, Serialize)]
 pub struct ApiError {
-    errcode: ApiErrorCode,
+    errcode: ErrCode,
     error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    /// How long the client should wait before retrying, e.g. for `M_LIMIT_EXCEEDED` or 503 errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
+    /// Whether or not the client may keep its data and log in again, for `M_UNKNOWN_TOKEN`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    soft_logout: Option<bool>,
+    /// Where the user can give their consent, for `M_CONSENT_NOT_GIVEN`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    consent_uri: Option<String>,
+    /// The current version of the key backup, for `M_WRONG_ROOM_KEYS_VERSION`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current_version: Option<String>,
+    /// Whether or not the transaction that failed with the error can be run again as is.
+    #[serde(skip_serializing)]
+    retryable: bool,
+    /// Whether or not the error is a unique violation of a generated room or event ID.
+    #[serde(skip_serializing)]
+    id_collision: bool,
 }
 
 /// The error code for a client-facing error.
 #[derive(Clone, Debug)]
-pub enum ApiErrorCode {
+pub enum ErrCode {
     /// The requested room alias is already taken.
     AliasTaken,
     /// Request contained an event that was not valid input for the requested API.
     BadEvent,
+    /// The request conflicts with data that already exists.
+    Conflict,
     /// The request contained valid JSON, but it was malformed in some way,
     /// e.g. missing required keys, invalid values for keys.
     BadJson,
+    /// The state change requested is not valid, e.g. a canonical alias of another room.
+    BadState,
+    /// The user has to agree to the server's terms before using it.
+    ConsentNotGiven,
+    /// The resource is reserved by an application service, e.g. a user ID in its namespace.
+    Exclusive,
     /// Forbidden access, e.g. joining a room without permission, failed login.
     Forbidden,
     /// Guests are not allowed to perform the requested operation.
@@ -47,18 +100,40 @@ pub enum ApiErrorCode {
     InvalidParam,
     /// Too many requests have been sent in a short period of time. Wait a while then try again.
     LimitExceeded,
+    /// The endpoint exists, but not for the HTTP method of the request.
+    MethodNotAllowed,
     /// A required input parameter was not supplied, e.g. query string or URL path-based parameter.
     MissingParam,
+    /// The request did not contain an access token.
+    MissingToken,
     /// No resource was found for this request.
     NotFound,
     /// Request did not contain valid JSON.
     NotJson,
+    /// The request body is larger than the server accepts.
+    TooLarge,
+    /// The client took too long to send its request.
+    RequestTimeout,
+    /// The room alias to create a room with is already taken.
+    RoomInUse,
+    /// The server is temporarily unable to handle the request, e.g. because it is shutting down.
+    Unavailable,
     /// Ruma does not implement the requested API.
     Unimplemented,
+    /// The request was not correctly authorized by the server that sent it.
+    Unauthorized,
+    /// The server does not recognize the requested endpoint.
+    Unrecognized,
     /// Errors not fitting into another category.
     Unknown,
     /// The access token specified was not recognised.
     UnknownToken,
+    /// The version of the room is not supported by this server.
+    UnsupportedRoomVersion,
+    /// The user ID to register is already taken.
+    UserInUse,
+    /// Keys were sent for a version of the key backup that isn't the current one.
+    WrongRoomKeysVersion,
 }
 
 /// An operator-facing error.
@@ -66,6 +141,14 @@ pub struct CliError {
     error: String,
 }
 
+/// A problem with a value in the user's configuration, found while loading it or by
+/// `Config::validate`.
+#[derive(Debug)]
+pub struct ConfigError {
+    field: String,
+    error: String,
+}
+
 /// Extensions for `Result` to make handling Ruma API errors easier.
 pub trait MapApiError {
     /// The type contained in a successful result.
@@ -82,125 +165,324 @@ pub trait MapApiError {
 }
 
 impl ApiError {
+    /// Create an error with the given code and message, and none of the optional fields.
+    fn new(errcode: ErrCode, error: String) -> ApiError {
+        ApiError {
+            errcode: errcode,
+            error: error,
+            request_id: None,
+            retry_after_ms: None,
+            soft_logout: None,
+            consent_uri: None,
+            current_version: None,
+            retryable: false,
+            id_collision: false,
+        }
+    }
+
     /// Create an error for requests that try to create a room alias that is already taken.
     pub fn alias_taken<T: Into<Option<String>>>(message: T) -> ApiError {
         let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::AliasTaken,
-            error: message.unwrap_or_else(|| "Alias already taken.".to_string()),
-        }
+        ApiError::new(
+            ErrCode::AliasTaken,
+            message.unwrap_or_else(|| "Alias already taken.".to_string()),
+        )
     }
 
     /// Create an error for invalid or incomplete input to event creation API endpoints.
     pub fn bad_event<T: Into<Option<String>>>(message: T) -> ApiError {
         let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::BadEvent,
-            error: message.unwrap_or_else(|| "Invalid event data.".to_string()),
-        }
+        ApiError::new(
+            ErrCode::BadEvent,
+            message.unwrap_or_else(|| "Invalid event data.".to_string()),
+        )
     }
 
     /// Create an error for invalid or incomplete JSON in request bodies.
     pub fn bad_json<T: Into<Option<String>>>(message: T) -> ApiError {
         let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::BadJson,
-            error: message.unwrap_or_else(|| {
+        ApiError::new(
+            ErrCode::BadJson,
+            message.unwrap_or_else(|| {
                 "Invalid or missing key-value pairs in JSON.".to_string()
             }),
-        }
+        )
+    }
+
+    /// Create an error for state changes that are not valid.
+    pub fn bad_state<T: Into<Option<String>>>(message: T) -> ApiError {
+        let message = message.into();
+        ApiError::new(
+            ErrCode::BadState,
+            message.unwrap_or_else(|| "The state change is not valid.".to_string()),
+        )
+    }
+
+    /// Create an error for requests that conflict with data that already exists.
+    pub fn conflict<T: Into<Option<String>>>(message: T) -> ApiError {
+        let message = message.into();
+        ApiError::new(
+            ErrCode::Conflict,
+            message.unwrap_or_else(|| "The request conflicts with existing data.".to_string()),
+        )
     }
 
     /// Create an error for endpoints where guest accounts are not supported.
     pub fn guest_forbidden<T: Into<Option<String>>>(message: T) -> ApiError {
         let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::GuestAccessForbidden,
-            error: message.unwrap_or_else(|| "Guest accounts are forbidden.".to_string()),
-        }
+        ApiError::new(
+            ErrCode::GuestAccessForbidden,
+            message.unwrap_or_else(|| "Guest accounts are forbidden.".to_string()),
+        )
     }
 
     /// Create an error for invalid input parameters.
     pub fn invalid_param(param_name: &str, msg: &str) -> ApiError {
-        ApiError {
-            errcode: ApiErrorCode::InvalidParam,
-            error: format!("Parameter '{}' is not valid: {}", param_name, msg),
-        }
+        ApiError::new(
+            ErrCode::InvalidParam,
+            format!("Parameter '{}' is not valid: {}", param_name, msg),
+        )
     }
 
     /// Create an error for requests missing a value for a required parameter.
     pub fn missing_param(param_name: &str) -> ApiError {
-        ApiError {
-            errcode: ApiErrorCode::MissingParam,
-            error: format!("Missing value for required parameter: {}.", param_name),
-        }
+        ApiError::new(
+            ErrCode::MissingParam,
+            format!("Missing value for required parameter: {}.", param_name),
+        )
+    }
+
+    /// Create an error for requests without an access token.
+    pub fn missing_token() -> ApiError {
+        ApiError::new(
+            ErrCode::MissingToken,
+            "Missing access token.".to_string(),
+        )
     }
 
     /// Create an error for requests that do not map to a resource.
     pub fn not_found<T: Into<Option<String>>>(message: T) -> ApiError {
         let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::NotFound,
-            error: message.unwrap_or_else(|| "No resource was found for this request.".to_string()),
-        }
+        ApiError::new(
+            ErrCode::NotFound,
+            message.unwrap_or_else(|| "No resource was found for this request.".to_string()),
+        )
     }
 
     /// Create an error for requests without JSON bodies.
     pub fn not_json<T: Into<Option<String>>>(message: T) -> ApiError {
         let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::NotJson,
-            error: message.unwrap_or_else(|| "No JSON found in request body.".to_string()),
-        }
+        ApiError::new(
+            ErrCode::NotJson,
+            message.unwrap_or_else(|| "No JSON found in request body.".to_string()),
+        )
+    }
+
+    /// Create an error for requests to a known endpoint with an HTTP method it doesn't support.
+    pub fn method_not_allowed<T: Into<Option<String>>>(message: T) -> ApiError {
+        let message = message.into();
+        ApiError::new(
+            ErrCode::MethodNotAllowed,
+            message.unwrap_or_else(|| "Method not allowed for this endpoint.".to_string()),
+        )
+    }
+
+    /// Create an error for requests whose body didn't arrive in time.
+    pub fn request_timeout<T: Into<Option<String>>>(message: T) -> ApiError {
+        let message = message.into();
+        ApiError::new(
+            ErrCode::RequestTimeout,
+            message.unwrap_or_else(|| "Timed out reading the request body.".to_string()),
+        )
+    }
+
+    /// Create an error for requests with bodies larger than the server accepts.
+    pub fn too_large<T: Into<Option<String>>>(message: T) -> ApiError {
+        let message = message.into();
+        ApiError::new(
+            ErrCode::TooLarge,
+            message.unwrap_or_else(|| "The request body is too large.".to_string()),
+        )
     }
 
     /// Create an error for requests that are not marked as containing JSON.
     pub fn wrong_content_type<T: Into<Option<String>>>(message: T) -> ApiError {
         let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::NotJson,
-            error: message.unwrap_or_else(|| {
+        ApiError::new(
+            ErrCode::NotJson,
+            message.unwrap_or_else(|| {
                 "Request's Content-Type header must be application/json.".to_string()
             }),
-        }
+        )
     }
 
     /// Create an error for requests that did not provide required authentication parameters.
     pub fn unauthorized<T: Into<Option<String>>>(message: T) -> ApiError {
         let message = message.into();
+        ApiError::new(
+            ErrCode::Forbidden,
+            message.unwrap_or_else(|| "Authentication is required.".to_string()),
+        )
+    }
+
+    /// Create an error for access tokens that are unknown, revoked, or of deactivated users.
+    pub fn unknown_token<T: Into<Option<String>>>(message: T) -> ApiError {
+        let message = message.into();
+        ApiError::new(
+            ErrCode::UnknownToken,
+            message.unwrap_or_else(|| "Unrecognised access token.".to_string()),
+        )
+    }
+
+    /// Create an error for registering a user ID that is already taken.
+    pub fn user_in_use<T: Into<Option<String>>>(message: T) -> ApiError {
+        let message = message.into();
+        ApiError::new(
+            ErrCode::UserInUse,
+            message.unwrap_or_else(|| "The user ID is already taken.".to_string()),
+        )
+    }
+
+    /// Create an error for creating a room with an alias that is already taken.
+    pub fn room_in_use<T: Into<Option<String>>>(message: T) -> ApiError {
+        let message = message.into();
+        ApiError::new(
+            ErrCode::RoomInUse,
+            message.unwrap_or_else(|| "The room alias is already taken.".to_string()),
+        )
+    }
+
+    /// Create an error for rooms of a version this server does not support.
+    pub fn unsupported_room_version<T: Into<Option<String>>>(message: T) -> ApiError {
+        let message = message.into();
+        ApiError::new(
+            ErrCode::UnsupportedRoomVersion,
+            message.unwrap_or_else(|| "The room version is not supported.".to_string()),
+        )
+    }
+
+    /// Create an error for resources that are reserved by an application service.
+    pub fn exclusive<T: Into<Option<String>>>(message: T) -> ApiError {
+        let message = message.into();
+        ApiError::new(
+            ErrCode::Exclusive,
+            message.unwrap_or_else(|| {
+                "The resource is reserved by an application service.".to_string()
+            }),
+        )
+    }
+
+    /// Create an error for users who have to agree to the terms at `consent_uri` first.
+    pub fn consent_not_given(consent_uri: &str) -> ApiError {
         ApiError {
-            errcode: ApiErrorCode::Forbidden,
-            error: message.unwrap_or_else(|| "Authentication is required.".to_string()),
+            consent_uri: Some(consent_uri.to_string()),
+            ..ApiError::new(
+                ErrCode::ConsentNotGiven,
+                format!("You have to agree to the terms at {} to use this server.", consent_uri),
+            )
         }
     }
 
+    /// Create an error for requests the server is temporarily unable to handle.
+    pub fn unavailable<T: Into<Option<String>>>(message: T) -> ApiError {
+        let message = message.into();
+        ApiError::new(
+            ErrCode::Unavailable,
+            message.unwrap_or_else(|| {
+                "The server is temporarily unavailable.".to_string()
+            }),
+        )
+    }
+
+    /// Create an error for federation requests without a valid `X-Matrix` authorization.
+    pub fn unauthorized_server<T: Into<Option<String>>>(message: T) -> ApiError {
+        let message = message.into();
+        ApiError::new(
+            ErrCode::Unauthorized,
+            message.unwrap_or_else(|| "Server authentication is required.".to_string()),
+        )
+    }
+
     /// Create an error for Matrix APIs that Ruma intentionally does not implement.
     pub fn unimplemented<T: Into<Option<String>>>(message: T) -> ApiError {
         let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::Unimplemented,
-            error: message.unwrap_or_else(|| {
+        ApiError::new(
+            ErrCode::Unimplemented,
+            message.unwrap_or_else(|| {
                 "The homeserver does not implement this API.".to_string()
             }),
-        }
+        )
     }
 
-    /// Create an error for Matrix APIs that Ruma intentionally does not implement.
+    /// Create an error for clients that sent too many requests in a short period of time.
     pub fn limited_rate<T: Into<Option<String>>>(message: T) -> ApiError {
         let message = message.into();
+        ApiError::new(
+            ErrCode::LimitExceeded,
+            message.unwrap_or_else(|| "Too many retry!".to_string()),
+        )
+    }
+
+    /// Create an error for requests to endpoints that don't exist.
+    pub fn unrecognized<T: Into<Option<String>>>(message: T) -> ApiError {
+        let message = message.into();
+        ApiError::new(
+            ErrCode::Unrecognized,
+            message.unwrap_or_else(|| "Unrecognized request.".to_string()),
+        )
+    }
+
+    /// Create an error for keys sent for a version of the key backup that isn't the current one,
+    /// `current_version`.
+    pub fn wrong_room_keys_version(current_version: Option<String>) -> ApiError {
         ApiError {
-            errcode: ApiErrorCode::LimitExceeded,
-            error: message.unwrap_or_else(|| "Too many retry!".to_string()),
+            current_version: current_version,
+            ..ApiError::new(
+                ErrCode::WrongRoomKeysVersion,
+                "The version is not the current version of the key backup.".to_string(),
+            )
         }
     }
 
     /// Create a generic error for anything not specifically covered by the Matrix spec.
     pub fn unknown<T: Into<Option<String>>>(message: T) -> ApiError {
         let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::Unknown,
-            error: message.unwrap_or_else(|| "An unknown server-side error occurred.".to_string()),
-        }
+        ApiError::new(
+            ErrCode::Unknown,
+            message.unwrap_or_else(|| "An unknown server-side error occurred.".to_string()),
+        )
+    }
+
+    /// Attaches the ID of the request that caused the error, so it can be matched to the logs.
+    pub fn with_request_id(mut self, request_id: &str) -> ApiError {
+        self.request_id = Some(request_id.to_string());
+        self
+    }
+
+    /// Tells the client how long to wait before retrying.
+    pub fn with_retry_after(mut self, retry_after: Duration) -> ApiError {
+        let millis = retry_after.as_secs() * 1000 + (retry_after.subsec_nanos() / 1_000_000) as u64;
+
+        self.retry_after_ms = Some(millis);
+        self
+    }
+
+    /// Tells the client whether or not it may keep its data and log in again.
+    pub fn with_soft_logout(mut self, soft_logout: bool) -> ApiError {
+        self.soft_logout = Some(soft_logout);
+        self
+    }
+
+    /// Whether or not the error is a failure of a transaction that may succeed if run again, like
+    /// a serialization failure or deadlock. See `db::transaction_with_retry`.
+    pub fn is_retryable(&self) -> bool {
+        self.retryable
+    }
+
+    /// Whether or not the error is a violation of one of `ids::ID_CONSTRAINTS`, because a newly
+    /// generated ID was already taken. See `ids::with_unique_id`.
+    pub fn is_id_collision(&self) -> bool {
+        self.id_collision
     }
 }
 
@@ -241,14 +523,60 @@ impl From<Base64Error> for ApiError {
     }
 }
 
+/// Maps database errors to errors that are safe to show to clients.
+///
+/// Missing rows are `M_NOT_FOUND`. Unique violations are conflicts, described by the message
+/// registered for the violated constraint in `UNIQUE_CONSTRAINTS`. Transactions that failed to
+/// serialize with concurrent ones or were aborted to resolve a deadlock can be retried. Anything
+/// else is logged and only reported as an unknown error, since the details of database errors
+/// include SQL and data.
 impl From<DieselError> for ApiError {
     fn from(error: DieselError) -> ApiError {
-        debug!("Converting to ApiError from: {:?}", error);
+        match error {
+            DieselError::NotFound => ApiError::not_found(None),
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, ref info) => {
+                debug!("Unique violation: {}", info.message());
+
+                let message = quoted_name(info.message()).and_then(|constraint| {
+                    UNIQUE_CONSTRAINTS.iter()
+                        .find(|&&(name, _)| name == constraint)
+                        .map(|&(_, message)| message.to_string())
+                });
+
+                let mut error = ApiError::conflict(message);
+                error.id_collision = quoted_name(info.message())
+                    .map_or(false, |constraint| ID_CONSTRAINTS.contains(&constraint));
+
+                error
+            }
+            DieselError::DatabaseError(_, ref info) if is_retryable_failure(info.message()) => {
+                debug!("Retryable transaction failure: {}", info.message());
 
-        ApiError::unknown(None)
+                let mut error = ApiError::unavailable(
+                    "The request conflicted with a concurrent one, try again.".to_string()
+                ).with_retry_after(Duration::from_millis(SERIALIZATION_RETRY_AFTER_MS));
+                error.retryable = true;
+
+                error
+            }
+            error => {
+                error!("Database error: {:?}", error);
+
+                ApiError::unknown(None)
+            }
+        }
     }
 }
 
+/// Whether or not a database error message is that of a serialization failure or deadlock.
+///
+/// Diesel 0.12 reports both as `DatabaseErrorKind::__Unknown` and doesn't expose their SQLSTATE,
+/// so the message is all there is to go by. This only works as long as the database server's
+/// `lc_messages` setting keeps its messages in English.
+fn is_retryable_failure(message: &str) -> bool {
+    RETRYABLE_FAILURES.iter().any(|failure| message.starts_with(failure))
+}
+
 impl From<SystemTimeError> for ApiError {
     fn from(error: SystemTimeError) -> ApiError {
         debug!("Converting to ApiError from: {:?}", error);
@@ -281,14 +609,22 @@ impl From<RumaIdentifiersError> for ApiError {
     }
 }
 
-impl From<GetTimeout> for ApiError {
-    fn from(error: GetTimeout) -> ApiError {
+impl From<RumaSignaturesError> for ApiError {
+    fn from(error: RumaSignaturesError) -> ApiError {
         debug!("Converting to ApiError from: {:?}", error);
 
         ApiError::unknown(None)
     }
 }
 
+impl From<GetTimeout> for ApiError {
+    fn from(error: GetTimeout) -> ApiError {
+        warn!("Timed out waiting for a database connection: {}", error);
+
+        ApiError::unavailable("No database connection is available, try again later.".to_string())
+    }
+}
+
 impl From<FromUtf8Error> for ApiError {
     fn from(error: FromUtf8Error) -> ApiError {
         debug!("Converting to ApiError from: {:?}", error);
@@ -313,6 +649,7 @@ impl From<SerdeJsonError> for ApiError {
     }
 }
 
+/// Lets handlers return an `ApiError` with `?`, responding with the error's status and body.
 impl From<ApiError> for IronError {
     fn from(error: ApiError) -> IronError {
         IronError::new(error.clone(), error)
@@ -321,49 +658,85 @@ impl From<ApiError> for IronError {
 
 impl Modifier<Response> for ApiError {
     fn modify(self, response: &mut Response) {
-        response.headers.set(ContentType::json());
         response.status = Some(self.errcode.status_code());
-        response.body = Some(Box::new(to_string(&self).expect("ApiError should always serialize")));
+
+        // The header is in whole seconds, so the delay is rounded up.
+        if let Some(retry_after_ms) = self.retry_after_ms {
+            let seconds = (retry_after_ms + 999) / 1000;
+            response.headers.set_raw("Retry-After", vec![seconds.to_string().into_bytes()]);
+        }
+
+        set_json_body(response, to_string(&self).expect("ApiError should always serialize"));
     }
 }
 
-impl ApiErrorCode {
-    /// The HTTP status code that should be used to represent the `ApiErrorCode`.
+impl ErrCode {
+    /// The HTTP status code that should be used to represent the `ErrCode`.
     pub fn status_code(&self) -> Status {
         match *self {
-            ApiErrorCode::AliasTaken => Status::Conflict,
-            ApiErrorCode::BadEvent |
-            ApiErrorCode::BadJson => Status::UnprocessableEntity,
-            ApiErrorCode::Forbidden |
-            ApiErrorCode::GuestAccessForbidden => Status::Forbidden,
-            ApiErrorCode::InvalidParam |
-            ApiErrorCode::MissingParam |
-            ApiErrorCode::NotJson => Status::BadRequest,
-            ApiErrorCode::LimitExceeded => Status::TooManyRequests,
-            ApiErrorCode::NotFound |
-            ApiErrorCode::Unimplemented => Status::NotFound,
-            ApiErrorCode::Unknown => Status::InternalServerError,
-            ApiErrorCode::UnknownToken => Status::Unauthorized,
+            ErrCode::AliasTaken |
+            ErrCode::Conflict => Status::Conflict,
+            ErrCode::BadEvent |
+            ErrCode::BadJson |
+            ErrCode::BadState |
+            ErrCode::Exclusive |
+            ErrCode::InvalidParam |
+            ErrCode::MissingParam |
+            ErrCode::NotJson |
+            ErrCode::RoomInUse |
+            ErrCode::UnsupportedRoomVersion |
+            ErrCode::UserInUse => Status::BadRequest,
+            ErrCode::ConsentNotGiven |
+            ErrCode::Forbidden |
+            ErrCode::GuestAccessForbidden |
+            ErrCode::WrongRoomKeysVersion => Status::Forbidden,
+            ErrCode::LimitExceeded => Status::TooManyRequests,
+            ErrCode::MethodNotAllowed => Status::MethodNotAllowed,
+            ErrCode::NotFound |
+            ErrCode::Unimplemented |
+            ErrCode::Unrecognized => Status::NotFound,
+            ErrCode::RequestTimeout => Status::RequestTimeout,
+            ErrCode::TooLarge => Status::PayloadTooLarge,
+            ErrCode::Unavailable => Status::ServiceUnavailable,
+            ErrCode::Unknown => Status::InternalServerError,
+            ErrCode::MissingToken |
+            ErrCode::Unauthorized |
+            ErrCode::UnknownToken => Status::Unauthorized,
         }
     }
 }
 
-impl Serialize for ApiErrorCode {
+impl Serialize for ErrCode {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
         let value = match *self {
-            ApiErrorCode::AliasTaken => "IO_RUMA_ALIAS_TAKEN",
-            ApiErrorCode::BadEvent => "IO_RUMA_BAD_EVENT",
-            ApiErrorCode::BadJson => "M_BAD_JSON",
-            ApiErrorCode::Forbidden => "M_FORBIDDEN",
-            ApiErrorCode::GuestAccessForbidden => "M_GUEST_ACCESS_FORBIDDEN",
-            ApiErrorCode::InvalidParam => "IO_RUMA_INVALID_PARAM",
-            ApiErrorCode::LimitExceeded => "M_LIMIT_EXCEEDED",
-            ApiErrorCode::MissingParam => "M_MISSING_PARAM",
-            ApiErrorCode::NotFound => "M_NOT_FOUND",
-            ApiErrorCode::NotJson => "M_NOT_JSON",
-            ApiErrorCode::Unimplemented => "IO_RUMA_UNIMPLEMENTED",
-            ApiErrorCode::Unknown => "M_UNKNOWN",
-            ApiErrorCode::UnknownToken => "M_UNKNOWN_TOKEN",
+            ErrCode::AliasTaken => "IO_RUMA_ALIAS_TAKEN",
+            ErrCode::BadEvent => "IO_RUMA_BAD_EVENT",
+            ErrCode::BadJson => "M_BAD_JSON",
+            ErrCode::BadState => "M_BAD_STATE",
+            ErrCode::Conflict => "IO_RUMA_CONFLICT",
+            ErrCode::ConsentNotGiven => "M_CONSENT_NOT_GIVEN",
+            ErrCode::Exclusive => "M_EXCLUSIVE",
+            ErrCode::Forbidden => "M_FORBIDDEN",
+            ErrCode::GuestAccessForbidden => "M_GUEST_ACCESS_FORBIDDEN",
+            ErrCode::InvalidParam => "M_INVALID_PARAM",
+            ErrCode::LimitExceeded => "M_LIMIT_EXCEEDED",
+            ErrCode::MethodNotAllowed => "M_UNRECOGNIZED",
+            ErrCode::MissingParam => "M_MISSING_PARAM",
+            ErrCode::MissingToken => "M_MISSING_TOKEN",
+            ErrCode::NotFound => "M_NOT_FOUND",
+            ErrCode::NotJson => "M_NOT_JSON",
+            ErrCode::RequestTimeout => "M_UNKNOWN",
+            ErrCode::RoomInUse => "M_ROOM_IN_USE",
+            ErrCode::TooLarge => "M_TOO_LARGE",
+            ErrCode::Unavailable => "M_UNKNOWN",
+            ErrCode::Unimplemented => "IO_RUMA_UNIMPLEMENTED",
+            ErrCode::Unauthorized => "M_UNAUTHORIZED",
+            ErrCode::Unrecognized => "M_UNRECOGNIZED",
+            ErrCode::Unknown => "M_UNKNOWN",
+            ErrCode::UnknownToken => "M_UNKNOWN_TOKEN",
+            ErrCode::UnsupportedRoomVersion => "M_UNSUPPORTED_ROOM_VERSION",
+            ErrCode::UserInUse => "M_USER_IN_USE",
+            ErrCode::WrongRoomKeysVersion => "M_WRONG_ROOM_KEYS_VERSION",
         };
 
         serializer.serialize_str(value)
@@ -391,6 +764,22 @@ impl Display for CliError {
     }
 }
 
+impl ConfigError {
+    /// Create a new `ConfigError` for the given configuration field.
+    pub fn new<F, E>(field: F, error: E) -> ConfigError where F: Into<String>, E: Into<String> {
+        ConfigError {
+            field: field.into(),
+            error: error.into(),
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "{}: {}", self.field, self.error)
+    }
+}
+
 impl<T, E> MapApiError for Result<T, E> where E: Debug {
     type Output = T;
     type Error = E;
@@ -408,14 +797,28 @@ impl<T, E> MapApiError for Result<T, E> where E: Debug {
     }
 }
 
+/// The first double-quoted name in a database error message, e.g. the constraint in
+/// `duplicate key value violates unique constraint "users_pkey"`.
+fn quoted_name(message: &str) -> Option<&str> {
+    let start = match message.find('"') {
+        Some(start) => start + 1,
+        None => return None,
+    };
+
+    message[start..].find('"').map(|end| &message[start..start + end])
+}
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use iron::Response;
     use iron::headers::ContentType;
     use iron::modifier::Modifier;
     use iron::status::Status;
-    use error::ApiError;
+    use serde_json::to_value;
+
+    use error::{ApiError, is_retryable_failure, quoted_name};
 
     #[test]
     fn api_error_status_and_headers_modified() {
@@ -426,4 +829,103 @@ mod tests {
         assert_eq!(response.headers.get::<ContentType>().unwrap(), &ContentType::json());
         assert_eq!(response.status.unwrap(), Status::Forbidden);
     }
+
+    #[test]
+    fn errcodes_and_statuses() {
+        let errors = vec![
+            (ApiError::alias_taken(None), "IO_RUMA_ALIAS_TAKEN", Status::Conflict),
+            (ApiError::bad_event(None), "IO_RUMA_BAD_EVENT", Status::BadRequest),
+            (ApiError::bad_json(None), "M_BAD_JSON", Status::BadRequest),
+            (ApiError::bad_state(None), "M_BAD_STATE", Status::BadRequest),
+            (
+                ApiError::consent_not_given("https://ruma.test/terms"),
+                "M_CONSENT_NOT_GIVEN",
+                Status::Forbidden,
+            ),
+            (ApiError::exclusive(None), "M_EXCLUSIVE", Status::BadRequest),
+            (ApiError::guest_forbidden(None), "M_GUEST_ACCESS_FORBIDDEN", Status::Forbidden),
+            (
+                ApiError::invalid_param("limit", "Must be a number."),
+                "M_INVALID_PARAM",
+                Status::BadRequest,
+            ),
+            (ApiError::limited_rate(None), "M_LIMIT_EXCEEDED", Status::TooManyRequests),
+            (ApiError::method_not_allowed(None), "M_UNRECOGNIZED", Status::MethodNotAllowed),
+            (ApiError::missing_param("limit"), "M_MISSING_PARAM", Status::BadRequest),
+            (ApiError::missing_token(), "M_MISSING_TOKEN", Status::Unauthorized),
+            (ApiError::not_found(None), "M_NOT_FOUND", Status::NotFound),
+            (ApiError::not_json(None), "M_NOT_JSON", Status::BadRequest),
+            (ApiError::request_timeout(None), "M_UNKNOWN", Status::RequestTimeout),
+            (ApiError::room_in_use(None), "M_ROOM_IN_USE", Status::BadRequest),
+            (ApiError::too_large(None), "M_TOO_LARGE", Status::PayloadTooLarge),
+            (ApiError::unauthorized(None), "M_FORBIDDEN", Status::Forbidden),
+            (ApiError::unauthorized_server(None), "M_UNAUTHORIZED", Status::Unauthorized),
+            (ApiError::unavailable(None), "M_UNKNOWN", Status::ServiceUnavailable),
+            (ApiError::unimplemented(None), "IO_RUMA_UNIMPLEMENTED", Status::NotFound),
+            (ApiError::unknown(None), "M_UNKNOWN", Status::InternalServerError),
+            (ApiError::unknown_token(None), "M_UNKNOWN_TOKEN", Status::Unauthorized),
+            (ApiError::unrecognized(None), "M_UNRECOGNIZED", Status::NotFound),
+            (
+                ApiError::unsupported_room_version(None),
+                "M_UNSUPPORTED_ROOM_VERSION",
+                Status::BadRequest,
+            ),
+            (ApiError::user_in_use(None), "M_USER_IN_USE", Status::BadRequest),
+            (ApiError::wrong_content_type(None), "M_NOT_JSON", Status::BadRequest),
+        ];
+
+        for (error, errcode, status) in errors {
+            let mut response = Response::new();
+            let json = to_value(&error).unwrap();
+
+            error.modify(&mut response);
+
+            assert_eq!(json.get("errcode").unwrap().as_str().unwrap(), errcode);
+            assert!(json.get("error").unwrap().is_string());
+            assert_eq!(response.status.unwrap(), status, "{}", errcode);
+        }
+    }
+
+    #[test]
+    fn optional_fields_are_only_serialized_when_set() {
+        let json = to_value(&ApiError::not_found(None)).unwrap();
+        let fields = json.as_object().unwrap();
+
+        assert_eq!(fields.len(), 2);
+        assert!(fields.contains_key("errcode"));
+        assert!(fields.contains_key("error"));
+
+        let error = ApiError::limited_rate(None).with_retry_after(Duration::from_millis(1500));
+        let json = to_value(&error).unwrap();
+        assert_eq!(json.get("retry_after_ms").unwrap().as_u64().unwrap(), 1500);
+
+        let json = to_value(&ApiError::unknown_token(None).with_soft_logout(true)).unwrap();
+        assert_eq!(json.get("soft_logout").unwrap().as_bool().unwrap(), true);
+
+        let json = to_value(&ApiError::consent_not_given("https://ruma.test/terms")).unwrap();
+        assert_eq!(json.get("consent_uri").unwrap().as_str().unwrap(), "https://ruma.test/terms");
+
+        let json = to_value(&ApiError::unknown(None).with_request_id("abc")).unwrap();
+        assert_eq!(json.get("request_id").unwrap().as_str().unwrap(), "abc");
+    }
+
+    #[test]
+    fn constraint_names_are_found_in_messages() {
+        assert_eq!(
+            quoted_name(r#"duplicate key value violates unique constraint "users_pkey""#),
+            Some("users_pkey")
+        );
+        assert_eq!(quoted_name("could not serialize access"), None);
+    }
+
+    #[test]
+    fn serialization_failures_and_deadlocks_are_retryable() {
+        assert!(is_retryable_failure(
+            "could not serialize access due to read/write dependencies among transactions"
+        ));
+        assert!(is_retryable_failure("deadlock detected"));
+        assert!(!is_retryable_failure(
+            r#"duplicate key value violates unique constraint "users_pkey""#
+        ));
+    }
 }
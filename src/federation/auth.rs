@@ -0,0 +1,370 @@
+//! Authentication of federation requests.
+
+use base64::{decode, encode};
+use iron::method::Method;
+use iron::typemap::Key;
+use ruma_signatures::{Ed25519KeyPair, Ed25519Verifier, Signature, sign_json, verify_json};
+use serde_json::{Map, Value};
+
+use config::Config;
+use crypto::SigningKey;
+use error::ApiError;
+
+/// The name of the server that sent an authenticated federation request.
+///
+/// `FederationAuth` stores it in the request's extensions.
+pub struct Origin;
+
+impl Key for Origin {
+    type Value = String;
+}
+
+/// The parameters of an `Authorization: X-Matrix ...` header.
+#[derive(Debug, PartialEq)]
+pub struct XMatrix {
+    /// The name of the server that sent the request.
+    pub origin: String,
+    /// The ID of the key the request was signed with, e.g. `ed25519:1`.
+    pub key: String,
+    /// The unpadded Base64 signature.
+    pub sig: String,
+}
+
+impl XMatrix {
+    /// Parses the value of an `Authorization` header.
+    pub fn parse(header: &str) -> Option<XMatrix> {
+        let params = if header.starts_with("X-Matrix ") {
+            &header["X-Matrix ".len()..]
+        } else {
+            return None;
+        };
+
+        let mut origin = None;
+        let mut key = None;
+        let mut sig = None;
+
+        for param in params.split(',') {
+            let mut parts = param.trim().splitn(2, '=');
+            let name = parts.next();
+            let value = parts.next().map(|value| value.trim_matches('"').to_string());
+
+            match name {
+                Some("origin") => origin = value,
+                Some("key") => key = value,
+                Some("sig") => sig = value,
+                _ => (),
+            }
+        }
+
+        match (origin, key, sig) {
+            (Some(origin), Some(key), Some(sig)) => Some(XMatrix {
+                origin: origin,
+                key: key,
+                sig: sig,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Verifies the `X-Matrix` authorization of an incoming federation request.
+///
+/// Returns the name of the server that sent the request.
+pub fn verify_request(
+    config: &Config,
+    method: &Method,
+    uri: &str,
+    authorization: &str,
+    content: Option<&Value>,
+) -> Result<String, ApiError> {
+    let x_matrix = XMatrix::parse(authorization).ok_or_else(|| {
+        ApiError::unauthorized_server("Malformed X-Matrix authorization header.".to_string())
+    })?;
+
+    let public_key = verify_key(config, &x_matrix.origin, &x_matrix.key)?;
+    let signature_bytes = decode(&pad_base64(&x_matrix.sig)).map_err(|_| {
+        ApiError::unauthorized_server("Signature is not valid Base64.".to_string())
+    })?;
+    let signature = Signature::new(&x_matrix.key, &signature_bytes).map_err(|_| {
+        ApiError::unauthorized_server("Unsupported signing key algorithm.".to_string())
+    })?;
+
+    let request = request_json(method, uri, &x_matrix.origin, &config.domain, content);
+
+    verify_json(&Ed25519Verifier, &public_key, &signature, &request).map_err(|_| {
+        ApiError::unauthorized_server("Invalid signature.".to_string())
+    })?;
+
+    Ok(x_matrix.origin)
+}
+
+/// Verifies that an event received over federation is signed by `server_name`.
+///
+/// The signature covers the event without its `signatures` and `unsigned` keys. One valid
+/// signature with a key known for the server is enough.
+pub fn verify_event(config: &Config, server_name: &str, event: &Map<String, Value>)
+-> Result<(), ApiError> {
+    let server_signatures = event.get("signatures")
+        .and_then(|signatures| signatures.get(server_name))
+        .and_then(Value::as_object);
+
+    let server_signatures = match server_signatures {
+        Some(server_signatures) => server_signatures,
+        None => Err(ApiError::unauthorized(
+            format!("The event is not signed by {}.", server_name)
+        ))?,
+    };
+
+    let mut signed_event = event.clone();
+    signed_event.remove("signatures");
+    signed_event.remove("unsigned");
+    let signed_event = Value::Object(signed_event);
+
+    for (key_id, signature) in server_signatures {
+        let public_key = match verify_key(config, server_name, key_id) {
+            Ok(public_key) => public_key,
+            Err(_) => continue,
+        };
+
+        let signature_bytes = match signature.as_str() {
+            Some(signature) => match decode(&pad_base64(signature)) {
+                Ok(signature_bytes) => signature_bytes,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+
+        let signature = match Signature::new(key_id, &signature_bytes) {
+            Ok(signature) => signature,
+            Err(_) => continue,
+        };
+
+        if verify_json(&Ed25519Verifier, &public_key, &signature, &signed_event).is_ok() {
+            return Ok(());
+        }
+    }
+
+    Err(ApiError::unauthorized(format!("The event has no valid signature of {}.", server_name)))
+}
+
+/// Looks up the public key with the given ID for a server.
+///
+/// Only the server's own keys, including its `old_verify_keys`, and those of the configured
+/// `known_servers` are known for now. Keys of other servers will be looked up once Ruma can fetch
+/// and cache them.
+fn verify_key(config: &Config, origin: &str, key_id: &str) -> Result<Vec<u8>, ApiError> {
+    let known_key = if origin == config.domain {
+        if let Some(ref signing_key) = config.signing_key {
+            if key_id == signing_key.key_id() {
+                return Ok(signing_key.public_key.clone());
+            }
+        }
+
+        config.old_verify_keys.get(key_id)
+    } else {
+        config.known_servers
+            .get(origin)
+            .and_then(|known_server| known_server.verify_keys.get(key_id))
+    };
+
+    if let Some(verify_key) = known_key {
+        return decode(&pad_base64(verify_key)).map_err(ApiError::from);
+    }
+
+    Err(ApiError::unauthorized_server(format!("Unknown signing key {} for {}.", key_id, origin)))
+}
+
+/// Restores the padding that Matrix strips from Base64 strings.
+pub fn pad_base64(unpadded: &str) -> String {
+    let mut padded = unpadded.to_string();
+
+    while padded.len() % 4 != 0 {
+        padded.push('=');
+    }
+
+    padded
+}
+
+/// The JSON object whose signature authenticates a federation request.
+fn request_json(
+    method: &Method,
+    uri: &str,
+    origin: &str,
+    destination: &str,
+    content: Option<&Value>,
+) -> Value {
+    let mut request = Map::new();
+
+    request.insert("method".to_string(), Value::String(method.to_string()));
+    request.insert("uri".to_string(), Value::String(uri.to_string()));
+    request.insert("origin".to_string(), Value::String(origin.to_string()));
+    request.insert("destination".to_string(), Value::String(destination.to_string()));
+
+    if let Some(content) = content {
+        request.insert("content".to_string(), content.clone());
+    }
+
+    Value::Object(request)
+}
+
+/// Signs outgoing federation requests with the server's signing key.
+///
+/// The signature covers the request's method, URI, origin, destination, and JSON body, and is
+/// sent in an `Authorization: X-Matrix origin=...,key="...",sig="..."` header.
+pub struct OutgoingFederationAuth {
+    key_pair: Ed25519KeyPair,
+    origin: String,
+}
+
+impl OutgoingFederationAuth {
+    /// Creates a new `OutgoingFederationAuth` for the given server name and signing key.
+    pub fn new(origin: &str, signing_key: &SigningKey) -> Result<Self, ApiError> {
+        Ok(OutgoingFederationAuth {
+            key_pair: signing_key.key_pair()?,
+            origin: origin.to_string(),
+        })
+    }
+
+    /// The name of the server the requests originate from.
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    /// Builds the value of the `Authorization` header for a request.
+    ///
+    /// `uri` is the path and query string of the request, e.g. `/_matrix/key/v2/server`.
+    pub fn authorization_header(
+        &self,
+        method: &Method,
+        uri: &str,
+        destination: &str,
+        content: Option<&Value>,
+    ) -> Result<String, ApiError> {
+        let signature = self.sign(method, uri, destination, content)?;
+
+        Ok(format!(
+            r#"X-Matrix origin={},key="{}",sig="{}""#,
+            self.origin,
+            signature.id(),
+            encode(signature.as_bytes()).trim_right_matches('='),
+        ))
+    }
+
+    /// Signs the canonical JSON representation of a request.
+    fn sign(&self, method: &Method, uri: &str, destination: &str, content: Option<&Value>)
+    -> Result<Signature, ApiError> {
+        let request = request_json(method, uri, &self.origin, destination, content);
+
+        sign_json(&self.key_pair, &request).map_err(ApiError::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::method::Method;
+    use serde_json::{Map, Value, from_str};
+
+    use crypto::SigningKey;
+    use federation::sender::sign_pdu;
+    use test::Test;
+    use super::{OutgoingFederationAuth, XMatrix, verify_event};
+
+    const SIGNING_KEY: &'static str =
+        "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8DoQe/884Qvh1w3RjnS8CZZ+TWMJulDV8d3IZkElUxuA==";
+
+    fn auth() -> OutgoingFederationAuth {
+        let signing_key = SigningKey::from_base64("1", SIGNING_KEY).unwrap();
+
+        OutgoingFederationAuth::new("ruma.test", &signing_key).unwrap()
+    }
+
+    #[test]
+    fn authorization_header_without_content() {
+        let header = auth().authorization_header(
+            &Method::Get,
+            "/_matrix/key/v2/server",
+            "example.com",
+            None,
+        ).unwrap();
+
+        assert_eq!(
+            header,
+            "X-Matrix origin=ruma.test,key=\"ed25519:1\",sig=\"IUpV/mvpNLHAl6YlNvjX95fq3kc4RMzmPPx3\
+            LIe3b9cNXPPcTLowLMhQfKRtsxMakKrVgw+IPOQ6IN+L3cf9DQ\""
+        );
+    }
+
+    #[test]
+    fn authorization_header_signs_content() {
+        let content = from_str(r#"{"pdus": []}"#).unwrap();
+
+        let header = auth().authorization_header(
+            &Method::Put,
+            "/_matrix/federation/v1/send/1",
+            "example.com",
+            Some(&content),
+        ).unwrap();
+
+        assert_eq!(
+            header,
+            "X-Matrix origin=ruma.test,key=\"ed25519:1\",sig=\"aO2pL+H+DFmsIVSfvbFu8oFGvMEuPu5/YAO3\
+            I7PeyGjhZXtBWT+u4knV136JL2p1KawtFWESzGelzKA1MQDpCw\""
+        );
+    }
+
+    #[test]
+    fn parse_x_matrix_header() {
+        let x_matrix = XMatrix::parse(r#"X-Matrix origin=example.com,key="ed25519:1",sig="abc""#);
+
+        assert_eq!(x_matrix, Some(XMatrix {
+            origin: "example.com".to_string(),
+            key: "ed25519:1".to_string(),
+            sig: "abc".to_string(),
+        }));
+    }
+
+    #[test]
+    fn parse_rejects_other_schemes() {
+        assert_eq!(XMatrix::parse("Bearer abc"), None);
+        assert_eq!(XMatrix::parse("X-Matrix origin=example.com"), None);
+    }
+
+    #[test]
+    fn events_need_a_valid_signature_of_the_server() {
+        let config = Test::config();
+        let signing_key = SigningKey::from_base64("1", SIGNING_KEY).unwrap();
+
+        let event: Map<String, Value> = from_str(
+            r#"{"type": "m.room.member", "content": {"membership": "leave"}}"#
+        ).unwrap();
+        let signed_event = sign_pdu("ruma.test", &signing_key, event).unwrap();
+        let mut signed_event = signed_event.as_object().unwrap().clone();
+
+        assert!(verify_event(&config, "ruma.test", &signed_event).is_ok());
+        assert!(verify_event(&config, "other.test", &signed_event).is_err());
+
+        signed_event.insert("content".to_string(), from_str(r#"{"membership": "join"}"#).unwrap());
+
+        assert!(verify_event(&config, "ruma.test", &signed_event).is_err());
+    }
+
+    #[test]
+    fn signatures_of_old_signing_keys_stay_valid() {
+        let old_signing_key = SigningKey::from_base64("1", SIGNING_KEY).unwrap();
+
+        let event: Map<String, Value> = from_str(
+            r#"{"type": "m.room.member", "content": {"membership": "leave"}}"#
+        ).unwrap();
+        let signed_event = sign_pdu("ruma.test", &old_signing_key, event).unwrap();
+        let signed_event = signed_event.as_object().unwrap().clone();
+
+        let mut config = Test::config();
+        config.signing_key = Some(SigningKey::generate("2").unwrap());
+
+        assert!(verify_event(&config, "ruma.test", &signed_event).is_err());
+
+        config.old_verify_keys.insert(old_signing_key.key_id(), old_signing_key.verify_key());
+
+        assert!(verify_event(&config, "ruma.test", &signed_event).is_ok());
+    }
+}
@@ -0,0 +1,166 @@
+//! Canonical JSON, the encoding of JSON that event hashes and signatures are computed over.
+//!
+//! Canonical JSON has no insignificant whitespace and sorts the keys of objects. Numbers have to
+//! be integers in the range every JSON implementation can represent exactly.
+
+use serde_json::{Value, to_string};
+
+use error::ApiError;
+
+/// The largest integer allowed in canonical JSON, 2^53 - 1. Its negation is the smallest one.
+pub const MAX_SAFE_INTEGER: i64 = 9007199254740991;
+
+/// Checks that the value only contains numbers allowed in canonical JSON.
+pub fn check(value: &Value) -> Result<(), ApiError> {
+    match *value {
+        Value::Number(ref number) => match number.as_i64() {
+            Some(integer) if integer >= -MAX_SAFE_INTEGER && integer <= MAX_SAFE_INTEGER => Ok(()),
+            _ => Err(ApiError::bad_json(
+                format!("The number {} is not allowed in canonical JSON.", number)
+            )),
+        },
+        Value::Array(ref values) => {
+            for value in values {
+                check(value)?;
+            }
+
+            Ok(())
+        }
+        Value::Object(ref object) => {
+            for value in object.values() {
+                check(value)?;
+            }
+
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Serializes the value as canonical JSON.
+///
+/// `Map` keeps its keys sorted, so serializing without whitespace is all there is to it. The
+/// numbers in the value aren't checked.
+pub fn to_canonical_json(value: &Value) -> Result<String, ApiError> {
+    to_string(value).map_err(ApiError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use quickcheck::{Arbitrary, Gen, TestResult};
+    use rand::Rng;
+    use serde_json::{Map, Value, from_str, to_string};
+
+    use test::{AnyInteger, check_property};
+    use super::{MAX_SAFE_INTEGER, check, to_canonical_json};
+
+    /// A JSON value with numbers allowed in canonical JSON.
+    #[derive(Clone, Debug)]
+    struct CanonicalValue(Value);
+
+    impl Arbitrary for CanonicalValue {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            CanonicalValue(arbitrary_value(g, 3))
+        }
+    }
+
+    fn arbitrary_value<G: Gen>(g: &mut G, depth: usize) -> Value {
+        let kinds = if depth == 0 { 4 } else { 6 };
+
+        match g.gen_range(0, kinds) {
+            0 => Value::Null,
+            1 => Value::Bool(g.gen()),
+            2 => Value::from(g.gen_range(-MAX_SAFE_INTEGER, MAX_SAFE_INTEGER + 1)),
+            3 => Value::String(String::arbitrary(g)),
+            4 => {
+                let length = g.gen_range(0, 5);
+
+                Value::Array((0..length).map(|_| arbitrary_value(g, depth - 1)).collect())
+            }
+            _ => {
+                let length = g.gen_range(0, 5);
+                let mut object = Map::new();
+
+                for _ in 0..length {
+                    object.insert(String::arbitrary(g), arbitrary_value(g, depth - 1));
+                }
+
+                Value::Object(object)
+            }
+        }
+    }
+
+    /// Serializes the value with the keys of objects explicitly sorted.
+    fn sorted_json(value: &Value) -> String {
+        match *value {
+            Value::Array(ref values) => {
+                let elements: Vec<String> = values.iter().map(sorted_json).collect();
+
+                format!("[{}]", elements.join(","))
+            }
+            Value::Object(ref object) => {
+                let mut keys: Vec<&String> = object.keys().collect();
+                keys.sort();
+
+                let members: Vec<String> = keys.into_iter().map(|key| {
+                    format!("{}:{}", to_string(key).unwrap(), sorted_json(&object[key]))
+                }).collect();
+
+                format!("{{{}}}", members.join(","))
+            }
+            _ => to_string(value).unwrap(),
+        }
+    }
+
+    #[test]
+    fn canonical_json_reparses_with_sorted_keys() {
+        fn property(value: CanonicalValue) -> TestResult {
+            if check(&value.0).is_err() {
+                return TestResult::failed();
+            }
+
+            let canonical_json = to_canonical_json(&value.0).unwrap();
+            let reparsed: Value = from_str(&canonical_json).unwrap();
+
+            TestResult::from_bool(reparsed == value.0 && canonical_json == sorted_json(&value.0))
+        }
+
+        check_property(property as fn(CanonicalValue) -> TestResult);
+    }
+
+    #[test]
+    fn integers_outside_the_safe_range_are_rejected() {
+        fn property(integer: AnyInteger) -> bool {
+            let integer = integer.0;
+            let allowed = integer >= -MAX_SAFE_INTEGER && integer <= MAX_SAFE_INTEGER;
+            let nested: Value = from_str(
+                &format!(r#"{{"content": {{"values": [{}]}}}}"#, integer)
+            ).unwrap();
+
+            check(&Value::from(integer)).is_ok() == allowed && check(&nested).is_ok() == allowed
+        }
+
+        check_property(property as fn(AnyInteger) -> bool);
+
+        assert!(check(&Value::from(MAX_SAFE_INTEGER)).is_ok());
+        assert!(check(&Value::from(MAX_SAFE_INTEGER + 1)).is_err());
+        assert!(check(&Value::from(-MAX_SAFE_INTEGER)).is_ok());
+        assert!(check(&Value::from(-MAX_SAFE_INTEGER - 1)).is_err());
+        assert!(check(&Value::from(u64::max_value())).is_err());
+    }
+
+    #[test]
+    fn floats_are_rejected() {
+        fn property(float: f64) -> TestResult {
+            let value = Value::from(float);
+
+            if !value.is_number() {
+                return TestResult::discard();
+            }
+
+            TestResult::from_bool(check(&value).is_err())
+        }
+
+        check_property(property as fn(f64) -> TestResult);
+    }
+}
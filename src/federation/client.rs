@@ -0,0 +1,202 @@
+//! HTTP client for outgoing federation requests.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::time::Duration;
+
+use hyper::Client;
+use hyper::header::{ContentType, Headers};
+use hyper::net::HttpsConnector;
+use hyper::status::StatusCode;
+use hyper_native_tls::NativeTlsClient;
+use iron::method::Method;
+use serde_json::{Value, from_str, to_string};
+
+use config::Config;
+use error::{ApiError, MapApiError};
+use federation::auth::OutgoingFederationAuth;
+
+/// The largest response body, in bytes, read from another server.
+const MAX_RESPONSE_SIZE: u64 = 50 * 1024 * 1024;
+
+/// Makes HTTP requests to other homeservers, signing each one with `OutgoingFederationAuth`.
+pub struct FederationHttpClient {
+    auth: OutgoingFederationAuth,
+    base_urls: HashMap<String, String>,
+    client: Client,
+}
+
+impl FederationHttpClient {
+    /// Creates a new `FederationHttpClient`.
+    ///
+    /// The `Client` should be configured with a TLS connector for the `https` scheme.
+    pub fn new(client: Client, auth: OutgoingFederationAuth) -> Self {
+        FederationHttpClient {
+            auth: auth,
+            base_urls: HashMap::new(),
+            client: client,
+        }
+    }
+
+    /// Creates a `FederationHttpClient` that connects over TLS and signs with the configured key.
+    pub fn from_config(config: &Config) -> Result<Self, ApiError> {
+        let signing_key = match config.signing_key {
+            Some(ref signing_key) => signing_key,
+            None => Err(ApiError::unavailable(
+                "Federation requests can't be made without a signing key.".to_string()
+            ))?,
+        };
+
+        let tls = NativeTlsClient::new().map_api_err(|_| {
+            ApiError::unknown("Failed to set up TLS for federation.".to_string())
+        })?;
+
+        let mut client = FederationHttpClient::new(
+            Client::with_connector(HttpsConnector::new(tls)),
+            OutgoingFederationAuth::new(&config.domain, signing_key)?,
+        );
+
+        for (server_name, known_server) in &config.known_servers {
+            client.set_base_url(server_name, &known_server.base_url);
+        }
+
+        Ok(client)
+    }
+
+    /// Sends requests for the given server to `base_url`, e.g. `http://127.0.0.1:8448`, instead
+    /// of `https://` followed by the server name.
+    pub fn set_base_url(&mut self, server_name: &str, base_url: &str) {
+        self.base_urls.insert(
+            server_name.to_string(),
+            base_url.trim_right_matches('/').to_string(),
+        );
+    }
+
+    /// Gives up on requests whose server takes longer than `timeout` to accept or answer them.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.client.set_read_timeout(Some(timeout));
+        self.client.set_write_timeout(Some(timeout));
+    }
+
+    /// Sends a signed request to `path` on the `destination` server and returns the JSON response.
+    ///
+    /// A 404 `M_NOT_FOUND` error of the other server is returned as `M_NOT_FOUND`, other failures
+    /// as `M_UNKNOWN`.
+    pub fn request(
+        &self,
+        method: Method,
+        destination: &str,
+        path: &str,
+        content: Option<&Value>,
+    ) -> Result<Value, ApiError> {
+        let authorization = self.auth.authorization_header(&method, path, destination, content)?;
+        let body = match content {
+            Some(content) => to_string(content).map_err(ApiError::from)?,
+            None => String::new(),
+        };
+
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        headers.set_raw("Authorization", vec![authorization.into_bytes()]);
+
+        let url = match self.base_urls.get(destination) {
+            Some(base_url) => format!("{}{}", base_url, path),
+            None => format!("https://{}{}", destination, path),
+        };
+
+        let mut response = self.client.request(method, &url)
+            .headers(headers)
+            .body(&body[..])
+            .send()
+            .map_api_err(|_| {
+                ApiError::unknown(format!("Failed to send federation request to {}.", destination))
+            })?;
+
+        let response_body = read_response(&mut response, destination, MAX_RESPONSE_SIZE)?;
+
+        if !response.status.is_success() {
+            return Err(remote_error(destination, response.status, &response_body));
+        }
+
+        from_str(&response_body).map_err(ApiError::from)
+    }
+}
+
+/// Reads a response body, failing once it is larger than `max_size` so that another server can't
+/// make this one buffer an endless response.
+fn read_response<R>(response: R, destination: &str, max_size: u64) -> Result<String, ApiError>
+where R: Read {
+    let mut body = String::new();
+    response.take(max_size + 1).read_to_string(&mut body).map_err(ApiError::from)?;
+
+    if body.len() as u64 > max_size {
+        return Err(ApiError::unknown(
+            format!("The response of {} is larger than {} bytes.", destination, max_size)
+        ));
+    }
+
+    Ok(body)
+}
+
+/// The error for a failed response from another server.
+fn remote_error(destination: &str, status: StatusCode, body: &str) -> ApiError {
+    let errcode = from_str::<Value>(body).ok().and_then(|body| {
+        body.get("errcode").and_then(Value::as_str).map(str::to_string)
+    });
+
+    match (status, errcode) {
+        (StatusCode::NotFound, Some(ref errcode)) if errcode == "M_NOT_FOUND" => {
+            ApiError::not_found(format!("{} did not find the requested resource.", destination))
+        }
+        _ => ApiError::unknown(format!(
+            "Federation request to {} failed with status {}.",
+            destination,
+            status,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::status::StatusCode;
+    use serde_json::to_value;
+
+    use error::ApiError;
+    use super::{read_response, remote_error};
+
+    fn errcode(error: &ApiError) -> String {
+        to_value(error).unwrap().get("errcode").unwrap().as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn remote_not_found_is_kept() {
+        let error = remote_error(
+            "example.com",
+            StatusCode::NotFound,
+            r#"{"errcode":"M_NOT_FOUND","error":"Room alias not found."}"#,
+        );
+
+        assert_eq!(errcode(&error), "M_NOT_FOUND");
+    }
+
+    #[test]
+    fn other_remote_errors_are_unknown() {
+        let error = remote_error("example.com", StatusCode::NotFound, "Not Found");
+        assert_eq!(errcode(&error), "M_UNKNOWN");
+
+        let error = remote_error(
+            "example.com",
+            StatusCode::InternalServerError,
+            r#"{"errcode":"M_NOT_FOUND"}"#,
+        );
+        assert_eq!(errcode(&error), "M_UNKNOWN");
+    }
+
+    #[test]
+    fn responses_are_read_up_to_the_limit() {
+        assert_eq!(read_response(&b"{}"[..], "example.com", 2).unwrap(), "{}");
+
+        let error = read_response(&b"{} "[..], "example.com", 2).unwrap_err();
+        assert_eq!(errcode(&error), "M_UNKNOWN");
+    }
+}
@@ -0,0 +1,150 @@
+//! Discovery of the aliases other servers have for a room.
+//!
+//! The directory query of the server-server API resolves an alias to a room. Ruma also answers it
+//! in reverse: given a `room_id`, `/_matrix/federation/v1/query/directory` returns the server's
+//! aliases for the room. A server answering a reverse query may ask the other servers in the room
+//! in turn, so the query carries its `depth`, and `max_alias_resolution_depth` ends the recursion.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use diesel::pg::PgConnection;
+use iron::method::Method;
+use ruma_identifiers::{RoomAliasId, RoomId};
+use serde_json::from_value;
+use url::form_urlencoded::Serializer;
+
+use config::Config;
+use error::ApiError;
+use federation::client::FederationHttpClient;
+use federation::sender::participating_servers;
+use models::remote_alias::RemoteAlias;
+use models::room_alias::RoomAlias;
+
+/// The highest `max_alias_resolution_depth` that can be configured.
+pub const MAX_ALIAS_RESOLUTION_DEPTH: u64 = 5;
+
+/// The response of another server to a directory query.
+#[derive(Debug, Deserialize)]
+struct DirectoryQueryResponse {
+    /// The room ID associated with the room alias.
+    room_id: RoomId,
+    /// A list of servers that are aware of this room ID.
+    servers: Vec<String>,
+}
+
+/// The response of another server to a reverse directory query.
+#[derive(Debug, Deserialize)]
+struct ReverseDirectoryQueryResponse {
+    /// The server's aliases for the room.
+    aliases: Vec<RoomAliasId>,
+}
+
+/// Looks up an alias of another server in the cache, or asks its server if it isn't cached.
+///
+/// With a `timeout`, the server is given up on if it takes longer to answer.
+pub fn resolve_remote_alias(
+    connection: &PgConnection,
+    config: &Config,
+    room_alias_id: &RoomAliasId,
+    timeout: Option<Duration>,
+) -> Result<RemoteAlias, ApiError> {
+    if let Some(remote_alias) = RemoteAlias::find_fresh(connection, room_alias_id)? {
+        return Ok(remote_alias);
+    }
+
+    let destination = room_alias_id.hostname().to_string();
+    let query = Serializer::new(String::new())
+        .append_pair("room_alias", &room_alias_id.to_string())
+        .finish();
+    let path = format!("/_matrix/federation/v1/query/directory?{}", query);
+
+    let mut client = FederationHttpClient::from_config(config)?;
+
+    if let Some(timeout) = timeout {
+        client.set_timeout(timeout);
+    }
+
+    let response = client.request(Method::Get, &destination, &path, None)?;
+    let response: DirectoryQueryResponse = from_value(response).map_err(ApiError::from)?;
+
+    RemoteAlias::store(
+        connection,
+        room_alias_id,
+        &response.room_id,
+        response.servers,
+        Duration::from_secs(config.remote_alias_cache_ttl),
+    )
+}
+
+/// Returns the aliases of the room, both of this server and of the other servers in the room.
+///
+/// `depth` is the number of hops the lookup already is away from the server that started it.
+/// Other servers are only asked while it is below `max_alias_resolution_depth`, and never the
+/// ones in `skip`. Servers that can't be reached or give an invalid answer are left out.
+pub fn find_room_aliases(
+    connection: &PgConnection,
+    config: &Config,
+    room_id: &RoomId,
+    depth: u64,
+    skip: &HashSet<String>,
+) -> Result<Vec<RoomAliasId>, ApiError> {
+    let mut aliases: Vec<RoomAliasId> = RoomAlias::find_by_room_id(connection, room_id)?
+        .into_iter()
+        .map(|room_alias| room_alias.alias)
+        .collect();
+
+    if depth >= config.max_alias_resolution_depth {
+        return Ok(aliases);
+    }
+
+    let mut servers: Vec<String> = participating_servers(connection, room_id)?
+        .into_iter()
+        .filter(|server| *server != config.domain && !skip.contains(server))
+        .collect();
+
+    if servers.is_empty() {
+        return Ok(aliases);
+    }
+
+    servers.sort();
+
+    let client = match FederationHttpClient::from_config(config) {
+        Ok(client) => client,
+        Err(error) => {
+            warn!("Not asking other servers for the aliases of {}: {}", room_id, error);
+
+            return Ok(aliases);
+        }
+    };
+
+    let query = Serializer::new(String::new())
+        .append_pair("room_id", &room_id.to_string())
+        .append_pair("depth", &(depth + 1).to_string())
+        .finish();
+    let path = format!("/_matrix/federation/v1/query/directory?{}", query);
+
+    let mut seen: HashSet<String> = aliases.iter().map(|alias| alias.to_string()).collect();
+
+    for server in &servers {
+        let response = client.request(Method::Get, server, &path, None)
+            .and_then(|response| from_value(response).map_err(ApiError::from));
+
+        let response: ReverseDirectoryQueryResponse = match response {
+            Ok(response) => response,
+            Err(error) => {
+                debug!("Failed to get the aliases of {} from {}: {}", room_id, server, error);
+
+                continue;
+            }
+        };
+
+        for alias in response.aliases {
+            if seen.insert(alias.to_string()) {
+                aliases.push(alias);
+            }
+        }
+    }
+
+    Ok(aliases)
+}
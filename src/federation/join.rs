@@ -0,0 +1,221 @@
+//! Joining rooms through other servers.
+//!
+//! A server that isn't in a room can only join it through a server that is. Clients name the
+//! servers to try with the `via` parameter of the join endpoints, e.g. the servers a room alias
+//! resolved to. They are asked in order, and the first one that answers `make_join` is used.
+
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use iron::method::Method;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::Value;
+use url::percent_encoding::{PATH_SEGMENT_ENCODE_SET, utf8_percent_encode};
+
+use config::Config;
+use error::ApiError;
+use federation::client::FederationHttpClient;
+
+/// The longest allowed host name.
+const MAX_HOSTNAME_LENGTH: usize = 255;
+
+/// A join event template from another server's `make_join` endpoint.
+#[derive(Debug)]
+pub struct JoinTemplate {
+    /// The server that made the template.
+    pub server: String,
+    /// The unsigned join event.
+    pub event: Value,
+}
+
+/// Whether or not the string is a valid server name: a host name, an IPv4 address or a
+/// bracketed IPv6 address, optionally followed by `:` and a port.
+pub fn is_valid_server_name(server_name: &str) -> bool {
+    let (host, port) = if server_name.starts_with('[') {
+        match server_name.find(']') {
+            Some(end) => (&server_name[..end + 1], &server_name[end + 1..]),
+            None => return false,
+        }
+    } else {
+        match server_name.find(':') {
+            Some(colon) => (&server_name[..colon], &server_name[colon..]),
+            None => (server_name, ""),
+        }
+    };
+
+    let is_valid_port = port.is_empty() || port.starts_with(':') && {
+        let digits = &port[1..];
+
+        !digits.is_empty() && !digits.starts_with('0') &&
+            digits.chars().all(|c| c.is_digit(10)) && digits.parse::<u16>().is_ok()
+    };
+
+    is_valid_port && is_valid_host(host)
+}
+
+/// Whether or not the string is a host name, an IPv4 address or a bracketed IPv6 address.
+fn is_valid_host(host: &str) -> bool {
+    if host.starts_with('[') && host.ends_with(']') {
+        return host[1..host.len() - 1].parse::<Ipv6Addr>().is_ok();
+    }
+
+    if host.parse::<Ipv4Addr>().is_ok() {
+        return true;
+    }
+
+    let is_valid_label = |label: &str| {
+        !label.is_empty() && label.len() <= 63 && !label.starts_with('-') &&
+            !label.ends_with('-') && label.chars().all(|c| match c {
+                'a'...'z' | 'A'...'Z' | '0'...'9' | '-' => true,
+                _ => false,
+            })
+    };
+
+    host.len() <= MAX_HOSTNAME_LENGTH && host.split('.').all(is_valid_label)
+}
+
+/// Returns the servers to join the room through, in the order they should be tried.
+///
+/// These are the `via` servers without duplicates and this server, or the server of the room ID
+/// if none were given. Invalid server names are rejected with `M_INVALID_PARAM`.
+pub fn join_servers(config: &Config, room_id: &RoomId, via: &[String])
+-> Result<Vec<String>, ApiError> {
+    if let Some(server) = via.iter().find(|server| !is_valid_server_name(server)) {
+        let message = format!("{} is not a valid server name.", server);
+
+        return Err(ApiError::invalid_param("via", &message));
+    }
+
+    let mut seen = HashSet::new();
+    let mut servers: Vec<String> = via.iter()
+        .filter(|server| **server != config.domain && seen.insert(server.to_string()))
+        .cloned()
+        .collect();
+
+    let room_server = room_id.hostname().to_string();
+
+    if servers.is_empty() && room_server != config.domain {
+        servers.push(room_server);
+    }
+
+    Ok(servers)
+}
+
+/// Asks the servers in order for a template of the user's join event.
+///
+/// Returns the template of the first server that answers. If none do, the last failure is
+/// returned.
+pub fn make_join(config: &Config, room_id: &RoomId, user_id: &UserId, servers: &[String])
+-> Result<JoinTemplate, ApiError> {
+    let client = FederationHttpClient::from_config(config)?;
+
+    let path = format!(
+        "/_matrix/federation/v1/make_join/{}/{}",
+        utf8_percent_encode(&room_id.to_string(), PATH_SEGMENT_ENCODE_SET),
+        utf8_percent_encode(&user_id.to_string(), PATH_SEGMENT_ENCODE_SET),
+    );
+
+    first_accepting(servers, |server| {
+        let response = client.request(Method::Get, server, &path, None)?;
+
+        match response.get("event") {
+            Some(event) if event.is_object() => Ok(event.clone()),
+            _ => Err(ApiError::unknown(format!("{} sent an invalid join template.", server))),
+        }
+    }).map(|(server, event)| {
+        JoinTemplate {
+            server: server,
+            event: event,
+        }
+    })
+}
+
+/// Tries `attempt` on each server in order, returning the first server it succeeds for.
+fn first_accepting<T, F>(servers: &[String], attempt: F) -> Result<(String, T), ApiError>
+where F: Fn(&str) -> Result<T, ApiError> {
+    let mut last_error = None;
+
+    for server in servers {
+        match attempt(server) {
+            Ok(result) => return Ok((server.clone(), result)),
+            Err(error) => {
+                debug!("{} did not accept the join: {}", server, error);
+
+                last_error = Some(error);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        ApiError::not_found("There are no servers to join the room through.".to_string())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use error::ApiError;
+    use super::{first_accepting, is_valid_server_name};
+
+    #[test]
+    fn server_names() {
+        for server_name in &[
+            "ruma.test",
+            "ruma.test:8448",
+            "localhost",
+            "matrix-1.example.com",
+            "192.168.1.1:443",
+            "[::1]",
+            "[2001:db8::1]:8448",
+        ] {
+            assert!(is_valid_server_name(server_name), "{}", server_name);
+        }
+
+        for server_name in &[
+            "",
+            "ruma.test:",
+            "ruma.test:0",
+            "ruma.test:65536",
+            "ruma.test:08448",
+            "ruma.test:port",
+            "-ruma.test",
+            "ruma..test",
+            "ruma_test",
+            "https://ruma.test",
+            "[::1",
+            "[ruma.test]",
+            "::1",
+        ] {
+            assert!(!is_valid_server_name(server_name), "{}", server_name);
+        }
+    }
+
+    #[test]
+    fn servers_are_tried_in_order() {
+        let servers = vec!["a.test".to_string(), "b.test".to_string(), "c.test".to_string()];
+        let tried = RefCell::new(Vec::new());
+
+        let (server, result) = first_accepting(&servers, |server| {
+            tried.borrow_mut().push(server.to_string());
+
+            if server == "a.test" {
+                Err(ApiError::unknown(None))
+            } else {
+                Ok(server.len())
+            }
+        }).unwrap();
+
+        assert_eq!(server, "b.test");
+        assert_eq!(result, 6);
+        assert_eq!(*tried.borrow(), vec!["a.test", "b.test"]);
+    }
+
+    #[test]
+    fn last_error_is_returned() {
+        let servers = vec!["a.test".to_string()];
+
+        assert!(first_accepting(&servers, |_| Err::<(), _>(ApiError::unknown(None))).is_err());
+        assert!(first_accepting(&[], |_| Ok(())).is_err());
+    }
+}
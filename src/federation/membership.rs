@@ -0,0 +1,161 @@
+//! Membership events of users of other servers about themselves.
+//!
+//! Leaving and knocking take two steps: the user's server asks for a template of the membership
+//! event with `make_leave` or `make_knock`, signs it, and sends it back with `send_leave` or
+//! `send_knock`.
+
+use std::convert::TryFrom;
+
+use diesel::pg::PgConnection;
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde_json::{Map, Value, to_string};
+
+use clock::Clock;
+use config::Config;
+use error::ApiError;
+use federation::auth::verify_event;
+use models::event::NewEvent;
+use models::event_batch::EventBatch;
+use models::room_membership::{NewRoomMembership, RoomMembership};
+use state_cache::StateCache;
+
+/// A membership event about oneself without its event ID, hashes and signatures.
+///
+/// Auth events and `prev_events` are not tracked yet. The event is placed after the room's latest
+/// events when it is sent back regardless.
+#[derive(Debug, Serialize)]
+pub struct MembershipEventTemplate {
+    /// The content of the event.
+    content: MembershipEventContent,
+    /// The server that made the template.
+    origin: String,
+    /// The time the template was made, in milliseconds since the Unix epoch.
+    origin_server_ts: u64,
+    /// The room of the membership.
+    room_id: String,
+    /// The user whose membership changes.
+    sender: String,
+    /// The user whose membership changes, as for every membership event about oneself.
+    state_key: String,
+    /// Always `m.room.member`.
+    #[serde(rename = "type")]
+    event_type: String,
+}
+
+#[derive(Debug, Serialize)]
+struct MembershipEventContent {
+    /// The new membership of the user.
+    membership: String,
+}
+
+impl MembershipEventTemplate {
+    /// Makes the template of the event of `user_id` changing their membership in the room.
+    pub fn new(
+        origin: &str,
+        origin_server_ts: u64,
+        room_id: &RoomId,
+        user_id: &UserId,
+        membership: &str,
+    ) -> Self {
+        MembershipEventTemplate {
+            content: MembershipEventContent {
+                membership: membership.to_string(),
+            },
+            origin: origin.to_string(),
+            origin_server_ts: origin_server_ts,
+            room_id: room_id.to_string(),
+            sender: user_id.to_string(),
+            state_key: user_id.to_string(),
+            event_type: "m.room.member".to_string(),
+        }
+    }
+}
+
+/// Checks that an event sent by `origin` is a membership event of one of its users about
+/// themselves with the given membership and is signed by `origin`, and converts it into a new
+/// event that keeps the signed PDU as it was received.
+pub fn membership_event(
+    config: &Config,
+    origin: &str,
+    room_id: &RoomId,
+    event_id: &EventId,
+    event: &Map<String, Value>,
+    membership: &str,
+) -> Result<NewEvent, ApiError> {
+    let field = |key: &str| event.get(key).and_then(Value::as_str);
+
+    if field("type") != Some("m.room.member") {
+        Err(ApiError::bad_json("The event is not a membership event.".to_string()))?;
+    }
+
+    if field("event_id") != Some(&event_id.to_string()[..]) ||
+        field("room_id") != Some(&room_id.to_string()[..]) {
+        Err(ApiError::bad_json("The event ID and room ID must match the path.".to_string()))?;
+    }
+
+    if event_id.hostname().to_string() != origin {
+        Err(ApiError::bad_json(format!("The event ID {} was not made by {}.", event_id, origin)))?;
+    }
+
+    let sender = match field("sender").map(|sender| UserId::try_from(sender)) {
+        Some(Ok(sender)) => sender,
+        _ => Err(ApiError::bad_json("The event has no valid sender.".to_string()))?,
+    };
+
+    if field("state_key") != Some(&sender.to_string()[..]) {
+        Err(ApiError::bad_json(
+            format!("Users can only change their own membership to {}.", membership)
+        ))?;
+    }
+
+    let content = match event.get("content") {
+        Some(content) if content.get("membership").and_then(Value::as_str) == Some(membership) => {
+            content
+        }
+        _ => Err(ApiError::bad_json(format!("The event's membership is not {}.", membership)))?,
+    };
+
+    verify_event(config, origin, event)?;
+
+    Ok(NewEvent {
+        event_type: "m.room.member".to_string(),
+        extra_content: None,
+        id: event_id.clone(),
+        content: to_string(content).map_err(ApiError::from)?,
+        room_id: room_id.clone(),
+        state_key: Some(sender.to_string()),
+        user_id: sender,
+        pdu: Some(to_string(event).map_err(ApiError::from)?),
+    })
+}
+
+/// Applies a membership event made by `membership_event` to the room's state, and sends its PDU
+/// on to the other servers in the room, except the one it came from.
+pub fn apply_membership_event(
+    connection: &PgConnection,
+    state_cache: &StateCache,
+    clock: &Clock,
+    config: &Config,
+    event: NewEvent,
+    membership: &str,
+) -> Result<(), ApiError> {
+    let new_membership = NewRoomMembership {
+        event_id: event.id.clone(),
+        room_id: event.room_id.clone(),
+        user_id: event.user_id.clone(),
+        sender: event.user_id.clone(),
+        membership: membership.to_string(),
+    };
+
+    let mut batch = EventBatch::new();
+
+    if RoomMembership::find(connection, &event.room_id, &event.user_id)?.is_some() {
+        batch.update_membership(event, new_membership);
+    } else {
+        batch.add_membership(event, new_membership);
+    }
+
+    batch.commit(connection, state_cache, clock, config)?;
+
+    Ok(())
+}
@@ -0,0 +1,10 @@
+//! Support for the Matrix server-server API.
+
+pub mod auth;
+pub mod canonical_json;
+pub mod client;
+pub mod directory;
+pub mod join;
+pub mod membership;
+pub mod profile;
+pub mod sender;
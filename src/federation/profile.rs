@@ -0,0 +1,77 @@
+//! Profiles of users of other servers.
+
+use std::time::Duration;
+
+use diesel::pg::PgConnection;
+use iron::method::Method;
+use ruma_identifiers::UserId;
+use serde_json::from_value;
+use url::form_urlencoded::Serializer;
+
+use clock::Clock;
+use config::Config;
+use error::ApiError;
+use federation::client::FederationHttpClient;
+use models::remote_profile::RemoteProfile;
+
+/// The response of another server to a profile query.
+#[derive(Debug, Deserialize)]
+struct ProfileQueryResponse {
+    /// The user's avatar URL, if they have set one.
+    avatar_url: Option<String>,
+    /// The user's display name, if they have set one.
+    displayname: Option<String>,
+}
+
+/// Looks up the profile of a user of another server in the cache, or asks their server if it
+/// isn't cached or has expired.
+///
+/// If the server can't be reached or gives an invalid answer, the last cached profile is used,
+/// however old it is. Whether it has expired is told by the clock.
+pub fn fetch_remote_profile(
+    connection: &PgConnection,
+    config: &Config,
+    clock: &Clock,
+    user_id: &UserId,
+) -> Result<RemoteProfile, ApiError> {
+    let cached_profile = RemoteProfile::find(connection, user_id)?;
+
+    if let Some(ref cached_profile) = cached_profile {
+        if cached_profile.is_fresh(clock) {
+            return Ok(cached_profile.clone());
+        }
+    }
+
+    let response = query_profile(config, user_id);
+
+    match (response, cached_profile) {
+        (Ok(response), _) => RemoteProfile::store(
+            connection,
+            clock,
+            user_id,
+            response.avatar_url,
+            response.displayname,
+            Duration::from_secs(config.remote_profile_cache_ttl),
+        ),
+        (Err(error), Some(cached_profile)) => {
+            debug!("Using the cached profile of {}: {}", user_id, error);
+
+            Ok(cached_profile)
+        }
+        (Err(error), None) => Err(error),
+    }
+}
+
+/// Asks the user's server for their profile.
+fn query_profile(config: &Config, user_id: &UserId) -> Result<ProfileQueryResponse, ApiError> {
+    let destination = user_id.hostname().to_string();
+    let query = Serializer::new(String::new())
+        .append_pair("user_id", &user_id.to_string())
+        .finish();
+    let path = format!("/_matrix/federation/v1/query/profile?{}", query);
+
+    let client = FederationHttpClient::from_config(config)?;
+    let response = client.request(Method::Get, &destination, &path, None)?;
+
+    from_value(response).map_err(ApiError::from)
+}
@@ -0,0 +1,460 @@
+//! Delivery of local events to the other servers participating in a room.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+use base64::encode;
+use diesel::{ExpressionMethods, FilterDsl, LoadDsl, SelectDsl};
+use diesel::pg::PgConnection;
+use iron::method::Method;
+use rand::{Rng, thread_rng};
+use ring::digest::{SHA256, digest};
+use ruma_identifiers::{EventId, RoomId, UserId};
+use ruma_signatures::sign_json;
+use serde_json::{Map, Value, from_str};
+
+use api::r0::milliseconds_since_epoch;
+use clock::Clock;
+use config::Config;
+use crypto::SigningKey;
+use error::ApiError;
+use federation::canonical_json::to_canonical_json;
+use federation::client::FederationHttpClient;
+use jobs::JobRegistry;
+use models::background_job::Job;
+use models::event::{Event, NewEvent};
+use schema::room_memberships;
+
+/// The kind of background job that delivers an event to another server.
+pub const SEND_EVENT_JOB: &'static str = "federation.send_event";
+
+/// The number of events queued for a server after which further events for it are dropped, unless
+/// configured otherwise.
+pub const DEFAULT_MAX_QUEUE_DEPTH: usize = 1000;
+
+/// The number of deliveries dropped because their destination's queue was full.
+static DROPPED_DELIVERIES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Queues delivery of local events to every other server with members in their rooms.
+///
+/// The servers of joined members receive the events, as do the servers of the users membership
+/// events are about, so that kicked, banned, and unbanned users' servers learn about the change.
+/// The servers participating in each room are only looked up once. The events are signed by the
+/// background job right before they are sent. Events received from another server are sent as
+/// they were signed by it, and never back to it.
+///
+/// Deliveries to servers that already have `max_queue_depth_per_server` events waiting are
+/// dropped, so that an unreachable server can't make the queue grow without bounds.
+///
+/// Returns the number of deliveries that were queued.
+pub fn federate_events(
+    connection: &PgConnection,
+    config: &Config,
+    clock: &Clock,
+    events: &[NewEvent],
+) -> Result<usize, ApiError> {
+    queue_events(connection, clock, &config.domain, events, config.max_queue_depth_per_server)
+}
+
+/// The number of deliveries dropped since the server started because the queue of their
+/// destination was full.
+pub fn dropped_deliveries() -> usize {
+    DROPPED_DELIVERIES.load(Ordering::Relaxed)
+}
+
+/// Queues delivery of the events, with at most `max_queue_depth` events waiting per server.
+fn queue_events(
+    connection: &PgConnection,
+    clock: &Clock,
+    homeserver_domain: &str,
+    events: &[NewEvent],
+    max_queue_depth: usize,
+) -> Result<usize, ApiError> {
+    let mut servers_by_room: HashMap<RoomId, HashSet<String>> = HashMap::new();
+    let mut queue_depths: HashMap<String, usize> = HashMap::new();
+    let mut queued = 0;
+
+    for event in events {
+        if !servers_by_room.contains_key(&event.room_id) {
+            let servers = participating_servers(connection, &event.room_id)?;
+
+            servers_by_room.insert(event.room_id.clone(), servers);
+        }
+
+        let mut destinations = servers_by_room[&event.room_id].clone();
+
+        if event.event_type == "m.room.member" {
+            if let Some(ref state_key) = event.state_key {
+                if let Ok(user_id) = UserId::try_from(state_key.as_ref()) {
+                    destinations.insert(user_id.hostname().to_string());
+                }
+            }
+        }
+
+        destinations.remove(homeserver_domain);
+
+        if event.pdu.is_some() {
+            destinations.remove(&event.user_id.hostname().to_string());
+        }
+
+        for destination in &destinations {
+            if !queue_depths.contains_key(destination) {
+                let queue_depth = Job::count_queued_with(
+                    connection,
+                    SEND_EVENT_JOB,
+                    "destination",
+                    destination,
+                )?;
+
+                queue_depths.insert(destination.clone(), queue_depth);
+            }
+
+            let queue_depth = queue_depths.get_mut(destination)
+                .expect("The queue depth should have been looked up");
+
+            if *queue_depth >= max_queue_depth {
+                warn!(
+                    "Dropping event {} for {}, which already has {} events queued.",
+                    event.id,
+                    destination,
+                    queue_depth
+                );
+
+                DROPPED_DELIVERIES.fetch_add(1, Ordering::Relaxed);
+
+                continue;
+            }
+
+            let txn_id: String = thread_rng().gen_ascii_chars().take(16).collect();
+
+            let mut payload = Map::new();
+            payload.insert("destination".to_string(), Value::String(destination.clone()));
+            payload.insert("event_id".to_string(), Value::String(event.id.to_string()));
+            payload.insert("txn_id".to_string(), Value::String(txn_id));
+
+            Job::enqueue(connection, SEND_EVENT_JOB, &Value::Object(payload), clock.now())?;
+
+            *queue_depth += 1;
+            queued += 1;
+        }
+    }
+
+    Ok(queued)
+}
+
+/// Registers the job that delivers queued events.
+pub fn register_jobs(registry: &mut JobRegistry, config: &Config, clock: Arc<Clock>) {
+    let config = config.clone();
+
+    registry.register(SEND_EVENT_JOB, move |connection, payload| {
+        send_event(connection, &config, &*clock, payload)
+    });
+}
+
+/// The names of the servers with joined members in a room.
+pub fn participating_servers(connection: &PgConnection, room_id: &RoomId)
+-> Result<HashSet<String>, ApiError> {
+    let user_ids: Vec<UserId> = room_memberships::table
+        .filter(room_memberships::room_id.eq(room_id))
+        .filter(room_memberships::membership.eq("join"))
+        .select(room_memberships::user_id)
+        .get_results(connection)
+        .map_err(ApiError::from)?;
+
+    Ok(user_ids.iter().map(|user_id| user_id.hostname().to_string()).collect())
+}
+
+/// Signs a queued event and sends it to its destination in a transaction of its own.
+fn send_event(connection: &PgConnection, config: &Config, clock: &Clock, payload: &Value)
+-> Result<(), ApiError> {
+    let field = |name: &str| {
+        payload.get(name).and_then(Value::as_str).map(|value| value.to_string()).ok_or_else(|| {
+            ApiError::unknown(format!("The job payload is missing {}.", name))
+        })
+    };
+
+    let destination = field("destination")?;
+    let txn_id = field("txn_id")?;
+    let event_id = EventId::try_from(field("event_id")?.as_ref()).map_err(ApiError::from)?;
+
+    let event = match Event::find(connection, &event_id)? {
+        Some(event) => event,
+        None => {
+            debug!("Not sending purged event {} to {}.", event_id, destination);
+
+            return Ok(());
+        }
+    };
+
+    let signing_key = match config.signing_key {
+        Some(ref signing_key) => signing_key,
+        None => Err(ApiError::unavailable(
+            "Events can't be federated without a signing key.".to_string()
+        ))?,
+    };
+
+    let pdu = signed_pdu(connection, &config.domain, signing_key, &event)?;
+
+    let client = FederationHttpClient::from_config(config)?;
+
+    let mut transaction = Map::new();
+    transaction.insert("origin".to_string(), Value::String(config.domain.clone()));
+    transaction.insert(
+        "origin_server_ts".to_string(),
+        Value::from(milliseconds_since_epoch(clock.now())?),
+    );
+    transaction.insert("pdus".to_string(), Value::Array(vec![pdu]));
+
+    client.request(
+        Method::Put,
+        &destination,
+        &format!("/_matrix/federation/v1/send/{}", txn_id),
+        Some(&Value::Object(transaction)),
+    )?;
+
+    Ok(())
+}
+
+/// The federation representation of an event, with its content hash and signed with the server's
+/// signing key. Events received from another server are returned as the PDU it signed.
+///
+/// Auth events are not tracked yet, so `auth_events` is always empty.
+pub fn signed_pdu(connection: &PgConnection, origin: &str, signing_key: &SigningKey, event: &Event)
+-> Result<Value, ApiError> {
+    if let Some(ref pdu) = event.pdu {
+        return from_str(pdu).map_err(ApiError::from);
+    }
+
+    let prev_events = Event::find_prev_event_ids(connection, &event.id)?
+        .into_iter()
+        .map(|prev_event_id| {
+            Value::Array(vec![Value::String(prev_event_id.to_string()), Value::Object(Map::new())])
+        })
+        .collect();
+
+    let mut pdu = Map::new();
+    pdu.insert("auth_events".to_string(), Value::Array(Vec::new()));
+    pdu.insert("content".to_string(), from_str(&event.content).map_err(ApiError::from)?);
+    pdu.insert("depth".to_string(), Value::from(event.depth));
+    pdu.insert("event_id".to_string(), Value::String(event.id.to_string()));
+    pdu.insert("origin".to_string(), Value::String(origin.to_string()));
+    pdu.insert(
+        "origin_server_ts".to_string(),
+        Value::from(event.origin_server_ts()),
+    );
+    pdu.insert("prev_events".to_string(), Value::Array(prev_events));
+    pdu.insert("room_id".to_string(), Value::String(event.room_id.to_string()));
+    pdu.insert("sender".to_string(), Value::String(event.user_id.to_string()));
+    pdu.insert("type".to_string(), Value::String(event.event_type.clone()));
+
+    if let Some(ref state_key) = event.state_key {
+        pdu.insert("state_key".to_string(), Value::String(state_key.clone()));
+    }
+
+    sign_pdu(origin, signing_key, pdu)
+}
+
+/// Adds the content hash of a PDU and signs it as `origin` with the signing key.
+pub fn sign_pdu(origin: &str, signing_key: &SigningKey, mut pdu: Map<String, Value>)
+-> Result<Value, ApiError> {
+    let hash = content_hash(&pdu)?;
+    let mut hashes = Map::new();
+    hashes.insert("sha256".to_string(), Value::String(hash));
+    pdu.insert("hashes".to_string(), Value::Object(hashes));
+
+    let signature = sign_json(&signing_key.key_pair()?, &Value::Object(pdu.clone()))
+        .map_err(ApiError::from)?;
+
+    let mut server_signatures = Map::new();
+    server_signatures.insert(
+        signature.id().to_string(),
+        Value::String(encode(signature.as_bytes()).trim_right_matches('=').to_string()),
+    );
+
+    let mut signatures = Map::new();
+    signatures.insert(origin.to_string(), Value::Object(server_signatures));
+
+    pdu.insert("signatures".to_string(), Value::Object(signatures));
+
+    Ok(Value::Object(pdu))
+}
+
+/// The unpadded Base64 SHA-256 hash of the canonical JSON of an event without its hashes and
+/// signatures.
+fn content_hash(pdu: &Map<String, Value>) -> Result<String, ApiError> {
+    let mut hashed = pdu.clone();
+    hashed.remove("hashes");
+    hashed.remove("signatures");
+    hashed.remove("unsigned");
+
+    let canonical_json = to_canonical_json(&Value::Object(hashed))?;
+    let hash = digest(&SHA256, canonical_json.as_bytes());
+
+    Ok(encode(hash.as_ref()).trim_right_matches('=').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use base64::decode;
+    use diesel::{ExecuteDsl, insert};
+    use diesel::pg::PgConnection;
+    use iron::status::Status;
+    use ruma_identifiers::{EventId, RoomId, UserId};
+    use ruma_signatures::{Ed25519Verifier, Signature, verify_json};
+    use serde_json::Value;
+
+    use crypto::SigningKey;
+    use models::background_job::Job;
+    use models::event::{Event, NewEvent};
+    use models::room_membership::NewRoomMembership;
+    use schema::room_memberships;
+    use test::Test;
+    use super::{SEND_EVENT_JOB, dropped_deliveries, queue_events, signed_pdu};
+
+    const SIGNING_KEY: &'static str =
+        "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8DoQe/884Qvh1w3RjnS8CZZ+TWMJulDV8d3IZkElUxuA==";
+
+    fn add_remote_member(connection: &PgConnection, room_id: &str, user_id: &str, membership: &str) {
+        let user_id = UserId::try_from(user_id).unwrap();
+
+        insert(&NewRoomMembership {
+            event_id: EventId::new(user_id.hostname().to_string().as_ref()).unwrap(),
+            room_id: RoomId::try_from(room_id).unwrap(),
+            user_id: user_id.clone(),
+            sender: user_id,
+            membership: membership.to_string(),
+        }).into(room_memberships::table).execute(connection).unwrap();
+    }
+
+    fn queued_destinations(connection: &PgConnection) -> Vec<String> {
+        let mut destinations: Vec<String> = Job::all(connection).unwrap()
+            .into_iter()
+            .filter(|job| job.kind == SEND_EVENT_JOB)
+            .map(|job| job.payload().unwrap().get("destination").unwrap().as_str().unwrap().to_string())
+            .collect();
+
+        destinations.sort();
+        destinations
+    }
+
+    #[test]
+    fn membership_changes_are_queued_for_participating_servers() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_options = format!(r#"{{"invite": ["{}"]}}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        {
+            let connection = test.connection();
+
+            assert!(queued_destinations(&connection).is_empty());
+
+            add_remote_member(&connection, &room_id, "@carl:remote.test", "join");
+            add_remote_member(&connection, &room_id, "@dan:left.test", "leave");
+        }
+
+        assert_eq!(test.kick_from_room(&alice.token, &room_id, &bob.id, None).status, Status::Ok);
+
+        assert_eq!(queued_destinations(&test.connection()), vec!["remote.test".to_string()]);
+    }
+
+    #[test]
+    fn unban_is_queued_for_the_unbanned_users_server() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        {
+            let connection = test.connection();
+
+            add_remote_member(&connection, &room_id, "@carl:remote.test", "join");
+            add_remote_member(&connection, &room_id, "@eve:banned.test", "ban");
+        }
+
+        assert_eq!(test.unban_from_room(&alice.token, &room_id, "@eve:banned.test").status, Status::Ok);
+
+        assert_eq!(
+            queued_destinations(&test.connection()),
+            vec!["banned.test".to_string(), "remote.test".to_string()]
+        );
+    }
+
+    #[test]
+    fn full_queues_drop_new_events() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let connection = test.connection();
+
+        add_remote_member(&connection, &room_id, "@carl:remote.test", "join");
+
+        let event = |body: &str| NewEvent {
+            event_type: "m.room.message".to_string(),
+            extra_content: None,
+            id: EventId::new("ruma.test").unwrap(),
+            content: format!(r#"{{"msgtype": "m.text", "body": "{}"}}"#, body),
+            room_id: RoomId::try_from(room_id.as_ref()).unwrap(),
+            state_key: None,
+            user_id: UserId::try_from(alice.id.as_ref()).unwrap(),
+            pdu: None,
+        };
+
+        let dropped_before = dropped_deliveries();
+
+        let events = [event("1"), event("2")];
+        let queued = queue_events(&connection, test.clock(), "ruma.test", &events, 3).unwrap();
+        assert_eq!(queued, 2);
+
+        let events = [event("3"), event("4")];
+        let queued = queue_events(&connection, test.clock(), "ruma.test", &events, 3).unwrap();
+        assert_eq!(queued, 1);
+
+        assert_eq!(queued_destinations(&connection).len(), 3);
+        assert!(dropped_deliveries() >= dropped_before + 1);
+    }
+
+    #[test]
+    fn pdu_is_signed() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        assert_eq!(test.send_message(&alice.token, &room_id, "Hi", 1).status, Status::Ok);
+
+        let connection = test.connection();
+        let room_id = RoomId::try_from(room_id.as_ref()).unwrap();
+        let event_id = Event::find_latest_event_ids(&connection, &room_id).unwrap().remove(0);
+        let event = Event::find(&connection, &event_id).unwrap().unwrap();
+        let signing_key = SigningKey::from_base64("1", SIGNING_KEY).unwrap();
+
+        let mut pdu = signed_pdu(&connection, "ruma.test", &signing_key, &event).unwrap();
+
+        assert_eq!(pdu.get("event_id").unwrap().as_str().unwrap(), event_id.to_string());
+        assert_eq!(pdu.get("depth").unwrap().as_i64().unwrap(), event.depth);
+        assert_eq!(pdu.get("prev_events").unwrap().as_array().unwrap().len(), 1);
+
+        let signatures = pdu.as_object_mut().unwrap().remove("signatures").unwrap();
+        let mut signature = signatures.pointer("/ruma.test/ed25519:1").unwrap().as_str().unwrap().to_string();
+
+        while signature.len() % 4 != 0 {
+            signature.push('=');
+        }
+
+        let signature = Signature::new("ed25519:1", &decode(&signature).unwrap()).unwrap();
+
+        assert!(verify_json(&Ed25519Verifier, &signing_key.public_key, &signature, &pdu).is_ok());
+        assert!(pdu.get("content").map(Value::is_object).unwrap());
+        assert_eq!(
+            pdu.pointer("/hashes/sha256").unwrap().as_str().unwrap(),
+            super::content_hash(pdu.as_object().unwrap()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! Validation of the Matrix identifiers clients send.
+//!
+//! `ruma_identifiers` checks the structure of identifiers, but not their overall length, so
+//! anything parsing an identifier from a request should use these functions instead of `try_from`.
+//! They reject identifiers longer than the spec allows and report any problem as `M_INVALID_PARAM`
+//! naming the offending field.
+
+use std::convert::TryFrom;
+use std::error::Error;
+
+use ruma_identifiers::{Error as IdentifierError, UserId};
+
+use error::ApiError;
+
+/// The maximum length in bytes of a user, room, room alias or event ID, including the sigil and
+/// the server name.
+pub const MAX_IDENTIFIER_LENGTH: usize = 255;
+
+/// Parses an identifier received in the given field of a request.
+pub fn parse<T>(field: &str, id: &str) -> Result<T, ApiError>
+where T: for<'a> TryFrom<&'a str, Error = IdentifierError> {
+    check_length(field, id)?;
+
+    T::try_from(id).map_err(|error| ApiError::invalid_param(field, error.description()))
+}
+
+/// Checks the length of an identifier received in the given field of a request.
+///
+/// Identifiers deserialized as part of a request body have already been parsed, but still have
+/// to be checked.
+pub fn check_length(field: &str, id: &str) -> Result<(), ApiError> {
+    if id.len() > MAX_IDENTIFIER_LENGTH {
+        return Err(ApiError::invalid_param(
+            field,
+            &format!("must not be longer than {} bytes", MAX_IDENTIFIER_LENGTH),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Builds the ID of a new user on this server from the requested localpart.
+///
+/// Users may only be created with the localpart characters of the spec: lowercase letters,
+/// digits, '.', '_', '=', '-' and '/'. Existing users with other characters can still sign in.
+pub fn new_user_id(field: &str, localpart: &str, domain: &str) -> Result<UserId, ApiError> {
+    if localpart.is_empty() {
+        return Err(ApiError::invalid_param(field, "must not be empty"));
+    }
+
+    let is_allowed = |c: char| match c {
+        'a'...'z' | '0'...'9' | '.' | '_' | '=' | '-' | '/' => true,
+        _ => false,
+    };
+
+    if !localpart.chars().all(is_allowed) {
+        return Err(ApiError::invalid_param(
+            field,
+            "may only contain the characters a-z, 0-9, '.', '_', '=', '-' and '/'",
+        ));
+    }
+
+    parse(field, &format!("@{}:{}", localpart, domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma_identifiers::{RoomId, UserId};
+
+    use super::{new_user_id, parse};
+
+    #[test]
+    fn identifiers_longer_than_255_bytes_are_rejected() {
+        let user_id = format!("@{}:ruma.test", "a".repeat(300));
+        let room_id = format!("!{}:ruma.test", "a".repeat(300));
+
+        assert!(parse::<UserId>("user_id", &user_id).is_err());
+        assert!(parse::<RoomId>("room_id", &room_id).is_err());
+        assert!(parse::<UserId>("user_id", "@alice:ruma.test").is_ok());
+    }
+
+    #[test]
+    fn new_user_ids() {
+        assert!(new_user_id("username", "alice.smith_1", "ruma.test").is_ok());
+
+        for localpart in &["", "Alice", "al ice", "al:ice", "caf\u{e9}"] {
+            assert!(new_user_id("username", localpart, "ruma.test").is_err());
+        }
+
+        assert!(new_user_id("username", &"a".repeat(250), "ruma.test").is_err());
+    }
+}
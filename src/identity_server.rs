@@ -0,0 +1,98 @@
+//! HTTP client for the configured identity server.
+
+use std::io::Read;
+
+use hyper::Client;
+use hyper::header::{ContentType, Headers};
+use hyper::net::HttpsConnector;
+use hyper_native_tls::NativeTlsClient;
+use iron::method::Method;
+use serde_json::{Value, from_str, to_string};
+use url::Url;
+
+use config::Config;
+use error::{ApiError, MapApiError};
+use federation::auth::OutgoingFederationAuth;
+
+/// Makes requests to the identity server, signing each one with the server's signing key.
+pub struct IdentityServerClient {
+    auth: OutgoingFederationAuth,
+    base_url: Url,
+    client: Client,
+}
+
+impl IdentityServerClient {
+    /// Creates a client for the identity server in the `Config`.
+    ///
+    /// Returns `None` if no identity server is configured.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ApiError> {
+        let identity_server_url = match config.identity_server_url {
+            Some(ref identity_server_url) => identity_server_url,
+            None => return Ok(None),
+        };
+
+        let base_url = Url::parse(identity_server_url).map_api_err(|_| {
+            ApiError::unknown(format!("Invalid identity server URL {}.", identity_server_url))
+        })?;
+
+        let signing_key = match config.signing_key {
+            Some(ref signing_key) => signing_key,
+            None => Err(ApiError::unavailable(
+                "Requests to the identity server can't be signed without a signing key.".to_string()
+            ))?,
+        };
+
+        let tls = NativeTlsClient::new().map_api_err(|_| {
+            ApiError::unknown("Failed to set up TLS for the identity server.".to_string())
+        })?;
+
+        Ok(Some(IdentityServerClient {
+            auth: OutgoingFederationAuth::new(&config.domain, signing_key)?,
+            base_url: base_url,
+            client: Client::with_connector(HttpsConnector::new(tls)),
+        }))
+    }
+
+    /// Sends a signed request to `path` on the identity server and returns the JSON response.
+    pub fn request(&self, method: Method, path: &str, content: Option<&Value>)
+    -> Result<Value, ApiError> {
+        let destination = match (self.base_url.host_str(), self.base_url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => Err(ApiError::unknown("The identity server URL has no host.".to_string()))?,
+        };
+
+        let authorization = self.auth.authorization_header(&method, path, &destination, content)?;
+        let body = match content {
+            Some(content) => to_string(content).map_err(ApiError::from)?,
+            None => String::new(),
+        };
+
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        headers.set_raw("Authorization", vec![authorization.into_bytes()]);
+
+        let url = format!("{}{}", self.base_url.as_str().trim_right_matches('/'), path);
+
+        let mut response = self.client.request(method, &url)
+            .headers(headers)
+            .body(&body[..])
+            .send()
+            .map_api_err(|_| {
+                ApiError::unknown(format!("Failed to send identity server request to {}.", destination))
+            })?;
+
+        let mut response_body = String::new();
+        response.read_to_string(&mut response_body).map_err(ApiError::from)?;
+
+        if !response.status.is_success() {
+            return Err(ApiError::unknown(format!(
+                "Identity server request to {} failed with status {}.",
+                destination,
+                response.status,
+            )));
+        }
+
+        from_str(&response_body).map_err(ApiError::from)
+    }
+}
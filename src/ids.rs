@@ -0,0 +1,149 @@
+//! Generation of the IDs of new rooms and events.
+//!
+//! The opaque parts of the IDs come from the operating system's cryptographically secure RNG, so
+//! they can't be guessed or predicted. They are long enough that a collision is practically
+//! impossible, but saving a room or event whose ID is already taken fails with a unique violation
+//! of `ID_CONSTRAINTS`, which `with_unique_id` handles by trying again with a new ID.
+
+use std::convert::TryFrom;
+
+use rand::{OsRng, Rng};
+use ruma_identifiers::{EventId, RoomId};
+
+use error::ApiError;
+
+/// The number of random alphanumeric characters in the opaque part of an ID, about 143 bits.
+pub const OPAQUE_ID_LENGTH: usize = 24;
+
+/// The unique constraints violated when a generated ID is already taken.
+pub const ID_CONSTRAINTS: [&'static str; 2] = ["events_pkey", "rooms_pkey"];
+
+/// How many IDs `with_unique_id` tries before giving up.
+const ID_ATTEMPTS: usize = 3;
+
+/// Generates the ID of a new room on this server, with a random opaque localpart.
+pub fn generate_room_id(domain: &str) -> Result<RoomId, ApiError> {
+    let room_id = format!("!{}:{}", opaque_id()?, domain);
+
+    RoomId::try_from(room_id.as_ref()).map_err(ApiError::from)
+}
+
+/// Generates the ID of a new event on this server, in the format of the room's version.
+///
+/// Rooms of versions 1 and 2 use `$` followed by a random opaque part and the server name. Later
+/// versions derive event IDs from the event's hash, which isn't supported yet.
+pub fn generate_event_id(domain: &str, room_version: &str) -> Result<EventId, ApiError> {
+    match room_version {
+        "1" | "2" => {
+            let event_id = format!("${}:{}", opaque_id()?, domain);
+
+            EventId::try_from(event_id.as_ref()).map_err(ApiError::from)
+        }
+        _ => Err(ApiError::unsupported_room_version(format!(
+            "Event IDs of room version {} can't be generated.",
+            room_version
+        ))),
+    }
+}
+
+/// Runs `f` with a new ID from `generate`, running it again with another one if the ID was
+/// already taken.
+///
+/// `f` must save the ID in its own transaction, since the unique violation aborts it. Errors
+/// other than collisions, and the collision of the last attempt, are returned as is.
+pub fn with_unique_id<I, T, G, F>(mut generate: G, f: F) -> Result<T, ApiError>
+where G: FnMut() -> Result<I, ApiError>, F: Fn(I) -> Result<T, ApiError> {
+    let mut attempt = 1;
+
+    loop {
+        match f(generate()?) {
+            Err(ref error) if error.is_id_collision() && attempt < ID_ATTEMPTS => {
+                warn!("Generated ID was already taken, retrying after attempt {}", attempt);
+            }
+            result => return result,
+        }
+
+        attempt += 1;
+    }
+}
+
+/// A random string of `OPAQUE_ID_LENGTH` alphanumeric characters.
+fn opaque_id() -> Result<String, ApiError> {
+    let mut rng = OsRng::new()?;
+
+    Ok(rng.gen_ascii_chars().take(OPAQUE_ID_LENGTH).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::convert::TryFrom;
+
+    use diesel::{LoadDsl, insert};
+    use ruma_identifiers::{EventId, RoomId, UserId};
+
+    use error::ApiError;
+    use models::room::{NewRoom, Room};
+    use schema::rooms;
+    use super::{OPAQUE_ID_LENGTH, generate_event_id, generate_room_id, with_unique_id};
+    use test::Test;
+
+    #[test]
+    fn generated_ids_are_valid() {
+        let room_id = generate_room_id("ruma.test").unwrap();
+        let event_id = generate_event_id("ruma.test", "1").unwrap();
+
+        assert!(RoomId::try_from(room_id.to_string().as_ref()).is_ok());
+        assert!(EventId::try_from(event_id.to_string().as_ref()).is_ok());
+        assert_eq!(room_id.hostname().to_string(), "ruma.test");
+        assert_eq!(event_id.hostname().to_string(), "ruma.test");
+        assert_eq!(room_id.opaque_id().len(), OPAQUE_ID_LENGTH);
+        assert_eq!(event_id.opaque_id().len(), OPAQUE_ID_LENGTH);
+        assert!(event_id.to_string().starts_with('$'));
+    }
+
+    #[test]
+    fn event_ids_of_hash_based_room_versions_are_not_generated() {
+        assert!(generate_event_id("ruma.test", "3").is_err());
+        assert!(generate_event_id("ruma.test", "999").is_err());
+    }
+
+    #[test]
+    fn generated_ids_are_unique() {
+        let mut room_ids = HashSet::new();
+        let mut event_ids = HashSet::new();
+
+        for _ in 0..10_000 {
+            assert!(room_ids.insert(generate_room_id("ruma.test").unwrap().to_string()));
+            assert!(event_ids.insert(generate_event_id("ruma.test", "1").unwrap().to_string()));
+        }
+    }
+
+    #[test]
+    fn taken_ids_are_retried() {
+        let test = Test::new();
+        let connection = test.connection();
+        let user_id = UserId::try_from("@alice:ruma.test").unwrap();
+
+        let insert_room = |id: RoomId| -> Result<Room, ApiError> {
+            let new_room = NewRoom { id: id, user_id: user_id.clone(), public: false };
+
+            insert(&new_room).into(rooms::table).get_result(&*connection).map_err(ApiError::from)
+        };
+
+        let taken = RoomId::try_from("!taken:ruma.test").unwrap();
+        insert_room(taken.clone()).unwrap();
+
+        // The first ID generated is the taken one, the second a new one.
+        let mut ids = vec![generate_room_id("ruma.test").unwrap(), taken.clone()];
+
+        let room = with_unique_id(|| Ok(ids.pop().unwrap()), &insert_room).unwrap();
+
+        assert!(room.id != taken);
+        assert!(ids.is_empty());
+
+        let error = with_unique_id(|| Ok(taken.clone()), &insert_room).unwrap_err();
+
+        assert!(error.is_id_collision());
+    }
+}
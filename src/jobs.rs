@@ -0,0 +1,609 @@
+//! Workers that run background jobs.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use diesel::pg::PgConnection;
+use r2d2::Pool;
+use r2d2_diesel::ConnectionManager;
+use rand::{Rng, thread_rng};
+use serde_json::Value;
+
+use clock::Clock;
+use error::ApiError;
+use models::background_job::{Job, LOCK_RENEWAL_INTERVAL_SECS};
+use shutdown::{Shutdown, ShuttingDown};
+
+/// How long an idle worker waits before checking the queue again.
+const POLL_INTERVAL_MS: u64 = 1000;
+
+/// A function that runs a kind of job, given the job's payload.
+pub type JobHandler = Box<Fn(&PgConnection, &Value) -> Result<(), ApiError> + Send + Sync>;
+
+/// The handlers for each kind of job.
+#[derive(Default)]
+pub struct JobRegistry {
+    handlers: HashMap<String, JobHandler>,
+}
+
+impl JobRegistry {
+    /// Creates an empty `JobRegistry`.
+    pub fn new() -> Self {
+        JobRegistry::default()
+    }
+
+    /// Registers the handler for jobs of the given kind, replacing any previous one.
+    pub fn register<F>(&mut self, kind: &str, handler: F)
+    where F: Fn(&PgConnection, &Value) -> Result<(), ApiError> + Send + Sync + 'static {
+        self.handlers.insert(kind.to_string(), Box::new(handler));
+    }
+
+    /// Looks up the handler for jobs of the given kind.
+    fn handler(&self, kind: &str) -> Option<&JobHandler> {
+        self.handlers.get(kind)
+    }
+}
+
+/// Claims jobs from the queue and runs them.
+pub struct Worker {
+    id: String,
+    registry: Arc<JobRegistry>,
+    clock: Arc<Clock>,
+    lock_renewal: Option<(Pool<ConnectionManager<PgConnection>>, Duration)>,
+}
+
+/// Renews the lock of a running job on a thread of its own until it is dropped.
+struct LockRenewal {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    /// Creates a new `Worker` with a random ID, which tells whether jobs are due and when failed
+    /// jobs are retried with the given clock.
+    pub fn new(registry: Arc<JobRegistry>, clock: Arc<Clock>) -> Self {
+        let suffix: String = thread_rng().gen_ascii_chars().take(8).collect();
+
+        Worker {
+            id: format!("worker-{}", suffix),
+            registry: registry,
+            clock: clock,
+            lock_renewal: None,
+        }
+    }
+
+    /// Makes the worker renew the lock of the job it runs every `interval`, with connections of
+    /// its own from the pool, so that jobs running for longer than a lock stays fresh aren't
+    /// claimed by another worker.
+    pub fn renewing_locks(
+        mut self,
+        connection_pool: Pool<ConnectionManager<PgConnection>>,
+        interval: Duration,
+    ) -> Self {
+        self.lock_renewal = Some((connection_pool, interval));
+        self
+    }
+
+    /// The ID the worker locks jobs with.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Claims and runs a single job.
+    ///
+    /// Returns `false` if there was no job to run. Jobs whose handler fails or panics are
+    /// rescheduled, or dead-lettered after too many attempts.
+    pub fn run_once(&self, connection: &PgConnection) -> Result<bool, ApiError> {
+        match Job::claim(connection, &self.id, &*self.clock)? {
+            Some(job) => self.run(connection, job).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Runs all jobs that are due at the given time, one after the other, on the calling thread.
+    ///
+    /// Jobs scheduled by the jobs that ran are also run if they are due. Tests use this to run the
+    /// jobs that became due after moving their clock forward. Returns the number of jobs that ran.
+    pub fn run_due_jobs(&self, connection: &PgConnection, now: SystemTime)
+    -> Result<usize, ApiError> {
+        let mut jobs_run = 0;
+
+        while let Some(job) = Job::claim_due(connection, &self.id, now)? {
+            self.run(connection, job)?;
+            jobs_run += 1;
+        }
+
+        Ok(jobs_run)
+    }
+
+    /// Runs the jobs of the given kind that are due, one after the other, until none is left or
+    /// the deadline has passed.
+    ///
+    /// Returns the number of jobs that ran.
+    pub fn run_due_jobs_of_kind(&self, connection: &PgConnection, kind: &str, deadline: Instant)
+    -> Result<usize, ApiError> {
+        let mut jobs_run = 0;
+
+        while Instant::now() < deadline {
+            match Job::claim_due_of_kind(connection, &self.id, kind, self.clock.now())? {
+                Some(job) => self.run(connection, job)?,
+                None => break,
+            }
+
+            jobs_run += 1;
+        }
+
+        Ok(jobs_run)
+    }
+
+    /// Runs a claimed job, then removes it from the queue or records its failure.
+    fn run(&self, connection: &PgConnection, mut job: Job) -> Result<(), ApiError> {
+        let lock_renewal = self.lock_renewal.as_ref().map(|&(ref connection_pool, interval)| {
+            let clock = self.clock.clone();
+
+            LockRenewal::start(connection_pool.clone(), &job, &self.id, clock, interval)
+        });
+
+        let result = match self.registry.handler(&job.kind) {
+            Some(handler) => {
+                let payload = job.payload()?;
+
+                match catch_unwind(AssertUnwindSafe(|| handler(connection, &payload))) {
+                    Ok(Ok(())) => Ok(()),
+                    Ok(Err(error)) => Err(error.to_string()),
+                    Err(panic) => Err(format!("Handler panicked: {}", panic_message(&panic))),
+                }
+            }
+            None => Err(format!("No handler is registered for jobs of kind {}.", job.kind)),
+        };
+
+        drop(lock_renewal);
+
+        match result {
+            Ok(()) => job.complete(connection)?,
+            Err(error) => {
+                warn!("Background job {} ({}) failed: {}", job.id, job.kind, error);
+
+                job.fail(connection, &error, &*self.clock)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl LockRenewal {
+    /// Starts renewing the lock `worker_id` holds on the job every `interval`.
+    fn start(
+        connection_pool: Pool<ConnectionManager<PgConnection>>,
+        job: &Job,
+        worker_id: &str,
+        clock: Arc<Clock>,
+        interval: Duration,
+    ) -> Self {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stopped = stopped.clone();
+        let job_id = job.id;
+        let worker_id = worker_id.to_string();
+
+        let thread = thread::spawn(move || {
+            let &(ref is_stopped, ref stop) = &*thread_stopped;
+
+            loop {
+                {
+                    let guard = is_stopped.lock().unwrap_or_else(PoisonError::into_inner);
+                    let (guard, _) = stop.wait_timeout(guard, interval)
+                        .unwrap_or_else(PoisonError::into_inner);
+
+                    if *guard {
+                        break;
+                    }
+                }
+
+                let connection = match connection_pool.get() {
+                    Ok(connection) => connection,
+                    Err(error) => {
+                        error!("Failed to get a connection to renew a job's lock: {}", error);
+                        continue;
+                    }
+                };
+
+                match Job::renew_lock(&connection, job_id, &worker_id, clock.now()) {
+                    Ok(true) => debug!("Worker {} renewed the lock of job {}.", worker_id, job_id),
+                    Ok(false) => {
+                        warn!("Worker {} lost the lock of job {}.", worker_id, job_id);
+                        break;
+                    }
+                    Err(error) => error!("Failed to renew the lock of job {}: {}", job_id, error),
+                }
+            }
+        });
+
+        LockRenewal {
+            stopped: stopped,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for LockRenewal {
+    fn drop(&mut self) {
+        let &(ref is_stopped, ref stop) = &*self.stopped;
+
+        *is_stopped.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        stop.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("The thread renewing a job's lock panicked.");
+            }
+        }
+    }
+}
+
+/// A set of worker threads running background jobs until the server shuts down.
+pub struct WorkerPool {
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Starts `size` worker threads.
+    pub fn start(
+        size: usize,
+        connection_pool: Pool<ConnectionManager<PgConnection>>,
+        registry: Arc<JobRegistry>,
+        clock: Arc<Clock>,
+        shutting_down: ShuttingDown,
+    ) -> Self {
+        let threads = (0..size).map(|_| {
+            let connection_pool = connection_pool.clone();
+            let worker = Worker::new(registry.clone(), clock.clone()).renewing_locks(
+                connection_pool.clone(),
+                Duration::from_secs(LOCK_RENEWAL_INTERVAL_SECS),
+            );
+            let shutting_down = shutting_down.clone();
+
+            thread::spawn(move || {
+                while !shutting_down.is_set() {
+                    let ran_job = match connection_pool.get() {
+                        Ok(connection) => match worker.run_once(&connection) {
+                            Ok(ran_job) => ran_job,
+                            Err(error) => {
+                                error!("Worker {} failed to run a job: {}", worker.id(), error);
+                                false
+                            }
+                        },
+                        Err(error) => {
+                            error!("Worker {} failed to get a connection: {}", worker.id(), error);
+                            false
+                        }
+                    };
+
+                    if !ran_job {
+                        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+                    }
+                }
+            })
+        }).collect();
+
+        WorkerPool {
+            threads: threads,
+        }
+    }
+
+    /// Waits for all workers to finish their current job and stop.
+    ///
+    /// The workers only stop once the server's `ShuttingDown` flag is set.
+    pub fn join(self) {
+        for thread in self.threads {
+            if thread.join().is_err() {
+                error!("A background worker thread panicked.");
+            }
+        }
+    }
+}
+
+/// Registers a shutdown hook that runs the due jobs of the given kinds, so that the queues are
+/// empty when the server exits.
+///
+/// It runs after the workers stopped, and gives up once `timeout` has passed. The jobs left are
+/// still queued, and run once the server is started again.
+pub fn flush_on_shutdown(
+    shutdown: &Shutdown,
+    connection_pool: Pool<ConnectionManager<PgConnection>>,
+    registry: Arc<JobRegistry>,
+    clock: Arc<Clock>,
+    kinds: &'static [&'static str],
+    timeout: Duration,
+) {
+    let worker = Worker::new(registry, clock).renewing_locks(
+        connection_pool.clone(),
+        Duration::from_secs(LOCK_RENEWAL_INTERVAL_SECS),
+    );
+
+    shutdown.on_shutdown(move || {
+        let deadline = Instant::now() + timeout;
+
+        let connection = match connection_pool.get() {
+            Ok(connection) => connection,
+            Err(error) => {
+                error!("Failed to get a connection to flush the job queues: {}", error);
+                return;
+            }
+        };
+
+        for kind in kinds {
+            match worker.run_due_jobs_of_kind(&connection, kind, deadline) {
+                Ok(jobs_run) => debug!("Ran {} queued {} jobs before exiting.", jobs_run, kind),
+                Err(error) => error!("Failed to flush the queue of {} jobs: {}", kind, error),
+            }
+        }
+    });
+}
+
+/// Extracts the message of a panic, if it has one.
+fn panic_message(panic: &Box<Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    use diesel::{Connection, ExecuteDsl, ExpressionMethods, FindDsl, update};
+    use diesel::pg::PgConnection;
+    use r2d2::{Config as R2D2Config, Pool};
+    use r2d2_diesel::ConnectionManager;
+    use serde_json::from_str;
+
+    use clock::{Clock, MockClock, SystemClock};
+    use error::ApiError;
+    use models::background_job::{Job, MAX_ATTEMPTS, retry_delay};
+    use schema::background_jobs;
+    use test::Test;
+    use super::{JobRegistry, Worker, flush_on_shutdown};
+
+    fn make_due(connection: &PgConnection, id: i64) {
+        update(background_jobs::table.find(id))
+            .set(background_jobs::run_at.eq(SystemTime::now()))
+            .execute(connection)
+            .unwrap();
+    }
+
+    #[test]
+    fn worker_runs_enqueued_job() {
+        let test = Test::new();
+        let connection = test.connection();
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let mut registry = JobRegistry::new();
+        let handler_runs = runs.clone();
+        registry.register("test.count", move |_, payload| {
+            assert_eq!(payload.get("n").unwrap().as_u64(), Some(1));
+            handler_runs.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+
+        let job = Job::enqueue(
+            &connection,
+            "test.count",
+            &from_str(r#"{"n": 1}"#).unwrap(),
+            SystemTime::now(),
+        ).unwrap();
+
+        let worker = Worker::new(Arc::new(registry), Arc::new(SystemClock));
+
+        assert!(worker.run_once(&connection).unwrap());
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert!(Job::find(&connection, job.id).unwrap().is_none());
+        assert!(!worker.run_once(&connection).unwrap());
+    }
+
+    #[test]
+    fn panicking_job_retries_then_dead_letters() {
+        let test = Test::new();
+        let connection = test.connection();
+
+        let mut registry = JobRegistry::new();
+        registry.register("test.panic", |_, _| panic!("boom"));
+
+        let clock = Arc::new(MockClock::new());
+
+        let job = Job::enqueue(
+            &connection,
+            "test.panic",
+            &from_str("{}").unwrap(),
+            clock.now(),
+        ).unwrap();
+
+        let worker = Worker::new(Arc::new(registry), clock.clone());
+
+        assert!(worker.run_once(&connection).unwrap());
+
+        let retried = Job::find(&connection, job.id).unwrap().unwrap();
+        assert_eq!(retried.attempts, 1);
+        assert!(!retried.dead);
+        assert!(retried.run_at > clock.now());
+        assert!(retried.last_error.unwrap().contains("boom"));
+
+        // The retries are due once the worker's clock has moved forward.
+        assert!(!worker.run_once(&connection).unwrap());
+
+        for attempts in 1..MAX_ATTEMPTS {
+            clock.advance(retry_delay(attempts));
+            assert!(worker.run_once(&connection).unwrap());
+        }
+
+        let dead = Job::find(&connection, job.id).unwrap().unwrap();
+        assert_eq!(dead.attempts, MAX_ATTEMPTS);
+        assert!(dead.dead);
+
+        make_due(&connection, job.id);
+        assert!(!worker.run_once(&connection).unwrap());
+    }
+
+    #[test]
+    fn run_due_jobs_only_runs_jobs_due_at_the_given_time() {
+        let test = Test::new();
+        let connection = test.connection();
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let mut registry = JobRegistry::new();
+        let handler_runs = runs.clone();
+        registry.register("test.count", move |_, _| {
+            handler_runs.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(60 * 60);
+
+        for &run_at in &[now, later] {
+            Job::enqueue(&connection, "test.count", &from_str("{}").unwrap(), run_at).unwrap();
+        }
+
+        let worker = Worker::new(Arc::new(registry), Arc::new(SystemClock));
+
+        assert_eq!(worker.run_due_jobs(&connection, now).unwrap(), 1);
+        assert_eq!(worker.run_due_jobs(&connection, now).unwrap(), 0);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        assert_eq!(worker.run_due_jobs(&connection, later).unwrap(), 1);
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn workers_never_claim_the_same_job() {
+        let test = Test::new();
+        let postgres_url = test.database_url();
+
+        // The test connections never commit, so the workers get connections of their own.
+        let connection = PgConnection::establish(&postgres_url).unwrap();
+
+        let job = Job::enqueue(
+            &connection,
+            "test.noop",
+            &from_str("{}").unwrap(),
+            SystemTime::now(),
+        ).unwrap();
+
+        let (first, second) = connection.transaction::<_, ApiError, _>(|| {
+            let first = Job::claim(&connection, "worker-a", &SystemClock)?;
+
+            let second = thread::spawn(move || {
+                let connection = PgConnection::establish(&postgres_url).unwrap();
+
+                Job::claim(&connection, "worker-b", &SystemClock).unwrap()
+            });
+
+            // Gives the second worker time to find the job and wait for the first one's claim.
+            thread::sleep(Duration::from_millis(200));
+
+            Ok((first, second))
+        }).unwrap();
+
+        let first = first.unwrap();
+        assert_eq!(first.id, job.id);
+        assert_eq!(first.locked_by, Some("worker-a".to_string()));
+
+        assert!(second.join().unwrap().is_none());
+    }
+
+    #[test]
+    fn long_running_jobs_are_not_claimed_again() {
+        let test = Test::new();
+
+        // The test connections never commit, so the worker gets connections of its own.
+        let connection_pool = Pool::new(
+            R2D2Config::default(),
+            ConnectionManager::<PgConnection>::new(test.database_url()),
+        ).unwrap();
+        let clock = Arc::new(MockClock::new());
+        let claimed_again = Arc::new(AtomicBool::new(false));
+
+        let mut registry = JobRegistry::new();
+        let handler_pool = connection_pool.clone();
+        let handler_clock = clock.clone();
+        let handler_claimed_again = claimed_again.clone();
+        registry.register("test.long", move |_, _| {
+            // The job runs for an hour, long after its lock would have become stale.
+            handler_clock.advance(Duration::from_secs(60 * 60));
+            thread::sleep(Duration::from_millis(500));
+
+            let connection = handler_pool.get().unwrap();
+            let claim = Job::claim(&connection, "worker-b", &*handler_clock).unwrap();
+
+            handler_claimed_again.store(claim.is_some(), Ordering::SeqCst);
+
+            Ok(())
+        });
+
+        let connection = connection_pool.get().unwrap();
+        let job = Job::enqueue(&connection, "test.long", &from_str("{}").unwrap(), clock.now())
+            .unwrap();
+
+        let worker = Worker::new(Arc::new(registry), clock.clone())
+            .renewing_locks(connection_pool.clone(), Duration::from_millis(100));
+
+        assert!(worker.run_once(&connection).unwrap());
+        assert!(!claimed_again.load(Ordering::SeqCst));
+        assert!(Job::find(&connection, job.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn queues_are_flushed_on_shutdown() {
+        let test = Test::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let mut registry = JobRegistry::new();
+        let handler_runs = runs.clone();
+        registry.register("test.flushed", move |_, _| {
+            handler_runs.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+
+        {
+            let connection = test.connection();
+
+            for kind in &["test.flushed", "test.flushed", "test.kept"] {
+                Job::enqueue(&connection, kind, &from_str("{}").unwrap(), SystemTime::now())
+                    .unwrap();
+            }
+        }
+
+        flush_on_shutdown(
+            test.shutdown(),
+            test.connection_pool(),
+            Arc::new(registry),
+            Arc::new(SystemClock),
+            &["test.flushed"],
+            Duration::from_secs(10),
+        );
+
+        test.shutdown().begin();
+        test.shutdown().run_hooks();
+
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        let connection = test.connection();
+        let kinds: Vec<String> = Job::all(&connection).unwrap()
+            .into_iter()
+            .map(|job| job.kind)
+            .collect();
+
+        assert_eq!(kinds, vec!["test.kept"]);
+    }
+}
@@ -0,0 +1,192 @@
+//! Files holding the server's secret keys.
+//!
+//! Instead of being written into the configuration, `macaroon_secret_key` and `signing_key` can
+//! be kept in files of their own that only the user running Ruma may read.
+
+use std::fs::{File, OpenOptions, metadata};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+use crypto::{SigningKey, generate_macaroon_secret_key};
+use error::CliError;
+
+/// The permissions of generated key files: readable and writable by their owner only.
+const KEY_FILE_MODE: u32 = 0o600;
+
+/// The permission bit that lets every user read a file.
+const WORLD_READABLE: u32 = 0o004;
+
+/// The key files named in the configuration.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct KeyFiles {
+    /// The path of the file holding the macaroon secret key.
+    pub macaroon_secret_key_file: Option<String>,
+    /// The path of the file holding the signing key.
+    pub signing_key_file: Option<String>,
+    /// The version of the signing key. Defaults to 1.
+    pub signing_key_version: Option<String>,
+}
+
+/// The key files after `generate_key_files`.
+pub struct GeneratedKeys {
+    /// The paths of the key files that were created, leaving out those that already existed.
+    pub created: Vec<String>,
+    /// The key in the signing key file, if one is configured.
+    pub signing_key: Option<SigningKey>,
+}
+
+/// Reads the key in a key file, refusing files that every user may read.
+///
+/// Surrounding whitespace, like a trailing newline, is ignored.
+pub fn read_key_file(path: &str) -> Result<String, CliError> {
+    let permissions = metadata(path)
+        .map_err(|error| CliError::new(format!("Cannot read `{}`: {}.", path, error)))?
+        .permissions();
+
+    if permissions.mode() & WORLD_READABLE != 0 {
+        return Err(CliError::new(format!(
+            "`{}` is readable by every user. Restrict it with `chmod 600 {}`.",
+            path,
+            path
+        )));
+    }
+
+    let mut contents = String::new();
+
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|error| CliError::new(format!("Cannot read `{}`: {}.", path, error)))?;
+
+    Ok(contents.trim().to_string())
+}
+
+/// Creates the configured key files that don't exist yet with new random keys.
+///
+/// Key files that already exist are kept as they are, so running this again changes nothing.
+pub fn generate_key_files(key_files: &KeyFiles) -> Result<GeneratedKeys, CliError> {
+    let mut created = Vec::new();
+
+    if let Some(ref path) = key_files.macaroon_secret_key_file {
+        if create_key_file(path, &generate_macaroon_secret_key()?)? {
+            created.push(path.clone());
+        }
+    }
+
+    let signing_key = match key_files.signing_key_file {
+        Some(ref path) => {
+            let version = key_files.signing_key_version.as_ref().map_or("1", |v| &v[..]);
+
+            if create_key_file(path, &SigningKey::generate(version)?.to_base64())? {
+                created.push(path.clone());
+            }
+
+            Some(SigningKey::from_base64(version, &read_key_file(path)?)?)
+        }
+        None => None,
+    };
+
+    Ok(GeneratedKeys {
+        created: created,
+        signing_key: signing_key,
+    })
+}
+
+/// Writes a new key file readable and writable by its owner only, unless the file already
+/// exists.
+///
+/// Returns whether or not the file was created.
+fn create_key_file(path: &str, key: &str) -> Result<bool, CliError> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(KEY_FILE_MODE)
+        .open(path);
+
+    let mut file = match file {
+        Ok(file) => file,
+        Err(ref error) if error.kind() == ErrorKind::AlreadyExists => return Ok(false),
+        Err(error) => {
+            return Err(CliError::new(format!("Cannot create `{}`: {}.", path, error)));
+        }
+    };
+
+    writeln!(file, "{}", key)
+        .map_err(|error| CliError::new(format!("Cannot write `{}`: {}.", path, error)))?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{File, Permissions, metadata, set_permissions};
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+
+    use test::TempDir;
+    use super::{KeyFiles, generate_key_files, read_key_file};
+
+    fn contents(path: &str) -> String {
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+
+        contents
+    }
+
+    #[test]
+    fn generating_key_files_keeps_existing_keys() {
+        let dir = TempDir::new();
+        let key_files = KeyFiles {
+            macaroon_secret_key_file: Some(dir.file("macaroon_secret_key")),
+            signing_key_file: Some(dir.file("signing_key")),
+            signing_key_version: Some("a".to_string()),
+        };
+
+        let generated = generate_key_files(&key_files).unwrap();
+
+        assert_eq!(
+            generated.created,
+            vec![dir.file("macaroon_secret_key"), dir.file("signing_key")]
+        );
+
+        for path in &generated.created {
+            assert_eq!(metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        let signing_key = generated.signing_key.unwrap();
+        assert_eq!(signing_key.key_id(), "ed25519:a");
+
+        let macaroon_secret_key = contents(&dir.file("macaroon_secret_key"));
+        let encoded_signing_key = contents(&dir.file("signing_key"));
+
+        let generated = generate_key_files(&key_files).unwrap();
+
+        assert!(generated.created.is_empty());
+        assert_eq!(generated.signing_key.unwrap().public_key, signing_key.public_key);
+        assert_eq!(contents(&dir.file("macaroon_secret_key")), macaroon_secret_key);
+        assert_eq!(contents(&dir.file("signing_key")), encoded_signing_key);
+    }
+
+    #[test]
+    fn world_readable_key_files_are_refused() {
+        let dir = TempDir::new();
+        let path = dir.file("macaroon_secret_key");
+        let key_files = KeyFiles {
+            macaroon_secret_key_file: Some(path.clone()),
+            ..Default::default()
+        };
+
+        generate_key_files(&key_files).unwrap();
+        assert!(read_key_file(&path).is_ok());
+
+        set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+
+        let error = read_key_file(&path).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!("`{}` is readable by every user. Restrict it with `chmod 600 {}`.", path, path)
+        );
+
+        set_permissions(&path, Permissions::from_mode(0o640)).unwrap();
+        assert!(read_key_file(&path).is_ok());
+    }
+}
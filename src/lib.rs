@@ -11,8 +11,14 @@ extern crate clap;
 #[macro_use] extern crate diesel;
 #[macro_use] extern crate diesel_codegen;
 #[cfg(test)] extern crate env_logger;
+extern crate flate2;
+extern crate hyper;
+extern crate hyper_native_tls;
 extern crate iron;
 #[cfg(test)] extern crate iron_test;
+#[cfg(test)] #[macro_use] extern crate lazy_static;
+#[cfg(test)] extern crate quickcheck;
+extern crate libc;
 #[macro_use] extern crate log;
 extern crate macaroons;
 extern crate mount;
@@ -21,9 +27,11 @@ extern crate persistent;
 extern crate r2d2;
 extern crate r2d2_diesel;
 extern crate rand;
+extern crate ring;
 extern crate router;
 extern crate ruma_events;
 extern crate ruma_identifiers;
+extern crate ruma_signatures;
 extern crate serde;
 #[macro_use] extern crate serde_derive;
 extern crate serde_json;
@@ -36,20 +44,55 @@ extern crate url;
 pub mod middleware;
 /// API endpoints as Iron handlers.
 pub mod api {
+    /// API endpoints for server administration.
+    pub mod admin {
+        pub mod v1;
+    }
+    /// API endpoints proxied to the configured identity server.
+    pub mod identity {
+        pub mod v2;
+    }
+    /// API endpoints for the Matrix server-server API.
+    pub mod federation {
+        pub mod v1;
+    }
     pub mod r0;
 }
+pub mod access_token_cache;
 pub mod authentication;
+pub mod cli;
+pub mod clock;
 pub mod config;
+pub mod content_validation;
 pub mod crypto;
 pub mod db;
 pub mod error;
+pub mod federation;
+pub mod identifiers;
+pub mod ids;
+pub mod identity_server;
+pub mod jobs;
+pub mod key_files;
+pub mod metrics;
 /// Models for the API's domain objects.
 pub mod models;
 pub mod modifier;
+pub mod notifier;
 pub mod schema;
 pub mod server;
+pub mod shutdown;
+pub mod state_cache;
 pub mod query;
+pub mod query_params;
+#[cfg(test)] mod query_budgets;
+pub mod redaction;
+pub mod request_ext;
+pub mod retention;
+pub mod room_version;
+pub mod routing;
 pub mod swagger;
+pub mod text_validation;
+pub mod unstable_features;
 #[cfg(test)] pub mod test;
 
 embed_migrations!();
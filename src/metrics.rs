@@ -0,0 +1,123 @@
+//! Server metrics for capacity planning.
+//!
+//! A background job periodically snapshots server-wide counts like the number of users and rooms
+//! into the `admin_metrics` table, so admins can follow their growth over time. The latest
+//! snapshot is also exposed in the Prometheus text format, along with live figures of the running
+//! server like the hits of the access token cache.
+
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use diesel::pg::PgConnection;
+use serde_json::Value;
+
+use clock::Clock;
+use error::ApiError;
+use jobs::JobRegistry;
+use models::admin_metric::AdminMetric;
+use models::background_job::Job;
+
+/// The kind of the background job that collects metrics.
+pub const COLLECT_METRICS_JOB: &'static str = "collect_metrics";
+
+/// The number of seconds between two snapshots of the metrics.
+const COLLECTION_INTERVAL_SECS: u64 = 60 * 60;
+
+/// The prefix of the names of the metrics in the Prometheus format.
+const PROMETHEUS_PREFIX: &'static str = "ruma_";
+
+/// A figure read from the running server when the metrics are exposed, rather than from a
+/// snapshot.
+#[derive(Clone, Copy, Debug)]
+pub enum LiveMetric {
+    /// A count that only grows while the server runs, by name.
+    Counter(&'static str, u64),
+    /// A value that goes up and down, by name.
+    Gauge(&'static str, i64),
+}
+
+/// Saves a snapshot of the metrics at the current time of `clock`.
+pub fn collect_metrics(connection: &PgConnection, clock: &Clock) -> Result<(), ApiError> {
+    let metrics = AdminMetric::collect(connection, clock.now())?;
+
+    AdminMetric::save_all(connection, &metrics)
+}
+
+/// Registers the handler of the collection job.
+///
+/// Every run of the job schedules the next one.
+pub fn register_jobs(registry: &mut JobRegistry, clock: Arc<Clock>) {
+    registry.register(COLLECT_METRICS_JOB, move |connection, _| {
+        collect_metrics(connection, &*clock)?;
+
+        let next_run = clock.now() + Duration::from_secs(COLLECTION_INTERVAL_SECS);
+
+        Job::enqueue(connection, COLLECT_METRICS_JOB, &Value::Null, next_run).map(|_| ())
+    });
+}
+
+/// Queues the collection job to run right away, unless it is already queued.
+pub fn schedule_collection(connection: &PgConnection) -> Result<(), ApiError> {
+    if !Job::is_queued(connection, COLLECT_METRICS_JOB)? {
+        Job::enqueue(connection, COLLECT_METRICS_JOB, &Value::Null, SystemTime::now())?;
+    }
+
+    Ok(())
+}
+
+/// Formats the snapshot of the metrics as gauges, followed by the live metrics, in the Prometheus
+/// text exposition format.
+pub fn prometheus_text(metrics: &[AdminMetric], live_metrics: &[LiveMetric]) -> String {
+    let mut text = String::new();
+
+    let snapshot = metrics.iter().map(|metric| ("gauge", &metric.metric_name[..], metric.value));
+    let live = live_metrics.iter().map(|live_metric| match *live_metric {
+        LiveMetric::Counter(name, value) => ("counter", name, value as i64),
+        LiveMetric::Gauge(name, value) => ("gauge", name, value),
+    });
+
+    for (kind, name, value) in snapshot.chain(live) {
+        let name = format!("{}{}", PROMETHEUS_PREFIX, name);
+
+        // Writing to a `String` can't fail.
+        let _ = writeln!(text, "# TYPE {} {}", name, kind);
+        let _ = writeln!(text, "{} {}", name, value);
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use models::admin_metric::AdminMetric;
+    use super::{LiveMetric, prometheus_text};
+
+    #[test]
+    fn metrics_are_formatted_as_gauges() {
+        let metric = |name: &str, value: i64| AdminMetric {
+            ts: SystemTime::now(),
+            metric_name: name.to_string(),
+            value: value,
+        };
+
+        let text = prometheus_text(
+            &[metric("total_users", 12), metric("total_rooms", 3)],
+            &[
+                LiveMetric::Counter("access_token_cache_hits_total", 7),
+                LiveMetric::Gauge("busy_workers", 2),
+            ],
+        );
+
+        assert_eq!(
+            text,
+            "# TYPE ruma_total_users gauge\nruma_total_users 12\n\
+            # TYPE ruma_total_rooms gauge\nruma_total_rooms 3\n\
+            # TYPE ruma_access_token_cache_hits_total counter\n\
+            ruma_access_token_cache_hits_total 7\n\
+            # TYPE ruma_busy_workers gauge\nruma_busy_workers 2\n"
+        );
+    }
+}
@@ -1,22 +1,41 @@
 use std::convert::TryFrom;
 
 use bodyparser;
+use diesel::pg::PgConnection;
 use iron::{BeforeMiddleware, IronError, IronResult, Plugin, Request};
+use iron::headers::UserAgent;
+use mount::OriginalUrl;
 use ruma_identifiers::UserId;
 use serde_json::Value;
 use url::Url;
 
+use access_token_cache::AccessTokenCache;
 use authentication::{AuthParams, InteractiveAuth, PasswordAuthParams};
 use config::Config;
 use db::DB;
 use error::ApiError;
+use federation::auth::{Origin, verify_request};
 use models::access_token::AccessToken;
 use models::user::User;
+use models::user_ip::UserIp;
+use request_ext::authed_user;
+use super::client_ip;
 
 /// Handles access token authentication for all API endpoints that require it.
+///
+/// The access token is taken from the `access_token` query parameter, or else from an
+/// `Authorization: Bearer` header.
 #[derive(Debug)]
 pub struct AccessTokenAuth;
 
+/// Ensures that the user authenticated by `AccessTokenAuth` is a server admin.
+#[derive(Debug)]
+pub struct AdminAuth;
+
+/// Handles `X-Matrix` authentication for all federation API endpoints that require it.
+#[derive(Debug)]
+pub struct FederationAuth;
+
 /// Handles Matrix's interactive authentication protocol for all API endpoints that require it.
 #[derive(Debug)]
 pub struct UIAuth {
@@ -35,29 +54,100 @@ impl UIAuth {
 impl BeforeMiddleware for AccessTokenAuth {
     fn before(&self, request: &mut Request) -> IronResult<()> {
         let connection = DB::from_request(request)?;
-        let url: Url = request.url.clone().into();
-        let mut query_pairs = url.query_pairs();
-
-        if let Some((_, ref token)) = query_pairs.find(|&(ref key, _)| key == "access_token") {
-            let access_token = match AccessToken::find_valid_by_token(&connection, token)? {
-                Some(access_token) => access_token,
-                None => Err(ApiError::unauthorized("Unknown token".to_string()))?,
-            };
 
-            match User::find_active_user(&connection, &access_token.user_id)? {
-                Some(user) => {
-                    request.extensions.insert::<AccessToken>(access_token);
-                    request.extensions.insert::<User>(user);
+        if let Some(ref token) = find_access_token(request) {
+            let access_token_cache = AccessTokenCache::from_request(request)?;
 
-                    return Ok(());
-                },
+            let (access_token, user) = match access_token_cache.get(token) {
+                Some(cached) => cached,
                 None => {
-                    Err(ApiError::unauthorized("No user with the given token was found".to_string()))?
+                    let access_token = match AccessToken::find_valid_by_token(&connection, token)? {
+                        Some(access_token) => access_token,
+                        None => Err(ApiError::unknown_token(None))?,
+                    };
+
+                    let config = Config::from_request(request)?;
+
+                    if !access_token.is_signed_with(&config.macaroon_secret_key) {
+                        Err(ApiError::unknown_token(
+                            "The access token was issued with a previous macaroon secret key"
+                                .to_string()
+                        ))?;
+                    }
+
+                    let user = match User::find_active_user(&connection, &access_token.user_id)? {
+                        Some(user) => user,
+                        None => Err(ApiError::unknown_token(
+                            "No user with the given token was found".to_string()
+                        ))?,
+                    };
+
+                    access_token_cache.insert(access_token.clone(), user.clone());
+
+                    (access_token, user)
                 }
+            };
+
+            if Config::from_request(request)?.collect_user_ips {
+                record_user_ip(&connection, request, &access_token);
             }
+
+            request.extensions.insert::<AccessToken>(access_token);
+            request.extensions.insert::<User>(user);
+
+            return Ok(());
         }
 
-        Err(IronError::from(ApiError::unauthorized(None)))
+        Err(IronError::from(ApiError::missing_token()))
+    }
+}
+
+impl BeforeMiddleware for AdminAuth {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let is_admin = authed_user(request)?.admin;
+
+        if is_admin {
+            Ok(())
+        } else {
+            Err(IronError::from(ApiError::unauthorized("Only server admins can do this.".to_string())))
+        }
+    }
+}
+
+impl BeforeMiddleware for FederationAuth {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let authorization = match request.headers.get_raw("Authorization") {
+            Some(values) if values.len() == 1 => String::from_utf8(values[0].clone())
+                .map_err(|_| ApiError::unauthorized_server(None))?,
+            _ => Err(ApiError::unauthorized_server(None))?,
+        };
+
+        let config = Config::from_request(request)?;
+        let content = match request.get::<bodyparser::Json>() {
+            Ok(Some(json)) => Some(json),
+            Ok(None) | Err(_) => None,
+        };
+
+        let url: Url = request.extensions.get::<OriginalUrl>()
+            .unwrap_or(&request.url)
+            .clone()
+            .into();
+        let uri = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+
+        let origin = verify_request(
+            &config,
+            &request.method,
+            &uri,
+            &authorization,
+            content.as_ref(),
+        )?;
+
+        request.extensions.insert::<Origin>(origin);
+
+        Ok(())
     }
 }
 
@@ -92,6 +182,44 @@ impl BeforeMiddleware for UIAuth {
     }
 }
 
+/// The access token of the request, from the `access_token` query parameter or the
+/// `Authorization: Bearer` header.
+pub fn find_access_token(request: &Request) -> Option<String> {
+    let url: Url = request.url.clone().into();
+    let query_token = url.query_pairs()
+        .find(|&(ref key, _)| key == "access_token")
+        .map(|(_, token)| token.into_owned());
+
+    if query_token.is_some() {
+        return query_token;
+    }
+
+    match request.headers.get_raw("Authorization") {
+        Some(values) if values.len() == 1 => String::from_utf8(values[0].clone())
+            .ok()
+            .and_then(|authorization| {
+                if authorization.starts_with("Bearer ") {
+                    Some(authorization["Bearer ".len()..].trim().to_string())
+                } else {
+                    None
+                }
+            }),
+        _ => None,
+    }
+}
+
+/// Records the IP address and user agent an access token is used from.
+///
+/// Failing to do so is logged rather than failing the request.
+fn record_user_ip(connection: &PgConnection, request: &Request, access_token: &AccessToken) {
+    let ip = client_ip(request).to_string();
+    let user_agent = request.headers.get::<UserAgent>().map_or("", |user_agent| &user_agent[..]);
+
+    if let Err(error) = UserIp::record(connection, access_token, &ip, user_agent) {
+        warn!("Failed to record the client of access token {}: {}", access_token.id, error);
+    }
+}
+
 fn get_user_id_and_password(json: &Value, config: &Config) -> Result<(UserId, String), ()> {
     let username = json.get("user").and_then(|username_json| username_json.as_str());
     let password = json.get("password").and_then(|password_json| password_json.as_str());
@@ -117,3 +245,106 @@ fn is_m_login_password(json: &Value) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use iron::headers::{Authorization, ContentType, Headers};
+    use iron::method::Method;
+    use iron::status::Status;
+
+    use test::Test;
+
+    #[test]
+    fn access_token_is_looked_up_once() {
+        let test = Test::new();
+        let user = test.create_user();
+        let path = format!("/_matrix/client/r0/account/3pid?access_token={}", user.token);
+
+        let scans_before = test.table_scans("access_tokens");
+
+        assert_eq!(test.get(&path).status, Status::Ok);
+        let scans_after_first_request = test.table_scans("access_tokens");
+
+        assert_eq!(test.get(&path).status, Status::Ok);
+        let scans_after_second_request = test.table_scans("access_tokens");
+
+        assert!(scans_after_first_request > scans_before);
+        assert_eq!(scans_after_second_request, scans_after_first_request);
+        assert_eq!(test.access_token_cache().hits(), 1);
+    }
+
+    #[test]
+    fn expired_access_token_is_looked_up_again() {
+        let test = Test::with_config(|config| config.access_token_cache_ttl = 60);
+        let user = test.create_user();
+        let path = format!("/_matrix/client/r0/account/3pid?access_token={}", user.token);
+
+        assert_eq!(test.get(&path).status, Status::Ok);
+        assert_eq!(test.get(&path).status, Status::Ok);
+        assert_eq!(test.access_token_cache().hits(), 1);
+        let scans_before_expiry = test.table_scans("access_tokens");
+
+        test.advance_time(Duration::from_secs(61));
+
+        assert_eq!(test.get(&path).status, Status::Ok);
+
+        assert!(test.table_scans("access_tokens") > scans_before_expiry);
+        assert_eq!(test.access_token_cache().hits(), 1);
+    }
+
+    #[test]
+    fn access_token_in_authorization_header() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        headers.set(Authorization(format!("Bearer {}", user.token)));
+
+        let response = test.request_with_headers(
+            Method::Get,
+            "/_matrix/client/r0/account/3pid",
+            "",
+            headers,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        headers.set(Authorization("Bearer nope".to_string()));
+
+        let response = test.request_with_headers(
+            Method::Get,
+            "/_matrix/client/r0/account/3pid",
+            "",
+            headers,
+        );
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_UNKNOWN_TOKEN");
+    }
+
+    #[test]
+    fn access_tokens_from_before_a_macaroon_secret_key_change_are_rejected() {
+        let mut test = Test::new();
+        let user = test.create_user();
+        let path = format!("/_matrix/client/r0/account/3pid?access_token={}", user.token);
+
+        assert_eq!(test.get(&path).status, Status::Ok);
+
+        test.restart_with_config(|config| {
+            config.macaroon_secret_key = b"a new macaroon secret key".to_vec();
+        });
+
+        let response = test.get(&path);
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_UNKNOWN_TOKEN");
+
+        // Access tokens issued after the change are made with the new key.
+        let user = test.create_user();
+        let path = format!("/_matrix/client/r0/account/3pid?access_token={}", user.token);
+
+        assert_eq!(test.get(&path).status, Status::Ok);
+    }
+}
@@ -0,0 +1,231 @@
+use std::io::{Error as IoError, ErrorKind, Read};
+use std::time::{Duration, Instant};
+
+use bodyparser::Raw;
+use flate2::read::{DeflateDecoder, GzDecoder};
+use iron::{BeforeMiddleware, IronError, IronResult, Request};
+use iron::headers::{ContentEncoding, ContentLength, Encoding};
+
+use error::ApiError;
+
+/// The number of bytes read from the body at a time.
+const CHUNK_SIZE: usize = 8192;
+
+/// Reads request bodies before any handler parses them, rejecting bodies that are too large or
+/// take too long to arrive.
+///
+/// Bodies sent with `Content-Encoding: gzip` or `deflate` are decompressed while reading, and
+/// the limit applies to the decompressed size. Reading stops as soon as the body is larger than
+/// the limit, so oversized bodies and decompression bombs are never buffered in full. The body is
+/// stored where `bodyparser` looks for it, so handlers keep using `bodyparser` to parse it.
+pub struct BodyLimit {
+    max_size: usize,
+    timeout: Duration,
+}
+
+impl BodyLimit {
+    /// Creates a new `BodyLimit` accepting bodies of up to `max_size` bytes that arrive within
+    /// `timeout` seconds.
+    pub fn new(max_size: usize, timeout: u64) -> Self {
+        BodyLimit {
+            max_size: max_size,
+            timeout: Duration::from_secs(timeout),
+        }
+    }
+}
+
+impl BeforeMiddleware for BodyLimit {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        if let Some(&ContentLength(length)) = request.headers.get::<ContentLength>() {
+            if length > self.max_size as u64 {
+                return Err(IronError::from(ApiError::too_large(None)));
+            }
+        }
+
+        let encoding = match request.headers.get::<ContentEncoding>() {
+            Some(&ContentEncoding(ref encodings)) => {
+                let mut encodings = encodings.iter()
+                    .filter(|encoding| **encoding != Encoding::Identity);
+
+                match (encodings.next(), encodings.next()) {
+                    (None, _) => None,
+                    (Some(&Encoding::Gzip), None) => Some(Encoding::Gzip),
+                    (Some(&Encoding::Deflate), None) => Some(Encoding::Deflate),
+                    _ => {
+                        let error = ApiError::not_json(
+                            "Only gzip and deflate are supported as Content-Encoding.".to_string()
+                        );
+
+                        return Err(IronError::from(error));
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let body = read_body(&mut request.body, encoding, self.max_size, self.timeout)?;
+
+        let body = if body.is_empty() {
+            None
+        } else {
+            let body = String::from_utf8(body).map_err(|_| {
+                ApiError::not_json("The request body is not valid UTF-8.".to_string())
+            })?;
+
+            Some(body)
+        };
+
+        request.extensions.insert::<Raw>(body);
+
+        Ok(())
+    }
+}
+
+/// Reads and decompresses a body, failing once it is larger than `max_size` or takes longer than
+/// `timeout`.
+fn read_body<'a, R>(body: R, encoding: Option<Encoding>, max_size: usize, timeout: Duration)
+-> Result<Vec<u8>, ApiError> where R: Read + 'a {
+    let is_compressed = encoding.is_some();
+
+    let mut reader: Box<Read + 'a> = match encoding {
+        Some(Encoding::Gzip) => match GzDecoder::new(body) {
+            Ok(decoder) => Box::new(decoder),
+            Err(error) => return Err(read_error(error, true)),
+        },
+        Some(Encoding::Deflate) => Box::new(DeflateDecoder::new(body)),
+        _ => Box::new(body),
+    };
+
+    let started = Instant::now();
+    let mut bytes = Vec::new();
+    let mut chunk = [0; CHUNK_SIZE];
+
+    loop {
+        let read = reader.read(&mut chunk).map_err(|error| read_error(error, is_compressed))?;
+
+        if read == 0 {
+            return Ok(bytes);
+        }
+
+        if bytes.len() + read > max_size {
+            return Err(ApiError::too_large(None));
+        }
+
+        bytes.extend_from_slice(&chunk[..read]);
+
+        if started.elapsed() > timeout {
+            return Err(ApiError::request_timeout(None));
+        }
+    }
+}
+
+/// The `ApiError` for a failure to read the body, either from the connection or, for compressed
+/// bodies, from the decoder.
+fn read_error(error: IoError, is_compressed: bool) -> ApiError {
+    debug!("Failed to read the request body: {}", error);
+
+    match error.kind() {
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => ApiError::request_timeout(None),
+        _ if is_compressed => {
+            ApiError::not_json("The request body could not be decompressed.".to_string())
+        }
+        _ => ApiError::request_timeout(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::time::Duration;
+
+    use flate2::Compression;
+    use flate2::write::{DeflateEncoder, GzEncoder};
+    use iron::headers::Encoding;
+    use iron::status::Status;
+    use serde_json::to_value;
+
+    use error::ApiError;
+    use test::Test;
+    use super::read_body;
+
+    const TIMEOUT: u64 = 30;
+
+    fn errcode(error: &ApiError) -> String {
+        to_value(error).unwrap().get("errcode").unwrap().as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn oversized_body_is_rejected() {
+        let test = Test::with_config(|config| config.max_request_size = 1024);
+        let user = test.create_user();
+        let topic = "A topic that doesn't fit. ".repeat(50);
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/createRoom?access_token={}", user.token),
+            &format!(r#"{{"topic": "{}"}}"#, topic),
+        );
+
+        assert_eq!(response.status, Status::PayloadTooLarge);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_TOO_LARGE");
+    }
+
+    #[test]
+    fn body_within_limit_is_accepted() {
+        let test = Test::with_config(|config| config.max_request_size = 1024);
+        let user = test.create_user();
+        let topic = "A topic that fits. ".repeat(10);
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/createRoom?access_token={}", user.token),
+            &format!(r#"{{"topic": "{}"}}"#, topic),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("room_id").is_some());
+    }
+
+    #[test]
+    fn gzipped_body_is_decompressed() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(br#"{"topic": "Compressed"}"#).unwrap();
+        let body = encoder.finish().unwrap();
+
+        let decompressed = read_body(
+            &body[..],
+            Some(Encoding::Gzip),
+            1024,
+            Duration::from_secs(TIMEOUT),
+        ).unwrap();
+
+        assert_eq!(decompressed, br#"{"topic": "Compressed"}"#.to_vec());
+    }
+
+    #[test]
+    fn decompressed_size_is_limited() {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(&[b' '; 100000]).unwrap();
+        let body = encoder.finish().unwrap();
+        assert!(body.len() < 1024);
+
+        let error = read_body(
+            &body[..],
+            Some(Encoding::Deflate),
+            1024,
+            Duration::from_secs(TIMEOUT),
+        ).unwrap_err();
+
+        assert_eq!(errcode(&error), "M_TOO_LARGE");
+    }
+
+    #[test]
+    fn corrupt_body_is_not_json() {
+        let error = read_body(
+            &b"not gzip"[..],
+            Some(Encoding::Gzip),
+            1024,
+            Duration::from_secs(TIMEOUT),
+        ).unwrap_err();
+
+        assert_eq!(errcode(&error), "M_NOT_JSON");
+    }
+}
@@ -0,0 +1,294 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use iron::{BeforeMiddleware, IronResult, Request};
+use iron::typemap::Key;
+use serde::{Serialize, Serializer};
+
+/// Resolves the IP address of the client behind trusted reverse proxies.
+///
+/// `X-Forwarded-For` is walked from the right, starting at the socket peer, for as long as the
+/// hops are trusted proxies. The first untrusted hop is the client. The header is ignored if the
+/// peer isn't a trusted proxy, so clients connecting directly can't spoof their address.
+///
+/// The result is stored in the request's extensions as `ClientIp`.
+pub struct TrustedProxies {
+    ranges: Vec<IpRange>,
+}
+
+/// The IP address of the client of the current request.
+///
+/// Use `client_ip` to read it, which falls back to the socket peer if it wasn't resolved.
+pub struct ClientIp;
+
+impl Key for ClientIp {
+    type Value = IpAddr;
+}
+
+/// A range of IP addresses in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`.
+///
+/// A single address without a prefix length is a range of one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpRange {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl TrustedProxies {
+    /// Creates a new `TrustedProxies` trusting the proxies in the given ranges.
+    pub fn new(ranges: Vec<IpRange>) -> Self {
+        TrustedProxies {
+            ranges: ranges,
+        }
+    }
+
+    /// Whether or not the address is one of a trusted proxy.
+    fn is_trusted(&self, address: &IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(address))
+    }
+
+    /// The client's address given the socket peer and the `X-Forwarded-For` header.
+    fn resolve(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        let mut client = peer;
+
+        if !self.is_trusted(&client) {
+            return client;
+        }
+
+        let forwarded_for = match forwarded_for {
+            Some(forwarded_for) => forwarded_for,
+            None => return client,
+        };
+
+        for hop in forwarded_for.rsplit(',') {
+            client = match hop.trim().parse::<IpAddr>() {
+                Ok(address) => address,
+                Err(_) => return client,
+            };
+
+            if !self.is_trusted(&client) {
+                return client;
+            }
+        }
+
+        client
+    }
+}
+
+impl BeforeMiddleware for TrustedProxies {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let forwarded_for = request.headers.get_raw("X-Forwarded-For").map(|lines| {
+            lines.iter()
+                .map(|line| String::from_utf8_lossy(line).into_owned())
+                .collect::<Vec<String>>()
+                .join(",")
+        });
+
+        let forwarded_for = forwarded_for.as_ref().map(|forwarded_for| &forwarded_for[..]);
+        let client_ip = self.resolve(request.remote_addr.ip(), forwarded_for);
+
+        request.extensions.insert::<ClientIp>(client_ip);
+
+        Ok(())
+    }
+}
+
+impl IpRange {
+    /// Whether or not the address is in the range.
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address, *address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(range: &str) -> Result<Self, Self::Err> {
+        let mut parts = range.splitn(2, '/');
+        let address = parts.next().unwrap_or("");
+        let prefix_len = parts.next();
+
+        let address = address.parse::<IpAddr>()
+            .map_err(|_| format!("`{}` is not a valid IP address.", address))?;
+
+        let max_prefix_len = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => match prefix_len.parse::<u8>() {
+                Ok(prefix_len) if prefix_len <= max_prefix_len => prefix_len,
+                _ => return Err(format!("`{}` is not a valid prefix length.", prefix_len)),
+            },
+            None => max_prefix_len,
+        };
+
+        Ok(IpRange {
+            address: address,
+            prefix_len: prefix_len,
+        })
+    }
+}
+
+impl Display for IpRange {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+impl Serialize for IpRange {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// The client IP address resolved by `TrustedProxies`, or the socket peer's address.
+pub fn client_ip(request: &Request) -> IpAddr {
+    request.extensions.get::<ClientIp>().cloned().unwrap_or_else(|| request.remote_addr.ip())
+}
+
+/// Whether or not the first `prefix_len` bits of the two addresses are the same.
+fn prefix_matches(network: &[u8], address: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let remaining_bits = prefix_len % 8;
+
+    if network[..full_bytes] != address[..full_bytes] {
+        return false;
+    }
+
+    if remaining_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - remaining_bits);
+
+    network[full_bytes] & mask == address[full_bytes] & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use iron::headers::Headers;
+    use iron::method::Method;
+    use iron::status::Status;
+
+    use test::Test;
+    use super::{IpRange, TrustedProxies};
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    fn trusted_proxies(ranges: &[&str]) -> TrustedProxies {
+        TrustedProxies::new(ranges.iter().map(|range| range.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn parse_ranges() {
+        assert!("10.0.0.0/8".parse::<IpRange>().unwrap().contains(&ip("10.1.2.3")));
+        assert!(!"10.0.0.0/8".parse::<IpRange>().unwrap().contains(&ip("11.0.0.1")));
+        assert!("192.168.1.1".parse::<IpRange>().unwrap().contains(&ip("192.168.1.1")));
+        assert!(!"192.168.1.1".parse::<IpRange>().unwrap().contains(&ip("192.168.1.2")));
+        assert!("fd00::/8".parse::<IpRange>().unwrap().contains(&ip("fd12::1")));
+        assert!(!"fd00::/8".parse::<IpRange>().unwrap().contains(&ip("10.0.0.1")));
+        assert!("172.16.0.0/12".parse::<IpRange>().unwrap().contains(&ip("172.31.255.255")));
+        assert!(!"172.16.0.0/12".parse::<IpRange>().unwrap().contains(&ip("172.32.0.0")));
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("proxy.local".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn trusted_peer_forwards_the_client() {
+        let proxies = trusted_proxies(&["10.0.0.0/8"]);
+
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), Some("203.0.113.7")), ip("203.0.113.7"));
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), None), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let proxies = trusted_proxies(&["10.0.0.0/8"]);
+
+        assert_eq!(proxies.resolve(ip("198.51.100.1"), Some("203.0.113.7")), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn chain_of_proxies_is_walked_from_the_right() {
+        let proxies = trusted_proxies(&["10.0.0.0/8", "fd00::/8"]);
+
+        assert_eq!(
+            proxies.resolve(ip("10.0.0.1"), Some("192.0.2.66, 203.0.113.7, fd00::2, 10.0.0.2")),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            proxies.resolve(ip("10.0.0.1"), Some("not an address, 10.0.0.3")),
+            ip("10.0.0.3")
+        );
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), Some("10.0.0.3, 10.0.0.2")), ip("10.0.0.3"));
+    }
+
+    #[test]
+    fn forwarded_ip_is_recorded() {
+        let test = Test::with_config(|config| {
+            config.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
+        });
+        let alice = test.create_user();
+
+        let mut headers = Headers::new();
+        headers.set_raw("X-Forwarded-For", vec![b"203.0.113.7".to_vec()]);
+
+        let response = test.request_with_headers(
+            Method::Get,
+            &format!("/_matrix/client/r0/account/3pid?access_token={}", alice.token),
+            "",
+            headers,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/whois/{}?access_token={}",
+            alice.id,
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.body.contains("203.0.113.7"));
+    }
+
+    #[test]
+    fn forwarded_ip_from_untrusted_peer_is_ignored() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let mut headers = Headers::new();
+        headers.set_raw("X-Forwarded-For", vec![b"203.0.113.7".to_vec()]);
+
+        let response = test.request_with_headers(
+            Method::Get,
+            &format!("/_matrix/client/r0/account/3pid?access_token={}", alice.token),
+            "",
+            headers,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/whois/{}?access_token={}",
+            alice.id,
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(!response.body.contains("203.0.113.7"));
+    }
+}
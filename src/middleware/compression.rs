@@ -0,0 +1,322 @@
+//! Compression of response bodies.
+
+use std::io::{self, Write};
+
+use flate2::Compression as CompressionLevel;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use iron::{AfterMiddleware, IronResult, Request, Response};
+use iron::headers::{
+    AcceptEncoding,
+    ContentEncoding,
+    ContentLength,
+    ContentType,
+    Encoding,
+    Vary,
+    qitem,
+};
+use iron::mime::{Mime, SubLevel, TopLevel};
+use iron::response::WriteBody;
+use unicase::UniCase;
+
+/// The size in bytes below which bodies are sent uncompressed, since compressing them would save
+/// next to nothing.
+const MIN_COMPRESSED_SIZE: usize = 1024;
+
+/// Compresses response bodies with gzip or deflate if the client accepts it.
+///
+/// Only textual content types like JSON are compressed. Media and other binary content is
+/// usually compressed already and is sent as is. Content-Length is updated to the size of the
+/// compressed body, so bodies set by `SerializableResponse` stay consistent. Streamed bodies
+/// without a Content-Length are compressed as they are written.
+///
+/// Responses advertise the encodings `BodyLimit` can decompress in `Accept-Encoding`.
+pub struct Compression;
+
+impl AfterMiddleware for Compression {
+    fn after(&self, request: &mut Request, mut response: Response) -> IronResult<Response> {
+        let accept_encoding = request.headers.get::<AcceptEncoding>().cloned();
+
+        response.headers.set(AcceptEncoding(vec![qitem(Encoding::Gzip), qitem(Encoding::Deflate)]));
+
+        Ok(compress(accept_encoding.as_ref(), response))
+    }
+}
+
+/// A response body compressed while it is written.
+struct CompressedBody {
+    /// The uncompressed body.
+    body: Box<WriteBody>,
+    /// Gzip or deflate.
+    encoding: Encoding,
+}
+
+impl WriteBody for CompressedBody {
+    fn write_body(&mut self, res: &mut Write) -> io::Result<()> {
+        if self.encoding == Encoding::Gzip {
+            let mut encoder = GzEncoder::new(res, CompressionLevel::Default);
+            self.body.write_body(&mut encoder)?;
+            encoder.finish().map(|_| ())
+        } else {
+            let mut encoder = DeflateEncoder::new(res, CompressionLevel::Default);
+            self.body.write_body(&mut encoder)?;
+            encoder.finish().map(|_| ())
+        }
+    }
+}
+
+/// Compresses the body of the response with the best encoding the client accepts, if any.
+fn compress(accept_encoding: Option<&AcceptEncoding>, mut response: Response) -> Response {
+    if response.headers.has::<ContentEncoding>() || !is_compressible(response.headers.get()) {
+        return response;
+    }
+
+    let mut body = match response.body.take() {
+        Some(body) => body,
+        None => return response,
+    };
+
+    // Bodies without a length, like those of `StreamingJsonSerializer`, are compressed while they
+    // are written instead of being buffered.
+    if !response.headers.has::<ContentLength>() {
+        if let Some(encoding) = preferred_encoding(accept_encoding) {
+            response.headers.set(Vary::Items(vec![UniCase("Accept-Encoding".to_string())]));
+            response.headers.set(ContentEncoding(vec![encoding.clone()]));
+            response.body = Some(Box::new(CompressedBody {
+                body: body,
+                encoding: encoding,
+            }));
+        } else {
+            response.body = Some(body);
+        }
+
+        return response;
+    }
+
+    let mut bytes = Vec::new();
+
+    if body.write_body(&mut bytes).is_err() {
+        response.body = Some(body);
+
+        return response;
+    }
+
+    if bytes.len() < MIN_COMPRESSED_SIZE {
+        response.body = Some(Box::new(bytes));
+
+        return response;
+    }
+
+    // The body depends on Accept-Encoding from here on, whether it is compressed or not.
+    response.headers.set(Vary::Items(vec![UniCase("Accept-Encoding".to_string())]));
+
+    let encoded = match preferred_encoding(accept_encoding) {
+        Some(Encoding::Gzip) => {
+            let mut encoder = GzEncoder::new(Vec::new(), CompressionLevel::Default);
+
+            encoder.write_all(&bytes).and_then(|_| encoder.finish()).ok().map(|encoded| {
+                (Encoding::Gzip, encoded)
+            })
+        }
+        Some(Encoding::Deflate) => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), CompressionLevel::Default);
+
+            encoder.write_all(&bytes).and_then(|_| encoder.finish()).ok().map(|encoded| {
+                (Encoding::Deflate, encoded)
+            })
+        }
+        _ => None,
+    };
+
+    match encoded {
+        Some((encoding, encoded)) => {
+            response.headers.set(ContentEncoding(vec![encoding]));
+            response.headers.set(ContentLength(encoded.len() as u64));
+            response.body = Some(Box::new(encoded));
+        }
+        None => response.body = Some(Box::new(bytes)),
+    }
+
+    response
+}
+
+/// Whether or not bodies of the given content type benefit from compression.
+fn is_compressible(content_type: Option<&ContentType>) -> bool {
+    match content_type {
+        Some(&ContentType(Mime(TopLevel::Text, _, _))) => true,
+        Some(&ContentType(Mime(TopLevel::Application, ref sub_level, _))) => match *sub_level {
+            SubLevel::Json | SubLevel::Javascript => true,
+            SubLevel::Ext(ref ext) => ext == "xml",
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Returns gzip or deflate, whichever the client prefers, favoring gzip if it likes both equally.
+fn preferred_encoding(accept_encoding: Option<&AcceptEncoding>) -> Option<Encoding> {
+    let accept_encoding = match accept_encoding {
+        Some(accept_encoding) => accept_encoding,
+        None => return None,
+    };
+
+    let mut preferred: Option<(Encoding, u16)> = None;
+
+    for quality_item in accept_encoding.iter() {
+        let quality = quality_item.quality.0;
+
+        if quality == 0 {
+            continue;
+        }
+
+        let is_better = match (&quality_item.item, &preferred) {
+            (&Encoding::Gzip, &Some((_, best))) => quality >= best,
+            (&Encoding::Deflate, &Some((_, best))) => quality > best,
+            (&Encoding::Gzip, &None) | (&Encoding::Deflate, &None) => true,
+            _ => false,
+        };
+
+        if is_better {
+            preferred = Some((quality_item.item.clone(), quality));
+        }
+    }
+
+    preferred.map(|(encoding, _)| encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use iron::Response;
+    use iron::headers::{
+        AcceptEncoding,
+        ContentEncoding,
+        ContentLength,
+        ContentType,
+        Encoding,
+        Headers,
+        Vary,
+        qitem,
+    };
+    use iron::method::Method;
+    use iron::mime::{Mime, SubLevel, TopLevel};
+    use iron::response::WriteBody;
+    use iron::status::Status;
+    use serde_json::{Value, from_slice};
+
+    use modifier::StreamingJsonSerializer;
+    use test::Test;
+    use super::compress;
+
+    fn accept_gzip() -> Headers {
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        headers.set(AcceptEncoding(vec![qitem(Encoding::Gzip)]));
+        headers
+    }
+
+    #[test]
+    fn large_json_is_gzipped() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let topic = "A topic long enough to make the state worth compressing. ".repeat(50);
+        let room_id = test.create_room_with_params(
+            &alice.token,
+            &format!(r#"{{"topic": "{}"}}"#, topic),
+        );
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id,
+            alice.token
+        );
+
+        let uncompressed = test.get(&path);
+        let compressed = test.request_with_headers(Method::Get, &path, "", accept_gzip());
+
+        assert_eq!(compressed.status, Status::Ok);
+        assert_eq!(
+            compressed.headers.get::<ContentEncoding>(),
+            Some(&ContentEncoding(vec![Encoding::Gzip]))
+        );
+        assert!(compressed.headers.get::<Vary>().is_some());
+        assert!(compressed.raw_body.len() < uncompressed.raw_body.len());
+
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&compressed.raw_body[..]).unwrap().read_to_end(&mut decompressed).unwrap();
+
+        // The state events are in no particular order.
+        let sorted_event_ids = |json: &Value| {
+            let mut event_ids: Vec<String> = json.as_array().unwrap().iter()
+                .map(|event| event.get("event_id").unwrap().as_str().unwrap().to_string())
+                .collect();
+            event_ids.sort();
+            event_ids
+        };
+
+        let json: Value = from_slice(&decompressed).unwrap();
+        assert_eq!(sorted_event_ids(&json), sorted_event_ids(uncompressed.json()));
+        assert!(String::from_utf8(decompressed).unwrap().contains(&topic));
+    }
+
+    #[test]
+    fn small_response_is_untouched() {
+        let test = Test::new();
+
+        let response = test.request_with_headers(
+            Method::Get,
+            "/_matrix/client/versions",
+            "",
+            accept_gzip(),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.headers.get::<ContentEncoding>().is_none());
+        assert!(response.headers.get::<Vary>().is_none());
+        assert!(response.json().get("versions").is_some());
+        assert_eq!(
+            response.headers.get::<AcceptEncoding>(),
+            Some(&AcceptEncoding(vec![qitem(Encoding::Gzip), qitem(Encoding::Deflate)]))
+        );
+    }
+
+    #[test]
+    fn images_are_not_recompressed() {
+        let image = vec![0u8; 4096];
+        let mut response = Response::with((Status::Ok, image.clone()));
+        response.headers.set(ContentType(Mime(TopLevel::Image, SubLevel::Png, vec![])));
+
+        let accept_encoding = AcceptEncoding(vec![qitem(Encoding::Gzip)]);
+        let mut response = compress(Some(&accept_encoding), response);
+
+        assert!(response.headers.get::<ContentEncoding>().is_none());
+
+        let mut body = Vec::new();
+        response.body.take().unwrap().write_body(&mut body).unwrap();
+        assert_eq!(body, image);
+    }
+
+    #[test]
+    fn streamed_json_is_gzipped_while_written() {
+        let values = vec!["streamed"; 10];
+        let response = Response::with((Status::Ok, StreamingJsonSerializer(values.clone())));
+
+        let accept_encoding = AcceptEncoding(vec![qitem(Encoding::Gzip)]);
+        let mut response = compress(Some(&accept_encoding), response);
+
+        assert_eq!(
+            response.headers.get::<ContentEncoding>(),
+            Some(&ContentEncoding(vec![Encoding::Gzip]))
+        );
+        assert!(response.headers.get::<ContentLength>().is_none());
+
+        let mut body = Vec::new();
+        response.body.take().unwrap().write_body(&mut body).unwrap();
+
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&body[..]).unwrap().read_to_end(&mut decompressed).unwrap();
+
+        let json: Vec<String> = from_slice(&decompressed).unwrap();
+        assert_eq!(json, values);
+    }
+}
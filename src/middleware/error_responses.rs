@@ -0,0 +1,205 @@
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+use iron::{AfterMiddleware, AroundMiddleware, Handler, IronError, IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::mime::{Mime, SubLevel, TopLevel};
+use iron::status::Status;
+use router::NoRoute;
+
+use error::ApiError;
+use redaction::{redact_headers, redact_url};
+use super::request_id;
+
+/// Turns error responses that don't have a Matrix error body into ones that do.
+///
+/// Errors raised by Ruma are already `ApiError`s. This covers the ones raised by Iron and the
+/// router, e.g. for unknown endpoints, which would otherwise be sent with an empty or plain text
+/// body. Redirects are left alone.
+///
+/// It must be linked before `ResponseHeaders` and `RequestLogger`, so that the error gets their
+/// headers and request ID.
+pub struct JsonErrors;
+
+/// Answers requests whose handler panicked with `M_UNKNOWN`, instead of dropping the connection.
+pub struct CatchPanics;
+
+/// Turns errors that reach the top of the server into their responses.
+///
+/// Iron would otherwise log the whole request of every error, including its access token. The
+/// errors are logged here with secrets redacted instead: server errors as errors, others at the
+/// debug level.
+pub struct RespondToErrors;
+
+/// The handler wrapped by `CatchPanics`.
+struct PanicSafeHandler(Box<Handler>);
+
+impl AfterMiddleware for JsonErrors {
+    fn after(&self, _: &mut Request, response: Response) -> IronResult<Response> {
+        let status = match response.status {
+            Some(status) if status.is_client_error() || status.is_server_error() => status,
+            _ => return Ok(response),
+        };
+
+        if is_json(&response) {
+            return Ok(response);
+        }
+
+        Err(IronError::from(api_error_for(status)))
+    }
+
+    fn catch(&self, _: &mut Request, error: IronError) -> IronResult<Response> {
+        if error.error.downcast_ref::<ApiError>().is_some() {
+            return Err(error);
+        }
+
+        if error.error.downcast_ref::<NoRoute>().is_some() {
+            return Err(IronError::from(ApiError::unrecognized(None)));
+        }
+
+        match error.response.status {
+            Some(status) if status.is_client_error() || status.is_server_error() => {
+                debug!("Converting to ApiError from: {:?}", error.error);
+
+                Err(IronError::from(api_error_for(status)))
+            }
+            Some(_) => Err(error),
+            None => {
+                debug!("Converting to ApiError from: {:?}", error.error);
+
+                Err(IronError::from(ApiError::unknown(None)))
+            }
+        }
+    }
+}
+
+impl AfterMiddleware for RespondToErrors {
+    fn catch(&self, request: &mut Request, error: IronError) -> IronResult<Response> {
+        let is_server_error = error.response.status.map_or(true, |status| status.is_server_error());
+
+        let url = redact_url(&request.url);
+
+        if is_server_error {
+            error!("{} Error handling {} {}: {}", request_id(request), request.method, url, error);
+        } else {
+            debug!("{} Error handling {} {}: {}", request_id(request), request.method, url, error);
+        }
+
+        Ok(error.response)
+    }
+}
+
+impl AroundMiddleware for CatchPanics {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(PanicSafeHandler(handler))
+    }
+}
+
+impl Handler for PanicSafeHandler {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        match catch_unwind(AssertUnwindSafe(|| self.0.handle(request))) {
+            Ok(result) => result,
+            Err(_) => {
+                error!(
+                    "{} The handler for {} {} panicked. Headers:\n{}",
+                    request_id(request),
+                    request.method,
+                    redact_url(&request.url),
+                    redact_headers(&request.headers)
+                );
+
+                Err(IronError::from(ApiError::unknown(None)))
+            }
+        }
+    }
+}
+
+/// The `ApiError` closest to an error response with the given status.
+fn api_error_for(status: Status) -> ApiError {
+    match status {
+        Status::NotFound => ApiError::unrecognized(None),
+        Status::MethodNotAllowed => ApiError::method_not_allowed(None),
+        Status::PayloadTooLarge => ApiError::too_large(None),
+        _ => ApiError::unknown(None),
+    }
+}
+
+/// Whether or not the response has a JSON body.
+fn is_json(response: &Response) -> bool {
+    match response.headers.get::<ContentType>() {
+        Some(&ContentType(Mime(TopLevel::Application, SubLevel::Json, _))) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::{Chain, IronResult, Request, Response};
+    use iron::headers::Headers;
+    use iron::status::Status;
+    use iron_test::{request, response};
+    use serde_json::{Value, from_str};
+
+    use test::Test;
+    use super::{CatchPanics, JsonErrors};
+
+    fn panicking_handler(_: &mut Request) -> IronResult<Response> {
+        panic!("The handler failed.");
+    }
+
+    #[test]
+    fn unknown_endpoint_is_unrecognized() {
+        let test = Test::new();
+        let response = test.get("/_matrix/client/r0/no_such_endpoint");
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_UNRECOGNIZED");
+        assert!(response.json().get("request_id").is_some());
+    }
+
+    #[test]
+    fn non_json_body_is_not_json() {
+        let test = Test::new();
+        let user = test.create_user();
+        let room_id = test.create_room(&user.token);
+
+        let response = test.put(
+            &format!("/_matrix/client/r0/directory/room/my_room?access_token={}", user.token),
+            &format!("room_id: {}", room_id),
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_NOT_JSON");
+        assert!(response.json().get("error").is_some());
+    }
+
+    #[test]
+    fn panics_are_internal_errors() {
+        let mut chain = Chain::new(panicking_handler);
+        chain.link_around(CatchPanics);
+        chain.link_after(JsonErrors);
+
+        let error = request::get("http://localhost/", Headers::new(), &chain).unwrap_err();
+
+        assert_eq!(error.response.status, Some(Status::InternalServerError));
+
+        let json: Value = from_str(&response::extract_body_to_string(error.response)).unwrap();
+        assert_eq!(json.get("errcode").unwrap().as_str().unwrap(), "M_UNKNOWN");
+    }
+
+    #[test]
+    fn plain_error_responses_get_json_bodies() {
+        fn plain_not_found(_: &mut Request) -> IronResult<Response> {
+            Ok(Response::with((Status::NotFound, "Not found")))
+        }
+
+        let mut chain = Chain::new(plain_not_found);
+        chain.link_after(JsonErrors);
+
+        let error = request::get("http://localhost/", Headers::new(), &chain).unwrap_err();
+
+        assert_eq!(error.response.status, Some(Status::NotFound));
+
+        let json: Value = from_str(&response::extract_body_to_string(error.response)).unwrap();
+        assert_eq!(json.get("errcode").unwrap().as_str().unwrap(), "M_UNRECOGNIZED");
+    }
+}
@@ -1,4 +1,6 @@
-use bodyparser;
+use std::io::ErrorKind;
+
+use bodyparser::{self, BodyErrorCause};
 use iron::{BeforeMiddleware, IronResult, Plugin, Request};
 use iron::headers::ContentType;
 use iron::mime::{Mime, SubLevel, TopLevel};
@@ -6,8 +8,13 @@ use iron::typemap::Key;
 use serde_json::Value;
 
 use error::ApiError;
+use text_validation;
 
 /// Ensures that requests contain valid JSON and stores the parsed JSON in the Iron request.
+///
+/// Bodies that aren't JSON fail with `M_NOT_JSON`, bodies over the size limit with
+/// `M_TOO_LARGE`. Handlers report JSON of the wrong shape with `M_BAD_JSON`, as does this
+/// middleware for strings with unpaired surrogate escapes.
 pub struct JsonRequest;
 
 impl Key for JsonRequest {
@@ -25,7 +32,23 @@ impl BeforeMiddleware for JsonRequest {
 
         match request.get::<bodyparser::Json>() {
             Ok(Some(_)) => Ok(()),
-            Ok(_) | Err(_) => Err(ApiError::not_json(None))?,
+            Ok(None) => Err(ApiError::not_json(None))?,
+            Err(error) => match error.cause {
+                BodyErrorCause::IoError(ref error) if error.kind() == ErrorKind::InvalidInput => {
+                    Err(ApiError::too_large(None))?
+                }
+                BodyErrorCause::Utf8Error(_) => {
+                    Err(ApiError::not_json("The request body is not valid UTF-8.".to_string()))?
+                }
+                BodyErrorCause::JsonError(_) => {
+                    if let Ok(Some(body)) = request.get::<bodyparser::Raw>() {
+                        text_validation::check_surrogates(&body)?;
+                    }
+
+                    Err(ApiError::not_json(None))?
+                }
+                BodyErrorCause::IoError(_) => Err(ApiError::not_json(None))?,
+            },
         }
     }
 }
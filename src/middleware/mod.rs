@@ -2,13 +2,35 @@
 use iron::Chain;
 
 mod authentication;
+mod body_limit;
+mod client_ip;
+mod compression;
+mod error_responses;
 mod json;
+mod path_normalization;
 mod path_params;
+mod request_log;
 mod response_headers;
+mod shutdown;
+mod transaction_idempotency;
 
-pub use self::authentication::{AccessTokenAuth, UIAuth};
+pub use self::authentication::{
+    AccessTokenAuth,
+    AdminAuth,
+    FederationAuth,
+    UIAuth,
+    find_access_token,
+};
+pub use self::body_limit::BodyLimit;
+pub use self::client_ip::{ClientIp, IpRange, TrustedProxies, client_ip};
+pub use self::compression::Compression;
+pub use self::error_responses::{CatchPanics, JsonErrors, RespondToErrors};
+pub use self::request_log::{LOG_TARGET as REQUEST_LOG_TARGET, RequestId, RequestLogger, request_id};
 pub use self::response_headers::ResponseHeaders;
+pub use self::shutdown::InFlightRequests;
+pub use self::transaction_idempotency::TransactionIdempotency;
 pub use self::json::JsonRequest;
+pub use self::path_normalization::NormalizePath;
 pub use self::path_params::{
     DataTypeParam,
     EventTypeParam,
@@ -0,0 +1,116 @@
+//! Normalization of request paths before routing.
+
+use iron::{BeforeMiddleware, IronResult, Request};
+use mount::OriginalUrl;
+
+/// Collapses duplicate slashes in the request path and strips trailing slashes, so that
+/// `//_matrix/client/r0/sync` and `/_matrix/client/r0/directory/room/my_room/` are routed like
+/// their canonical paths.
+///
+/// A trailing slash after `/state/:event_type` stands for an empty state key, which the routes
+/// without a state key already treat as empty, so stripping it keeps its meaning.
+///
+/// The URL as sent is kept as the `OriginalUrl`, which federation signatures are verified
+/// against.
+pub struct NormalizePath;
+
+impl BeforeMiddleware for NormalizePath {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let normalized = match normalize(&request.url.path()) {
+            Some(normalized) => normalized,
+            None => return Ok(()),
+        };
+
+        if !request.extensions.contains::<OriginalUrl>() {
+            let original_url = request.url.clone();
+
+            request.extensions.insert::<OriginalUrl>(original_url);
+        }
+
+        request.url.as_mut().set_path(&normalized);
+
+        Ok(())
+    }
+}
+
+/// The normalized path for the given path segments, or `None` if they are already normal.
+fn normalize(segments: &[&str]) -> Option<String> {
+    let kept: Vec<&str> = segments.iter().cloned().filter(|segment| !segment.is_empty()).collect();
+
+    if kept.is_empty() || kept.len() == segments.len() {
+        return None;
+    }
+
+    Some(format!("/{}", kept.join("/")))
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+    use super::normalize;
+
+    #[test]
+    fn paths_are_normalized() {
+        assert_eq!(normalize(&["", "_matrix", "client"]), Some("/_matrix/client".to_string()));
+        assert_eq!(normalize(&["_matrix", "", "client"]), Some("/_matrix/client".to_string()));
+        assert_eq!(normalize(&["_matrix", "client", ""]), Some("/_matrix/client".to_string()));
+        assert_eq!(normalize(&["_matrix", "client"]), None);
+        assert_eq!(normalize(&[""]), None);
+    }
+
+    #[test]
+    fn double_slash_sync() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.get(&format!("//_matrix/client/r0/sync?access_token={}", alice.token));
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("next_batch").is_some());
+    }
+
+    #[test]
+    fn trailing_slash_directory_lookup() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/createRoom?access_token={}", alice.token),
+            r#"{"room_alias_name": "my_room"}"#,
+        );
+        let room_id = response.json().get("room_id").unwrap().as_str().unwrap().to_string();
+
+        let response = test.get("/_matrix/client/r0/directory/room/my_room/");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("room_id").unwrap().as_str().unwrap(), room_id);
+    }
+
+    #[test]
+    fn empty_state_key_route_still_resolves() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/state/m.room.topic/?access_token={}",
+                room_id,
+                alice.token
+            ),
+            r#"{"topic": "Rust"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic/?access_token={}",
+            room_id,
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("topic").unwrap().as_str().unwrap(), "Rust");
+    }
+}
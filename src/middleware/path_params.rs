@@ -1,10 +1,8 @@
-use std::convert::TryFrom;
-use std::convert::From;
+use std::convert::{From, TryFrom};
 use std::error::Error;
 
 use iron::{BeforeMiddleware, IronResult, Request};
 use iron::typemap::Key;
-use router::Router;
 use ruma_events::EventType;
 use ruma_identifiers::{
     UserId,
@@ -14,7 +12,10 @@ use ruma_identifiers::{
 };
 
 use config::Config;
-use error::{ApiError, MapApiError};
+use error::ApiError;
+use identifiers::{self, MAX_IDENTIFIER_LENGTH};
+use models::room_alias::check_alias_localpart;
+use request_ext::params;
 use url::percent_encoding::percent_decode;
 
 /// Extracts a `RoomId` from the URL path parameter `room_id`.
@@ -26,7 +27,7 @@ impl Key for RoomIdParam {
 
 impl BeforeMiddleware for RoomIdParam {
     fn before(&self, request: &mut Request) -> IronResult<()> {
-        let params = request.extensions.get::<Router>().expect("Params object is missing").clone();
+        let params = params(request)?.clone();
         let room_id = match params.find("room_id") {
             Some(room_id) => {
                 let decoded_room_id = percent_decode(room_id.as_bytes())
@@ -35,9 +36,7 @@ impl BeforeMiddleware for RoomIdParam {
                         ApiError::invalid_param("room_id", err.description())
                     })?;
 
-                RoomId::try_from(&decoded_room_id).map_api_err(|err| {
-                    ApiError::invalid_param("room_id", err.description())
-                })
+                identifiers::parse::<RoomId>("room_id", &decoded_room_id)
             },
             None => Err(ApiError::missing_param("room_id"))
         }?;
@@ -55,7 +54,7 @@ impl Key for RoomIdOrAliasParam {
 
 impl BeforeMiddleware for RoomIdOrAliasParam {
     fn before(&self, request: &mut Request) -> IronResult<()> {
-        let params = request.extensions.get::<Router>().expect("Params object is missing").clone();
+        let params = params(request)?.clone();
         let room_id_or_alias = match params.find("room_id_or_alias") {
             Some(room_id_or_alias) => {
                 let decoded_room_id_or_alias = percent_decode(room_id_or_alias.as_bytes())
@@ -64,9 +63,7 @@ impl BeforeMiddleware for RoomIdOrAliasParam {
                         ApiError::invalid_param("room_id_or_alias", err.description())
                     })?;
 
-                RoomIdOrAliasId::try_from(&decoded_room_id_or_alias).map_api_err(|err| {
-                    ApiError::invalid_param("room_id_or_alias", err.description())
-                })
+                identifiers::parse::<RoomIdOrAliasId>("room_id_or_alias", &decoded_room_id_or_alias)
             },
             None => Err(ApiError::missing_param("room_id_or_alias"))
         }?;
@@ -84,8 +81,7 @@ impl Key for UserIdParam {
 
 impl BeforeMiddleware for UserIdParam {
     fn before(&self, request: &mut Request) -> IronResult<()> {
-        let params = request.extensions.get::<Router>()
-            .expect("Params object is missing").clone();
+        let params = params(request)?.clone();
 
         let user_id = match params.find("user_id") {
             Some(user_id) => {
@@ -95,9 +91,7 @@ impl BeforeMiddleware for UserIdParam {
                         ApiError::invalid_param("user_id", err.description())
                     })?;
 
-                UserId::try_from(&decoded_user_id).map_api_err(|err| {
-                    ApiError::invalid_param("user_id", err.description())
-                })
+                identifiers::parse::<UserId>("user_id", &decoded_user_id)
             },
             None => Err(ApiError::missing_param("user_id"))
         }?;
@@ -117,8 +111,7 @@ impl Key for DataTypeParam {
 
 impl BeforeMiddleware for DataTypeParam {
     fn before(&self, request: &mut Request) -> IronResult<()> {
-        let params = request.extensions.get::<Router>()
-            .expect("Params object is missing").clone();
+        let params = params(request)?.clone();
 
         let data_type = params.find("type")
             .ok_or_else(||ApiError::missing_param("type"))?;
@@ -139,8 +132,7 @@ impl Key for FilterIdParam {
 
 impl BeforeMiddleware for FilterIdParam {
     fn before(&self, request: &mut Request) -> IronResult<()> {
-        let params = request.extensions.get::<Router>()
-            .expect("Params object is missing").clone();
+        let params = params(request)?.clone();
 
         let filter_id = params.find("filter_id")
             .ok_or_else(||ApiError::missing_param("filter_id"))?;
@@ -154,6 +146,9 @@ impl BeforeMiddleware for FilterIdParam {
 }
 
 /// Extracts `RoomAliasId` from the URL path parameter `room_alias`.
+///
+/// The parameter is either the local part of an alias on this server or a full alias like
+/// `#room:example.com`, which may belong to another server.
 pub struct RoomAliasIdParam;
 
 impl Key for RoomAliasIdParam {
@@ -162,8 +157,7 @@ impl Key for RoomAliasIdParam {
 
 impl BeforeMiddleware for RoomAliasIdParam {
     fn before(&self, request: &mut Request) -> IronResult<()> {
-        let params = request.extensions.get::<Router>()
-            .expect("Params object is missing").clone();
+        let params = params(request)?.clone();
 
         let config = Config::from_request(request)?;
 
@@ -171,10 +165,20 @@ impl BeforeMiddleware for RoomAliasIdParam {
             Some(room_alias) => {
                 debug!("room_alias param: {}", room_alias);
 
-                RoomAliasId::try_from(
-                    &format!("#{}:{}", room_alias, config.domain)
-                ).map_api_err(|err| {
-                    ApiError::invalid_param("room_alias", err.description())
+                let decoded = percent_decode(room_alias.as_bytes())
+                    .decode_utf8()
+                    .map(|decoded| decoded.to_string())
+                    .map_err(|error| (room_alias.to_string(), error.description().to_string()));
+
+                decoded.and_then(|room_alias| {
+                    parse_room_alias(&room_alias, &config).map_err(|reason| (room_alias, reason))
+                }).map_err(|(room_alias, reason)| {
+                    // The input is echoed with `{:?}` so that quotes and control characters in
+                    // it are escaped.
+                    ApiError::invalid_param(
+                        "room_alias",
+                        &format!("{:?} is not a valid room alias: {}", room_alias, reason),
+                    )
                 })?
             }
             None => Err(ApiError::missing_param("room_alias"))?,
@@ -186,6 +190,32 @@ impl BeforeMiddleware for RoomAliasIdParam {
     }
 }
 
+/// Parses the decoded `room_alias` path parameter, returning why it is invalid otherwise.
+///
+/// The local part of aliases on this server must also be valid for new aliases.
+fn parse_room_alias(room_alias: &str, config: &Config) -> Result<RoomAliasId, String> {
+    let (localpart, full_alias) = if room_alias.starts_with('#') {
+        let localpart = room_alias[1..].split(':').next().unwrap_or("").to_string();
+
+        (localpart, room_alias.to_string())
+    } else {
+        (room_alias.to_string(), format!("#{}:{}", room_alias, config.domain))
+    };
+
+    let is_local = !room_alias.starts_with('#') ||
+        full_alias.ends_with(&format!(":{}", config.domain));
+
+    if is_local {
+        check_alias_localpart(&localpart, config.max_alias_length)?;
+    }
+
+    if full_alias.len() > MAX_IDENTIFIER_LENGTH {
+        return Err(format!("must not be longer than {} bytes", MAX_IDENTIFIER_LENGTH));
+    }
+
+    RoomAliasId::try_from(&full_alias[..]).map_err(|error| error.description().to_string())
+}
+
 /// Extracts `EventType` from the URL path parameter `event_type`.
 pub struct EventTypeParam;
 
@@ -195,8 +225,7 @@ impl Key for EventTypeParam {
 
 impl BeforeMiddleware for EventTypeParam {
     fn before(&self, request: &mut Request) -> IronResult<()> {
-        let params = request.extensions.get::<Router>()
-            .expect("Params object is missing").clone();
+        let params = params(request)?.clone();
 
         let event_type = params.find("event_type")
             .ok_or_else(||ApiError::missing_param("event_type"))
@@ -217,8 +246,7 @@ impl Key for TagParam {
 
 impl BeforeMiddleware for TagParam {
     fn before(&self, request: &mut Request) -> IronResult<()> {
-        let params = request.extensions.get::<Router>()
-            .expect("Params object is missing").clone();
+        let params = params(request)?.clone();
 
         let tag = params.find("tag")
             .ok_or_else(||ApiError::missing_param("tag"))?;
@@ -239,8 +267,7 @@ impl Key for TransactionIdParam {
 
 impl BeforeMiddleware for TransactionIdParam {
     fn before(&self, request: &mut Request) -> IronResult<()> {
-        let params = request.extensions.get::<Router>()
-            .expect("Params object is missing").clone();
+        let params = params(request)?.clone();
 
         let transaction_id = params.find("transaction_id")
             .ok_or_else(||ApiError::missing_param("transaction_id"))?;
@@ -0,0 +1,270 @@
+use std::time::{Duration, Instant};
+
+use iron::{AfterMiddleware, BeforeMiddleware, IronError, IronResult, Request, Response, Set};
+use iron::typemap::Key;
+use mount::OriginalUrl;
+use rand::{Rng, thread_rng};
+
+use error::ApiError;
+use models::user::User;
+use super::client_ip;
+
+/// The `log` target for the line logged when a request completes.
+pub const LOG_TARGET: &'static str = "ruma::request";
+
+/// The header carrying the ID of a request.
+const REQUEST_ID_HEADER: &'static str = "X-Request-Id";
+
+/// The maximum length of a request ID provided by a reverse proxy.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Assigns every request an ID and logs a line for it when it completes.
+///
+/// The ID is taken from the `X-Request-Id` request header if a reverse proxy already set one, so
+/// that the proxy's logs can be matched to Ruma's, and generated as a random UUID otherwise. It
+/// is stored in the request's extensions as `RequestId`, returned in the `X-Request-Id`
+/// response header, and included in the body of error responses. Requests that take longer
+/// than the threshold are logged as warnings.
+///
+/// It must be linked as both the first before middleware and the last after middleware of a
+/// chain.
+pub struct RequestLogger {
+    slow_request_threshold: Duration,
+}
+
+/// The ID of the current request.
+pub struct RequestId;
+
+impl Key for RequestId {
+    type Value = String;
+}
+
+/// The time the current request started.
+struct RequestStart;
+
+impl Key for RequestStart {
+    type Value = Instant;
+}
+
+impl RequestLogger {
+    /// Creates a new `RequestLogger` that warns about requests slower than the given number of
+    /// milliseconds.
+    pub fn new(slow_request_threshold: u64) -> Self {
+        RequestLogger {
+            slow_request_threshold: Duration::from_millis(slow_request_threshold),
+        }
+    }
+
+    /// The ID provided by a reverse proxy in the request's headers, if it is a valid one.
+    ///
+    /// IDs are only accepted if they are short and made of characters that are safe to log.
+    fn provided_request_id(request: &Request) -> Option<String> {
+        let values = match request.headers.get_raw(REQUEST_ID_HEADER) {
+            Some(values) if values.len() == 1 => values,
+            _ => return None,
+        };
+
+        let request_id = match String::from_utf8(values[0].clone()) {
+            Ok(request_id) => request_id,
+            Err(_) => return None,
+        };
+
+        let is_valid = !request_id.is_empty() && request_id.len() <= MAX_REQUEST_ID_LENGTH &&
+            request_id.chars().all(|c| match c {
+                'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' | '.' | ':' => true,
+                _ => false,
+            });
+
+        if is_valid { Some(request_id) } else { None }
+    }
+
+    /// Logs the completion of a request, returning its ID.
+    fn log(&self, request: &mut Request, response: &mut Response) -> String {
+        let request_id = request.extensions.get::<RequestId>().cloned()
+            .unwrap_or_else(generate_request_id);
+        let duration = request.extensions.get::<RequestStart>()
+            .map_or(Duration::from_secs(0), Instant::elapsed);
+        let milliseconds = duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64;
+
+        let path = request.extensions.get::<OriginalUrl>().unwrap_or(&request.url).path().join("/");
+        let status = response.status.map_or("-".to_string(), |status| status.to_u16().to_string());
+        let user_id = request.extensions.get::<User>()
+            .map_or("-".to_string(), |user| user.id.to_string());
+        let ip = client_ip(request);
+
+        if duration >= self.slow_request_threshold {
+            warn!(
+                target: LOG_TARGET,
+                "{} {} /{} {} {}ms user={} ip={} (slow request)",
+                request_id,
+                request.method,
+                path,
+                status,
+                milliseconds,
+                user_id,
+                ip,
+            );
+        } else {
+            info!(
+                target: LOG_TARGET,
+                "{} {} /{} {} {}ms user={} ip={}",
+                request_id,
+                request.method,
+                path,
+                status,
+                milliseconds,
+                user_id,
+                ip,
+            );
+        }
+
+        response.headers.set_raw(REQUEST_ID_HEADER, vec![request_id.clone().into_bytes()]);
+
+        request_id
+    }
+}
+
+impl BeforeMiddleware for RequestLogger {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let request_id = RequestLogger::provided_request_id(request)
+            .unwrap_or_else(generate_request_id);
+
+        request.extensions.insert::<RequestId>(request_id);
+        request.extensions.insert::<RequestStart>(Instant::now());
+
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for RequestLogger {
+    fn after(&self, request: &mut Request, mut response: Response) -> IronResult<Response> {
+        self.log(request, &mut response);
+
+        Ok(response)
+    }
+
+    fn catch(&self, request: &mut Request, mut error: IronError) -> IronResult<Response> {
+        let api_error = error.error.downcast_ref::<ApiError>().cloned();
+        let request_id = self.log(request, &mut error.response);
+
+        if let Some(api_error) = api_error {
+            error.response.set_mut(api_error.with_request_id(&request_id));
+        }
+
+        Err(error)
+    }
+}
+
+/// The ID of the current request, or `-` if it doesn't have one, for use in log lines.
+pub fn request_id(request: &Request) -> &str {
+    request.extensions.get::<RequestId>().map_or("-", |request_id| request_id.as_str())
+}
+
+/// Generates a random (version 4) UUID for a request.
+fn generate_request_id() -> String {
+    let mut bytes = [0u8; 16];
+
+    thread_rng().fill_bytes(&mut bytes);
+
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex[0..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..16].concat(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::headers::{ContentType, Headers};
+    use iron::method::Method;
+    use iron::status::Status;
+
+    use test::{Test, captured_logs};
+    use super::{LOG_TARGET, generate_request_id};
+
+    fn request_id_header(response: &::test::Response) -> String {
+        String::from_utf8(response.headers.get_raw("X-Request-Id").unwrap()[0].clone()).unwrap()
+    }
+
+    #[test]
+    fn responses_have_request_id_header() {
+        let test = Test::new();
+        let response = test.get("/_matrix/client/versions");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.headers.get_raw("X-Request-Id").is_some());
+    }
+
+    #[test]
+    fn error_response_can_be_matched_to_log_line() {
+        let test = Test::new();
+        let response = test.get("/_matrix/client/r0/sync?access_token=invalid");
+
+        assert_eq!(response.status, Status::Unauthorized);
+
+        let request_id = response.json().get("request_id").unwrap().as_str().unwrap().to_string();
+        assert_eq!(request_id, request_id_header(&response));
+        assert!(captured_logs(LOG_TARGET).iter().any(|line| {
+            line.starts_with(&request_id) && line.contains("/_matrix/client/r0/sync 401")
+        }));
+    }
+
+    #[test]
+    fn generated_request_ids_are_uuids() {
+        let request_id = generate_request_id();
+        let groups: Vec<&str> = request_id.split('-').collect();
+
+        assert_eq!(request_id.len(), 36);
+        let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+
+        assert_eq!(lengths, vec![8, 4, 4, 4, 12]);
+        assert!(groups.iter().all(|group| group.chars().all(|c| c.is_digit(16))));
+        assert!(groups[2].starts_with('4'));
+        assert!("89ab".contains(&groups[3][0..1]));
+        assert!(request_id != generate_request_id());
+    }
+
+    #[test]
+    fn request_id_from_proxy_is_echoed_back() {
+        let test = Test::new();
+        let mut headers = Headers::new();
+
+        headers.set(ContentType::json());
+        headers.set_raw("X-Request-Id", vec![b"proxy-1234".to_vec()]);
+
+        let response = test.request_with_headers(
+            Method::Get,
+            "/_matrix/client/r0/sync?access_token=invalid",
+            "",
+            headers,
+        );
+
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(request_id_header(&response), "proxy-1234");
+        assert_eq!(response.json().get("request_id").unwrap().as_str().unwrap(), "proxy-1234");
+        assert!(captured_logs(LOG_TARGET).iter().any(|line| line.starts_with("proxy-1234 ")));
+    }
+
+    #[test]
+    fn invalid_request_id_from_proxy_is_replaced() {
+        let test = Test::new();
+        let mut headers = Headers::new();
+
+        headers.set(ContentType::json());
+        headers.set_raw("X-Request-Id", vec![b"bad id\nforged log line".to_vec()]);
+
+        let response =
+            test.request_with_headers(Method::Get, "/_matrix/client/versions", "", headers);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(request_id_header(&response).len(), 36);
+    }
+}
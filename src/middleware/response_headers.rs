@@ -18,13 +18,22 @@ fn add_server_header(response: &mut Response) {
     );
 }
 
+/// The request headers browser-based clients may send, as recommended by the specification.
+const ALLOWED_HEADERS: [&'static str; 5] = [
+    "origin",
+    "x-requested-with",
+    "content-type",
+    "accept",
+    "authorization",
+];
+
 /// Adds Cross-Origin Resource Sharing headers to HTTP responses.
 fn add_cors_headers(response: &mut Response) {
     response.headers.set(AccessControlAllowHeaders(
-        vec![UniCase("accept".to_string()), UniCase("content-type".to_string())]
+        ALLOWED_HEADERS.iter().map(|header| UniCase(header.to_string())).collect()
     ));
     response.headers.set(AccessControlAllowMethods(
-        vec![Method::Get, Method::Post, Method::Put, Method::Delete]
+        vec![Method::Get, Method::Post, Method::Put, Method::Delete, Method::Options]
     ));
     response.headers.set(AccessControlAllowOrigin::Any);
 }
@@ -40,7 +49,13 @@ impl AfterMiddleware for ResponseHeaders {
         Ok(response)
     }
 
-    fn catch(&self, _: &mut Request, mut error: IronError) -> IronResult<Response> {
+    fn catch(&self, request: &mut Request, mut error: IronError) -> IronResult<Response> {
+        // Preflight requests are answered before authentication, path parameters, or routing
+        // could reject them, since browsers don't send credentials with them.
+        if request.method == Method::Options {
+            return self.after(request, Response::with(status::Ok));
+        }
+
         add_server_header(&mut error.response);
         add_cors_headers(&mut error.response);
 
@@ -51,6 +66,7 @@ impl AfterMiddleware for ResponseHeaders {
 #[cfg(test)]
 mod tests {
     use iron::method::Method;
+    use iron::status::Status;
     use iron::headers::{
         AccessControlAllowHeaders,
         AccessControlAllowMethods,
@@ -65,16 +81,13 @@ mod tests {
             response.headers.get::<Server>().unwrap(),
             &Server(format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")))
         );
-        assert_eq!(
-            response.headers.get::<AccessControlAllowHeaders>().unwrap(),
-            &AccessControlAllowHeaders(
-                vec![UniCase("accept".to_string()), UniCase("content-type".to_string())]
-            )
-        );
+        let allowed_headers = response.headers.get::<AccessControlAllowHeaders>().unwrap();
+        assert!(allowed_headers.contains(&UniCase("content-type".to_string())));
+        assert!(allowed_headers.contains(&UniCase("authorization".to_string())));
         assert_eq!(
             response.headers.get::<AccessControlAllowMethods>().unwrap(),
             &AccessControlAllowMethods(
-                vec![Method::Get, Method::Post, Method::Put, Method::Delete]
+                vec![Method::Get, Method::Post, Method::Put, Method::Delete, Method::Options]
             )
         );
         assert_eq!(
@@ -109,4 +122,26 @@ mod tests {
         // Check to see if the expected headers have been added to the response.
         check_for_modified_headers(&response);
     }
+
+    #[test]
+    fn preflight_requests_succeed_without_authentication() {
+        let test = Test::new();
+        let response = test.request(
+            Method::Options,
+            "/_matrix/client/r0/directory/room/my_room",
+            "",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        check_for_modified_headers(&response);
+    }
+
+    #[test]
+    fn error_responses_have_cors_headers() {
+        let test = Test::new();
+        let response = test.get("/_matrix/client/r0/directory/room/no_room");
+
+        assert_eq!(response.status, Status::NotFound);
+        check_for_modified_headers(&response);
+    }
 }
@@ -0,0 +1,54 @@
+use iron::{AfterMiddleware, BeforeMiddleware, IronError, IronResult, Request, Response};
+use iron::typemap::Key;
+
+use error::ApiError;
+use shutdown::Shutdown;
+
+/// Keeps track of in-flight requests and rejects new ones once the server is shutting down.
+///
+/// It must be linked as both the first before middleware and an after middleware of a chain.
+pub struct InFlightRequests(pub Shutdown);
+
+/// Marks a request as counted by `InFlightRequests`.
+struct Counted;
+
+impl Key for Counted {
+    type Value = ();
+}
+
+impl InFlightRequests {
+    fn finish(&self, request: &mut Request) {
+        if request.extensions.remove::<Counted>().is_some() {
+            self.0.request_finished();
+        }
+    }
+}
+
+impl BeforeMiddleware for InFlightRequests {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        if self.0.is_shutting_down() {
+            let error = ApiError::unavailable("The server is shutting down.".to_string());
+
+            return Err(IronError::from(error));
+        }
+
+        self.0.request_started();
+        request.extensions.insert::<Counted>(());
+
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for InFlightRequests {
+    fn after(&self, request: &mut Request, response: Response) -> IronResult<Response> {
+        self.finish(request);
+
+        Ok(response)
+    }
+
+    fn catch(&self, request: &mut Request, error: IronError) -> IronResult<Response> {
+        self.finish(request);
+
+        Err(error)
+    }
+}
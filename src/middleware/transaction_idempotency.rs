@@ -0,0 +1,237 @@
+use std::rc::Rc;
+
+use diesel::Connection;
+use diesel::connection::TransactionManager;
+use diesel::pg::PgConnection;
+use iron::{AroundMiddleware, Handler, IronResult, Request, Response};
+use iron::response::WriteBody;
+use iron::status::Status;
+
+use clock::{self, Clock};
+use db::{DB, SharedConnection};
+use error::ApiError;
+use models::access_token::AccessToken;
+use models::transaction::Transaction;
+use modifier::set_json_body;
+use request_ext::extension;
+
+/// Makes endpoints with a transaction ID in their path idempotent per access token.
+///
+/// The body of the first successful response is saved in the `transactions` table. Repeated
+/// requests with the same method and path and the same access token get the saved body back,
+/// byte for byte, without running the handler again. Failed requests aren't saved, so they can be
+/// retried.
+///
+/// The handler runs in a database transaction on a connection shared through `DB::from_request`,
+/// which also reserves the transaction ID and saves the response. A request that fails, or a
+/// server that crashes, leaves neither the changes of the handler nor the transaction behind.
+///
+/// It must be linked around the handler, so that `AccessTokenAuth` runs before it.
+pub struct TransactionIdempotency;
+
+/// The handler wrapped by `TransactionIdempotency`.
+struct IdempotentHandler(Box<Handler>);
+
+impl AroundMiddleware for TransactionIdempotency {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(IdempotentHandler(handler))
+    }
+}
+
+impl Handler for IdempotentHandler {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let method = request.method.to_string();
+        let path = request.url.path().join("/");
+        let access_token = extension::<AccessToken>(request)?.value;
+        let clock = clock::from_request(request)?;
+
+        let pool = DB::pool_from_request(request)?;
+        let connection = Rc::new(pool.get().map_err(ApiError::from)?);
+
+        connection.transaction_manager().begin_transaction(&**connection)
+            .map_err(ApiError::from)?;
+
+        request.extensions.insert::<SharedConnection>(connection.clone());
+
+        let result =
+            self.handle_reserved(request, &connection, &*clock, &method, &path, &access_token);
+
+        request.extensions.remove::<SharedConnection>();
+
+        let transaction_manager = connection.transaction_manager();
+
+        match result {
+            Ok((response, true)) => {
+                transaction_manager.commit_transaction(&**connection).map_err(ApiError::from)?;
+
+                Ok(response)
+            }
+            Ok((response, false)) => {
+                transaction_manager.rollback_transaction(&**connection)
+                    .map_err(ApiError::from)?;
+
+                Ok(response)
+            }
+            Err(error) => {
+                let rollback = transaction_manager.rollback_transaction(&**connection);
+
+                if let Err(rollback_error) = rollback {
+                    warn!("Failed to roll back an idempotent request: {}", rollback_error);
+                }
+
+                Err(error)
+            }
+        }
+    }
+}
+
+impl IdempotentHandler {
+    /// Replays the saved response, or runs the handler and saves its response if it succeeds.
+    ///
+    /// Returns whether the database transaction should be committed along with the response.
+    fn handle_reserved(
+        &self,
+        request: &mut Request,
+        connection: &PgConnection,
+        clock: &Clock,
+        method: &str,
+        path: &str,
+        access_token: &str,
+    ) -> IronResult<(Response, bool)> {
+        if let Some(body) = Transaction::reserve(connection, clock, method, path, access_token)? {
+            let mut response = Response::with(Status::Ok);
+            set_json_body(&mut response, body);
+
+            return Ok((response, false));
+        }
+
+        let mut response = self.0.handle(request)?;
+
+        if response.status != Some(Status::Ok) {
+            return Ok((response, false));
+        }
+
+        let mut bytes = Vec::new();
+
+        if let Some(mut body) = response.body.take() {
+            body.write_body(&mut bytes).map_err(ApiError::from)?;
+        }
+
+        let body = String::from_utf8(bytes).map_err(ApiError::from)?;
+
+        Transaction::complete(connection, method, path, access_token, &body)?;
+
+        set_json_body(&mut response, body);
+
+        Ok((response, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use diesel::{LoadDsl, SelectDsl};
+    use diesel::expression::dsl::count_star;
+    use iron::status::Status;
+
+    use models::transaction::TRANSACTION_TTL;
+    use schema::transactions;
+    use test::Test;
+
+    fn event_id(test: &Test, access_token: &str, room_id: &str, message: &str, txn_id: u64)
+    -> String {
+        let response = test.send_message(access_token, room_id, message, txn_id);
+        assert_eq!(response.status, Status::Ok);
+
+        response.json().get("event_id").unwrap().as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn repeated_transaction_is_replayed() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let first = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let second = test.send_message(&alice.token, &room_id, "Hi again", 1);
+
+        assert_eq!(first.status, Status::Ok);
+        assert_eq!(second.status, Status::Ok);
+        assert_eq!(second.raw_body, first.raw_body);
+    }
+
+    #[test]
+    fn new_transaction_creates_new_event() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let first_event_id = event_id(&test, &alice.token, &room_id, "Hi", 1);
+        let second_event_id = event_id(&test, &alice.token, &room_id, "Hi", 2);
+
+        assert_ne!(first_event_id, second_event_id);
+    }
+
+    #[test]
+    fn transactions_expire_with_the_clock() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let first_event_id = event_id(&test, &alice.token, &room_id, "Hi", 1);
+
+        test.advance_time(Duration::from_secs(TRANSACTION_TTL - 1));
+        assert_eq!(event_id(&test, &alice.token, &room_id, "Hi", 1), first_event_id);
+
+        test.advance_time(Duration::from_secs(2));
+        assert_ne!(event_id(&test, &alice.token, &room_id, "Hi", 1), first_event_id);
+    }
+
+    #[test]
+    fn transactions_are_per_access_token() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+        let other_token = test.login_device(&alice, "PHONE");
+
+        let first_event_id = event_id(&test, &alice.token, &room_id, "Hi", 1);
+        let second_event_id = event_id(&test, &other_token, &room_id, "Hi", 1);
+
+        assert_ne!(first_event_id, second_event_id);
+    }
+
+    #[test]
+    fn failed_transaction_leaves_nothing_behind() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?access_token={}",
+            room_id,
+            alice.token
+        );
+
+        let response = test.put(&path, r#"{"msgtype": "m.text"}"#);
+        assert_eq!(response.status, Status::BadRequest);
+
+        {
+            let connection = test.connection();
+            let saved: i64 =
+                transactions::table.select(count_star()).get_result(&*connection).unwrap();
+
+            assert_eq!(saved, 0);
+        }
+
+        let first_event_id = event_id(&test, &alice.token, &room_id, "Hi", 1);
+
+        assert_eq!(event_id(&test, &alice.token, &room_id, "Hi", 1), first_event_id);
+    }
+
+    #[test]
+    fn transaction_is_replayed_after_restart() {
+        let mut test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let first_event_id = event_id(&test, &alice.token, &room_id, "Hi", 1);
+
+        test.restart();
+
+        assert_eq!(event_id(&test, &alice.token, &room_id, "Hi", 1), first_event_id);
+    }
+}
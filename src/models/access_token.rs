@@ -1,20 +1,26 @@
 //! User access tokens.
 
-use base64::encode;
+use base64::{decode, encode};
 use chrono::{Duration, UTC};
-use diesel::{ExpressionMethods, FilterDsl, LoadDsl, SaveChangesDsl, insert};
+use diesel::{ExecuteDsl, ExpressionMethods, FilterDsl, LoadDsl, OrderDsl, SaveChangesDsl, insert, update};
+use diesel::expression::dsl::any;
 use diesel::pg::PgConnection;
 use diesel::pg::data_types::PgTimestamp;
 use diesel::result::Error as DieselError;
 use iron::typemap::Key;
-use macaroons::caveat::Caveat;
+use macaroons::caveat::{Caveat, Predicate};
 use macaroons::token::Token;
 use macaroons::v1::V1Token;
+use macaroons::verifier::Verifier;
+use rand::{Rng, thread_rng};
 use ruma_identifiers::UserId;
 
 use error::ApiError;
 use schema::access_tokens;
 
+/// The length of generated device IDs.
+const DEVICE_ID_LENGTH: usize = 10;
+
 /// A User access token.
 #[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
 #[table_name = "access_tokens"]
@@ -31,6 +37,8 @@ pub struct AccessToken {
     pub created_at: PgTimestamp,
     /// The time the access token was last modified.
     pub updated_at: PgTimestamp,
+    /// The ID of the device the access token was issued to.
+    pub device_id: String,
 }
 
 /// A new access token, not yet saved.
@@ -41,18 +49,24 @@ pub struct NewAccessToken {
     pub user_id: UserId,
     /// The value of the access token. This is a Base64-encoded macaroon.
     pub value: String,
+    /// The ID of the device the access token is issued to.
+    pub device_id: String,
 }
 
 impl AccessToken {
-    /// Create a new `AccessToken` for the given user.
+    /// Create a new `AccessToken` for the given user and device.
+    ///
+    /// A new device ID is generated if none is given.
     pub fn create(
         connection: &PgConnection,
         user_id: &UserId,
+        device_id: Option<String>,
         macaroon_secret_key: &[u8],
     ) -> Result<Self, ApiError> {
         let new_access_token = NewAccessToken {
             user_id: user_id.clone(),
             value: create_macaroon(macaroon_secret_key, user_id)?,
+            device_id: device_id.unwrap_or_else(generate_device_id),
         };
 
         insert(&new_access_token)
@@ -79,6 +93,55 @@ impl AccessToken {
         }
     }
 
+    /// Whether or not the access token's macaroon was made with the given secret key for the
+    /// token's user.
+    ///
+    /// Access tokens made before the macaroon secret key was changed fail this check. The
+    /// expiration time of the macaroon is not enforced.
+    pub fn is_signed_with(&self, macaroon_secret_key: &[u8]) -> bool {
+        let token = match decode(&self.value).map(V1Token::deserialize) {
+            Ok(Ok(token)) => token,
+            Ok(Err(_)) | Err(_) => return false,
+        };
+
+        let mut verifier = Verifier::new();
+
+        verifier.satisfy_exact(Predicate(format!("user_id = {}", self.user_id).into_bytes()));
+        verifier.satisfy_exact(Predicate(b"type = access".to_vec()));
+        verifier.satisfy_general(Box::new(|predicate: &Predicate| {
+            predicate.0.starts_with(b"time < ")
+        }));
+
+        token.verify(macaroon_secret_key, &mut verifier)
+    }
+
+    /// Return the access tokens of a user that have not been revoked, oldest first.
+    pub fn find_valid_by_uid(connection: &PgConnection, user_id: &UserId)
+    -> Result<Vec<AccessToken>, ApiError> {
+        access_tokens::table
+            .filter(access_tokens::user_id.eq(user_id))
+            .filter(access_tokens::revoked.eq(false))
+            .order(access_tokens::id.asc())
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Revoke the access tokens of a user issued to any of the given devices.
+    ///
+    /// Returns the number of revoked access tokens.
+    pub fn revoke_by_device_ids(connection: &PgConnection, user_id: &UserId, device_ids: &[String])
+    -> Result<usize, ApiError> {
+        let tokens = access_tokens::table
+            .filter(access_tokens::user_id.eq(user_id))
+            .filter(access_tokens::device_id.eq(any(device_ids.to_vec())))
+            .filter(access_tokens::revoked.eq(false));
+
+        update(tokens)
+            .set(access_tokens::revoked.eq(true))
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Revoke the access token so it cannot be used again.
     pub fn revoke(&mut self, connection: &PgConnection) -> Result<(), ApiError> {
         self.revoked = true;
@@ -94,6 +157,15 @@ impl Key for AccessToken {
     type Value = AccessToken;
 }
 
+fn generate_device_id() -> String {
+    thread_rng()
+        .gen_ascii_chars()
+        .filter(|c| c.is_alphabetic())
+        .take(DEVICE_ID_LENGTH)
+        .collect::<String>()
+        .to_uppercase()
+}
+
 fn create_macaroon(macaroon_secret_key: &[u8], user_id: &UserId) -> Result<String, ApiError> {
     let expiration = match UTC::now().checked_add_signed(Duration::hours(1)) {
         Some(datetime) => datetime,
@@ -17,6 +17,9 @@ use ruma_identifiers::{UserId, RoomId};
 use error::ApiError;
 use schema::{account_data, room_account_data};
 
+/// The content of account data that was deleted.
+pub const EMPTY_CONTENT: &'static str = "{}";
+
 /// Holds personal information/configuration for a user.
 #[derive(AsChangeset, Debug, Clone, Identifiable, Queryable)]
 #[table_name = "account_data"]
@@ -186,6 +189,15 @@ impl RoomAccountData {
             .map_err(ApiError::from)
     }
 
+    /// Get the account data of all rooms given a `UserId`.
+    pub fn find_by_uid(connection: &PgConnection, uid: &UserId)
+    -> Result<Vec<RoomAccountData>, ApiError> {
+        room_account_data::table
+            .filter(room_account_data::user_id.eq(uid))
+            .load::<RoomAccountData>(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Update an existing entry or create a new one.
     pub fn upsert(connection: &PgConnection, new_data: &NewRoomAccountData)
     -> Result<RoomAccountData, ApiError> {
@@ -0,0 +1,219 @@
+//! Periodic snapshots of server-wide counts, for capacity planning.
+
+use std::time::{Duration, SystemTime};
+
+use diesel::{ExecuteDsl, ExpressionMethods, FilterDsl, LoadDsl, OrderDsl, SelectDsl, insert};
+use diesel::expression::dsl::{count_star, sql};
+use diesel::pg::PgConnection;
+use diesel::types::{BigInt, Text, Timestamp};
+
+use error::ApiError;
+use federation::sender::SEND_EVENT_JOB;
+use schema::{admin_metrics, background_jobs, events, rooms, user_ips, users};
+
+/// The number of users who haven't been deactivated.
+pub const TOTAL_USERS: &'static str = "total_users";
+
+/// The number of users seen in the last 30 days.
+pub const MONTHLY_ACTIVE_USERS: &'static str = "monthly_active_users";
+
+/// The number of rooms created on this server.
+pub const TOTAL_ROOMS: &'static str = "total_rooms";
+
+/// The number of rooms published in the room directory.
+pub const PUBLIC_ROOMS: &'static str = "public_rooms";
+
+/// The number of events stored, from this server and others.
+pub const TOTAL_EVENTS: &'static str = "total_events";
+
+/// The number of events waiting to be sent to other servers.
+pub const FEDERATION_SEND_QUEUE_DEPTH: &'static str = "federation_send_queue_depth";
+
+/// How recently users must have been seen to count as monthly active.
+const MONTHLY_ACTIVE_PERIOD_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// The value of a metric at some point in time.
+#[derive(Clone, Debug, Insertable, Queryable)]
+#[table_name = "admin_metrics"]
+pub struct AdminMetric {
+    /// When the value was collected.
+    pub ts: SystemTime,
+    /// The name of the metric, e.g. `total_users`.
+    pub metric_name: String,
+    /// The value of the metric.
+    pub value: i64,
+}
+
+impl AdminMetric {
+    /// Counts the current value of every metric, timestamped with `now`.
+    pub fn collect(connection: &PgConnection, now: SystemTime)
+    -> Result<Vec<AdminMetric>, ApiError> {
+        let total_users = users::table
+            .filter(users::active.eq(true))
+            .select(count_star())
+            .first(connection)
+            .map_err(ApiError::from)?;
+
+        let active_since = now - Duration::from_secs(MONTHLY_ACTIVE_PERIOD_SECS);
+        let monthly_active_users = user_ips::table
+            .filter(user_ips::last_seen.gt(active_since))
+            .select(sql::<BigInt>("count(DISTINCT user_id)"))
+            .first(connection)
+            .map_err(ApiError::from)?;
+
+        let total_rooms = rooms::table
+            .select(count_star())
+            .first(connection)
+            .map_err(ApiError::from)?;
+
+        let public_rooms = rooms::table
+            .filter(rooms::public.eq(true))
+            .select(count_star())
+            .first(connection)
+            .map_err(ApiError::from)?;
+
+        let total_events = events::table
+            .select(count_star())
+            .first(connection)
+            .map_err(ApiError::from)?;
+
+        let federation_send_queue_depth = background_jobs::table
+            .filter(background_jobs::kind.eq(SEND_EVENT_JOB))
+            .filter(background_jobs::dead.eq(false))
+            .select(count_star())
+            .first(connection)
+            .map_err(ApiError::from)?;
+
+        let metric = |name: &str, value: i64| AdminMetric {
+            ts: now,
+            metric_name: name.to_string(),
+            value: value,
+        };
+
+        Ok(vec![
+            metric(TOTAL_USERS, total_users),
+            metric(MONTHLY_ACTIVE_USERS, monthly_active_users),
+            metric(TOTAL_ROOMS, total_rooms),
+            metric(PUBLIC_ROOMS, public_rooms),
+            metric(TOTAL_EVENTS, total_events),
+            metric(FEDERATION_SEND_QUEUE_DEPTH, federation_send_queue_depth),
+        ])
+    }
+
+    /// Saves a snapshot of metrics.
+    pub fn save_all(connection: &PgConnection, metrics: &[AdminMetric]) -> Result<(), ApiError> {
+        insert(metrics)
+            .into(admin_metrics::table)
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Returns the most recent value of every metric that was collected at least once.
+    pub fn find_latest(connection: &PgConnection) -> Result<Vec<AdminMetric>, ApiError> {
+        sql::<(Timestamp, Text, BigInt)>(
+            "SELECT DISTINCT ON (metric_name) ts, metric_name, value FROM admin_metrics \
+            ORDER BY metric_name, ts DESC"
+        ).load(connection).map_err(ApiError::from)
+    }
+
+    /// Returns the last value of the metric on each day since `since`, oldest first.
+    ///
+    /// Days are in UTC and identified by their start. Days without a snapshot are left out.
+    pub fn find_daily(connection: &PgConnection, metric_name: &str, since: SystemTime)
+    -> Result<Vec<(SystemTime, i64)>, ApiError> {
+        admin_metrics::table
+            .select((
+                sql::<Timestamp>("DISTINCT ON (date_trunc('day', ts)) date_trunc('day', ts)"),
+                admin_metrics::value,
+            ))
+            .filter(admin_metrics::metric_name.eq(metric_name))
+            .filter(admin_metrics::ts.ge(since))
+            .order(sql::<Timestamp>("date_trunc('day', ts), ts DESC"))
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use test::Test;
+    use super::{
+        AdminMetric,
+        MONTHLY_ACTIVE_USERS,
+        PUBLIC_ROOMS,
+        TOTAL_ROOMS,
+        TOTAL_USERS,
+    };
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn value(metrics: &[AdminMetric], name: &str) -> i64 {
+        metrics.iter().find(|metric| metric.metric_name == name).unwrap().value
+    }
+
+    #[test]
+    fn collect_counts_users_and_rooms() {
+        let test = Test::new();
+        let alice = test.create_user();
+        test.create_user();
+        test.create_public_room(&alice.token);
+        test.create_room(&alice.token);
+
+        let connection = test.connection();
+        let metrics = AdminMetric::collect(&connection, SystemTime::now()).unwrap();
+
+        assert_eq!(value(&metrics, TOTAL_USERS), 2);
+        assert_eq!(value(&metrics, TOTAL_ROOMS), 2);
+        assert_eq!(value(&metrics, PUBLIC_ROOMS), 1);
+    }
+
+    #[test]
+    fn users_not_seen_for_30_days_are_not_monthly_active() {
+        let test = Test::new();
+        let alice = test.create_user();
+        test.create_room(&alice.token);
+
+        let connection = test.connection();
+        let now = SystemTime::now();
+        let metrics = AdminMetric::collect(&connection, now).unwrap();
+
+        assert_eq!(value(&metrics, MONTHLY_ACTIVE_USERS), 1);
+
+        let metrics = AdminMetric::collect(&connection, now + Duration::from_secs(31 * DAY))
+            .unwrap();
+
+        assert_eq!(value(&metrics, MONTHLY_ACTIVE_USERS), 0);
+    }
+
+    #[test]
+    fn daily_values_are_the_last_of_each_day() {
+        let test = Test::new();
+        let connection = test.connection();
+        // One hour into the day ten days ago, so that the snapshots don't straddle midnight.
+        let today = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / DAY * DAY;
+        let start = UNIX_EPOCH + Duration::from_secs(today - 10 * DAY + 60 * 60);
+
+        for (offset, value) in vec![(0, 1), (60, 2), (DAY, 3), (3 * DAY, 4)] {
+            let metric = AdminMetric {
+                ts: start + Duration::from_secs(offset),
+                metric_name: MONTHLY_ACTIVE_USERS.to_string(),
+                value: value,
+            };
+
+            AdminMetric::save_all(&connection, &[metric]).unwrap();
+        }
+
+        let daily = AdminMetric::find_daily(&connection, MONTHLY_ACTIVE_USERS, start).unwrap();
+        let values: Vec<i64> = daily.iter().map(|&(_, value)| value).collect();
+
+        assert_eq!(values, vec![2, 3, 4]);
+
+        let latest = AdminMetric::find_latest(&connection).unwrap();
+
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].value, 4);
+    }
+}
@@ -0,0 +1,295 @@
+//! Deferred work that survives restarts.
+
+use std::cmp;
+use std::time::{Duration, SystemTime};
+
+use diesel::{
+    BoolExpressionMethods,
+    BoxedDsl,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LimitDsl,
+    LoadDsl,
+    OrderDsl,
+    SaveChangesDsl,
+    SelectDsl,
+    delete,
+    insert,
+    update,
+};
+use diesel::expression::dsl::count_star;
+use diesel::pg::PgConnection;
+use diesel::types::Text;
+use serde_json::{Value, from_str, to_string};
+
+use clock::Clock;
+use error::ApiError;
+use schema::background_jobs;
+
+/// The number of attempts after which a failing job is dead-lettered.
+pub const MAX_ATTEMPTS: i32 = 5;
+
+/// The delay before the first retry of a failed job. It doubles with every further attempt.
+const BASE_RETRY_DELAY_SECS: u64 = 10;
+
+/// The longest delay between two attempts of a job.
+const MAX_RETRY_DELAY_SECS: u64 = 60 * 60;
+
+/// The number of seconds after which a job whose lock wasn't renewed may be claimed again, as the
+/// worker that locked it must have died.
+const STALE_LOCK_SECS: u64 = 5 * 60;
+
+/// How often a worker renews the lock of the job it runs, well before the lock becomes stale.
+pub const LOCK_RENEWAL_INTERVAL_SECS: u64 = 60;
+
+/// The number of runnable jobs `Job::claim` tries to claim, in order, before looking for more.
+const CLAIM_CANDIDATES: i64 = 10;
+
+sql_function!(
+    background_job_payload_field,
+    background_job_payload_field_t,
+    (payload: Text, field: Text) -> Text
+);
+
+/// A new job, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "background_jobs"]
+pub struct NewJob {
+    /// The kind of the job, which determines the handler that runs it.
+    pub kind: String,
+    /// JSON input for the handler.
+    pub payload: String,
+    /// The earliest time the job may run.
+    pub run_at: SystemTime,
+}
+
+/// A unit of deferred work.
+#[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
+#[changeset_options(treat_none_as_null = "true")]
+#[table_name = "background_jobs"]
+pub struct Job {
+    /// The job's ID.
+    pub id: i64,
+    /// The kind of the job, which determines the handler that runs it.
+    pub kind: String,
+    /// JSON input for the handler.
+    pub payload: String,
+    /// The earliest time the job may run.
+    pub run_at: SystemTime,
+    /// The number of times a worker has claimed the job.
+    pub attempts: i32,
+    /// The ID of the worker currently running the job.
+    pub locked_by: Option<String>,
+    /// The time the job was claimed by the worker currently running it.
+    pub locked_at: Option<SystemTime>,
+    /// The error of the last failed attempt.
+    pub last_error: Option<String>,
+    /// Whether or not the job failed too many times to be retried.
+    pub dead: bool,
+    /// The time the job was created.
+    pub created_at: SystemTime,
+}
+
+impl Job {
+    /// Adds a job to the queue, to be run by a worker no earlier than `run_at`.
+    pub fn enqueue(connection: &PgConnection, kind: &str, payload: &Value, run_at: SystemTime)
+    -> Result<Job, ApiError> {
+        let new_job = NewJob {
+            kind: kind.to_string(),
+            payload: to_string(payload).map_err(ApiError::from)?,
+            run_at: run_at,
+        };
+
+        insert(&new_job)
+            .into(background_jobs::table)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Claims the next runnable job for the given worker.
+    ///
+    /// A job being claimed by another worker at the same time is waited for, and only claimed if
+    /// the other worker's claim failed, so concurrent workers never claim the same job. The
+    /// current time is taken from the server's clock rather than from PostgreSQL's `now()`, which
+    /// is fixed for the duration of a transaction and depends on the session's time zone.
+    pub fn claim(connection: &PgConnection, worker_id: &str, clock: &Clock)
+    -> Result<Option<Job>, ApiError> {
+        Job::claim_due(connection, worker_id, clock.now())
+    }
+
+    /// Claims the next job that is runnable at the given time for the given worker.
+    ///
+    /// Used to run the jobs that became due when a test moved its clock forward.
+    pub fn claim_due(connection: &PgConnection, worker_id: &str, now: SystemTime)
+    -> Result<Option<Job>, ApiError> {
+        Job::claim_next(connection, worker_id, now, None)
+    }
+
+    /// Claims the next job of the given kind that is runnable at the given time.
+    ///
+    /// Used to flush a queue when the server shuts down.
+    pub fn claim_due_of_kind(
+        connection: &PgConnection,
+        worker_id: &str,
+        kind: &str,
+        now: SystemTime,
+    ) -> Result<Option<Job>, ApiError> {
+        Job::claim_next(connection, worker_id, now, Some(kind))
+    }
+
+    /// Claims the next job runnable at the given time, of the given kind if there is one.
+    fn claim_next(connection: &PgConnection, worker_id: &str, now: SystemTime, kind: Option<&str>)
+    -> Result<Option<Job>, ApiError> {
+        let stale_cutoff = now - Duration::from_secs(STALE_LOCK_SECS);
+
+        loop {
+            let mut query = background_jobs::table
+                .select(background_jobs::id)
+                .filter(background_jobs::dead.eq(false))
+                .filter(background_jobs::run_at.le(now))
+                .filter(
+                    background_jobs::locked_by.is_null()
+                        .or(background_jobs::locked_at.lt(stale_cutoff))
+                )
+                .order((background_jobs::run_at, background_jobs::id))
+                .limit(CLAIM_CANDIDATES)
+                .into_boxed();
+
+            if let Some(kind) = kind {
+                query = query.filter(background_jobs::kind.eq(kind));
+            }
+
+            let candidates: Vec<i64> = query.load(connection).map_err(ApiError::from)?;
+
+            if candidates.is_empty() {
+                return Ok(None);
+            }
+
+            for id in candidates {
+                // The update waits for a concurrent claim of the job, and checks again that it's
+                // unclaimed once that claim was committed or rolled back.
+                let unclaimed = background_jobs::table
+                    .filter(background_jobs::id.eq(id))
+                    .filter(background_jobs::dead.eq(false))
+                    .filter(
+                        background_jobs::locked_by.is_null()
+                            .or(background_jobs::locked_at.lt(stale_cutoff))
+                    );
+
+                let mut jobs: Vec<Job> = update(unclaimed)
+                    .set((
+                        background_jobs::locked_by.eq(Some(worker_id)),
+                        background_jobs::locked_at.eq(Some(now)),
+                        background_jobs::attempts.eq(background_jobs::attempts + 1),
+                    ))
+                    .get_results(connection)
+                    .map_err(ApiError::from)?;
+
+                if let Some(job) = jobs.pop() {
+                    return Ok(Some(job));
+                }
+            }
+        }
+    }
+
+    /// Renews the lock of a job the given worker is still running, so that other workers don't
+    /// claim it again while it runs.
+    ///
+    /// Returns `false` if the worker doesn't hold the lock anymore.
+    pub fn renew_lock(connection: &PgConnection, id: i64, worker_id: &str, now: SystemTime)
+    -> Result<bool, ApiError> {
+        let locked_job = background_jobs::table
+            .filter(background_jobs::id.eq(id))
+            .filter(background_jobs::locked_by.eq(worker_id));
+
+        update(locked_job)
+            .set(background_jobs::locked_at.eq(Some(now)))
+            .execute(connection)
+            .map(|rows| rows == 1)
+            .map_err(ApiError::from)
+    }
+
+    /// Returns all jobs in the queue, including dead-lettered ones, in the order they will run.
+    pub fn all(connection: &PgConnection) -> Result<Vec<Job>, ApiError> {
+        background_jobs::table
+            .order((background_jobs::dead, background_jobs::run_at, background_jobs::id))
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Looks up a job by its ID.
+    pub fn find(connection: &PgConnection, id: i64) -> Result<Option<Job>, ApiError> {
+        background_jobs::table
+            .find(id)
+            .load(connection)
+            .map(|mut jobs: Vec<Job>| jobs.pop())
+            .map_err(ApiError::from)
+    }
+
+    /// Whether or not a job of the given kind is waiting to run or running.
+    pub fn is_queued(connection: &PgConnection, kind: &str) -> Result<bool, ApiError> {
+        background_jobs::table
+            .filter(background_jobs::kind.eq(kind))
+            .filter(background_jobs::dead.eq(false))
+            .load(connection)
+            .map(|jobs: Vec<Job>| !jobs.is_empty())
+            .map_err(ApiError::from)
+    }
+
+    /// The number of jobs of the given kind waiting to run or running whose payload has the given
+    /// value for a top-level string field.
+    pub fn count_queued_with(connection: &PgConnection, kind: &str, field: &str, value: &str)
+    -> Result<usize, ApiError> {
+        let count: i64 = background_jobs::table
+            .filter(background_jobs::kind.eq(kind))
+            .filter(background_jobs::dead.eq(false))
+            .filter(background_job_payload_field(background_jobs::payload, field).eq(value))
+            .select(count_star())
+            .first(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(count as usize)
+    }
+
+    /// The job's payload, parsed as JSON.
+    pub fn payload(&self) -> Result<Value, ApiError> {
+        from_str(&self.payload).map_err(ApiError::from)
+    }
+
+    /// Removes a successfully run job from the queue.
+    pub fn complete(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        delete(background_jobs::table.filter(background_jobs::id.eq(self.id)))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Records a failed attempt, rescheduling the job with exponential backoff or
+    /// dead-lettering it once it has been attempted `MAX_ATTEMPTS` times.
+    ///
+    /// The delay is counted from the current time of the clock.
+    pub fn fail(&mut self, connection: &PgConnection, error: &str, clock: &Clock)
+    -> Result<(), ApiError> {
+        self.locked_by = None;
+        self.locked_at = None;
+        self.last_error = Some(error.to_string());
+
+        if self.attempts >= MAX_ATTEMPTS {
+            self.dead = true;
+        } else {
+            self.run_at = clock.now() + retry_delay(self.attempts);
+        }
+
+        self.save_changes::<Job>(connection).map(|_| ()).map_err(ApiError::from)
+    }
+}
+
+/// The delay before the next attempt of a job that has been attempted `attempts` times.
+pub fn retry_delay(attempts: i32) -> Duration {
+    let exponent = cmp::min(cmp::max(attempts - 1, 0), 16) as u32;
+    let delay = BASE_RETRY_DELAY_SECS.saturating_mul(2u64.pow(exponent));
+
+    Duration::from_secs(cmp::min(delay, MAX_RETRY_DELAY_SECS))
+}
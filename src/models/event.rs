@@ -1,21 +1,32 @@
 //! Matrix events.
 
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::convert::{TryInto, TryFrom};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use diesel::{
+    BoxedDsl,
+    Connection,
+    ExecuteDsl,
     ExpressionMethods,
     FilterDsl,
     FindDsl,
     GroupByDsl,
+    LimitDsl,
     LoadDsl,
     OrderDsl,
     SelectDsl,
     TextExpressionMethods,
+    delete,
+    insert,
+    update,
 };
-use diesel::expression::dsl::{any, max};
+use diesel::expression::dsl::{all, any, count_star, max, min, sql};
 use diesel::result::Error as DieselError;
 use diesel::pg::data_types::PgTimestamp;
 use diesel::pg::PgConnection;
+use diesel::types::BigInt;
 use ruma_events::{
     CustomRoomEvent,
     CustomStateEvent,
@@ -60,8 +71,22 @@ use ruma_events::stripped::{
 use ruma_identifiers::{EventId, RoomId, UserId};
 use serde_json::{Value, from_str, from_value, to_string};
 
+use clock::Clock;
 use error::ApiError;
-use schema::events;
+use models::filter::RoomEventFilter;
+use models::room_state::RoomState;
+use schema::{event_edges, events, room_current_state};
+
+/// Milliseconds between the Unix epoch and the PostgreSQL epoch, 2000-01-01.
+pub const POSTGRES_EPOCH_MS: i64 = 946_684_800_000;
+
+/// The first key of the PostgreSQL advisory locks taken on rooms while saving events in them. The
+/// second key is derived from the room ID. See `Event::lock_rooms`.
+const ROOM_LOCK_CLASS: i32 = 0x6576;
+
+/// The number of events on each side of the binary search result that
+/// `Event::find_closest_to_timestamp` compares by timestamp.
+const TIMESTAMP_SEARCH_WINDOW: i64 = 50;
 
 const STATE_EVENTS: [EventType; 12] = [
     EventType::RoomAliases,
@@ -96,6 +121,9 @@ pub struct NewEvent {
     pub state_key: Option<String>,
     /// The user who sent the event.
     pub user_id: UserId,
+    /// The signed PDU the event was received as from another server, exactly as it was received.
+    /// `None` for events of this server.
+    pub pdu: Option<String>,
 }
 
 /// A Matrix event.
@@ -103,7 +131,7 @@ pub struct NewEvent {
 pub struct Event {
     /// The unique event ID.
     pub id: EventId,
-    /// The depth of the event within its room, with the first event in the room being 1.
+    /// The position of the event in the order the server received events.
     pub ordering: i64,
     /// The room the event was sent in.
     pub room_id: RoomId,
@@ -119,23 +147,344 @@ pub struct Event {
     pub extra_content: Option<String>,
     /// The time the event was created.
     pub created_at: PgTimestamp,
+    /// The length of the longest path from the event back to the start of the room's event graph,
+    /// with the first event in the room being 1.
+    pub depth: i64,
+    /// Whether or not the event failed authorization against the current state of its room.
+    ///
+    /// Soft-failed events are kept for the auth chain of other events, but never shown to clients
+    /// and never followed by new events.
+    pub soft_failed: bool,
+    /// The signed PDU the event was received as from another server. Other servers are sent this
+    /// PDU instead of one signed by this server.
+    pub pdu: Option<String>,
+}
+
+/// The direction `Event::find_closest_to_timestamp` looks in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimestampDirection {
+    /// The earliest event at or after the timestamp.
+    Forward,
+    /// The latest event at or before the timestamp.
+    Backward,
+}
+
+/// The direction `Event::find_room_events_page` pages through a room's history in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaginationDirection {
+    /// From older to newer events.
+    Forward,
+    /// From newer to older events.
+    Backward,
+}
+
+/// A reference from an event to one of its `prev_events`.
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "event_edges"]
+pub struct NewEventEdge {
+    /// The referencing event.
+    pub event_id: EventId,
+    /// The event it follows.
+    pub prev_event_id: EventId,
+}
+
+impl NewEvent {
+    /// Save the event, placing it after the latest event in its room.
+    pub fn save(&self, connection: &PgConnection, clock: &Clock) -> Result<(), ApiError> {
+        let created_at = Event::lock_rooms(connection, clock, &[&self.room_id])?;
+        let prev_events = Event::find_latest_event_ids(connection, &self.room_id)?;
+
+        self.save_at(connection, created_at, &prev_events)
+    }
+
+    /// Save the event with the given `prev_events`, e.g. for an event received over federation.
+    ///
+    /// The depth of the event is computed from its `prev_events`. Events which would introduce a
+    /// cycle in the event graph are rejected.
+    pub fn save_with_prev_events(
+        &self,
+        connection: &PgConnection,
+        clock: &Clock,
+        prev_events: &[EventId],
+    ) -> Result<(), ApiError> {
+        let created_at = Event::lock_rooms(connection, clock, &[&self.room_id])?;
+
+        self.save_at(connection, created_at, prev_events)
+    }
+
+    /// Save the event with the time returned by `Event::lock_rooms`.
+    fn save_at(&self, connection: &PgConnection, created_at: PgTimestamp, prev_events: &[EventId])
+    -> Result<(), ApiError> {
+        if Event::creates_cycle(connection, &self.id, prev_events)? {
+            return Err(ApiError::bad_event(
+                format!("Event {} cannot be one of its own prev_events.", self.id)
+            ));
+        }
+
+        let depth = Event::next_depth(connection, prev_events)?;
+
+        insert(self)
+            .into(events::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        RoomState::update_current(connection, Some(self))?;
+
+        update(events::table.find(&self.id))
+            .set((events::depth.eq(depth), events::created_at.eq(created_at)))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        let edges: Vec<NewEventEdge> = prev_events.iter().map(|prev_event_id| {
+            NewEventEdge {
+                event_id: self.id.clone(),
+                prev_event_id: prev_event_id.clone(),
+            }
+        }).collect();
+
+        if !edges.is_empty() {
+            insert(&edges)
+                .into(event_edges::table)
+                .execute(connection)
+                .map_err(ApiError::from)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Event {
-    /// Return room join rules for given `room_id`.
-    pub fn find_room_join_rules_by_room_id(connection: &PgConnection, room_id: RoomId)
-        -> Result<JoinRulesEvent, ApiError>
-    {
-        let event: Event = events::table
-            .filter(events::event_type.eq(EventType::RoomJoinRules.to_string()))
+    /// Locks the rooms until the end of the transaction, returning the time to save new events in
+    /// them with.
+    ///
+    /// Events of a room are saved by one transaction at a time, which gets their `ordering` from
+    /// the database sequence while holding the lock, so they are committed in the order of their
+    /// `ordering`. Transactions saving events in different rooms don't wait for each other; see
+    /// `Event::stable_ordering` for how syncs still never skip an event. The time is taken from
+    /// the clock, but never goes back behind the latest event's in any of the rooms.
+    pub fn lock_rooms(connection: &PgConnection, clock: &Clock, room_ids: &[&RoomId])
+    -> Result<PgTimestamp, ApiError> {
+        let mut keys: Vec<i32> = room_ids.iter().map(|room_id| room_lock_key(room_id)).collect();
+
+        // Taking the locks in the same order in every transaction keeps them from deadlocking.
+        keys.sort();
+        keys.dedup();
+
+        for key in keys {
+            connection.execute(
+                &format!("SELECT pg_advisory_xact_lock({}, {})", ROOM_LOCK_CLASS, key)
+            ).map_err(ApiError::from)?;
+        }
+
+        let since_epoch = clock.now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        let mut created_at = since_epoch.as_secs() as i64 * 1_000_000 +
+            since_epoch.subsec_nanos() as i64 / 1000 -
+            POSTGRES_EPOCH_MS * 1000;
+
+        for room_id in room_ids {
+            let latest: Vec<PgTimestamp> = events::table
+                .select(events::created_at)
+                .filter(events::room_id.eq(*room_id))
+                .order(events::ordering.desc())
+                .limit(1)
+                .load(connection)
+                .map_err(ApiError::from)?;
+
+            if let Some(latest) = latest.first() {
+                created_at = cmp::max(created_at, latest.0);
+            }
+        }
+
+        Ok(PgTimestamp(created_at))
+    }
+
+    /// Returns the `ordering` up to which all events have been committed.
+    ///
+    /// Only events of the same room are committed in the order of their `ordering`, so an event
+    /// in one room can still be committed after a later one in another room. This waits for the
+    /// transactions holding a room lock to finish, after reading the last `ordering` assigned:
+    /// every event up to it was saved by one of them or has been committed already, and events
+    /// saved afterwards get a higher `ordering`. Reading no further than the returned `ordering`
+    /// keeps a `next_batch` token from going past an event a later sync would then skip.
+    ///
+    /// The connection must not be in a transaction, which would keep the locks taken here.
+    pub fn stable_ordering(connection: &PgConnection) -> Result<i64, ApiError> {
+        let assigned = sql::<BigInt>(
+            "SELECT CASE WHEN is_called THEN last_value ELSE 0 END FROM events_ordering_seq"
+        ).get_result(connection).map_err(ApiError::from)?;
+
+        connection.execute(&format!(
+            "SELECT pg_advisory_xact_lock_shared(classid::int, objid::int) FROM pg_locks \
+             WHERE locktype = 'advisory' AND classid = {} AND objsubid = 2 AND granted",
+            ROOM_LOCK_CLASS,
+        )).map_err(ApiError::from)?;
+
+        Ok(assigned)
+    }
+
+    /// Return the ID of the most recent event in a room, which new local events follow.
+    pub fn find_latest_event_ids(connection: &PgConnection, room_id: &RoomId)
+    -> Result<Vec<EventId>, ApiError> {
+        events::table
+            .select(events::id)
             .filter(events::room_id.eq(room_id))
+            .filter(events::soft_failed.eq(false))
             .order(events::ordering.desc())
+            .limit(1)
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Marks the event as soft-failed, hiding it from clients.
+    pub fn soft_fail(connection: &PgConnection, event_id: &EventId) -> Result<(), ApiError> {
+        update(events::table.find(event_id))
+            .set(events::soft_failed.eq(true))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Return the IDs of the events directly preceding an event.
+    pub fn find_prev_event_ids(connection: &PgConnection, event_id: &EventId)
+    -> Result<Vec<EventId>, ApiError> {
+        event_edges::table
+            .select(event_edges::prev_event_id)
+            .filter(event_edges::event_id.eq(event_id))
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Delete the events of a room created before the given time.
+    ///
+    /// The latest event of the room, the events making up the room's current state, and the
+    /// events listed in the room's `m.room.pinned_events` are kept. Returns the number of deleted
+    /// events. Rooms whose pinned events can't be parsed are skipped, as they can't be kept.
+    pub fn purge_room_history(connection: &PgConnection, room_id: &RoomId, before: SystemTime)
+    -> Result<usize, ApiError> {
+        let mut protected: HashSet<EventId> = Event::find_latest_event_ids(connection, room_id)?
+            .into_iter()
+            .collect();
+
+        let current_state: Vec<EventId> = room_current_state::table
+            .select(room_current_state::event_id)
+            .filter(room_current_state::room_id.eq(room_id))
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        let pinned_events = Event::find_current_state_event(
+            connection,
+            room_id,
+            "m.room.pinned_events",
+            "",
+        )?;
+
+        if let Some(pinned_events) = pinned_events {
+            let content: Value = match from_str(&pinned_events.content) {
+                Ok(content) => content,
+                Err(error) => {
+                    warn!(
+                        "Not purging room {} with invalid pinned events {}: {}",
+                        room_id,
+                        pinned_events.id,
+                        error
+                    );
+
+                    return Ok(0);
+                }
+            };
+
+            if let Some(pinned) = content.get("pinned").and_then(Value::as_array) {
+                protected.extend(
+                    pinned.iter()
+                        .filter_map(Value::as_str)
+                        .filter_map(|event_id| EventId::try_from(event_id).ok())
+                );
+            }
+        }
+
+        protected.extend(current_state);
+
+        let expired: Vec<EventId> = events::table
+            .select(events::id)
+            .filter(events::room_id.eq(room_id))
+            .filter(events::created_at.lt(before))
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        let purged: Vec<String> = expired.into_iter()
+            .filter(|event_id| !protected.contains(event_id))
+            .map(|event_id| event_id.to_string())
+            .collect();
+
+        if purged.is_empty() {
+            return Ok(0);
+        }
+
+        delete(event_edges::table.filter(event_edges::event_id.eq(any(purged.clone()))))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        delete(events::table.filter(events::id.eq(any(purged))))
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Compute the depth of an event following `prev_events`.
+    pub fn next_depth(connection: &PgConnection, prev_events: &[EventId]) -> Result<i64, ApiError> {
+        let prev_event_ids: Vec<String> = prev_events.iter().map(EventId::to_string).collect();
+
+        let max_depth: Option<i64> = events::table
+            .select(max(events::depth))
+            .filter(events::id.eq(any(prev_event_ids)))
             .first(connection)
-            .map_err(|err| match err {
-                DieselError::NotFound => ApiError::not_found(None),
-                _ => ApiError::from(err),
-            })?;
-        TryInto::try_into(event).map_err(ApiError::from)
+            .map_err(ApiError::from)?;
+
+        Ok(max_depth.unwrap_or(0) + 1)
+    }
+
+    /// Check whether an event following `prev_events` would be its own ancestor.
+    ///
+    /// This is the case if the event is one of its `prev_events`, or if it can be reached by
+    /// walking back through the event graph from them.
+    fn creates_cycle(connection: &PgConnection, event_id: &EventId, prev_events: &[EventId])
+    -> Result<bool, ApiError> {
+        if prev_events.contains(event_id) {
+            return Ok(true);
+        }
+
+        // An event which nothing refers to yet has no descendants to form a cycle with.
+        let child_count: i64 = event_edges::table
+            .select(count_star())
+            .filter(event_edges::prev_event_id.eq(event_id))
+            .first(connection)
+            .map_err(ApiError::from)?;
+
+        if child_count == 0 {
+            return Ok(false);
+        }
+
+        let mut visited: HashSet<EventId> = HashSet::new();
+        let mut frontier: Vec<EventId> = prev_events.to_vec();
+
+        while !frontier.is_empty() {
+            if frontier.contains(event_id) {
+                return Ok(true);
+            }
+
+            visited.extend(frontier.iter().cloned());
+
+            let frontier_ids: Vec<String> = frontier.iter().map(EventId::to_string).collect();
+
+            let parents: Vec<EventId> = event_edges::table
+                .select(event_edges::prev_event_id)
+                .filter(event_edges::event_id.eq(any(frontier_ids)))
+                .load(connection)
+                .map_err(ApiError::from)?;
+
+            frontier = parents.into_iter().filter(|id| !visited.contains(id)).collect();
+        }
+
+        Ok(false)
     }
 
     /// Return all `RoomEvent`'s for a `RoomId` after a specific point in time.
@@ -144,6 +493,7 @@ impl Event {
             .filter(events::event_type.like("m.room.%"))
             .filter(events::ordering.gt(since))
             .filter(events::room_id.eq(room_id))
+            .filter(events::soft_failed.eq(false))
             .order(events::ordering.asc())
             .get_results(connection)
             .map_err(|err| match err {
@@ -152,22 +502,48 @@ impl Event {
             })
     }
 
-    /// Return all `RoomEvent`'s for a `RoomId` up to a specific point in time.
-    pub fn find_room_events_until(
+    /// Return the latest `RoomEvent`'s for a `RoomId` after `since` and, if given, before `until`,
+    /// in the order they happened.
+    ///
+    /// With a `limit`, only that many events are read, going backwards from the latest one, and
+    /// whether or not earlier events were left out is returned along with them.
+    pub fn find_latest_room_events(
         connection: &PgConnection,
         room_id: &RoomId,
-        until: &i64
-    ) -> Result<Vec<Event>, ApiError> {
-        events::table
+        since: i64,
+        until: Option<i64>,
+        limit: Option<i64>,
+    ) -> Result<(Vec<Event>, bool), ApiError> {
+        let mut query = events::table
             .filter(events::event_type.like("m.room.%"))
-            .filter(events::ordering.lt(until))
+            .filter(events::ordering.gt(since))
             .filter(events::room_id.eq(room_id))
-            .order(events::ordering.asc())
-            .get_results(connection)
-            .map_err(|err| match err {
-                DieselError::NotFound => ApiError::not_found(None),
-                _ => ApiError::from(err),
-            })
+            .filter(events::soft_failed.eq(false))
+            .order(events::ordering.desc())
+            .into_boxed();
+
+        if let Some(until) = until {
+            query = query.filter(events::ordering.lt(until));
+        }
+
+        if let Some(limit) = limit {
+            // One more event than needed tells whether any were left out.
+            query = query.limit(limit + 1);
+        }
+
+        let mut events: Vec<Event> = query.load(connection).map_err(ApiError::from)?;
+
+        let limited = match limit {
+            Some(limit) if events.len() as i64 > limit => {
+                events.truncate(limit as usize);
+                true
+            }
+            _ => false,
+        };
+
+        events.reverse();
+
+        Ok((events, limited))
     }
 
     /// Look up an event given its `EventId`.
@@ -179,6 +555,46 @@ impl Event {
         }
     }
 
+    /// Look up the event currently setting the given piece of a room's state.
+    pub fn find_current_state_event(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        event_type: &str,
+        state_key: &str,
+    ) -> Result<Option<Event>, ApiError> {
+        let current_event_id = room_current_state::table
+            .select(room_current_state::event_id)
+            .filter(room_current_state::room_id.eq(room_id))
+            .filter(room_current_state::event_type.eq(event_type))
+            .filter(room_current_state::state_key.eq(state_key));
+
+        events::table
+            .filter(events::id.eq(any(current_event_id)))
+            .load(connection)
+            .map(|mut events: Vec<Event>| events.pop())
+            .map_err(ApiError::from)
+    }
+
+    /// Look up the event setting the given piece of a room's state right before a specified event.
+    pub fn find_state_event_until(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        event_type: &str,
+        state_key: &str,
+        until: &Event,
+    ) -> Result<Option<Event>, ApiError> {
+        events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::event_type.eq(event_type))
+            .filter(events::state_key.eq(state_key))
+            .filter(events::ordering.lt(until.ordering))
+            .order(events::ordering.desc())
+            .limit(1)
+            .load(connection)
+            .map(|mut events: Vec<Event>| events.pop())
+            .map_err(ApiError::from)
+    }
+
     /// Return the room's state before a specified event.
     pub fn get_room_state_events_until(
         connection: &PgConnection,
@@ -203,8 +619,37 @@ impl Event {
     }
 
     /// Returns the room's current state.
+    ///
+    /// Like `get_room_state_events_since`, only the most recent event of each type is included.
     pub fn get_room_full_state(connection: &PgConnection, room_id: &RoomId) -> Result<Vec<Event>, ApiError> {
-        Event::get_room_state_events_since(connection, room_id, -1)
+        let state_events: Vec<String> = STATE_EVENTS.iter()
+            .map(EventType::to_string)
+            .collect();
+
+        let current_event_ids = room_current_state::table
+            .select(room_current_state::event_id)
+            .filter(room_current_state::room_id.eq(room_id))
+            .filter(room_current_state::event_type.eq(any(state_events)));
+
+        let current_state: Vec<Event> = events::table
+            .filter(events::id.eq(any(current_event_ids)))
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        let mut latest_by_type: HashMap<String, Event> = HashMap::new();
+
+        for event in current_state {
+            let is_later = match latest_by_type.get(&event.event_type) {
+                Some(latest) => event.ordering > latest.ordering,
+                None => true,
+            };
+
+            if is_later {
+                latest_by_type.insert(event.event_type.clone(), event);
+            }
+        }
+
+        Ok(latest_by_type.into_iter().map(|(_, event)| event).collect())
     }
 
     /// Return the state changes in a room after a specific point in time.
@@ -229,8 +674,206 @@ impl Event {
             .get_results(connection)
             .map_err(ApiError::from)
     }
+
+    /// The time the event was created, in milliseconds since the Unix epoch.
+    pub fn origin_server_ts(&self) -> i64 {
+        self.created_at.0 / 1000 + POSTGRES_EPOCH_MS
+    }
+
+    /// Find the room's event closest to `ts`, in milliseconds since the Unix epoch, in the given
+    /// direction.
+    ///
+    /// Events are stored in roughly the order of their timestamps, so the first event at or after
+    /// `ts` is found with a binary search over `ordering`, which takes one indexed lookup per step.
+    /// As timestamps can be out of order because of clock skew, the events in a window around that
+    /// event are then compared by timestamp. The room's events are only scanned if none of the
+    /// events in the window match.
+    pub fn find_closest_to_timestamp(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        ts: i64,
+        direction: TimestampDirection,
+    ) -> Result<Option<Event>, ApiError> {
+        let timestamp = PgTimestamp((ts - POSTGRES_EPOCH_MS) * 1000);
+
+        let pivot = match Event::search_ordering(connection, room_id, timestamp)? {
+            Some(pivot) => pivot,
+            None => return Ok(None),
+        };
+
+        let mut window: Vec<Event> = events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::ordering.lt(pivot))
+            .order(events::ordering.desc())
+            .limit(TIMESTAMP_SEARCH_WINDOW)
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        let mut after: Vec<Event> = events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::ordering.ge(pivot))
+            .order(events::ordering.asc())
+            .limit(TIMESTAMP_SEARCH_WINDOW)
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        window.append(&mut after);
+
+        let closest = match direction {
+            TimestampDirection::Forward => window.into_iter()
+                .filter(|event| event.created_at.0 >= timestamp.0)
+                .min_by_key(|event| (event.created_at.0, event.ordering)),
+            TimestampDirection::Backward => window.into_iter()
+                .filter(|event| event.created_at.0 <= timestamp.0)
+                .max_by_key(|event| (event.created_at.0, event.ordering)),
+        };
+
+        if closest.is_some() {
+            return Ok(closest);
+        }
+
+        debug!("No event close to {} in the search window of room {}, scanning.", ts, room_id);
+
+        let result = match direction {
+            TimestampDirection::Forward => events::table
+                .filter(events::room_id.eq(room_id))
+                .filter(events::created_at.ge(timestamp))
+                .order(events::created_at.asc())
+                .first(connection),
+            TimestampDirection::Backward => events::table
+                .filter(events::room_id.eq(room_id))
+                .filter(events::created_at.le(timestamp))
+                .order(events::created_at.desc())
+                .first(connection),
+        };
+
+        match result {
+            Ok(event) => Ok(Some(event)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Binary search for the ordering of the room's first event created at or after `timestamp`,
+    /// assuming the room's events are stored in the order of their timestamps.
+    ///
+    /// The result is one past the room's last event if all of them are older. Returns `None` if
+    /// the room has no events.
+    fn search_ordering(connection: &PgConnection, room_id: &RoomId, timestamp: PgTimestamp)
+    -> Result<Option<i64>, ApiError> {
+        let lowest: Option<i64> = events::table
+            .select(min(events::ordering))
+            .filter(events::room_id.eq(room_id))
+            .first(connection)
+            .map_err(ApiError::from)?;
+
+        let highest: Option<i64> = events::table
+            .select(max(events::ordering))
+            .filter(events::room_id.eq(room_id))
+            .first(connection)
+            .map_err(ApiError::from)?;
+
+        let (mut low, mut high) = match (lowest, highest) {
+            (Some(lowest), Some(highest)) => (lowest, highest + 1),
+            _ => return Ok(None),
+        };
+
+        // Events before `low` are older than the timestamp. The first event at or after `high`, if
+        // any, is not.
+        while low < high {
+            let middle = low + (high - low) / 2;
+
+            let event: Option<(i64, PgTimestamp)> = match events::table
+                .select((events::ordering, events::created_at))
+                .filter(events::room_id.eq(room_id))
+                .filter(events::ordering.ge(middle))
+                .filter(events::ordering.lt(high))
+                .order(events::ordering.asc())
+                .first(connection)
+            {
+                Ok(event) => Some(event),
+                Err(DieselError::NotFound) => None,
+                Err(err) => return Err(ApiError::from(err)),
+            };
+
+            match event {
+                Some((ordering, created_at)) if created_at.0 < timestamp.0 => low = ordering + 1,
+                _ => high = middle,
+            }
+        }
+
+        Ok(Some(low))
+    }
+
+    /// Return up to `limit` of a room's events matching the filter, starting at the position
+    /// `from` and going in the given direction, in the order they are paginated through.
+    ///
+    /// Positions are between events: the events after position `n` have an `ordering` greater
+    /// than `n`. Pagination stops at the position `to`, if given. The filter's event types and
+    /// senders are applied in the query, so filtered out events don't count towards the limit.
+    pub fn find_room_events_page(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        from: i64,
+        to: Option<i64>,
+        direction: PaginationDirection,
+        limit: i64,
+        filter: Option<&RoomEventFilter>,
+    ) -> Result<Vec<Event>, ApiError> {
+        let mut query = events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::soft_failed.eq(false))
+            .into_boxed();
+
+        query = match direction {
+            PaginationDirection::Forward => {
+                query.filter(events::ordering.gt(from)).order(events::ordering.asc())
+            }
+            PaginationDirection::Backward => {
+                query.filter(events::ordering.le(from)).order(events::ordering.desc())
+            }
+        };
+
+        if let Some(to) = to {
+            query = match direction {
+                PaginationDirection::Forward => query.filter(events::ordering.le(to)),
+                PaginationDirection::Backward => query.filter(events::ordering.gt(to)),
+            };
+        }
+
+        if let Some(filter) = filter {
+            if !filter.types.is_empty() {
+                query = query.filter(events::event_type.eq(any(&filter.types)));
+            }
+
+            if !filter.not_types.is_empty() {
+                query = query.filter(events::event_type.ne(all(&filter.not_types)));
+            }
+
+            if !filter.senders.is_empty() {
+                query = query.filter(events::user_id.eq(any(&filter.senders)));
+            }
+
+            if !filter.not_senders.is_empty() {
+                query = query.filter(events::user_id.ne(all(&filter.not_senders)));
+            }
+        }
+
+        query.limit(limit).load::<Event>(connection).map_err(ApiError::from)
+    }
 }
 
+/// The second key of the advisory lock on the room, a 32-bit FNV-1a hash of its ID.
+///
+/// Rooms whose IDs have the same hash share a lock, which only makes their events wait for
+/// each other.
+fn room_lock_key(room_id: &RoomId) -> i32 {
+    let hash = room_id.to_string().bytes().fold(0x811c_9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    });
+
+    (hash & 0x7fff_ffff) as i32
+}
 
 macro_rules! impl_try_from_room_event_for_new_event {
     ($ty:ty) => {
@@ -246,6 +889,7 @@ macro_rules! impl_try_from_room_event_for_new_event {
                     room_id: event.room_id().clone(),
                     state_key: None,
                     user_id: event.user_id().clone(),
+                    pdu: None,
                 })
             }
         }
@@ -271,6 +915,7 @@ macro_rules! impl_try_from_state_event_for_new_event {
                     room_id: event.room_id().clone(),
                     state_key: Some(event.state_key().to_string()),
                     user_id: event.user_id().clone(),
+                    pdu: None,
                 })
             }
         }
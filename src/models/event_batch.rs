@@ -0,0 +1,361 @@
+//! Saving several events and their side effects at once.
+
+use std::collections::{HashMap, HashSet};
+
+use diesel::{ExecuteDsl, ExpressionMethods, FilterDsl, LoadDsl, insert, update};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use ruma_identifiers::{EventId, RoomId, UserId};
+
+use clock::Clock;
+use config::Config;
+use db::transaction_with_retry;
+use error::ApiError;
+use federation::sender::federate_events;
+use models::event::{Event, NewEvent, NewEventEdge};
+use models::room_alias::NewRoomAlias;
+use models::room_member_counts::RoomMemberCounts;
+use models::room_membership::{NewRoomMembership, RoomMembership};
+use models::room_state::RoomState;
+use schema::{event_edges, events, room_aliases, room_memberships};
+use state_cache::StateCache;
+
+/// An event as it is inserted into the database, with its depth already computed.
+#[derive(Debug, Insertable)]
+#[table_name = "events"]
+struct NewEventRow {
+    event_type: String,
+    extra_content: Option<String>,
+    id: EventId,
+    content: String,
+    room_id: RoomId,
+    state_key: Option<String>,
+    user_id: UserId,
+    pdu: Option<String>,
+    depth: i64,
+    created_at: PgTimestamp,
+}
+
+/// A set of new events, and the rows that change along with them, saved in a single transaction.
+///
+/// Events follow each other in the order they were added, starting from the latest events of
+/// their rooms. Either all of the batch is saved or, if anything fails, none of it.
+#[derive(Debug, Default)]
+pub struct EventBatch {
+    events: Vec<NewEvent>,
+    new_memberships: Vec<NewRoomMembership>,
+    updated_memberships: Vec<NewRoomMembership>,
+    new_room_aliases: Vec<NewRoomAlias>,
+}
+
+impl EventBatch {
+    /// Creates an empty `EventBatch`.
+    pub fn new() -> Self {
+        EventBatch::default()
+    }
+
+    /// Adds an event.
+    pub fn add_event(&mut self, event: NewEvent) -> &mut Self {
+        self.events.push(event);
+        self
+    }
+
+    /// Adds an `m.room.member` event for a user without a membership in the room yet.
+    pub fn add_membership(&mut self, event: NewEvent, membership: NewRoomMembership) -> &mut Self {
+        self.events.push(event);
+        self.new_memberships.push(membership);
+        self
+    }
+
+    /// Adds an `m.room.member` event replacing the user's current membership in the room.
+    pub fn update_membership(&mut self, event: NewEvent, membership: NewRoomMembership) -> &mut Self {
+        self.events.push(event);
+        self.updated_memberships.push(membership);
+        self
+    }
+
+    /// Adds a new room alias, without an event.
+    ///
+    /// The `m.room.aliases` event listing it should be added with `add_event`.
+    pub fn add_room_alias(&mut self, room_alias: NewRoomAlias) -> &mut Self {
+        self.new_room_aliases.push(room_alias);
+        self
+    }
+
+    /// Whether or not the batch contains any events.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Saves the batch and queues its events for delivery to the other servers in their rooms.
+    ///
+    /// All events are inserted with one statement, regardless of how many there are. The
+    /// transaction is retried if it conflicted with a concurrent one, and the cached state of the
+    /// rooms is invalidated once it succeeded.
+    ///
+    /// Returns the new and updated memberships, in that order.
+    pub fn commit(
+        self,
+        connection: &PgConnection,
+        state_cache: &StateCache,
+        clock: &Clock,
+        config: &Config,
+    ) -> Result<Vec<RoomMembership>, ApiError> {
+        let memberships = transaction_with_retry(connection, || {
+            self.insert_events(connection, clock)?;
+
+            if !self.new_room_aliases.is_empty() {
+                insert(&self.new_room_aliases)
+                    .into(room_aliases::table)
+                    .execute(connection)
+                    .map_err(|err| match err {
+                        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)
+                            => ApiError::alias_taken(None),
+                        _ => ApiError::from(err),
+                    })?;
+            }
+
+            let mut memberships: Vec<RoomMembership> = if self.new_memberships.is_empty() {
+                Vec::new()
+            } else {
+                insert(&self.new_memberships)
+                    .into(room_memberships::table)
+                    .get_results(connection)
+                    .map_err(ApiError::from)?
+            };
+
+            for membership in &self.updated_memberships {
+                let target = room_memberships::table
+                    .filter(room_memberships::room_id.eq(&membership.room_id))
+                    .filter(room_memberships::user_id.eq(&membership.user_id));
+
+                let updated = update(target)
+                    .set((
+                        room_memberships::event_id.eq(&membership.event_id),
+                        room_memberships::sender.eq(&membership.sender),
+                        room_memberships::membership.eq(&membership.membership),
+                    ))
+                    .get_result(connection)
+                    .map_err(ApiError::from)?;
+
+                memberships.push(updated);
+            }
+
+            let membership_room_ids: HashSet<&RoomId> = self.new_memberships.iter()
+                .chain(self.updated_memberships.iter())
+                .map(|membership| &membership.room_id)
+                .collect();
+
+            for room_id in membership_room_ids {
+                RoomMemberCounts::refresh(connection, room_id)?;
+            }
+
+            federate_events(connection, config, clock, &self.events)?;
+
+            Ok(memberships)
+        })?;
+
+        let room_ids: HashSet<&RoomId> = self.events.iter()
+            .filter(|event| event.state_key.is_some())
+            .map(|event| &event.room_id)
+            .collect();
+
+        for room_id in room_ids {
+            state_cache.invalidate(room_id);
+        }
+
+        Ok(memberships)
+    }
+
+    /// Inserts the events and the edges to their `prev_events`.
+    ///
+    /// The latest events of each room are looked up once. As the events in the batch are new,
+    /// they can't form a cycle with the existing ones. All events get the same time, and their
+    /// order within the batch is kept by their `ordering`.
+    fn insert_events(&self, connection: &PgConnection, clock: &Clock) -> Result<(), ApiError> {
+        if self.events.is_empty() {
+            return Ok(());
+        }
+
+        let mut room_ids: Vec<&RoomId> = Vec::new();
+
+        for event in &self.events {
+            if !room_ids.contains(&&event.room_id) {
+                room_ids.push(&event.room_id);
+            }
+        }
+
+        let created_at = Event::lock_rooms(connection, clock, &room_ids)?;
+
+        let mut heads: HashMap<RoomId, (Vec<EventId>, i64)> = HashMap::new();
+        let mut rows = Vec::with_capacity(self.events.len());
+        let mut edges = Vec::new();
+
+        for event in &self.events {
+            if !heads.contains_key(&event.room_id) {
+                let prev_events = Event::find_latest_event_ids(connection, &event.room_id)?;
+                let depth = Event::next_depth(connection, &prev_events)?;
+
+                heads.insert(event.room_id.clone(), (prev_events, depth));
+            }
+
+            let head = heads.get_mut(&event.room_id).expect("The room's head was just inserted");
+
+            for prev_event_id in &head.0 {
+                edges.push(NewEventEdge {
+                    event_id: event.id.clone(),
+                    prev_event_id: prev_event_id.clone(),
+                });
+            }
+
+            rows.push(NewEventRow {
+                event_type: event.event_type.clone(),
+                extra_content: event.extra_content.clone(),
+                id: event.id.clone(),
+                content: event.content.clone(),
+                room_id: event.room_id.clone(),
+                state_key: event.state_key.clone(),
+                user_id: event.user_id.clone(),
+                pdu: event.pdu.clone(),
+                depth: head.1,
+                created_at: PgTimestamp(created_at.0),
+            });
+
+            *head = (vec![event.id.clone()], head.1 + 1);
+        }
+
+        insert(&rows)
+            .into(events::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        RoomState::update_current(connection, &self.events)?;
+
+        if !edges.is_empty() {
+            insert(&edges)
+                .into(event_edges::table)
+                .execute(connection)
+                .map_err(ApiError::from)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma_identifiers::{EventId, RoomId, UserId};
+
+    use models::event::{Event, NewEvent};
+    use models::room_membership::{NewRoomMembership, RoomMembership};
+    use test::Test;
+    use super::EventBatch;
+
+    fn member_event(room_id: &RoomId, user_id: &UserId) -> (NewEvent, NewRoomMembership) {
+        let event = NewEvent {
+            event_type: "m.room.member".to_string(),
+            extra_content: None,
+            id: EventId::new("ruma.test").unwrap(),
+            content: r#"{"membership":"join"}"#.to_string(),
+            room_id: room_id.clone(),
+            state_key: Some(user_id.to_string()),
+            user_id: user_id.clone(),
+            pdu: None,
+        };
+
+        let membership = NewRoomMembership {
+            event_id: event.id.clone(),
+            room_id: room_id.clone(),
+            user_id: user_id.clone(),
+            sender: user_id.clone(),
+            membership: "join".to_string(),
+        };
+
+        (event, membership)
+    }
+
+    #[test]
+    fn failing_batch_saves_nothing() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = RoomId::try_from(test.create_room(&alice.token).as_ref()).unwrap();
+        let alice_id = UserId::try_from(alice.id.as_ref()).unwrap();
+        let bob_id = UserId::try_from(bob.id.as_ref()).unwrap();
+
+        let connection = test.connection();
+        let latest_event_ids = Event::find_latest_event_ids(&connection, &room_id).unwrap();
+
+        let message = NewEvent {
+            event_type: "m.room.message".to_string(),
+            extra_content: None,
+            id: EventId::new("ruma.test").unwrap(),
+            content: r#"{"body":"Hi","msgtype":"m.text"}"#.to_string(),
+            room_id: room_id.clone(),
+            state_key: None,
+            user_id: alice_id.clone(),
+            pdu: None,
+        };
+        let (bob_event, bob_membership) = member_event(&room_id, &bob_id);
+        // Alice is already a member, so inserting her membership fails after the events were
+        // inserted.
+        let (alice_event, alice_membership) = member_event(&room_id, &alice_id);
+
+        let mut batch = EventBatch::new();
+        batch
+            .add_event(message.clone())
+            .add_membership(bob_event.clone(), bob_membership)
+            .add_membership(alice_event.clone(), alice_membership);
+
+        let config = Test::config();
+
+        assert!(batch.commit(&connection, test.state_cache(), test.clock(), &config).is_err());
+
+        assert!(Event::find(&connection, &message.id).unwrap().is_none());
+        assert!(Event::find(&connection, &bob_event.id).unwrap().is_none());
+        assert!(Event::find(&connection, &alice_event.id).unwrap().is_none());
+        assert!(RoomMembership::find(&connection, &room_id, &bob_id).unwrap().is_none());
+        assert_eq!(Event::find_latest_event_ids(&connection, &room_id).unwrap(), latest_event_ids);
+    }
+
+    #[test]
+    fn events_follow_each_other() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = RoomId::try_from(test.create_room(&alice.token).as_ref()).unwrap();
+        let bob_id = UserId::try_from(bob.id.as_ref()).unwrap();
+
+        let connection = test.connection();
+        let latest_event_ids = Event::find_latest_event_ids(&connection, &room_id).unwrap();
+
+        let (first_event, first_membership) = member_event(&room_id, &bob_id);
+        let (second_event, _) = member_event(&room_id, &bob_id);
+
+        let mut batch = EventBatch::new();
+        batch
+            .add_membership(first_event.clone(), first_membership)
+            .add_event(second_event.clone());
+
+        let config = Test::config();
+        let memberships = batch.commit(&connection, test.state_cache(), test.clock(), &config)
+            .unwrap();
+
+        assert_eq!(memberships.len(), 1);
+        assert_eq!(
+            Event::find_prev_event_ids(&connection, &first_event.id).unwrap(),
+            latest_event_ids
+        );
+        assert_eq!(
+            Event::find_prev_event_ids(&connection, &second_event.id).unwrap(),
+            vec![first_event.id.clone()]
+        );
+        assert_eq!(
+            Event::find_latest_event_ids(&connection, &room_id).unwrap(),
+            vec![second_event.id]
+        );
+    }
+}
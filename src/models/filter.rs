@@ -9,7 +9,6 @@ use diesel::{
     insert,
 };
 use diesel::pg::PgConnection;
-use diesel::result::Error as DieselError;
 use ruma_identifiers::{RoomId, UserId};
 use serde::{Deserializer, Serializer};
 use serde::de::{Error as SerdeError, Unexpected, Visitor};
@@ -60,6 +59,7 @@ pub struct RoomEventFilter {
     #[serde(default = "default_vec_room_id")]
     pub rooms: Vec<RoomId>,
     /// The maximum number of events to return.
+    #[serde(default)]
     pub limit: usize,
     /// A list of sender IDs to exclude.
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -219,15 +219,10 @@ impl Filter {
 
     /// Return `Filter`'s for given `UserId` and `id`.
     pub fn find(connection: &PgConnection, user_id: UserId, id: i64) -> Result<Filter, ApiError> {
-        let filter = filters::table
+        filters::table
             .filter(filters::id.eq(id))
             .filter(filters::user_id.eq(user_id))
-            .first(connection);
-
-        match filter {
-            Ok(filter) => Ok(filter),
-            Err(DieselError::NotFound) => Err(ApiError::not_found("".to_string())),
-            Err(err) => Err(ApiError::from(err)),
-        }
+            .first(connection)
+            .map_err(ApiError::from)
     }
 }
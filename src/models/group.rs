@@ -0,0 +1,123 @@
+//! Groups of users and rooms, also known as communities.
+//!
+//! Rooms declare the groups they are affiliated with in their `m.room.related_groups` state event.
+//! The `groups` and `group_rooms` tables hold the metadata of groups and the rooms they list,
+//! for the groups API.
+
+use std::time::SystemTime;
+
+use diesel::{ExpressionMethods, FilterDsl, FindDsl, LoadDsl, SelectDsl};
+use diesel::expression::dsl::any;
+use diesel::pg::PgConnection;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::RoomId;
+use serde_json::{Value, from_str};
+
+use error::ApiError;
+use identifiers::MAX_IDENTIFIER_LENGTH;
+use models::event::Event;
+use schema::{group_rooms, groups};
+
+/// The type of the state event listing the groups a room is affiliated with.
+pub const RELATED_GROUPS_EVENT_TYPE: &'static str = "m.room.related_groups";
+
+/// A group.
+#[derive(Clone, Debug, Queryable)]
+pub struct Group {
+    /// The ID of the group, e.g. `+ruma:ruma.io`.
+    pub id: String,
+    /// The name of the group.
+    pub name: Option<String>,
+    /// The URL of the group's avatar.
+    pub avatar_url: Option<String>,
+    /// A one-line description of the group.
+    pub short_description: Option<String>,
+    /// A longer description of the group.
+    pub long_description: Option<String>,
+    /// Whether or not anyone may view the group.
+    pub public: bool,
+    /// The time the group was created.
+    pub created_at: SystemTime,
+}
+
+impl Group {
+    /// Looks up a group by its ID.
+    pub fn find(connection: &PgConnection, group_id: &str) -> Result<Option<Group>, ApiError> {
+        match groups::table.find(group_id).first(connection) {
+            Ok(group) => Ok(Some(group)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+
+    /// Returns the groups listing the room.
+    pub fn find_by_room_id(connection: &PgConnection, room_id: &RoomId)
+    -> Result<Vec<Group>, ApiError> {
+        let group_ids: Vec<String> = group_rooms::table
+            .filter(group_rooms::room_id.eq(room_id))
+            .select(group_rooms::group_id)
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        groups::table
+            .filter(groups::id.eq(any(group_ids)))
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+}
+
+/// Whether or not the string is a valid group ID: `+`, a localpart of lowercase letters, digits
+/// and `._=-/`, `:` and a server name.
+pub fn is_valid_group_id(group_id: &str) -> bool {
+    if group_id.len() > MAX_IDENTIFIER_LENGTH || !group_id.starts_with('+') {
+        return false;
+    }
+
+    let mut parts = group_id[1..].splitn(2, ':');
+
+    let localpart = parts.next().unwrap_or("");
+    let server_name = parts.next().unwrap_or("");
+
+    let is_allowed = |c: char| match c {
+        'a'...'z' | '0'...'9' | '.' | '_' | '=' | '-' | '/' => true,
+        _ => false,
+    };
+
+    !localpart.is_empty() && localpart.chars().all(is_allowed) && !server_name.is_empty()
+}
+
+/// Returns the groups in the room's current `m.room.related_groups` event.
+///
+/// Rooms without the event, or with invalid content, aren't related to any groups.
+pub fn find_related_groups(connection: &PgConnection, room_id: &RoomId)
+-> Result<Vec<String>, ApiError> {
+    let event =
+        Event::find_current_state_event(connection, room_id, RELATED_GROUPS_EVENT_TYPE, "")?;
+
+    Ok(event.map_or_else(Vec::new, |event| related_groups(&event.content)))
+}
+
+/// Returns the groups in the content of an `m.room.related_groups` event.
+pub fn related_groups(content: &str) -> Vec<String> {
+    from_str::<Value>(content).ok()
+        .and_then(|content| content.get("groups").and_then(Value::as_array).cloned())
+        .map(|groups| groups.iter().filter_map(Value::as_str).map(str::to_string).collect())
+        .unwrap_or_else(Vec::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_valid_group_id;
+
+    #[test]
+    fn group_ids() {
+        assert!(is_valid_group_id("+ruma:ruma.test"));
+        assert!(is_valid_group_id("+ruma.dev/core:ruma.test:8448"));
+
+        for group_id in &["ruma:ruma.test", "+:ruma.test", "+ruma", "+ruma:", "+Ruma:ruma.test"] {
+            assert!(!is_valid_group_id(group_id));
+        }
+
+        assert!(!is_valid_group_id(&format!("+{}:ruma.test", "a".repeat(250))));
+    }
+}
@@ -0,0 +1,362 @@
+//! Backups of users' end-to-end encryption room keys.
+//!
+//! The keys are encrypted by the clients, so the server only stores their ciphertext.
+
+use std::time::SystemTime;
+
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    LoadDsl,
+    OrderDsl,
+    SelectDsl,
+    delete,
+    insert,
+    update,
+};
+use diesel::expression::dsl::count_star;
+use diesel::pg::PgConnection;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use ruma_identifiers::{RoomId, UserId};
+
+use error::ApiError;
+use schema::{key_backup, key_backup_versions};
+
+/// How many times a new key backup version is numbered before giving up on concurrent requests.
+const VERSION_ATTEMPTS: u32 = 5;
+
+/// A version of a user's key backup. The keys of a version can only be decrypted with the key
+/// described by its `auth_data`.
+#[derive(Clone, Debug, Queryable)]
+pub struct KeyBackupVersion {
+    /// The user the backup belongs to.
+    pub user_id: UserId,
+    /// The number of the version, counting up from 1 for each user.
+    pub version: i64,
+    /// The algorithm the keys are encrypted with, e.g. `m.megolm_backup.v1.curve25519-aes-sha2`.
+    pub algorithm: String,
+    /// The JSON data about the backup the algorithm needs, e.g. its public key.
+    pub auth_data: String,
+    /// A number that changes whenever keys are added to the backup.
+    pub etag: i64,
+    /// Whether or not the version was deleted. Deleted versions keep their number.
+    pub deleted: bool,
+    /// The time the version was created.
+    pub created_at: SystemTime,
+}
+
+/// A new key backup version, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "key_backup_versions"]
+struct NewKeyBackupVersion {
+    user_id: UserId,
+    version: i64,
+    algorithm: String,
+    auth_data: String,
+}
+
+/// A room key in a key backup.
+#[derive(Clone, Debug, Insertable, Queryable)]
+#[table_name = "key_backup"]
+pub struct BackedUpKey {
+    /// The user the backup belongs to.
+    pub user_id: UserId,
+    /// The backup version the key belongs to.
+    pub version: i64,
+    /// The room the key is used in.
+    pub room_id: RoomId,
+    /// The ID of the Megolm session the key belongs to.
+    pub session_id: String,
+    /// The index of the first message the key can decrypt.
+    pub first_message_index: i64,
+    /// The number of times the key has been forwarded between devices.
+    pub forwarded_count: i64,
+    /// Whether or not the device the key came from is verified.
+    pub is_verified: bool,
+    /// The encrypted JSON data of the key.
+    pub session_data: String,
+}
+
+impl KeyBackupVersion {
+    /// Creates a new version of the user's key backup, which becomes its current version.
+    ///
+    /// The number of the version follows the user's latest one. When a concurrent request takes
+    /// the same number first, the number is picked again, up to `VERSION_ATTEMPTS` times.
+    pub fn create(connection: &PgConnection, user_id: &UserId, algorithm: &str, auth_data: &str)
+    -> Result<KeyBackupVersion, ApiError> {
+        connection.transaction::<KeyBackupVersion, ApiError, _>(|| {
+            let mut attempt = 1;
+
+            loop {
+                // The nested transaction is a savepoint, so a conflict doesn't abort the others.
+                let result = connection.transaction::<KeyBackupVersion, DieselError, _>(|| {
+                    let latest_version = key_backup_versions::table
+                        .filter(key_backup_versions::user_id.eq(user_id))
+                        .select(key_backup_versions::version)
+                        .order(key_backup_versions::version.desc())
+                        .first::<i64>(connection);
+
+                    let version = match latest_version {
+                        Ok(latest_version) => latest_version + 1,
+                        Err(DieselError::NotFound) => 1,
+                        Err(error) => return Err(error),
+                    };
+
+                    let new_version = NewKeyBackupVersion {
+                        user_id: user_id.clone(),
+                        version: version,
+                        algorithm: algorithm.to_string(),
+                        auth_data: auth_data.to_string(),
+                    };
+
+                    insert(&new_version)
+                        .into(key_backup_versions::table)
+                        .get_result(connection)
+                });
+
+                match result {
+                    Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _))
+                    if attempt < VERSION_ATTEMPTS => {
+                        debug!("Key backup version of {} taken, picking another one", user_id);
+                    }
+                    result => return result.map_err(ApiError::from),
+                }
+
+                attempt += 1;
+            }
+        })
+    }
+
+    /// Returns the current version of the user's key backup, the latest one not deleted.
+    pub fn find_current(connection: &PgConnection, user_id: &UserId)
+    -> Result<Option<KeyBackupVersion>, ApiError> {
+        let result = key_backup_versions::table
+            .filter(key_backup_versions::user_id.eq(user_id))
+            .filter(key_backup_versions::deleted.eq(false))
+            .order(key_backup_versions::version.desc())
+            .first(connection);
+
+        match result {
+            Ok(version) => Ok(Some(version)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+
+    /// Returns the given version of the user's key backup, unless it was deleted.
+    pub fn find(connection: &PgConnection, user_id: &UserId, version: i64)
+    -> Result<Option<KeyBackupVersion>, ApiError> {
+        let result = key_backup_versions::table
+            .filter(key_backup_versions::user_id.eq(user_id))
+            .filter(key_backup_versions::version.eq(version))
+            .filter(key_backup_versions::deleted.eq(false))
+            .first(connection);
+
+        match result {
+            Ok(version) => Ok(Some(version)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+
+    /// Deletes a version of the user's key backup along with its keys.
+    ///
+    /// Returns `false` if there is no such version.
+    pub fn delete(connection: &PgConnection, user_id: &UserId, version: i64)
+    -> Result<bool, ApiError> {
+        connection.transaction::<bool, ApiError, _>(|| {
+            let deleted_versions = update(
+                key_backup_versions::table
+                    .filter(key_backup_versions::user_id.eq(user_id))
+                    .filter(key_backup_versions::version.eq(version))
+                    .filter(key_backup_versions::deleted.eq(false))
+            ).set(key_backup_versions::deleted.eq(true))
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+            delete(
+                key_backup::table
+                    .filter(key_backup::user_id.eq(user_id))
+                    .filter(key_backup::version.eq(version))
+            ).execute(connection).map_err(ApiError::from)?;
+
+            Ok(deleted_versions > 0)
+        })
+    }
+
+    /// Stores keys in this version of the backup.
+    ///
+    /// A key already in the backup is only replaced by a better one: one from a verified device,
+    /// one that can decrypt earlier messages, or one that was forwarded less often, in that order.
+    /// The version's `etag` changes if any key was stored.
+    pub fn store_keys(&mut self, connection: &PgConnection, keys: Vec<BackedUpKey>)
+    -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            let mut stored_any = false;
+
+            for key in keys {
+                let existing_key = self.find_key(connection, &key.room_id, &key.session_id)?;
+
+                if let Some(existing_key) = existing_key {
+                    if !key.is_better_than(&existing_key) {
+                        continue;
+                    }
+
+                    delete(
+                        key_backup::table
+                            .filter(key_backup::user_id.eq(&self.user_id))
+                            .filter(key_backup::version.eq(self.version))
+                            .filter(key_backup::room_id.eq(&key.room_id))
+                            .filter(key_backup::session_id.eq(&key.session_id))
+                    ).execute(connection).map_err(ApiError::from)?;
+                }
+
+                insert(&key)
+                    .into(key_backup::table)
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                stored_any = true;
+            }
+
+            if stored_any {
+                self.etag += 1;
+
+                update(
+                    key_backup_versions::table
+                        .filter(key_backup_versions::user_id.eq(&self.user_id))
+                        .filter(key_backup_versions::version.eq(self.version))
+                ).set(key_backup_versions::etag.eq(self.etag))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Returns all keys in this version of the backup.
+    pub fn keys(&self, connection: &PgConnection) -> Result<Vec<BackedUpKey>, ApiError> {
+        key_backup::table
+            .filter(key_backup::user_id.eq(&self.user_id))
+            .filter(key_backup::version.eq(self.version))
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Returns the number of keys in this version of the backup.
+    pub fn count_keys(&self, connection: &PgConnection) -> Result<i64, ApiError> {
+        key_backup::table
+            .filter(key_backup::user_id.eq(&self.user_id))
+            .filter(key_backup::version.eq(self.version))
+            .select(count_star())
+            .first(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Returns the key of the given session in this version of the backup, if there is one.
+    fn find_key(&self, connection: &PgConnection, room_id: &RoomId, session_id: &str)
+    -> Result<Option<BackedUpKey>, ApiError> {
+        let result = key_backup::table
+            .filter(key_backup::user_id.eq(&self.user_id))
+            .filter(key_backup::version.eq(self.version))
+            .filter(key_backup::room_id.eq(room_id))
+            .filter(key_backup::session_id.eq(session_id))
+            .first(connection);
+
+        match result {
+            Ok(key) => Ok(Some(key)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(error) => Err(ApiError::from(error)),
+        }
+    }
+}
+
+impl BackedUpKey {
+    /// Whether or not this key should replace the other key of the same session in a backup.
+    pub fn is_better_than(&self, other: &BackedUpKey) -> bool {
+        (!self.is_verified, self.first_message_index, self.forwarded_count) <
+            (!other.is_verified, other.first_message_index, other.forwarded_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::Duration;
+
+    use diesel::Connection;
+    use diesel::pg::PgConnection;
+    use rand::{Rng, thread_rng};
+    use ruma_identifiers::{RoomId, UserId};
+
+    use error::ApiError;
+    use test::Test;
+    use super::{BackedUpKey, KeyBackupVersion};
+
+    fn key(is_verified: bool, first_message_index: i64, forwarded_count: i64) -> BackedUpKey {
+        BackedUpKey {
+            user_id: UserId::try_from("@carl:ruma.test").unwrap(),
+            version: 1,
+            room_id: RoomId::try_from("!room:ruma.test").unwrap(),
+            session_id: "session".to_string(),
+            first_message_index: first_message_index,
+            forwarded_count: forwarded_count,
+            is_verified: is_verified,
+            session_data: "{}".to_string(),
+        }
+    }
+
+    #[test]
+    fn better_keys_are_preferred_in_order() {
+        assert!(key(true, 10, 10).is_better_than(&key(false, 0, 0)));
+        assert!(key(false, 0, 10).is_better_than(&key(false, 1, 0)));
+        assert!(key(false, 1, 0).is_better_than(&key(false, 1, 1)));
+        assert!(!key(false, 1, 1).is_better_than(&key(false, 1, 1)));
+        assert!(!key(false, 0, 0).is_better_than(&key(true, 10, 10)));
+    }
+
+    #[test]
+    fn concurrently_created_versions_get_different_numbers() {
+        let test = Test::new();
+        let postgres_url = test.database_url();
+
+        // The test connections never commit, so the versions are created on connections of their
+        // own, for a user of their own.
+        let user_id = format!("@carl{}:ruma.test", thread_rng().gen::<u32>());
+        let barrier = Arc::new(Barrier::new(2));
+
+        let first = {
+            let (postgres_url, user_id, barrier) =
+                (postgres_url.clone(), user_id.clone(), barrier.clone());
+
+            thread::spawn(move || {
+                let connection = PgConnection::establish(&postgres_url).unwrap();
+                let user_id = UserId::try_from(user_id.as_str()).unwrap();
+
+                connection.transaction::<i64, ApiError, _>(|| {
+                    let version = KeyBackupVersion::create(&connection, &user_id, "algo", "{}")?;
+
+                    // Keeps the version uncommitted while the other one picks the same number.
+                    barrier.wait();
+                    thread::sleep(Duration::from_millis(200));
+
+                    Ok(version.version)
+                }).unwrap()
+            })
+        };
+
+        let connection = PgConnection::establish(&postgres_url).unwrap();
+        let user_id = UserId::try_from(user_id.as_str()).unwrap();
+
+        barrier.wait();
+        let second = KeyBackupVersion::create(&connection, &user_id, "algo", "{}").unwrap();
+
+        assert_eq!(first.join().unwrap(), 1);
+        assert_eq!(second.version, 2);
+    }
+}
@@ -0,0 +1,93 @@
+//! The Megolm sessions whose keys devices claim to hold.
+//!
+//! Room keys are sent between devices encrypted, so the server only knows which sessions a device
+//! can decrypt if the device tells it. The claims are only hints for clients, they never restrict
+//! which events a device is sent.
+
+use std::collections::HashSet;
+
+use diesel::{Connection, ExecuteDsl, ExpressionMethods, FilterDsl, LoadDsl, SelectDsl};
+use diesel::{delete, insert};
+use diesel::expression::dsl::any;
+use diesel::pg::PgConnection;
+use ruma_identifiers::{RoomId, UserId};
+
+use error::ApiError;
+use schema::claimed_megolm_sessions;
+
+/// A Megolm session of a room whose key a device claims to hold.
+#[derive(Clone, Debug, Insertable, Queryable)]
+#[table_name = "claimed_megolm_sessions"]
+pub struct ClaimedMegolmSession {
+    /// The user the device belongs to.
+    pub user_id: UserId,
+    /// The device holding the key.
+    pub device_id: String,
+    /// The room the session is used in.
+    pub room_id: RoomId,
+    /// The ID of the session.
+    pub session_id: String,
+}
+
+impl ClaimedMegolmSession {
+    /// Records that the device holds the keys of the given sessions of the room, in addition to
+    /// the sessions it claimed before. Claiming a session again changes nothing.
+    pub fn claim(
+        connection: &PgConnection,
+        user_id: &UserId,
+        device_id: &str,
+        room_id: &RoomId,
+        session_ids: &[String],
+    ) -> Result<(), ApiError> {
+        let claims: Vec<ClaimedMegolmSession> = session_ids
+            .iter()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .map(|session_id| ClaimedMegolmSession {
+                user_id: user_id.clone(),
+                device_id: device_id.to_string(),
+                room_id: room_id.clone(),
+                session_id: session_id.clone(),
+            })
+            .collect();
+
+        if claims.is_empty() {
+            return Ok(());
+        }
+
+        connection.transaction::<(), ApiError, _>(|| {
+            delete(
+                claimed_megolm_sessions::table
+                    .filter(claimed_megolm_sessions::user_id.eq(user_id))
+                    .filter(claimed_megolm_sessions::device_id.eq(device_id))
+                    .filter(claimed_megolm_sessions::room_id.eq(room_id))
+                    .filter(claimed_megolm_sessions::session_id.eq(any(session_ids)))
+            ).execute(connection).map_err(ApiError::from)?;
+
+            insert(&claims)
+                .into(claimed_megolm_sessions::table)
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+            Ok(())
+        })
+    }
+
+    /// Returns the IDs of the sessions of the room the device claimed.
+    pub fn find_session_ids(
+        connection: &PgConnection,
+        user_id: &UserId,
+        device_id: &str,
+        room_id: &RoomId,
+    ) -> Result<HashSet<String>, ApiError> {
+        let session_ids: Vec<String> = claimed_megolm_sessions::table
+            .filter(claimed_megolm_sessions::user_id.eq(user_id))
+            .filter(claimed_megolm_sessions::device_id.eq(device_id))
+            .filter(claimed_megolm_sessions::room_id.eq(room_id))
+            .select(claimed_megolm_sessions::session_id)
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(session_ids.into_iter().collect())
+    }
+}
@@ -1,14 +1,32 @@
 pub mod access_token;
 pub mod account_data;
+pub mod admin_metric;
+pub mod background_job;
 pub mod event;
+pub mod event_batch;
 pub mod filter;
+pub mod group;
+pub mod key_backup;
+pub mod megolm_session;
+pub mod notification;
 pub mod presence_list;
 pub mod presence_status;
 pub mod profile;
+pub mod push_rule;
 pub mod pusher;
+pub mod receipt;
+pub mod rejected_event;
+pub mod remote_alias;
+pub mod remote_profile;
 pub mod room;
 pub mod room_alias;
+pub mod room_directory;
+pub mod room_member_counts;
 pub mod room_membership;
+pub mod room_state;
 pub mod tags;
+pub mod to_device_message;
 pub mod transaction;
 pub mod user;
+pub mod user_ip;
+pub mod user_threepid;
@@ -0,0 +1,147 @@
+//! Notifications of users about the events in their rooms.
+//!
+//! Ruma has no push rule engine yet, so events are evaluated against a subset of the spec's
+//! default push rules when they are looked up: messages from other users notify, and highlight
+//! if they mention the user's localpart or display name. Each of these rules only applies while
+//! the user has it enabled, and enabling the master rule stops all notifications.
+
+use diesel::pg::PgConnection;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Value, from_str};
+
+use error::ApiError;
+use models::event::Event;
+use models::profile::Profile;
+use models::push_rule::{
+    CONTAINS_DISPLAY_NAME_RULE,
+    CONTAINS_USER_NAME_RULE,
+    ENCRYPTED_RULE,
+    MASTER_RULE,
+    MESSAGE_RULE,
+    PushRules,
+};
+use models::receipt::Receipt;
+use models::room_membership::RoomMembership;
+
+/// The event types that notify the members of a room.
+const NOTIFYING_EVENT_TYPES: [&'static str; 2] = ["m.room.message", "m.room.encrypted"];
+
+/// An event the user is notified about.
+#[derive(Clone, Debug)]
+pub struct Notification {
+    /// The event.
+    pub event: Event,
+    /// Whether or not the event mentions the user.
+    pub highlight: bool,
+}
+
+impl Notification {
+    /// Returns the notifications of the user for the events in the room after their latest read
+    /// receipt, oldest first.
+    ///
+    /// Users who haven't sent a receipt yet are notified about everything since their current
+    /// membership event.
+    pub fn find_unread(connection: &PgConnection, room_id: &RoomId, user_id: &UserId)
+    -> Result<Vec<Notification>, ApiError> {
+        let since = match read_up_to(connection, room_id, user_id)? {
+            Some(ordering) => ordering,
+            None => return Ok(Vec::new()),
+        };
+
+        let push_rules = PushRules::find(connection, user_id)?;
+
+        if push_rules.is_enabled(MASTER_RULE) {
+            return Ok(Vec::new());
+        }
+
+        let mut mentions = Vec::new();
+
+        if push_rules.is_enabled(CONTAINS_USER_NAME_RULE) {
+            mentions.push(user_id.localpart().to_lowercase());
+        }
+
+        if push_rules.is_enabled(CONTAINS_DISPLAY_NAME_RULE) {
+            mentions.extend(displayname_of(connection, user_id)?);
+        }
+
+        let notifications = Event::find_room_events(connection, room_id, since)?
+            .into_iter()
+            .filter(|event| {
+                event.user_id != *user_id &&
+                    NOTIFYING_EVENT_TYPES.contains(&event.event_type.as_ref())
+            })
+            .filter_map(|event| {
+                let highlight = is_mention(&event, &mentions);
+                let notify = highlight || match event.event_type.as_ref() {
+                    "m.room.encrypted" => push_rules.is_enabled(ENCRYPTED_RULE),
+                    _ => push_rules.is_enabled(MESSAGE_RULE),
+                };
+
+                if !notify {
+                    return None;
+                }
+
+                Some(Notification {
+                    event: event,
+                    highlight: highlight,
+                })
+            })
+            .collect();
+
+        Ok(notifications)
+    }
+}
+
+/// The ordering of the latest event the user has read in the room, if they are a member.
+fn read_up_to(connection: &PgConnection, room_id: &RoomId, user_id: &UserId)
+-> Result<Option<i64>, ApiError> {
+    let membership = match RoomMembership::find(connection, room_id, user_id)? {
+        Some(membership) => membership,
+        None => return Ok(None),
+    };
+
+    let mut event_ids = vec![membership.event_id];
+    event_ids.extend(
+        Receipt::find_by_user(connection, room_id, user_id)?
+            .into_iter()
+            .map(|receipt| receipt.event_id)
+    );
+
+    let mut read_up_to = None;
+
+    for event_id in &event_ids {
+        if let Some(event) = Event::find(connection, event_id)? {
+            if read_up_to.map_or(true, |ordering| event.ordering > ordering) {
+                read_up_to = Some(event.ordering);
+            }
+        }
+    }
+
+    Ok(read_up_to)
+}
+
+/// The user's display name in lowercase, if they have set one.
+fn displayname_of(connection: &PgConnection, user_id: &UserId)
+-> Result<Option<String>, ApiError> {
+    if let Some(profile) = Profile::find_by_uid(connection, user_id)? {
+        if let Some(displayname) = profile.displayname {
+            if !displayname.is_empty() {
+                return Ok(Some(displayname.to_lowercase()));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Whether or not the body of the message mentions the user.
+fn is_mention(event: &Event, mentions: &[String]) -> bool {
+    let body = from_str::<Value>(&event.content).ok().and_then(|content| {
+        content.get("body").and_then(Value::as_str).map(str::to_lowercase)
+    });
+
+    match body {
+        Some(body) => mentions.iter().any(|mention| body.contains(mention.as_str())),
+        None => false,
+    }
+}
@@ -18,6 +18,7 @@ use ruma_events::EventType;
 use ruma_events::presence::{PresenceEvent, PresenceEventContent, PresenceState};
 use ruma_identifiers::UserId;
 
+use clock::Clock;
 use error::ApiError;
 use models::presence_status::{PresenceStatus, get_now};
 use models::profile::Profile;
@@ -134,6 +135,7 @@ impl PresenceList {
     /// Return `PresenceEvent`'s for given `UserId`.
     pub fn find_events_by_uid(
         connection: &PgConnection,
+        clock: &Clock,
         user_id: &UserId,
         since: Option<i64>
     ) -> Result<(i64, Vec<PresenceEvent>), ApiError> {
@@ -157,7 +159,7 @@ impl PresenceList {
             presence_key = cmp::max(last_update, presence_key);
 
             let presence_state: PresenceState = status.presence.parse().unwrap();
-            let last_active_ago = get_now() - last_update;
+            let last_active_ago = get_now(clock) - last_update;
 
             let profile: Option<&Profile> = profiles.iter()
                 .find(|profile| profile.id == status.user_id);
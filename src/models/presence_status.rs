@@ -1,6 +1,6 @@
 //! Storage and querying of presence status.
 
-use chrono::{Duration, NaiveDateTime, NaiveDate, UTC};
+use chrono::{Duration, NaiveDateTime, NaiveDate};
 use diesel::{
     insert,
     Connection,
@@ -18,7 +18,11 @@ use diesel::result::Error as DieselError;
 use ruma_events::presence::PresenceState;
 use ruma_identifiers::{UserId, EventId};
 
+use clock::Clock;
 use error::ApiError;
+use ids::generate_event_id;
+use models::event::POSTGRES_EPOCH_MS;
+use room_version::DEFAULT_ROOM_VERSION;
 use schema::presence_status;
 
 /// A Matrix presence status, not saved yet.
@@ -54,10 +58,9 @@ pub struct PresenceStatus {
     pub updated_at: PgTimestamp,
 }
 
-/// Return current time in milliseconds
-pub fn get_now() -> i64 {
-    let now = UTC::now().naive_utc();
-    get_milliseconds(now)
+/// Return the current time of `clock` in milliseconds with the same epoch as PostgreSQL.
+pub fn get_now(clock: &Clock) -> i64 {
+    clock.now_ms() as i64 - POSTGRES_EPOCH_MS
 }
 
 /// Return `time` in milliseconds with a same epoch as PostgreSQL.
@@ -71,12 +74,14 @@ impl PresenceStatus {
     /// Update or insert a presence status entry.
     pub fn upsert(
         connection: &PgConnection,
+        clock: &Clock,
         homeserver_domain: &str,
         user_id: &UserId,
         presence: Option<PresenceState>,
         status_msg: Option<String>
     ) -> Result<(), ApiError> {
-        let event_id = &EventId::new(homeserver_domain).map_err(ApiError::from)?;
+        // Presence events aren't part of a room, so their IDs have the default version's format.
+        let event_id = &generate_event_id(homeserver_domain, DEFAULT_ROOM_VERSION)?;
 
         connection.transaction::<(), ApiError, _>(|| {
             let status = PresenceStatus::find_by_uid(connection, user_id)?;
@@ -89,8 +94,17 @@ impl PresenceStatus {
             };
 
             match status {
-                Some(mut status) => status.update(connection, presence, status_msg, event_id),
-                None => PresenceStatus::create(connection, user_id, presence, status_msg, event_id),
+                Some(mut status) => {
+                    status.update(connection, clock, presence, status_msg, event_id)
+                }
+                None => PresenceStatus::create(
+                    connection,
+                    clock,
+                    user_id,
+                    presence,
+                    status_msg,
+                    event_id,
+                ),
             }
         }).map_err(ApiError::from)
     }
@@ -99,6 +113,7 @@ impl PresenceStatus {
     fn update(
         &mut self,
         connection: &PgConnection,
+        clock: &Clock,
         presence: String,
         status_msg: Option<String>,
         event_id: &EventId
@@ -106,7 +121,7 @@ impl PresenceStatus {
         self.presence = presence;
         self.status_msg = status_msg;
         self.event_id = event_id.clone();
-        self.updated_at = PgTimestamp(get_now());
+        self.updated_at = PgTimestamp(get_now(clock));
 
         match self.save_changes::<PresenceStatus>(connection) {
             Ok(_) => Ok(()),
@@ -117,6 +132,7 @@ impl PresenceStatus {
     /// Create a presence status entry.
     fn create(
         connection: &PgConnection,
+        clock: &Clock,
         user_id: &UserId,
         presence: String,
         status_msg: Option<String>,
@@ -127,7 +143,7 @@ impl PresenceStatus {
             event_id: event_id.clone(),
             presence: presence,
             status_msg: status_msg,
-            updated_at: PgTimestamp(get_now()),
+            updated_at: PgTimestamp(get_now(clock)),
         };
         insert(&new_status)
             .into(presence_status::table)
@@ -14,10 +14,13 @@ use diesel::pg::PgConnection;
 use diesel::result::Error as DieselError;
 use ruma_identifiers::UserId;
 
+use clock::Clock;
+use config::Config;
 use error::ApiError;
 use models::room_membership::{RoomMembership, RoomMembershipOptions};
 use models::presence_status::PresenceStatus;
 use schema::profiles;
+use state_cache::StateCache;
 
 /// A Matrix profile.
 #[derive(AsChangeset, Debug, Clone, Identifiable, Insertable, Queryable)]
@@ -35,6 +38,7 @@ impl Profile {
     /// Update or Create a `Profile` entry with new avatar_url.
     pub fn update_avatar_url(
         connection: &PgConnection,
+        clock: &Clock,
         homeserver_domain: &str,
         user_id: UserId,
         avatar_url: Option<String>
@@ -54,7 +58,7 @@ impl Profile {
                 }
             };
 
-            PresenceStatus::upsert(connection, homeserver_domain, &user_id, None, None)?;
+            PresenceStatus::upsert(connection, clock, homeserver_domain, &user_id, None, None)?;
             Ok(profile)
         }).map_err(ApiError::from)
     }
@@ -62,6 +66,7 @@ impl Profile {
     /// Update or Create a `Profile` entry with new displayname.
     pub fn update_displayname(
         connection: &PgConnection,
+        clock: &Clock,
         homeserver_domain: &str,
         user_id: UserId,
         displayname: Option<String>
@@ -81,7 +86,7 @@ impl Profile {
                 }
             };
 
-            PresenceStatus::upsert(connection, homeserver_domain, &user_id, None, None)?;
+            PresenceStatus::upsert(connection, clock, homeserver_domain, &user_id, None, None)?;
             Ok(profile)
         }).map_err(ApiError::from)
     }
@@ -109,20 +114,25 @@ impl Profile {
     }
 
     /// Update `RoomMembership`'s due to changed `Profile`.
-    pub fn update_memberships(connection: &PgConnection, homeserver_domain: &str, user_id: UserId)
-    -> Result<(), ApiError> {
-        let mut room_memberships = RoomMembership::find_by_uid(connection, user_id.clone())?;
+    pub fn update_memberships(
+        connection: &PgConnection,
+        state_cache: &StateCache,
+        clock: &Clock,
+        config: &Config,
+        user_id: UserId,
+    ) -> Result<(), ApiError> {
+        let room_memberships = RoomMembership::find_by_uid(connection, user_id.clone())?;
 
-        for room_membership in &mut room_memberships {
-            let options = RoomMembershipOptions {
-                room_id: room_membership.room_id.clone(),
+        let options = room_memberships.into_iter().map(|room_membership| {
+            RoomMembershipOptions {
+                room_id: room_membership.room_id,
                 user_id: user_id.clone(),
                 sender: user_id.clone(),
                 membership: "join".to_string(),
-            };
+            }
+        }).collect();
 
-            room_membership.update(connection, homeserver_domain, options)?;
-        }
+        RoomMembership::update_many(connection, state_cache, clock, config, options)?;
 
         Ok(())
     }
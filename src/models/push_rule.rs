@@ -0,0 +1,180 @@
+//! The push rules that decide which events users are notified about.
+//!
+//! Ruma only has a subset of the spec's default push rules, which users can enable and disable
+//! but not otherwise change. Only the rules whose enabled state a user changed are stored.
+
+use std::collections::HashMap;
+
+use diesel::{Connection, ExecuteDsl, ExpressionMethods, FilterDsl, LoadDsl};
+use diesel::{delete, insert};
+use diesel::pg::PgConnection;
+use ruma_identifiers::UserId;
+
+use error::ApiError;
+use schema::push_rule_overrides;
+
+/// The kinds of push rules, in the order they are evaluated.
+pub const PUSH_RULE_KINDS: [&'static str; 5] =
+    ["override", "content", "room", "sender", "underride"];
+
+/// The rule that, when enabled, stops all notifications.
+pub const MASTER_RULE: (&'static str, &'static str) = ("override", ".m.rule.master");
+
+/// The rule that notifies and highlights messages containing the user's display name.
+pub const CONTAINS_DISPLAY_NAME_RULE: (&'static str, &'static str) =
+    ("override", ".m.rule.contains_display_name");
+
+/// The rule that notifies and highlights messages containing the user's localpart.
+pub const CONTAINS_USER_NAME_RULE: (&'static str, &'static str) =
+    ("content", ".m.rule.contains_user_name");
+
+/// The rule that notifies about messages.
+pub const MESSAGE_RULE: (&'static str, &'static str) = ("underride", ".m.rule.message");
+
+/// The rule that notifies about encrypted messages.
+pub const ENCRYPTED_RULE: (&'static str, &'static str) = ("underride", ".m.rule.encrypted");
+
+/// The default push rules Ruma evaluates, along with whether or not they are enabled by default.
+const DEFAULT_PUSH_RULES: [((&'static str, &'static str), bool); 5] = [
+    (MASTER_RULE, false),
+    (CONTAINS_DISPLAY_NAME_RULE, true),
+    (CONTAINS_USER_NAME_RULE, true),
+    (MESSAGE_RULE, true),
+    (ENCRYPTED_RULE, true),
+];
+
+/// A user's choice to enable or disable a default push rule.
+#[derive(Clone, Debug, Insertable, Queryable)]
+#[table_name = "push_rule_overrides"]
+pub struct PushRuleOverride {
+    /// The user who changed the rule.
+    pub user_id: UserId,
+    /// The kind of the rule.
+    pub kind: String,
+    /// The ID of the rule.
+    pub rule_id: String,
+    /// Whether or not the rule is enabled.
+    pub enabled: bool,
+}
+
+/// The enabled state of all push rules of a user.
+#[derive(Clone, Debug)]
+pub struct PushRules {
+    overrides: HashMap<(String, String), bool>,
+}
+
+impl PushRuleOverride {
+    /// Enables or disables a default push rule for the user.
+    ///
+    /// Returns `false` if there is no such rule.
+    pub fn set_enabled(
+        connection: &PgConnection,
+        user_id: &UserId,
+        kind: &str,
+        rule_id: &str,
+        enabled: bool,
+    ) -> Result<bool, ApiError> {
+        if default_enabled(kind, rule_id).is_none() {
+            return Ok(false);
+        }
+
+        let push_rule_override = PushRuleOverride {
+            user_id: user_id.clone(),
+            kind: kind.to_string(),
+            rule_id: rule_id.to_string(),
+            enabled: enabled,
+        };
+
+        connection.transaction::<(), ApiError, _>(|| {
+            delete(
+                push_rule_overrides::table
+                    .filter(push_rule_overrides::user_id.eq(user_id))
+                    .filter(push_rule_overrides::kind.eq(kind))
+                    .filter(push_rule_overrides::rule_id.eq(rule_id))
+            ).execute(connection).map_err(ApiError::from)?;
+
+            insert(&push_rule_override)
+                .into(push_rule_overrides::table)
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+            Ok(())
+        })?;
+
+        Ok(true)
+    }
+}
+
+impl PushRules {
+    /// Looks up which push rules the user enabled or disabled.
+    pub fn find(connection: &PgConnection, user_id: &UserId) -> Result<PushRules, ApiError> {
+        let overrides: Vec<PushRuleOverride> = push_rule_overrides::table
+            .filter(push_rule_overrides::user_id.eq(user_id))
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(PushRules {
+            overrides: overrides
+                .into_iter()
+                .map(|rule| ((rule.kind, rule.rule_id), rule.enabled))
+                .collect(),
+        })
+    }
+
+    /// Whether or not the rule is enabled, or `None` if there is no such rule.
+    pub fn enabled(&self, kind: &str, rule_id: &str) -> Option<bool> {
+        default_enabled(kind, rule_id).map(|default| {
+            match self.overrides.get(&(kind.to_string(), rule_id.to_string())) {
+                Some(&enabled) => enabled,
+                None => default,
+            }
+        })
+    }
+
+    /// Whether or not the rule is enabled. Rules that don't exist are never enabled.
+    pub fn is_enabled(&self, rule: (&str, &str)) -> bool {
+        self.enabled(rule.0, rule.1).unwrap_or(false)
+    }
+}
+
+/// Whether or not a default push rule is enabled by default, or `None` if there is no such rule.
+fn default_enabled(kind: &str, rule_id: &str) -> Option<bool> {
+    DEFAULT_PUSH_RULES
+        .iter()
+        .find(|&&((rule_kind, rule_rule_id), _)| rule_kind == kind && rule_rule_id == rule_id)
+        .map(|&(_, enabled)| enabled)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma_identifiers::UserId;
+
+    use test::Test;
+    use super::{MASTER_RULE, MESSAGE_RULE, PushRuleOverride, PushRules};
+
+    #[test]
+    fn overrides_replace_the_default_enabled_state() {
+        let test = Test::new();
+        let connection = test.connection();
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+
+        let rules = PushRules::find(&connection, &user_id).unwrap();
+        assert!(!rules.is_enabled(MASTER_RULE));
+        assert!(rules.is_enabled(MESSAGE_RULE));
+        assert_eq!(rules.enabled("override", ".m.rule.unknown"), None);
+
+        let set_enabled = |kind, rule_id, enabled| {
+            PushRuleOverride::set_enabled(&connection, &user_id, kind, rule_id, enabled).unwrap()
+        };
+
+        assert!(set_enabled("override", ".m.rule.master", true));
+        assert!(set_enabled("underride", ".m.rule.message", false));
+        assert!(!set_enabled("room", ".m.rule.message", false));
+
+        let rules = PushRules::find(&connection, &user_id).unwrap();
+        assert!(rules.is_enabled(MASTER_RULE));
+        assert!(!rules.is_enabled(MESSAGE_RULE));
+    }
+}
@@ -0,0 +1,85 @@
+//! Read receipts of users in rooms.
+
+use std::time::SystemTime;
+
+use diesel::{Connection, ExecuteDsl, ExpressionMethods, FilterDsl, LoadDsl};
+use diesel::{delete, insert};
+use diesel::pg::PgConnection;
+use ruma_identifiers::{EventId, RoomId, UserId};
+
+use error::ApiError;
+use schema::receipts;
+
+/// The receipt type of private read receipts (MSC2285).
+pub const PRIVATE_READ_RECEIPT: &'static str = "m.read.private";
+
+/// The latest receipt of one type a user sent for a room.
+#[derive(Clone, Debug, Insertable, Queryable)]
+#[table_name = "receipts"]
+pub struct Receipt {
+    /// The room the receipt is for.
+    pub room_id: RoomId,
+    /// The user who sent the receipt.
+    pub user_id: UserId,
+    /// The type of receipt, e.g. `m.read.private`.
+    pub receipt_type: String,
+    /// The event up to which the receipt applies.
+    pub event_id: EventId,
+    /// Whether or not the receipt is only visible to the user who sent it.
+    ///
+    /// Private receipts are never shared with the other members of the room.
+    pub private: bool,
+    /// The time the receipt was last moved.
+    pub updated_at: SystemTime,
+}
+
+impl Receipt {
+    /// Stores a user's receipt, replacing their previous receipt of the same type in the room.
+    pub fn upsert(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        user_id: &UserId,
+        receipt_type: &str,
+        event_id: &EventId,
+        private: bool,
+    ) -> Result<Receipt, ApiError> {
+        let receipt = Receipt {
+            room_id: room_id.clone(),
+            user_id: user_id.clone(),
+            receipt_type: receipt_type.to_string(),
+            event_id: event_id.clone(),
+            private: private,
+            updated_at: SystemTime::now(),
+        };
+
+        connection.transaction::<(), ApiError, _>(|| {
+            delete(
+                receipts::table
+                    .filter(receipts::room_id.eq(room_id))
+                    .filter(receipts::user_id.eq(user_id))
+                    .filter(receipts::receipt_type.eq(receipt_type))
+            )
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+            insert(&receipt)
+                .into(receipts::table)
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+            Ok(())
+        })?;
+
+        Ok(receipt)
+    }
+
+    /// Returns the user's receipts for the room.
+    pub fn find_by_user(connection: &PgConnection, room_id: &RoomId, user_id: &UserId)
+    -> Result<Vec<Receipt>, ApiError> {
+        receipts::table
+            .filter(receipts::room_id.eq(room_id))
+            .filter(receipts::user_id.eq(user_id))
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+}
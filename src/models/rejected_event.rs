@@ -0,0 +1,115 @@
+//! Events from other servers that were rejected.
+
+use std::time::SystemTime;
+
+use diesel::{
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    LimitDsl,
+    LoadDsl,
+    OffsetDsl,
+    OrderDsl,
+    SelectDsl,
+    delete,
+    insert,
+};
+use diesel::pg::PgConnection;
+use serde_json::{Value, to_string};
+
+use error::ApiError;
+use schema::rejected_events;
+
+/// An event that was rejected, kept with the reason for debugging.
+///
+/// Rejected events are never stored as events, so they can't show up anywhere else.
+#[derive(Clone, Debug, Queryable)]
+pub struct RejectedEvent {
+    /// The ID of the rejection.
+    pub id: i64,
+    /// The ID of the event, if it had one.
+    pub event_id: Option<String>,
+    /// The room of the event, if it had one.
+    pub room_id: Option<String>,
+    /// The server the event was received from.
+    pub origin: String,
+    /// The JSON of the event as it was received.
+    pub event_json: String,
+    /// Why the event was rejected.
+    pub reason: String,
+    /// The time the event was rejected.
+    pub rejected_at: SystemTime,
+}
+
+/// A rejected event, not saved yet.
+#[derive(Debug, Insertable)]
+#[table_name = "rejected_events"]
+struct NewRejectedEvent {
+    event_id: Option<String>,
+    room_id: Option<String>,
+    origin: String,
+    event_json: String,
+    reason: String,
+}
+
+impl RejectedEvent {
+    /// Records that an event received from `origin` was rejected.
+    ///
+    /// Only the `max_rejected_events` most recent rejections are kept, so a server sending bad
+    /// events can't fill the table.
+    pub fn record(
+        connection: &PgConnection,
+        max_rejected_events: usize,
+        origin: &str,
+        event: &Value,
+        reason: &str,
+    ) -> Result<(), ApiError> {
+        let field = |key: &str| event.get(key).and_then(Value::as_str).map(str::to_string);
+
+        let rejected_event = NewRejectedEvent {
+            event_id: field("event_id"),
+            room_id: field("room_id"),
+            origin: origin.to_string(),
+            event_json: to_string(event).map_err(ApiError::from)?,
+            reason: reason.to_string(),
+        };
+
+        warn!(
+            "Rejected event {} from {}: {}",
+            rejected_event.event_id.as_ref().map_or("without an ID", String::as_str),
+            origin,
+            reason
+        );
+
+        insert(&rejected_event)
+            .into(rejected_events::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        let oldest_kept: Vec<i64> = rejected_events::table
+            .select(rejected_events::id)
+            .order(rejected_events::id.desc())
+            .offset(max_rejected_events as i64 - 1)
+            .limit(1)
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        if let Some(oldest_kept) = oldest_kept.first() {
+            delete(rejected_events::table.filter(rejected_events::id.lt(oldest_kept)))
+                .execute(connection)
+                .map_err(ApiError::from)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the most recently rejected events, newest first.
+    pub fn find_recent(connection: &PgConnection, limit: i64)
+    -> Result<Vec<RejectedEvent>, ApiError> {
+        rejected_events::table
+            .order(rejected_events::id.desc())
+            .limit(limit)
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+}
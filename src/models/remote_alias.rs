@@ -0,0 +1,104 @@
+//! Room aliases of other servers, cached after being resolved over federation.
+
+use std::time::{Duration, SystemTime};
+
+use diesel::{Connection, ExecuteDsl, ExpressionMethods, FilterDsl, FindDsl, LoadDsl};
+use diesel::{delete, insert};
+use diesel::pg::PgConnection;
+use ruma_identifiers::{RoomAliasId, RoomId};
+
+use error::ApiError;
+use schema::remote_alias_cache;
+
+/// A remote room alias as resolved by the server it belongs to.
+#[derive(Clone, Debug, Insertable, Queryable)]
+#[table_name = "remote_alias_cache"]
+pub struct RemoteAlias {
+    /// The human-readable alias.
+    pub alias: RoomAliasId,
+    /// The ID of the room the alias points to.
+    pub room_id: RoomId,
+    /// The servers that are aware of the room, according to the alias's server.
+    pub servers: Vec<String>,
+    /// The time after which the alias has to be resolved again.
+    pub expires_at: SystemTime,
+}
+
+impl RemoteAlias {
+    /// Returns the cached resolution of the alias, unless it is missing or expired.
+    pub fn find_fresh(connection: &PgConnection, alias: &RoomAliasId)
+    -> Result<Option<RemoteAlias>, ApiError> {
+        let remote_aliases: Vec<RemoteAlias> = remote_alias_cache::table
+            .find(alias)
+            .filter(remote_alias_cache::expires_at.gt(SystemTime::now()))
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(remote_aliases.into_iter().next())
+    }
+
+    /// Caches the resolution of an alias for `ttl`, replacing any previous one.
+    pub fn store(
+        connection: &PgConnection,
+        alias: &RoomAliasId,
+        room_id: &RoomId,
+        servers: Vec<String>,
+        ttl: Duration,
+    ) -> Result<RemoteAlias, ApiError> {
+        let remote_alias = RemoteAlias {
+            alias: alias.clone(),
+            room_id: room_id.clone(),
+            servers: servers,
+            expires_at: SystemTime::now() + ttl,
+        };
+
+        connection.transaction::<(), ApiError, _>(|| {
+            delete(remote_alias_cache::table.find(alias))
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+            insert(&remote_alias)
+                .into(remote_alias_cache::table)
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+            Ok(())
+        })?;
+
+        Ok(remote_alias)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    use ruma_identifiers::{RoomAliasId, RoomId};
+
+    use test::Test;
+    use super::RemoteAlias;
+
+    #[test]
+    fn expired_aliases_are_not_found() {
+        let test = Test::new();
+        let connection = test.connection();
+
+        let alias = RoomAliasId::try_from("#rust:example.com").unwrap();
+        let room_id = RoomId::try_from("!abc:example.com").unwrap();
+
+        assert!(RemoteAlias::find_fresh(&connection, &alias).unwrap().is_none());
+
+        let servers = vec!["example.com".to_string()];
+        RemoteAlias::store(&connection, &alias, &room_id, servers.clone(), Duration::from_secs(60))
+            .unwrap();
+
+        let cached = RemoteAlias::find_fresh(&connection, &alias).unwrap().unwrap();
+        assert_eq!(cached.room_id, room_id);
+        assert_eq!(cached.servers, servers);
+
+        RemoteAlias::store(&connection, &alias, &room_id, servers, Duration::from_secs(0)).unwrap();
+
+        assert!(RemoteAlias::find_fresh(&connection, &alias).unwrap().is_none());
+    }
+}
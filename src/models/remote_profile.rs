@@ -0,0 +1,118 @@
+//! Profiles of users of other servers, cached after being fetched over federation.
+
+use std::time::{Duration, SystemTime};
+
+use diesel::{Connection, ExecuteDsl, FindDsl, LoadDsl};
+use diesel::{delete, insert};
+use diesel::pg::PgConnection;
+use ruma_identifiers::UserId;
+
+use clock::Clock;
+use error::ApiError;
+use schema::remote_profiles;
+
+/// A remote user's profile as returned by the user's server.
+#[derive(Clone, Debug, Insertable, Queryable)]
+#[table_name = "remote_profiles"]
+pub struct RemoteProfile {
+    /// The ID of the user.
+    pub user_id: UserId,
+    /// The user's avatar URL, if they have set one.
+    pub avatar_url: Option<String>,
+    /// The user's display name, if they have set one.
+    pub displayname: Option<String>,
+    /// The time after which the profile has to be fetched again.
+    pub expires_at: SystemTime,
+}
+
+impl RemoteProfile {
+    /// Returns the cached profile of the user, even if it is expired.
+    pub fn find(connection: &PgConnection, user_id: &UserId)
+    -> Result<Option<RemoteProfile>, ApiError> {
+        let remote_profiles: Vec<RemoteProfile> = remote_profiles::table
+            .find(user_id)
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(remote_profiles.into_iter().next())
+    }
+
+    /// Caches the profile of a user for `ttl` from the current time of the clock, replacing any
+    /// previous one.
+    pub fn store(
+        connection: &PgConnection,
+        clock: &Clock,
+        user_id: &UserId,
+        avatar_url: Option<String>,
+        displayname: Option<String>,
+        ttl: Duration,
+    ) -> Result<RemoteProfile, ApiError> {
+        let remote_profile = RemoteProfile {
+            user_id: user_id.clone(),
+            avatar_url: avatar_url,
+            displayname: displayname,
+            expires_at: clock.now() + ttl,
+        };
+
+        connection.transaction::<(), ApiError, _>(|| {
+            delete(remote_profiles::table.find(user_id))
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+            insert(&remote_profile)
+                .into(remote_profiles::table)
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+            Ok(())
+        })?;
+
+        Ok(remote_profile)
+    }
+
+    /// Whether or not the profile can still be used without fetching it again at the current time
+    /// of the clock.
+    pub fn is_fresh(&self, clock: &Clock) -> bool {
+        self.expires_at > clock.now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    use ruma_identifiers::UserId;
+
+    use test::Test;
+    use super::RemoteProfile;
+
+    #[test]
+    fn expired_profiles_are_still_found() {
+        let test = Test::new();
+        let connection = test.connection();
+
+        let user_id = UserId::try_from("@carl:example.com").unwrap();
+
+        assert!(RemoteProfile::find(&connection, &user_id).unwrap().is_none());
+
+        let displayname = Some("Carl".to_string());
+        let ttl = Duration::from_secs(60);
+        RemoteProfile::store(&connection, test.clock(), &user_id, None, displayname, ttl)
+            .unwrap();
+
+        let cached = RemoteProfile::find(&connection, &user_id).unwrap().unwrap();
+        assert_eq!(cached.displayname, Some("Carl".to_string()));
+        assert!(cached.is_fresh(test.clock()));
+
+        test.clock().advance(ttl);
+        assert!(!cached.is_fresh(test.clock()));
+
+        let ttl = Duration::from_secs(0);
+        RemoteProfile::store(&connection, test.clock(), &user_id, None, None, ttl).unwrap();
+
+        let cached = RemoteProfile::find(&connection, &user_id).unwrap().unwrap();
+        assert_eq!(cached.displayname, None);
+        assert!(!cached.is_fresh(test.clock()));
+    }
+}
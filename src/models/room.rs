@@ -3,7 +3,7 @@
 use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 
-use diesel::{Connection, ExecuteDsl, ExpressionMethods, FilterDsl, FindDsl, LoadDsl, OrderDsl, insert};
+use diesel::{Connection, FindDsl, LoadDsl, SelectDsl, insert};
 use diesel::pg::PgConnection;
 use diesel::pg::data_types::PgTimestamp;
 use diesel::result::Error as DieselError;
@@ -25,13 +25,20 @@ use ruma_events::room::join_rules::{
 use ruma_events::room::name::{NameEvent, NameEventContent};
 use ruma_events::room::power_levels::{PowerLevelsEvent, PowerLevelsEventContent};
 use ruma_events::room::topic::{TopicEvent, TopicEventContent};
-use ruma_identifiers::{EventId, RoomAliasId, RoomId, UserId};
+use ruma_identifiers::{RoomAliasId, RoomId, UserId};
+use serde_json::{Map, Value, from_str, to_string};
 
+use clock::Clock;
+use config::Config;
 use error::ApiError;
-use models::event::{Event, NewEvent};
+use ids::generate_event_id;
+use models::event::NewEvent;
+use models::event_batch::EventBatch;
 use models::room_alias::{NewRoomAlias, RoomAlias};
 use models::room_membership::RoomMembership;
-use schema::{events, rooms};
+use models::room_state::RoomState;
+use schema::rooms;
+use state_cache::StateCache;
 
 /// Options provided by the user to customize the room upon creation.
 pub struct CreationOptions {
@@ -47,6 +54,8 @@ pub struct CreationOptions {
     pub name: Option<String>,
     /// A convenience parameter for setting a few default state events.
     pub preset: RoomPreset,
+    /// The version of the room, set in its `m.room.create` event.
+    pub room_version: String,
     /// An initial topic for the room.
     pub topic: Option<String>,
 }
@@ -111,10 +120,15 @@ impl Room {
     /// 4. Invite events implied by invite and invite_3pid.
     pub fn create(
         connection: &PgConnection,
+        state_cache: &StateCache,
+        clock: &Clock,
         new_room: &NewRoom,
-        homeserver_domain: &str,
+        config: &Config,
         creation_options: &CreationOptions,
     ) -> Result<Room, ApiError> {
+        let homeserver_domain = &config.domain[..];
+        let room_version = &creation_options.room_version;
+
         connection.transaction::<Room, ApiError, _>(|| {
             let room: Room = insert(new_room)
                 .into(rooms::table)
@@ -123,12 +137,12 @@ impl Room {
 
             let mut new_events = Vec::new();
 
-            let new_create_event: NewEvent = CreateEvent {
+            let mut new_create_event: NewEvent = CreateEvent {
                 content: CreateEventContent {
                     creator: new_room.user_id.clone(),
                     federate: creation_options.federate,
                 },
-                event_id: EventId::new(homeserver_domain)?,
+                event_id: generate_event_id(homeserver_domain, room_version)?,
                 event_type: EventType::RoomCreate,
                 prev_content: None,
                 room_id: room.id.clone(),
@@ -137,6 +151,15 @@ impl Room {
                 user_id: new_room.user_id.clone(),
             }.try_into()?;
 
+            // `CreateEventContent` has no `room_version` yet.
+            let mut create_content: Map<String, Value> = from_str(&new_create_event.content)
+                .map_err(ApiError::from)?;
+            create_content.insert(
+                "room_version".to_string(),
+                Value::String(creation_options.room_version.clone()),
+            );
+            new_create_event.content = to_string(&create_content).map_err(ApiError::from)?;
+
             new_events.push(new_create_event);
 
             let mut is_canonical_alias_set = false;
@@ -149,7 +172,7 @@ impl Room {
                 RoomPreset::PrivateChat => {
                     let new_join_rules_event: NewEvent = JoinRulesEvent {
                         content: JoinRulesEventContent { join_rule: JoinRule::Invite },
-                        event_id: EventId::new(homeserver_domain)?,
+                        event_id: generate_event_id(homeserver_domain, room_version)?,
                         event_type: EventType::RoomJoinRules,
                         prev_content: None,
                         room_id: room.id.clone(),
@@ -163,7 +186,7 @@ impl Room {
                 RoomPreset::PublicChat => {
                     let new_join_rules_event: NewEvent = JoinRulesEvent {
                         content: JoinRulesEventContent { join_rule: JoinRule::Public },
-                        event_id: EventId::new(homeserver_domain)?,
+                        event_id: generate_event_id(homeserver_domain, room_version)?,
                         event_type: EventType::RoomJoinRules,
                         prev_content: None,
                         room_id: room.id.clone(),
@@ -179,7 +202,7 @@ impl Room {
 
                     let new_join_rules_event: NewEvent = JoinRulesEvent {
                         content: JoinRulesEventContent { join_rule: JoinRule::Invite },
-                        event_id: EventId::new(homeserver_domain)?,
+                        event_id: generate_event_id(homeserver_domain, room_version)?,
                         event_type: EventType::RoomJoinRules,
                         prev_content: None,
                         room_id: room.id.clone(),
@@ -197,7 +220,7 @@ impl Room {
                     content: NameEventContent {
                         name: name.to_string(),
                     },
-                    event_id: EventId::new(homeserver_domain)?,
+                    event_id: generate_event_id(homeserver_domain, room_version)?,
                     event_type: EventType::RoomName,
                     prev_content: None,
                     room_id: room.id.clone(),
@@ -214,7 +237,7 @@ impl Room {
                     content: TopicEventContent {
                         topic: topic.to_string(),
                     },
-                    event_id: EventId::new(homeserver_domain)?,
+                    event_id: generate_event_id(homeserver_domain, room_version)?,
                     event_type: EventType::RoomTopic,
                     prev_content: None,
                     room_id: room.id.clone(),
@@ -252,7 +275,7 @@ impl Room {
                         StrippedState::RoomAvatar(event) => {
                             let new_avatar_event: NewEvent = AvatarEvent {
                                 content: event.content.clone(),
-                                event_id: EventId::new(homeserver_domain)?,
+                                event_id: generate_event_id(homeserver_domain, room_version)?,
                                 event_type: EventType::RoomAvatar,
                                 prev_content: None,
                                 room_id: room.id.clone(),
@@ -272,7 +295,7 @@ impl Room {
 
                             let new_canonical_alias_event: NewEvent = CanonicalAliasEvent {
                                 content: event.content.clone(),
-                                event_id: EventId::new(homeserver_domain)?,
+                                event_id: generate_event_id(homeserver_domain, room_version)?,
                                 event_type: EventType::RoomCanonicalAlias,
                                 prev_content: None,
                                 room_id: room.id.clone(),
@@ -291,7 +314,7 @@ impl Room {
 
                             let new_history_visibility_event: NewEvent = HistoryVisibilityEvent {
                                 content: event.content.clone(),
-                                event_id: EventId::new(homeserver_domain)?,
+                                event_id: generate_event_id(homeserver_domain, room_version)?,
                                 event_type: EventType::RoomHistoryVisibility,
                                 prev_content: None,
                                 room_id: room.id.clone(),
@@ -305,7 +328,7 @@ impl Room {
                         StrippedState::RoomJoinRules(event) => {
                             let new_join_rules_event: NewEvent = JoinRulesEvent {
                                 content: event.content.clone(),
-                                event_id: EventId::new(homeserver_domain)?,
+                                event_id: generate_event_id(homeserver_domain, room_version)?,
                                 event_type: EventType::RoomJoinRules,
                                 prev_content: None,
                                 room_id: room.id.clone(),
@@ -323,7 +346,7 @@ impl Room {
 
                             let new_name_event: NewEvent = NameEvent {
                                 content: event.content.clone(),
-                                event_id: EventId::new(homeserver_domain)?,
+                                event_id: generate_event_id(homeserver_domain, room_version)?,
                                 event_type: EventType::RoomName,
                                 prev_content: None,
                                 room_id: room.id.clone(),
@@ -346,7 +369,7 @@ impl Room {
 
                             let new_power_levels_event: NewEvent = PowerLevelsEvent {
                                 content: event.content.clone(),
-                                event_id: EventId::new(homeserver_domain)?,
+                                event_id: generate_event_id(homeserver_domain, room_version)?,
                                 event_type: EventType::RoomPowerLevels,
                                 prev_content: None,
                                 room_id: room.id.clone(),
@@ -367,7 +390,7 @@ impl Room {
 
                             let new_topic_event: NewEvent = TopicEvent {
                                 content: event.content.clone(),
-                                event_id: EventId::new(homeserver_domain)?,
+                                event_id: generate_event_id(homeserver_domain, room_version)?,
                                 event_type: EventType::RoomTopic,
                                 prev_content: None,
                                 room_id: room.id.clone(),
@@ -394,7 +417,7 @@ impl Room {
                     content: HistoryVisibilityEventContent {
                         history_visibility: HistoryVisibility::Shared,
                     },
-                    event_id: EventId::new(homeserver_domain)?,
+                    event_id: generate_event_id(homeserver_domain, room_version)?,
                     event_type: EventType::RoomHistoryVisibility,
                     prev_content: None,
                     room_id: room.id.clone(),
@@ -428,7 +451,7 @@ impl Room {
                         users: user_power,
                         users_default: 0,
                     },
-                    event_id: EventId::new(homeserver_domain)?,
+                    event_id: generate_event_id(homeserver_domain, room_version)?,
                     event_type: EventType::RoomPowerLevels,
                     prev_content: None,
                     room_id: room.id.clone(),
@@ -447,7 +470,7 @@ impl Room {
                             &format!("#{}:{}", creation_options.alias.clone().unwrap(), homeserver_domain)
                         )?
                     },
-                    event_id: EventId::new(homeserver_domain)?,
+                    event_id: generate_event_id(homeserver_domain, room_version)?,
                     event_type: EventType::RoomCanonicalAlias,
                     prev_content: None,
                     room_id: room.id.clone(),
@@ -459,15 +482,6 @@ impl Room {
                 new_events.push(new_canonical_alias_event);
             }
 
-            insert(&new_events)
-                .into(events::table)
-                .execute(connection)
-                .map_err(ApiError::from)?;
-
-            for alias in new_room_aliases {
-                RoomAlias::create(connection, homeserver_domain, &alias)?;
-            }
-
             if let Some(ref alias) = creation_options.alias {
                 let new_room_alias = NewRoomAlias {
                     alias: RoomAliasId::try_from(&format!("#{}:{}", alias, homeserver_domain))?,
@@ -476,49 +490,65 @@ impl Room {
                     servers: vec![homeserver_domain.to_string()],
                 };
 
-                RoomAlias::create(connection, homeserver_domain, &new_room_alias)?;
+                new_room_aliases.push(new_room_alias);
+            }
+
+            let mut batch = EventBatch::new();
+
+            for new_event in new_events {
+                batch.add_event(new_event);
             }
 
+            if !new_room_aliases.is_empty() {
+                let aliases = new_room_aliases.iter().map(|room_alias| room_alias.alias.clone()).collect();
+
+                batch.add_event(
+                    RoomAlias::new_aliases_event(
+                        homeserver_domain,
+                        room_version,
+                        &room.id,
+                        &new_room.user_id,
+                        aliases,
+                    )?
+                );
+
+                for new_room_alias in new_room_aliases {
+                    batch.add_room_alias(new_room_alias);
+                }
+            }
+
+            batch.commit(connection, state_cache, clock, config)?;
+
             if let Some(ref invite_list) = creation_options.invite_list {
-                RoomMembership::create_memberships(connection, &room, invite_list, homeserver_domain)?;
+                RoomMembership::create_memberships(
+                    connection,
+                    state_cache,
+                    clock,
+                    &room,
+                    invite_list,
+                    config,
+                )?;
             }
 
             Ok(room)
         }).map_err(ApiError::from)
     }
 
-    /// Looks up the most recent power levels event for the room.
+    /// Looks up the current power levels of the room.
     ///
     /// If the room does not have a power levels event, a default one is created according to the
     /// specification.
-    pub fn current_power_levels(&self, connection: &PgConnection)
+    pub fn current_power_levels(&self, connection: &PgConnection, state_cache: &StateCache)
     -> Result<PowerLevelsEventContent, ApiError> {
-        match events::table
-            .filter(events::room_id.eq(self.id.clone()))
-            .filter(events::event_type.eq(EventType::RoomPowerLevels.to_string()))
-            .order(events::ordering.desc())
-            .first::<Event>(connection)
-        {
-            Ok(event) => {
-                let power_levels_event: PowerLevelsEvent = event.try_into()?;
-
-                Ok(power_levels_event.content)
-            }
-            Err(error) => match error {
-                DieselError::NotFound => Ok(PowerLevelsEventContent {
-                    ban: 50,
-                    events: HashMap::new(),
-                    events_default: 0,
-                    invite: 50,
-                    kick: 50,
-                    redact: 50,
-                    state_default: 0,
-                    users: HashMap::new(),
-                    users_default: 0,
-                }),
-                _ => Err(error.into()),
-            },
-        }
+        RoomState::current(connection, state_cache, &self.id)?.power_levels()
+    }
+
+    /// Return the IDs of all rooms on the server.
+    pub fn all_ids(connection: &PgConnection) -> Result<Vec<RoomId>, ApiError> {
+        rooms::table
+            .select(rooms::id)
+            .load(connection)
+            .map_err(ApiError::from)
     }
 
     /// Look up a `Room` given the `RoomId`.
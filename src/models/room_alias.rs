@@ -9,23 +9,67 @@ use diesel::{
     FindDsl,
     LoadDsl,
     ExecuteDsl,
-    insert,
     delete,
 };
 use diesel::pg::PgConnection;
 use diesel::pg::data_types::PgTimestamp;
-use diesel::result::{Error as DieselError, DatabaseErrorKind};
-use ruma_identifiers::{EventId, RoomAliasId, RoomId, UserId};
+use diesel::result::Error as DieselError;
+use ruma_identifiers::{RoomAliasId, RoomId, UserId};
 use ruma_events::room::aliases::{AliasesEvent, AliasesEventContent};
 use ruma_events::EventType;
 
+use clock::Clock;
+use config::Config;
 use error::ApiError;
+use ids::generate_event_id;
 use models::event::NewEvent;
+use models::event_batch::EventBatch;
 use models::room::Room;
-use schema::{events, room_aliases};
+use models::room_membership::RoomMembership;
+use models::room_state::RoomState;
+use schema::room_aliases;
+use state_cache::StateCache;
+
+/// The power level from which room members may delete aliases other users created.
+const ROOM_ADMIN_POWER_LEVEL: u64 = 100;
+
+/// Checks that the local part of a new room alias is at most `max_length` characters long and only
+/// uses the characters `a-z`, `A-Z`, `0-9`, `.`, `_`, `-` and `/`.
+///
+/// Limiting aliases to these ASCII characters rules out null bytes, direction overrides and
+/// characters that look like others, which could be used to impersonate aliases in clients.
+pub fn validate_alias_localpart(localpart: &str, max_length: usize) -> Result<(), ApiError> {
+    check_alias_localpart(localpart, max_length)
+        .map_err(|reason| ApiError::invalid_param("room_alias", &reason))
+}
+
+/// Like `validate_alias_localpart`, but returns why the local part is invalid instead of an
+/// `ApiError`.
+pub fn check_alias_localpart(localpart: &str, max_length: usize) -> Result<(), String> {
+    if localpart.is_empty() {
+        return Err("must not be empty".to_string());
+    }
+
+    if localpart.len() > max_length {
+        return Err(format!("must not be longer than {} characters", max_length));
+    }
+
+    let is_allowed = |c: char| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '.' | '_' | '-' | '/' => true,
+        _ => false,
+    };
+
+    if !localpart.chars().all(is_allowed) {
+        return Err(
+            "may only contain the characters a-z, A-Z, 0-9, '.', '_', '-' and '/'".to_string()
+        );
+    }
+
+    Ok(())
+}
 
 /// A new room alias, not yet saved.
-#[derive(Debug, Insertable)]
+#[derive(Clone, Debug, Insertable)]
 #[table_name = "room_aliases"]
 pub struct NewRoomAlias {
     /// The human-readable alias.
@@ -57,9 +101,14 @@ pub struct RoomAlias {
 
 impl RoomAlias {
     /// Creates a new room alias in the database.
-    pub fn create(connection: &PgConnection, homeserver_domain: &str, new_room_alias: &NewRoomAlias)
-    -> Result<RoomAlias, ApiError> {
-        connection.transaction(|| {
+    pub fn create(
+        connection: &PgConnection,
+        state_cache: &StateCache,
+        clock: &Clock,
+        config: &Config,
+        new_room_alias: &NewRoomAlias,
+    ) -> Result<RoomAlias, ApiError> {
+        connection.transaction::<RoomAlias, ApiError, _>(|| {
             if Room::find(connection, &new_room_alias.room_id)?.is_none() {
                 return Err(ApiError::bad_json("Room not found".to_string()));
             }
@@ -68,33 +117,47 @@ impl RoomAlias {
             let mut ids: Vec<RoomAliasId> = aliases.iter().map(|a| a.alias.clone()).collect();
             ids.push(new_room_alias.alias.clone());
 
-            let new_room_alias_event: NewEvent = AliasesEvent {
-                content: AliasesEventContent { aliases: ids },
-                event_id: EventId::new(homeserver_domain)?,
-                event_type: EventType::RoomAliases,
-                prev_content: None,
-                room_id: new_room_alias.room_id.clone(),
-                state_key: homeserver_domain.to_string(),
-                unsigned: None,
-                user_id: new_room_alias.user_id.clone(),
-            }.try_into()?;
-
-            insert(&new_room_alias_event)
-                .into(events::table)
-                .execute(connection)
-                .map_err(ApiError::from)?;
-
-            insert(new_room_alias)
-                .into(room_aliases::table)
-                .get_result(connection)
-                .map_err(|err| match err {
-                    DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)
-                        => ApiError::alias_taken(None),
-                    _ => ApiError::from(err),
-                })
+            let room_version = RoomState::current(connection, state_cache, &new_room_alias.room_id)?
+                .room_version()?;
+
+            let mut batch = EventBatch::new();
+
+            batch
+                .add_event(RoomAlias::new_aliases_event(
+                    &config.domain,
+                    &room_version,
+                    &new_room_alias.room_id,
+                    &new_room_alias.user_id,
+                    ids,
+                )?)
+                .add_room_alias(new_room_alias.clone());
+
+            batch.commit(connection, state_cache, clock, config)?;
+
+            RoomAlias::find_by_alias(connection, &new_room_alias.alias)
         }).map_err(ApiError::from)
     }
 
+    /// Create the `m.room.aliases` event listing this server's aliases for a room.
+    pub fn new_aliases_event(
+        homeserver_domain: &str,
+        room_version: &str,
+        room_id: &RoomId,
+        user_id: &UserId,
+        aliases: Vec<RoomAliasId>,
+    ) -> Result<NewEvent, ApiError> {
+        AliasesEvent {
+            content: AliasesEventContent { aliases: aliases },
+            event_id: generate_event_id(homeserver_domain, room_version)?,
+            event_type: EventType::RoomAliases,
+            prev_content: None,
+            room_id: room_id.clone(),
+            state_key: homeserver_domain.to_string(),
+            unsigned: None,
+            user_id: user_id.clone(),
+        }.try_into()
+    }
+
     /// Return the `RoomAlias` entry for given `RoomAliasId`.
     pub fn find_by_alias(connection: &PgConnection, alias: &RoomAliasId)
     -> Result<RoomAlias, ApiError> {
@@ -102,13 +165,15 @@ impl RoomAlias {
             .find(alias)
             .get_result(connection)
             .map_err(|err| match err {
-                DieselError::NotFound => ApiError::not_found(None),
+                DieselError::NotFound => {
+                    ApiError::not_found(format!("The room alias {} was not found.", alias))
+                }
                 _ => ApiError::from(err),
             })
     }
 
     /// Return all aliases associated with the given `RoomId`.
-    fn find_by_room_id(connection: &PgConnection, room_id: &RoomId)
+    pub fn find_by_room_id(connection: &PgConnection, room_id: &RoomId)
     -> Result<Vec<RoomAlias>, ApiError> {
         let aliases: Vec<RoomAlias> = room_aliases::table
             .filter(room_aliases::room_id.eq(room_id))
@@ -118,15 +183,74 @@ impl RoomAlias {
         Ok(aliases)
     }
 
-    /// Deletes a room alias in the database.
-    pub fn delete(connection: &PgConnection, alias_id: &RoomAliasId, user_id: &UserId)
-    -> Result<usize, ApiError> {
-        let alias = room_aliases::table
-            .filter(room_aliases::alias.eq(alias_id.to_string()))
-            .filter(room_aliases::user_id.eq(user_id.to_string()));
+    /// Deletes a room alias in the database, if the user created it or is an admin of the room
+    /// it points to.
+    ///
+    /// Returns the number of deleted aliases, which is 0 if the alias doesn't exist or the user
+    /// may not delete it.
+    pub fn delete(
+        connection: &PgConnection,
+        state_cache: &StateCache,
+        alias_id: &RoomAliasId,
+        user_id: &UserId,
+    ) -> Result<usize, ApiError> {
+        let result = room_aliases::table.find(alias_id).get_result::<RoomAlias>(connection);
+
+        let room_alias = match result {
+            Ok(room_alias) => room_alias,
+            Err(DieselError::NotFound) => return Ok(0),
+            Err(error) => return Err(ApiError::from(error)),
+        };
+
+        let may_delete = room_alias.user_id == *user_id ||
+            room_alias.is_room_admin(connection, state_cache, user_id)?;
 
-        delete(alias)
+        if !may_delete {
+            return Ok(0);
+        }
+
+        delete(room_aliases::table.find(alias_id))
             .execute(connection)
             .map_err(ApiError::from)
     }
+
+    /// Whether or not the user is a joined member of the aliased room with at least the power
+    /// level of a room admin.
+    fn is_room_admin(&self, connection: &PgConnection, state_cache: &StateCache, user_id: &UserId)
+    -> Result<bool, ApiError> {
+        match RoomMembership::find(connection, &self.room_id, user_id)? {
+            Some(ref membership) if membership.membership == "join" => {}
+            _ => return Ok(false),
+        }
+
+        let state = RoomState::current(connection, state_cache, &self.room_id)?;
+        let power_levels = state.power_levels()?;
+        let user_power_level = power_levels
+            .users
+            .get(user_id)
+            .unwrap_or(&power_levels.users_default);
+
+        Ok(*user_power_level >= ROOM_ADMIN_POWER_LEVEL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::validate_alias_localpart;
+
+    #[test]
+    fn valid_alias_localparts() {
+        for localpart in &["room", "My.Room_1", "team/general", "a-b"] {
+            assert!(validate_alias_localpart(localpart, 255).is_ok());
+        }
+    }
+
+    #[test]
+    fn invalid_alias_localparts() {
+        for localpart in &["", "my room", "room\0", "caf\u{e9}", "\u{202e}moor", "r\u{43e}om", "a:b"] {
+            assert!(validate_alias_localpart(localpart, 255).is_err());
+        }
+
+        assert!(validate_alias_localpart("toolong", 6).is_err());
+    }
 }
@@ -0,0 +1,202 @@
+//! The directory of public rooms.
+//!
+//! The entries of the directory are read from the `public_room_directory` view, which gathers the
+//! current state of each public room, so the rooms are filtered, ranked, and paginated by
+//! PostgreSQL.
+
+use diesel::{BoolExpressionMethods, ExpressionMethods, FilterDsl, LimitDsl, LoadDsl, OffsetDsl};
+use diesel::{OrderDsl, SelectDsl};
+use diesel::expression::dsl::count_star;
+use diesel::pg::PgConnection;
+use diesel::types::{Array, Integer, Nullable, Text};
+use ruma_identifiers::RoomId;
+
+use error::ApiError;
+use models::group::related_groups;
+use schema::public_room_directory;
+
+sql_function!(
+    public_room_relevance,
+    public_room_relevance_t,
+    (
+        name: Nullable<Text>,
+        canonical_alias: Nullable<Text>,
+        aliases: Array<Text>,
+        topic: Nullable<Text>,
+        term: Nullable<Text>
+    ) -> Integer
+);
+
+/// Criteria for narrowing down the rooms in the directory.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PublicRoomsFilter {
+    /// A string to search for in the room's name, topic, and aliases.
+    pub generic_search_term: Option<String>,
+}
+
+/// Who the directory is being listed for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DirectoryAccess {
+    /// An authenticated user or server, who can see every public room.
+    Authenticated,
+    /// A guest, who can only see the rooms that guests may join or view.
+    Guest,
+}
+
+/// A page of the public room directory.
+#[derive(Debug, Serialize)]
+pub struct PublicRooms {
+    /// The rooms on this page.
+    chunk: Vec<PublicRoomsChunk>,
+    /// A token for fetching the next page, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_batch: Option<String>,
+    /// A token for fetching the previous page, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev_batch: Option<String>,
+    /// The number of rooms matching the filter.
+    total_room_count_estimate: usize,
+}
+
+/// A public room as listed in the directory.
+#[derive(Debug, Serialize)]
+pub struct PublicRoomsChunk {
+    /// The aliases of the room.
+    aliases: Vec<String>,
+    /// The URL of the room's avatar.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+    /// The canonical alias of the room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    canonical_alias: Option<String>,
+    /// Whether or not guest users may join the room.
+    guest_can_join: bool,
+    /// The name of the room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// The number of members joined to the room.
+    num_joined_members: i64,
+    /// The groups the room is affiliated with, from its `m.room.related_groups` event.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    related_groups: Vec<String>,
+    /// The ID of the room.
+    room_id: RoomId,
+    /// The topic of the room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    /// Whether or not the room may be viewed by guest users without joining.
+    world_readable: bool,
+}
+
+/// A row of the `public_room_directory` view.
+#[derive(Debug, Queryable)]
+struct DirectoryEntry {
+    room_id: RoomId,
+    name: Option<String>,
+    topic: Option<String>,
+    canonical_alias: Option<String>,
+    avatar_url: Option<String>,
+    guest_can_join: bool,
+    world_readable: bool,
+    num_joined_members: i64,
+    aliases: Vec<String>,
+    /// The content of the room's `m.room.related_groups` event.
+    related_groups: Option<String>,
+}
+
+impl PublicRooms {
+    /// Looks up a page of public rooms visible with the given access.
+    ///
+    /// Rooms are sorted by their relevance to the filter's search term, then by the number of
+    /// joined members. `since` is a token from the `next_batch` or `prev_batch` of a previous page.
+    ///
+    /// The search is a case-insensitive substring search. Matches in the name count the most,
+    /// followed by the canonical alias, the other aliases, and the topic. A match at the start of
+    /// a field counts twice.
+    pub fn find(
+        connection: &PgConnection,
+        limit: Option<usize>,
+        since: Option<&str>,
+        filter: &PublicRoomsFilter,
+        access: DirectoryAccess,
+    ) -> Result<PublicRooms, ApiError> {
+        let offset = match since {
+            Some(since) => since.parse::<u32>().map_err(|_| {
+                ApiError::invalid_param("since", "Invalid pagination token.")
+            })? as i64,
+            None => 0,
+        };
+
+        let everyone = access == DirectoryAccess::Authenticated;
+        let visible = || {
+            public_room_directory::guest_can_join
+                .or(public_room_directory::world_readable)
+                .or(everyone)
+        };
+
+        let relevance = || public_room_relevance(
+            public_room_directory::name,
+            public_room_directory::canonical_alias,
+            public_room_directory::aliases,
+            public_room_directory::topic,
+            filter.generic_search_term.clone(),
+        );
+
+        let total_room_count_estimate: i64 = public_room_directory::table
+            .filter(visible())
+            .filter(relevance().gt(0))
+            .select(count_star())
+            .first(connection)
+            .map_err(ApiError::from)?;
+
+        let limit = limit.map_or(total_room_count_estimate, |limit| limit as i64);
+
+        let entries: Vec<DirectoryEntry> = public_room_directory::table
+            .filter(visible())
+            .filter(relevance().gt(0))
+            .order((
+                relevance().desc(),
+                public_room_directory::num_joined_members.desc(),
+                public_room_directory::room_id.asc(),
+            ))
+            .offset(offset)
+            .limit(limit)
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        let end = offset.saturating_add(limit);
+
+        Ok(PublicRooms {
+            chunk: entries.into_iter().map(PublicRoomsChunk::from).collect(),
+            next_batch: if end < total_room_count_estimate {
+                Some(end.to_string())
+            } else {
+                None
+            },
+            prev_batch: if offset > 0 {
+                Some((offset - limit).max(0).to_string())
+            } else {
+                None
+            },
+            total_room_count_estimate: total_room_count_estimate as usize,
+        })
+    }
+}
+
+impl From<DirectoryEntry> for PublicRoomsChunk {
+    fn from(entry: DirectoryEntry) -> PublicRoomsChunk {
+        PublicRoomsChunk {
+            aliases: entry.aliases,
+            avatar_url: entry.avatar_url,
+            canonical_alias: entry.canonical_alias,
+            guest_can_join: entry.guest_can_join,
+            name: entry.name,
+            num_joined_members: entry.num_joined_members,
+            related_groups: entry.related_groups
+                .map_or_else(Vec::new, |content| related_groups(&content)),
+            room_id: entry.room_id,
+            topic: entry.topic,
+            world_readable: entry.world_readable,
+        }
+    }
+}
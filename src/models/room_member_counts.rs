@@ -0,0 +1,80 @@
+//! Counts of the joined and invited members of rooms.
+
+use diesel::{
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    SelectDsl,
+    insert,
+};
+use diesel::expression::dsl::count_star;
+use diesel::pg::PgConnection;
+use diesel::pg::upsert::{OnConflictExtension, do_update};
+use ruma_identifiers::RoomId;
+
+use error::ApiError;
+use schema::{room_member_counts, room_memberships};
+
+/// The number of joined and invited members of a room.
+///
+/// The counts are kept up to date by `EventBatch`, in the transaction saving membership changes,
+/// so reading them doesn't have to scan the room's memberships.
+#[derive(Clone, Debug, Insertable, Queryable)]
+#[table_name = "room_member_counts"]
+pub struct RoomMemberCounts {
+    /// The ID of the room.
+    pub room_id: RoomId,
+    /// The number of users whose membership is `join`.
+    pub joined: i64,
+    /// The number of users whose membership is `invite`.
+    pub invited: i64,
+}
+
+impl RoomMemberCounts {
+    /// Returns the counts of the room, which are zero for rooms without any memberships.
+    pub fn find(connection: &PgConnection, room_id: &RoomId) -> Result<RoomMemberCounts, ApiError> {
+        let counts: Vec<RoomMemberCounts> = room_member_counts::table
+            .find(room_id)
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(counts.into_iter().next().unwrap_or_else(|| RoomMemberCounts {
+            room_id: room_id.clone(),
+            joined: 0,
+            invited: 0,
+        }))
+    }
+
+    /// Recounts the members of the room from its memberships.
+    ///
+    /// This must happen in the transaction changing the memberships.
+    pub fn refresh(connection: &PgConnection, room_id: &RoomId) -> Result<(), ApiError> {
+        let count = |membership: &str| {
+            room_memberships::table
+                .filter(room_memberships::room_id.eq(room_id))
+                .filter(room_memberships::membership.eq(membership))
+                .select(count_star())
+                .first::<i64>(connection)
+                .map_err(ApiError::from)
+        };
+
+        let counts = RoomMemberCounts {
+            room_id: room_id.clone(),
+            joined: count("join")?,
+            invited: count("invite")?,
+        };
+
+        let changes = (
+            room_member_counts::joined.eq(counts.joined),
+            room_member_counts::invited.eq(counts.invited),
+        );
+
+        insert(&counts.on_conflict(room_member_counts::room_id, do_update().set(changes)))
+            .into(room_member_counts::table)
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+}
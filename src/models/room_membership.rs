@@ -1,19 +1,15 @@
 //! Matrix room membership.
 
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::error::Error;
 
 use diesel::{
-    Connection,
     ExpressionMethods,
-    ExecuteDsl,
     FilterDsl,
-    FindDsl,
+    GroupByDsl,
     LoadDsl,
-    SaveChangesDsl,
     SelectDsl,
-    insert,
-    update,
 };
 use diesel::expression::dsl::*;
 use diesel::pg::PgConnection;
@@ -29,12 +25,18 @@ use ruma_events::room::member::{
 use ruma_identifiers::{EventId, RoomId, UserId};
 use serde_json::{Value, from_value};
 
+use clock::Clock;
+use config::Config;
 use error::ApiError;
+use ids::generate_event_id;
 use models::event::{NewEvent, Event};
+use models::event_batch::EventBatch;
 use models::user::User;
 use models::profile::Profile;
 use models::room::Room;
+use models::room_state::RoomState;
 use schema::{events, room_memberships};
+use state_cache::StateCache;
 
 /// Room membership update or create data.
 #[derive(Debug, Clone)]
@@ -86,14 +88,32 @@ pub struct RoomMembership {
 
 impl RoomMembership {
     /// Creates a new `RoomMembership` in the database.
-    pub fn create(connection: &PgConnection, homeserver_domain: &str, options: RoomMembershipOptions)
-    -> Result<RoomMembership, ApiError> {
-        RoomMembership::verify_creation_priviledges(connection, &options)?;
+    pub fn create(
+        connection: &PgConnection,
+        state_cache: &StateCache,
+        clock: &Clock,
+        config: &Config,
+        options: RoomMembershipOptions,
+    ) -> Result<RoomMembership, ApiError> {
+        RoomMembership::verify_creation_priviledges(connection, state_cache, &options)?;
+        RoomMembership::create_unchecked(connection, state_cache, clock, config, options)
+    }
 
+    /// Creates a new `RoomMembership` in the database without checking the sender's privileges.
+    fn create_unchecked(
+        connection: &PgConnection,
+        state_cache: &StateCache,
+        clock: &Clock,
+        config: &Config,
+        options: RoomMembershipOptions,
+    ) -> Result<RoomMembership, ApiError> {
         let profile = Profile::find_by_uid(connection, &options.user_id)?;
+        let room_version = RoomState::current(connection, state_cache, &options.room_id)?
+            .room_version()?;
 
         let new_member_event = RoomMembership::create_new_room_member_event(
-            homeserver_domain,
+            &config.domain,
+            &room_version,
             &options,
             profile,
         )?;
@@ -108,6 +128,9 @@ impl RoomMembership {
 
         let memberships = RoomMembership::save_memberships(
             connection,
+            state_cache,
+            clock,
+            config,
             vec![new_member_event],
             vec![new_membership]
         )?;
@@ -116,18 +139,26 @@ impl RoomMembership {
     }
 
     /// Creates many `RoomMembership`s in the database.
-    pub fn create_many(connection: &PgConnection, homeserver_domain: &str, options: Vec<RoomMembershipOptions>)
-    -> Result<Vec<RoomMembership>, ApiError> {
+    pub fn create_many(
+        connection: &PgConnection,
+        state_cache: &StateCache,
+        clock: &Clock,
+        config: &Config,
+        options: Vec<RoomMembershipOptions>,
+    ) -> Result<Vec<RoomMembership>, ApiError> {
         let mut events: Vec<NewEvent> = Vec::new();
         let mut new_memberships: Vec<NewRoomMembership> = Vec::new();
 
         for option in options {
-            RoomMembership::verify_creation_priviledges(connection, &option)?;
+            RoomMembership::verify_creation_priviledges(connection, state_cache, &option)?;
 
             let profile = Profile::find_by_uid(connection, &option.user_id)?;
+            let room_version = RoomState::current(connection, state_cache, &option.room_id)?
+                .room_version()?;
 
             let new_member_event = RoomMembership::create_new_room_member_event(
-                homeserver_domain,
+                &config.domain,
+                &room_version,
                 &option,
                 profile,
             )?;
@@ -144,46 +175,59 @@ impl RoomMembership {
             new_memberships.push(new_membership);
         }
 
-        RoomMembership::save_memberships(connection, events, new_memberships)
+        RoomMembership::save_memberships(
+            connection,
+            state_cache,
+            clock,
+            config,
+            events,
+            new_memberships,
+        )
     }
 
-    /// Save new memberships along with their corresponding `m.room.member` events.
-    fn save_memberships(connection: &PgConnection, events: Vec<NewEvent>, new_memberships: Vec<NewRoomMembership>)
-    -> Result<Vec<RoomMembership>, ApiError> {
-        connection.transaction::<Vec<RoomMembership>, ApiError, _>(|| {
-            insert(&events)
-                .into(events::table)
-                .execute(connection)
-                .map_err(ApiError::from)?;
-
-            let memberships: Vec<RoomMembership> = insert(&new_memberships)
-                                                    .into(room_memberships::table)
-                                                    .get_results(connection)
-                                                    .map_err(ApiError::from)?;
-            Ok(memberships)
-        }).map_err(ApiError::from)
+    /// Save new memberships along with their corresponding `m.room.member` events, and queue the
+    /// events for delivery to the other servers in the room.
+    fn save_memberships(
+        connection: &PgConnection,
+        state_cache: &StateCache,
+        clock: &Clock,
+        config: &Config,
+        events: Vec<NewEvent>,
+        new_memberships: Vec<NewRoomMembership>,
+    ) -> Result<Vec<RoomMembership>, ApiError> {
+        let mut batch = EventBatch::new();
+
+        for (event, new_membership) in events.into_iter().zip(new_memberships) {
+            batch.add_membership(event, new_membership);
+        }
+
+        batch.commit(connection, state_cache, clock, config)
     }
 
     /// Check if a `User` has enough priviledges to create a `RoomMembership`.
-    fn verify_creation_priviledges(connection: &PgConnection, options: &RoomMembershipOptions)
-    -> Result<(), ApiError> {
+    fn verify_creation_priviledges(
+        connection: &PgConnection,
+        state_cache: &StateCache,
+        options: &RoomMembershipOptions,
+    ) -> Result<(), ApiError> {
         let room = match Room::find(connection, &options.room_id)? {
             Some(room) => room,
             None => Err(ApiError::unauthorized("The room was not found on this server".to_string()))?,
         };
 
-        let join_rules_event = Event::find_room_join_rules_by_room_id(connection, room.id.clone())?;
+        let state = RoomState::current(connection, state_cache, &room.id)?;
+        let join_rule = state.join_rule()?.ok_or_else(|| ApiError::not_found(None))?;
 
         // Only the creator of the room can join an invite-only room, without an invite.
         if options.membership == "join" {
-            if join_rules_event.content.join_rule == JoinRule::Invite && options.sender != room.user_id {
+            if join_rule == JoinRule::Invite && options.sender != room.user_id {
                 return Err(ApiError::unauthorized("You are not invited to this room".to_string()));
             }
 
             return Ok(());
         }
 
-        let power_levels = room.current_power_levels(connection)?;
+        let power_levels = state.power_levels()?;
         let user_power_level = power_levels
             .users
             .get(&options.sender)
@@ -210,6 +254,33 @@ impl RoomMembership {
         }
     }
 
+    /// Return the user's `RoomMembership` if it is one of `memberships`.
+    ///
+    /// Otherwise, unless the room is world-readable, the same `M_NOT_FOUND` error is returned
+    /// whether or not the room exists, so its ID can't be probed for. Endpoints reading room data
+    /// should check access with this.
+    pub fn require(
+        connection: &PgConnection,
+        state_cache: &StateCache,
+        room_id: &RoomId,
+        user_id: &UserId,
+        memberships: &[&str],
+    ) -> Result<RoomMembership, ApiError> {
+        match RoomMembership::find(connection, room_id, user_id)? {
+            Some(entry) if memberships.contains(&entry.membership.as_str()) => return Ok(entry),
+            _ => {}
+        }
+
+        let is_world_readable = Room::find(connection, room_id)?.is_some() &&
+            RoomState::current(connection, state_cache, room_id)?.is_world_readable()?;
+
+        if is_world_readable {
+            Err(ApiError::unauthorized("The user is not a member of the room".to_string()))
+        } else {
+            Err(ApiError::not_found("The room was not found.".to_string()))
+        }
+    }
+
     /// Return `RoomMembership`'s for given `UserId`.
     pub fn find_by_uid(connection: &PgConnection, user_id: UserId) -> Result<Vec<RoomMembership>, ApiError> {
         let room_memberships: Vec<RoomMembership> = room_memberships::table
@@ -224,8 +295,13 @@ impl RoomMembership {
     }
 
     /// Update an existing `RoomMembership` entry or insert a new one.
-    pub fn upsert(connection: &PgConnection, domain: &str, options: RoomMembershipOptions)
-    -> Result<RoomMembership, ApiError> {
+    pub fn upsert(
+        connection: &PgConnection,
+        state_cache: &StateCache,
+        clock: &Clock,
+        config: &Config,
+        options: RoomMembershipOptions,
+    ) -> Result<RoomMembership, ApiError> {
         let room_membership = RoomMembership::find(
             connection,
             &options.room_id,
@@ -233,51 +309,108 @@ impl RoomMembership {
         )?;
 
         match room_membership {
-            Some(mut entry) => entry.update(connection, domain, options),
-            None => RoomMembership::create(connection, domain, options)
+            Some(mut entry) => entry.update(connection, state_cache, clock, config, options),
+            None => RoomMembership::create(connection, state_cache, clock, config, options)
+        }
+    }
+
+    /// Update an existing `RoomMembership` entry or insert a new one, regardless of the room's
+    /// join rules and power levels.
+    ///
+    /// This is reserved for server administrators, the `sender` of the options should be the
+    /// administrator acting on the membership.
+    pub fn force_upsert(
+        connection: &PgConnection,
+        state_cache: &StateCache,
+        clock: &Clock,
+        config: &Config,
+        options: RoomMembershipOptions,
+    ) -> Result<RoomMembership, ApiError> {
+        let room_membership = RoomMembership::find(
+            connection,
+            &options.room_id,
+            &options.user_id
+        )?;
+
+        match room_membership {
+            Some(mut entry) => entry.update(connection, state_cache, clock, config, options),
+            None => {
+                RoomMembership::create_unchecked(connection, state_cache, clock, config, options)
+            }
         }
     }
 
     /// Update a `RoomMembership` entry using new `RoomMembershipOptions`.
     ///
     /// After the update a new `MemberEvent` is created.
-    pub fn update(&mut self, connection: &PgConnection, homeserver_domain: &str, options: RoomMembershipOptions)
-    -> Result<RoomMembership, ApiError> {
-        let profile = Profile::find_by_uid(connection, &options.user_id)?;
+    pub fn update(
+        &mut self,
+        connection: &PgConnection,
+        state_cache: &StateCache,
+        clock: &Clock,
+        config: &Config,
+        options: RoomMembershipOptions,
+    ) -> Result<RoomMembership, ApiError> {
+        self.membership = options.membership.clone();
+        self.sender = options.sender.clone();
 
-        let event = RoomMembership::create_new_room_member_event(
-            homeserver_domain,
-            &options,
-            profile,
+        let mut memberships = RoomMembership::update_many(
+            connection,
+            state_cache,
+            clock,
+            config,
+            vec![options],
         )?;
 
-        self.membership = options.membership.clone();
-        self.sender = options.sender.clone();
+        Ok(memberships.remove(0))
+    }
+
+    /// Update several existing `RoomMembership` entries, creating a new `MemberEvent` for each.
+    ///
+    /// The events are saved in a single `EventBatch`.
+    pub fn update_many(
+        connection: &PgConnection,
+        state_cache: &StateCache,
+        clock: &Clock,
+        config: &Config,
+        options: Vec<RoomMembershipOptions>,
+    ) -> Result<Vec<RoomMembership>, ApiError> {
+        let mut batch = EventBatch::new();
+
+        for option in options {
+            let profile = Profile::find_by_uid(connection, &option.user_id)?;
+            let room_version = RoomState::current(connection, state_cache, &option.room_id)?
+                .room_version()?;
 
-        connection.transaction::<RoomMembership, ApiError, _>(|| {
-            insert(&event)
-                .into(events::table)
-                .execute(connection)
-                .map_err(ApiError::from)?;
-
-            self.save_changes::<RoomMembership>(connection)
-                .map_err(ApiError::from)?;
-
-            // Use the new `EventId` as primary key.
-            update(room_memberships::table.find(self.event_id.clone()))
-                .set(room_memberships::event_id.eq(event.id.clone()))
-                .get_result(connection)
-                .map_err(ApiError::from)
-        }).map_err(ApiError::from)
+            let event = RoomMembership::create_new_room_member_event(
+                &config.domain,
+                &room_version,
+                &option,
+                profile,
+            )?;
+
+            let updated_membership = NewRoomMembership {
+                event_id: event.id.clone(),
+                room_id: option.room_id,
+                user_id: option.user_id,
+                sender: option.sender,
+                membership: option.membership,
+            };
+
+            batch.update_membership(event, updated_membership);
+        }
+
+        batch.commit(connection, state_cache, clock, config)
     }
 
     /// Create a new `MemberEvent`.
     pub fn create_new_room_member_event(
         homeserver_domain: &str,
+        room_version: &str,
         options: &RoomMembershipOptions,
         profile: Option<Profile>
     ) -> Result<NewEvent, ApiError> {
-        let event_id = EventId::new(homeserver_domain).map_err(ApiError::from)?;
+        let event_id = generate_event_id(homeserver_domain, room_version)?;
         let membership_string = Value::String(options.membership.clone());
         let membership: MembershipState = from_value(membership_string)?;
 
@@ -298,7 +431,7 @@ impl RoomMembership {
             invite_room_state: None,
             prev_content: None,
             room_id: options.room_id.clone(),
-            state_key: format!("@{}:{}", options.user_id.clone(), &homeserver_domain),
+            state_key: options.user_id.to_string(),
             unsigned: None,
             user_id: options.user_id.clone(),
         }.try_into()?;
@@ -309,12 +442,14 @@ impl RoomMembership {
     /// Given a list of invited users create the appropriate membership entries and `m.room.member` events.
     pub fn create_memberships(
         connection: &PgConnection,
+        state_cache: &StateCache,
+        clock: &Clock,
         room: &Room,
         invite_list: &[UserId],
-        homeserver_domain: &str
+        config: &Config
     ) -> Result<(), ApiError> {
         for invitee in invite_list {
-            if invitee.hostname().to_string() != homeserver_domain {
+            if invitee.hostname().to_string() != config.domain {
                 return Err(
                     ApiError::unimplemented("Federation is not yet supported.".to_string())
                 );
@@ -343,7 +478,7 @@ impl RoomMembership {
             }
         }).collect::<Vec<RoomMembershipOptions>>();
 
-        RoomMembership::create_many(connection, homeserver_domain, options)?;
+        RoomMembership::create_many(connection, state_cache, clock, config, options)?;
 
         Ok(())
     }
@@ -365,6 +500,25 @@ impl RoomMembership {
         events.into_iter().map(TryInto::try_into).collect()
     }
 
+    /// Return the member events of the members of the room with the given membership.
+    pub fn get_events_by_room_and_state(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        membership: &str
+    ) -> Result<Vec<MemberEvent>, ApiError> {
+        let event_ids = room_memberships::table
+            .filter(room_memberships::room_id.eq(room_id))
+            .filter(room_memberships::membership.eq(membership))
+            .select(room_memberships::event_id);
+
+        let events: Vec<Event> = events::table
+            .filter(events::id.eq(any(event_ids)))
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        events.into_iter().map(TryInto::try_into).collect()
+    }
+
     /// Return all `RoomMembership`'s for given `UserId`.
     pub fn find_all_by_uid(connection: &PgConnection, user_id: &UserId) -> Result<Vec<RoomMembership>, ApiError> {
         room_memberships::table
@@ -442,4 +596,45 @@ impl RoomMembership {
             .get_results(connection)
             .map_err(ApiError::from)
     }
+
+    /// Returns up to five joined or invited members of the room other than the given user, the
+    /// most recently active first, for clients to name rooms that have no name.
+    ///
+    /// Members who never sent an event in the room come last, ordered by user ID.
+    pub fn find_heroes(connection: &PgConnection, room_id: &RoomId, user_id: &UserId)
+    -> Result<Vec<UserId>, ApiError> {
+        let members: Vec<UserId> = room_memberships::table
+            .filter(room_memberships::room_id.eq(room_id))
+            .filter(room_memberships::user_id.ne(user_id))
+            .filter(room_memberships::membership.eq(any(vec!["join", "invite"])))
+            .select(room_memberships::user_id)
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        if members.is_empty() {
+            return Ok(members);
+        }
+
+        let latest_orderings: HashMap<UserId, Option<i64>> = events::table
+            .select((events::user_id, max(events::ordering)))
+            .filter(events::room_id.eq(room_id))
+            .filter(events::user_id.eq(any(&members)))
+            .group_by(events::user_id)
+            .load::<(UserId, Option<i64>)>(connection)
+            .map_err(ApiError::from)?
+            .into_iter()
+            .collect();
+
+        let mut heroes: Vec<(Option<i64>, String, UserId)> = members.into_iter()
+            .map(|member| {
+                let latest_ordering = latest_orderings.get(&member).cloned().unwrap_or(None);
+
+                (latest_ordering, member.to_string(), member)
+            })
+            .collect();
+
+        heroes.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+        Ok(heroes.into_iter().take(5).map(|(_, _, member)| member).collect())
+    }
 }
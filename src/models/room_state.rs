@@ -0,0 +1,296 @@
+//! The current state of a room.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::Arc;
+
+use base64::encode;
+use diesel::{ExecuteDsl, ExpressionMethods, FilterDsl, LimitDsl, LoadDsl, SelectDsl};
+use diesel::{insert, update};
+use diesel::expression::dsl::{any, sql};
+use diesel::pg::PgConnection;
+use diesel::pg::upsert::{OnConflictExtension, do_update};
+use diesel::types::Text;
+use ring::digest::{SHA256, digest};
+use ruma_events::EventType;
+use ruma_events::room::create::CreateEvent;
+use ruma_events::room::history_visibility::{HistoryVisibility, HistoryVisibilityEvent};
+use ruma_events::room::join_rules::{JoinRule, JoinRulesEvent};
+use ruma_events::room::power_levels::{PowerLevelsEvent, PowerLevelsEventContent};
+use ruma_identifiers::{EventId, RoomId};
+use serde_json::{Value, from_str};
+
+use error::ApiError;
+use models::event::{Event, NewEvent};
+use room_version::DEFAULT_ROOM_VERSION;
+use schema::{events, room_current_state};
+use state_cache::StateCache;
+
+/// The type of the state event that enables end-to-end encryption in a room.
+pub const ENCRYPTION_EVENT_TYPE: &'static str = "m.room.encryption";
+
+/// The events making up the current state of a room, keyed by event type and state key.
+#[derive(Clone, Debug, Default)]
+pub struct RoomState {
+    events: Arc<HashMap<(String, String), Event>>,
+}
+
+/// A row of the `room_current_state` table, pointing at the event currently setting a piece of a
+/// room's state.
+///
+/// Every row of a room carries the room's current `etag`.
+#[derive(Debug, Insertable)]
+#[table_name = "room_current_state"]
+struct CurrentStateEntry {
+    room_id: RoomId,
+    event_type: String,
+    state_key: String,
+    event_id: EventId,
+    etag: String,
+}
+
+impl RoomState {
+    /// Records new state events as the current state of their rooms.
+    ///
+    /// This must happen in the transaction saving the events. Of several events setting the same
+    /// piece of state, the last one wins. Events without a state key are ignored. The new entries
+    /// are inserted or replace the existing ones with a single statement.
+    pub fn update_current<'a, I>(connection: &PgConnection, events: I) -> Result<(), ApiError>
+    where I: IntoIterator<Item = &'a NewEvent> {
+        let mut entries: HashMap<(RoomId, String, String), EventId> = HashMap::new();
+        let mut etags: HashMap<RoomId, String> = HashMap::new();
+
+        for event in events {
+            if let Some(ref state_key) = event.state_key {
+                let key = (event.room_id.clone(), event.event_type.clone(), state_key.clone());
+
+                entries.insert(key, event.id.clone());
+                etags.insert(event.room_id.clone(), state_etag(&event.id));
+            }
+        }
+
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let rows: Vec<CurrentStateEntry> = entries.into_iter()
+            .map(|((room_id, event_type, state_key), event_id)| CurrentStateEntry {
+                etag: etags[&room_id].clone(),
+                room_id: room_id,
+                event_type: event_type,
+                state_key: state_key,
+                event_id: event_id,
+            })
+            .collect();
+
+        let target = (
+            room_current_state::room_id,
+            room_current_state::event_type,
+            room_current_state::state_key,
+        );
+        let changes = (
+            room_current_state::event_id.eq(sql::<Text>("excluded.event_id")),
+            room_current_state::etag.eq(sql::<Text>("excluded.etag")),
+        );
+
+        insert(&rows.on_conflict(target, do_update().set(changes)))
+            .into(room_current_state::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        // The rest of the room's state gets the new etag too.
+        for (room_id, etag) in etags {
+            let outdated = room_current_state::table
+                .filter(room_current_state::room_id.eq(&room_id))
+                .filter(room_current_state::etag.ne(&etag));
+
+            update(outdated)
+                .set(room_current_state::etag.eq(&etag))
+                .execute(connection)
+                .map_err(ApiError::from)?;
+        }
+
+        Ok(())
+    }
+
+    /// The entity tag of the room's current state, which changes with every state event.
+    ///
+    /// Returns `None` if the room has no state, or if its state hasn't changed since it was
+    /// migrated from a version without entity tags.
+    pub fn etag(connection: &PgConnection, room_id: &RoomId) -> Result<Option<String>, ApiError> {
+        let etag = room_current_state::table
+            .select(room_current_state::etag)
+            .filter(room_current_state::room_id.eq(room_id))
+            .limit(1)
+            .load::<String>(connection)
+            .map_err(ApiError::from)?
+            .pop();
+
+        Ok(etag.and_then(|etag| if etag.is_empty() { None } else { Some(etag) }))
+    }
+
+    /// Returns the current state of the room, loading it from the database if it isn't cached.
+    pub fn current(connection: &PgConnection, state_cache: &StateCache, room_id: &RoomId)
+    -> Result<RoomState, ApiError> {
+        if let Some(state) = state_cache.get(room_id) {
+            return Ok(state);
+        }
+
+        let state = RoomState::load(connection, room_id)?;
+
+        state_cache.insert(room_id.clone(), state.clone());
+
+        Ok(state)
+    }
+
+    /// Loads the current state of the room from the database.
+    fn load(connection: &PgConnection, room_id: &RoomId) -> Result<RoomState, ApiError> {
+        let current_event_ids = room_current_state::table
+            .select(room_current_state::event_id)
+            .filter(room_current_state::room_id.eq(room_id));
+
+        let state_events: Vec<Event> = events::table
+            .filter(events::id.eq(any(current_event_ids)))
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        let mut events = HashMap::new();
+
+        for event in state_events {
+            let key = (event.event_type.clone(), event.state_key.clone().unwrap_or_default());
+
+            events.insert(key, event);
+        }
+
+        Ok(RoomState { events: Arc::new(events) })
+    }
+
+    /// Returns the event currently setting the given piece of state, if any.
+    pub fn get(&self, event_type: &EventType, state_key: &str) -> Option<&Event> {
+        self.events.get(&(event_type.to_string(), state_key.to_string()))
+    }
+
+    /// Returns the room's power levels.
+    ///
+    /// If the room does not have a power levels event, the defaults from the specification are
+    /// returned, in which the room's creator has a power level of 100.
+    pub fn power_levels(&self) -> Result<PowerLevelsEventContent, ApiError> {
+        if let Some(event) = self.get(&EventType::RoomPowerLevels, "") {
+            let power_levels_event: PowerLevelsEvent = event.clone().try_into()?;
+
+            return Ok(power_levels_event.content);
+        }
+
+        let mut users = HashMap::new();
+
+        if let Some(event) = self.get(&EventType::RoomCreate, "") {
+            let create_event: CreateEvent = event.clone().try_into()?;
+
+            users.insert(create_event.content.creator, 100);
+        }
+
+        Ok(PowerLevelsEventContent {
+            ban: 50,
+            events: HashMap::new(),
+            events_default: 0,
+            invite: 50,
+            kick: 50,
+            redact: 50,
+            state_default: 0,
+            users: users,
+            users_default: 0,
+        })
+    }
+
+    /// Returns the room's join rule, if it has a join rules event.
+    pub fn join_rule(&self) -> Result<Option<JoinRule>, ApiError> {
+        match self.get(&EventType::RoomJoinRules, "") {
+            Some(event) => {
+                let join_rules_event: JoinRulesEvent = event.clone().try_into()?;
+
+                Ok(Some(join_rules_event.content.join_rule))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the room's version, the `room_version` of its `m.room.create` event.
+    pub fn room_version(&self) -> Result<String, ApiError> {
+        let content: Value = match self.get(&EventType::RoomCreate, "") {
+            Some(event) => from_str(&event.content).map_err(ApiError::from)?,
+            None => return Ok(DEFAULT_ROOM_VERSION.to_string()),
+        };
+
+        let room_version = content.get("room_version").and_then(Value::as_str);
+
+        Ok(room_version.unwrap_or(DEFAULT_ROOM_VERSION).to_string())
+    }
+
+    /// Returns whether anyone may read the room's history, members or not.
+    pub fn is_world_readable(&self) -> Result<bool, ApiError> {
+        match self.get(&EventType::RoomHistoryVisibility, "") {
+            Some(event) => {
+                let history_visibility_event: HistoryVisibilityEvent = event.clone().try_into()?;
+
+                Ok(history_visibility_event.content.history_visibility ==
+                    HistoryVisibility::WorldReadable)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Returns whether end-to-end encryption is enabled in the room.
+    pub fn is_encrypted(&self) -> bool {
+        self.get(&EventType::Custom(ENCRYPTION_EVENT_TYPE.to_string()), "").is_some()
+    }
+}
+
+/// The entity tag of a room's state whose latest state event is `event_id`: the unpadded Base64
+/// SHA-256 hash of the event ID.
+fn state_etag(event_id: &EventId) -> String {
+    let hash = digest(&SHA256, event_id.to_string().as_bytes());
+
+    encode(hash.as_ref()).trim_right_matches('=').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::{ExpressionMethods, FilterDsl, LoadDsl, SelectDsl};
+    use ruma_events::EventType;
+    use ruma_identifiers::{EventId, RoomId};
+
+    use models::event::Event;
+    use schema::room_current_state;
+    use state_cache::StateCache;
+    use test::Test;
+    use super::RoomState;
+
+    #[test]
+    fn current_state_follows_latest_state_event() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room_with_params(&alice.token, r#"{"name": "Before"}"#);
+
+        test.send_state_event(&alice.token, &room_id, "m.room.name", r#"{"name": "After"}"#);
+
+        let room_id = RoomId::try_from(room_id.as_ref()).unwrap();
+        let connection = test.connection();
+
+        let name_event_ids: Vec<EventId> = room_current_state::table
+            .select(room_current_state::event_id)
+            .filter(room_current_state::room_id.eq(&room_id))
+            .filter(room_current_state::event_type.eq("m.room.name"))
+            .load(&*connection)
+            .unwrap();
+        assert_eq!(name_event_ids.len(), 1);
+
+        let name_event = Event::find(&connection, &name_event_ids[0]).unwrap().unwrap();
+        assert!(name_event.content.contains("After"));
+
+        let state = RoomState::current(&connection, &StateCache::new(0), &room_id).unwrap();
+        let cached_name_event = state.get(&EventType::RoomName, "").unwrap();
+        assert_eq!(cached_name_event.id, name_event.id);
+    }
+}
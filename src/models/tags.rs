@@ -83,6 +83,25 @@ impl RoomTag {
         Ok(map)
     }
 
+    /// Return the `RoomTag`'s of every room for the given `UserId`, grouped by room.
+    pub fn find_by_user(
+        connection: &PgConnection,
+        user_id: UserId,
+    ) -> Result<HashMap<RoomId, HashMap<String, TagInfo>>, ApiError> {
+        let tags: Vec<RoomTag> = room_tags::table
+            .filter(room_tags::user_id.eq(user_id))
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        let mut map: HashMap<RoomId, HashMap<String, TagInfo>> = HashMap::new();
+        for tag in tags {
+            let info = from_str(&tag.content).map_err(ApiError::from)?;
+            map.entry(tag.room_id).or_insert_with(HashMap::new).insert(tag.tag, info);
+        }
+
+        Ok(map)
+    }
+
     /// Return `RoomTag` for given `UserId`, `RoomId` and `tag`.
     pub fn first(
         connection: &PgConnection,
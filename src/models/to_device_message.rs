@@ -0,0 +1,119 @@
+//! Messages sent directly to the devices of users.
+
+use std::collections::BTreeSet;
+use std::time::SystemTime;
+
+use diesel::{ExecuteDsl, ExpressionMethods, FilterDsl, LoadDsl, OrderDsl, SelectDsl, insert};
+use diesel::pg::PgConnection;
+use ruma_identifiers::UserId;
+
+use error::ApiError;
+use schema::{access_tokens, to_device_messages};
+
+/// A message waiting to be delivered to a device.
+#[derive(Clone, Debug, Queryable)]
+pub struct ToDeviceMessage {
+    /// The ID of the message, in the order the messages were sent.
+    pub id: i64,
+    /// The user the message is for.
+    pub user_id: UserId,
+    /// The device of the user the message is for.
+    pub device_id: String,
+    /// The user who sent the message.
+    pub sender: UserId,
+    /// The type of the message, e.g. `m.room_key_request`.
+    pub event_type: String,
+    /// The JSON content of the message.
+    pub content: String,
+    /// The time the message was sent.
+    pub created_at: SystemTime,
+}
+
+/// A message to a device, not saved yet.
+#[derive(Debug, Insertable)]
+#[table_name = "to_device_messages"]
+struct NewToDeviceMessage {
+    user_id: UserId,
+    device_id: String,
+    sender: UserId,
+    event_type: String,
+    content: String,
+}
+
+impl ToDeviceMessage {
+    /// Queues a message for one device of a user.
+    pub fn create(
+        connection: &PgConnection,
+        sender: &UserId,
+        event_type: &str,
+        user_id: &UserId,
+        device_id: &str,
+        content: &str,
+    ) -> Result<(), ApiError> {
+        let message = NewToDeviceMessage {
+            user_id: user_id.clone(),
+            device_id: device_id.to_string(),
+            sender: sender.clone(),
+            event_type: event_type.to_string(),
+            content: content.to_string(),
+        };
+
+        insert(&message)
+            .into(to_device_messages::table)
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Queues a message for every device of a user, i.e. every device with an access token that
+    /// hasn't been revoked.
+    ///
+    /// The messages are inserted with a single statement, however many devices the user has.
+    /// Returns the number of devices the message was queued for.
+    pub fn create_for_all_devices(
+        connection: &PgConnection,
+        sender: &UserId,
+        event_type: &str,
+        user_id: &UserId,
+        content: &str,
+    ) -> Result<usize, ApiError> {
+        let device_ids: BTreeSet<String> = access_tokens::table
+            .filter(access_tokens::user_id.eq(user_id))
+            .filter(access_tokens::revoked.eq(false))
+            .select(access_tokens::device_id)
+            .load::<String>(connection)
+            .map_err(ApiError::from)?
+            .into_iter()
+            .collect();
+
+        if device_ids.is_empty() {
+            return Ok(0);
+        }
+
+        let messages: Vec<NewToDeviceMessage> = device_ids.into_iter()
+            .map(|device_id| NewToDeviceMessage {
+                user_id: user_id.clone(),
+                device_id: device_id,
+                sender: sender.clone(),
+                event_type: event_type.to_string(),
+                content: content.to_string(),
+            })
+            .collect();
+
+        insert(&messages)
+            .into(to_device_messages::table)
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Returns the messages queued for a device, oldest first.
+    pub fn find_by_device(connection: &PgConnection, user_id: &UserId, device_id: &str)
+    -> Result<Vec<ToDeviceMessage>, ApiError> {
+        to_device_messages::table
+            .filter(to_device_messages::user_id.eq(user_id))
+            .filter(to_device_messages::device_id.eq(device_id))
+            .order(to_device_messages::id.asc())
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+}
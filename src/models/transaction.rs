@@ -1,19 +1,31 @@
 //! Matrix transaction.
 
+use std::time::{Duration, SystemTime};
+
 use diesel::{
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
     FindDsl,
     LoadDsl,
+    delete,
     insert,
+    update,
 };
 use diesel::pg::PgConnection;
-use diesel::result::Error as DieselError;
+use diesel::pg::upsert::OnConflictExtension;
 
+use clock::Clock;
 use error::ApiError;
 use schema::transactions;
 
+/// The number of seconds a transaction is remembered. The same transaction ID can be used again
+/// afterwards.
+pub const TRANSACTION_TTL: u64 = 24 * 60 * 60;
+
 /// A Transaction.
-#[derive(AsChangeset, Clone, Debug, Identifiable, Insertable, Queryable)]
-#[primary_key(path, access_token)]
+#[derive(Clone, Debug, Identifiable, Queryable)]
+#[primary_key(method, path, access_token)]
 #[table_name = "transactions"]
 pub struct Transaction {
     /// The full path of the endpoint used for the transaction.
@@ -22,43 +34,96 @@ pub struct Transaction {
     pub access_token: String,
     /// The serialized response of the endpoint. It should be used
     /// as the response on future requests.
-    pub response: String,
+    ///
+    /// It is only missing while the transaction that reserved it hasn't been committed.
+    pub response: Option<String>,
+    /// The time the transaction was made.
+    pub created_at: SystemTime,
+    /// The HTTP method of the request.
+    pub method: String,
+}
+
+/// A new transaction, reserved before its response is known.
+#[derive(Debug, Insertable)]
+#[table_name = "transactions"]
+struct NewTransaction<'a> {
+    method: &'a str,
+    path: &'a str,
+    access_token: &'a str,
+    created_at: SystemTime,
 }
 
 impl Transaction {
-    /// Create a new transaction entry.
-    pub fn create(
+    /// Reserves a transaction, or returns the saved response if it has been made before.
+    ///
+    /// The reservation is a row without a response, which `complete` fills in. It must be made in
+    /// the same database transaction as the changes of the request, so that they are committed
+    /// or rolled back together. A concurrent request for the same transaction waits for that
+    /// database transaction to end, and then gets the saved response or makes the reservation
+    /// itself.
+    ///
+    /// Expired transactions of the access token are deleted, so that their IDs can be reused. The
+    /// age of transactions is measured with the clock.
+    pub fn reserve(
         connection: &PgConnection,
-        path: String,
-        access_token: String,
-        response: String
-    ) -> Result<Transaction, ApiError> {
-        let new_transaction = Transaction {
+        clock: &Clock,
+        method: &str,
+        path: &str,
+        access_token: &str,
+    ) -> Result<Option<String>, ApiError> {
+        let expired = transactions::table
+            .filter(transactions::access_token.eq(access_token))
+            .filter(transactions::created_at.lt(Transaction::expired_before(clock)));
+
+        delete(expired)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        let new_transaction = NewTransaction {
+            method: method,
             path: path,
             access_token: access_token,
-            response: response,
+            created_at: clock.now(),
         };
 
-        insert(&new_transaction)
+        let reserved = insert(&new_transaction.on_conflict_do_nothing())
             .into(transactions::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        if reserved == 1 {
+            return Ok(None);
+        }
+
+        let transaction: Transaction = transactions::table
+            .find((method, path, access_token))
             .get_result(connection)
-            .map_err(ApiError::from)
+            .map_err(ApiError::from)?;
+
+        match transaction.response {
+            Some(response) => Ok(Some(response)),
+            None => Err(ApiError::unknown(None)),
+        }
     }
 
-    /// Look up a transaction with the url path of the endpoint and the access token.
-    pub fn find(
+    /// Saves the response of a transaction reserved with `reserve`.
+    pub fn complete(
         connection: &PgConnection,
+        method: &str,
         path: &str,
-        access_token: &str
-    ) -> Result<Option<Transaction>, ApiError> {
-        let transaction = transactions::table
-            .find((path, access_token))
-            .get_result(connection);
-
-        match transaction {
-            Ok(transaction) => Ok(Some(transaction)),
-            Err(DieselError::NotFound) => Ok(None),
-            Err(err) => Err(ApiError::from(err)),
-        }
+        access_token: &str,
+        response: &str,
+    ) -> Result<(), ApiError> {
+        update(transactions::table.find((method, path, access_token)))
+            .set(transactions::response.eq(Some(response)))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Transactions made before this time are expired.
+    fn expired_before(clock: &Clock) -> SystemTime {
+        clock.now() - Duration::from_secs(TRANSACTION_TTL)
     }
 }
@@ -12,10 +12,11 @@ use diesel::{
     SaveChangesDsl,
     SelectDsl,
 };
-use diesel::expression::dsl::any;
+use diesel::expression::dsl::{any, sql};
 use diesel::pg::PgConnection;
 use diesel::pg::data_types::PgTimestamp;
 use diesel::result::Error as DieselError;
+use diesel::types::Text;
 use iron::typemap::Key;
 use ruma_identifiers::UserId;
 
@@ -24,6 +25,8 @@ use error::ApiError;
 use models::access_token::AccessToken;
 use schema::users;
 
+sql_function!(lower, lower_t, (text: Text) -> Text);
+
 /// A Matrix user.
 #[derive(AsChangeset, Debug, Clone, Identifiable, Queryable)]
 #[table_name = "users"]
@@ -38,6 +41,8 @@ pub struct User {
     pub created_at: PgTimestamp,
     /// The time the user was last modified.
     pub updated_at: PgTimestamp,
+    /// Whether or not the user can use the admin APIs.
+    pub admin: bool,
 }
 
 /// A new Matrix user, not yet saved.
@@ -48,6 +53,8 @@ pub struct NewUser {
     pub id: UserId,
     /// The user's hashed password.
     pub password_hash: String,
+    /// Whether or not the user can use the admin APIs.
+    pub admin: bool,
 }
 
 impl User {
@@ -63,12 +70,21 @@ impl User {
                 .get_result(connection)
                 .map_err(ApiError::from)?;
 
-            let access_token = AccessToken::create(connection, &user.id, macaroon_secret_key)?;
+            let access_token = AccessToken::create(connection, &user.id, None, macaroon_secret_key)?;
 
             Ok((user, access_token))
         }).map_err(ApiError::from)
     }
 
+    /// Creates a new user in the database without an access token, for users who log in later.
+    pub fn create_without_access_token(connection: &PgConnection, new_user: &NewUser)
+    -> Result<User, ApiError> {
+        insert(new_user)
+            .into(users::table)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Verify that a `User` with the given `UserId` and plaintext password exists.
     pub fn verify(
         connection: &PgConnection,
@@ -103,6 +119,50 @@ impl User {
         }
     }
 
+    /// Look up a registered `User` whose `UserId` only differs from the given one in case.
+    ///
+    /// Localparts are lowercase, but users registered before they were normalized may not be.
+    pub fn find_registered_user_ignoring_case(connection: &PgConnection, id: &UserId)
+    -> Result<Option<User>, ApiError> {
+        let users: Vec<User> = users::table
+            .filter(lower(users::id).eq(id.to_string().to_lowercase()))
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(users.into_iter().next())
+    }
+
+    /// Makes sure user IDs are unique regardless of case, as enforced by the
+    /// `users_lower_id_key` index.
+    ///
+    /// The index can't be created while users differ only in the case of their IDs, so these are
+    /// logged for an administrator to resolve. Once there are none left, the index is created.
+    pub fn check_case_conflicts(connection: &PgConnection) -> Result<(), ApiError> {
+        let query = "SELECT string_agg(id, ', ' ORDER BY id) FROM users GROUP BY lower(id) \
+            HAVING count(*) > 1";
+        let conflicts: Vec<String> = sql::<Text>(query)
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        if conflicts.is_empty() {
+            let query = "CREATE UNIQUE INDEX IF NOT EXISTS users_lower_id_key ON users (lower(id))";
+
+            connection.execute(query).map_err(ApiError::from)?;
+
+            return Ok(());
+        }
+
+        for conflict in conflicts {
+            error!(
+                "The user IDs {} only differ in case. User IDs can't be made unique regardless \
+                of case until all but one of them are removed.",
+                conflict
+            );
+        }
+
+        Ok(())
+    }
+
     /// Look up an active `User` using the given `UserId`.
     ///
     /// A user stops being active when he deactivates his account.
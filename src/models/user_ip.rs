@@ -0,0 +1,106 @@
+//! The IP addresses and user agents access tokens are used from.
+
+use std::time::{Duration, SystemTime};
+
+use diesel::{ExecuteDsl, ExpressionMethods, FilterDsl, FindDsl, LoadDsl, OrderDsl, SelectDsl};
+use diesel::{insert, update};
+use diesel::expression::dsl::any;
+use diesel::pg::PgConnection;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use ruma_identifiers::UserId;
+
+use error::ApiError;
+use models::access_token::AccessToken;
+use schema::user_ips;
+
+/// The minimum number of seconds between two updates of the time a client was last seen.
+///
+/// This keeps busy clients from causing a write on every request.
+const LAST_SEEN_UPDATE_INTERVAL_SECS: u64 = 60;
+
+/// A new client of an access token, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "user_ips"]
+struct NewUserIp {
+    access_token_id: i64,
+    user_id: UserId,
+    device_id: String,
+    ip: String,
+    user_agent: String,
+    last_seen: SystemTime,
+}
+
+/// An IP address and user agent an access token was used from.
+#[derive(Clone, Debug, Queryable)]
+pub struct UserIp {
+    /// The ID of the access token.
+    pub access_token_id: i64,
+    /// The user who owns the access token.
+    pub user_id: UserId,
+    /// The device the access token was issued to.
+    pub device_id: String,
+    /// The IP address of the client.
+    pub ip: String,
+    /// The `User-Agent` header of the client, or an empty string if it didn't send one.
+    pub user_agent: String,
+    /// The last time the client used the access token, give or take a minute.
+    pub last_seen: SystemTime,
+}
+
+impl UserIp {
+    /// Records that an access token was used from the given IP address and user agent.
+    pub fn record(connection: &PgConnection, access_token: &AccessToken, ip: &str, user_agent: &str)
+    -> Result<(), ApiError> {
+        let now = SystemTime::now();
+        let client = user_ips::table.find((access_token.id, ip, user_agent));
+
+        let last_seen = client
+            .select(user_ips::last_seen)
+            .load::<SystemTime>(connection)
+            .map_err(ApiError::from)?
+            .pop();
+
+        match last_seen {
+            Some(last_seen) => {
+                let interval = Duration::from_secs(LAST_SEEN_UPDATE_INTERVAL_SECS);
+
+                if now.duration_since(last_seen).map(|elapsed| elapsed >= interval).unwrap_or(false) {
+                    update(client)
+                        .set(user_ips::last_seen.eq(now))
+                        .execute(connection)
+                        .map_err(ApiError::from)?;
+                }
+
+                Ok(())
+            }
+            None => {
+                let new_user_ip = NewUserIp {
+                    access_token_id: access_token.id,
+                    user_id: access_token.user_id.clone(),
+                    device_id: access_token.device_id.clone(),
+                    ip: ip.to_string(),
+                    user_agent: user_agent.to_string(),
+                    last_seen: now,
+                };
+
+                match insert(&new_user_ip).into(user_ips::table).execute(connection) {
+                    // A concurrent request from the same client got there first.
+                    Ok(_) | Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Ok(()),
+                    Err(error) => Err(ApiError::from(error)),
+                }
+            }
+        }
+    }
+
+    /// Returns the clients of the given access tokens, most recently seen first.
+    pub fn find_by_access_tokens(connection: &PgConnection, access_tokens: &[AccessToken])
+    -> Result<Vec<UserIp>, ApiError> {
+        let access_token_ids: Vec<i64> = access_tokens.iter().map(|token| token.id).collect();
+
+        user_ips::table
+            .filter(user_ips::access_token_id.eq(any(access_token_ids)))
+            .order(user_ips::last_seen.desc())
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+}
@@ -0,0 +1,76 @@
+//! Third-party identifiers, such as email addresses, associated with users.
+//!
+//! Third-party identifiers are personal information. They are only ever shown to the user they
+//! belong to and must not be exposed through the admin API.
+
+use std::time::SystemTime;
+
+use diesel::{ExpressionMethods, FilterDsl, FindDsl, LoadDsl, OrderDsl, insert, update};
+use diesel::pg::PgConnection;
+use ruma_identifiers::UserId;
+
+use error::ApiError;
+use schema::user_threepids;
+
+/// A new third-party identifier, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "user_threepids"]
+pub struct NewUserThreepid {
+    /// The medium of the identifier, e.g. *email*.
+    pub medium: String,
+    /// The identifier itself, e.g. an email address.
+    pub address: String,
+    /// The user the identifier belongs to.
+    pub user_id: UserId,
+}
+
+/// A third-party identifier associated with a user.
+#[derive(Clone, Debug, Queryable)]
+pub struct UserThreepid {
+    /// The medium of the identifier, e.g. *email*.
+    pub medium: String,
+    /// The identifier itself, e.g. an email address.
+    pub address: String,
+    /// The user the identifier belongs to.
+    pub user_id: UserId,
+    /// The time the identity server confirmed the binding, if it has.
+    pub validated_at: Option<SystemTime>,
+    /// The time the identifier was added.
+    pub added_at: SystemTime,
+}
+
+impl UserThreepid {
+    /// Associates a new third-party identifier with a user.
+    pub fn create(connection: &PgConnection, new_threepid: &NewUserThreepid)
+    -> Result<UserThreepid, ApiError> {
+        insert(new_threepid)
+            .into(user_threepids::table)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Returns the third-party identifiers of a user, oldest first.
+    ///
+    /// Identifiers added at the same time are ordered by their medium and address, so the order
+    /// is the same every time.
+    pub fn find_by_uid(connection: &PgConnection, user_id: &UserId)
+    -> Result<Vec<UserThreepid>, ApiError> {
+        user_threepids::table
+            .filter(user_threepids::user_id.eq(user_id))
+            .order((
+                user_threepids::added_at.asc(),
+                user_threepids::medium.asc(),
+                user_threepids::address.asc(),
+            ))
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Records that the identity server confirmed the binding of the identifier.
+    pub fn validate(&self, connection: &PgConnection) -> Result<UserThreepid, ApiError> {
+        update(user_threepids::table.find((&self.medium, &self.address)))
+            .set(user_threepids::validated_at.eq(Some(SystemTime::now())))
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+}
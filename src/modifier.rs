@@ -1,21 +1,62 @@
 //! Iron modifiers.
 
+use std::io::{BufWriter, Error as IoError, ErrorKind, Result as IoResult, Write};
+
 use iron::Response;
-use iron::headers::ContentType;
+use iron::headers::{ContentLength, ContentType};
 use iron::modifier::Modifier;
+use iron::response::WriteBody;
 use iron::status::Status;
 use serde::Serialize;
-use serde_json::to_string;
+use serde_json::{Serializer, to_string};
 
 /// Set the response's Content-Type header to "application/json" and set its body to the `T`
 /// serialized to JSON.
+///
+/// The Content-Length header is set explicitly from the size of the serialized body, since Iron
+/// only computes it for some body types and some proxies wait for the connection to close
+/// without it.
 #[derive(Clone, Debug)]
 pub struct SerializableResponse<T: Serialize>(pub T);
 
 impl<T> Modifier<Response> for SerializableResponse<T> where T: Serialize {
+    fn modify(self, response: &mut Response) {
+        let body = to_string(&self.0).expect("could not serialize response data");
+
+        set_json_body(response, body);
+    }
+}
+
+/// Set the response's Content-Type header to "application/json" and stream the `T` serialized to
+/// JSON as its body.
+///
+/// Unlike `SerializableResponse`, the JSON is written to the connection while it is serialized
+/// instead of being built in memory first, which matters for large bodies like initial syncs.
+/// As the size of the body isn't known up front, there is no Content-Length header and the body
+/// is sent with chunked transfer encoding.
+#[derive(Clone, Debug)]
+pub struct StreamingJsonSerializer<T: Serialize + Send + 'static>(pub T);
+
+impl<T> Modifier<Response> for StreamingJsonSerializer<T> where T: Serialize + Send + 'static {
     fn modify(self, response: &mut Response) {
         response.headers.set(ContentType::json());
-        response.body = Some(Box::new(to_string(&self.0).expect("could not serialize response data")));
+        response.headers.remove::<ContentLength>();
+        response.body = Some(Box::new(self));
+    }
+}
+
+impl<T> WriteBody for StreamingJsonSerializer<T> where T: Serialize + Send + 'static {
+    fn write_body(&mut self, body: &mut Write) -> IoResult<()> {
+        // Serializers write many small pieces, each of which would otherwise become a chunk.
+        let mut serializer = Serializer::new(BufWriter::new(body));
+
+        if let Err(error) = self.0.serialize(&mut serializer) {
+            error!("Failed to stream a response body: {}", error);
+
+            return Err(IoError::new(ErrorKind::Other, error));
+        }
+
+        serializer.into_inner().flush()
     }
 }
 
@@ -25,8 +66,59 @@ pub struct EmptyResponse(pub Status);
 
 impl Modifier<Response> for EmptyResponse {
     fn modify(self, response: &mut Response) {
-        response.headers.set(ContentType::json());
-        response.body = Some(Box::new("{}"));
+        set_json_body(response, "{}".to_string());
         response.status = Some(self.0);
     }
 }
+
+/// Sets the JSON Content-Type and Content-Length headers and the body of the response.
+pub fn set_json_body(response: &mut Response, body: String) {
+    response.headers.set(ContentType::json());
+    response.headers.set(ContentLength(body.len() as u64));
+    response.body = Some(Box::new(body));
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::Response;
+    use iron::headers::{ContentLength, ContentType};
+    use iron::modifier::Modifier;
+    use iron::status::Status;
+
+    use iron::response::WriteBody;
+
+    use super::{EmptyResponse, SerializableResponse, StreamingJsonSerializer};
+
+    #[test]
+    fn serializable_response_sets_content_length() {
+        let mut response = Response::new();
+        SerializableResponse(vec!["é", "b"]).modify(&mut response);
+
+        assert_eq!(response.headers.get::<ContentType>().unwrap(), &ContentType::json());
+        // The length is in bytes, not characters.
+        assert_eq!(response.headers.get::<ContentLength>().unwrap(), &ContentLength(10));
+    }
+
+    #[test]
+    fn streaming_json_serializer_writes_the_same_json() {
+        let mut response = Response::new();
+        response.headers.set(ContentLength(3));
+        StreamingJsonSerializer(vec!["é", "b"]).modify(&mut response);
+
+        assert_eq!(response.headers.get::<ContentType>().unwrap(), &ContentType::json());
+        assert!(response.headers.get::<ContentLength>().is_none());
+
+        let mut body = Vec::new();
+        response.body.take().unwrap().write_body(&mut body).unwrap();
+
+        assert_eq!(String::from_utf8(body).unwrap(), r#"["é","b"]"#);
+    }
+
+    #[test]
+    fn empty_response_sets_content_length() {
+        let mut response = Response::new();
+        EmptyResponse(Status::Ok).modify(&mut response);
+
+        assert_eq!(response.headers.get::<ContentLength>().unwrap(), &ContentLength(2));
+    }
+}
@@ -0,0 +1,187 @@
+//! Wake-ups for requests that are waiting on new data, such as long-polling syncs.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use std::usize;
+
+use iron::{AfterMiddleware, IronResult, Plugin, Request, Response};
+use iron::method::Method;
+use iron::typemap::Key;
+use persistent::Read as PersistentRead;
+
+use error::ApiError;
+
+/// Wakes up parked requests when something they may be waiting for has changed.
+///
+/// As an after middleware, it notifies all waiters whenever a request that is not a GET
+/// completes successfully, since such a request may have changed what a sync would return.
+///
+/// Waiting requests occupy a server thread each, so the number of them can be bounded: a request
+/// has to reserve a `WaitSlot` before it waits.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    inner: Arc<(Mutex<u64>, Condvar)>,
+    waiters: Arc<AtomicUsize>,
+    max_waiters: usize,
+}
+
+/// Permission for one request to wait on a `Notifier`, given back when dropped.
+#[derive(Debug)]
+pub struct WaitSlot {
+    waiters: Arc<AtomicUsize>,
+}
+
+impl Notifier {
+    /// Creates a new `Notifier` with no waiters and no limit on their number.
+    pub fn new() -> Self {
+        Notifier::with_max_waiters(usize::MAX)
+    }
+
+    /// Creates a new `Notifier` that lets at most `max_waiters` requests wait at once.
+    pub fn with_max_waiters(max_waiters: usize) -> Self {
+        Notifier {
+            inner: Arc::new((Mutex::new(0), Condvar::new())),
+            waiters: Arc::new(AtomicUsize::new(0)),
+            max_waiters: max_waiters,
+        }
+    }
+
+    /// Reserves a slot for waiting, or returns `None` if all slots are taken.
+    pub fn reserve_wait_slot(&self) -> Option<WaitSlot> {
+        if self.waiters.fetch_add(1, Ordering::SeqCst) >= self.max_waiters {
+            self.waiters.fetch_sub(1, Ordering::SeqCst);
+
+            return None;
+        }
+
+        Some(WaitSlot { waiters: self.waiters.clone() })
+    }
+
+    /// The number of wait slots currently reserved.
+    pub fn waiting(&self) -> usize {
+        self.waiters.load(Ordering::SeqCst)
+    }
+
+    /// Wakes up every request currently waiting on the notifier.
+    pub fn notify_all(&self) {
+        let &(ref generation, ref condvar) = &*self.inner;
+
+        match generation.lock() {
+            Ok(mut generation) => *generation = generation.wrapping_add(1),
+            Err(poisoned) => {
+                let mut generation = poisoned.into_inner();
+                *generation = generation.wrapping_add(1);
+            }
+        }
+
+        condvar.notify_all();
+    }
+
+    /// Blocks the current thread until the notifier is notified or the deadline passes.
+    ///
+    /// Returns `true` if the thread was woken up by a notification.
+    pub fn wait_until(&self, deadline: Instant) -> bool {
+        let &(ref generation, ref condvar) = &*self.inner;
+
+        let mut current = match generation.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let start = *current;
+
+        while *current == start {
+            let now = Instant::now();
+
+            if now >= deadline {
+                return false;
+            }
+
+            current = match condvar.wait_timeout(current, deadline - now) {
+                Ok((guard, _)) => guard,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+
+        true
+    }
+
+    /// Extract the `Notifier` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<Notifier>, ApiError> {
+        request.get::<PersistentRead<Notifier>>().map_err(ApiError::from)
+    }
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Notifier::new()
+    }
+}
+
+impl Drop for WaitSlot {
+    fn drop(&mut self) {
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Key for Notifier {
+    type Value = Notifier;
+}
+
+impl AfterMiddleware for Notifier {
+    fn after(&self, request: &mut Request, response: Response) -> IronResult<Response> {
+        if request.method != Method::Get {
+            self.notify_all();
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::Notifier;
+
+    #[test]
+    fn wait_until_times_out_without_notification() {
+        let notifier = Notifier::new();
+
+        assert!(!notifier.wait_until(Instant::now() + Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn notify_all_wakes_waiters() {
+        let notifier = Notifier::new();
+        let waiter = notifier.clone();
+
+        let handle = thread::spawn(move || {
+            waiter.wait_until(Instant::now() + Duration::from_secs(30))
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        notifier.notify_all();
+
+        assert!(handle.join().unwrap());
+    }
+
+    #[test]
+    fn wait_slots_are_bounded() {
+        let notifier = Notifier::with_max_waiters(2);
+
+        let first = notifier.reserve_wait_slot();
+        let second = notifier.reserve_wait_slot();
+
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(notifier.reserve_wait_slot().is_none());
+        assert_eq!(notifier.waiting(), 2);
+
+        drop(first);
+
+        assert_eq!(notifier.waiting(), 1);
+        assert!(notifier.reserve_wait_slot().is_some());
+    }
+}
@@ -14,16 +14,26 @@ use ruma_events::stripped::StrippedState;
 use ruma_events::collections::all::{RoomEvent, StateEvent};
 use ruma_events::presence::PresenceEvent;
 use ruma_events::presence::PresenceState;
-use ruma_identifiers::RoomId;
-use serde_json::Value;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Value, from_str};
 
+use clock::Clock;
 use error::ApiError;
 use models::event::Event;
 use models::filter::{ContentFilter, RoomEventFilter, RoomFilter};
+use models::room_member_counts::RoomMemberCounts;
 use models::room_membership::RoomMembership;
 use models::presence_list::PresenceList;
 use models::presence_status::PresenceStatus;
 use models::user::User;
+use unstable_features::UnstableFeatures;
+
+/// Registers the unstable sync features.
+///
+/// Lazy loading of room members is not implemented yet, so it can't be enabled.
+pub fn register_unstable_features(unstable_features: &mut UnstableFeatures) {
+    unstable_features.register_unimplemented("m.lazy_load_members");
+}
 
 /// Counts of unread notifications for a room.
 #[derive(Debug, Clone, Serialize)]
@@ -64,8 +74,24 @@ struct InvitedRoom {
     invite_state: Events<StrippedState>,
 }
 
+/// Information about a room that clients need to display it.
+#[derive(Debug, Clone, Serialize)]
+struct RoomSummary {
+    /// Members to name the room after. Only set if the room has no name or canonical alias.
+    #[serde(rename = "m.heroes", skip_serializing_if = "Vec::is_empty")]
+    heroes: Vec<UserId>,
+    /// The number of members whose membership is `join`.
+    #[serde(rename = "m.joined_member_count")]
+    joined_member_count: i64,
+    /// The number of members whose membership is `invite`.
+    #[serde(rename = "m.invited_member_count")]
+    invited_member_count: i64,
+}
+
 #[derive(Debug, Clone, Serialize)]
 struct JoinedRoom {
+    /// Information about the room that clients need to display it.
+    summary: RoomSummary,
     /// Counts of unread notifications for this room.
     unread_notifications: UnreadNotificationCounts,
     /// The timeline of messages and state changes in the room.
@@ -174,11 +200,23 @@ pub enum Context<'a> {
 }
 
 impl Sync {
+    /// Whether or not the sync contains no updates for the user.
+    pub fn is_empty(&self) -> bool {
+        self.presence.events.is_empty() &&
+            self.rooms.invite.is_empty() &&
+            self.rooms.join.is_empty() &&
+            self.rooms.leave.is_empty()
+    }
+
     /// Query sync.
+    ///
+    /// Room events are read up to `stable_ordering`, see `Event::stable_ordering`.
     pub fn sync(
         connection: &PgConnection,
+        clock: &Clock,
         homeserver_domain: &str,
         user: &User,
+        stable_ordering: i64,
         options: SyncOptions
     ) -> Result<Sync, ApiError> {
         let mut context = Context::Initial;
@@ -198,13 +236,20 @@ impl Sync {
 
         let (presence_key, presence) = Sync::get_presence_events(
             connection,
+            clock,
             homeserver_domain,
             user,
             options.set_presence,
             &context
         )?;
 
-        let (room_key, rooms) = Sync::get_rooms_events(connection, user, filter_room, &context)?;
+        let (room_key, rooms) = Sync::get_rooms_events(
+            connection,
+            user,
+            filter_room,
+            stable_ordering,
+            &context,
+        )?;
         let batch = Batch::new(room_key, presence_key);
         let state = Sync {
             next_batch: batch.to_string(),
@@ -220,6 +265,7 @@ impl Sync {
     /// Return presence events for sync from database and options.
     fn get_presence_events(
         connection: &PgConnection,
+        clock: &Clock,
         homeserver_domain: &str,
         user: &User,
         set_presence: Option<PresenceState>,
@@ -230,7 +276,14 @@ impl Sync {
             None => PresenceState::Online,
         };
 
-        PresenceStatus::upsert(connection, homeserver_domain, &user.id, Some(set_presence), None)?;
+        PresenceStatus::upsert(
+            connection,
+            clock,
+            homeserver_domain,
+            &user.id,
+            Some(set_presence),
+            None,
+        )?;
 
         let since = match *context {
             Context::Incremental(batch) | Context::FullState(batch)  => {
@@ -241,6 +294,7 @@ impl Sync {
 
         PresenceList::find_events_by_uid(
             connection,
+            clock,
             &user.id,
             since
         )
@@ -251,6 +305,7 @@ impl Sync {
         connection: &PgConnection,
         user: &User,
         room_filter: Option<RoomFilter>,
+        stable_ordering: i64,
         context: &Context,
     ) -> Result<(i64, Rooms), ApiError> {
         let mut join = HashMap::new();
@@ -275,10 +330,21 @@ impl Sync {
             None => (None, false),
         };
 
+        let timeline_limit = match timeline_filter {
+            Some(ref filter) if filter.limit > 0 => Some(filter.limit as i64),
+            _ => None,
+        };
+
         for room_membership in room_memberships {
             match room_membership.membership.as_str() {
                 "join" => {
-                    let events: Vec<Event> = Event::find_room_events(connection, &room_membership.room_id, since)?;
+                    let (events, limited) = Event::find_latest_room_events(
+                        connection,
+                        &room_membership.room_id,
+                        since,
+                        Some(stable_ordering + 1),
+                        timeline_limit,
+                    )?;
 
                     let room_state_events: Vec<Event> = if is_full_state {
                         Event::get_room_full_state(connection, &room_membership.room_id)?
@@ -290,14 +356,17 @@ impl Sync {
                         continue;
                     }
 
-                    let (ordering, timeline) = Sync::convert_events_to_timeline(events, &timeline_filter)?;
+                    let (ordering, timeline) = Sync::convert_events_to_timeline(events, limited)?;
                     room_ordering = cmp::max(ordering, room_ordering);
 
                     let state_events: Vec<StateEvent> = room_state_events.iter().cloned()
                         .map(|e| e.try_into())
                         .collect::<Result<Vec<StateEvent>, ApiError>>()?;
 
+                    let summary = Sync::room_summary(connection, &room_membership.room_id, &user.id)?;
+
                     join.insert(room_membership.room_id, JoinedRoom {
+                        summary: summary,
                         unread_notifications: UnreadNotificationCounts {
                             highlight_count: 0,
                             notification_count: 0,
@@ -335,13 +404,15 @@ impl Sync {
                     let last_event = Event::find(&connection, &room_membership.event_id)?
                         .expect("A room membership should be associated with an event");
 
-                    let events = Event::find_room_events_until(
+                    let (events, limited) = Event::find_latest_room_events(
                         connection,
                         &room_membership.room_id,
-                        &last_event.ordering,
+                        -1,
+                        Some(last_event.ordering),
+                        timeline_limit,
                     )?;
 
-                    let (ordering, timeline) = Sync::convert_events_to_timeline(events, &timeline_filter)?;
+                    let (ordering, timeline) = Sync::convert_events_to_timeline(events, limited)?;
                     room_ordering = cmp::max(ordering, room_ordering);
 
                     let room_state_events = Event::get_room_state_events_until(
@@ -371,62 +442,60 @@ impl Sync {
         }))
     }
 
+    /// Builds the summary of a joined room for the given user.
+    fn room_summary(connection: &PgConnection, room_id: &RoomId, user_id: &UserId)
+    -> Result<RoomSummary, ApiError> {
+        let counts = RoomMemberCounts::find(connection, room_id)?;
+
+        let has_state = |event_type: EventType, key: &str| -> Result<bool, ApiError> {
+            let event_type = event_type.to_string();
+            let event = Event::find_current_state_event(connection, room_id, &event_type, "")?;
+
+            Ok(event
+                .and_then(|event| from_str::<Value>(&event.content).ok())
+                .and_then(|content| content.get(key).and_then(Value::as_str).map(str::to_string))
+                .map_or(false, |value| !value.is_empty()))
+        };
+
+        let is_named = has_state(EventType::RoomName, "name")? ||
+            has_state(EventType::RoomCanonicalAlias, "alias")?;
+
+        let heroes = if is_named {
+            Vec::new()
+        } else {
+            RoomMembership::find_heroes(connection, room_id, user_id)?
+        };
+
+        Ok(RoomSummary {
+            heroes: heroes,
+            joined_member_count: counts.joined,
+            invited_member_count: counts.invited,
+        })
+    }
+
     /// Converting events in the correct format for timeline.
     ///
     /// Also returns the max ordering from the given events that will be used
     /// as the `next_batch` token.
-    fn convert_events_to_timeline(
-        events: Vec<Event>,
-        timeline_filter: &Option<RoomEventFilter>
-    ) -> Result<(i64, Timeline), ApiError> {
+    fn convert_events_to_timeline(events: Vec<Event>, limited: bool)
+    -> Result<(i64, Timeline), ApiError> {
         let mut room_ordering = 0;
         let mut timeline_events = Vec::new();
-        let mut limited = false;
-
-        let length = events.len();
-
-        let count = match *timeline_filter {
-            None => 0,
-            Some(ref filter) => {
-                match filter.limit {
-                    0 => 0,
-                    x => {
-                        if length > x {
-                            limited = true;
-                            length - x
-                        } else {
-                            0
-                        }
-                    },
-                }
-            }
-        };
 
-        for event in events.into_iter().skip(count) {
+        for event in events {
             room_ordering = cmp::max(room_ordering, event.ordering);
 
-            let value = match EventType::from(event.event_type.as_ref()) {
-                EventType::CallAnswer => RoomEvent::CallAnswer(event.try_into()?),
-                EventType::CallCandidates => RoomEvent::CallCandidates(event.try_into()?),
-                EventType::CallHangup => RoomEvent::CallHangup(event.try_into()?),
-                EventType::CallInvite => RoomEvent::CallInvite(event.try_into()?),
-                EventType::RoomAliases => RoomEvent::RoomAliases(event.try_into()?),
-                EventType::RoomAvatar => RoomEvent::RoomAvatar(event.try_into()?),
-                EventType::RoomCanonicalAlias => RoomEvent::RoomCanonicalAlias(event.try_into()?),
-                EventType::RoomCreate => RoomEvent::RoomCreate(event.try_into()?),
-                EventType::RoomGuestAccess => RoomEvent::RoomGuestAccess(event.try_into()?),
-                EventType::RoomHistoryVisibility => RoomEvent::RoomHistoryVisibility(event.try_into()?),
-                EventType::RoomJoinRules => RoomEvent::RoomJoinRules(event.try_into()?),
-                EventType::RoomMember => RoomEvent::RoomMember(event.try_into()?),
-                EventType::RoomMessage => RoomEvent::RoomMessage(event.try_into()?),
-                EventType::RoomName => RoomEvent::RoomName(event.try_into()?),
-                EventType::RoomPowerLevels => RoomEvent::RoomPowerLevels(event.try_into()?),
-                EventType::RoomThirdPartyInvite => RoomEvent::RoomThirdPartyInvite(event.try_into()?),
-                EventType::RoomTopic => RoomEvent::RoomTopic(event.try_into()?),
-                _ => {
-                    println!("unhandled {:?}", event.event_type);
+            let event_type = event.event_type.clone();
+
+            let value = match room_event(event)? {
+                Some(value) => value,
+                None => {
+                    debug!(
+                        "Leaving an event of unsupported type {} out of the timeline.",
+                        event_type
+                    );
                     continue;
-                },
+                }
             };
 
             timeline_events.push(value);
@@ -440,27 +509,111 @@ impl Sync {
     }
 }
 
-#[test]
-fn batch_to_str() {
-    let batch = Batch::new(10, 10);
-    assert_eq!(batch.to_string(), String::from("10_10"));
+/// Converts a stored event into the representation sent to clients, if it is a room event.
+pub fn room_event(event: Event) -> Result<Option<RoomEvent>, ApiError> {
+    let value = match EventType::from(event.event_type.as_ref()) {
+        EventType::CallAnswer => RoomEvent::CallAnswer(event.try_into()?),
+        EventType::CallCandidates => RoomEvent::CallCandidates(event.try_into()?),
+        EventType::CallHangup => RoomEvent::CallHangup(event.try_into()?),
+        EventType::CallInvite => RoomEvent::CallInvite(event.try_into()?),
+        EventType::RoomAliases => RoomEvent::RoomAliases(event.try_into()?),
+        EventType::RoomAvatar => RoomEvent::RoomAvatar(event.try_into()?),
+        EventType::RoomCanonicalAlias => RoomEvent::RoomCanonicalAlias(event.try_into()?),
+        EventType::RoomCreate => RoomEvent::RoomCreate(event.try_into()?),
+        EventType::RoomGuestAccess => RoomEvent::RoomGuestAccess(event.try_into()?),
+        EventType::RoomHistoryVisibility => RoomEvent::RoomHistoryVisibility(event.try_into()?),
+        EventType::RoomJoinRules => RoomEvent::RoomJoinRules(event.try_into()?),
+        EventType::RoomMember => RoomEvent::RoomMember(event.try_into()?),
+        EventType::RoomMessage => RoomEvent::RoomMessage(event.try_into()?),
+        EventType::RoomName => RoomEvent::RoomName(event.try_into()?),
+        EventType::RoomPowerLevels => RoomEvent::RoomPowerLevels(event.try_into()?),
+        EventType::RoomThirdPartyInvite => RoomEvent::RoomThirdPartyInvite(event.try_into()?),
+        EventType::RoomTopic => RoomEvent::RoomTopic(event.try_into()?),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(value))
 }
 
-#[test]
-fn batch_parse() {
-    let batch = Batch::from_str("10_12").unwrap();
-    assert_eq!(batch.room_key, 10);
-    assert_eq!(batch.presence_key, 12);
-}
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
 
-#[test]
-fn batch_parse_non_number() {
-    let batch = Batch::from_str("10_12a");
-    assert!(batch.is_err());
-}
+    use quickcheck::{Arbitrary, Gen};
+    use rand::Rng;
+
+    use test::{AnyInteger, check_property};
+    use super::Batch;
+
+    /// A string made mostly of the characters of batch tokens, so that it is close to a valid
+    /// token more often than an arbitrary string.
+    #[derive(Clone, Debug)]
+    struct TokenLike(String);
+
+    impl Arbitrary for TokenLike {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            const CHARACTERS: &'static [char] =
+                &['0', '1', '9', '_', '_', '-', '+', ' ', 'a', '\u{0}', '\u{661}', 'é'];
+
+            let length = g.gen_range(0, 30);
+            let token = (0..length).map(|_| *g.choose(CHARACTERS).unwrap()).collect();
+
+            TokenLike(token)
+        }
+    }
+
+    #[test]
+    fn batch_to_str() {
+        let batch = Batch::new(10, 10);
+        assert_eq!(batch.to_string(), String::from("10_10"));
+    }
+
+    #[test]
+    fn batch_parse() {
+        let batch = Batch::from_str("10_12").unwrap();
+        assert_eq!(batch.room_key, 10);
+        assert_eq!(batch.presence_key, 12);
+    }
 
-#[test]
-fn batch_parse_too_many() {
-    let batch = Batch::from_str("10_12_12");
-    assert!(batch.is_err());
+    #[test]
+    fn batch_parse_non_number() {
+        let batch = Batch::from_str("10_12a");
+        assert!(batch.is_err());
+    }
+
+    #[test]
+    fn batch_parse_too_many() {
+        let batch = Batch::from_str("10_12_12");
+        assert!(batch.is_err());
+    }
+
+    #[test]
+    fn batches_round_trip_through_strings() {
+        fn property(room_key: AnyInteger, presence_key: AnyInteger) -> bool {
+            let batch = Batch::new(room_key.0, presence_key.0);
+
+            batch.to_string().parse::<Batch>() == Ok(batch)
+        }
+
+        check_property(property as fn(AnyInteger, AnyInteger) -> bool);
+    }
+
+    #[test]
+    fn junk_batches_are_rejected_without_panicking() {
+        fn property(token: TokenLike) -> bool {
+            match token.0.parse::<Batch>() {
+                Ok(batch) => batch.to_string().parse::<Batch>() == Ok(batch),
+                Err(_) => true,
+            }
+        }
+
+        fn arbitrary_string_property(token: String) -> bool {
+            let _ = token.parse::<Batch>();
+
+            true
+        }
+
+        check_property(property as fn(TokenLike) -> bool);
+        check_property(arbitrary_string_property as fn(String) -> bool);
+    }
 }
@@ -0,0 +1,78 @@
+//! Budgets for the database work of common requests, checked by load-test style tests.
+//!
+//! The budgets leave some headroom over what the requests need today. A change that makes a
+//! request exceed its budget should either be fixed or raise the budget here deliberately.
+
+/// The number of rooms the user is in for `INITIAL_SYNC_MAX_QUERIES`.
+pub const INITIAL_SYNC_ROOMS: usize = 100;
+
+/// The most queries an initial sync of a user in `INITIAL_SYNC_ROOMS` rooms may make, 15 per room.
+pub const INITIAL_SYNC_MAX_QUERIES: i64 = 15 * INITIAL_SYNC_ROOMS as i64;
+
+/// The most queries sending a message may make.
+pub const SEND_MESSAGE_MAX_QUERIES: i64 = 60;
+
+/// The number of public rooms for `PUBLIC_ROOMS_MAX_ROWS_FETCHED`.
+pub const PUBLIC_ROOMS: usize = 500;
+
+/// The most rows listing the first page of `PUBLIC_ROOMS` public rooms may fetch, 40 per room.
+pub const PUBLIC_ROOMS_MAX_ROWS_FETCHED: i64 = 40 * PUBLIC_ROOMS as i64;
+
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+    use super::{
+        INITIAL_SYNC_MAX_QUERIES,
+        INITIAL_SYNC_ROOMS,
+        PUBLIC_ROOMS,
+        PUBLIC_ROOMS_MAX_ROWS_FETCHED,
+        SEND_MESSAGE_MAX_QUERIES,
+    };
+
+    #[test]
+    fn initial_sync_query_budget() {
+        let test = Test::new();
+        let alice = test.register("alice");
+
+        for _ in 0..INITIAL_SYNC_ROOMS {
+            alice.create_room(&test, "{}");
+        }
+
+        let (response, stats) = test.measure(|| alice.sync_since(&test, None));
+
+        assert_eq!(response.status, Status::Ok);
+        stats.assert_queries_at_most(INITIAL_SYNC_MAX_QUERIES, "An initial sync");
+    }
+
+    #[test]
+    fn send_message_query_budget() {
+        let test = Test::new();
+        let alice = test.register("alice");
+        let room_id = alice.create_room(&test, "{}");
+
+        alice.send_message(&test, &room_id, "Warming up the caches");
+
+        let (response, stats) = test.measure(|| alice.send_message(&test, &room_id, "Hi"));
+
+        assert_eq!(response.status, Status::Ok);
+        stats.assert_queries_at_most(SEND_MESSAGE_MAX_QUERIES, "Sending a message");
+    }
+
+    #[test]
+    fn public_rooms_row_budget() {
+        let test = Test::new();
+        let alice = test.register("alice");
+
+        for _ in 0..PUBLIC_ROOMS {
+            alice.create_room(&test, r#"{"visibility": "public"}"#);
+        }
+
+        let (response, stats) = test.measure(|| {
+            test.get("/_matrix/client/r0/publicRooms?limit=10")
+        });
+
+        assert_eq!(response.status, Status::Ok);
+        stats.assert_rows_fetched_at_most(PUBLIC_ROOMS_MAX_ROWS_FETCHED, "Listing public rooms");
+    }
+}
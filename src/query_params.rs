@@ -0,0 +1,167 @@
+//! Parsing of query string parameters.
+//!
+//! Malformed parameters are rejected with `M_INVALID_PARAM` naming the parameter. If a parameter
+//! is repeated, the first value is used, unless all values are asked for with `get_all`.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use iron::Request;
+use url::Url;
+
+use error::ApiError;
+
+/// The first value of the query parameter, if it was given.
+pub fn get(request: &Request, name: &str) -> Option<String> {
+    let url: Url = request.url.clone().into();
+
+    url.query_pairs()
+        .find(|&(ref key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// All values of a repeatable query parameter like `via`, in the order they were given.
+pub fn get_all(request: &Request, name: &str) -> Vec<String> {
+    let url: Url = request.url.clone().into();
+
+    url.query_pairs()
+        .filter(|&(ref key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+        .collect()
+}
+
+/// An unsigned integer parameter like `limit`, or `default` if it wasn't given.
+///
+/// Values larger than `max` are reduced to it.
+pub fn get_u64(request: &Request, name: &str, default: u64, max: u64) -> Result<u64, ApiError> {
+    let value = match get(request, name) {
+        Some(value) => value.parse::<u64>()
+            .map_err(|_| ApiError::invalid_param(name, "Must be a non-negative integer."))?,
+        None => default,
+    };
+
+    Ok(if value > max { max } else { value })
+}
+
+/// A parameter parsed with `FromStr`, like a pagination token.
+pub fn get_token<T>(request: &Request, name: &str) -> Result<Option<T>, ApiError>
+where T: FromStr, T::Err: Display {
+    match get(request, name) {
+        Some(value) => {
+            let token = value.parse::<T>()
+                .map_err(|err| ApiError::invalid_param(name, &err.to_string()))?;
+
+            Ok(Some(token))
+        }
+        None => Ok(None),
+    }
+}
+
+/// A boolean parameter, either `true` or `false`, or `default` if it wasn't given.
+pub fn get_bool(request: &Request, name: &str, default: bool) -> Result<bool, ApiError> {
+    match get(request, name) {
+        Some(value) => match value.as_ref() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(ApiError::invalid_param(name, "Must be true or false.")),
+        },
+        None => Ok(default),
+    }
+}
+
+/// A parameter with one of the given values, returning the variant it maps to.
+pub fn get_enum<T: Clone>(request: &Request, name: &str, variants: &[(&str, T)])
+-> Result<Option<T>, ApiError> {
+    let value = match get(request, name) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    for &(variant_name, ref variant) in variants {
+        if variant_name == value {
+            return Ok(Some(variant.clone()));
+        }
+    }
+
+    let names: Vec<&str> = variants.iter().map(|&(variant_name, _)| variant_name).collect();
+
+    Err(ApiError::invalid_param(name, &format!("Must be one of: {}.", names.join(", "))))
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    #[test]
+    fn malformed_limit_is_named() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/messages?from=1_0&dir=b&limit=abc&access_token={}",
+            room_id,
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_INVALID_PARAM");
+        assert!(response.json().get("error").unwrap().as_str().unwrap().contains("limit"));
+    }
+
+    #[test]
+    fn large_limit_is_clamped() {
+        let test = Test::with_config(|config| config.max_pagination_limit = 2);
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        for txn_id in 0..3 {
+            test.send_message(&alice.token, &room_id, "Hi", txn_id);
+        }
+
+        let response = test.get(&format!("/_matrix/client/r0/sync?access_token={}", alice.token));
+        let latest = response.json().get("next_batch").unwrap().as_str().unwrap().to_string();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/messages?from={}&dir=b&limit=100000&access_token={}",
+            room_id,
+            latest,
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("chunk").unwrap().as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn unknown_enum_value_lists_allowed_values() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/messages?from=1_0&dir=sideways&access_token={}",
+            room_id,
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::BadRequest);
+
+        let error = response.json().get("error").unwrap().as_str().unwrap().to_string();
+        assert!(error.contains("dir"));
+        assert!(error.contains("f, b"));
+    }
+
+    #[test]
+    fn first_repeated_parameter_wins() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/messages?from=1_0&dir=f&dir=sideways&access_token={}",
+            room_id,
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+    }
+}
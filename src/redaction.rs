@@ -0,0 +1,151 @@
+//! Redaction of secrets from logged URLs and headers.
+//!
+//! Clients may send their access token as the `access_token` query parameter or in the
+//! `Authorization` header. Anything logging a request's URL or headers should format them with
+//! these functions, which replace the secrets with `<redacted>` unless `log_access_tokens` is
+//! configured.
+
+use std::sync::atomic::{ATOMIC_BOOL_INIT, AtomicBool, Ordering};
+
+use iron::Url;
+use iron::headers::Headers;
+use url::Url as ParsedUrl;
+use url::percent_encoding::percent_decode;
+
+/// The replacement for redacted values.
+const REDACTED: &'static str = "<redacted>";
+
+/// The query parameters whose values are redacted.
+const SECRET_QUERY_PARAMS: [&'static str; 1] = ["access_token"];
+
+/// The headers whose values are redacted.
+const SECRET_HEADERS: [&'static str; 1] = ["Authorization"];
+
+/// Set from the configured `log_access_tokens` when the server is created.
+static LOG_ACCESS_TOKENS: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Sets whether or not secrets are logged as they are, for debugging.
+pub fn set_log_access_tokens(log_access_tokens: bool) {
+    LOG_ACCESS_TOKENS.store(log_access_tokens, Ordering::Relaxed);
+}
+
+/// The URL with the values of secret query parameters redacted.
+pub fn redact_url(url: &Url) -> String {
+    let mut url: ParsedUrl = url.clone().into();
+
+    if LOG_ACCESS_TOKENS.load(Ordering::Relaxed) {
+        return url.to_string();
+    }
+
+    let query = match url.query() {
+        Some(query) => redact_query(query),
+        None => return url.to_string(),
+    };
+
+    url.set_query(None);
+
+    format!("{}?{}", url, query)
+}
+
+/// The headers, one `Name: value` per line, with the values of secret headers redacted.
+pub fn redact_headers(headers: &Headers) -> String {
+    let log_access_tokens = LOG_ACCESS_TOKENS.load(Ordering::Relaxed);
+
+    headers.iter().map(|header| {
+        let name = header.name().to_lowercase();
+        let is_secret = SECRET_HEADERS.iter().any(|secret| secret.to_lowercase() == name);
+
+        if is_secret && !log_access_tokens {
+            format!("{}: {}", header.name(), REDACTED)
+        } else {
+            format!("{}: {}", header.name(), header.value_string())
+        }
+    }).collect::<Vec<String>>().join("\n")
+}
+
+/// The query string with the values of secret parameters redacted.
+fn redact_query(query: &str) -> String {
+    query.split('&').map(|pair| {
+        let name = pair.split('=').next().unwrap_or("");
+        let decoded_name = percent_decode(name.as_bytes()).decode_utf8_lossy();
+
+        if SECRET_QUERY_PARAMS.contains(&decoded_name.as_ref()) {
+            format!("{}={}", name, REDACTED)
+        } else {
+            pair.to_string()
+        }
+    }).collect::<Vec<String>>().join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::{Chain, IronResult, Request, Response, Url};
+    use iron::headers::{Authorization, Headers, UserAgent};
+    use iron_test::request;
+
+    use middleware::CatchPanics;
+    use test::{Test, all_captured_logs};
+    use super::{redact_headers, redact_url};
+
+    #[test]
+    fn access_tokens_in_urls_are_redacted() {
+        let url = Url::parse("http://ruma.test/_matrix/client/r0/sync?since=s1&access_token=abc")
+            .unwrap();
+
+        assert_eq!(
+            redact_url(&url),
+            "http://ruma.test/_matrix/client/r0/sync?since=s1&access_token=<redacted>"
+        );
+
+        let url = Url::parse("http://ruma.test/_matrix/client/r0/sync?access%5Ftoken=abc").unwrap();
+        assert!(!redact_url(&url).contains("abc"));
+
+        let url = Url::parse("http://ruma.test/_matrix/client/versions").unwrap();
+        assert_eq!(redact_url(&url), "http://ruma.test/_matrix/client/versions");
+    }
+
+    #[test]
+    fn authorization_headers_are_redacted() {
+        let mut headers = Headers::new();
+        headers.set(Authorization("Bearer abc".to_string()));
+        headers.set(UserAgent("Riot/1.0".to_string()));
+
+        let formatted = redact_headers(&headers);
+
+        assert!(!formatted.contains("abc"));
+        assert!(formatted.contains("Authorization: <redacted>"));
+        assert!(formatted.contains("User-Agent: Riot/1.0"));
+    }
+
+    #[test]
+    fn logs_never_contain_access_tokens() {
+        fn panicking_handler(_: &mut Request) -> IronResult<Response> {
+            panic!("The handler failed.");
+        }
+
+        let test = Test::new();
+        let alice = test.create_user();
+
+        test.get(&format!("/_matrix/client/r0/sync?access_token={}", alice.token));
+        test.get(&format!("/_matrix/client/r0/no_such_endpoint?access_token={}", alice.token));
+        test.get(&format!(
+            "/_matrix/client/r0/rooms/!nope:ruma.test/state?access_token={}",
+            alice.token
+        ));
+
+        let mut chain = Chain::new(panicking_handler);
+        chain.link_around(CatchPanics);
+
+        let mut headers = Headers::new();
+        headers.set(Authorization(format!("Bearer {}", alice.token)));
+
+        let result = request::get(
+            &format!("http://ruma.test/sync?access_token={}", alice.token),
+            headers,
+            &chain,
+        );
+
+        assert!(result.is_err());
+        assert!(all_captured_logs().iter().all(|line| !line.contains(&alice.token)));
+    }
+}
@@ -0,0 +1,147 @@
+//! Typed access to the data that routing and middleware attach to requests.
+//!
+//! Handlers rely on their middleware chain to put things like the authenticated user or parsed
+//! path parameters into the request's extensions. If a handler is mounted without the middleware
+//! it needs, these helpers log the mistake and fail the request with a 500 `M_UNKNOWN` error
+//! instead of panicking the worker thread.
+
+use iron::Request;
+use iron::typemap::Key;
+use router::{Params, Router};
+
+use error::ApiError;
+use models::user::User;
+use redaction::redact_url;
+
+/// The parameters the router extracted from the request's path.
+pub fn params(request: &Request) -> Result<&Params, ApiError> {
+    match request.extensions.get::<Router>() {
+        Some(params) => Ok(params),
+        None => Err(missing(request, "router parameters")),
+    }
+}
+
+/// The value of the named path parameter, e.g. `room_id` for `/rooms/:room_id/members`.
+pub fn path_param(request: &Request, name: &str) -> Result<String, ApiError> {
+    match params(request)?.find(name) {
+        Some(value) => Ok(value.to_string()),
+        None => Err(missing(request, &format!("path parameter `{}`", name))),
+    }
+}
+
+/// The user authenticated by `AccessTokenAuth`.
+pub fn authed_user(request: &Request) -> Result<User, ApiError> {
+    match request.extensions.get::<User>() {
+        Some(user) => Ok(user.clone()),
+        None => Err(missing(request, "authenticated user")),
+    }
+}
+
+/// A copy of the value stored under the key `K`, e.g. by one of the path parameter middlewares.
+pub fn extension<K>(request: &Request) -> Result<K::Value, ApiError>
+where K: Key, K::Value: Clone {
+    match request.extensions.get::<K>() {
+        Some(value) => Ok(value.clone()),
+        None => Err(missing(request, "request extension")),
+    }
+}
+
+/// A mutable reference to the value stored under the key `K`.
+pub fn extension_mut<K: Key>(request: &mut Request) -> Result<&mut K::Value, ApiError> {
+    if !request.extensions.contains::<K>() {
+        return Err(missing(request, "request extension"));
+    }
+
+    match request.extensions.get_mut::<K>() {
+        Some(value) => Ok(value),
+        None => Err(ApiError::unknown(None)),
+    }
+}
+
+/// Logs a missing piece of request data and creates the error returned to the client.
+fn missing(request: &Request, what: &str) -> ApiError {
+    error!(
+        "The {} for {} {} is missing. Is the endpoint mounted without its middleware?",
+        what,
+        request.method,
+        redact_url(&request.url)
+    );
+
+    ApiError::unknown("The server failed to process the request.".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::{Handler, IronResult, Request, Response};
+    use iron::headers::Headers;
+    use iron::status::Status;
+    use iron_test::{request, response};
+    use router::Router;
+    use serde_json::{Value, from_str};
+
+    use middleware::RoomIdParam;
+    use super::{authed_user, extension, path_param};
+
+    struct NeedsUser;
+
+    impl Handler for NeedsUser {
+        fn handle(&self, request: &mut Request) -> IronResult<Response> {
+            authed_user(request)?;
+
+            Ok(Response::with(Status::Ok))
+        }
+    }
+
+    struct NeedsRoomId;
+
+    impl Handler for NeedsRoomId {
+        fn handle(&self, request: &mut Request) -> IronResult<Response> {
+            extension::<RoomIdParam>(request)?;
+            path_param(request, "room_id")?;
+
+            Ok(Response::with(Status::Ok))
+        }
+    }
+
+    fn assert_unknown_error<H: Handler>(handler: H, path: &str) {
+        let url = format!("http://localhost:3000{}", path);
+
+        let error = match request::get(&url, Headers::new(), &handler) {
+            Ok(_) => panic!("the request should fail"),
+            Err(error) => error,
+        };
+
+        assert_eq!(error.response.status, Some(Status::InternalServerError));
+
+        let body: Value = from_str(&response::extract_body_to_string(error.response)).unwrap();
+        assert_eq!(body.get("errcode").unwrap().as_str().unwrap(), "M_UNKNOWN");
+    }
+
+    #[test]
+    fn handler_without_authentication_fails_cleanly() {
+        assert_unknown_error(NeedsUser, "/account/whoami");
+    }
+
+    #[test]
+    fn handler_without_routing_fails_cleanly() {
+        assert_unknown_error(NeedsRoomId, "/rooms/!abc:ruma.test/members");
+    }
+
+    #[test]
+    fn routed_path_params_are_found() {
+        let mut router = Router::new();
+        router.get("/rooms/:room_id", |request: &mut Request| {
+            let room_id = path_param(request, "room_id")?;
+
+            Ok(Response::with((Status::Ok, room_id)))
+        }, "room");
+
+        let response = request::get(
+            "http://localhost:3000/rooms/!abc:ruma.test",
+            Headers::new(),
+            &router,
+        ).unwrap();
+
+        assert_eq!(response::extract_body_to_string(response), "!abc:ruma.test");
+    }
+}
@@ -0,0 +1,526 @@
+//! Server-side retention of room history.
+//!
+//! Rooms set how long their events are kept with an `m.room.retention` state event, and the
+//! server provides a default for rooms that don't. A background job periodically purges the
+//! events that outlived their room's policy.
+
+use std::cmp;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use diesel::Connection;
+use diesel::pg::PgConnection;
+use ruma_identifiers::RoomId;
+use serde_json::{Value, from_str};
+
+use clock::Clock;
+use error::ApiError;
+use jobs::JobRegistry;
+use models::background_job::Job;
+use models::event::Event;
+use models::room::Room;
+
+/// The kind of the background job that purges expired events.
+pub const PURGE_EXPIRED_EVENTS_JOB: &'static str = "purge_expired_events";
+
+/// The type of the state event holding a room's retention policy.
+pub const RETENTION_EVENT_TYPE: &'static str = "m.room.retention";
+
+/// The server's retention settings.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RetentionConfig {
+    /// The number of milliseconds events are kept in rooms without a retention policy. Events in
+    /// such rooms are kept forever if it isn't set.
+    pub default_max_lifetime: Option<u64>,
+    /// The lowest `max_lifetime`, in milliseconds, rooms may set.
+    pub allowed_lifetime_min: Option<u64>,
+    /// The highest `max_lifetime`, in milliseconds, rooms may set.
+    pub allowed_lifetime_max: Option<u64>,
+    /// The number of seconds between two runs of the purge job. Defaults to 3600.
+    #[serde(default = "default_purge_interval")]
+    pub purge_interval: u64,
+}
+
+/// The content of an `m.room.retention` event.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct RetentionPolicy {
+    /// The number of milliseconds after which events may be purged.
+    pub max_lifetime: Option<u64>,
+    /// The number of milliseconds events must be kept at least.
+    pub min_lifetime: Option<u64>,
+}
+
+impl RetentionConfig {
+    /// The number of milliseconds events are kept in a room with the given policy, or `None` if
+    /// they are kept forever.
+    ///
+    /// The room's `max_lifetime` is clamped to the allowed range, and never shorter than its own
+    /// `min_lifetime`.
+    pub fn max_lifetime(&self, policy: Option<&RetentionPolicy>) -> Option<u64> {
+        let requested = match policy.and_then(|policy| policy.max_lifetime) {
+            Some(max_lifetime) => max_lifetime,
+            None => match self.default_max_lifetime {
+                Some(max_lifetime) => max_lifetime,
+                None => return None,
+            },
+        };
+
+        let mut max_lifetime = requested;
+
+        if let Some(allowed_min) = self.allowed_lifetime_min {
+            max_lifetime = cmp::max(max_lifetime, allowed_min);
+        }
+
+        if let Some(allowed_max) = self.allowed_lifetime_max {
+            max_lifetime = cmp::min(max_lifetime, allowed_max);
+        }
+
+        if let Some(min_lifetime) = policy.and_then(|policy| policy.min_lifetime) {
+            max_lifetime = cmp::max(max_lifetime, min_lifetime);
+        }
+
+        Some(max_lifetime)
+    }
+}
+
+impl RetentionPolicy {
+    /// Look up the current retention policy of a room.
+    ///
+    /// Policies whose content can't be parsed are ignored.
+    pub fn find_by_room_id(connection: &PgConnection, room_id: &RoomId)
+    -> Result<Option<RetentionPolicy>, ApiError> {
+        let event = Event::find_current_state_event(connection, room_id, RETENTION_EVENT_TYPE, "")?;
+
+        let event = match event {
+            Some(event) => event,
+            None => return Ok(None),
+        };
+
+        match from_str(&event.content) {
+            Ok(policy) => Ok(Some(policy)),
+            Err(error) => {
+                warn!("Ignoring invalid retention policy {} in room {}: {}", event.id, room_id, error);
+
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Purges the events of all rooms that outlived the room's retention policy at the current time
+/// of `clock`.
+///
+/// Returns the number of purged events.
+pub fn purge_expired_events(connection: &PgConnection, config: &RetentionConfig, clock: &Clock)
+-> Result<usize, ApiError> {
+    let now = clock.now();
+    let since_epoch = now.duration_since(UNIX_EPOCH)?;
+    let mut purged = 0;
+
+    for room_id in Room::all_ids(connection)? {
+        let policy = RetentionPolicy::find_by_room_id(connection, &room_id)?;
+
+        let max_lifetime = match config.max_lifetime(policy.as_ref()) {
+            Some(max_lifetime) => Duration::from_millis(max_lifetime),
+            None => continue,
+        };
+
+        // Nothing can be older than the Unix epoch.
+        if max_lifetime >= since_epoch {
+            continue;
+        }
+
+        purged += connection.transaction::<usize, ApiError, _>(|| {
+            Event::purge_room_history(connection, &room_id, now - max_lifetime)
+        }).map_err(ApiError::from)?;
+    }
+
+    Ok(purged)
+}
+
+/// Registers the handler of the purge job.
+///
+/// Every run of the job schedules the next one.
+pub fn register_jobs(registry: &mut JobRegistry, config: &RetentionConfig, clock: Arc<Clock>) {
+    let config = config.clone();
+
+    registry.register(PURGE_EXPIRED_EVENTS_JOB, move |connection, _| {
+        let purged = purge_expired_events(connection, &config, &*clock)?;
+
+        info!("Purged {} expired events.", purged);
+
+        let next_run = clock.now() + Duration::from_secs(config.purge_interval);
+
+        Job::enqueue(connection, PURGE_EXPIRED_EVENTS_JOB, &Value::Null, next_run).map(|_| ())
+    });
+}
+
+/// Queues the purge job to run right away at the current time of `clock`, unless it is already
+/// queued.
+pub fn schedule_purge(connection: &PgConnection, clock: &Clock) -> Result<(), ApiError> {
+    if !Job::is_queued(connection, PURGE_EXPIRED_EVENTS_JOB)? {
+        Job::enqueue(connection, PURGE_EXPIRED_EVENTS_JOB, &Value::Null, clock.now())?;
+    }
+
+    Ok(())
+}
+
+fn default_purge_interval() -> u64 {
+    60 * 60
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::{Duration, SystemTime};
+
+    use diesel::{ExecuteDsl, ExpressionMethods, FilterDsl, update};
+    use iron::status::Status;
+    use ruma_identifiers::{EventId, RoomId, UserId};
+    use serde_json::Value;
+
+    use models::background_job::Job;
+    use models::event::{Event, NewEvent};
+    use query::SyncOptions;
+    use schema::{background_jobs, events};
+    use test::Test;
+    use super::{
+        PURGE_EXPIRED_EVENTS_JOB,
+        RetentionConfig,
+        RetentionPolicy,
+        purge_expired_events,
+        schedule_purge,
+    };
+
+    const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+    fn retention_config(default_max_lifetime: Option<u64>) -> RetentionConfig {
+        RetentionConfig {
+            default_max_lifetime: default_max_lifetime,
+            allowed_lifetime_min: None,
+            allowed_lifetime_max: None,
+            purge_interval: 3600,
+        }
+    }
+
+    /// Pretends all events of a room were created the given number of days ago.
+    fn age_events(test: &Test, room_id: &RoomId, days: u64) {
+        let connection = test.connection();
+
+        update(events::table.filter(events::room_id.eq(room_id)))
+            .set(events::created_at.eq(SystemTime::now() - Duration::from_millis(days * DAY_MS)))
+            .execute(&*connection)
+            .unwrap();
+    }
+
+    fn purge(test: &Test, default_max_lifetime: Option<u64>) -> usize {
+        let connection = test.connection();
+
+        purge_expired_events(&connection, &retention_config(default_max_lifetime), test.clock())
+            .unwrap()
+    }
+
+    fn count_messages(test: &Test, room_id: &RoomId) -> usize {
+        let connection = test.connection();
+
+        Event::find_room_events(&connection, room_id, -1)
+            .unwrap()
+            .into_iter()
+            .filter(|event| event.event_type == "m.room.message")
+            .count()
+    }
+
+    #[test]
+    fn max_lifetime() {
+        let config = RetentionConfig {
+            default_max_lifetime: Some(7 * DAY_MS),
+            allowed_lifetime_min: Some(DAY_MS),
+            allowed_lifetime_max: Some(30 * DAY_MS),
+            purge_interval: 3600,
+        };
+
+        let policy = |max_lifetime, min_lifetime| RetentionPolicy {
+            max_lifetime: max_lifetime,
+            min_lifetime: min_lifetime,
+        };
+
+        assert_eq!(config.max_lifetime(None), Some(7 * DAY_MS));
+        assert_eq!(config.max_lifetime(Some(&policy(None, None))), Some(7 * DAY_MS));
+        assert_eq!(config.max_lifetime(Some(&policy(Some(2 * DAY_MS), None))), Some(2 * DAY_MS));
+        assert_eq!(config.max_lifetime(Some(&policy(Some(1000), None))), Some(DAY_MS));
+        assert_eq!(config.max_lifetime(Some(&policy(Some(90 * DAY_MS), None))), Some(30 * DAY_MS));
+        assert_eq!(
+            config.max_lifetime(Some(&policy(Some(2 * DAY_MS), Some(3 * DAY_MS)))),
+            Some(3 * DAY_MS)
+        );
+        assert_eq!(retention_config(None).max_lifetime(None), None);
+    }
+
+    #[test]
+    fn purge_expired_events_keeps_latest_event_and_state() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = RoomId::try_from(test.create_room(&carl.token).as_ref()).unwrap();
+
+        for txn_id in 1..4 {
+            test.send_message(&carl.token, &room_id.to_string(), "Hi", txn_id);
+        }
+
+        age_events(&test, &room_id, 2);
+
+        let connection = test.connection();
+        let latest_event_ids = Event::find_latest_event_ids(&connection, &room_id).unwrap();
+        let state_before = Event::get_room_full_state(&connection, &room_id).unwrap();
+
+        let purged =
+            purge_expired_events(&connection, &retention_config(Some(DAY_MS)), test.clock())
+                .unwrap();
+        assert_eq!(purged, 2);
+
+        assert!(Event::find(&connection, &latest_event_ids[0]).unwrap().is_some());
+
+        let state_after = Event::get_room_full_state(&connection, &room_id).unwrap();
+        assert_eq!(state_after.len(), state_before.len());
+        drop(connection);
+
+        assert_eq!(count_messages(&test, &room_id), 1);
+
+        let response = test.sync(&carl.token, SyncOptions {
+            filter: None,
+            since: None,
+            full_state: true,
+            set_presence: None,
+            timeout: 0,
+        });
+        assert_eq!(response.status, Status::Ok);
+
+        let room = response.json().pointer(&format!("/rooms/join/{}", room_id)).unwrap();
+        assert!(!room.pointer("/state/events").unwrap().as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn purge_expired_events_respects_room_policy() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = RoomId::try_from(test.create_room(&carl.token).as_ref()).unwrap();
+
+        let response = test.send_state_event(
+            &carl.token,
+            &room_id.to_string(),
+            "m.room.retention",
+            &format!(r#"{{"max_lifetime": {}}}"#, 7 * DAY_MS),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        test.send_message(&carl.token, &room_id.to_string(), "Hi", 1);
+        test.send_message(&carl.token, &room_id.to_string(), "Hi again", 2);
+        age_events(&test, &room_id, 2);
+
+        assert_eq!(purge(&test, Some(DAY_MS)), 0);
+
+        age_events(&test, &room_id, 8);
+
+        assert_eq!(purge(&test, Some(DAY_MS)), 1);
+
+        assert_eq!(count_messages(&test, &room_id), 1);
+    }
+
+    #[test]
+    fn purge_expired_events_keeps_pinned_events() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = RoomId::try_from(test.create_room(&carl.token).as_ref()).unwrap();
+
+        test.send_message(&carl.token, &room_id.to_string(), "Hi", 1);
+
+        let connection = test.connection();
+        let pinned_event_id = Event::find_latest_event_ids(&connection, &room_id).unwrap().remove(0);
+
+        NewEvent {
+            event_type: "m.room.pinned_events".to_string(),
+            extra_content: None,
+            id: EventId::new("ruma.test").unwrap(),
+            content: format!(r#"{{"pinned": ["{}"]}}"#, pinned_event_id),
+            room_id: room_id.clone(),
+            state_key: Some("".to_string()),
+            user_id: UserId::try_from(carl.id.as_ref()).unwrap(),
+            pdu: None,
+        }.save(&connection, test.clock()).unwrap();
+        drop(connection);
+
+        test.send_message(&carl.token, &room_id.to_string(), "Hi again", 2);
+        age_events(&test, &room_id, 2);
+
+        assert_eq!(purge(&test, Some(DAY_MS)), 0);
+
+        let connection = test.connection();
+        assert!(Event::find(&connection, &pinned_event_id).unwrap().is_some());
+    }
+
+    #[test]
+    fn purge_expired_events_skips_rooms_with_invalid_pinned_events() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = RoomId::try_from(test.create_room(&carl.token).as_ref()).unwrap();
+        let other_room_id = RoomId::try_from(test.create_room(&carl.token).as_ref()).unwrap();
+
+        for room_id in &[&room_id, &other_room_id] {
+            test.send_message(&carl.token, &room_id.to_string(), "Hi", 1);
+            test.send_message(&carl.token, &room_id.to_string(), "Hi again", 2);
+        }
+
+        let connection = test.connection();
+        NewEvent {
+            event_type: "m.room.pinned_events".to_string(),
+            extra_content: None,
+            id: EventId::new("ruma.test").unwrap(),
+            content: "not json".to_string(),
+            room_id: room_id.clone(),
+            state_key: Some("".to_string()),
+            user_id: UserId::try_from(carl.id.as_ref()).unwrap(),
+            pdu: None,
+        }.save(&connection, test.clock()).unwrap();
+        drop(connection);
+
+        age_events(&test, &room_id, 2);
+        age_events(&test, &other_room_id, 2);
+
+        assert_eq!(purge(&test, Some(DAY_MS)), 1);
+        assert_eq!(count_messages(&test, &room_id), 2);
+        assert_eq!(count_messages(&test, &other_room_id), 1);
+    }
+
+    #[test]
+    fn messages_paginate_backwards_through_purged_history() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = RoomId::try_from(test.create_room(&carl.token).as_ref()).unwrap();
+
+        for txn_id in 1..5 {
+            test.send_message(&carl.token, &room_id.to_string(), &format!("Hi {}", txn_id), txn_id);
+        }
+
+        let response = test.sync(&carl.token, SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        });
+        let mut from = Test::get_next_batch(&response).to_string();
+
+        age_events(&test, &room_id, 2);
+        assert_eq!(purge(&test, Some(DAY_MS)), 3);
+
+        let mut bodies = Vec::new();
+
+        // The pagination ends once a page comes back empty, with `end` where it started.
+        for _ in 0..20 {
+            let response = test.get(&format!(
+                "/_matrix/client/r0/rooms/{}/messages?from={}&dir=b&limit=2&access_token={}",
+                room_id,
+                from,
+                carl.token
+            ));
+            assert_eq!(response.status, Status::Ok);
+
+            let chunk = response.json().get("chunk").unwrap().as_array().unwrap().clone();
+            let end = response.json().get("end").unwrap().as_str().unwrap().to_string();
+
+            bodies.extend(chunk.iter().filter_map(|event| {
+                event.pointer("/content/body").and_then(Value::as_str).map(str::to_string)
+            }));
+
+            if chunk.is_empty() {
+                assert_eq!(end, from);
+                break;
+            }
+
+            from = end;
+        }
+
+        assert_eq!(bodies, vec!["Hi 4".to_string()]);
+    }
+
+    #[test]
+    fn rooms_without_policy_are_kept_by_default() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = RoomId::try_from(test.create_room(&carl.token).as_ref()).unwrap();
+
+        test.send_message(&carl.token, &room_id.to_string(), "Hi", 1);
+        test.send_message(&carl.token, &room_id.to_string(), "Hi again", 2);
+        age_events(&test, &room_id, 365);
+
+        assert_eq!(purge(&test, None), 0);
+    }
+
+    #[test]
+    fn events_are_purged_once_they_outlived_the_policy() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = RoomId::try_from(test.create_room(&carl.token).as_ref()).unwrap();
+
+        for txn_id in 1..4 {
+            test.send_message(&carl.token, &room_id.to_string(), "Hi", txn_id);
+        }
+
+        assert_eq!(purge(&test, Some(DAY_MS)), 0);
+
+        test.clock().advance(Duration::from_millis(DAY_MS / 2));
+        assert_eq!(purge(&test, Some(DAY_MS)), 0);
+        assert_eq!(count_messages(&test, &room_id), 3);
+
+        test.clock().advance(Duration::from_millis(DAY_MS));
+        assert_eq!(purge(&test, Some(DAY_MS)), 2);
+        assert_eq!(count_messages(&test, &room_id), 1);
+    }
+
+    #[test]
+    fn scheduled_purges_run_as_time_passes() {
+        let test = Test::with_config(|config| {
+            config.retention = Some(retention_config(Some(DAY_MS)));
+        });
+        let carl = test.create_user();
+        let room_id = RoomId::try_from(test.create_room(&carl.token).as_ref()).unwrap();
+
+        for txn_id in 1..4 {
+            test.send_message(&carl.token, &room_id.to_string(), "Hi", txn_id);
+        }
+
+        schedule_purge(&test.connection(), test.clock()).unwrap();
+
+        assert_eq!(test.advance_time(Duration::from_millis(DAY_MS / 2)), 1);
+        assert_eq!(count_messages(&test, &room_id), 3);
+
+        // The next purge is only due an hour later.
+        assert_eq!(test.advance_time(Duration::from_secs(60)), 0);
+
+        assert_eq!(test.advance_time(Duration::from_millis(DAY_MS)), 1);
+        assert_eq!(count_messages(&test, &room_id), 1);
+    }
+
+    #[test]
+    fn schedule_purge_once() {
+        let test = Test::new();
+        let connection = test.connection();
+
+        schedule_purge(&connection, test.clock()).unwrap();
+        schedule_purge(&connection, test.clock()).unwrap();
+
+        let jobs: Vec<Job> = Job::all(&connection)
+            .unwrap()
+            .into_iter()
+            .filter(|job| job.kind == PURGE_EXPIRED_EVENTS_JOB)
+            .collect();
+        assert_eq!(jobs.len(), 1);
+
+        update(background_jobs::table.filter(background_jobs::id.eq(jobs[0].id)))
+            .set(background_jobs::dead.eq(true))
+            .execute(&*connection)
+            .unwrap();
+
+        schedule_purge(&connection, test.clock()).unwrap();
+        assert!(Job::is_queued(&connection, PURGE_EXPIRED_EVENTS_JOB).unwrap());
+    }
+}
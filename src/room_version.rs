@@ -0,0 +1,38 @@
+//! The room versions this server supports.
+//!
+//! The version of a room is set by the `room_version` of its `m.room.create` event, which
+//! defaults to `1` if it is missing. Rooms of versions not listed here can't be created or
+//! accepted over federation, and are rejected with `M_UNSUPPORTED_ROOM_VERSION`.
+
+use error::ApiError;
+
+/// The room versions this server supports.
+pub const SUPPORTED_ROOM_VERSIONS: &'static [&'static str] = &["1"];
+
+/// The version of new rooms, and of rooms whose `m.room.create` event has no `room_version`.
+pub const DEFAULT_ROOM_VERSION: &'static str = "1";
+
+/// Checks that the room version is supported.
+pub fn check(room_version: &str) -> Result<(), ApiError> {
+    if SUPPORTED_ROOM_VERSIONS.contains(&room_version) {
+        Ok(())
+    } else {
+        Err(ApiError::unsupported_room_version(format!(
+            "Room version {} is not supported, only {}.",
+            room_version,
+            SUPPORTED_ROOM_VERSIONS.join(", ")
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DEFAULT_ROOM_VERSION, check};
+
+    #[test]
+    fn supported_room_versions() {
+        assert!(check(DEFAULT_ROOM_VERSION).is_ok());
+        assert!(check("").is_err());
+        assert!(check("999").is_err());
+    }
+}
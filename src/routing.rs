@@ -0,0 +1,296 @@
+//! Routing of requests to endpoints.
+
+use std::sync::Arc;
+
+use iron::{Handler, IronError, IronResult, Request, Response};
+use iron::headers::Allow;
+use iron::method::Method;
+use iron::typemap::Key;
+use router::{NoRoute, Router};
+
+use error::ApiError;
+
+/// A `Router` that remembers the path templates and methods of its routes.
+///
+/// Requests for a known path with a method none of its routes accept are answered with
+/// `405 Method Not Allowed`, an `Allow` header listing the supported methods, and
+/// `M_UNRECOGNIZED`. Requests for unknown paths fail with the router's `NoRoute` error as before.
+pub struct Routes {
+    router: Router,
+    routes: Vec<(Method, String)>,
+}
+
+impl Routes {
+    /// Creates a new `Routes` without any routes.
+    pub fn new() -> Self {
+        Routes {
+            router: Router::new(),
+            routes: Vec::new(),
+        }
+    }
+
+    /// Adds a route for GET requests.
+    pub fn get<H: Handler>(&mut self, glob: &str, handler: H, route_id: &str) -> &mut Self {
+        self.route(Method::Get, glob, handler, route_id)
+    }
+
+    /// Adds a route for POST requests.
+    pub fn post<H: Handler>(&mut self, glob: &str, handler: H, route_id: &str) -> &mut Self {
+        self.route(Method::Post, glob, handler, route_id)
+    }
+
+    /// Adds a route for PUT requests.
+    pub fn put<H: Handler>(&mut self, glob: &str, handler: H, route_id: &str) -> &mut Self {
+        self.route(Method::Put, glob, handler, route_id)
+    }
+
+    /// Adds a route for DELETE requests.
+    pub fn delete<H: Handler>(&mut self, glob: &str, handler: H, route_id: &str) -> &mut Self {
+        self.route(Method::Delete, glob, handler, route_id)
+    }
+
+    /// Adds a route for requests with the given method.
+    pub fn route<H: Handler>(&mut self, method: Method, glob: &str, handler: H, route_id: &str)
+    -> &mut Self {
+        self.router.route(method.clone(), glob, handler, route_id);
+        self.routes.push((method, glob.to_string()));
+
+        self
+    }
+
+    /// The methods of all routes whose template matches the path.
+    pub fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let mut methods: Vec<Method> = Vec::new();
+
+        for &(ref method, ref glob) in &self.routes {
+            if matches(glob, path) && !methods.contains(method) {
+                methods.push(method.clone());
+            }
+        }
+
+        methods
+    }
+}
+
+impl Handler for Routes {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let error = match self.router.handle(request) {
+            Ok(response) => return Ok(response),
+            Err(error) => error,
+        };
+
+        if error.error.downcast_ref::<NoRoute>().is_none() {
+            return Err(error);
+        }
+
+        let methods = self.allowed_methods(&request.url.path().join("/"));
+
+        if methods.is_empty() {
+            return Err(error);
+        }
+
+        let mut error = IronError::from(ApiError::method_not_allowed(None));
+        error.response.headers.set(Allow(methods));
+
+        Err(error)
+    }
+}
+
+/// A version of the client API, identified by the path prefix a request was made to.
+///
+/// `VersionedApi` stores it in the request's extensions, so endpoints that behave differently
+/// between versions can look it up with `request_ext::extension`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientApiVersion {
+    /// The `r0` prefix of the Matrix r0.x.x specifications.
+    R0,
+    /// The `v3` prefix of the Matrix v1.x specifications.
+    V3,
+}
+
+impl ClientApiVersion {
+    /// Every version the client API is served under.
+    pub fn all() -> [ClientApiVersion; 2] {
+        [ClientApiVersion::R0, ClientApiVersion::V3]
+    }
+
+    /// The path the version's endpoints are mounted at.
+    pub fn prefix(&self) -> &'static str {
+        match *self {
+            ClientApiVersion::R0 => "/_matrix/client/r0/",
+            ClientApiVersion::V3 => "/_matrix/client/v3/",
+        }
+    }
+}
+
+impl Key for ClientApiVersion {
+    type Value = ClientApiVersion;
+}
+
+/// An API shared by several versions, mounted once per version.
+///
+/// Notes the version of the prefix the request was made to before handling it.
+pub struct VersionedApi {
+    version: ClientApiVersion,
+    handler: Arc<Handler>,
+}
+
+impl VersionedApi {
+    /// Creates a new `VersionedApi` serving `handler` as the given version.
+    pub fn new(version: ClientApiVersion, handler: Arc<Handler>) -> Self {
+        VersionedApi {
+            version: version,
+            handler: handler,
+        }
+    }
+}
+
+impl Handler for VersionedApi {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        request.extensions.insert::<ClientApiVersion>(self.version);
+
+        self.handler.handle(request)
+    }
+}
+
+/// Whether or not a route template like `/rooms/:room_id/members` matches the path.
+///
+/// Parameters match any non-empty segment, and a trailing `*` matches the rest of the path.
+fn matches(glob: &str, path: &str) -> bool {
+    let mut glob_segments = glob.trim_left_matches('/').split('/');
+    let mut path_segments = path.trim_left_matches('/').split('/');
+
+    loop {
+        match (glob_segments.next(), path_segments.next()) {
+            (None, None) => return true,
+            (Some("*"), Some(_)) => return true,
+            (Some(glob_segment), Some(path_segment)) => {
+                let is_match = if glob_segment.starts_with(':') {
+                    !path_segment.is_empty()
+                } else {
+                    glob_segment == path_segment
+                };
+
+                if !is_match {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use iron::{Handler, IronResult, Request, Response};
+    use iron::headers::{Allow, Headers};
+    use iron::method::Method;
+    use iron::status::Status;
+    use iron_test::{request, response};
+
+    use request_ext::extension;
+    use test::Test;
+    use super::{ClientApiVersion, VersionedApi, matches};
+
+    #[test]
+    fn templates_match_paths() {
+        assert!(matches("/sync", "sync"));
+        assert!(matches("/rooms/:room_id/members", "rooms/!abc:ruma.test/members"));
+        assert!(matches("/media/*", "media/download/ruma.test/abc"));
+        assert!(!matches("/rooms/:room_id/members", "rooms//members"));
+        assert!(!matches("/rooms/:room_id/members", "rooms/!abc:ruma.test"));
+        assert!(!matches("/sync", "sync/extra"));
+    }
+
+    #[test]
+    fn wrong_method_is_not_allowed() {
+        let test = Test::new();
+        let user = test.create_user();
+        let room_id = test.create_room(&user.token);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1?access_token={}",
+            room_id,
+            user.token
+        ));
+
+        assert_eq!(response.status, Status::MethodNotAllowed);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_UNRECOGNIZED");
+        assert_eq!(response.headers.get::<Allow>(), Some(&Allow(vec![Method::Put])));
+    }
+
+    #[test]
+    fn unknown_client_endpoint_is_unrecognized() {
+        let test = Test::new();
+        let response = test.get("/_matrix/client/r0/snyc");
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_UNRECOGNIZED");
+        assert!(response.headers.get::<Allow>().is_none());
+    }
+
+    #[test]
+    fn unknown_path_is_plain_not_found() {
+        let test = Test::new();
+        let response = test.get("/no/such/path");
+
+        assert_eq!(response.status, Status::NotFound);
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn versioned_api_notes_version() {
+        struct ShowVersion;
+
+        impl Handler for ShowVersion {
+            fn handle(&self, request: &mut Request) -> IronResult<Response> {
+                let version = extension::<ClientApiVersion>(request)?;
+
+                Ok(Response::with((Status::Ok, format!("{:?}", version))))
+            }
+        }
+
+        let api = VersionedApi::new(ClientApiVersion::V3, Arc::new(ShowVersion));
+        let response = request::get("http://localhost:3000/sync", Headers::new(), &api).unwrap();
+
+        assert_eq!(response::extract_body_to_string(response), "V3");
+    }
+
+    #[test]
+    fn endpoints_are_served_under_r0_and_v3() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let response = test.post(
+            &format!("/_matrix/client/v3/createRoom?access_token={}", user.token),
+            r#"{"name": "Versions"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+        let room_id = response.json().get("room_id").unwrap().as_str().unwrap().to_string();
+
+        let responses: Vec<_> = ClientApiVersion::all().iter().map(|version| {
+            test.get(&format!(
+                "{}rooms/{}/state/m.room.name?access_token={}",
+                version.prefix(),
+                room_id,
+                user.token
+            ))
+        }).collect();
+
+        assert_eq!(responses[0].status, Status::Ok);
+        assert_eq!(responses[0].status, responses[1].status);
+        assert_eq!(responses[0].json(), responses[1].json());
+
+        let response = test.get(&format!(
+            "/_matrix/client/v3/rooms/{}/send/m.room.message/1?access_token={}",
+            room_id,
+            user.token
+        ));
+        assert_eq!(response.status, Status::MethodNotAllowed);
+
+        let response = test.get("/_matrix/client/v3/snyc");
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_UNRECOGNIZED");
+    }
+}
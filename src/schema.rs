@@ -12,6 +12,7 @@ table! {
         revoked -> Bool,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        device_id -> Text,
     }
 }
 
@@ -24,6 +25,28 @@ table! {
     }
 }
 
+table! {
+    background_jobs {
+        id -> BigSerial,
+        kind -> Text,
+        payload -> Text,
+        run_at -> Timestamp,
+        attempts -> Integer,
+        locked_by -> Nullable<Text>,
+        locked_at -> Nullable<Timestamp>,
+        last_error -> Nullable<Text>,
+        dead -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    event_edges (event_id, prev_event_id) {
+        event_id -> Text,
+        prev_event_id -> Text,
+    }
+}
+
 table! {
     events {
         id -> Text,
@@ -35,6 +58,9 @@ table! {
         content -> Text,
         extra_content -> Nullable<Text>,
         created_at -> Timestamp,
+        depth -> BigInt,
+        soft_failed -> Bool,
+        pdu -> Nullable<Text>,
     }
 }
 
@@ -46,6 +72,21 @@ table! {
     }
 }
 
+table! {
+    public_room_directory (room_id) {
+        room_id -> Text,
+        name -> Nullable<Text>,
+        topic -> Nullable<Text>,
+        canonical_alias -> Nullable<Text>,
+        avatar_url -> Nullable<Text>,
+        guest_can_join -> Bool,
+        world_readable -> Bool,
+        num_joined_members -> BigInt,
+        aliases -> Array<Text>,
+        related_groups -> Nullable<Text>,
+    }
+}
+
 table! {
     room_aliases (alias) {
         alias -> Text,
@@ -57,6 +98,16 @@ table! {
     }
 }
 
+table! {
+    room_current_state (room_id, event_type, state_key) {
+        room_id -> Text,
+        event_type -> Text,
+        state_key -> Text,
+        event_id -> Text,
+        etag -> Text,
+    }
+}
+
 table! {
     room_memberships (event_id) {
         event_id -> Text,
@@ -77,6 +128,27 @@ table! {
     }
 }
 
+table! {
+    user_ips (access_token_id, ip, user_agent) {
+        access_token_id -> BigInt,
+        user_id -> Text,
+        device_id -> Text,
+        ip -> Text,
+        user_agent -> Text,
+        last_seen -> Timestamp,
+    }
+}
+
+table! {
+    user_threepids (medium, address) {
+        medium -> Text,
+        address -> Text,
+        user_id -> Text,
+        validated_at -> Nullable<Timestamp>,
+        added_at -> Timestamp,
+    }
+}
+
 table! {
     users {
         id -> Text,
@@ -84,6 +156,7 @@ table! {
         active -> Bool,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        admin -> Bool,
     }
 }
 
@@ -108,10 +181,12 @@ table! {
 }
 
 table! {
-    transactions (path, access_token) {
+    transactions (method, path, access_token) {
         path -> Text,
         access_token -> Text,
-        response -> Text,
+        response -> Nullable<Text>,
+        created_at -> Timestamp,
+        method -> Text,
     }
 }
 
@@ -153,3 +228,135 @@ table! {
         app_display_name -> Text,
     }
 }
+
+table! {
+    remote_alias_cache (alias) {
+        alias -> Text,
+        room_id -> Text,
+        servers -> Array<Text>,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    receipts (room_id, user_id, receipt_type) {
+        room_id -> Text,
+        user_id -> Text,
+        receipt_type -> Text,
+        event_id -> Text,
+        private -> Bool,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    room_member_counts (room_id) {
+        room_id -> Text,
+        joined -> BigInt,
+        invited -> BigInt,
+    }
+}
+
+table! {
+    to_device_messages {
+        id -> BigSerial,
+        user_id -> Text,
+        device_id -> Text,
+        sender -> Text,
+        event_type -> Text,
+        content -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    groups {
+        id -> Text,
+        name -> Nullable<Text>,
+        avatar_url -> Nullable<Text>,
+        short_description -> Nullable<Text>,
+        long_description -> Nullable<Text>,
+        public -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    group_rooms (group_id, room_id) {
+        group_id -> Text,
+        room_id -> Text,
+        public -> Bool,
+    }
+}
+
+table! {
+    rejected_events {
+        id -> BigSerial,
+        event_id -> Nullable<Text>,
+        room_id -> Nullable<Text>,
+        origin -> Text,
+        event_json -> Text,
+        reason -> Text,
+        rejected_at -> Timestamp,
+    }
+}
+
+table! {
+    admin_metrics (metric_name, ts) {
+        ts -> Timestamp,
+        metric_name -> Text,
+        value -> BigInt,
+    }
+}
+
+table! {
+    key_backup_versions (user_id, version) {
+        user_id -> Text,
+        version -> BigInt,
+        algorithm -> Text,
+        auth_data -> Text,
+        etag -> BigInt,
+        deleted -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    key_backup (user_id, version, room_id, session_id) {
+        user_id -> Text,
+        version -> BigInt,
+        room_id -> Text,
+        session_id -> Text,
+        first_message_index -> BigInt,
+        forwarded_count -> BigInt,
+        is_verified -> Bool,
+        session_data -> Text,
+    }
+}
+
+table! {
+    remote_profiles (user_id) {
+        user_id -> Text,
+        avatar_url -> Nullable<Text>,
+        displayname -> Nullable<Text>,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    push_rule_overrides (user_id, kind, rule_id) {
+        user_id -> Text,
+        kind -> Text,
+        rule_id -> Text,
+        enabled -> Bool,
+    }
+}
+
+table! {
+    claimed_megolm_sessions (user_id, device_id, room_id, session_id) {
+        user_id -> Text,
+        device_id -> Text,
+        room_id -> Text,
+        session_id -> Text,
+    }
+}
@@ -1,29 +1,85 @@
 //! Iron web server that serves the API.
 use diesel::migrations::setup_database;
 use diesel::pg::PgConnection;
-use iron::{Chain, Iron, IronError, IronResult, Listening, Request, Response};
-use iron::error::HttpResult;
+use std::io::Result as IoResult;
+use std::net::{SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use hyper::Result as HyperResult;
+use hyper::net::{HttpListener, HttpsListener, NetworkListener};
+use hyper_native_tls::NativeTlsServer;
+use iron::{Chain, Handler, Iron, IronError, IronResult, Listening, Protocol, Request, Response};
 use mount::Mount;
-use persistent::{Read, Write};
-use r2d2::Config as R2D2Config;
-use r2d2_diesel::Error as R2D2DieselError;
-use router::Router;
+use persistent::Read;
+use r2d2::{Config as R2D2Config, Pool};
+use r2d2_diesel::{ConnectionManager, Error as R2D2DieselError};
+use serde_json::Value;
 
+use access_token_cache::AccessTokenCache;
+use clock::{Clock, ServerClock, SystemClock};
+use api::admin::v1::{
+    DeleteDevices,
+    ForceJoin,
+    GetDevices,
+    GetMetrics,
+    GetMonthlyActiveUsers,
+    GetRejectedEvents,
+    PurgeHistory,
+    RemoveUser,
+    Whois,
+};
+use api::federation::v1::{
+    GetPublicRooms,
+    MakeKnock,
+    MakeLeave,
+    QueryDirectory,
+    QueryProfile,
+    SendKnock,
+    SendLeave,
+    SendTransaction,
+    Version,
+};
+use api::identity::v2::{HashDetails, Lookup};
 use api::r0::{
     AccountPassword,
+    BatchSendEvents,
+    ClaimMegolmSessions,
+    Context,
+    CreateKeyBackupVersion,
     CreateRoom,
     DeactivateAccount,
+    DeleteAccountData,
+    DeleteKeyBackupVersion,
     DeleteRoomAlias,
     DeleteTag,
+    GetAllRoomAccountData,
+    GetAllTags,
     GetAvatarUrl,
+    GetBackgroundJobs,
     GetDisplayName,
     GetFilter,
+    GetKeyBackupVersion,
     GetPresenceList,
     GetPresenceStatus,
+    GetPublicRooms as GetClientPublicRooms,
+    GetPushRuleEnabled,
     GetPushers,
+    GetRelatedGroups,
     GetRoomAlias,
+    GetRoomAliases,
+    GetRoomKeys,
+    GetRoomNotifications,
+    GetStateEvent,
     GetTags,
+    GetThreePids,
+    GetWorkers,
     InviteToRoom,
+    InvitedMembers,
     JoinRoom,
     JoinRoomWithIdOrAlias,
     KickFromRoom,
@@ -31,49 +87,153 @@ use api::r0::{
     Login,
     Logout,
     Members,
+    Messages,
     PostFilter,
     PostPresenceList,
+    PostPublicRooms,
+    PrivateReadReceipt,
     Profile,
     PutAccountData,
     PutAvatarUrl,
     PutDisplayName,
     PutPresenceStatus,
+    PutPushRuleEnabled,
     PutRoomAccountData,
     PutRoomAlias,
+    PutRoomKey,
+    PutRoomKeys,
     PutTag,
     Register,
     RoomState,
     SendMessageEvent,
+    SendToDevice,
     SetPushers,
     StateMessageEvent,
     Sync,
+    TimestampToEvent,
+    UnbanFromRoom,
     Versions,
 };
-use config::Config;
+use config::{Config, ListenerConfig, Resource};
 use embedded_migrations::run as run_pending_migrations;
+use federation::sender;
+use jobs::{self, JobRegistry, WorkerPool};
+use metrics;
 use error::{ApiError, CliError};
 use db::DB;
-use middleware::{ResponseHeaders, MiddlewareChain};
+use models::user::User;
+use middleware::{
+    BodyLimit,
+    CatchPanics,
+    Compression,
+    InFlightRequests,
+    JsonErrors,
+    MiddlewareChain,
+    NormalizePath,
+    RequestLogger,
+    RespondToErrors,
+    ResponseHeaders,
+    TrustedProxies,
+};
+use notifier::Notifier;
+use redaction;
+use retention;
+use routing::{ClientApiVersion, Routes, VersionedApi};
+use shutdown::{Shutdown, ShuttingDown};
+use state_cache::StateCache;
 use swagger::Swagger;
 
+/// The kinds of background jobs whose queues are flushed before the server exits.
+const FLUSHED_ON_SHUTDOWN: &'static [&'static str] = &[sender::SEND_EVENT_JOB];
+
 /// Ruma's web server.
 pub struct Server<'a> {
+    access_token_cache: AccessTokenCache,
+    clock: Arc<Clock>,
     config: &'a Config,
-    mount: Mount,
+    connection_pool: Option<Pool<ConnectionManager<PgConnection>>>,
+    job_registry: JobRegistry,
+    mounted_apis: Vec<MountedApi>,
+    notifier: Notifier,
+    shutdown: Shutdown,
+    state_cache: StateCache,
+}
+
+/// A listener serving requests on its own threads until it is closed.
+///
+/// Closing hyper's `Listening` doesn't stop its threads from accepting connections, so closing
+/// shuts the listening socket down instead, and the threads stop accepting.
+pub struct ServerListening {
+    listening: Listening,
+    socket: TcpListener,
+    closed: Arc<AtomicBool>,
+}
+
+/// A `NetworkListener` whose threads stop accepting connections once its socket was closed.
+#[derive(Clone)]
+struct ClosableListener<L> {
+    listener: L,
+    closed: Arc<AtomicBool>,
+}
+
+/// An API mounted on the server, served by the listeners that include its resource.
+struct MountedApi {
+    resource: Resource,
+    path: &'static str,
+    handler: Arc<Handler>,
 }
 
 impl<'a> Server<'a> {
     /// Create a new `Server` from a `Config`.
     pub fn new(config: &'a Config) -> Self {
+        Server::with_clock(config, Arc::new(SystemClock))
+    }
+
+    /// Create a new `Server` from a `Config` that takes the current time from the given clock.
+    pub fn with_clock(config: &'a Config, clock: Arc<Clock>) -> Self {
+        redaction::set_log_access_tokens(config.log_access_tokens);
+
+        let notifier = Notifier::with_max_waiters(config.sync_workers);
+        let mut job_registry = JobRegistry::new();
+
+        sender::register_jobs(&mut job_registry, config, clock.clone());
+        metrics::register_jobs(&mut job_registry, clock.clone());
+
+        if let Some(ref retention) = config.retention {
+            retention::register_jobs(&mut job_registry, retention, clock.clone());
+        }
+
         Server {
+            access_token_cache: AccessTokenCache::new(
+                config.access_token_cache_size,
+                Duration::from_secs(config.access_token_cache_ttl),
+                clock.clone(),
+            ),
+            clock: clock,
             config,
-            mount: Mount::new(),
+            connection_pool: None,
+            job_registry: job_registry,
+            mounted_apis: Vec::new(),
+            notifier: notifier.clone(),
+            shutdown: Shutdown::new(notifier),
+            state_cache: StateCache::new(config.state_cache_size),
         }
     }
 
+    /// Uses an existing connection pool instead of connecting to PostgreSQL when mounting.
+    pub fn with_connection_pool(mut self, connection_pool: Pool<ConnectionManager<PgConnection>>)
+    -> Self {
+        self.connection_pool = Some(connection_pool);
+        self
+    }
+
     /// Mount all APIs.
     pub fn mount_all(self) -> Result<Self, CliError> {
-        self.mount_extra().mount_client()
+        self.mount_extra()
+            .mount_client()?
+            .mount_federation()?
+            .mount_admin()?
+            .mount_identity()
     }
 
     /// Mount all APIs with some extra options.
@@ -82,12 +242,16 @@ impl<'a> Server<'a> {
         r2d2_config: R2D2Config<PgConnection, R2D2DieselError>,
         set_up_db: bool,
     ) -> Result<Self, CliError> {
-        self.mount_extra().mount_client_with_options(r2d2_config, set_up_db)
+        self.mount_extra()
+            .mount_client_with_options(r2d2_config, set_up_db)?
+            .mount_federation()?
+            .mount_admin()?
+            .mount_identity()
     }
 
     /// Mount the client APIs.
     pub fn mount_client(self) -> Result<Self, CliError> {
-        self.mount_client_with_options(R2D2Config::default(), true)
+        self.mount_client_with_options(DB::r2d2_config(self.config), true)
     }
 
     /// Mount the client APIs with some extra options.
@@ -96,10 +260,14 @@ impl<'a> Server<'a> {
         r2d2_config: R2D2Config<PgConnection, R2D2DieselError>,
         set_up_db: bool,
     ) -> Result<Self, CliError> {
-        let mut r0_router = Router::new();
+        let mut r0_router = Routes::new();
 
         r0_router.post("/account/password", AccountPassword::chain(), "account_password");
         r0_router.post("/account/deactivate", DeactivateAccount::chain(), "deactivate_account");
+        r0_router.get("/account/3pid", GetThreePids::chain(), "get_threepids");
+        r0_router.get("/admin/background_jobs", GetBackgroundJobs::chain(), "get_background_jobs");
+        r0_router.get("/admin/whois/:user_id", Whois::chain(), "admin_whois");
+        r0_router.get("/admin/workers", GetWorkers::chain(), "get_workers");
         r0_router.post("/createRoom", CreateRoom::chain(), "create_room");
         r0_router.get("/directory/room/:room_alias", GetRoomAlias::chain(), "get_room_alias");
         r0_router.delete(
@@ -108,6 +276,8 @@ impl<'a> Server<'a> {
             "delete_room_alias",
         );
         r0_router.put("/directory/room/:room_alias", PutRoomAlias::chain(), "put_room_alias");
+        r0_router.get("/publicRooms", GetClientPublicRooms::chain(), "get_public_rooms");
+        r0_router.post("/publicRooms", PostPublicRooms::chain(), "post_public_rooms");
         r0_router.post("/login", Login::chain(), "login");
         r0_router.post("/logout", Logout::chain(), "logout");
         r0_router.post("/register", Register::chain(), "register");
@@ -117,6 +287,16 @@ impl<'a> Server<'a> {
             PutAccountData::chain(),
             "put_account_data",
         );
+        r0_router.delete(
+            "/user/:user_id/account_data/:type",
+            DeleteAccountData::chain(),
+            "delete_account_data",
+        );
+        r0_router.get(
+            "/user/:user_id/account_data_all_rooms",
+            GetAllRoomAccountData::chain(),
+            "get_all_room_account_data",
+        );
         r0_router.put(
             "/user/:user_id/rooms/:room_id/account_data/:type",
             PutRoomAccountData::chain(),
@@ -127,6 +307,7 @@ impl<'a> Server<'a> {
             SendMessageEvent::chain(),
             "send_message_event",
         );
+        r0_router.post("/rooms/:room_id/batch_send", BatchSendEvents::chain(), "batch_send_events");
         r0_router.put(
             "/rooms/:room_id/state/:event_type",
             StateMessageEvent::chain(),
@@ -141,14 +322,55 @@ impl<'a> Server<'a> {
         r0_router.post("/rooms/:room_id/invite", InviteToRoom::chain(), "invite_to_room");
         r0_router.post("/join/:room_id_or_alias", JoinRoomWithIdOrAlias::chain(), "join_room_with_alias");
         r0_router.post("rooms/:room_id/kick", KickFromRoom::chain(), "kick_from_room");
+        r0_router.post("rooms/:room_id/unban", UnbanFromRoom::chain(), "unban_from_room");
         r0_router.post("rooms/:room_id/leave", LeaveRoom::chain(), "leave_room");
         r0_router.get("/rooms/:room_id/members", Members::chain(), "members");
+        r0_router.get(
+            "/rooms/:room_id/invited_members",
+            InvitedMembers::chain(),
+            "invited_members",
+        );
+        r0_router.get("/rooms/:room_id/messages", Messages::chain(), "messages");
+        r0_router.post(
+            "/rooms/:room_id/megolm_sessions",
+            ClaimMegolmSessions::chain(),
+            "claim_megolm_sessions",
+        );
+        r0_router.get("/rooms/:room_id/context/:event_id", Context::chain(), "context");
+        r0_router.get(
+            "/rooms/:room_id/notifications",
+            GetRoomNotifications::chain(),
+            "get_room_notifications",
+        );
+        r0_router.get("/rooms/:room_id/aliases", GetRoomAliases::chain(), "get_room_aliases");
+        r0_router.get(
+            "/rooms/:room_id/related_groups",
+            GetRelatedGroups::chain(),
+            "get_related_groups",
+        );
+        r0_router.get(
+            "/rooms/:room_id/timestamp_to_event",
+            TimestampToEvent::chain(),
+            "timestamp_to_event",
+        );
+        r0_router.post(
+            "/rooms/:room_id/receipt/m.read.private/:event_id",
+            PrivateReadReceipt::chain(),
+            "private_read_receipt",
+        );
         r0_router.get("/rooms/:room_id/state", RoomState::chain(), "get_room_state");
+        r0_router.get("/rooms/:room_id/state/:event_type", GetStateEvent::chain(), "get_state_event");
+        r0_router.get(
+            "/rooms/:room_id/state/:event_type/:state_key",
+            GetStateEvent::chain(),
+            "get_state_event_with_key",
+        );
         r0_router.get("/profile/:user_id", Profile::chain(), "profile");
         r0_router.get("/profile/:user_id/avatar_url", GetAvatarUrl::chain(), "get_avatar_url");
         r0_router.get("/profile/:user_id/displayname", GetDisplayName::chain(), "get_display_name");
         r0_router.put("/profile/:user_id/avatar_url", PutAvatarUrl::chain(), "put_avatar_url");
         r0_router.put("/profile/:user_id/displayname", PutDisplayName::chain(), "put_display_name");
+        r0_router.get("/user/:user_id/tags", GetAllTags::chain(), "get_all_tags");
         r0_router.get("/user/:user_id/rooms/:room_id/tags", GetTags::chain(), "get_tags");
         r0_router.put("/user/:user_id/rooms/:room_id/tags/:tag", PutTag::chain(), "add_tag");
         r0_router.delete("/user/:user_id/rooms/:room_id/tags/:tag", DeleteTag::chain(), "delete_tag");
@@ -159,10 +381,406 @@ impl<'a> Server<'a> {
         r0_router.put("/presence/:user_id/status", PutPresenceStatus::chain(), "put_presence_status");
         r0_router.get("/presence/list/:user_id", GetPresenceList::chain(), "get_presence_list");
         r0_router.post("/presence/list/:user_id", PostPresenceList::chain(), "post_presence_list");
+        r0_router.put(
+            "/sendToDevice/:event_type/:transaction_id",
+            SendToDevice::chain(),
+            "send_to_device",
+        );
+        r0_router.get(
+            "/pushrules/global/:kind/:rule_id/enabled",
+            GetPushRuleEnabled::chain(),
+            "get_push_rule_enabled",
+        );
+        r0_router.put(
+            "/pushrules/global/:kind/:rule_id/enabled",
+            PutPushRuleEnabled::chain(),
+            "put_push_rule_enabled",
+        );
         r0_router.get("/pushers", GetPushers::chain(), "pushers");
         r0_router.post("/pushers/set", SetPushers::chain(), "set_pushers");
+        r0_router.post(
+            "/room_keys/version",
+            CreateKeyBackupVersion::chain(),
+            "create_key_backup_version",
+        );
+        r0_router.get("/room_keys/version", GetKeyBackupVersion::chain(), "get_key_backup_version");
+        r0_router.delete(
+            "/room_keys/version/:version",
+            DeleteKeyBackupVersion::chain(),
+            "delete_key_backup_version",
+        );
+        r0_router.get("/room_keys/keys", GetRoomKeys::chain(), "get_room_keys");
+        r0_router.put("/room_keys/keys", PutRoomKeys::chain(), "put_room_keys");
+        r0_router.put("/room_keys/keys/:room_id/:session_id", PutRoomKey::chain(), "put_room_key");
 
         let mut r0 = Chain::new(r0_router);
+        r0.link_around(CatchPanics);
+
+        let connection_pool = self.ensure_connection_pool(r2d2_config, set_up_db)?;
+
+        r0.link_before(self.request_logger());
+        r0.link_before(self.trusted_proxies());
+        r0.link_before(InFlightRequests(self.shutdown.clone()));
+        r0.link_before(Read::<Config>::one(self.config.clone()));
+        r0.link_before(Read::<ServerClock>::one(self.clock.clone()));
+        r0.link_before(Read::<DB>::one(connection_pool));
+        r0.link_before(Read::<Notifier>::one(self.notifier.clone()));
+        r0.link_before(Read::<Shutdown>::one(self.shutdown.clone()));
+        r0.link_before(Read::<ShuttingDown>::one(self.shutdown.flag()));
+        r0.link_before(Read::<StateCache>::one(self.state_cache.clone()));
+        r0.link_before(Read::<AccessTokenCache>::one(self.access_token_cache.clone()));
+        r0.link_before(self.body_limit());
+        r0.link_after(InFlightRequests(self.shutdown.clone()));
+        r0.link_after(self.notifier.clone());
+        r0.link_after(JsonErrors);
+        r0.link_after(ResponseHeaders);
+        r0.link_after(Compression);
+        r0.link_after(self.request_logger());
+
+        let mut versions_router = Routes::new();
+
+        versions_router.get("/versions", Versions::supported(self.config), "versions");
+
+        let mut versions = Chain::new(versions_router);
+        versions.link_around(CatchPanics);
+        versions.link_before(self.request_logger());
+        versions.link_after(JsonErrors);
+        versions.link_after(ResponseHeaders);
+        versions.link_after(Compression);
+        versions.link_after(self.request_logger());
+
+        self.mount_api(Resource::Client, "/_matrix/client/", versions);
+        self.mount_client_versions(r0);
+
+        Ok(self)
+    }
+
+    /// Mount the federation APIs.
+    ///
+    /// Reuses the connection pool of the client APIs if they are mounted first.
+    pub fn mount_federation(mut self) -> Result<Self, CliError> {
+        let mut v1_router = Routes::new();
+
+        v1_router.get("/make_knock/:room_id/:user_id", MakeKnock::chain(), "make_knock");
+        v1_router.get("/make_leave/:room_id/:user_id", MakeLeave::chain(), "make_leave");
+        v1_router.get("/publicRooms", GetPublicRooms::chain(), "public_rooms");
+        v1_router.get("/query/directory", QueryDirectory::chain(), "query_directory");
+        v1_router.get("/query/profile", QueryProfile::chain(), "query_profile");
+        v1_router.put("/send/:transaction_id", SendTransaction::chain(), "send_transaction");
+        v1_router.put("/send_knock/:room_id/:event_id", SendKnock::chain(), "send_knock");
+        v1_router.put("/send_leave/:room_id/:event_id", SendLeave::chain(), "send_leave");
+        v1_router.get("/version", Version::current(), "version");
+
+        let v1 = self.api_chain(v1_router)?;
+
+        self.mount_api(Resource::Federation, "/_matrix/federation/v1/", v1);
+
+        Ok(self)
+    }
+
+    /// Mount the admin APIs.
+    ///
+    /// Reuses the connection pool of the client APIs if they are mounted first.
+    pub fn mount_admin(mut self) -> Result<Self, CliError> {
+        let mut v1_router = Routes::new();
+
+        v1_router.get("/users/:user_id/devices", GetDevices::chain(), "get_devices");
+        v1_router.post("/users/:user_id/delete_devices", DeleteDevices::chain(), "delete_devices");
+        v1_router.post("/join/:room_id", ForceJoin::chain(), "force_join");
+        v1_router.post("/purge_history/:room_id", PurgeHistory::chain(), "purge_history");
+        v1_router.get("/rejected_events", GetRejectedEvents::chain(), "rejected_events");
+        v1_router.post("/rooms/:room_id/remove_user", RemoveUser::chain(), "remove_user");
+        v1_router.get("/whois/:user_id", Whois::chain(), "whois");
+
+        let v1 = self.api_chain(v1_router)?;
+
+        self.mount_api(Resource::Admin, "/_synapse/admin/v1/", v1);
+
+        let mut matrix_v1_router = Routes::new();
+
+        matrix_v1_router.get("/metrics", GetMetrics::chain(), "metrics");
+        matrix_v1_router.get(
+            "/statistics/users/monthly_active",
+            GetMonthlyActiveUsers::chain(),
+            "monthly_active_users",
+        );
+
+        let matrix_v1 = self.api_chain(matrix_v1_router)?;
+
+        self.mount_api(Resource::Admin, "/_matrix/admin/v1/", matrix_v1);
+
+        Ok(self)
+    }
+
+    /// Mount the identity service APIs proxied to the configured identity server.
+    ///
+    /// Reuses the connection pool of the client APIs if they are mounted first.
+    pub fn mount_identity(mut self) -> Result<Self, CliError> {
+        let mut v2_router = Routes::new();
+
+        v2_router.get("/hash_details", HashDetails::chain(), "hash_details");
+        v2_router.post("/lookup", Lookup::chain(), "lookup");
+
+        let v2 = self.api_chain(v2_router)?;
+
+        self.mount_api(Resource::Identity, "/_matrix/identity/v2/", v2);
+
+        Ok(self)
+    }
+
+    /// Mount the extra APIs.
+    pub fn mount_extra(mut self) -> Self {
+        self.mount_api(Resource::Extra, "/ruma/swagger.json", Swagger::chain());
+
+        self
+    }
+
+    /// Registers the handler for background jobs of the given kind.
+    pub fn register_job<F>(&mut self, kind: &str, handler: F)
+    where F: Fn(&PgConnection, &Value) -> Result<(), ApiError> + Send + Sync + 'static {
+        self.job_registry.register(kind, handler);
+    }
+
+    /// The server's connection pool, if any APIs that use the database have been mounted.
+    pub fn connection_pool(&self) -> Option<Pool<ConnectionManager<PgConnection>>> {
+        self.connection_pool.clone()
+    }
+
+    /// The handle used to shut the server down gracefully.
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// The cache of access token lookups shared by all requests.
+    pub fn access_token_cache(&self) -> AccessTokenCache {
+        self.access_token_cache.clone()
+    }
+
+    /// The cache of current room state shared by all requests.
+    pub fn state_cache(&self) -> StateCache {
+        self.state_cache.clone()
+    }
+
+    /// Start a listener for each configured listener, serving the mounted APIs of its resources.
+    ///
+    /// The listeners share the APIs' handlers, and with them the connection pool and caches.
+    pub fn listen(&self) -> Result<Vec<ServerListening>, CliError> {
+        let mut listenings = Vec::new();
+
+        info!(
+            "Using {} worker threads and {} more for long-polling syncs per listener.",
+            self.config.worker_threads,
+            self.config.sync_workers
+        );
+
+        for listener in self.config.effective_listeners() {
+            listenings.push(self.start_listener(&listener)?);
+        }
+
+        Ok(listenings)
+    }
+
+    /// Run the server and block the current thread until stopped or interrupted.
+    ///
+    /// Background jobs are run by a pool of workers if any APIs that use the database have been
+    /// mounted. Server metrics are collected right away, and expired events are purged right away
+    /// if retention is configured.
+    ///
+    /// On SIGINT or SIGTERM, the server closes its listeners, wakes up long-polling syncs, waits
+    /// up to the configured grace period for in-flight requests, waits for the background workers
+    /// to finish their current jobs, and then runs the registered shutdown hooks. These include
+    /// sending the queued federation events, for up to another grace period. Requests still in
+    /// flight after that are abandoned.
+    pub fn run(self) -> Result<(), CliError> {
+        let mut listenings = self.listen()?;
+        let grace_period = Duration::from_secs(self.config.shutdown_grace_period);
+        let shutdown = self.shutdown.clone();
+
+        let workers = match self.connection_pool {
+            Some(connection_pool) => {
+                metrics::schedule_collection(&*connection_pool.get()?)?;
+
+                if self.config.retention.is_some() {
+                    retention::schedule_purge(&*connection_pool.get()?, &*self.clock)?;
+                }
+
+                debug!("Starting {} background workers.", self.config.background_workers);
+
+                let job_registry = Arc::new(self.job_registry);
+
+                jobs::flush_on_shutdown(
+                    &shutdown,
+                    connection_pool.clone(),
+                    job_registry.clone(),
+                    self.clock.clone(),
+                    FLUSHED_ON_SHUTDOWN,
+                    grace_period,
+                );
+
+                Some(WorkerPool::start(
+                    self.config.background_workers,
+                    connection_pool,
+                    job_registry,
+                    self.clock.clone(),
+                    shutdown.flag(),
+                ))
+            }
+            None => None,
+        };
+
+        shutdown.install_signal_handlers();
+        shutdown.wait_for_signal();
+
+        info!("Shutting down Ruma server.");
+        shutdown.begin();
+
+        for listening in &mut listenings {
+            listening.close()?;
+        }
+
+        if !shutdown.drain(grace_period) {
+            warn!("Some requests were still in flight when the grace period ended.");
+        }
+
+        if let Some(workers) = workers {
+            workers.join();
+        }
+
+        shutdown.run_hooks();
+
+        Ok(())
+    }
+
+    /// Mounts an API at the given path for the listeners serving its resource.
+    fn mount_api<H: Handler>(&mut self, resource: Resource, path: &'static str, handler: H) {
+        self.mounted_apis.push(MountedApi {
+            resource: resource,
+            path: path,
+            handler: Arc::new(handler),
+        });
+    }
+
+    /// Mounts the client API under the prefix of every `ClientApiVersion`.
+    fn mount_client_versions<H: Handler>(&mut self, handler: H) {
+        let handler = Arc::new(handler);
+
+        for version in &ClientApiVersion::all() {
+            let api = VersionedApi::new(*version, handler.clone());
+
+            self.mount_api(Resource::Client, version.prefix(), api);
+        }
+    }
+
+    /// A `Mount` with the mounted APIs of the given resources, routing normalized paths.
+    pub fn mount(&self, resources: &[Resource]) -> Chain {
+        let mut mount = Mount::new();
+
+        for api in self.mounted_apis.iter().filter(|api| resources.contains(&api.resource)) {
+            let handler = api.handler.clone();
+
+            mount.mount(api.path, move |request: &mut Request| handler.handle(request));
+        }
+
+        let mut chain = Chain::new(mount);
+        chain.link_before(NormalizePath);
+        chain.link_after(RespondToErrors);
+
+        chain
+    }
+
+    /// Starts serving the listener's resources on its address.
+    fn start_listener(&self, listener: &ListenerConfig) -> Result<ServerListening, CliError> {
+        let address = format!("{}:{}", listener.bind_address, listener.bind_port);
+
+        let mut iron = Iron::new(self.mount(&listener.resources));
+        iron.timeouts.read = Some(Duration::from_secs(self.config.request_read_timeout));
+        iron.threads = self.config.worker_threads + self.config.sync_workers;
+
+        let socket = TcpListener::bind(&address[..])?;
+        let http_listener = HttpListener::from(socket.try_clone()?);
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let listening = match listener.tls {
+            Some(ref tls) => {
+                let tls_server = NativeTlsServer::new(
+                    &tls.certificate_path,
+                    &tls.certificate_password,
+                ).map_err(|error| CliError::new(format!(
+                    "Failed to load the certificate `{}`: {}",
+                    tls.certificate_path,
+                    error
+                )))?;
+
+                info!("Starting Ruma server on https://{} for {:?}.", address, listener.resources);
+
+                let https_listener = HttpsListener::with_listener(http_listener, tls_server);
+
+                iron.listen(ClosableListener::new(https_listener, &closed), Protocol::https())?
+            }
+            None => {
+                info!("Starting Ruma server on http://{} for {:?}.", address, listener.resources);
+
+                iron.listen(ClosableListener::new(http_listener, &closed), Protocol::http())?
+            }
+        };
+
+        Ok(ServerListening {
+            listening: listening,
+            socket: socket,
+            closed: closed,
+        })
+    }
+
+    /// Wraps a router in the middleware shared by the APIs mounted besides the client APIs.
+    fn api_chain(&mut self, router: Routes) -> Result<Chain, CliError> {
+        let mut chain = Chain::new(router);
+        chain.link_around(CatchPanics);
+
+        let connection_pool = self.ensure_connection_pool(DB::r2d2_config(self.config), true)?;
+
+        chain.link_before(self.request_logger());
+        chain.link_before(self.trusted_proxies());
+        chain.link_before(InFlightRequests(self.shutdown.clone()));
+        chain.link_before(Read::<Config>::one(self.config.clone()));
+        chain.link_before(Read::<ServerClock>::one(self.clock.clone()));
+        chain.link_before(Read::<DB>::one(connection_pool));
+        chain.link_before(Read::<Shutdown>::one(self.shutdown.clone()));
+        chain.link_before(Read::<StateCache>::one(self.state_cache.clone()));
+        chain.link_before(Read::<AccessTokenCache>::one(self.access_token_cache.clone()));
+        chain.link_before(self.body_limit());
+        chain.link_after(InFlightRequests(self.shutdown.clone()));
+        chain.link_after(JsonErrors);
+        chain.link_after(ResponseHeaders);
+        chain.link_after(Compression);
+        chain.link_after(self.request_logger());
+
+        Ok(chain)
+    }
+
+    /// Creates the middleware that reads request bodies within the configured limits.
+    fn body_limit(&self) -> BodyLimit {
+        BodyLimit::new(self.config.max_request_size, self.config.request_read_timeout)
+    }
+
+    /// Creates the middleware that resolves the client's IP address behind reverse proxies.
+    fn trusted_proxies(&self) -> TrustedProxies {
+        TrustedProxies::new(self.config.trusted_proxies.clone())
+    }
+
+    /// Creates the middleware that assigns request IDs and logs completed requests.
+    fn request_logger(&self) -> RequestLogger {
+        RequestLogger::new(self.config.slow_request_threshold)
+    }
+
+    /// Returns the server's connection pool, connecting to PostgreSQL the first time.
+    fn ensure_connection_pool(
+        &mut self,
+        r2d2_config: R2D2Config<PgConnection, R2D2DieselError>,
+        set_up_db: bool,
+    ) -> Result<Pool<ConnectionManager<PgConnection>>, CliError> {
+        if let Some(ref connection_pool) = self.connection_pool {
+            return Ok(connection_pool.clone());
+        }
 
         debug!("Connecting to PostgreSQL.");
         let connection_pool = DB::create_connection_pool(r2d2_config, &self.config.postgres_url)?;
@@ -174,49 +792,300 @@ impl<'a> Server<'a> {
 
             debug!("Running pending database migrations.");
             run_pending_migrations(&*connection).map_err(CliError::from)?;
-        }
 
-        r0.link_before(Read::<Config>::one(self.config.clone()));
-        r0.link_before(Write::<DB>::one(connection_pool));
-        r0.link_after(ResponseHeaders);
+            debug!("Checking for user IDs that only differ in case.");
+            User::check_case_conflicts(&*connection).map_err(CliError::from)?;
+        }
 
-        let mut versions_router = Router::new();
+        self.connection_pool = Some(connection_pool.clone());
 
-        versions_router.get("/versions", Versions::supported(), "versions");
+        Ok(connection_pool)
+    }
 
-        let mut versions = Chain::new(versions_router);
-        versions.link_after(ResponseHeaders);
+    /// Moves out a `Mount` with all of the server's APIs. Useful for testing.
+    pub fn into_mount(self) -> Chain {
+        self.mount(&Resource::all())
+    }
 
-        self.mount.mount("/_matrix/client/", versions);
-        self.mount.mount("/_matrix/client/r0/", r0);
+    /// Moves out a `Mount` with all of the server's APIs along with the handlers for background
+    /// jobs. Useful for testing.
+    pub fn into_mount_and_job_registry(self) -> (Chain, JobRegistry) {
+        let mount = self.mount(&Resource::all());
 
-        Ok(self)
+        (mount, self.job_registry)
     }
+}
 
-    /// Mount the extra APIs.
-    pub fn mount_extra(mut self) -> Self {
-        self.mount.mount("/ruma/swagger.json", Swagger::chain());
+impl ServerListening {
+    /// Stops accepting connections. Requests being handled are not interrupted.
+    pub fn close(&mut self) -> Result<(), CliError> {
+        self.closed.store(true, Ordering::SeqCst);
 
-        self
+        // Shutting the socket down wakes up the threads waiting for a connection, and makes new
+        // connections fail.
+        #[cfg(unix)]
+        unsafe {
+            ::libc::shutdown(self.socket.as_raw_fd(), ::libc::SHUT_RDWR);
+        }
+
+        self.listening.close()?;
+
+        Ok(())
     }
+}
 
-    /// Run the server and block the current thread until stopped or interrupted.
-    pub fn run(self) -> HttpResult<Listening> {
-        let address = format!("{}:{}", self.config.bind_address, self.config.bind_port);
+impl<L> ClosableListener<L> {
+    /// Wraps a listener, which stops accepting connections once `closed` is set.
+    fn new(listener: L, closed: &Arc<AtomicBool>) -> Self {
+        ClosableListener {
+            listener: listener,
+            closed: closed.clone(),
+        }
+    }
+}
+
+impl<L: NetworkListener> NetworkListener for ClosableListener<L> {
+    type Stream = L::Stream;
 
-        info!("Starting Ruma server on {}.", address);
+    fn accept(&mut self) -> HyperResult<L::Stream> {
+        let stream = self.listener.accept();
 
-        let iron = Iron::new(self.mount);
+        // hyper accepts again right away when accepting fails, so the thread is parked instead
+        // of failing on the closed socket over and over. It ends with the process.
+        if self.closed.load(Ordering::SeqCst) {
+            drop(stream);
 
-        iron.http(&address[..])
+            loop {
+                thread::park();
+            }
+        }
+
+        stream
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.listener.local_addr()
     }
 
-    /// Moves out the server's `Mount`. Useful for testing.
-    pub fn into_mount(self) -> Mount {
-        self.mount
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.listener.set_read_timeout(timeout);
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.listener.set_write_timeout(timeout);
     }
 }
 
 fn deprecated(_: &mut Request) -> IronResult<Response> {
     Err(IronError::from(ApiError::unauthorized("tokenrefresh is no longer supported".to_string())))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use diesel::Connection as DieselConnection;
+    use diesel::pg::PgConnection;
+    use hyper::Client;
+    use hyper::header::Connection;
+    use hyper::status::StatusCode;
+    use r2d2::Config as R2D2Config;
+    use serde_json::{Value, from_reader};
+
+    use config::{ListenerConfig, Resource};
+    use test::{Test, TestTransactionConnectionCustomizer};
+    use super::Server;
+
+    /// A port nothing is listening on.
+    fn free_port() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        listener.local_addr().unwrap().port().to_string()
+    }
+
+    fn listener(port: &str, resources: Vec<Resource>) -> ListenerConfig {
+        ListenerConfig {
+            bind_address: "127.0.0.1".to_string(),
+            bind_port: port.to_string(),
+            tls: None,
+            resources: resources,
+        }
+    }
+
+    fn status(port: &str, path: &str) -> StatusCode {
+        Client::new().get(&format!("http://127.0.0.1:{}{}", port, path)).send().unwrap().status
+    }
+
+    #[test]
+    fn listeners_only_serve_their_resources() {
+        // Creates the test database.
+        let test = Test::new();
+
+        let client_port = free_port();
+        let federation_port = free_port();
+
+        let mut config = Test::config();
+        config.postgres_url = test.database_url();
+        config.listeners = vec![
+            listener(&client_port, vec![Resource::Client]),
+            listener(&federation_port, vec![Resource::Federation]),
+        ];
+
+        let server = Server::new(&config)
+            .mount_all_with_options(R2D2Config::default(), false)
+            .unwrap();
+
+        let mut listenings = server.listen().unwrap();
+
+        assert_eq!(status(&client_port, "/_matrix/client/versions"), StatusCode::Ok);
+        assert_eq!(status(&client_port, "/_matrix/federation/v1/version"), StatusCode::NotFound);
+        assert_eq!(status(&federation_port, "/_matrix/federation/v1/version"), StatusCode::Ok);
+        assert_eq!(status(&federation_port, "/_matrix/client/versions"), StatusCode::NotFound);
+
+        for listening in &mut listenings {
+            listening.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn parked_syncs_do_not_starve_other_requests() {
+        // Creates the test database.
+        let test = Test::new();
+
+        let port = free_port();
+
+        let mut config = Test::config();
+        config.postgres_url = test.database_url();
+        config.listeners = vec![listener(&port, vec![Resource::Client])];
+        config.worker_threads = 2;
+        config.sync_workers = 2;
+
+        let r2d2_config = R2D2Config::builder()
+            .pool_size(1)
+            .connection_customizer(Box::new(TestTransactionConnectionCustomizer))
+            .build();
+
+        let server = Server::new(&config)
+            .mount_all_with_options(r2d2_config, false)
+            .unwrap();
+
+        let mut listenings = server.listen().unwrap();
+
+        let base_url = format!("http://127.0.0.1:{}/_matrix/client", port);
+
+        let response = Client::new()
+            .post(&format!("{}/r0/register", base_url))
+            .header(Connection::close())
+            .body(r#"{"password": "secret"}"#)
+            .send()
+            .unwrap();
+        let body: Value = from_reader(response).unwrap();
+        let access_token = body.get("access_token").unwrap().as_str().unwrap().to_string();
+
+        let response = Client::new()
+            .get(&format!("{}/r0/sync?access_token={}", base_url, access_token))
+            .header(Connection::close())
+            .send()
+            .unwrap();
+        let body: Value = from_reader(response).unwrap();
+        let next_batch = body.get("next_batch").unwrap().as_str().unwrap().to_string();
+
+        let sync_url = format!(
+            "{}/r0/sync?since={}&timeout=2000&access_token={}",
+            base_url,
+            next_batch,
+            access_token
+        );
+        let syncs: Vec<_> = (0..5).map(|_| {
+            let sync_url = sync_url.clone();
+
+            thread::spawn(move || {
+                Client::new().get(&sync_url).header(Connection::close()).send().unwrap().status
+            })
+        }).collect();
+
+        thread::sleep(Duration::from_millis(200));
+
+        let start = Instant::now();
+        let status = Client::new()
+            .get(&format!("{}/versions", base_url))
+            .header(Connection::close())
+            .send()
+            .unwrap()
+            .status;
+
+        assert_eq!(status, StatusCode::Ok);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Only two syncs can wait at once, the others are asked to retry later.
+        let statuses: Vec<StatusCode> = syncs
+            .into_iter()
+            .map(|sync| sync.join().unwrap())
+            .collect();
+
+        assert!(statuses.iter().all(|status| {
+            *status == StatusCode::Ok || *status == StatusCode::ServiceUnavailable
+        }));
+        assert!(statuses.contains(&StatusCode::ServiceUnavailable));
+
+        for listening in &mut listenings {
+            listening.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn run_returns_after_the_grace_period_with_requests_in_flight() {
+        // Creates the test database.
+        let test = Test::new();
+
+        let port = free_port();
+
+        let mut config = Test::config();
+        config.postgres_url = test.database_url();
+        config.listeners = vec![listener(&port, vec![Resource::Client])];
+        config.shutdown_grace_period = 1;
+
+        let server = Server::new(&config)
+            .mount_all_with_options(R2D2Config::default(), false)
+            .unwrap();
+        let shutdown = server.shutdown();
+
+        // Requests looking up an access token wait until the table is unlocked.
+        let lock_connection = PgConnection::establish(&test.database_url()).unwrap();
+        lock_connection.execute("BEGIN").unwrap();
+        lock_connection.execute("LOCK TABLE access_tokens IN ACCESS EXCLUSIVE MODE").unwrap();
+
+        let url = format!(
+            "http://127.0.0.1:{}/_matrix/client/r0/account/3pid?access_token=x",
+            port
+        );
+        let request = thread::spawn(move || {
+            // The listener may not be accepting yet.
+            thread::sleep(Duration::from_millis(500));
+
+            let _ = Client::new().get(&url).header(Connection::close()).send();
+        });
+
+        let stopper_shutdown = shutdown.clone();
+        let stopper = thread::spawn(move || {
+            while stopper_shutdown.in_flight_requests() == 0 {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            stopper_shutdown.begin();
+        });
+
+        let start = Instant::now();
+        server.run().unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(shutdown.in_flight_requests(), 1);
+        assert!(TcpStream::connect(&format!("127.0.0.1:{}", port)[..]).is_err());
+
+        stopper.join().unwrap();
+        drop(lock_connection);
+        request.join().unwrap();
+    }
+}
@@ -0,0 +1,296 @@
+//! Graceful shutdown of the server.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{ATOMIC_BOOL_INIT, AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read as PersistentRead;
+
+use error::ApiError;
+use notifier::Notifier;
+
+/// Set from the signal handler when the process receives SIGINT or SIGTERM.
+static SIGNALED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// How often the signal flag is checked while waiting for a shutdown signal.
+const SIGNAL_POLL_INTERVAL_MS: u64 = 100;
+
+/// A shared flag indicating that the server is shutting down.
+///
+/// Background workers should check it between work items and stop picking up new work once it
+/// is set.
+#[derive(Clone, Debug, Default)]
+pub struct ShuttingDown(Arc<AtomicBool>);
+
+impl ShuttingDown {
+    /// Whether or not the server has started shutting down.
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Extract the `ShuttingDown` flag stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<ShuttingDown>, ApiError> {
+        request.get::<PersistentRead<ShuttingDown>>().map_err(ApiError::from)
+    }
+
+    fn set(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Key for ShuttingDown {
+    type Value = ShuttingDown;
+}
+
+/// Coordinates a graceful shutdown of the server.
+///
+/// Shutting down happens in the following order:
+///
+/// 1. The `ShuttingDown` flag is set, so new requests are rejected and background workers stop
+///    picking up new work.
+/// 2. Requests parked on the `Notifier` (such as long-polling syncs) are woken up so they can
+///    return immediately.
+/// 3. In-flight requests are given a grace period to finish.
+/// 4. Registered shutdown hooks are run, e.g. to flush queues and persist their positions.
+#[derive(Clone)]
+pub struct Shutdown {
+    flag: ShuttingDown,
+    hooks: Arc<Mutex<Vec<Box<Fn() + Send>>>>,
+    in_flight: Arc<(Mutex<usize>, Condvar)>,
+    notifier: Notifier,
+}
+
+impl Shutdown {
+    /// Creates a new `Shutdown` that wakes up waiters on the given `Notifier`.
+    pub fn new(notifier: Notifier) -> Self {
+        Shutdown {
+            flag: ShuttingDown::default(),
+            hooks: Arc::new(Mutex::new(Vec::new())),
+            in_flight: Arc::new((Mutex::new(0), Condvar::new())),
+            notifier: notifier,
+        }
+    }
+
+    /// The flag shared with request handlers and background workers.
+    pub fn flag(&self) -> ShuttingDown {
+        self.flag.clone()
+    }
+
+    /// Whether or not the server has started shutting down.
+    pub fn is_shutting_down(&self) -> bool {
+        self.flag.is_set()
+    }
+
+    /// Registers a function to run once in-flight requests have drained.
+    pub fn on_shutdown<F>(&self, hook: F) where F: Fn() + Send + 'static {
+        match self.hooks.lock() {
+            Ok(mut hooks) => hooks.push(Box::new(hook)),
+            Err(poisoned) => poisoned.into_inner().push(Box::new(hook)),
+        }
+    }
+
+    /// Installs handlers for SIGINT and SIGTERM that trigger a shutdown.
+    #[cfg(unix)]
+    pub fn install_signal_handlers(&self) {
+        extern "C" fn handle_signal(_: ::libc::c_int) {
+            SIGNALED.store(true, Ordering::SeqCst);
+        }
+
+        unsafe {
+            ::libc::signal(::libc::SIGINT, handle_signal as ::libc::sighandler_t);
+            ::libc::signal(::libc::SIGTERM, handle_signal as ::libc::sighandler_t);
+        }
+    }
+
+    /// Installs handlers for SIGINT and SIGTERM that trigger a shutdown.
+    #[cfg(not(unix))]
+    pub fn install_signal_handlers(&self) {}
+
+    /// Blocks the current thread until a shutdown signal is received or a shutdown is started.
+    pub fn wait_for_signal(&self) {
+        while !SIGNALED.load(Ordering::SeqCst) && !self.is_shutting_down() {
+            thread::sleep(Duration::from_millis(SIGNAL_POLL_INTERVAL_MS));
+        }
+    }
+
+    /// Starts shutting down: sets the `ShuttingDown` flag and wakes up all parked requests.
+    pub fn begin(&self) {
+        self.flag.set();
+        self.notifier.notify_all();
+    }
+
+    /// Waits up to `grace_period` for all in-flight requests to finish.
+    ///
+    /// Returns `true` if every request finished in time.
+    pub fn drain(&self, grace_period: Duration) -> bool {
+        let deadline = Instant::now() + grace_period;
+        let &(ref count, ref condvar) = &*self.in_flight;
+
+        let mut count = match count.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        while *count > 0 {
+            let now = Instant::now();
+
+            if now >= deadline {
+                return false;
+            }
+
+            count = match condvar.wait_timeout(count, deadline - now) {
+                Ok((guard, _)) => guard,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+
+        true
+    }
+
+    /// Runs all registered shutdown hooks in the order they were registered.
+    pub fn run_hooks(&self) {
+        let hooks = match self.hooks.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        for hook in hooks.iter() {
+            hook();
+        }
+    }
+
+    /// The number of requests currently being handled, including waiting syncs.
+    pub fn in_flight_requests(&self) -> usize {
+        let &(ref count, _) = &*self.in_flight;
+
+        match count.lock() {
+            Ok(count) => *count,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+
+    /// The number of threads handling a request other than a sync waiting for new events.
+    pub fn busy_workers(&self) -> usize {
+        self.in_flight_requests().saturating_sub(self.notifier.waiting())
+    }
+
+    /// Extract the `Shutdown` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<Shutdown>, ApiError> {
+        request.get::<PersistentRead<Shutdown>>().map_err(ApiError::from)
+    }
+
+    /// Records the start of a request.
+    pub fn request_started(&self) {
+        let &(ref count, _) = &*self.in_flight;
+
+        match count.lock() {
+            Ok(mut count) => *count += 1,
+            Err(poisoned) => *poisoned.into_inner() += 1,
+        }
+    }
+
+    /// Records the end of a request, waking up `drain` if it was the last one.
+    pub fn request_finished(&self) {
+        let &(ref count, ref condvar) = &*self.in_flight;
+
+        let mut count = match count.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        *count = count.saturating_sub(1);
+
+        if *count == 0 {
+            condvar.notify_all();
+        }
+    }
+}
+
+impl Key for Shutdown {
+    type Value = Shutdown;
+}
+
+impl Debug for Shutdown {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        formatter.debug_struct("Shutdown")
+            .field("flag", &self.flag)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use iron::status::Status;
+
+    use query::SyncOptions;
+    use test::Test;
+
+    #[test]
+    fn shutdown_wakes_parked_sync() {
+        let test = Arc::new(Test::new());
+        let alice = test.create_user();
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let batch = Test::get_next_batch(&test.sync(&alice.token, options));
+
+        let sync_path = format!(
+            "/_matrix/client/r0/sync?since={}&timeout=30000&access_token={}",
+            batch,
+            alice.token
+        );
+        let sync_test = test.clone();
+        let start = Instant::now();
+        let handle = thread::spawn(move || sync_test.get(&sync_path));
+
+        thread::sleep(Duration::from_millis(200));
+        test.shutdown().begin();
+
+        let response = handle.join().unwrap();
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(test.shutdown().drain(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn requests_are_rejected_while_shutting_down() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        test.shutdown().begin();
+
+        let response = test.get(&format!("/_matrix/client/r0/sync?access_token={}", alice.token));
+
+        assert_eq!(response.status, Status::ServiceUnavailable);
+        assert!(test.shutdown().drain(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn hooks_run_in_order() {
+        let test = Test::new();
+        let calls = Arc::new(::std::sync::Mutex::new(Vec::new()));
+
+        let first = calls.clone();
+        test.shutdown().on_shutdown(move || first.lock().unwrap().push(1));
+        let second = calls.clone();
+        test.shutdown().on_shutdown(move || second.lock().unwrap().push(2));
+
+        test.shutdown().run_hooks();
+
+        assert_eq!(*calls.lock().unwrap(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,187 @@
+//! An in-memory cache of the current state of rooms.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read as PersistentRead;
+use ruma_identifiers::RoomId;
+
+use error::ApiError;
+use models::room_state::RoomState;
+
+/// Caches the current state of the most recently used rooms, so that permission checks don't
+/// have to query the database for every request.
+///
+/// The cache holds at most `capacity` rooms and evicts the least recently used one when it is
+/// full. Entries are invalidated whenever a state event is saved in their room.
+#[derive(Clone, Debug)]
+pub struct StateCache {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    capacity: usize,
+    entries: Mutex<Entries>,
+    loads: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    clock: u64,
+    rooms: HashMap<RoomId, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    last_used: u64,
+    state: RoomState,
+}
+
+impl StateCache {
+    /// Creates an empty `StateCache` holding the state of at most `capacity` rooms.
+    ///
+    /// A capacity of 0 disables caching.
+    pub fn new(capacity: usize) -> Self {
+        StateCache {
+            inner: Arc::new(Inner {
+                capacity: capacity,
+                entries: Mutex::new(Entries::default()),
+                loads: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Returns the cached state of the room, marking it as recently used.
+    pub fn get(&self, room_id: &RoomId) -> Option<RoomState> {
+        let mut entries = self.lock();
+        entries.clock += 1;
+        let clock = entries.clock;
+
+        match entries.rooms.get_mut(room_id) {
+            Some(entry) => {
+                entry.last_used = clock;
+
+                Some(entry.state.clone())
+            }
+            None => None,
+        }
+    }
+
+    /// Caches the state of the room after it was loaded from the database, evicting the least
+    /// recently used room if the cache is full.
+    pub fn insert(&self, room_id: RoomId, state: RoomState) {
+        self.inner.loads.fetch_add(1, Ordering::SeqCst);
+
+        if self.inner.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.lock();
+        entries.clock += 1;
+        let clock = entries.clock;
+
+        if !entries.rooms.contains_key(&room_id) && entries.rooms.len() >= self.inner.capacity {
+            let least_recently_used = entries.rooms.iter()
+                .min_by_key(|&(_, entry)| entry.last_used)
+                .map(|(room_id, _)| room_id.clone());
+
+            if let Some(room_id) = least_recently_used {
+                entries.rooms.remove(&room_id);
+            }
+        }
+
+        entries.rooms.insert(room_id, Entry { last_used: clock, state: state });
+    }
+
+    /// Drops the cached state of the room, e.g. after a new state event was saved in it.
+    pub fn invalidate(&self, room_id: &RoomId) {
+        self.lock().rooms.remove(room_id);
+    }
+
+    /// The number of rooms whose state is currently cached.
+    pub fn len(&self) -> usize {
+        self.lock().rooms.len()
+    }
+
+    /// Whether or not the state of any room is cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of times the state of a room had to be loaded from the database.
+    pub fn loads(&self) -> usize {
+        self.inner.loads.load(Ordering::SeqCst)
+    }
+
+    /// Extract the `StateCache` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<StateCache>, ApiError> {
+        request.get::<PersistentRead<StateCache>>().map_err(ApiError::from)
+    }
+
+    /// Locks the entries, recovering them if another thread panicked while holding the lock.
+    fn lock(&self) -> MutexGuard<Entries> {
+        match self.inner.entries.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Key for StateCache {
+    type Value = StateCache;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ruma_identifiers::RoomId;
+
+    use models::room_state::RoomState;
+    use super::StateCache;
+
+    fn room_id(n: u8) -> RoomId {
+        RoomId::try_from(format!("!room{}:ruma.test", n).as_ref()).unwrap()
+    }
+
+    #[test]
+    fn evicts_least_recently_used_room() {
+        let cache = StateCache::new(2);
+
+        cache.insert(room_id(1), RoomState::default());
+        cache.insert(room_id(2), RoomState::default());
+        assert!(cache.get(&room_id(1)).is_some());
+
+        cache.insert(room_id(3), RoomState::default());
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&room_id(1)).is_some());
+        assert!(cache.get(&room_id(2)).is_none());
+        assert!(cache.get(&room_id(3)).is_some());
+    }
+
+    #[test]
+    fn invalidate_drops_room() {
+        let cache = StateCache::new(2);
+
+        cache.insert(room_id(1), RoomState::default());
+        cache.invalidate(&room_id(1));
+
+        assert!(cache.get(&room_id(1)).is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn zero_capacity_disables_caching() {
+        let cache = StateCache::new(0);
+
+        cache.insert(room_id(1), RoomState::default());
+
+        assert!(cache.get(&room_id(1)).is_none());
+        assert_eq!(cache.loads(), 1);
+    }
+}
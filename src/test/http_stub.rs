@@ -0,0 +1,319 @@
+//! A local HTTP server standing in for the servers Ruma makes requests to, like identity servers
+//! and other homeservers.
+//!
+//! Tests register the requests they expect along with canned responses, point the server's
+//! configuration at the stub, and assert on the requests it received:
+//!
+//! ```ignore
+//! let stub = HttpStub::start();
+//! stub.on(Method::Post, "/_matrix/identity/v2/lookup", StubResponse::json(r#"{"mappings": {}}"#));
+//!
+//! let test = Test::with_config(|config| config.identity_server_url = Some(stub.url()));
+//! ```
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use hyper::header::{ContentType, Headers};
+use hyper::method::Method;
+use hyper::server::{Listening, Request as HyperRequest, Response as HyperResponse, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+
+use config::KnownServerConfig;
+
+/// The body of the 404 response to requests no route matches.
+const NOT_STUBBED_BODY: &'static str =
+    r#"{"errcode": "M_UNRECOGNIZED", "error": "No stubbed route matches the request."}"#;
+
+/// Which request paths a stubbed route matches. The query string is not part of the path.
+#[derive(Clone, Debug)]
+pub enum PathMatcher {
+    /// Paths equal to the string.
+    Exact(String),
+    /// Paths starting with the string.
+    Prefix(String),
+}
+
+/// The canned response of a stubbed route.
+#[derive(Clone, Debug)]
+pub struct StubResponse {
+    status: StatusCode,
+    body: String,
+    delay: Option<Duration>,
+}
+
+/// A request the stub received.
+#[derive(Clone, Debug)]
+pub struct ReceivedRequest {
+    /// The request's method.
+    pub method: Method,
+    /// The request's path, without the query string.
+    pub path: String,
+    /// The request's query string, if it has one.
+    pub query: Option<String>,
+    /// The request's headers.
+    pub headers: Headers,
+    /// The request's body.
+    pub body: String,
+    /// Whether or not a route matched the request. Unmatched requests get a 404 response.
+    pub matched: bool,
+}
+
+/// A local HTTP server on an ephemeral port that answers with canned responses.
+///
+/// The server is stopped when the stub is dropped.
+pub struct HttpStub {
+    listening: Listening,
+    state: Arc<Mutex<StubState>>,
+}
+
+/// The routes of a stub and the requests it received.
+#[derive(Default)]
+struct StubState {
+    routes: Vec<Route>,
+    received: Vec<ReceivedRequest>,
+}
+
+/// A stubbed route.
+struct Route {
+    method: Method,
+    path: PathMatcher,
+    response: StubResponse,
+}
+
+impl PathMatcher {
+    /// Whether or not the path matches.
+    fn matches(&self, path: &str) -> bool {
+        match *self {
+            PathMatcher::Exact(ref exact) => path == exact,
+            PathMatcher::Prefix(ref prefix) => path.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for PathMatcher {
+    fn from(path: &'a str) -> PathMatcher {
+        PathMatcher::Exact(path.to_string())
+    }
+}
+
+impl StubResponse {
+    /// A 200 response with the given JSON body.
+    pub fn json(body: &str) -> StubResponse {
+        StubResponse::with_status(200, body)
+    }
+
+    /// A response with the given status code and JSON body.
+    pub fn with_status(status: u16, body: &str) -> StubResponse {
+        StubResponse {
+            status: StatusCode::from_u16(status),
+            body: body.to_string(),
+            delay: None,
+        }
+    }
+
+    /// A 502 response without a JSON body, like one of a broken server or proxy.
+    pub fn failure() -> StubResponse {
+        StubResponse {
+            status: StatusCode::BadGateway,
+            body: "Bad Gateway".to_string(),
+            delay: None,
+        }
+    }
+
+    /// Makes the stub wait before sending the response, e.g. to make a client time out.
+    pub fn after(mut self, delay: Duration) -> StubResponse {
+        self.delay = Some(delay);
+        self
+    }
+}
+
+impl HttpStub {
+    /// Starts a stub without any routes.
+    pub fn start() -> HttpStub {
+        let state = Arc::new(Mutex::new(StubState::default()));
+        let handler_state = state.clone();
+
+        let listening = Server::http("127.0.0.1:0")
+            .expect("Failed to bind the HTTP stub")
+            .handle(move |request: HyperRequest, response: HyperResponse| {
+                respond(&handler_state, request, response);
+            })
+            .expect("Failed to start the HTTP stub");
+
+        HttpStub {
+            listening: listening,
+            state: state,
+        }
+    }
+
+    /// The base URL of the stub, e.g. `http://127.0.0.1:49152`.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.listening.socket)
+    }
+
+    /// The configuration for a known server whose federation API is served by the stub.
+    ///
+    /// The stub doesn't sign anything, so the server has no verify keys.
+    pub fn known_server(&self) -> KnownServerConfig {
+        KnownServerConfig {
+            base_url: self.url(),
+            verify_keys: HashMap::new(),
+        }
+    }
+
+    /// Answers requests with the given method and a matching path with the response. Routes
+    /// registered later take precedence over earlier ones.
+    pub fn on<P>(&self, method: Method, path: P, response: StubResponse)
+    where P: Into<PathMatcher> {
+        lock(&self.state).routes.push(Route {
+            method: method,
+            path: path.into(),
+            response: response,
+        });
+    }
+
+    /// The requests received so far, oldest first.
+    pub fn received(&self) -> Vec<ReceivedRequest> {
+        lock(&self.state).received.clone()
+    }
+
+    /// Asserts that every request received so far matched a route.
+    pub fn assert_no_unexpected_requests(&self) {
+        let unexpected: Vec<String> = self.received()
+            .into_iter()
+            .filter(|request| !request.matched)
+            .map(|request| format!("{} {}", request.method, request.path))
+            .collect();
+
+        assert!(
+            unexpected.is_empty(),
+            "The HTTP stub received unexpected requests: {:?}",
+            unexpected
+        );
+    }
+}
+
+impl Drop for HttpStub {
+    fn drop(&mut self) {
+        if let Err(error) = self.listening.close() {
+            warn!("Failed to stop the HTTP stub: {}", error);
+        }
+    }
+}
+
+/// Records the request and sends the response of the first matching route, or a 404.
+fn respond(state: &Mutex<StubState>, mut request: HyperRequest, mut response: HyperResponse) {
+    let uri = match request.uri {
+        RequestUri::AbsolutePath(ref uri) => uri.clone(),
+        ref uri => uri.to_string(),
+    };
+    let (path, query) = match uri.find('?') {
+        Some(index) => (uri[..index].to_string(), Some(uri[index + 1..].to_string())),
+        None => (uri, None),
+    };
+
+    let mut body = String::new();
+
+    if let Err(error) = request.read_to_string(&mut body) {
+        warn!("The HTTP stub failed to read a request body: {}", error);
+    }
+
+    let stub_response = {
+        let mut state = lock(state);
+
+        let stub_response = state.routes
+            .iter()
+            .rev()
+            .find(|route| route.method == request.method && route.path.matches(&path))
+            .map(|route| route.response.clone());
+
+        state.received.push(ReceivedRequest {
+            method: request.method.clone(),
+            path: path,
+            query: query,
+            headers: request.headers.clone(),
+            body: body,
+            matched: stub_response.is_some(),
+        });
+
+        stub_response.unwrap_or_else(|| StubResponse::with_status(404, NOT_STUBBED_BODY))
+    };
+
+    if let Some(delay) = stub_response.delay {
+        thread::sleep(delay);
+    }
+
+    *response.status_mut() = stub_response.status;
+    response.headers_mut().set(ContentType::json());
+
+    if let Err(error) = response.send(stub_response.body.as_bytes()) {
+        warn!("The HTTP stub failed to send a response: {}", error);
+    }
+}
+
+/// Locks the state, even if a panicking request handler poisoned the lock.
+fn lock(state: &Mutex<StubState>) -> MutexGuard<StubState> {
+    match state.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::time::{Duration, Instant};
+
+    use hyper::Client;
+    use hyper::method::Method;
+    use hyper::status::StatusCode;
+
+    use super::{HttpStub, PathMatcher, StubResponse};
+
+    fn get(stub: &HttpStub, path: &str) -> (StatusCode, String) {
+        let mut response = Client::new()
+            .get(&format!("{}{}", stub.url(), path))
+            .send()
+            .unwrap();
+
+        let mut body = String::new();
+        response.read_to_string(&mut body).unwrap();
+
+        (response.status, body)
+    }
+
+    #[test]
+    fn stub_answers_with_canned_responses_and_records_requests() {
+        let stub = HttpStub::start();
+
+        stub.on(Method::Get, "/exact", StubResponse::json(r#"{"answer": 42}"#));
+        stub.on(
+            Method::Get,
+            PathMatcher::Prefix("/media/".to_string()),
+            StubResponse::failure().after(Duration::from_millis(100)),
+        );
+
+        assert_eq!(get(&stub, "/exact?key=value"), (StatusCode::Ok, r#"{"answer": 42}"#.into()));
+
+        let start = Instant::now();
+        assert_eq!(get(&stub, "/media/abc").0, StatusCode::BadGateway);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        stub.assert_no_unexpected_requests();
+
+        assert_eq!(get(&stub, "/unknown").0, StatusCode::NotFound);
+
+        let received = stub.received();
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[0].path, "/exact");
+        assert_eq!(received[0].query, Some("key=value".to_string()));
+        assert!(received[0].matched);
+        assert_eq!(received[1].path, "/media/abc");
+        assert!(!received[2].matched);
+    }
+}
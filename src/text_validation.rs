@@ -0,0 +1,209 @@
+//! Validation of text that is stored in Postgres.
+//!
+//! Postgres rejects strings containing NUL characters, which would otherwise end a request with
+//! `M_UNKNOWN`. Text fields like display names are therefore checked before they are stored and
+//! rejected with `M_BAD_JSON` naming the field. Event content can be anything, so NULs in it are
+//! stripped instead.
+//!
+//! JSON strings with unpaired surrogate escapes like `"\ud800"` can't be represented as Rust
+//! strings and fail to parse. `check_surrogates` finds the field they are in, so the error can
+//! name it.
+
+use std::str::from_utf8;
+
+use serde_json::{Map, Value};
+
+use error::ApiError;
+
+/// Checks that a text field of a request contains no NUL characters.
+pub fn check(field: &str, value: &str) -> Result<(), ApiError> {
+    if value.contains('\0') {
+        return Err(ApiError::bad_json(
+            format!("Field `{}` must not contain NUL characters.", field)
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks an optional text field of a request like `check`.
+pub fn check_optional(field: &str, value: &Option<String>) -> Result<(), ApiError> {
+    match *value {
+        Some(ref value) => check(field, value),
+        None => Ok(()),
+    }
+}
+
+/// Removes all NUL characters from the strings and keys of event content.
+pub fn strip_nul_characters(content: Value) -> Value {
+    let mut stripped = false;
+    let content = strip(content, &mut stripped);
+
+    if stripped {
+        warn!("Stripped NUL characters from event content.");
+    }
+
+    content
+}
+
+fn strip(value: Value, stripped: &mut bool) -> Value {
+    match value {
+        Value::String(string) => Value::String(strip_string(string, stripped)),
+        Value::Array(values) => {
+            Value::Array(values.into_iter().map(|value| strip(value, stripped)).collect())
+        }
+        Value::Object(object) => {
+            let object: Map<String, Value> = object.into_iter()
+                .map(|(key, value)| (strip_string(key, stripped), strip(value, stripped)))
+                .collect();
+
+            Value::Object(object)
+        }
+        value => value,
+    }
+}
+
+fn strip_string(string: String, stripped: &mut bool) -> String {
+    if string.contains('\0') {
+        *stripped = true;
+
+        string.replace('\0', "")
+    } else {
+        string
+    }
+}
+
+/// Checks JSON text for unpaired surrogate escapes, naming the field of the first one.
+pub fn check_surrogates(json: &str) -> Result<(), ApiError> {
+    let bytes = json.as_bytes();
+    let mut last_key: Option<&str> = None;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'"' {
+            i += 1;
+            continue;
+        }
+
+        let start = i + 1;
+        let (end, is_valid) = scan_string(bytes, start);
+
+        let mut next = end + 1;
+        while next < bytes.len() && (bytes[next] as char).is_whitespace() {
+            next += 1;
+        }
+        let is_key = next < bytes.len() && bytes[next] == b':';
+
+        // `start` and `end` are at quotes or the end of the text, so they are char boundaries.
+        let string = &json[start..end];
+
+        if !is_valid {
+            let location = match (is_key, last_key) {
+                (true, _) => format!("Field name `{}`", string),
+                (false, Some(key)) => format!("Field `{}`", key),
+                (false, None) => "The request body".to_string(),
+            };
+
+            return Err(ApiError::bad_json(
+                format!("{} contains an invalid surrogate escape.", location)
+            ));
+        }
+
+        if is_key {
+            last_key = Some(string);
+        }
+
+        i = end + 1;
+    }
+
+    Ok(())
+}
+
+/// Scans a JSON string starting after its opening quote.
+///
+/// Returns the index of the closing quote and whether or not all surrogate escapes are paired.
+fn scan_string(bytes: &[u8], start: usize) -> (usize, bool) {
+    let mut is_valid = true;
+    let mut pending_high_surrogate = false;
+    let mut j = start;
+
+    while j < bytes.len() && bytes[j] != b'"' {
+        let (unit, length) = match (bytes[j], bytes.get(j + 1)) {
+            (b'\\', Some(&b'u')) if j + 6 <= bytes.len() => {
+                let unit = from_utf8(&bytes[j + 2..j + 6]).ok()
+                    .and_then(|hex| u16::from_str_radix(hex, 16).ok());
+
+                (unit, 6)
+            }
+            (b'\\', _) => (None, 2),
+            _ => (None, 1),
+        };
+
+        match unit {
+            Some(0xD800...0xDBFF) => {
+                is_valid = is_valid && !pending_high_surrogate;
+                pending_high_surrogate = true;
+            }
+            Some(0xDC00...0xDFFF) => {
+                is_valid = is_valid && pending_high_surrogate;
+                pending_high_surrogate = false;
+            }
+            _ => {
+                is_valid = is_valid && !pending_high_surrogate;
+                pending_high_surrogate = false;
+            }
+        }
+
+        j += length;
+    }
+
+    (j.min(bytes.len()), is_valid && !pending_high_surrogate)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, from_str, to_value};
+
+    use super::{check, check_surrogates, strip_nul_characters};
+
+    fn error_message(json: &str) -> Option<String> {
+        check_surrogates(json).err().map(|error| {
+            to_value(&error).unwrap().get("error").unwrap().as_str().unwrap().to_string()
+        })
+    }
+
+    #[test]
+    fn nul_characters_are_rejected() {
+        assert!(check("displayname", "Alice").is_ok());
+        assert!(check("displayname", "Al\0ice").is_err());
+    }
+
+    #[test]
+    fn nul_characters_are_stripped_from_content() {
+        let content: Value = from_str(r#"{"body": "a\u0000b", "n\u0000": ["\u0000"], "x": 1}"#)
+            .unwrap();
+
+        assert_eq!(
+            strip_nul_characters(content),
+            from_str::<Value>(r#"{"body": "ab", "n": [""], "x": 1}"#).unwrap()
+        );
+    }
+
+    #[test]
+    fn paired_surrogates_are_valid() {
+        assert_eq!(error_message(r#"{"displayname": "😀", "a": "\\ud800"}"#), None);
+        assert_eq!(error_message(r#"{"displayname": "é\"\\"}"#), None);
+    }
+
+    #[test]
+    fn unpaired_surrogates_name_the_field() {
+        assert_eq!(
+            error_message(r#"{"avatar_url": "mxc://a", "displayname": "\ud800"}"#).unwrap(),
+            "Field `displayname` contains an invalid surrogate escape."
+        );
+        assert!(error_message(r#"{"displayname": "\udc00\ud800"}"#).is_some());
+        assert!(error_message(r#"{"displayname": "\ud800x"}"#).is_some());
+        assert!(error_message(r#"{"\ud800": 1}"#).unwrap().starts_with("Field name"));
+        assert!(error_message(r#"["\ud800"]"#).unwrap().starts_with("The request body"));
+    }
+}
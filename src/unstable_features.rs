@@ -0,0 +1,122 @@
+//! Registry of the unstable features advertised by the `/versions` endpoint.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::ser::{Serialize, Serializer};
+
+use api::r0::TimestampToEvent;
+use config::Config;
+use query;
+
+/// A function that registers the unstable features a module knows about.
+type Contributor = fn(&mut UnstableFeatures);
+
+/// Every module that contributes unstable features.
+///
+/// Add a module's contributor here to have its features show up in `/versions`.
+static CONTRIBUTORS: [Contributor; 2] = [
+    query::register_unstable_features,
+    TimestampToEvent::register_unstable_features,
+];
+
+/// The unstable features known to the server, and whether or not each of them is enabled.
+#[derive(Clone, Debug, Default)]
+pub struct UnstableFeatures {
+    features: BTreeMap<String, bool>,
+    /// The registered features that aren't implemented yet, which can't be enabled.
+    unimplemented: BTreeSet<String>,
+}
+
+impl UnstableFeatures {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        UnstableFeatures::default()
+    }
+
+    /// Collects the features from all contributors, as they are enabled by default.
+    pub fn registered() -> Self {
+        let mut unstable_features = UnstableFeatures::new();
+
+        for contribute in CONTRIBUTORS.iter() {
+            contribute(&mut unstable_features);
+        }
+
+        unstable_features
+    }
+
+    /// Collects the features from all contributors and enables those listed in the `Config`.
+    ///
+    /// Features in the `Config` that no module registered are ignored, and so are unimplemented
+    /// ones, which `Config::from_layers` refuses already.
+    pub fn from_config(config: &Config) -> Self {
+        let mut unstable_features = UnstableFeatures::registered();
+        unstable_features.enable(&config.unstable_features);
+
+        unstable_features
+    }
+
+    /// Enables the given features, ignoring unknown and unimplemented ones with a warning.
+    pub fn enable(&mut self, names: &[String]) {
+        for name in names {
+            if self.unimplemented.contains(name) {
+                warn!("Ignoring unimplemented unstable feature in configuration: {}", name);
+                continue;
+            }
+
+            match self.features.get_mut(name) {
+                Some(enabled) => *enabled = true,
+                None => warn!("Ignoring unknown unstable feature in configuration: {}", name),
+            }
+        }
+    }
+
+    /// Registers a feature, enabled or not by default.
+    pub fn register(&mut self, name: &str, enabled: bool) {
+        self.features.insert(name.to_string(), enabled);
+    }
+
+    /// Registers a feature that isn't implemented yet. It is advertised as disabled, and can't be
+    /// enabled.
+    pub fn register_unimplemented(&mut self, name: &str) {
+        self.features.insert(name.to_string(), false);
+        self.unimplemented.insert(name.to_string());
+    }
+
+    /// Whether or not the given feature is registered, but not implemented yet.
+    pub fn is_unimplemented(&self, name: &str) -> bool {
+        self.unimplemented.contains(name)
+    }
+
+    /// Whether or not the given feature is registered and enabled.
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.features.get(name).cloned().unwrap_or(false)
+    }
+}
+
+impl Serialize for UnstableFeatures {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        self.features.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UnstableFeatures;
+
+    #[test]
+    fn only_implemented_features_can_be_enabled() {
+        let mut unstable_features = UnstableFeatures::new();
+        unstable_features.register("org.example.finished", false);
+        unstable_features.register_unimplemented("org.example.unfinished");
+
+        unstable_features.enable(&[
+            "org.example.finished".to_string(),
+            "org.example.unfinished".to_string(),
+            "org.example.unknown".to_string(),
+        ]);
+
+        assert!(unstable_features.is_enabled("org.example.finished"));
+        assert!(!unstable_features.is_enabled("org.example.unfinished"));
+        assert!(!unstable_features.is_enabled("org.example.unknown"));
+    }
+}